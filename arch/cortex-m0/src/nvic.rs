@@ -63,12 +63,32 @@ pub unsafe fn next_pending() -> Option<u32> {
     None
 }
 
+/// Get the first interrupt of `first` that is pending, in the order given,
+/// or else the lowest number pending interrupt. Lets a chip service the
+/// interrupts it has deadlines on before the others.
+pub unsafe fn next_pending_with_priority(first: &[u32]) -> Option<u32> {
+    let nvic: &Registers = &*BASE_ADDRESS;
+
+    first
+        .iter()
+        .cloned()
+        .find(|&idx| nvic.ispr[idx as usize / 32].get() & (1 << (idx & 31)) != 0)
+        .or_else(|| next_pending())
+}
+
 pub unsafe fn has_pending() -> bool {
     let nvic: &Registers = &*BASE_ADDRESS;
 
     nvic.ispr.iter().fold(0, |i, ispr| ispr.get() | i) != 0
 }
 
+/// Number of interrupts currently pending
+pub unsafe fn pending_count() -> u32 {
+    let nvic: &Registers = &*BASE_ADDRESS;
+
+    nvic.ispr.iter().map(|ispr| ispr.get().count_ones()).sum()
+}
+
 /// An opaque wrapper for a single NVIC interrupt.
 ///
 /// Hand these out to low-level driver to let them control their own interrupts
@@ -139,6 +139,11 @@ impl kernel::mpu::MPU for MPU {
         regs.control.set(0b0);
     }
 
+    fn number_total_regions(&self) -> usize {
+        let regs = unsafe { &*self.0 };
+        regs.mpu_type.get().data_regions.get() as usize
+    }
+
     fn create_region(
         region_num: usize,
         start: usize,
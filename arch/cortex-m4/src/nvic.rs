@@ -69,6 +69,13 @@ pub unsafe fn has_pending() -> bool {
     nvic.ispr.iter().fold(0, |i, ispr| ispr.get() | i) != 0
 }
 
+/// Number of interrupts currently pending
+pub unsafe fn pending_count() -> u32 {
+    let nvic: &Registers = &*BASE_ADDRESS;
+
+    nvic.ispr.iter().map(|ispr| ispr.get().count_ones()).sum()
+}
+
 /// An opaque wrapper for a single NVIC interrupt.
 ///
 /// Hand these out to low-level driver to let them control their own interrupts
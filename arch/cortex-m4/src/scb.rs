@@ -49,6 +49,26 @@ pub unsafe fn unset_sleepdeep() {
     (*SCB).scr.set(scr & !(1 << 2));
 }
 
+/// Fault status and fault address registers, as read by the fault handlers
+pub struct FaultStatus {
+    pub shcsr: u32,
+    pub cfsr: u32,
+    pub hfsr: u32,
+    pub mmfar: u32,
+    pub bfar: u32,
+}
+
+/// Read the fault status and fault address registers.
+pub unsafe fn fault_status() -> FaultStatus {
+    FaultStatus {
+        shcsr: (*SCB).shcsr.get(),
+        cfsr: (*SCB).cfsr.get(),
+        hfsr: (*SCB).hfsr.get(),
+        mmfar: (*SCB).mmfar.get(),
+        bfar: (*SCB).bfar.get(),
+    }
+}
+
 /// Software reset using the ARM System Control Block
 pub unsafe fn reset() {
     let aircr = (*SCB).aircr.get();
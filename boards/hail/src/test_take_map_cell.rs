@@ -1,4 +1,19 @@
+use kernel::common::regs::ReadWrite;
 use kernel::common::take_cell::MapCell;
+use kernel::common::StaticRef;
+
+/// Cortex-M data watchpoint and trace unit, used for its cycle counter
+#[repr(C)]
+struct DwtRegisters {
+    ctrl: ReadWrite<u32>,
+    cyccnt: ReadWrite<u32>,
+}
+
+const DWT: StaticRef<DwtRegisters> = unsafe { StaticRef::new(0xE0001000 as *const DwtRegisters) };
+
+/// Debug exception and monitor control register, which gates the DWT
+const DEMCR: StaticRef<ReadWrite<u32>> =
+    unsafe { StaticRef::new(0xE000EDFC as *const ReadWrite<u32>) };
 
 pub unsafe fn test_take_map_cell() {
     static FOO: u32 = 1234;
@@ -31,14 +46,10 @@ pub unsafe fn test_take_map_cell() {
 #[inline(never)]
 #[allow(unused_unsafe)]
 unsafe fn test_map_cell<'a, A>(tc: &MapCell<A>) {
-    let dwt_ctl: *mut u32 = 0xE0001000 as *mut u32;
-    let dwt_cycles: *mut u32 = 0xE0001004 as *mut u32;
-    let demcr: *mut u32 = 0xE000EDFC as *mut u32;
-
-    ::core::ptr::write_volatile(demcr, 0x01000000);
-    ::core::ptr::write_volatile(dwt_cycles, 0);
-    ::core::ptr::write_volatile(dwt_ctl, ::core::ptr::read_volatile(dwt_ctl) | 1);
+    DEMCR.set(0x01000000);
+    DWT.cyccnt.set(0);
+    DWT.ctrl.set(DWT.ctrl.get() | 1);
     tc.map(|_| ());
-    let end = ::core::ptr::read_volatile(dwt_cycles);
+    let end = DWT.cyccnt.get();
     debug!("time: {}, size: {}", end, ::core::mem::size_of_val(tc));
 }
@@ -0,0 +1,77 @@
+use core::fmt::{Arguments, Write};
+use kernel::debug;
+use kernel::hil::gpio::Pin;
+use kernel::hil::led;
+use kernel::hil::uart::{self, UART};
+use nrf51;
+use nrf5x;
+
+/// Polls of `tx_ready` before a byte is given up on. A byte takes under 100us
+/// at 115200 baud, this is several milliseconds.
+const TX_TIMEOUT: usize = 100_000;
+
+struct Writer {
+    initialized: bool,
+    /// A byte never went out, so the panic goes on to blink the LED instead
+    /// of hanging on the UART
+    stuck: bool,
+}
+
+static mut WRITER: Writer = Writer {
+    initialized: false,
+    stuck: false,
+};
+
+impl Write for Writer {
+    fn write_str(&mut self, s: &str) -> ::core::fmt::Result {
+        let uart = unsafe { &mut nrf51::uart::UART0 };
+        if !self.initialized {
+            self.initialized = true;
+            uart.init(uart::UARTParams {
+                baud_rate: 115200,
+                stop_bits: uart::StopBits::One,
+                parity: uart::Parity::None,
+                hw_flow_control: false,
+            });
+        }
+        for c in s.bytes() {
+            if self.stuck {
+                return Err(::core::fmt::Error);
+            }
+            unsafe {
+                uart.send_byte(c);
+            }
+            let mut polls = 0;
+            while !uart.tx_ready() {
+                polls += 1;
+                if polls == TX_TIMEOUT {
+                    self.stuck = true;
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Panic handler
+#[cfg(not(test))]
+#[no_mangle]
+#[lang = "panic_fmt"]
+pub unsafe extern "C" fn panic_fmt(args: Arguments, file: &'static str, line: u32) -> ! {
+    // The top left LED of the matrix, on ROW1 and COL1
+    const ROW1_PIN: usize = 13;
+    const COL1_PIN: usize = 4;
+    let col1 = &nrf5x::gpio::PORT[COL1_PIN];
+    col1.make_output();
+    col1.clear();
+    let led = &mut led::LedHigh::new(&mut nrf5x::gpio::PORT[ROW1_PIN]);
+    let writer = &mut WRITER;
+    debug::panic_begin();
+    debug::panic_banner(writer, args, file, line);
+    debug::flush(writer);
+    nrf5x::interrupt_statistics::INTERRUPT_STATISTICS.statistics_str(writer);
+    nrf5x::peripheral_snapshot::snapshot_str(writer);
+    debug::panic_process_info(writer);
+    debug::panic_blink_forever(led)
+}
@@ -0,0 +1,302 @@
+//! Tock kernel for the BBC micro:bit. </br>
+//! This is an nRF51822 SoC (a Cortex M0 core with a BLE transceiver) with a
+//! 5x5 LED matrix, two buttons and an edge connector. </br>
+//!
+//! Currently the kernel provides:
+//!
+//! * Timers
+//! * GPIO, including the LED matrix
+//! * UART, over the USB interface chip
+//! * Buttons A and B
+//!
+//! ### GPIO driver pins
+//! The edge connector pads that are not shared with the LED matrix, the
+//! buttons or the I2C bus:
+//! * 0 -> P0.03   (pad 0)
+//! * 1 -> P0.02   (pad 1)
+//! * 2 -> P0.01   (pad 2)
+//! * 3 -> P0.18   (pad 8)
+//! * 4 -> P0.20   (pad 12)
+//! * 5 -> P0.16   (pad 16)
+//! * 6 -> P0.23   (pad 13, SCK)
+//! * 7 -> P0.22   (pad 14, MISO)
+//! * 8 -> P0.21   (pad 15, MOSI)
+//!
+//! ### LED matrix
+//! The 25 LEDs are wired as a matrix of 3 rows by 9 columns, an LED lights
+//! up when its row is high and its column low. The rows and columns are
+//! driven through the GPIO driver, the columns also being pads 3, 4, 6, 7,
+//! 9 and 10 of the edge connector.
+//! * 9 - 11 -> P0.13 - P0.15   (ROW1 - ROW3)
+//! * 12 - 20 -> P0.04 - P0.12  (COL1 - COL9)
+//!
+//! ### Buttons
+//! The button driver numbers button A 0 and button B 1. Both have pull-ups
+//! on the board and are low while pressed, presses and releases are
+//! debounced for 20 ms.
+//! * A -> P0.17   (pad 5)
+//! * B -> P0.26   (pad 11)
+//!
+//! ### Clocks
+//! The board has a 16 MHz crystal and no 32.768 kHz crystal, the RTC runs
+//! from the internal RC oscillator.
+
+#![no_std]
+#![no_main]
+#![feature(lang_items)]
+#![deny(missing_docs)]
+
+extern crate capsules;
+#[allow(unused_imports)]
+#[macro_use(debug, debug_verbose, debug_gpio, static_init)]
+extern crate kernel;
+extern crate nrf51;
+extern crate nrf5x;
+
+use capsules::alarm::AlarmDriver;
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use kernel::hil::uart::UART;
+use nrf5x::pinmux::Pinmux;
+use nrf5x::rtc::{Rtc, RTC};
+
+/// UART Writer
+#[macro_use]
+pub mod io;
+
+// The LED matrix
+const ROW1_PIN: usize = 13;
+const ROW2_PIN: usize = 14;
+const ROW3_PIN: usize = 15;
+const COL1_PIN: usize = 4;
+
+// The buttons
+const BUTTON_A_PIN: usize = 17;
+const BUTTON_B_PIN: usize = 26;
+
+// The UART lines to the USB interface chip
+const UART_TX_PIN: u32 = 24;
+const UART_RX_PIN: u32 = 25;
+
+type DebouncedButton =
+    capsules::debounce::DebouncedPin<'static, nrf5x::gpio::GPIOPin, VirtualMuxAlarm<'static, Rtc>>;
+
+// State for loading and holding applications.
+
+// How should the kernel respond when a process faults.
+const FAULT_RESPONSE: kernel::process::FaultResponse = kernel::process::FaultResponse::Panic;
+
+// Number of concurrent processes this platform supports. APP_MEMORY is split
+// evenly between the processes found in flash, at most this many.
+const NUM_PROCS: usize = 2;
+
+#[link_section = ".app_memory"]
+static mut APP_MEMORY: [u8; 8192] = [0; 8192];
+
+static mut PROCESSES: [Option<&'static mut kernel::Process<'static>>; NUM_PROCS] = [None, None];
+
+/// Supported drivers by the platform
+pub struct Platform {
+    button: &'static capsules::button::Button<'static, DebouncedButton>,
+    console: &'static capsules::console::Console<
+        'static,
+        capsules::virtual_uart::UartDevice<'static>,
+    >,
+    gpio: &'static capsules::gpio::GPIO<'static, nrf5x::gpio::GPIOPin>,
+    alarm: &'static AlarmDriver<'static, VirtualMuxAlarm<'static, Rtc>>,
+}
+
+impl kernel::Platform for Platform {
+    fn with_driver<F, R>(&self, driver_num: usize, f: F) -> R
+    where
+        F: FnOnce(Option<&kernel::Driver>) -> R,
+    {
+        match driver_num {
+            capsules::console::DRIVER_NUM => f(Some(self.console)),
+            capsules::gpio::DRIVER_NUM => f(Some(self.gpio)),
+            capsules::alarm::DRIVER_NUM => f(Some(self.alarm)),
+            capsules::button::DRIVER_NUM => f(Some(self.button)),
+            _ => f(None),
+        }
+    }
+}
+
+/// Entry point in the vector table called on hard reset.
+#[no_mangle]
+pub unsafe fn reset_handler() {
+    // Loads relocations and clears BSS
+    nrf51::init();
+
+    // Only the high frequency crystal is fitted, a 16 MHz one. RTC1, which
+    // drives the alarms, runs from the RC oscillator, the UART requests the
+    // high frequency clock only while it is in use.
+    nrf51::clock::CLOCK.configure(
+        nrf51::clock::LowClockSource::RC,
+        nrf51::clock::HighClockSource::XTAL,
+    );
+    nrf51::clock::CLOCK.high_set_freq(nrf51::clock::XtalFreq::F16MHz);
+
+    let gpio_pins = static_init!(
+        [&'static nrf5x::gpio::GPIOPin; 21],
+        [
+            &nrf5x::gpio::PORT[3],  // Edge connector pad 0
+            &nrf5x::gpio::PORT[2],  // Edge connector pad 1
+            &nrf5x::gpio::PORT[1],  // Edge connector pad 2
+            &nrf5x::gpio::PORT[18], // Edge connector pad 8
+            &nrf5x::gpio::PORT[20], // Edge connector pad 12
+            &nrf5x::gpio::PORT[16], // Edge connector pad 16
+            &nrf5x::gpio::PORT[23], // Edge connector pad 13
+            &nrf5x::gpio::PORT[22], // Edge connector pad 14
+            &nrf5x::gpio::PORT[21], // Edge connector pad 15
+            &nrf5x::gpio::PORT[ROW1_PIN],
+            &nrf5x::gpio::PORT[ROW2_PIN],
+            &nrf5x::gpio::PORT[ROW3_PIN],
+            &nrf5x::gpio::PORT[COL1_PIN], // COL1, pad 3
+            &nrf5x::gpio::PORT[5],        // COL2, pad 4
+            &nrf5x::gpio::PORT[6],        // COL3, pad 10
+            &nrf5x::gpio::PORT[7],        // COL4
+            &nrf5x::gpio::PORT[8],        // COL5
+            &nrf5x::gpio::PORT[9],        // COL6
+            &nrf5x::gpio::PORT[10],       // COL7, pad 9
+            &nrf5x::gpio::PORT[11],       // COL8, pad 7
+            &nrf5x::gpio::PORT[12],       // COL9, pad 6
+        ]
+    );
+
+    let gpio = static_init!(
+        capsules::gpio::GPIO<'static, nrf5x::gpio::GPIOPin>,
+        capsules::gpio::GPIO::new(gpio_pins, kernel::Grant::create())
+    );
+    for pin in gpio_pins.iter() {
+        pin.set_client(gpio);
+    }
+
+    // No flow control lines go to the interface chip
+    nrf51::uart::UART0.configure(
+        Pinmux::new(UART_TX_PIN),
+        Pinmux::new(UART_RX_PIN),
+        Pinmux::disconnected(),
+        Pinmux::disconnected(),
+    );
+    // UART0 is shared through a mux, so other kernel users can be added
+    // next to the console
+    let uart_mux = static_init!(
+        capsules::virtual_uart::MuxUart<'static>,
+        capsules::virtual_uart::MuxUart::new(
+            &nrf51::uart::UART0,
+            &mut capsules::virtual_uart::RX_BUF
+        )
+    );
+    UART::set_client(&nrf51::uart::UART0, uart_mux);
+
+    let console_uart = static_init!(
+        capsules::virtual_uart::UartDevice,
+        capsules::virtual_uart::UartDevice::new(uart_mux)
+    );
+    console_uart.setup();
+    let console = static_init!(
+        capsules::console::Console<capsules::virtual_uart::UartDevice>,
+        capsules::console::Console::new(
+            console_uart,
+            115200,
+            &mut capsules::console::WRITE_BUF,
+            &mut capsules::console::READ_BUF,
+            kernel::Grant::create()
+        )
+    );
+    UART::set_client(console_uart, console);
+    console.initialize();
+
+    // Attach the kernel debug interface to this console
+    let kc = static_init!(capsules::console::App, capsules::console::App::default());
+    kernel::debug::assign_console_driver(Some(console), kc);
+
+    let rtc = &nrf5x::rtc::RTC;
+    let mux_alarm = static_init!(MuxAlarm<'static, Rtc>, MuxAlarm::new(&RTC));
+    rtc.set_client(mux_alarm);
+
+    let virtual_alarm1 = static_init!(
+        VirtualMuxAlarm<'static, Rtc>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    let alarm = static_init!(
+        AlarmDriver<'static, VirtualMuxAlarm<'static, Rtc>>,
+        AlarmDriver::new(virtual_alarm1, kernel::Grant::create())
+    );
+    virtual_alarm1.set_client(alarm);
+
+    // The buttons have their own driver, with each press and release
+    // debounced before it reaches the apps
+
+    let button_a_alarm = static_init!(
+        VirtualMuxAlarm<'static, Rtc>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    let button_a = static_init!(
+        DebouncedButton,
+        capsules::debounce::DebouncedPin::new(&nrf5x::gpio::PORT[BUTTON_A_PIN], button_a_alarm)
+    );
+    nrf5x::gpio::PORT[BUTTON_A_PIN].set_client(button_a);
+    button_a_alarm.set_client(button_a);
+
+    let button_b_alarm = static_init!(
+        VirtualMuxAlarm<'static, Rtc>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    let button_b = static_init!(
+        DebouncedButton,
+        capsules::debounce::DebouncedPin::new(&nrf5x::gpio::PORT[BUTTON_B_PIN], button_b_alarm)
+    );
+    nrf5x::gpio::PORT[BUTTON_B_PIN].set_client(button_b);
+    button_b_alarm.set_client(button_b);
+
+    let button_pins = static_init!(
+        [(&'static DebouncedButton, capsules::button::GpioMode); 2],
+        [
+            (button_a, capsules::button::GpioMode::LowWhenPressed), // 17
+            (button_b, capsules::button::GpioMode::LowWhenPressed), // 26
+        ]
+    );
+    let button = static_init!(
+        capsules::button::Button<'static, DebouncedButton>,
+        capsules::button::Button::new(button_pins, kernel::Grant::create())
+    );
+    for &(btn, _) in button_pins.iter() {
+        use kernel::hil::gpio::PinCtl;
+        // The pull-ups are on the board
+        btn.set_input_mode(kernel::hil::gpio::InputMode::PullNone);
+        btn.set_client(button);
+    }
+
+    let platform = Platform {
+        button: button,
+        console: console,
+        gpio: gpio,
+        alarm: alarm,
+    };
+
+    rtc.start();
+
+    let mut chip = nrf51::chip::NRF51::new();
+
+    debug!("Initialization complete. Entering main loop");
+    extern "C" {
+        /// Beginning of the ROM region containing app images.
+        static _sapps: u8;
+        /// End of the ROM region containing app images.
+        static _eapps: u8;
+    }
+    kernel::process::load_processes_in_region(
+        &_sapps as *const u8,
+        &_eapps as *const u8 as usize - &_sapps as *const u8 as usize,
+        &mut APP_MEMORY,
+        &mut PROCESSES,
+        FAULT_RESPONSE,
+    );
+
+    kernel::main(
+        &platform,
+        &mut chip,
+        &mut PROCESSES,
+        &kernel::ipc::IPC::new(),
+    );
+}
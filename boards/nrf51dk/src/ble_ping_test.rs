@@ -0,0 +1,78 @@
+//! Round-trip time of BLE packets between two nRF51 DKs
+//!
+//! Flash one board with `Role::Initiator` and the other with
+//! `Role::Responder`, by adding somewhere after `mux_alarm` and the BLE
+//! driver have been set up in `reset_handler`:
+//!
+//! ```rustc
+//!     ble_ping_test::run(mux_alarm, capsules::test::ble_ping::Role::Initiator);
+//! ```
+//!
+//! The test takes the radio over from the BLE advertising driver. The
+//! initiator sends 1000 pings 50 ms apart on channel 39 and prints the
+//! statistics every 100 pings on the console.
+
+use capsules::test::alarm_jitter::ReferenceClock;
+use capsules::test::ble_ping::{self, BlePing, Role};
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use kernel::hil::ble_advertising::{BleAdvertisementDriver, RadioChannel};
+use kernel::hil::time::Freq1MHz;
+use nrf51;
+use nrf5x::rtc::Rtc;
+use nrf5x::timer::{BitmodeValue, Timer, TIMER2};
+
+/// TIMER2 counting at 1MHz, read with a capture into a CC channel allocated
+/// for the test
+pub struct Timer2Reference(&'static Timer, u8);
+
+impl ReferenceClock for Timer2Reference {
+    type Frequency = Freq1MHz;
+
+    fn now(&self) -> u32 {
+        self.0.capture(self.1)
+    }
+}
+
+type TestBlePing =
+    BlePing<'static, nrf51::radio::Radio, VirtualMuxAlarm<'static, Rtc>, Timer2Reference>;
+
+pub unsafe fn run(mux_alarm: &'static MuxAlarm<'static, Rtc>, role: Role) {
+    // 16MHz divided by 2^4
+    TIMER2.set_bitmode(BitmodeValue::Size32Bits);
+    TIMER2.set_prescaler(4);
+    TIMER2.clear();
+    TIMER2.start();
+    let cc = TIMER2
+        .allocate_cc()
+        .unwrap_or_else(|_| panic!("No free TIMER2 CC channel for the reference clock"));
+    let reference = static_init!(Timer2Reference, Timer2Reference(&TIMER2, cc));
+
+    let virtual_alarm = static_init!(
+        VirtualMuxAlarm<'static, Rtc>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    // Static random addresses have the two top bits set
+    let address = match role {
+        Role::Initiator => [0x01, 0x00, 0x00, 0x00, 0x00, 0xc0],
+        Role::Responder => [0x02, 0x00, 0x00, 0x00, 0x00, 0xc0],
+    };
+    let test = static_init!(
+        TestBlePing,
+        BlePing::new(
+            &nrf51::radio::RADIO,
+            virtual_alarm,
+            reference,
+            &mut ble_ping::BUF,
+            role,
+            RadioChannel::AdvertisingChannel39,
+            address,
+            50,
+            1000,
+            100
+        )
+    );
+    virtual_alarm.set_client(test);
+    nrf51::radio::RADIO.set_receive_client(test);
+    nrf51::radio::RADIO.set_transmit_client(test);
+    test.run();
+}
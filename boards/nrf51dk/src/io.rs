@@ -5,11 +5,21 @@ use kernel::hil::uart::{self, UART};
 use nrf51;
 use nrf5x;
 
+/// Polls of `tx_ready` before a byte is given up on. A byte takes under 100us
+/// at 115200 baud, this is several milliseconds.
+const TX_TIMEOUT: usize = 100_000;
+
 struct Writer {
     initialized: bool,
+    /// A byte never went out, so the panic goes on to blink the LED instead
+    /// of hanging on the UART
+    stuck: bool,
 }
 
-static mut WRITER: Writer = Writer { initialized: false };
+static mut WRITER: Writer = Writer {
+    initialized: false,
+    stuck: false,
+};
 
 impl Write for Writer {
     fn write_str(&mut self, s: &str) -> ::core::fmt::Result {
@@ -24,10 +34,20 @@ impl Write for Writer {
             });
         }
         for c in s.bytes() {
+            if self.stuck {
+                return Err(::core::fmt::Error);
+            }
             unsafe {
                 uart.send_byte(c);
             }
-            while !uart.tx_ready() {}
+            let mut polls = 0;
+            while !uart.tx_ready() {
+                polls += 1;
+                if polls == TX_TIMEOUT {
+                    self.stuck = true;
+                    break;
+                }
+            }
         }
         Ok(())
     }
@@ -42,5 +62,11 @@ pub unsafe extern "C" fn panic_fmt(args: Arguments, file: &'static str, line: u3
     const LED1_PIN: usize = 21;
     let led = &mut led::LedLow::new(&mut nrf5x::gpio::PORT[LED1_PIN]);
     let writer = &mut WRITER;
-    debug::panic(led, writer, args, file, line)
+    debug::panic_begin();
+    debug::panic_banner(writer, args, file, line);
+    debug::flush(writer);
+    nrf5x::interrupt_statistics::INTERRUPT_STATISTICS.statistics_str(writer);
+    nrf5x::peripheral_snapshot::snapshot_str(writer);
+    debug::panic_process_info(writer);
+    debug::panic_blink_forever(led)
 }
@@ -12,30 +12,43 @@
 //! * Bluetooth Low Energy Advertisements
 //! * Temperature Sensor
 //! * True Random Number Generator
+//! * ADC on the Arduino analog header (A0-A5)
+//! * Nonvolatile storage in internal flash
+//! * Chip reset, optionally into the serial bootloader
+//! * Kernel and application flash and RAM usage, also printed at boot
 //!
-//! ### Pin configuration
-//! * 0 -> LED1 (pin 21)
-//! * 1 -> LED2 (pin 22)
-//! * 2 -> LED3 (pin 23)
-//! * 3 -> LED4 (pin 24)
-//! * 5 -> BUTTON1 (pin 17)
-//! * 6 -> BUTTON2 (pin 18)
-//! * 7 -> BUTTON3 (pin 19)
-//! * 8 -> BUTTON4 (pin 20)
-//! * 9 -> P0.01   (bottom left header)
-//! * 10 -> P0.02   (bottom left header)
-//! * 11 -> P0.03   (bottom left header)
-//! * 12 -> P0.04   (bottom left header)
-//! * 12 -> P0.05   (bottom left header)
-//! * 13 -> P0.06   (bottom left header)
-//! * 14 -> P0.19   (mid right header)
-//! * 15 -> P0.18   (mid right header)
-//! * 16 -> P0.17   (mid right header)
-//! * 17 -> P0.16   (mid right header)
-//! * 18 -> P0.15   (mid right header)
-//! * 19 -> P0.14   (mid right header)
-//! * 20 -> P0.13   (mid right header)
-//! * 21 -> P0.12   (mid right header)
+//! ### GPIO driver pins
+//! * 0 -> P0.01   (bottom left header)
+//! * 1 -> P0.02   (bottom left header)
+//! * 2 -> P0.03   (bottom left header)
+//! * 3 -> P0.04   (bottom left header)
+//! * 4 -> P0.05   (bottom left header)
+//! * 5 -> P0.06   (bottom left header)
+//! * 6 -> P0.16   (mid right header)
+//! * 7 -> P0.15   (mid right header)
+//! * 8 -> P0.14   (mid right header)
+//! * 9 -> P0.13   (mid right header)
+//! * 10 -> P0.12  (mid right header)
+//!
+//! ### LEDs and buttons
+//! The LEDs and buttons have their own drivers, which number them as they are
+//! on the board. Button presses and releases are debounced for 20 ms.
+//! * LED1-LED4 -> pins 21-24
+//! * BUTTON1-BUTTON4 -> pins 17-20
+//!
+//! ### ADC channels
+//! The analog inputs share pins with the bottom left GPIO header.
+//! * 0 -> AIN2 (P0.01, A0)
+//! * 1 -> AIN3 (P0.02, A1)
+//! * 2 -> AIN4 (P0.03, A2)
+//! * 3 -> AIN5 (P0.04, A3)
+//! * 4 -> AIN6 (P0.05, A4)
+//! * 5 -> AIN7 (P0.06, A5)
+//!
+//! ### Nonvolatile storage
+//! The 4 kB of flash just below the applications, 0x1F000 - 0x20000, are
+//! kept out of the kernel image and accessible to applications through the
+//! nonvolatile storage driver.
 //!
 //! ### Authors
 //! * Philip Levis <pal@cs.stanford.edu>
@@ -57,7 +70,6 @@ extern crate nrf5x;
 use capsules::alarm::AlarmDriver;
 use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
 use kernel::hil::uart::UART;
-use kernel::{Chip, SysTick};
 use nrf5x::pinmux::Pinmux;
 use nrf5x::rtc::{Rtc, RTC};
 
@@ -66,6 +78,8 @@ use nrf5x::rtc::{Rtc, RTC};
 pub mod io;
 #[allow(dead_code)]
 mod aes_test;
+#[allow(dead_code)]
+mod ble_ping_test;
 
 // The nRF51 DK LEDs (see back of board)
 const LED1_PIN: usize = 21;
@@ -79,18 +93,22 @@ const BUTTON2_PIN: usize = 18;
 const BUTTON3_PIN: usize = 19;
 const BUTTON4_PIN: usize = 20;
 
+type DebouncedButton =
+    capsules::debounce::DebouncedPin<'static, nrf5x::gpio::GPIOPin, VirtualMuxAlarm<'static, Rtc>>;
+
 // State for loading and holding applications.
 
 // How should the kernel respond when a process faults.
 const FAULT_RESPONSE: kernel::process::FaultResponse = kernel::process::FaultResponse::Panic;
 
-// Number of concurrent processes this platform supports.
-const NUM_PROCS: usize = 1;
+// Number of concurrent processes this platform supports. APP_MEMORY is split
+// evenly between the processes found in flash, at most this many.
+const NUM_PROCS: usize = 2;
 
 #[link_section = ".app_memory"]
 static mut APP_MEMORY: [u8; 8192] = [0; 8192];
 
-static mut PROCESSES: [Option<&'static mut kernel::Process<'static>>; NUM_PROCS] = [None];
+static mut PROCESSES: [Option<&'static mut kernel::Process<'static>>; NUM_PROCS] = [None, None];
 
 /// Supported drivers by the platform
 pub struct Platform {
@@ -99,13 +117,20 @@ pub struct Platform {
         nrf51::radio::Radio,
         VirtualMuxAlarm<'static, Rtc>,
     >,
-    button: &'static capsules::button::Button<'static, nrf5x::gpio::GPIOPin>,
-    console: &'static capsules::console::Console<'static, nrf51::uart::UART>,
+    button: &'static capsules::button::Button<'static, DebouncedButton>,
+    console: &'static capsules::console::Console<
+        'static,
+        capsules::virtual_uart::UartDevice<'static>,
+    >,
     gpio: &'static capsules::gpio::GPIO<'static, nrf5x::gpio::GPIOPin>,
     led: &'static capsules::led::LED<'static, nrf5x::gpio::GPIOPin>,
     temp: &'static capsules::temperature::TemperatureSensor<'static>,
     alarm: &'static AlarmDriver<'static, VirtualMuxAlarm<'static, Rtc>>,
     rng: &'static capsules::rng::SimpleRng<'static, nrf5x::trng::Trng<'static>>,
+    adc: &'static capsules::adc::Adc<'static, nrf51::adc::Adc>,
+    nonvolatile_storage: &'static capsules::nonvolatile_storage_driver::NonvolatileStorage<'static>,
+    reset: &'static capsules::reset::Reset<'static, nrf5x::power::Power>,
+    memory_usage: &'static capsules::memory_usage::MemoryUsageDriver,
 }
 
 impl kernel::Platform for Platform {
@@ -122,6 +147,10 @@ impl kernel::Platform for Platform {
             capsules::rng::DRIVER_NUM => f(Some(self.rng)),
             capsules::ble_advertising_driver::DRIVER_NUM => f(Some(self.ble_radio)),
             capsules::temperature::DRIVER_NUM => f(Some(self.temp)),
+            capsules::adc::DRIVER_NUM => f(Some(self.adc)),
+            capsules::nonvolatile_storage_driver::DRIVER_NUM => f(Some(self.nonvolatile_storage)),
+            capsules::reset::DRIVER_NUM => f(Some(self.reset)),
+            capsules::memory_usage::DRIVER_NUM => f(Some(self.memory_usage)),
             _ => f(None),
         }
     }
@@ -133,6 +162,14 @@ pub unsafe fn reset_handler() {
     // Loads relocations and clears BSS
     nrf51::init();
 
+    // The DK has both crystals. RTC1, which drives the alarms, requests the
+    // low frequency clock, the UART and the radio request the high
+    // frequency clock only while they are in use.
+    nrf51::clock::CLOCK.configure(
+        nrf51::clock::LowClockSource::XTAL,
+        nrf51::clock::HighClockSource::XTAL,
+    );
+
     // LEDs
     let led_pins = static_init!(
         [(&'static nrf5x::gpio::GPIOPin, capsules::led::ActivationMode); 4],
@@ -153,48 +190,13 @@ pub unsafe fn reset_handler() {
                 &nrf5x::gpio::PORT[LED4_PIN],
                 capsules::led::ActivationMode::ActiveLow
             ), // 24
-        ],
-        256 / 8
+        ]
     );
     let led = static_init!(
         capsules::led::LED<'static, nrf5x::gpio::GPIOPin>,
-        capsules::led::LED::new(led_pins),
-        64 / 8
+        capsules::led::LED::new(led_pins)
     );
 
-    let button_pins = static_init!(
-        [(&'static nrf5x::gpio::GPIOPin, capsules::button::GpioMode); 4],
-        [
-            (
-                &nrf5x::gpio::PORT[BUTTON1_PIN],
-                capsules::button::GpioMode::LowWhenPressed
-            ), // 17
-            (
-                &nrf5x::gpio::PORT[BUTTON2_PIN],
-                capsules::button::GpioMode::LowWhenPressed
-            ), // 18
-            (
-                &nrf5x::gpio::PORT[BUTTON3_PIN],
-                capsules::button::GpioMode::LowWhenPressed
-            ), // 19
-            (
-                &nrf5x::gpio::PORT[BUTTON4_PIN],
-                capsules::button::GpioMode::LowWhenPressed
-            ), // 20
-        ],
-        4 * 4
-    );
-    let button = static_init!(
-        capsules::button::Button<'static, nrf5x::gpio::GPIOPin>,
-        capsules::button::Button::new(button_pins, kernel::Grant::create()),
-        96 / 8
-    );
-    for &(btn, _) in button_pins.iter() {
-        use kernel::hil::gpio::PinCtl;
-        btn.set_input_mode(kernel::hil::gpio::InputMode::PullUp);
-        btn.set_client(button);
-    }
-
     let gpio_pins = static_init!(
         [&'static nrf5x::gpio::GPIOPin; 11],
         [
@@ -209,8 +211,7 @@ pub unsafe fn reset_handler() {
             &nrf5x::gpio::PORT[14], //
             &nrf5x::gpio::PORT[13], //
             &nrf5x::gpio::PORT[12], //
-        ],
-        4 * 11
+        ]
     );
 
     // Configure kernel debug gpios as early as possible
@@ -222,8 +223,7 @@ pub unsafe fn reset_handler() {
 
     let gpio = static_init!(
         capsules::gpio::GPIO<'static, nrf5x::gpio::GPIOPin>,
-        capsules::gpio::GPIO::new(gpio_pins),
-        224 / 8
+        capsules::gpio::GPIO::new(gpio_pins, kernel::Grant::create())
     );
     for pin in gpio_pins.iter() {
         pin.set_client(gpio);
@@ -235,49 +235,135 @@ pub unsafe fn reset_handler() {
         Pinmux::new(10), /* cts */
         Pinmux::new(8),  /*. rts */
     );
+    // UART0 is shared through a mux, so other kernel users can be added
+    // next to the console
+    let uart_mux = static_init!(
+        capsules::virtual_uart::MuxUart<'static>,
+        capsules::virtual_uart::MuxUart::new(
+            &nrf51::uart::UART0,
+            &mut capsules::virtual_uart::RX_BUF
+        )
+    );
+    UART::set_client(&nrf51::uart::UART0, uart_mux);
+
+    let console_uart = static_init!(
+        capsules::virtual_uart::UartDevice,
+        capsules::virtual_uart::UartDevice::new(uart_mux)
+    );
+    console_uart.setup();
     let console = static_init!(
-        capsules::console::Console<nrf51::uart::UART>,
+        capsules::console::Console<capsules::virtual_uart::UartDevice>,
         capsules::console::Console::new(
-            &nrf51::uart::UART0,
+            console_uart,
             115200,
             &mut capsules::console::WRITE_BUF,
             &mut capsules::console::READ_BUF,
             kernel::Grant::create()
-        ),
-        224 / 8
+        )
     );
-    UART::set_client(&nrf51::uart::UART0, console);
+    UART::set_client(console_uart, console);
     console.initialize();
 
     // Attach the kernel debug interface to this console
-    let kc = static_init!(
-        capsules::console::App,
-        capsules::console::App::default(),
-        480 / 8
-    );
+    let kc = static_init!(capsules::console::App, capsules::console::App::default());
     kernel::debug::assign_console_driver(Some(console), kc);
 
     let rtc = &nrf5x::rtc::RTC;
-    rtc.start();
-    let mux_alarm = static_init!(MuxAlarm<'static, Rtc>, MuxAlarm::new(&RTC), 16);
+    let mux_alarm = static_init!(MuxAlarm<'static, Rtc>, MuxAlarm::new(&RTC));
     rtc.set_client(mux_alarm);
 
     let virtual_alarm1 = static_init!(
         VirtualMuxAlarm<'static, Rtc>,
-        VirtualMuxAlarm::new(mux_alarm),
-        24
+        VirtualMuxAlarm::new(mux_alarm)
     );
     let alarm = static_init!(
         AlarmDriver<'static, VirtualMuxAlarm<'static, Rtc>>,
-        AlarmDriver::new(virtual_alarm1, kernel::Grant::create()),
-        12
+        AlarmDriver::new(virtual_alarm1, kernel::Grant::create())
     );
     virtual_alarm1.set_client(alarm);
 
+    // Periodic edge count reports of the GPIO driver
+    let gpio_report_alarm = static_init!(
+        VirtualMuxAlarm<'static, Rtc>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    let gpio_report_timer = static_init!(
+        capsules::gpio::EdgeReportAlarm<'static, VirtualMuxAlarm<'static, Rtc>>,
+        capsules::gpio::EdgeReportAlarm::new(gpio_report_alarm)
+    );
+    gpio_report_alarm.set_client(gpio_report_timer);
+    gpio_report_timer.set_client(gpio);
+    gpio.set_report_timer(gpio_report_timer);
+
+    // The buttons have their own driver, with each press and release
+    // debounced before it reaches the apps
+
+    let button1_alarm = static_init!(
+        VirtualMuxAlarm<'static, Rtc>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    let button1 = static_init!(
+        DebouncedButton,
+        capsules::debounce::DebouncedPin::new(&nrf5x::gpio::PORT[BUTTON1_PIN], button1_alarm)
+    );
+    nrf5x::gpio::PORT[BUTTON1_PIN].set_client(button1);
+    button1_alarm.set_client(button1);
+
+    let button2_alarm = static_init!(
+        VirtualMuxAlarm<'static, Rtc>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    let button2 = static_init!(
+        DebouncedButton,
+        capsules::debounce::DebouncedPin::new(&nrf5x::gpio::PORT[BUTTON2_PIN], button2_alarm)
+    );
+    nrf5x::gpio::PORT[BUTTON2_PIN].set_client(button2);
+    button2_alarm.set_client(button2);
+
+    let button3_alarm = static_init!(
+        VirtualMuxAlarm<'static, Rtc>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    let button3 = static_init!(
+        DebouncedButton,
+        capsules::debounce::DebouncedPin::new(&nrf5x::gpio::PORT[BUTTON3_PIN], button3_alarm)
+    );
+    nrf5x::gpio::PORT[BUTTON3_PIN].set_client(button3);
+    button3_alarm.set_client(button3);
+
+    let button4_alarm = static_init!(
+        VirtualMuxAlarm<'static, Rtc>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    let button4 = static_init!(
+        DebouncedButton,
+        capsules::debounce::DebouncedPin::new(&nrf5x::gpio::PORT[BUTTON4_PIN], button4_alarm)
+    );
+    nrf5x::gpio::PORT[BUTTON4_PIN].set_client(button4);
+    button4_alarm.set_client(button4);
+
+    let button_pins = static_init!(
+        [(&'static DebouncedButton, capsules::button::GpioMode); 4],
+        [
+            (button1, capsules::button::GpioMode::LowWhenPressed), // 17
+            (button2, capsules::button::GpioMode::LowWhenPressed), // 18
+            (button3, capsules::button::GpioMode::LowWhenPressed), // 19
+            (button4, capsules::button::GpioMode::LowWhenPressed), // 20
+        ]
+    );
+    let button = static_init!(
+        capsules::button::Button<'static, DebouncedButton>,
+        capsules::button::Button::new(button_pins, kernel::Grant::create())
+    );
+    for &(btn, _) in button_pins.iter() {
+        use kernel::hil::gpio::PinCtl;
+        btn.set_input_mode(kernel::hil::gpio::InputMode::PullUp);
+        btn.set_client(button);
+    }
+
     let ble_radio_virtual_alarm = static_init!(
         VirtualMuxAlarm<'static, Rtc>,
-        VirtualMuxAlarm::new(mux_alarm),
-        192 / 8
+        VirtualMuxAlarm::new(mux_alarm)
     );
 
     let temp = static_init!(
@@ -285,18 +371,39 @@ pub unsafe fn reset_handler() {
         capsules::temperature::TemperatureSensor::new(
             &mut nrf5x::temperature::TEMP,
             kernel::Grant::create()
-        ),
-        96 / 8
+        )
     );
     kernel::hil::sensors::TemperatureDriver::set_client(&nrf5x::temperature::TEMP, temp);
 
     let rng = static_init!(
         capsules::rng::SimpleRng<'static, nrf5x::trng::Trng>,
-        capsules::rng::SimpleRng::new(&mut nrf5x::trng::TRNG, kernel::Grant::create()),
-        96 / 8
+        capsules::rng::SimpleRng::new(&mut nrf5x::trng::TRNG, kernel::Grant::create())
     );
     nrf5x::trng::TRNG.set_client(rng);
 
+    let adc_channels = static_init!(
+        [&'static nrf51::adc::AdcChannel; 6],
+        [
+            &nrf51::adc::CHANNEL_AIN2, // A0
+            &nrf51::adc::CHANNEL_AIN3, // A1
+            &nrf51::adc::CHANNEL_AIN4, // A2
+            &nrf51::adc::CHANNEL_AIN5, // A3
+            &nrf51::adc::CHANNEL_AIN6, // A4
+            &nrf51::adc::CHANNEL_AIN7, // A5
+        ]
+    );
+    let adc = static_init!(
+        capsules::adc::Adc<'static, nrf51::adc::Adc>,
+        capsules::adc::Adc::new(
+            &mut nrf51::adc::ADC,
+            adc_channels,
+            &mut capsules::adc::ADC_BUFFER1,
+            &mut capsules::adc::ADC_BUFFER2,
+            &mut capsules::adc::ADC_BUFFER3
+        )
+    );
+    nrf51::adc::ADC.set_client(adc);
+
     let ble_radio = static_init!(
         capsules::ble_advertising_driver::BLE<
             'static,
@@ -308,8 +415,7 @@ pub unsafe fn reset_handler() {
             kernel::Grant::create(),
             &mut capsules::ble_advertising_driver::BUF,
             ble_radio_virtual_alarm
-        ),
-        256 / 8
+        )
     );
     kernel::hil::ble_advertising::BleAdvertisementDriver::set_receive_client(
         &nrf51::radio::RADIO,
@@ -321,16 +427,42 @@ pub unsafe fn reset_handler() {
     );
     ble_radio_virtual_alarm.set_client(ble_radio);
 
-    // Start all of the clocks. Low power operation will require a better
-    // approach than this.
-    nrf51::clock::CLOCK.low_stop();
-    nrf51::clock::CLOCK.high_stop();
+    pub static mut FLASH_PAGEBUFFER: nrf5x::nvmc::NrfPage = nrf5x::nvmc::NrfPage::new();
+    let nv_to_page = static_init!(
+        capsules::nonvolatile_to_pages::NonvolatileToPages<'static, nrf5x::nvmc::Nvmc>,
+        capsules::nonvolatile_to_pages::NonvolatileToPages::new(
+            &mut nrf5x::nvmc::NVMC,
+            &mut FLASH_PAGEBUFFER
+        )
+    );
+    kernel::hil::flash::HasClient::set_client(&nrf5x::nvmc::NVMC, nv_to_page);
 
-    nrf51::clock::CLOCK.low_set_source(nrf51::clock::LowClockSource::XTAL);
-    nrf51::clock::CLOCK.low_start();
-    nrf51::clock::CLOCK.high_start();
-    while !nrf51::clock::CLOCK.low_started() {}
-    while !nrf51::clock::CLOCK.high_started() {}
+    let nonvolatile_storage = static_init!(
+        capsules::nonvolatile_storage_driver::NonvolatileStorage<'static>,
+        capsules::nonvolatile_storage_driver::NonvolatileStorage::new(
+            nv_to_page,
+            kernel::Grant::create(),
+            0x1F000, // Start address for userspace accessible region
+            0x1000,  // Length of userspace accessible region
+            0,       // Start address of kernel accessible region
+            0,       // Length of kernel accessible region
+            &mut capsules::nonvolatile_storage_driver::BUFFER
+        )
+    );
+    kernel::hil::nonvolatile_storage::NonvolatileStorage::set_client(
+        nv_to_page,
+        nonvolatile_storage,
+    );
+
+    let reset = static_init!(
+        capsules::reset::Reset<'static, nrf5x::power::Power>,
+        capsules::reset::Reset::new(&nrf5x::power::POWER)
+    );
+
+    let memory_usage = static_init!(
+        capsules::memory_usage::MemoryUsageDriver,
+        capsules::memory_usage::MemoryUsageDriver::new()
+    );
 
     let platform = Platform {
         // aes: aes,
@@ -340,6 +472,10 @@ pub unsafe fn reset_handler() {
         gpio: gpio,
         led: led,
         rng: rng,
+        adc: adc,
+        nonvolatile_storage: nonvolatile_storage,
+        reset: reset,
+        memory_usage: memory_usage,
         alarm: alarm,
         temp: temp,
     };
@@ -347,21 +483,26 @@ pub unsafe fn reset_handler() {
     rtc.start();
 
     let mut chip = nrf51::chip::NRF51::new();
-    chip.systick().reset();
-    chip.systick().enable(true);
 
     debug!("Initialization complete. Entering main loop");
     extern "C" {
         /// Beginning of the ROM region containing app images.
         static _sapps: u8;
+        /// End of the ROM region containing app images.
+        static _eapps: u8;
     }
-    kernel::process::load_processes(
+    kernel::process::load_processes_in_region(
         &_sapps as *const u8,
+        &_eapps as *const u8 as usize - &_sapps as *const u8 as usize,
         &mut APP_MEMORY,
         &mut PROCESSES,
         FAULT_RESPONSE,
     );
 
+    let usage = memory_usage_report();
+    usage.print();
+    memory_usage.set(usage);
+
     kernel::main(
         &platform,
         &mut chip,
@@ -369,3 +510,48 @@ pub unsafe fn reset_handler() {
         &kernel::ipc::IPC::new(),
     );
 }
+
+/// Flash and RAM used by the kernel, from the symbols of the linker script,
+/// and by the loaded processes.
+unsafe fn memory_usage_report() -> capsules::memory_usage::MemoryUsage {
+    extern "C" {
+        static _stext: u8;
+        static _etext: u8;
+        static _erom: u8;
+        static _sstack: u8;
+        static _estack: u8;
+        static _srelocate: u8;
+        static _erelocate: u8;
+        static _szero: u8;
+        static _ezero: u8;
+        static _eram: u8;
+        static _sapps: u8;
+        static _eapps: u8;
+    }
+    let addr = |symbol: &u8| symbol as *const u8 as usize;
+
+    let data = addr(&_erelocate) - addr(&_srelocate);
+    let ram_size = addr(&_eram) - addr(&_sstack);
+    let mut app_flash = 0;
+    let mut app_ram = 0;
+    for process in PROCESSES.iter() {
+        process.as_ref().map(|p| {
+            app_flash += p.flash_end() as usize - p.flash_start() as usize;
+            app_ram += p.mem_end() as usize - p.mem_start() as usize;
+        });
+    }
+
+    capsules::memory_usage::MemoryUsage {
+        // The initial values of the data are stored in flash after the text
+        kernel_flash: addr(&_etext) - addr(&_stext) + data,
+        kernel_flash_size: addr(&_erom) - addr(&_stext),
+        stack: addr(&_estack) - addr(&_sstack),
+        data: data,
+        bss: addr(&_ezero) - addr(&_szero),
+        kernel_ram_size: ram_size - APP_MEMORY.len(),
+        app_flash: app_flash,
+        app_flash_size: addr(&_eapps) - addr(&_sapps),
+        app_ram: app_ram,
+        app_ram_size: APP_MEMORY.len(),
+    }
+}
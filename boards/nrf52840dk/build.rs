@@ -0,0 +1,5 @@
+fn main() {
+    println!("cargo:rerun-if-changed=layout.ld");
+    println!("cargo:rerun-if-changed=chip_layout.ld");
+    println!("cargo:rerun-if-changed=../kernel_layout.ld");
+}
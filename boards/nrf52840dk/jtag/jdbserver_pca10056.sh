@@ -0,0 +1 @@
+JLinkGDBServer -device nrf52840_xxaa -speed 1200 -if swd -AutoConnect 1 -port 2331
@@ -0,0 +1,523 @@
+//! Tock kernel for the Nordic Semiconductor nRF52840 development kit (DK), a.k.a. the PCA10056.
+//! It is based on the nRF52840 SoC (Cortex M4 core with a BLE and 802.15.4 transceiver), with
+//! 1 MB of flash and 256 kB of RAM.
+//!
+//! The nRF52840 has two ports, port 0 with pins 0-31 and port 1 with pins 0-15. The kernel
+//! numbers the pins across both ports, P1.xx is pin 32 + xx.
+//!
+//! The ARM CryptoCell is not used and stays powered down, encrypted BLE connections derive their
+//! session keys with the AES ECB block.
+//!
+//! Pin Configuration
+//! -------------------
+//!
+//! ### `GPIOs`
+//! * P0.03 -> A0 (analog header)
+//! * P0.04 -> A1 (analog header)
+//! * P0.28 -> A2 (analog header)
+//! * P0.29 -> A3 (analog header)
+//! * P0.30 -> A4 (analog header)
+//! * P0.31 -> A5 (analog header)
+//! * P0.02 -> AREF (digital header)
+//! * P0.26 -> SDA (digital header)
+//! * P0.27 -> SCL (digital header)
+//! * P1.01 - P1.08 -> D0 - D7 (digital header)
+//! * P1.10 - P1.15 -> D8 - D13 (digital header)
+//!
+//! ### `LEDs`
+//! * P0.13 -> LED1
+//! * P0.14 -> LED2
+//! * P0.15 -> LED3
+//! * P0.16 -> LED4
+//!
+//! ### `Buttons`
+//! * P0.11 -> Button1
+//! * P0.12 -> Button2
+//! * P0.24 -> Button3
+//! * P0.25 -> Button4
+//! * P0.18 -> Reset Button
+//!
+//! ### `UART`
+//! * P0.05 -> RTS
+//! * P0.06 -> TXD
+//! * P0.07 -> CTS
+//! * P0.08 -> RXD
+//!
+//! With the `process_console` feature, the UART is shared by the console and
+//! the process console, as on the nRF52-DK.
+//!
+//! ### `QSPI`
+//! * P0.17, P0.19 - P0.23 -> the external flash, left unused
+//!
+//! ### `LFXO`
+//! * P0.01 -> XL2
+//! * P0.00 -> XL1
+
+#![no_std]
+#![no_main]
+#![feature(lang_items)]
+#![deny(missing_docs)]
+
+extern crate capsules;
+#[allow(unused_imports)]
+#[macro_use(debug, debug_verbose, debug_gpio, static_init)]
+extern crate kernel;
+extern crate nrf52;
+extern crate nrf5x;
+
+use capsules::virtual_alarm::VirtualMuxAlarm;
+use nrf5x::rtc::Rtc;
+
+// The nRF52840 DK LEDs (see back of board)
+const LED1_PIN: usize = 13;
+const LED2_PIN: usize = 14;
+const LED3_PIN: usize = 15;
+const LED4_PIN: usize = 16;
+
+// The nRF52840 DK buttons (see back of board)
+const BUTTON1_PIN: usize = 11;
+const BUTTON2_PIN: usize = 12;
+const BUTTON3_PIN: usize = 24;
+const BUTTON4_PIN: usize = 25;
+const BUTTON_RST_PIN: usize = 18;
+
+// Process slots, in the order the apps are loaded from flash, that may read
+// the BLE connection parameters of other processes. Apps cannot pick their
+// slot, so a diagnostics app is named here by where it is flashed.
+const BLE_DIAGNOSTICS_PROCESSES: &'static [usize] = &[];
+
+/// UART Writer
+#[macro_use]
+pub mod io;
+
+// State for loading and holding applications.
+// How should the kernel respond when a process faults. The MPU confines each
+// process to its own memory, a process that steps out of it is stopped and
+// the kernel and the other processes keep running.
+const FAULT_RESPONSE: kernel::process::FaultResponse = kernel::process::FaultResponse::Stop;
+
+// Number of concurrent processes this platform supports.
+const NUM_PROCS: usize = 8;
+
+// Callbacks each process can have pending, BLE scanning reports in bursts.
+const CALLBACK_QUEUE_DEPTH: usize = 20;
+
+// Time without a pass through the kernel main loop before the chip resets.
+const WATCHDOG_TIMEOUT_MS: usize = 2000;
+
+#[link_section = ".app_memory"]
+static mut APP_MEMORY: [u8; 131072] = [0; 131072];
+
+static mut PROCESSES: [Option<&'static mut kernel::Process<'static>>; NUM_PROCS] =
+    [None, None, None, None, None, None, None, None];
+
+/// Supported drivers by the platform
+pub struct Platform {
+    ble_radio: &'static nrf52::ble::ble_advertising_driver::BLE<
+        'static,
+        nrf52::ble::radio::Radio,
+        VirtualMuxAlarm<'static, Rtc>,
+    >,
+    button: &'static capsules::button::Button<'static, nrf5x::gpio::GPIOPin>,
+    console: &'static capsules::console::Console<
+        'static,
+        capsules::virtual_uart::UartDevice<'static>,
+    >,
+    gpio: &'static capsules::gpio::GPIO<'static, nrf5x::gpio::GPIOPin>,
+    led: &'static capsules::led::LED<'static, nrf5x::gpio::GPIOPin>,
+    rng: &'static capsules::rng::SimpleRng<'static, capsules::virtual_rng::RngDevice<'static>>,
+    temp: &'static capsules::temperature::TemperatureSensor<'static>,
+    ipc: kernel::ipc::IPC,
+    alarm: &'static capsules::alarm::AlarmDriver<
+        'static,
+        capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>,
+    >,
+    watchdog: &'static nrf5x::wdt::Wdt,
+}
+
+impl kernel::Platform for Platform {
+    fn with_driver<F, R>(&self, driver_num: usize, f: F) -> R
+    where
+        F: FnOnce(Option<&kernel::Driver>) -> R,
+    {
+        match driver_num {
+            capsules::console::DRIVER_NUM => f(Some(self.console)),
+            capsules::gpio::DRIVER_NUM => f(Some(self.gpio)),
+            capsules::alarm::DRIVER_NUM => f(Some(self.alarm)),
+            capsules::led::DRIVER_NUM => f(Some(self.led)),
+            capsules::button::DRIVER_NUM => f(Some(self.button)),
+            capsules::rng::DRIVER_NUM => f(Some(self.rng)),
+            capsules::ble_advertising_driver::DRIVER_NUM => f(Some(self.ble_radio)),
+            capsules::temperature::DRIVER_NUM => f(Some(self.temp)),
+            kernel::ipc::DRIVER_NUM => f(Some(&self.ipc)),
+            _ => f(None),
+        }
+    }
+
+    fn watchdog(&self) -> Option<&kernel::hil::watchdog::Watchdog> {
+        Some(self.watchdog)
+    }
+}
+
+/// Entry point in the vector table called on hard reset.
+#[no_mangle]
+pub unsafe fn reset_handler() {
+    // Loads relocations and clears BSS
+    nrf52::init();
+
+    // The low frequency clock runs while RTC1 counts. The HFXO is only
+    // started while a peripheral, i.e. the radio, requests it.
+    nrf52::clock::CLOCK.configure(
+        nrf52::clock::LowClockSource::XTAL,
+        nrf52::clock::HighClockSource::XTAL,
+    );
+
+    // Make non-volatile memory writable and activate the reset button (pin 18)
+    let nvmc = &nrf5x::nvmc::NVMC;
+    let uicr = nrf52::uicr::Uicr::new();
+    nvmc.configure_writeable();
+    while !nvmc.is_ready() {}
+    uicr.set_psel0_reset_pin(BUTTON_RST_PIN);
+    while !nvmc.is_ready() {}
+    uicr.set_psel1_reset_pin(BUTTON_RST_PIN);
+
+    // GPIOs
+    let gpio_pins = static_init!(
+        [&'static nrf5x::gpio::GPIOPin; 23],
+        [
+            &nrf5x::gpio::PORT[3], // Analog header on DK board
+            &nrf5x::gpio::PORT[4],
+            &nrf5x::gpio::PORT[28],
+            &nrf5x::gpio::PORT[29],
+            &nrf5x::gpio::PORT[30],
+            &nrf5x::gpio::PORT[31], // -----
+            &nrf5x::gpio::PORT[2],  // Digital header on DK board
+            &nrf5x::gpio::PORT[26],
+            &nrf5x::gpio::PORT[27],
+            &nrf5x::gpio::PORT[33], // D0 - D7, P1.01 - P1.08
+            &nrf5x::gpio::PORT[34],
+            &nrf5x::gpio::PORT[35],
+            &nrf5x::gpio::PORT[36],
+            &nrf5x::gpio::PORT[37],
+            &nrf5x::gpio::PORT[38],
+            &nrf5x::gpio::PORT[39],
+            &nrf5x::gpio::PORT[40],
+            &nrf5x::gpio::PORT[42], // D8 - D13, P1.10 - P1.15
+            &nrf5x::gpio::PORT[43],
+            &nrf5x::gpio::PORT[44],
+            &nrf5x::gpio::PORT[45],
+            &nrf5x::gpio::PORT[46],
+            &nrf5x::gpio::PORT[47], // -----
+        ]
+    );
+
+    // Configure kernel debug gpios as early as possible
+    kernel::debug::assign_gpios(
+        Some(&nrf5x::gpio::PORT[LED1_PIN]),
+        Some(&nrf5x::gpio::PORT[LED2_PIN]),
+        Some(&nrf5x::gpio::PORT[LED3_PIN]),
+    );
+
+    let gpio = static_init!(
+        capsules::gpio::GPIO<'static, nrf5x::gpio::GPIOPin>,
+        capsules::gpio::GPIO::new(gpio_pins, kernel::Grant::create())
+    );
+    for pin in gpio_pins.iter() {
+        pin.set_client(gpio);
+    }
+
+    // LEDs
+    let led_pins = static_init!(
+        [(&'static nrf5x::gpio::GPIOPin, capsules::led::ActivationMode); 4],
+        [
+            (
+                &nrf5x::gpio::PORT[LED1_PIN],
+                capsules::led::ActivationMode::ActiveLow
+            ),
+            (
+                &nrf5x::gpio::PORT[LED2_PIN],
+                capsules::led::ActivationMode::ActiveLow
+            ),
+            (
+                &nrf5x::gpio::PORT[LED3_PIN],
+                capsules::led::ActivationMode::ActiveLow
+            ),
+            (
+                &nrf5x::gpio::PORT[LED4_PIN],
+                capsules::led::ActivationMode::ActiveLow
+            ),
+        ]
+    );
+
+    let led = static_init!(
+        capsules::led::LED<'static, nrf5x::gpio::GPIOPin>,
+        capsules::led::LED::new(led_pins)
+    );
+
+    let button_pins = static_init!(
+        [(&'static nrf5x::gpio::GPIOPin, capsules::button::GpioMode); 4],
+        [
+            (
+                &nrf5x::gpio::PORT[BUTTON1_PIN],
+                capsules::button::GpioMode::LowWhenPressed
+            ), // 11
+            (
+                &nrf5x::gpio::PORT[BUTTON2_PIN],
+                capsules::button::GpioMode::LowWhenPressed
+            ), // 12
+            (
+                &nrf5x::gpio::PORT[BUTTON3_PIN],
+                capsules::button::GpioMode::LowWhenPressed
+            ), // 24
+            (
+                &nrf5x::gpio::PORT[BUTTON4_PIN],
+                capsules::button::GpioMode::LowWhenPressed
+            ), // 25
+        ]
+    );
+    let button = static_init!(
+        capsules::button::Button<'static, nrf5x::gpio::GPIOPin>,
+        capsules::button::Button::new(button_pins, kernel::Grant::create())
+    );
+    for &(btn, _) in button_pins.iter() {
+        use kernel::hil::gpio::PinCtl;
+        btn.set_input_mode(kernel::hil::gpio::InputMode::PullUp);
+        btn.set_client(button);
+    }
+
+    let rtc = &nrf5x::rtc::RTC;
+    rtc.start();
+    let mux_alarm = static_init!(
+        capsules::virtual_alarm::MuxAlarm<'static, nrf5x::rtc::Rtc>,
+        capsules::virtual_alarm::MuxAlarm::new(&nrf5x::rtc::RTC)
+    );
+    rtc.set_client(mux_alarm);
+
+    let virtual_alarm1 = static_init!(
+        capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>,
+        capsules::virtual_alarm::VirtualMuxAlarm::new(mux_alarm)
+    );
+    let alarm = static_init!(
+        capsules::alarm::AlarmDriver<
+            'static,
+            capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>,
+        >,
+        capsules::alarm::AlarmDriver::new(virtual_alarm1, kernel::Grant::create())
+    );
+    virtual_alarm1.set_client(alarm);
+
+    // Periodic edge count reports of the GPIO driver
+    let gpio_report_alarm = static_init!(
+        capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>,
+        capsules::virtual_alarm::VirtualMuxAlarm::new(mux_alarm)
+    );
+    let gpio_report_timer = static_init!(
+        capsules::gpio::EdgeReportAlarm<
+            'static,
+            capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>,
+        >,
+        capsules::gpio::EdgeReportAlarm::new(gpio_report_alarm)
+    );
+    gpio_report_alarm.set_client(gpio_report_timer);
+    gpio_report_timer.set_client(gpio);
+    gpio.set_report_timer(gpio_report_timer);
+    let ble_radio_virtual_alarm = static_init!(
+        capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>,
+        capsules::virtual_alarm::VirtualMuxAlarm::new(mux_alarm)
+    );
+
+    nrf52::uart::UARTE0.configure(
+        nrf5x::pinmux::Pinmux::new(6), // tx
+        nrf5x::pinmux::Pinmux::new(8), // rx
+        nrf5x::pinmux::Pinmux::new(7), // cts
+        nrf5x::pinmux::Pinmux::new(5),
+    ); // rts
+    // UARTE0 is shared through a mux by the console and, with the
+    // `process_console` feature, the process console
+    let uart_mux = static_init!(
+        capsules::virtual_uart::MuxUart<'static>,
+        capsules::virtual_uart::MuxUart::new(
+            &nrf52::uart::UARTE0,
+            &mut capsules::virtual_uart::RX_BUF
+        )
+    );
+    kernel::hil::uart::UART::set_client(&nrf52::uart::UARTE0, uart_mux);
+
+    let console_uart = static_init!(
+        capsules::virtual_uart::UartDevice,
+        capsules::virtual_uart::UartDevice::new(uart_mux)
+    );
+    console_uart.setup();
+    let console = static_init!(
+        capsules::console::Console<capsules::virtual_uart::UartDevice>,
+        capsules::console::Console::new(
+            console_uart,
+            115200,
+            &mut capsules::console::WRITE_BUF,
+            &mut capsules::console::READ_BUF,
+            kernel::Grant::create()
+        )
+    );
+    kernel::hil::uart::UART::set_client(console_uart, console);
+    // The console test app may change the baud rate and format of UARTE0
+    console.set_reconfigure_apps(&["console"]);
+    console.initialize();
+
+    // The process console shares UARTE0 with the console, and would take
+    // every line typed to an app for a command. Opt in with the
+    // `process_console` feature.
+    #[cfg(feature = "process_console")]
+    let process_console = {
+        let process_console_uart = static_init!(
+            capsules::virtual_uart::UartDevice,
+            capsules::virtual_uart::UartDevice::new(uart_mux)
+        );
+        process_console_uart.setup();
+        let process_console: &'static capsules::process_console::ProcessConsole<_> = static_init!(
+            capsules::process_console::ProcessConsole<capsules::virtual_uart::UartDevice>,
+            capsules::process_console::ProcessConsole::new(
+                process_console_uart,
+                115200,
+                &mut capsules::process_console::WRITE_BUF,
+                &mut capsules::process_console::QUEUE_BUF,
+                &mut capsules::process_console::READ_BUF,
+                &mut capsules::process_console::COMMAND_BUF
+            )
+        );
+        kernel::hil::uart::UART::set_client(process_console_uart, process_console);
+        kernel::process::set_debug_client(process_console);
+        process_console
+    };
+
+    // Attach the kernel debug interface to this console
+    let kc = static_init!(capsules::console::App, capsules::console::App::default());
+    kernel::debug::assign_console_driver(Some(console), kc);
+
+    let ble_radio = static_init!(
+        nrf52::ble::ble_advertising_driver::BLE<
+            'static,
+            nrf52::ble::radio::Radio,
+            VirtualMuxAlarm<'static, Rtc>,
+        >,
+        nrf52::ble::ble_advertising_driver::BLE::new(
+            &mut nrf52::ble::radio::RADIO,
+            kernel::Grant::create(),
+            &mut nrf52::ble::ble_advertising_driver::BUF,
+            ble_radio_virtual_alarm
+        )
+    );
+    nrf52::ble::ble_advertising_hil::BleAdvertisementDriver::set_receive_client(
+        &nrf52::ble::radio::RADIO,
+        ble_radio,
+    );
+    nrf52::ble::ble_advertising_hil::BleAdvertisementDriver::set_transmit_client(
+        &nrf52::ble::radio::RADIO,
+        ble_radio,
+    );
+    nrf52::ble::ble_advertising_hil::BleAdvertisementDriver::set_advertisement_client(
+        &nrf52::ble::radio::RADIO,
+        ble_radio,
+    );
+    ble_radio_virtual_alarm.set_client(ble_radio);
+    // Every app may read the parameters of its own connection, those listed
+    // here the parameters of any app's
+    ble_radio.set_diagnostics_apps(BLE_DIAGNOSTICS_PROCESSES);
+    // The BLE address app may replace the address of the FICR, for all apps
+    ble_radio.set_address_apps(&["ble_address"]);
+
+    // Step the TX power down while the die is above 70 degrees Celsius
+    let tx_power_throttle_virtual_alarm = static_init!(
+        capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>,
+        capsules::virtual_alarm::VirtualMuxAlarm::new(mux_alarm)
+    );
+    let tx_power_throttle = static_init!(
+        nrf52::ble::tx_power_throttle::TxPowerThrottle<'static, VirtualMuxAlarm<'static, Rtc>>,
+        nrf52::ble::tx_power_throttle::TxPowerThrottle::new(
+            &nrf52::ble::radio::RADIO,
+            &nrf5x::temperature::TEMP,
+            tx_power_throttle_virtual_alarm
+        )
+    );
+    tx_power_throttle_virtual_alarm.set_client(tx_power_throttle);
+    kernel::hil::sensors::TemperatureDriver::set_client(
+        &nrf5x::temperature::TEMP,
+        tx_power_throttle,
+    );
+    tx_power_throttle.set_client(ble_radio);
+    tx_power_throttle.set_threshold(7000, 500);
+    tx_power_throttle.start();
+
+    let temp = static_init!(
+        capsules::temperature::TemperatureSensor<'static>,
+        capsules::temperature::TemperatureSensor::new(tx_power_throttle, kernel::Grant::create())
+    );
+    kernel::hil::sensors::TemperatureDriver::set_client(tx_power_throttle, temp);
+
+    // The TRNG is shared by the RNG driver and the BLE driver, which draws
+    // the advertising delays from it
+    let mux_rng = static_init!(
+        capsules::virtual_rng::MuxRng<'static>,
+        capsules::virtual_rng::MuxRng::new(&nrf5x::trng::TRNG)
+    );
+    nrf5x::trng::TRNG.set_client(mux_rng);
+
+    let rng_device = static_init!(
+        capsules::virtual_rng::RngDevice<'static>,
+        capsules::virtual_rng::RngDevice::new(mux_rng)
+    );
+    let rng = static_init!(
+        capsules::rng::SimpleRng<'static, capsules::virtual_rng::RngDevice<'static>>,
+        capsules::rng::SimpleRng::new(rng_device, kernel::Grant::create())
+    );
+    rng_device.set_client(rng);
+
+    let ble_rng = static_init!(
+        capsules::virtual_rng::RngDevice<'static>,
+        capsules::virtual_rng::RngDevice::new(mux_rng)
+    );
+    ble_rng.set_client(ble_radio);
+    ble_radio.set_rng(ble_rng);
+
+    // Session keys of encrypted connections are derived with the AES block
+    kernel::hil::symmetric_encryption::AES128::set_client(&nrf5x::aes::AESECB, ble_radio);
+    ble_radio.set_aes(
+        &nrf5x::aes::AESECB,
+        &mut nrf52::ble::ble_advertising_driver::AES_BUF,
+    );
+
+    let platform = Platform {
+        button: button,
+        ble_radio: ble_radio,
+        console: console,
+        led: led,
+        gpio: gpio,
+        rng: rng,
+        temp: temp,
+        alarm: alarm,
+        ipc: kernel::ipc::IPC::new(),
+        watchdog: &nrf5x::wdt::WDT,
+    };
+
+    let mut chip = nrf52::chip::NRF52::new();
+
+    debug!("Initialization complete. Entering main loop\r");
+    debug!("{}", &nrf52::ficr::FICR_INSTANCE);
+
+    extern "C" {
+        /// Beginning of the ROM region containing app images.
+        static _sapps: u8;
+    }
+    kernel::process::set_callback_queue_depth(CALLBACK_QUEUE_DEPTH);
+    kernel::process::load_processes(
+        &_sapps as *const u8,
+        &mut APP_MEMORY,
+        &mut PROCESSES,
+        FAULT_RESPONSE,
+    );
+    #[cfg(feature = "process_console")]
+    process_console.start();
+
+    kernel::hil::watchdog::Watchdog::start(&nrf5x::wdt::WDT, WATCHDOG_TIMEOUT_MS);
+
+    kernel::main(&platform, &mut chip, &mut PROCESSES, &platform.ipc);
+}
@@ -5,11 +5,13 @@ use kernel::hil::uart::{self, UART};
 use nrf52;
 use nrf5x;
 
-struct Writer {
+/// Blocking writer on UARTE0, for panics and test kernels
+pub struct Writer {
     initialized: bool,
 }
 
-static mut WRITER: Writer = Writer { initialized: false };
+/// The board writer, initializes UARTE0 on first use
+pub static mut WRITER: Writer = Writer { initialized: false };
 
 impl Write for Writer {
     fn write_str(&mut self, s: &str) -> ::core::fmt::Result {
@@ -42,5 +44,11 @@ pub unsafe extern "C" fn panic_fmt(args: Arguments, file: &'static str, line: u3
     const LED1_PIN: usize = 17;
     let led = &mut led::LedLow::new(&mut nrf5x::gpio::PORT[LED1_PIN]);
     let writer = &mut WRITER;
-    debug::panic(led, writer, args, file, line)
+    debug::panic_begin();
+    debug::panic_banner(writer, args, file, line);
+    debug::flush(writer);
+    nrf5x::interrupt_statistics::INTERRUPT_STATISTICS.statistics_str(writer);
+    nrf5x::peripheral_snapshot::snapshot_str(writer);
+    debug::panic_process_info(writer);
+    debug::panic_blink_forever(led)
 }
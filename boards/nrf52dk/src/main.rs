@@ -4,9 +4,8 @@
 //!
 //! nRF52838 has only one port and uses pins 0-31!
 //!
-//! Furthermore, there exist another a preview development kit for nRF52840 but it is not supported
-//! yet because unfortunately the pin configuration differ from nRF52-DK whereas nRF52840 uses two
-//! ports where port 0 has 32 pins and port 1 has 16 pins.
+//! The nRF52840 development kit has a different pin configuration, it has its own board in
+//! `boards/nrf52840dk`.
 //!
 //! Pin Configuration
 //! -------------------
@@ -17,7 +16,6 @@
 //! * P0.02 -> (top left header)
 //! * P0.25 -> (top left header)
 //! * P0.24 -> (top left header)
-//! * P0.23 -> (top left header)
 //! * P0.22 -> (top left header)
 //! * P0.12 -> (top mid header)
 //! * P0.11 -> (top mid header)
@@ -34,6 +32,16 @@
 //! * P0.19 -> LED3
 //! * P0.20 -> LED4
 //!
+//! ### `Input capture`
+//! * P0.23 -> (top left header) the signal whose period and pulse width are
+//!   measured. It uses TIMER2, like continuous sampling and monitoring by the
+//!   ADC, so only one of them runs at a time.
+//!
+//! ### `PWM`
+//! * P0.20 -> channel 0, to dim LED4 (active low)
+//! * P0.22 -> channel 1, for a servo (also a GPIO, PWM takes over while the
+//!   channel runs)
+//!
 //! ### `Buttons`
 //! * P0.13 -> Button1
 //! * P0.14 -> Button2
@@ -47,6 +55,12 @@
 //! * P0.07 -> CTS
 //! * P0.08 -> RXD
 //!
+//! With the `process_console` feature, the UART is shared by the console and
+//! the process console, which lists, stops and starts processes and shows
+//! their memory and faults. Type `help` on the serial port of the DK for its
+//! commands. Every line typed then also reaches the process console, so apps
+//! reading from the console get it echoed and answered as a command.
+//!
 //! ### `NFC`
 //! * P0.09 -> NFC1
 //! * P0.10 -> NFC2
@@ -88,6 +102,18 @@ const BUTTON3_PIN: usize = 15;
 const BUTTON4_PIN: usize = 16;
 const BUTTON_RST_PIN: usize = 21;
 
+// The pin of the input capture driver, on the top left header
+const INPUT_CAPTURE_PIN: usize = 23;
+
+// The nRF52 DK has a 32 MHz crystal for the HFXO, which the radio needs for
+// BLE. Boards without one run the high frequency clock from the HFINT.
+const HF_CRYSTAL: bool = true;
+
+// Process slots, in the order the apps are loaded from flash, that may read
+// the BLE connection parameters of other processes. Apps cannot pick their
+// slot, so a diagnostics app is named here by where it is flashed.
+const BLE_DIAGNOSTICS_PROCESSES: &'static [usize] = &[];
+
 /// UART Writer
 #[macro_use]
 pub mod io;
@@ -99,12 +125,20 @@ pub mod io;
 mod tests;
 
 // State for loading and holding applications.
-// How should the kernel respond when a process faults.
-const FAULT_RESPONSE: kernel::process::FaultResponse = kernel::process::FaultResponse::Panic;
+// How should the kernel respond when a process faults. The MPU confines each
+// process to its own memory, a process that steps out of it is stopped and
+// the kernel and the other processes keep running.
+const FAULT_RESPONSE: kernel::process::FaultResponse = kernel::process::FaultResponse::Stop;
 
 // Number of concurrent processes this platform supports.
 const NUM_PROCS: usize = 4;
 
+// Callbacks each process can have pending, BLE scanning reports in bursts.
+const CALLBACK_QUEUE_DEPTH: usize = 20;
+
+// Time without a pass through the kernel main loop before the chip resets.
+const WATCHDOG_TIMEOUT_MS: usize = 2000;
+
 #[link_section = ".app_memory"]
 static mut APP_MEMORY: [u8; 32768] = [0; 32768];
 
@@ -119,16 +153,26 @@ pub struct Platform {
         VirtualMuxAlarm<'static, Rtc>,
     >,
     button: &'static capsules::button::Button<'static, nrf5x::gpio::GPIOPin>,
-    console: &'static capsules::console::Console<'static, nrf52::uart::Uarte>,
+    console: &'static capsules::console::Console<
+        'static,
+        capsules::virtual_uart::UartDevice<'static>,
+    >,
     gpio: &'static capsules::gpio::GPIO<'static, nrf5x::gpio::GPIOPin>,
     led: &'static capsules::led::LED<'static, nrf5x::gpio::GPIOPin>,
-    rng: &'static capsules::rng::SimpleRng<'static, nrf5x::trng::Trng<'static>>,
+    rng: &'static capsules::rng::SimpleRng<'static, capsules::virtual_rng::RngDevice<'static>>,
     temp: &'static capsules::temperature::TemperatureSensor<'static>,
+    adc: &'static capsules::adc::Adc<'static, nrf52::adc::Adc>,
+    pwm: &'static capsules::pwm::PwmDriver<'static, nrf52::pwm::Pwm>,
+    input_capture: &'static capsules::input_capture::InputCapture<
+        'static,
+        nrf5x::input_capture::InputCapture,
+    >,
     ipc: kernel::ipc::IPC,
     alarm: &'static capsules::alarm::AlarmDriver<
         'static,
         capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>,
     >,
+    watchdog: &'static nrf5x::wdt::Wdt,
 }
 
 impl kernel::Platform for Platform {
@@ -145,10 +189,17 @@ impl kernel::Platform for Platform {
             capsules::rng::DRIVER_NUM => f(Some(self.rng)),
             capsules::ble_advertising_driver::DRIVER_NUM => f(Some(self.ble_radio)),
             capsules::temperature::DRIVER_NUM => f(Some(self.temp)),
+            capsules::adc::DRIVER_NUM => f(Some(self.adc)),
+            capsules::pwm::DRIVER_NUM => f(Some(self.pwm)),
+            capsules::input_capture::DRIVER_NUM => f(Some(self.input_capture)),
             kernel::ipc::DRIVER_NUM => f(Some(&self.ipc)),
             _ => f(None),
         }
     }
+
+    fn watchdog(&self) -> Option<&kernel::hil::watchdog::Watchdog> {
+        Some(self.watchdog)
+    }
 }
 
 /// Entry point in the vector table called on hard reset.
@@ -157,8 +208,19 @@ pub unsafe fn reset_handler() {
     // Loads relocations and clears BSS
     nrf52::init();
 
+    // The low frequency clock runs while RTC1 counts. The HFXO is only
+    // started while a peripheral, i.e. the radio, requests it.
+    nrf52::clock::CLOCK.configure(
+        nrf52::clock::LowClockSource::XTAL,
+        if HF_CRYSTAL {
+            nrf52::clock::HighClockSource::XTAL
+        } else {
+            nrf52::clock::HighClockSource::RC
+        },
+    );
+
     // Make non-volatile memory writable and activate the reset button (pin 21)
-    let nvmc = nrf52::nvmc::Nvmc::new();
+    let nvmc = &nrf5x::nvmc::NVMC;
     let uicr = nrf52::uicr::Uicr::new();
     nvmc.configure_writeable();
     while !nvmc.is_ready() {}
@@ -168,7 +230,7 @@ pub unsafe fn reset_handler() {
 
     // GPIOs
     let gpio_pins = static_init!(
-        [&'static nrf5x::gpio::GPIOPin; 15],
+        [&'static nrf5x::gpio::GPIOPin; 14],
         [
             &nrf5x::gpio::PORT[3], // Bottom right header on DK board
             &nrf5x::gpio::PORT[4],
@@ -183,7 +245,6 @@ pub unsafe fn reset_handler() {
             &nrf5x::gpio::PORT[2],
             &nrf5x::gpio::PORT[25],
             &nrf5x::gpio::PORT[24],
-            &nrf5x::gpio::PORT[23],
             &nrf5x::gpio::PORT[22], // -----
         ]
     );
@@ -197,7 +258,7 @@ pub unsafe fn reset_handler() {
 
     let gpio = static_init!(
         capsules::gpio::GPIO<'static, nrf5x::gpio::GPIOPin>,
-        capsules::gpio::GPIO::new(gpio_pins)
+        capsules::gpio::GPIO::new(gpio_pins, kernel::Grant::create())
     );
     for pin in gpio_pins.iter() {
         pin.set_client(gpio);
@@ -282,6 +343,22 @@ pub unsafe fn reset_handler() {
         capsules::alarm::AlarmDriver::new(virtual_alarm1, kernel::Grant::create())
     );
     virtual_alarm1.set_client(alarm);
+
+    // Periodic edge count reports of the GPIO driver
+    let gpio_report_alarm = static_init!(
+        capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>,
+        capsules::virtual_alarm::VirtualMuxAlarm::new(mux_alarm)
+    );
+    let gpio_report_timer = static_init!(
+        capsules::gpio::EdgeReportAlarm<
+            'static,
+            capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>,
+        >,
+        capsules::gpio::EdgeReportAlarm::new(gpio_report_alarm)
+    );
+    gpio_report_alarm.set_client(gpio_report_timer);
+    gpio_report_timer.set_client(gpio);
+    gpio.set_report_timer(gpio_report_timer);
     let ble_radio_virtual_alarm = static_init!(
         capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>,
         capsules::virtual_alarm::VirtualMuxAlarm::new(mux_alarm)
@@ -293,19 +370,63 @@ pub unsafe fn reset_handler() {
         nrf5x::pinmux::Pinmux::new(7), // cts
         nrf5x::pinmux::Pinmux::new(5),
     ); // rts
+    // UARTE0 is shared through a mux by the console and, with the
+    // `process_console` feature, the process console
+    let uart_mux = static_init!(
+        capsules::virtual_uart::MuxUart<'static>,
+        capsules::virtual_uart::MuxUart::new(
+            &nrf52::uart::UARTE0,
+            &mut capsules::virtual_uart::RX_BUF
+        )
+    );
+    kernel::hil::uart::UART::set_client(&nrf52::uart::UARTE0, uart_mux);
+
+    let console_uart = static_init!(
+        capsules::virtual_uart::UartDevice,
+        capsules::virtual_uart::UartDevice::new(uart_mux)
+    );
+    console_uart.setup();
     let console = static_init!(
-        capsules::console::Console<nrf52::uart::Uarte>,
+        capsules::console::Console<capsules::virtual_uart::UartDevice>,
         capsules::console::Console::new(
-            &nrf52::uart::UARTE0,
+            console_uart,
             115200,
             &mut capsules::console::WRITE_BUF,
             &mut capsules::console::READ_BUF,
             kernel::Grant::create()
         )
     );
-    kernel::hil::uart::UART::set_client(&nrf52::uart::UARTE0, console);
+    kernel::hil::uart::UART::set_client(console_uart, console);
+    // The console test app may change the baud rate and format of UARTE0
+    console.set_reconfigure_apps(&["console"]);
     console.initialize();
 
+    // The process console shares UARTE0 with the console, and would take
+    // every line typed to an app for a command. Opt in with the
+    // `process_console` feature.
+    #[cfg(feature = "process_console")]
+    let process_console = {
+        let process_console_uart = static_init!(
+            capsules::virtual_uart::UartDevice,
+            capsules::virtual_uart::UartDevice::new(uart_mux)
+        );
+        process_console_uart.setup();
+        let process_console: &'static capsules::process_console::ProcessConsole<_> = static_init!(
+            capsules::process_console::ProcessConsole<capsules::virtual_uart::UartDevice>,
+            capsules::process_console::ProcessConsole::new(
+                process_console_uart,
+                115200,
+                &mut capsules::process_console::WRITE_BUF,
+                &mut capsules::process_console::QUEUE_BUF,
+                &mut capsules::process_console::READ_BUF,
+                &mut capsules::process_console::COMMAND_BUF
+            )
+        );
+        kernel::hil::uart::UART::set_client(process_console_uart, process_console);
+        kernel::process::set_debug_client(process_console);
+        process_console
+    };
+
     // Attach the kernel debug interface to this console
     let kc = static_init!(capsules::console::App, capsules::console::App::default());
     kernel::debug::assign_console_driver(Some(console), kc);
@@ -336,33 +457,116 @@ pub unsafe fn reset_handler() {
         ble_radio,
     );
     ble_radio_virtual_alarm.set_client(ble_radio);
+    // Every app may read the parameters of its own connection, those listed
+    // here the parameters of any app's
+    ble_radio.set_diagnostics_apps(BLE_DIAGNOSTICS_PROCESSES);
+    // The BLE address app may replace the address of the FICR, for all apps
+    ble_radio.set_address_apps(&["ble_address"]);
+
+    // Step the TX power down while the die is above 70 degrees Celsius
+    let tx_power_throttle_virtual_alarm = static_init!(
+        capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>,
+        capsules::virtual_alarm::VirtualMuxAlarm::new(mux_alarm)
+    );
+    let tx_power_throttle = static_init!(
+        nrf52::ble::tx_power_throttle::TxPowerThrottle<'static, VirtualMuxAlarm<'static, Rtc>>,
+        nrf52::ble::tx_power_throttle::TxPowerThrottle::new(
+            &nrf52::ble::radio::RADIO,
+            &nrf5x::temperature::TEMP,
+            tx_power_throttle_virtual_alarm
+        )
+    );
+    tx_power_throttle_virtual_alarm.set_client(tx_power_throttle);
+    kernel::hil::sensors::TemperatureDriver::set_client(
+        &nrf5x::temperature::TEMP,
+        tx_power_throttle,
+    );
+    tx_power_throttle.set_client(ble_radio);
+    tx_power_throttle.set_threshold(7000, 500);
+    tx_power_throttle.start();
 
     let temp = static_init!(
         capsules::temperature::TemperatureSensor<'static>,
-        capsules::temperature::TemperatureSensor::new(
-            &mut nrf5x::temperature::TEMP,
-            kernel::Grant::create()
-        )
+        capsules::temperature::TemperatureSensor::new(tx_power_throttle, kernel::Grant::create())
     );
-    kernel::hil::sensors::TemperatureDriver::set_client(&nrf5x::temperature::TEMP, temp);
+    kernel::hil::sensors::TemperatureDriver::set_client(tx_power_throttle, temp);
 
+    // The TRNG is shared by the RNG driver and the BLE driver, which draws
+    // the advertising delays from it
+    let mux_rng = static_init!(
+        capsules::virtual_rng::MuxRng<'static>,
+        capsules::virtual_rng::MuxRng::new(&nrf5x::trng::TRNG)
+    );
+    nrf5x::trng::TRNG.set_client(mux_rng);
+
+    let rng_device = static_init!(
+        capsules::virtual_rng::RngDevice<'static>,
+        capsules::virtual_rng::RngDevice::new(mux_rng)
+    );
     let rng = static_init!(
-        capsules::rng::SimpleRng<'static, nrf5x::trng::Trng>,
-        capsules::rng::SimpleRng::new(&mut nrf5x::trng::TRNG, kernel::Grant::create())
+        capsules::rng::SimpleRng<'static, capsules::virtual_rng::RngDevice<'static>>,
+        capsules::rng::SimpleRng::new(rng_device, kernel::Grant::create())
     );
-    nrf5x::trng::TRNG.set_client(rng);
+    rng_device.set_client(rng);
 
-    // Start all of the clocks. Low power operation will require a better
-    // approach than this.
-    nrf52::clock::CLOCK.low_stop();
-    nrf52::clock::CLOCK.high_stop();
+    let ble_rng = static_init!(
+        capsules::virtual_rng::RngDevice<'static>,
+        capsules::virtual_rng::RngDevice::new(mux_rng)
+    );
+    ble_rng.set_client(ble_radio);
+    ble_radio.set_rng(ble_rng);
+
+    // Session keys of encrypted connections are derived with the AES block
+    kernel::hil::symmetric_encryption::AES128::set_client(&nrf5x::aes::AESECB, ble_radio);
+    ble_radio.set_aes(
+        &nrf5x::aes::AESECB,
+        &mut nrf52::ble::ble_advertising_driver::AES_BUF,
+    );
+
+    let adc_channels = static_init!(
+        [&'static nrf52::adc::AdcChannel; 6],
+        [
+            &nrf52::adc::CHANNEL_AIN1, // A0
+            &nrf52::adc::CHANNEL_AIN2, // A1
+            &nrf52::adc::CHANNEL_AIN4, // A2
+            &nrf52::adc::CHANNEL_AIN5, // A3
+            &nrf52::adc::CHANNEL_AIN6, // A4
+            &nrf52::adc::CHANNEL_AIN7, // A5
+        ]
+    );
+    let adc = static_init!(
+        capsules::adc::Adc<'static, nrf52::adc::Adc>,
+        capsules::adc::Adc::new(
+            &mut nrf52::adc::ADC,
+            adc_channels,
+            &mut capsules::adc::ADC_BUFFER1,
+            &mut capsules::adc::ADC_BUFFER2,
+            &mut capsules::adc::ADC_BUFFER3
+        )
+    );
+    nrf52::adc::ADC.set_client(adc);
 
-    nrf52::clock::CLOCK.low_set_source(nrf52::clock::LowClockSource::XTAL);
-    nrf52::clock::CLOCK.low_start();
-    nrf52::clock::CLOCK.high_set_source(nrf52::clock::HighClockSource::XTAL);
-    nrf52::clock::CLOCK.high_start();
-    while !nrf52::clock::CLOCK.low_started() {}
-    while !nrf52::clock::CLOCK.high_started() {}
+    nrf52::pwm::PWM0.configure(0, nrf5x::pinmux::Pinmux::new(LED4_PIN as u32));
+    nrf52::pwm::PWM0.configure(1, nrf5x::pinmux::Pinmux::new(22));
+    let pwm = static_init!(
+        capsules::pwm::PwmDriver<'static, nrf52::pwm::Pwm>,
+        capsules::pwm::PwmDriver::new(&nrf52::pwm::PWM0)
+    );
+
+    let input_capture_pin = &nrf5x::gpio::PORT[INPUT_CAPTURE_PIN];
+    nrf5x::input_capture::INPUT_CAPTURE.set_pin(input_capture_pin);
+    input_capture_pin.set_client(&nrf5x::input_capture::INPUT_CAPTURE);
+    let input_capture = static_init!(
+        capsules::input_capture::InputCapture<'static, nrf5x::input_capture::InputCapture>,
+        capsules::input_capture::InputCapture::new(
+            &nrf5x::input_capture::INPUT_CAPTURE,
+            kernel::Grant::create()
+        )
+    );
+    kernel::hil::input_capture::InputCapture::set_client(
+        &nrf5x::input_capture::INPUT_CAPTURE,
+        input_capture,
+    );
 
     let platform = Platform {
         button: button,
@@ -372,8 +576,12 @@ pub unsafe fn reset_handler() {
         gpio: gpio,
         rng: rng,
         temp: temp,
+        adc: adc,
+        pwm: pwm,
+        input_capture: input_capture,
         alarm: alarm,
         ipc: kernel::ipc::IPC::new(),
+        watchdog: &nrf5x::wdt::WDT,
     };
 
     let mut chip = nrf52::chip::NRF52::new();
@@ -385,12 +593,17 @@ pub unsafe fn reset_handler() {
         /// Beginning of the ROM region containing app images.
         static _sapps: u8;
     }
+    kernel::process::set_callback_queue_depth(CALLBACK_QUEUE_DEPTH);
     kernel::process::load_processes(
         &_sapps as *const u8,
         &mut APP_MEMORY,
         &mut PROCESSES,
         FAULT_RESPONSE,
     );
+    #[cfg(feature = "process_console")]
+    process_console.start();
+
+    kernel::hil::watchdog::Watchdog::start(&nrf5x::wdt::WDT, WATCHDOG_TIMEOUT_MS);
 
     kernel::main(&platform, &mut chip, &mut PROCESSES, &platform.ipc);
 }
@@ -0,0 +1,62 @@
+use capsules::test::alarm_jitter::{ReferenceClock, TestAlarmJitter};
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use kernel::hil::time::Freq1MHz;
+use nrf5x::rtc::Rtc;
+use nrf5x::timer::{BitmodeValue, Timer, TIMER2};
+
+/// TIMER2 counting at 1MHz, read with a capture into a CC channel allocated
+/// for the test
+pub struct Timer2Reference(&'static Timer, u8);
+
+impl ReferenceClock for Timer2Reference {
+    type Frequency = Freq1MHz;
+
+    fn now(&self) -> u32 {
+        self.0.capture(self.1)
+    }
+}
+
+/// To run the tests add the following `main.rs::reset_handler` somewhere after that the RTC
+/// and `mux_alarm` have been set up:
+///
+/// ```rustc
+///     tests::alarm_jitter::run(mux_alarm);
+/// ```
+///
+/// The three tests share the RTC through `mux_alarm` with each other and the other alarm
+/// users of the board, and print their results with `debug!` when done, after about a
+/// minute.
+pub unsafe fn run(mux_alarm: &'static MuxAlarm<'static, Rtc>) {
+    // 16MHz divided by 2^4
+    TIMER2.set_bitmode(BitmodeValue::Size32Bits);
+    TIMER2.set_prescaler(4);
+    TIMER2.clear();
+    TIMER2.start();
+    let cc = TIMER2
+        .allocate_cc()
+        .unwrap_or_else(|_| panic!("No free TIMER2 CC channel for the reference clock"));
+    let reference = static_init!(Timer2Reference, Timer2Reference(&TIMER2, cc));
+
+    static_init_test(mux_alarm, reference, 5000, 1, 10).run();
+    static_init_test(mux_alarm, reference, 2000, 5, 50).run();
+    static_init_test(mux_alarm, reference, 500, 20, 200).run();
+}
+
+unsafe fn static_init_test(
+    mux_alarm: &'static MuxAlarm<'static, Rtc>,
+    reference: &'static Timer2Reference,
+    count: u32,
+    min_ms: u32,
+    max_ms: u32,
+) -> &'static TestAlarmJitter<'static, VirtualMuxAlarm<'static, Rtc>, Timer2Reference> {
+    let virtual_alarm = static_init!(
+        VirtualMuxAlarm<'static, Rtc>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    let test = static_init!(
+        TestAlarmJitter<'static, VirtualMuxAlarm<'static, Rtc>, Timer2Reference>,
+        TestAlarmJitter::new(virtual_alarm, reference, count, min_ms, max_ms)
+    );
+    virtual_alarm.set_client(test);
+    test
+}
@@ -0,0 +1,26 @@
+use core::fmt::Write;
+use io;
+use nrf52::ble::ll_replay;
+
+/// Runs the scripted replays of the nrf52 BLE link layer's connection event scheduling.
+///
+/// Build the kernel with the `ll_replay` feature:
+///
+/// ```text
+/// RUSTFLAGS="-C link-arg=-nostartfiles -C link-arg=-Tlayout.ld" \
+///     cargo build --release --target=thumbv7em-none-eabi --features ll_replay
+/// ```
+///
+/// and call it at the top of `main.rs::reset_handler`, right after `nrf52::init()`:
+///
+/// ```rustc
+///     tests::ll_replay::run();
+/// ```
+///
+/// It never returns, so the rest of the board is never set up. A failing scenario panics
+/// with the mismatch; otherwise "link layer replays passed" is printed on the UART.
+pub unsafe fn run() -> ! {
+    ll_replay::check_all();
+    let _ = write!(io::WRITER, "link layer replays passed\r\n");
+    loop {}
+}
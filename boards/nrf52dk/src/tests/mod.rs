@@ -1,2 +1,7 @@
 pub mod aes;
+pub mod alarm_jitter;
+#[cfg(feature = "ll_replay")]
+pub mod ll_replay;
+#[cfg(feature = "radio_mock")]
+pub mod radio_mock;
 pub mod uart;
@@ -0,0 +1,28 @@
+use core::fmt::Write;
+use io;
+use nrf52::ble::radio_mock;
+
+/// Runs the scripted scenarios of the nrf52 radio state machine on mock registers.
+///
+/// The scenarios share the packet buffers of the real radio, so they need a kernel of their
+/// own. Build it with the `radio_mock` feature:
+///
+/// ```text
+/// RUSTFLAGS="-C link-arg=-nostartfiles -C link-arg=-Tlayout.ld" \
+///     cargo build --release --target=thumbv7em-none-eabi --features radio_mock
+/// ```
+///
+/// and call it at the top of `main.rs::reset_handler`, right after `nrf52::init()`:
+///
+/// ```rustc
+///     tests::radio_mock::run();
+/// ```
+///
+/// It never returns, so the rest of the board, the BLE driver included, is never set up. A
+/// failing scenario panics with the mismatch; otherwise "radio scenarios passed" is printed
+/// on the UART.
+pub unsafe fn run() -> ! {
+    radio_mock::check_all();
+    let _ = write!(io::WRITER, "radio scenarios passed\r\n");
+    loop {}
+}
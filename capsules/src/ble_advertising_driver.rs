@@ -10,19 +10,35 @@
 //! Data payloads are limited to 31 bytes since the maximum advertising channel
 //! protocol data unit (PDU) is 37 bytes and includes a 6-byte header.
 //!
+//! The payload is a sequence of AD structures, each a length byte, an AD
+//! type and a value. The driver keeps its own copy of the payload, checked to
+//! be well formed before it is accepted: it is either copied from a buffer of
+//! AD structures with allow 0, or composed one AD structure at a time with
+//! command 4. The values of the AD types the driver knows are checked as
+//! well: one byte of «Flags» or «Tx Power Level», two bytes of
+//! «Appearance», service UUID lists of whole UUIDs, local names in UTF-8 and
+//! «Manufacturer Specific Data» starting with a company identifier.
+//!
 //! ### Allow system call
 //!
 //! The allow systems calls are used for buffers from allocated by userland
 //!
-//! There are two different buffers:
-//! * 0: Advertising data
+//! There are three different buffers:
+//! * 0: Advertising data, AD structures copied as the payload of the
+//!      advertisements. Zeroes after the last AD structure are padding.
+//!      Returns EINVAL if the buffer does not hold well formed AD structures
+//!      and ESIZE if they take more than 31 bytes, the payload is left
+//!      unchanged then. Writing to the buffer afterwards has no effect until
+//!      it is allowed again.
 //! * 1: Passive scanning buffer
+//! * 2: Value of the AD structure added by command 4
 //!
 //! The possible return codes from the 'allow' system call indicate the following:
 //!
 //! * SUCCESS: The buffer has successfully been filled
 //! * ENOMEM: No sufficient memory available
 //! * EINVAL: Invalid address of the buffer or other error
+//! * ESIZE: The advertising data does not fit in an advertisement
 //! * EBUSY: The driver is currently busy with other tasks
 //! * ENOSUPPORT: The operation is not supported
 //! * ERROR: Operation `map` on Option failed
@@ -48,12 +64,22 @@
 //!
 //! * 0: start advertisement
 //! * 1: stop advertisement or scanning
+//! * 2: configure tx power
+//! * 3: clear the advertising payload
+//! * 4: add an AD structure of type `data` to the advertising payload, its
+//!      value being the first `data2` bytes of the buffer of allow 2. It
+//!      replaces the AD structure of the same type, and a local name the
+//!      other local name, if the payload has one. Returns EINVAL if the
+//!      value is malformed for the type or the buffer is too short, and ESIZE
+//!      if the payload would take more than 31 bytes.
 //! * 5: start scanning
 //!
 //! The possible return codes from the `command` system call indicate the following:
 //!
 //! * SUCCESS:      The command was successful
 //! * EBUSY:        The driver is currently busy with other tasks
+//! * EINVAL:       An argument is invalid
+//! * ESIZE:        The advertising payload would not fit in an advertisement
 //! * ENOSUPPORT:   The operation is not supported
 //!
 //! Usage
@@ -97,6 +123,7 @@
 
 use core::cell::Cell;
 use core::cmp;
+use core::str;
 use kernel;
 use kernel::hil::ble_advertising;
 use kernel::hil::ble_advertising::RadioChannel;
@@ -112,6 +139,7 @@ pub static mut BUF: [u8; PACKET_LENGTH] = [0; PACKET_LENGTH];
 const PACKET_ADDR_LEN: usize = 6;
 const PACKET_LENGTH: usize = 39;
 const ADV_HEADER_TXADD_OFFSET: usize = 6;
+const ADV_DATA_MAX_LEN: usize = PACKET_LENGTH - 2 - PACKET_ADDR_LEN;
 
 #[derive(PartialEq, Debug)]
 enum BLEState {
@@ -159,13 +187,106 @@ const SCAN_RESP: AdvPduType = 0b0100;
 const CONNECT_IND: AdvPduType = 0b0101;
 const ADV_SCAN_IND: AdvPduType = 0b0110;
 
+// Bluetooth Core Specification Supplement, Part A, section 1
+const AD_FLAGS: u8 = 0x01;
+const AD_INCOMPLETE_UUID16: u8 = 0x02;
+const AD_COMPLETE_UUID16: u8 = 0x03;
+const AD_INCOMPLETE_UUID32: u8 = 0x04;
+const AD_COMPLETE_UUID32: u8 = 0x05;
+const AD_INCOMPLETE_UUID128: u8 = 0x06;
+const AD_COMPLETE_UUID128: u8 = 0x07;
+const AD_SHORTENED_LOCAL_NAME: u8 = 0x08;
+const AD_COMPLETE_LOCAL_NAME: u8 = 0x09;
+const AD_TX_POWER_LEVEL: u8 = 0x0A;
+const AD_SOLICITATION_UUID16: u8 = 0x14;
+const AD_SOLICITATION_UUID128: u8 = 0x15;
+const AD_APPEARANCE: u8 = 0x19;
+const AD_SOLICITATION_UUID32: u8 = 0x1F;
+const AD_MANUFACTURER_DATA: u8 = 0xFF;
+
+// Check the value of an AD structure of type `ad_type` against what the type
+// requires. Types not listed may hold any value.
+fn validate_ad_value(ad_type: u8, value: &[u8]) -> ReturnCode {
+    let valid = match ad_type {
+        AD_FLAGS | AD_TX_POWER_LEVEL => value.len() == 1,
+        AD_APPEARANCE => value.len() == 2,
+        AD_INCOMPLETE_UUID16 | AD_COMPLETE_UUID16 | AD_SOLICITATION_UUID16 => {
+            value.len() % 2 == 0
+        }
+        AD_INCOMPLETE_UUID32 | AD_COMPLETE_UUID32 | AD_SOLICITATION_UUID32 => {
+            value.len() % 4 == 0
+        }
+        AD_INCOMPLETE_UUID128 | AD_COMPLETE_UUID128 | AD_SOLICITATION_UUID128 => {
+            value.len() % 16 == 0
+        }
+        AD_SHORTENED_LOCAL_NAME | AD_COMPLETE_LOCAL_NAME => {
+            !value.is_empty() && str::from_utf8(value).is_ok()
+        }
+        // The company identifier comes first
+        AD_MANUFACTURER_DATA => value.len() >= 2,
+        _ => true,
+    };
+    if valid {
+        ReturnCode::SUCCESS
+    } else {
+        ReturnCode::EINVAL
+    }
+}
+
+// Check that `data` is a sequence of well formed AD structures fitting in an
+// advertisement. An AD structure of length 0 ends the data, what follows is
+// padding.
+//
+// Returns the length of the AD structures, EINVAL if they are malformed or
+// ESIZE if they do not fit.
+fn validate_ad_structures(data: &[u8]) -> Result<usize, ReturnCode> {
+    let mut idx = 0;
+    while idx < data.len() && data[idx] != 0 {
+        let end = idx + 1 + data[idx] as usize;
+        if end > data.len() {
+            return Err(ReturnCode::EINVAL);
+        }
+        let result = validate_ad_value(data[idx + 1], &data[idx + 2..end]);
+        if result != ReturnCode::SUCCESS {
+            return Err(result);
+        }
+        idx = end;
+    }
+    if idx > ADV_DATA_MAX_LEN {
+        Err(ReturnCode::ESIZE)
+    } else {
+        Ok(idx)
+    }
+}
+
+// Remove the AD structures of the `types` from the well formed AD structures
+// in the first `len` bytes of `data`, moving the ones after them forward.
+// Returns the length of what is left.
+fn remove_ad_structures(data: &mut [u8], len: usize, types: &[u8]) -> usize {
+    let mut idx = 0;
+    let mut kept = 0;
+    while idx < len {
+        let end = idx + 1 + data[idx] as usize;
+        if !types.contains(&data[idx + 1]) {
+            for i in idx..end {
+                data[kept + i - idx] = data[i];
+            }
+            kept += end - idx;
+        }
+        idx = end;
+    }
+    kept
+}
+
 /// Process specific memory
 pub struct App {
     process_status: Option<BLEState>,
     alarm_data: AlarmData,
 
     // Advertising meta-data
-    adv_data: Option<kernel::AppSlice<kernel::Shared, u8>>,
+    adv_payload: [u8; ADV_DATA_MAX_LEN],
+    adv_payload_len: usize,
+    ad_value: Option<kernel::AppSlice<kernel::Shared, u8>>,
     address: [u8; PACKET_ADDR_LEN],
     pdu_type: AdvPduType,
     advertisement_interval_ms: u32,
@@ -186,7 +307,9 @@ impl Default for App {
     fn default() -> App {
         App {
             alarm_data: AlarmData::new(),
-            adv_data: None,
+            adv_payload: [0; ADV_DATA_MAX_LEN],
+            adv_payload_len: 0,
+            ad_value: None,
             scan_buffer: None,
             address: [0; PACKET_ADDR_LEN],
             pdu_type: ADV_NONCONN_IND,
@@ -229,46 +352,112 @@ impl App {
         ReturnCode::SUCCESS
     }
 
+    // Generate the address of the process the first time it sets up an
+    // advertisement
+    fn initialize_advertising(&mut self, appid: kernel::AppId) -> ReturnCode {
+        match self.process_status {
+            Some(BLEState::NotInitialized) => {
+                if let ReturnCode::SUCCESS = self.generate_random_address(appid) {
+                    self.process_status = Some(BLEState::Initialized);
+                    ReturnCode::SUCCESS
+                } else {
+                    ReturnCode::FAIL
+                }
+            }
+            _ => ReturnCode::SUCCESS,
+        }
+    }
+
+    // See allow 0
+    fn set_advertising_data(&mut self, data: &[u8]) -> ReturnCode {
+        match validate_ad_structures(data) {
+            Ok(len) => {
+                self.adv_payload = [0; ADV_DATA_MAX_LEN];
+                self.adv_payload[..len].copy_from_slice(&data[..len]);
+                self.adv_payload_len = len;
+                ReturnCode::SUCCESS
+            }
+            Err(err) => err,
+        }
+    }
+
+    // See command 4
+    fn add_ad_structure(&mut self, ad_type: usize, len: usize) -> ReturnCode {
+        if ad_type == 0 || ad_type > 0xff {
+            return ReturnCode::EINVAL;
+        }
+        let ad_type = ad_type as u8;
+        let mut value = [0; ADV_DATA_MAX_LEN - 2];
+        match self.ad_value {
+            Some(ref slice) if len <= slice.len() => {
+                if len > value.len() {
+                    return ReturnCode::ESIZE;
+                }
+                value[..len].copy_from_slice(&slice.as_ref()[..len]);
+            }
+            _ => return ReturnCode::EINVAL,
+        }
+        let result = validate_ad_value(ad_type, &value[..len]);
+        if result != ReturnCode::SUCCESS {
+            return result;
+        }
+
+        let names = [AD_SHORTENED_LOCAL_NAME, AD_COMPLETE_LOCAL_NAME];
+        let same_type = [ad_type];
+        let replaced: &[u8] = if names.contains(&ad_type) {
+            &names
+        } else {
+            &same_type
+        };
+        let mut payload = self.adv_payload;
+        let kept = remove_ad_structures(&mut payload, self.adv_payload_len, replaced);
+        let end = kept + 2 + len;
+        if end > ADV_DATA_MAX_LEN {
+            return ReturnCode::ESIZE;
+        }
+        payload[kept] = (len + 1) as u8;
+        payload[kept + 1] = ad_type;
+        payload[kept + 2..end].copy_from_slice(&value[..len]);
+        for byte in payload[end..].iter_mut() {
+            *byte = 0;
+        }
+        self.adv_payload = payload;
+        self.adv_payload_len = end;
+        ReturnCode::SUCCESS
+    }
+
     fn send_advertisement<'a, B, A>(&self, ble: &BLE<'a, B, A>, channel: RadioChannel) -> ReturnCode
     where
         B: ble_advertising::BleAdvertisementDriver + ble_advertising::BleConfig + 'a,
         A: kernel::hil::time::Alarm + 'a,
     {
-        self.adv_data
-            .as_ref()
-            .map(|adv_data| {
-                ble.kernel_tx
-                    .take()
-                    .map(|kernel_tx| {
-                        let adv_data_len =
-                            cmp::min(kernel_tx.len() - PACKET_ADDR_LEN - 2, adv_data.len());
-                        let adv_data_corrected = &adv_data.as_ref()[..adv_data_len];
-                        let payload_len = adv_data_corrected.len() + PACKET_ADDR_LEN;
-                        {
-                            let (header, payload) = kernel_tx.split_at_mut(2);
-                            header[0] = self.pdu_type;
-                            match self.pdu_type {
-                                ADV_IND | ADV_NONCONN_IND | ADV_SCAN_IND => {
-                                    // Set TxAdd because AdvA field is going to be a "random"
-                                    // address
-                                    header[0] |= 1 << ADV_HEADER_TXADD_OFFSET;
-                                }
-                                _ => {}
-                            }
-                            // The LENGTH field is 6-bits wide, so make sure to truncate it
-                            header[1] = (payload_len & 0x3f) as u8;
-
-                            let (adva, data) = payload.split_at_mut(6);
-                            adva.copy_from_slice(&self.address);
-                            data[..adv_data_len].copy_from_slice(adv_data_corrected);
+        ble.kernel_tx
+            .take()
+            .map(|kernel_tx| {
+                let adv_data = &self.adv_payload[..self.adv_payload_len];
+                let payload_len = adv_data.len() + PACKET_ADDR_LEN;
+                {
+                    let (header, payload) = kernel_tx.split_at_mut(2);
+                    header[0] = self.pdu_type;
+                    match self.pdu_type {
+                        ADV_IND | ADV_NONCONN_IND | ADV_SCAN_IND => {
+                            // Set TxAdd because AdvA field is going to be a "random"
+                            // address
+                            header[0] |= 1 << ADV_HEADER_TXADD_OFFSET;
                         }
-                        let total_len = cmp::min(PACKET_LENGTH, payload_len + 2);
-                        let result = ble.radio
-                            .transmit_advertisement(kernel_tx, total_len, channel);
-                        ble.kernel_tx.replace(result);
-                        ReturnCode::SUCCESS
-                    })
-                    .unwrap_or(ReturnCode::FAIL)
+                        _ => {}
+                    }
+                    header[1] = payload_len as u8;
+
+                    let (adva, data) = payload.split_at_mut(6);
+                    adva.copy_from_slice(&self.address);
+                    data[..adv_data.len()].copy_from_slice(adv_data);
+                }
+                let total_len = payload_len + 2;
+                let result = ble.radio
+                    .transmit_advertisement(kernel_tx, total_len, channel);
+                ble.kernel_tx.replace(result);
+                ReturnCode::SUCCESS
             })
             .unwrap_or(ReturnCode::FAIL)
     }
@@ -548,7 +737,7 @@ where
         &self,
         command_num: usize,
         data: usize,
-        interval: usize,
+        data2: usize,
         appid: kernel::AppId,
     ) -> ReturnCode {
         match command_num {
@@ -562,7 +751,7 @@ where
                                 app.pdu_type = pdu_type;
                                 app.process_status = Some(BLEState::AdvertisingIdle);
                                 app.random_nonce = self.alarm.now();
-                                app.advertisement_interval_ms = cmp::max(20, interval as u32);
+                                app.advertisement_interval_ms = cmp::max(20, data2 as u32);
                                 app.set_next_alarm::<A::Frequency>(self.alarm.now());
                                 self.reset_active_alarm();
                                 ReturnCode::SUCCESS
@@ -617,6 +806,27 @@ where
                     .unwrap_or_else(|err| err.into())
             }
 
+            // Clear the advertising payload
+            3 => self.app
+                .enter(appid, |app, _| {
+                    app.adv_payload = [0; ADV_DATA_MAX_LEN];
+                    app.adv_payload_len = 0;
+                    app.initialize_advertising(appid)
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // Add an AD structure to the advertising payload
+            4 => self.app
+                .enter(appid, |app, _| {
+                    let result = app.add_ad_structure(data, data2);
+                    if result == ReturnCode::SUCCESS {
+                        app.initialize_advertising(appid)
+                    } else {
+                        result
+                    }
+                })
+                .unwrap_or_else(|err| err.into()),
+
             // Passive scanning mode
             5 => self.app
                 .enter(appid, |app, _| {
@@ -645,7 +855,13 @@ where
             // Advertisement buffer
             0 => self.app
                 .enter(appid, |app, _| {
-                    app.adv_data = slice;
+                    let result = {
+                        let data = slice.as_ref().map_or(&[][..], |slice| slice.as_ref());
+                        app.set_advertising_data(data)
+                    };
+                    if result != ReturnCode::SUCCESS {
+                        return result;
+                    }
                     if let ReturnCode::SUCCESS = app.generate_random_address(appid) {
                         app.process_status = Some(BLEState::Initialized);
                         ReturnCode::SUCCESS
@@ -667,6 +883,14 @@ where
                 })
                 .unwrap_or_else(|err| err.into()),
 
+            // Value of the AD structure added by command 4
+            2 => self.app
+                .enter(appid, |app, _| {
+                    app.ad_value = slice;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+
             // Operation not supported
             _ => ReturnCode::ENOSUPPORT,
         }
@@ -33,12 +33,61 @@
 //! When the buffer has been written successfully, the buffer is released from
 //! the driver. Successive writes must call `allow` each time a buffer is to be
 //! written.
+//!
+//! Writes longer than `WRITE_BUF` are streamed out one `WRITE_BUF` sized
+//! chunk at a time, and the callback is only invoked once all of the buffer
+//! has been written.
+//!
+//! Flow control
+//! ------------
+//!
+//! By default the console assumes the host keeps up with its output and only
+//! sends input when asked for. For hosts that do not, set the flow control
+//! before calling `initialize`:
+//!
+//! ```rust
+//! console.set_flow_control(console::FlowControl::Hardware);
+//! console.initialize();
+//! ```
+//!
+//! - `FlowControl::Hardware` enables RTS/CTS on the UART. The host holds
+//!   back long writes by deasserting CTS, and the UART tells the host when it
+//!   can take input with RTS. The board must connect the RTS and CTS pins.
+//! - `FlowControl::XonXoff` sends XON (0x11) to the host when a receive
+//!   starts and XOFF (0x13) when it completes, so the host only sends input
+//!   while there is a buffer for it. Output is not paused by the host.
+//!
+//! Reconfiguration
+//! ---------------
+//!
+//! Applications can change the baud rate, parity, stop bits and hardware
+//! flow control at runtime:
+//!
+//! ```c
+//! // Called with the result and the new baud rate once the change is made
+//! subscribe(CONSOLE_DRIVER_NUM, 3, my_callback);
+//! // 9600 baud, even parity, one stop bit, no hardware flow control
+//! command(CONSOLE_DRIVER_NUM, 3, 9600, 2);
+//! ```
+//!
+//! The parameters apply to everything sharing the UART, so only the
+//! processes the board names are allowed to change them:
+//!
+//! ```rust
+//! console.set_reconfigure_apps(&["serial_setup"]);
+//! ```
+//!
+//! The UART is only reconfigured once it is idle. The chunk being written
+//! is transmitted in full with the old parameters, the rest of the write
+//! and any queued writes follow with the new ones. A read in progress is
+//! aborted, its callback getting `ECANCEL`. Reads started before the change
+//! is made fail with `EBUSY`.
 
 use core::cell::Cell;
 use core::cmp;
 use kernel::common::take_cell::TakeCell;
 use kernel::hil::uart::{self, Client, UART};
-use kernel::process::Error;
+use kernel::process::{self, Error};
 use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
 
 /// Syscall driver number.
@@ -54,6 +103,8 @@ pub struct App {
     read_callback: Option<Callback>,
     read_buffer: Option<AppSlice<Shared, u8>>,
     read_len: usize,
+
+    reconfigure_callback: Option<Callback>,
 }
 
 impl Default for App {
@@ -68,10 +119,48 @@ impl Default for App {
             read_callback: None,
             read_buffer: None,
             read_len: 0,
+
+            reconfigure_callback: None,
         }
     }
 }
 
+/// How the console tells the host when it can take data
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FlowControl {
+    None,
+    Hardware,
+    XonXoff,
+}
+
+const XON: u8 = 0x11;
+const XOFF: u8 = 0x13;
+
+/// UART parameters from the arguments of command 3: the baud rate, and the
+/// parity in bits 0-1 of `format` (0: none, 1: odd, 2: even), two stop bits
+/// if bit 2 is set and hardware flow control if bit 3 is set
+fn params_from_args(baud_rate: usize, format: usize) -> Option<uart::UARTParams> {
+    let parity = match format & 0b11 {
+        0 => uart::Parity::None,
+        1 => uart::Parity::Odd,
+        2 => uart::Parity::Even,
+        _ => return None,
+    };
+    if baud_rate == 0 || format & !0b1111 != 0 {
+        return None;
+    }
+    Some(uart::UARTParams {
+        baud_rate: baud_rate as u32,
+        stop_bits: if format & 0b100 != 0 {
+            uart::StopBits::Two
+        } else {
+            uart::StopBits::One
+        },
+        parity: parity,
+        hw_flow_control: format & 0b1000 != 0,
+    })
+}
+
 pub static mut WRITE_BUF: [u8; 64] = [0; 64];
 pub static mut READ_BUF: [u8; 64] = [0; 64];
 
@@ -83,6 +172,13 @@ pub struct Console<'a, U: UART + 'a> {
     rx_in_progress: Cell<Option<AppId>>,
     rx_buffer: TakeCell<'static, [u8]>,
     baud_rate: u32,
+    flow_control: Cell<FlowControl>,
+    /// XON or XOFF waiting for the transmitter
+    pending_flow_byte: Cell<Option<u8>>,
+    /// Parameters waiting for the UART to be idle, and the app asking for them
+    reconfiguration: Cell<Option<(AppId, uart::UARTParams)>>,
+    /// Package names of the processes allowed to reconfigure the UART
+    reconfigure_apps: Cell<&'static [&'static str]>,
 }
 
 impl<'a, U: UART> Console<'a, U> {
@@ -101,18 +197,125 @@ impl<'a, U: UART> Console<'a, U> {
             rx_in_progress: Cell::new(None),
             rx_buffer: TakeCell::new(rx_buffer),
             baud_rate: baud_rate,
+            flow_control: Cell::new(FlowControl::None),
+            pending_flow_byte: Cell::new(None),
+            reconfiguration: Cell::new(None),
+            reconfigure_apps: Cell::new(&[]),
         }
     }
 
+    /// Set the flow control, before `initialize` is called.
+    pub fn set_flow_control(&self, flow_control: FlowControl) {
+        self.flow_control.set(flow_control);
+    }
+
+    /// Allow the processes with these package names to reconfigure the UART
+    /// with command 3.
+    pub fn set_reconfigure_apps(&self, package_names: &'static [&'static str]) {
+        self.reconfigure_apps.set(package_names);
+    }
+
+    fn is_reconfigure_app(&self, appid: AppId) -> bool {
+        process::package_name(appid).map_or(false, |name| {
+            self.reconfigure_apps.get().iter().any(|allowed| *allowed == name)
+        })
+    }
+
     pub fn initialize(&self) {
         self.uart.init(uart::UARTParams {
             baud_rate: self.baud_rate,
             stop_bits: uart::StopBits::One,
             parity: uart::Parity::None,
-            hw_flow_control: false,
+            hw_flow_control: self.flow_control.get() == FlowControl::Hardware,
+        });
+    }
+
+    /// Send XON or XOFF to the host, ahead of any pending writes. It goes out
+    /// once the chunk being transmitted, if any, completes.
+    fn send_flow_byte(&self, byte: u8) {
+        if self.flow_control.get() != FlowControl::XonXoff {
+            return;
+        }
+        self.pending_flow_byte.set(Some(byte));
+        if self.tx_in_progress.get().is_none() {
+            self.send_pending_flow_byte();
+        }
+    }
+
+    /// Returns true if a flow control byte is now being transmitted
+    fn send_pending_flow_byte(&self) -> bool {
+        match self.pending_flow_byte.get() {
+            Some(byte) => self.tx_buffer.take().map_or(false, |buffer| {
+                self.pending_flow_byte.set(None);
+                buffer[0] = byte;
+                self.uart.transmit(buffer, 1);
+                true
+            }),
+            None => false,
+        }
+    }
+
+    /// Reconfigure the UART if a reconfiguration is waiting and neither a
+    /// transmission nor a reception is in progress, then resume the writes
+    /// held back in the meantime.
+    fn reconfigure_if_idle(&self) {
+        if self.tx_buffer.is_none() || self.rx_buffer.is_none() {
+            return;
+        }
+        self.reconfiguration.take().map(|(appid, params)| {
+            let result = self.uart.reconfigure(params);
+            if result == ReturnCode::SUCCESS {
+                if params.hw_flow_control {
+                    self.flow_control.set(FlowControl::Hardware);
+                } else if self.flow_control.get() == FlowControl::Hardware {
+                    self.flow_control.set(FlowControl::None);
+                }
+            }
+            self.apps
+                .enter(appid, |app, _| {
+                    app.reconfigure_callback.map(|mut cb| {
+                        cb.schedule(From::from(result), params.baud_rate as usize, 0);
+                    });
+                })
+                .unwrap_or_default();
+            self.send_pending_writes();
         });
     }
 
+    /// Start the next write held back by an application, if the
+    /// transmitter is free.
+    fn send_pending_writes(&self) {
+        if self.tx_in_progress.get().is_some() {
+            return;
+        }
+        for cntr in self.apps.iter() {
+            let started_tx = cntr.enter(|app, _| {
+                if app.pending_write {
+                    app.pending_write = false;
+                    match self.send_continue(app.appid(), app) {
+                        Ok(more_to_send) => more_to_send,
+                        Err(return_code) => {
+                            // XXX This shouldn't ever happen?
+                            app.write_len = 0;
+                            app.write_remaining = 0;
+                            app.pending_write = false;
+                            let r0 = isize::from(return_code) as usize;
+                            app.write_callback.map(|mut cb| {
+                                cb.schedule(r0, 0, 0);
+                            });
+                            false
+                        }
+                    }
+                } else {
+                    false
+                }
+            });
+            if started_tx {
+                break;
+            }
+        }
+    }
+
     /// Internal helper function for setting up a new send transaction
     fn send_new(&self, app_id: AppId, app: &mut App, len: usize) -> ReturnCode {
         match app.write_buffer.take() {
@@ -144,7 +347,11 @@ impl<'a, U: UART> Console<'a, U> {
     /// Internal helper function for sending data for an existing transaction.
     /// Cannot fail. If can't send now, it will schedule for sending later.
     fn send(&self, app_id: AppId, app: &mut App, slice: AppSlice<Shared, u8>) {
-        if self.tx_in_progress.get().is_none() {
+        // The buffer is also out while a flow control byte is transmitted.
+        // Writes are held back until a pending reconfiguration is made.
+        if self.tx_in_progress.get().is_none() && self.tx_buffer.is_some()
+            && self.reconfiguration.get().is_none()
+        {
             self.tx_in_progress.set(Some(app_id));
             self.tx_buffer.take().map(|buffer| {
                 let mut transaction_len = app.write_remaining;
@@ -178,7 +385,7 @@ impl<'a, U: UART> Console<'a, U> {
 
     /// Internal helper function for starting a receive operation
     fn receive_new(&self, app_id: AppId, app: &mut App, len: usize) -> ReturnCode {
-        if self.rx_buffer.is_none() {
+        if self.rx_buffer.is_none() || self.reconfiguration.get().is_some() {
             // For now, we tolerate only one concurrent receive operation on this console.
             // Competing apps will have to retry until success.
             return ReturnCode::EBUSY;
@@ -198,6 +405,7 @@ impl<'a, U: UART> Console<'a, U> {
                         self.rx_in_progress.set(Some(app_id));
                         self.uart.receive(buffer, app.read_len);
                     });
+                    self.send_flow_byte(XON);
                     ReturnCode::SUCCESS
                 }
             }
@@ -244,6 +452,8 @@ impl<'a, U: UART> Driver for Console<'a, U> {
     /// ### `subscribe_num`
     ///
     /// - `1`: Write buffer completed callback
+    /// - `2`: Read buffer completed callback
+    /// - `3`: Reconfiguration completed callback
     fn subscribe(
         &self,
         subscribe_num: usize,
@@ -275,6 +485,12 @@ impl<'a, U: UART> Driver for Console<'a, U> {
                     }
                 })
             },
+            3 /* reconfigure done */ => {
+                self.apps.enter(app_id, |app, _| {
+                    app.reconfigure_callback = callback;
+                    ReturnCode::SUCCESS
+                }).unwrap_or_else(|err| err.into())
+            },
             _ => ReturnCode::ENOSUPPORT
         }
     }
@@ -288,7 +504,12 @@ impl<'a, U: UART> Driver for Console<'a, U> {
     ///        passed in `arg1`
     /// - `2`: Receives into a buffer passed via `allow`, up to the length
     ///        passed in `arg1`
-    fn command(&self, cmd_num: usize, arg1: usize, _: usize, appid: AppId) -> ReturnCode {
+    /// - `3`: Changes the baud rate to `arg1` and the format to `arg2`: the
+    ///        parity in bits 0-1 (0: none, 1: odd, 2: even), two stop bits if
+    ///        bit 2 is set and hardware flow control if bit 3 is set. Only
+    ///        allowed to the processes the board names with
+    ///        `set_reconfigure_apps`, returns `ENOSUPPORT` for the others.
+    fn command(&self, cmd_num: usize, arg1: usize, arg2: usize, appid: AppId) -> ReturnCode {
         match cmd_num {
             0 /* check if present */ => ReturnCode::SUCCESS,
             1 /* putstr */ => {
@@ -315,6 +536,26 @@ impl<'a, U: UART> Driver for Console<'a, U> {
                     }
                 })
             },
+            3 /* reconfigure */ => {
+                if !self.is_reconfigure_app(appid) {
+                    return ReturnCode::ENOSUPPORT;
+                }
+                let params = match params_from_args(arg1, arg2) {
+                    Some(params) => params,
+                    None => return ReturnCode::EINVAL,
+                };
+                if self.reconfiguration.get().is_some() {
+                    return ReturnCode::EBUSY;
+                }
+                self.reconfiguration.set(Some((appid, params)));
+                // The chunk being written goes out with the old parameters,
+                // a read in progress is cut short
+                if self.rx_buffer.is_none() {
+                    self.uart.abort_receive();
+                }
+                self.reconfigure_if_idle();
+                ReturnCode::SUCCESS
+            },
             _ => ReturnCode::ENOSUPPORT
         }
     }
@@ -325,6 +566,11 @@ impl<'a, U: UART> Client for Console<'a, U> {
         // Either print more from the AppSlice or send a callback to the
         // application.
         self.tx_buffer.replace(buffer);
+        // XON and XOFF go out between chunks, the write in progress, if any,
+        // continues once they have been transmitted
+        if self.send_pending_flow_byte() {
+            return;
+        }
         self.tx_in_progress.get().map(|appid| {
             self.tx_in_progress.set(None);
             self.apps.enter(appid, |app, _| {
@@ -355,38 +601,13 @@ impl<'a, U: UART> Client for Console<'a, U> {
 
         // If we are not printing more from the current AppSlice,
         // see if any other applications have pending messages.
-        if self.tx_in_progress.get().is_none() {
-            for cntr in self.apps.iter() {
-                let started_tx = cntr.enter(|app, _| {
-                    if app.pending_write {
-                        app.pending_write = false;
-                        match self.send_continue(app.appid(), app) {
-                            Ok(more_to_send) => more_to_send,
-                            Err(return_code) => {
-                                // XXX This shouldn't ever happen?
-                                app.write_len = 0;
-                                app.write_remaining = 0;
-                                app.pending_write = false;
-                                let r0 = isize::from(return_code) as usize;
-                                app.write_callback.map(|mut cb| {
-                                    cb.schedule(r0, 0, 0);
-                                });
-                                false
-                            }
-                        }
-                    } else {
-                        false
-                    }
-                });
-                if started_tx {
-                    break;
-                }
-            }
-        }
+        self.send_pending_writes();
+        self.reconfigure_if_idle();
     }
 
     fn receive_complete(&self, buffer: &'static mut [u8], _rx_len: usize, error: uart::Error) {
         self.rx_buffer.replace(buffer);
+        self.send_flow_byte(XOFF);
         self.rx_in_progress.get().map(|appid| {
             self.rx_in_progress.set(None);
 
@@ -414,6 +635,7 @@ impl<'a, U: UART> Client for Console<'a, U> {
                                     None => (ReturnCode::EINVAL, 0),
                                 }
                             }
+                            uart::Error::Aborted => (ReturnCode::ECANCEL, 0),
                             _ => {
                                 // Some UART error occurred
                                 (ReturnCode::FAIL, 0)
@@ -429,5 +651,6 @@ impl<'a, U: UART> Client for Console<'a, U> {
                 })
                 .unwrap_or_default();
         });
+        self.reconfigure_if_idle();
     }
 }
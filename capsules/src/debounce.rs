@@ -0,0 +1,165 @@
+//! Debounce a GPIO input pin.
+//!
+//! `DebouncedPin` wraps an input pin, such as a button, and filters the
+//! bounces of its contacts out of the interrupts. On the first edge it waits
+//! `DEBOUNCE_MS` and then reads the pin: if the level differs from the last
+//! one reported, and matches the interrupt mode, the client gets a single
+//! `fired`. Edges during the wait are ignored. It provides `hil::gpio::Pin`
+//! and `hil::gpio::PinCtl`, so it can stand in for the pin in capsules such
+//! as the button driver.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let debounce_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, Rtc>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let button1 = static_init!(
+//!     DebouncedPin<'static, nrf5x::gpio::GPIOPin, VirtualMuxAlarm<'static, Rtc>>,
+//!     DebouncedPin::new(&nrf5x::gpio::PORT[17], debounce_alarm)
+//! );
+//! nrf5x::gpio::PORT[17].set_client(button1);
+//! debounce_alarm.set_client(button1);
+//! ```
+
+use core::cell::Cell;
+use kernel::hil::gpio::{self, DriveMode, InputMode, InterruptMode};
+use kernel::hil::time::{self, Alarm, Frequency};
+use kernel::ReturnCode;
+
+/// Time the contacts are given to settle
+pub const DEBOUNCE_MS: u32 = 20;
+
+pub struct DebouncedPin<'a, P: gpio::Pin + gpio::PinCtl + 'a, A: Alarm + 'a> {
+    pin: &'a P,
+    alarm: &'a A,
+    client: Cell<Option<&'static gpio::Client>>,
+    identifier: Cell<usize>,
+    mode: Cell<InterruptMode>,
+    interrupt_enabled: Cell<bool>,
+    /// Level of the pin last reported to the client
+    level: Cell<bool>,
+    /// Waiting for the contacts to settle
+    settling: Cell<bool>,
+}
+
+impl<'a, P: gpio::Pin + gpio::PinCtl + 'a, A: Alarm + 'a> DebouncedPin<'a, P, A> {
+    pub fn new(pin: &'a P, alarm: &'a A) -> DebouncedPin<'a, P, A> {
+        DebouncedPin {
+            pin: pin,
+            alarm: alarm,
+            client: Cell::new(None),
+            identifier: Cell::new(0),
+            mode: Cell::new(InterruptMode::EitherEdge),
+            interrupt_enabled: Cell::new(false),
+            level: Cell::new(false),
+            settling: Cell::new(false),
+        }
+    }
+
+    pub fn set_client(&self, client: &'static gpio::Client) {
+        self.client.set(Some(client));
+    }
+}
+
+impl<'a, P: gpio::Pin + gpio::PinCtl + 'a, A: Alarm + 'a> gpio::Pin for DebouncedPin<'a, P, A> {
+    fn make_output(&self) {
+        self.pin.make_output();
+    }
+
+    fn make_input(&self) {
+        self.pin.make_input();
+    }
+
+    fn disable(&self) {
+        self.pin.disable();
+    }
+
+    fn set(&self) {
+        self.pin.set();
+    }
+
+    fn clear(&self) {
+        self.pin.clear();
+    }
+
+    fn toggle(&self) {
+        self.pin.toggle();
+    }
+
+    fn read(&self) -> bool {
+        self.pin.read()
+    }
+
+    fn enable_interrupt(&self, identifier: usize, mode: InterruptMode) {
+        self.identifier.set(identifier);
+        self.mode.set(mode);
+        if !self.interrupt_enabled.get() {
+            self.interrupt_enabled.set(true);
+            self.level.set(self.pin.read());
+            // Both edges start the settling time, whatever the mode
+            self.pin.enable_interrupt(0, InterruptMode::EitherEdge);
+        }
+    }
+
+    fn disable_interrupt(&self) {
+        self.interrupt_enabled.set(false);
+        self.pin.disable_interrupt();
+    }
+}
+
+impl<'a, P: gpio::Pin + gpio::PinCtl + 'a, A: Alarm + 'a> gpio::PinCtl
+    for DebouncedPin<'a, P, A>
+{
+    fn set_input_mode(&self, mode: InputMode) {
+        self.pin.set_input_mode(mode);
+    }
+
+    fn set_drive_mode(&self, mode: DriveMode) -> ReturnCode {
+        self.pin.set_drive_mode(mode)
+    }
+}
+
+impl<'a, P: gpio::Pin + gpio::PinCtl + 'a, A: Alarm + 'a> gpio::Client
+    for DebouncedPin<'a, P, A>
+{
+    fn fired(&self, _: usize) {
+        if self.settling.get() {
+            return;
+        }
+        self.settling.set(true);
+        let ticks = DEBOUNCE_MS * <A::Frequency>::frequency() / 1000;
+        let now = self.alarm.now();
+        self.alarm.set_alarm(now.wrapping_add(ticks));
+    }
+}
+
+impl<'a, P: gpio::Pin + gpio::PinCtl + 'a, A: Alarm + 'a> time::Client
+    for DebouncedPin<'a, P, A>
+{
+    fn fired(&self) {
+        self.settling.set(false);
+        if !self.interrupt_enabled.get() {
+            return;
+        }
+
+        let level = self.pin.read();
+        if level == self.level.get() {
+            return;
+        }
+        self.level.set(level);
+
+        let report = match self.mode.get() {
+            InterruptMode::EitherEdge => true,
+            InterruptMode::RisingEdge => level,
+            InterruptMode::FallingEdge => !level,
+        };
+        if report {
+            self.client
+                .get()
+                .map(|client| client.fired(self.identifier.get()));
+        }
+    }
+}
@@ -8,6 +8,13 @@
 //! attached to LEDs or buttons are generally wired directly to those capsules,
 //! not through this capsule as an intermediary.
 //!
+//! Instead of a callback per edge, a process can also have the edges of a
+//! pin counted in the kernel and the count reported every `N` edges, every
+//! `T` milliseconds, or both. Signals of high frequency, such as those of
+//! quadrature encoders or flow sensors, then do not overflow the callback
+//! queue. Each pin is counted for at most one process at a time. Periodic
+//! reports need a timer, see `EdgeReportAlarm`.
+//!
 //! Usage
 //! -----
 //!
@@ -20,10 +27,21 @@
 //!      &sam4l::gpio::PB[12]]);
 //! let gpio = static_init!(
 //!     capsules::gpio::GPIO<'static, sam4l::gpio::GPIOPin>,
-//!     capsules::gpio::GPIO::new(gpio_pins));
+//!     capsules::gpio::GPIO::new(gpio_pins, kernel::Grant::create()));
 //! for pin in gpio_pins.iter() {
 //!     pin.set_client(gpio);
 //! }
+//!
+//! // Optional, for periodic edge count reports
+//! let gpio_report_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+//!     VirtualMuxAlarm::new(mux_alarm));
+//! let gpio_report_timer = static_init!(
+//!     capsules::gpio::EdgeReportAlarm<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     capsules::gpio::EdgeReportAlarm::new(gpio_report_alarm));
+//! gpio_report_alarm.set_client(gpio_report_timer);
+//! gpio_report_timer.set_client(gpio);
+//! gpio.set_report_timer(gpio_report_timer);
 //! ```
 //!
 //! Syscall Interface
@@ -40,29 +58,145 @@
 //!
 //! ### Subscribes
 //!
-//! The GPIO interface provides only one callback, which is used for pins that
-//! have had interrupts enabled.
+//! The GPIO interface provides one callback for pins that have had
+//! interrupts enabled, shared by all processes, and one callback per process
+//! for the edge counts of the pins it counts.
 
 /// Syscall driver number.
 pub const DRIVER_NUM: usize = 0x00000004;
 
 use core::cell::Cell;
-use kernel::hil::gpio::{Client, InputMode, InterruptMode, Pin, PinCtl};
-use kernel::{AppId, Callback, Driver, ReturnCode};
+use core::cmp;
+use kernel::hil::gpio::{Client, DriveMode, InputMode, InterruptMode, Pin, PinCtl};
+use kernel::hil::time::{self, Alarm, Frequency};
+use kernel::{AppId, Callback, Driver, Grant, ReturnCode};
+
+/// Edges are only counted on the pins with an index below this
+const MAX_COUNTED_PINS: usize = 32;
+
+/// Edge counting state of a process
+#[derive(Default)]
+pub struct App {
+    count_callback: Option<Callback>,
+    /// The pins counted for the process, bit `n` for pin `n`
+    counting: u32,
+    /// Edges counted on each pin since they were last reported or read
+    edges: [usize; MAX_COUNTED_PINS],
+    /// Report a pin every this many edges, `0` for never
+    thresholds: [usize; MAX_COUNTED_PINS],
+    /// Report all counted pins every this many ms, `0` for never
+    period_ms: u32,
+    /// Time left until the next periodic report
+    remaining_ms: u32,
+}
+
+impl App {
+    fn report(&mut self, pin_num: usize) {
+        let edges = self.edges[pin_num];
+        self.edges[pin_num] = 0;
+        self.count_callback.map(|mut cb| cb.schedule(pin_num, edges, 0));
+    }
+
+    fn report_all(&mut self) {
+        for pin_num in 0..MAX_COUNTED_PINS {
+            if self.counting & (1 << pin_num) != 0 {
+                self.report(pin_num);
+            }
+        }
+    }
+}
+
+/// Timer for periodic edge count reports
+pub trait EdgeReportTimer {
+    /// Call the client in `ms` milliseconds, instead of any earlier request.
+    fn start(&self, ms: u32);
+
+    /// Cancel the outstanding request, if any.
+    fn stop(&self);
+
+    /// Milliseconds since the last `start`.
+    fn elapsed_ms(&self) -> u32;
+}
+
+pub trait EdgeReportClient {
+    fn timer_fired(&self);
+}
+
+/// An `EdgeReportTimer` on an alarm
+pub struct EdgeReportAlarm<'a, A: Alarm + 'a> {
+    alarm: &'a A,
+    started: Cell<u32>,
+    client: Cell<Option<&'a EdgeReportClient>>,
+}
+
+impl<'a, A: Alarm> EdgeReportAlarm<'a, A> {
+    pub fn new(alarm: &'a A) -> EdgeReportAlarm<'a, A> {
+        EdgeReportAlarm {
+            alarm: alarm,
+            started: Cell::new(0),
+            client: Cell::new(None),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a EdgeReportClient) {
+        self.client.set(Some(client));
+    }
+}
+
+impl<'a, A: Alarm> EdgeReportTimer for EdgeReportAlarm<'a, A> {
+    fn start(&self, ms: u32) {
+        let now = self.alarm.now();
+        let tics = ms as u64 * <A::Frequency>::frequency() as u64 / 1000;
+        self.started.set(now);
+        self.alarm.set_alarm(now.wrapping_add(tics as u32));
+    }
+
+    fn stop(&self) {
+        self.alarm.disable();
+    }
+
+    fn elapsed_ms(&self) -> u32 {
+        let tics = self.alarm.now().wrapping_sub(self.started.get());
+        let frequency = <A::Frequency>::frequency() as u64;
+        // Rounded, so that a timer fired on time is not a millisecond short
+        ((tics as u64 * 1000 + frequency / 2) / frequency) as u32
+    }
+}
+
+impl<'a, A: Alarm> time::Client for EdgeReportAlarm<'a, A> {
+    fn fired(&self) {
+        self.client.get().map(|client| client.timer_fired());
+    }
+}
 
 pub struct GPIO<'a, G: Pin + 'a> {
     pins: &'a [&'a G],
     callback: Cell<Option<Callback>>,
+    apps: Grant<App>,
+    /// The pins counted for some process, bit `n` for pin `n`
+    counted: Cell<u32>,
+    report_timer: Cell<Option<&'a EdgeReportTimer>>,
+    report_timer_running: Cell<bool>,
 }
 
 impl<'a, G: Pin + PinCtl> GPIO<'a, G> {
-    pub fn new(pins: &'a [&'a G]) -> GPIO<'a, G> {
+    pub fn new(pins: &'a [&'a G], grant: Grant<App>) -> GPIO<'a, G> {
         GPIO {
             pins: pins,
             callback: Cell::new(None),
+            apps: grant,
+            counted: Cell::new(0),
+            report_timer: Cell::new(None),
+            report_timer_running: Cell::new(false),
         }
     }
 
+    /// Enable periodic edge count reports. The GPIO driver must also be set
+    /// as the client of `timer`.
+    pub fn set_report_timer(&self, timer: &'a EdgeReportTimer) {
+        self.report_timer.set(Some(timer));
+    }
+
     fn configure_input_pin(&self, pin_num: usize, config: usize) -> ReturnCode {
         let pin = self.pins[pin_num];
         pin.make_input();
@@ -83,6 +217,19 @@ impl<'a, G: Pin + PinCtl> GPIO<'a, G> {
         }
     }
 
+    fn configure_drive(&self, pin_num: usize, config: usize) -> ReturnCode {
+        let mode = match config {
+            0 => DriveMode::PushPull,
+            1 => DriveMode::HighDrive,
+            2 => DriveMode::OpenDrain,
+            3 => DriveMode::OpenSource,
+            4 => DriveMode::OpenDrainHighDrive,
+            5 => DriveMode::OpenSourceHighDrive,
+            _ => return ReturnCode::ENOSUPPORT,
+        };
+        self.pins[pin_num].set_drive_mode(mode)
+    }
+
     fn configure_interrupt(&self, pin_num: usize, config: usize) -> ReturnCode {
         let pins = self.pins.as_ref();
         match config {
@@ -104,10 +251,148 @@ impl<'a, G: Pin + PinCtl> GPIO<'a, G> {
             _ => ReturnCode::ENOSUPPORT,
         }
     }
+
+    /// Whether a process other than `appid` counts the edges of `pin_num`
+    fn counted_by_other(&self, pin_num: usize, appid: AppId) -> bool {
+        let counted = Cell::new(false);
+        self.apps.each(|app| {
+            if app.counting & (1 << pin_num) != 0 && app.appid() != appid {
+                counted.set(true);
+            }
+        });
+        counted.get()
+    }
+
+    fn start_counting(&self, pin_num: usize, config: usize, appid: AppId) -> ReturnCode {
+        let mode = match config {
+            0 => InterruptMode::EitherEdge,
+            1 => InterruptMode::RisingEdge,
+            2 => InterruptMode::FallingEdge,
+            _ => return ReturnCode::ENOSUPPORT,
+        };
+        if self.counted_by_other(pin_num, appid) {
+            return ReturnCode::EBUSY;
+        }
+        self.apps
+            .enter(appid, |app, _| {
+                app.counting |= 1 << pin_num;
+                app.edges[pin_num] = 0;
+                self.counted.set(self.counted.get() | 1 << pin_num);
+                let pin = self.pins[pin_num];
+                pin.make_input();
+                pin.enable_interrupt(pin_num, mode);
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn stop_counting(&self, pin_num: usize, appid: AppId) -> ReturnCode {
+        if self.counted_by_other(pin_num, appid) {
+            return ReturnCode::EBUSY;
+        }
+        self.apps
+            .enter(appid, |app, _| {
+                if app.counting & (1 << pin_num) != 0 {
+                    app.counting &= !(1 << pin_num);
+                    app.edges[pin_num] = 0;
+                    self.stop_counting_pin(pin_num);
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+}
+
+impl<'a, G: Pin> GPIO<'a, G> {
+    fn stop_counting_pin(&self, pin_num: usize) {
+        self.counted.set(self.counted.get() & !(1 << pin_num));
+        self.pins[pin_num].disable_interrupt();
+    }
+
+    fn count_edge(&self, pin_num: usize) {
+        let counted = Cell::new(false);
+        self.apps.each(|app| {
+            if app.counting & (1 << pin_num) != 0 {
+                counted.set(true);
+                app.edges[pin_num] = app.edges[pin_num].wrapping_add(1);
+                let threshold = app.thresholds[pin_num];
+                if threshold != 0 && app.edges[pin_num] >= threshold {
+                    app.report(pin_num);
+                }
+            }
+        });
+
+        // The process counting the pin has since died
+        if !counted.get() {
+            self.stop_counting_pin(pin_num);
+        }
+    }
+
+    /// Report the counts of the processes whose period is over, and start
+    /// the timer for the next report. `set_period`, if given, is applied in
+    /// between.
+    fn update_reports(&self, set_period: Option<(AppId, u32)>) -> ReturnCode {
+        let timer = match self.report_timer.get() {
+            Some(timer) => timer,
+            None => return ReturnCode::ENOSUPPORT,
+        };
+        let elapsed = if self.report_timer_running.get() {
+            timer.elapsed_ms()
+        } else {
+            0
+        };
+        let next = Cell::new(None);
+        self.apps.each(|app| {
+            if app.period_ms == 0 {
+                return;
+            }
+            app.remaining_ms = app.remaining_ms.saturating_sub(elapsed);
+            if app.remaining_ms == 0 {
+                app.report_all();
+                app.remaining_ms = app.period_ms;
+            }
+        });
+
+        let result = set_period.map_or(ReturnCode::SUCCESS, |(appid, period_ms)| {
+            self.apps
+                .enter(appid, |app, _| {
+                    app.period_ms = period_ms;
+                    app.remaining_ms = period_ms;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into())
+        });
+
+        self.apps.each(|app| {
+            if app.period_ms != 0 {
+                let remaining = next.get().map_or(app.remaining_ms, |next| {
+                    cmp::min(next, app.remaining_ms)
+                });
+                next.set(Some(remaining));
+            }
+        });
+        match next.get() {
+            Some(ms) => timer.start(ms),
+            None => timer.stop(),
+        }
+        self.report_timer_running.set(next.get().is_some());
+        result
+    }
+}
+
+impl<'a, G: Pin> EdgeReportClient for GPIO<'a, G> {
+    fn timer_fired(&self) {
+        self.update_reports(None);
+    }
 }
 
 impl<'a, G: Pin> Client for GPIO<'a, G> {
     fn fired(&self, pin_num: usize) {
+        if pin_num < MAX_COUNTED_PINS && self.counted.get() & (1 << pin_num) != 0 {
+            self.count_edge(pin_num);
+            return;
+        }
+
         // read the value of the pin
         let pins = self.pins.as_ref();
         let pin_state = pins[pin_num].read();
@@ -126,11 +411,14 @@ impl<'a, G: Pin + PinCtl> Driver for GPIO<'a, G> {
     ///
     /// - `0`: Subscribe to interrupts from all pins with interrupts enabled.
     ///        The callback signature is `fn(pin_num: usize, pin_state: bool)`
+    /// - `1`: Subscribe to the edge count reports of the pins counted for the
+    ///        process. The callback signature is
+    ///        `fn(pin_num: usize, edges: usize)`
     fn subscribe(
         &self,
         subscribe_num: usize,
         callback: Option<Callback>,
-        _app_id: AppId,
+        app_id: AppId,
     ) -> ReturnCode {
         match subscribe_num {
             // subscribe to all pin interrupts (no affect or reliance on
@@ -140,6 +428,14 @@ impl<'a, G: Pin + PinCtl> Driver for GPIO<'a, G> {
                 ReturnCode::SUCCESS
             }
 
+            // subscribe to the edge counts of this process's pins
+            1 => self.apps
+                .enter(app_id, |app, _| {
+                    app.count_callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+
             // default
             _ => ReturnCode::ENOSUPPORT,
         }
@@ -162,6 +458,13 @@ impl<'a, G: Pin + PinCtl> Driver for GPIO<'a, G> {
     ///                   Set to `0` to interrupt on either edge.
     ///                   Set to `1` for rising edge.
     ///                   Set to `2` for falling edge.
+    ///   - `drive_config`: Output drive setting.
+    ///                   Set to `0` for push-pull.
+    ///                   Set to `1` for push-pull at high strength.
+    ///                   Set to `2` for open drain.
+    ///                   Set to `3` for open source.
+    ///                   Set to `4` for open drain at high strength.
+    ///                   Set to `5` for open source at high strength.
     ///
     /// ### `command_num`
     ///
@@ -175,7 +478,21 @@ impl<'a, G: Pin + PinCtl> Driver for GPIO<'a, G> {
     /// - `7`: Configure interrupt on `pin` with `irq_config` in 0x00XX00000
     /// - `8`: Disable interrupt on `pin`.
     /// - `9`: Disable `pin`.
-    fn command(&self, command_num: usize, data1: usize, data2: usize, _: AppId) -> ReturnCode {
+    /// - `10`: Set the output drive of `pin` to `drive_config` in `data2`.
+    ///         Returns `ENOSUPPORT` if the chip cannot drive the pin that way.
+    /// - `11`: Count the edges of `pin` selected by `irq_config` in `data2`,
+    ///         instead of calling back for each. Returns `EBUSY` if they are
+    ///         counted for another process.
+    /// - `12`: Stop counting the edges of `pin`, discarding the count.
+    /// - `13`: Report the count of `pin` every `data2` edges, `0` for never.
+    /// - `14`: Report the counts of all counted pins every `data1` ms, `0`
+    ///         for never. Returns `ENOSUPPORT` if the board has no timer for
+    ///         it.
+    /// - `15`: Read and clear the count of `pin`.
+    ///
+    /// Commands `11`, `12`, `13` and `15` also return `EINVAL` for a pin
+    /// numbered 32 or higher.
+    fn command(&self, command_num: usize, data1: usize, data2: usize, appid: AppId) -> ReturnCode {
         let pins = self.pins.as_ref();
         let pin = data1;
         match command_num {
@@ -279,6 +596,46 @@ impl<'a, G: Pin + PinCtl> Driver for GPIO<'a, G> {
                 }
             }
 
+            // configure output drive
+            10 => {
+                let drive_config = data2;
+                if pin >= pins.len() {
+                    ReturnCode::EINVAL /* impossible pin */
+                } else {
+                    self.configure_drive(pin, drive_config)
+                }
+            }
+
+            // the period is not tied to a pin
+            14 => self.update_reports(Some((appid, data1 as u32))),
+
+            11 | 12 | 13 | 15 if pin >= cmp::min(pins.len(), MAX_COUNTED_PINS) => {
+                ReturnCode::EINVAL /* impossible pin */
+            }
+
+            // count edges
+            11 => self.start_counting(pin, data2, appid),
+
+            // stop counting edges
+            12 => self.stop_counting(pin, appid),
+
+            // report every N edges
+            13 => self.apps
+                .enter(appid, |app, _| {
+                    app.thresholds[pin] = data2;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // read and clear the count
+            15 => self.apps
+                .enter(appid, |app, _| {
+                    let edges = app.edges[pin];
+                    app.edges[pin] = 0;
+                    ReturnCode::SuccessWithValue { value: edges }
+                })
+                .unwrap_or_else(|err| err.into()),
+
             // default
             _ => ReturnCode::ENOSUPPORT,
         }
@@ -0,0 +1,254 @@
+//! Exposes a register file to an I2C master, backed by an application buffer.
+//!
+//! The board appears on the bus as an I2C slave whose registers are the bytes
+//! of a buffer the application shares with `allow`. Like most I2C devices it
+//! keeps a register pointer:
+//!
+//! - A write from the master sets the register pointer to its first byte and
+//!   stores any further bytes into consecutive registers.
+//! - A read from the master returns consecutive registers starting at the
+//!   register pointer.
+//!
+//! The pointer advances past every register written or read, so a master
+//! can read back a block of registers after a single pointer write. Reads
+//! are answered from the application buffer directly, without waking the
+//! application. Writes are copied into the buffer and then reported to the
+//! application with a callback.
+//!
+//! This capsule sits directly on top of the I2C slave hardware, it cannot be
+//! shared with other users of the same bus controller.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let i2c_register_file = static_init!(
+//!     capsules::i2c_register_file::I2CRegisterFile<'static>,
+//!     capsules::i2c_register_file::I2CRegisterFile::new(
+//!         &nrf52::i2c::TWIS0,
+//!         &mut capsules::i2c_register_file::RX_BUFFER,
+//!         &mut capsules::i2c_register_file::TX_BUFFER
+//!     )
+//! );
+//! nrf52::i2c::TWIS0.set_client(i2c_register_file);
+//! ```
+//!
+//! Syscall interface
+//! -----------------
+//!
+//! - Allow 0: the register file. Registers past its end read as 0xFF and
+//!   writes to them are dropped.
+//! - Subscribe 0: called after the master writes registers, with the index
+//!   of the first register written and the number of registers written.
+//! - Command 0: check the driver is present.
+//! - Command 1: start listening on the 7-bit address `data`.
+//! - Command 2: stop listening.
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::take_cell::{MapCell, TakeCell};
+use kernel::hil;
+use kernel::ReturnCode;
+use kernel::{AppId, AppSlice, Callback, Driver, Shared};
+
+/// Syscall driver number.
+pub const DRIVER_NUM: usize = 0x20006;
+
+/// Largest transfer the I2C slave HIL can describe.
+pub const BUFFER_LEN: usize = 255;
+
+pub static mut RX_BUFFER: [u8; BUFFER_LEN] = [0; BUFFER_LEN];
+pub static mut TX_BUFFER: [u8; BUFFER_LEN] = [0; BUFFER_LEN];
+
+/// Sent for registers past the end of the register file.
+const UNMAPPED_REGISTER: u8 = 0xFF;
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    registers: Option<AppSlice<Shared, u8>>,
+}
+
+pub struct I2CRegisterFile<'a> {
+    i2c: &'a hil::i2c::I2CSlave,
+    rx_buffer: TakeCell<'static, [u8]>,
+    tx_buffer: TakeCell<'static, [u8]>,
+    /// Register the next read or write starts at
+    register: Cell<u8>,
+    app: MapCell<App>,
+}
+
+impl<'a> I2CRegisterFile<'a> {
+    pub fn new(
+        i2c: &'a hil::i2c::I2CSlave,
+        rx_buffer: &'static mut [u8],
+        tx_buffer: &'static mut [u8],
+    ) -> I2CRegisterFile<'a> {
+        I2CRegisterFile {
+            i2c: i2c,
+            rx_buffer: TakeCell::new(rx_buffer),
+            tx_buffer: TakeCell::new(tx_buffer),
+            register: Cell::new(0),
+            app: MapCell::new(App::default()),
+        }
+    }
+
+    fn max_len(buffer: &[u8]) -> u8 {
+        cmp::min(buffer.len(), BUFFER_LEN) as u8
+    }
+
+    /// Give the receive buffer back to the hardware so the next write from
+    /// the master does not have to wait.
+    fn prepare_write(&self) {
+        self.rx_buffer.take().map(|buffer| {
+            let len = Self::max_len(buffer);
+            self.i2c.write_receive(buffer, len);
+        });
+    }
+
+    /// Fill the transmit buffer with the registers from the register pointer
+    /// on and hand it to the hardware.
+    fn prepare_read(&self) {
+        self.tx_buffer.take().map(|buffer| {
+            let start = self.register.get() as usize;
+            for byte in buffer.iter_mut() {
+                *byte = UNMAPPED_REGISTER;
+            }
+            self.app.map(|app| {
+                app.registers.as_ref().map(|registers| {
+                    if start < registers.len() {
+                        let len = cmp::min(registers.len() - start, buffer.len());
+                        buffer[..len].copy_from_slice(&registers.as_ref()[start..start + len]);
+                    }
+                });
+            });
+            let len = Self::max_len(buffer);
+            self.i2c.read_send(buffer, len);
+        });
+    }
+
+    /// Store registers written by the master, `data[0]` is the new register
+    /// pointer.
+    fn store_write(&self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let start = data[0] as usize;
+        let values = &data[1..];
+        self.register.set((start + values.len()) as u8);
+
+        if values.is_empty() {
+            return;
+        }
+        self.app.map(|app| {
+            app.registers.as_mut().map(|registers| {
+                if start < registers.len() {
+                    let len = cmp::min(registers.len() - start, values.len());
+                    registers.as_mut()[start..start + len].copy_from_slice(&values[..len]);
+                }
+            });
+            app.callback.map(|mut cb| {
+                cb.schedule(start, values.len(), 0);
+            });
+        });
+    }
+}
+
+impl<'a> hil::i2c::I2CHwSlaveClient for I2CRegisterFile<'a> {
+    fn command_complete(
+        &self,
+        buffer: &'static mut [u8],
+        length: u8,
+        transmission_type: hil::i2c::SlaveTransmissionType,
+    ) {
+        match transmission_type {
+            hil::i2c::SlaveTransmissionType::Write => {
+                self.store_write(&buffer[..length as usize]);
+                self.rx_buffer.replace(buffer);
+                self.prepare_write();
+            }
+
+            hil::i2c::SlaveTransmissionType::Read => {
+                self.register.set(self.register.get().wrapping_add(length));
+                // The registers may change before the next read, so the
+                // transmit buffer is only filled once the master asks for it.
+                self.tx_buffer.replace(buffer);
+            }
+        }
+    }
+
+    fn read_expected(&self) {
+        self.prepare_read();
+    }
+
+    fn write_expected(&self) {
+        self.prepare_write();
+    }
+}
+
+impl<'a> Driver for I2CRegisterFile<'a> {
+    fn allow(
+        &self,
+        _appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        match allow_num {
+            // The register file
+            0 => {
+                self.app.map(|app| {
+                    app.registers = slice;
+                });
+                ReturnCode::SUCCESS
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        _app_id: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            // Registers written by the master
+            0 => {
+                self.app.map(|app| {
+                    app.callback = callback;
+                });
+                ReturnCode::SUCCESS
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    fn command(&self, command_num: usize, data: usize, _: usize, _: AppId) -> ReturnCode {
+        match command_num {
+            0 /* check if present */ => ReturnCode::SUCCESS,
+
+            // Listen on a slave address
+            1 => {
+                // We do not count the R/W bit as part of the address, so the
+                // valid range is 0x00-0x7f
+                if data > 0x7f {
+                    return ReturnCode::EINVAL;
+                }
+                self.register.set(0);
+                self.i2c.set_address(data as u8);
+                self.prepare_write();
+                self.i2c.enable();
+                self.i2c.listen();
+                ReturnCode::SUCCESS
+            }
+
+            // Stop listening
+            2 => {
+                self.i2c.disable();
+                ReturnCode::SUCCESS
+            }
+
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
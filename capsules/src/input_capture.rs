@@ -0,0 +1,189 @@
+//! Provides userspace with frequency and pulse width measurements of a
+//! digital signal.
+//!
+//! The edges of the signal are timestamped in hardware by the underlying
+//! `InputCapture` implementation, so measurements are not skewed by interrupt
+//! latency. Results are converted to microseconds before being delivered.
+//!
+//! There is a single signal, so processes share the measurements: one
+//! measurement is delivered to every process that asked for it, and the
+//! signal is measured again as long as one of them measures continuously.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let input_capture = static_init!(
+//!     capsules::input_capture::InputCapture<'static, nrf5x::input_capture::InputCapture>,
+//!     capsules::input_capture::InputCapture::new(&nrf5x::input_capture::INPUT_CAPTURE,
+//!                                                kernel::Grant::create()));
+//! hil::input_capture::InputCapture::set_client(&nrf5x::input_capture::INPUT_CAPTURE,
+//!                                              input_capture);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Take a single measurement.
+//! - `2`: Measure continuously, reporting every period until stopped.
+//! - `3`: Stop measuring.
+//!
+//! ### Subscribe
+//!
+//! - `0`: Set callback for measurements. The callback receives the signal
+//!   period and the time the signal was high, both in microseconds. Each
+//!   process has its own.
+
+use core::cell::Cell;
+use kernel::hil::input_capture;
+use kernel::hil::time::Frequency;
+use kernel::{AppId, Callback, Driver, Grant, ReturnCode};
+
+/// Syscall driver number.
+pub const DRIVER_NUM: usize = 0x00000008;
+
+#[derive(Copy, Clone, PartialEq)]
+enum Request {
+    None,
+    Single,
+    Continuous,
+}
+
+impl Default for Request {
+    fn default() -> Request {
+        Request::None
+    }
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    request: Request,
+}
+
+pub struct InputCapture<'a, I: input_capture::InputCapture + 'a> {
+    capture: &'a I,
+    apps: Grant<App>,
+    /// A measurement is in progress
+    measuring: Cell<bool>,
+}
+
+impl<'a, I: input_capture::InputCapture> InputCapture<'a, I> {
+    pub fn new(capture: &'a I, grant: Grant<App>) -> InputCapture<'a, I> {
+        InputCapture {
+            capture: capture,
+            apps: grant,
+            measuring: Cell::new(false),
+        }
+    }
+
+    fn start(&self, request: Request, appid: AppId) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                // A measurement in progress is delivered to this process too
+                if !self.measuring.get() {
+                    let rc = self.capture.measure();
+                    if rc != ReturnCode::SUCCESS {
+                        return rc;
+                    }
+                    self.measuring.set(true);
+                }
+                app.request = request;
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn stop(&self, appid: AppId) -> ReturnCode {
+        let rc = self.apps
+            .enter(appid, |app, _| {
+                app.request = Request::None;
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into());
+        if self.measuring.get() && !self.requested() {
+            self.measuring.set(false);
+            self.capture.stop();
+        }
+        rc
+    }
+
+    /// Whether any process waits for a measurement
+    fn requested(&self) -> bool {
+        let requested = Cell::new(false);
+        self.apps.each(|app| {
+            if app.request != Request::None {
+                requested.set(true);
+            }
+        });
+        requested.get()
+    }
+
+    fn tics_to_us(tics: u32) -> usize {
+        (tics as u64 * 1_000_000 / <I::Frequency>::frequency() as u64) as usize
+    }
+}
+
+impl<'a, I: input_capture::InputCapture> input_capture::Client for InputCapture<'a, I> {
+    fn measurement_done(&self, period: u32, high_time: u32) {
+        let period_us = Self::tics_to_us(period);
+        let high_us = Self::tics_to_us(high_time);
+        let continuous = Cell::new(false);
+        self.apps.each(|app| {
+            match app.request {
+                Request::None => return,
+                Request::Single => app.request = Request::None,
+                Request::Continuous => continuous.set(true),
+            }
+            app.callback.map(|mut cb| cb.schedule(period_us, high_us, 0));
+        });
+        self.measuring.set(continuous.get() && self.capture.measure() == ReturnCode::SUCCESS);
+    }
+}
+
+impl<'a, I: input_capture::InputCapture> Driver for InputCapture<'a, I> {
+    /// Subscribe to measurement results.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Subscribe to measurements. The callback signature is
+    ///        `fn(period_us: usize, high_us: usize)`
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self.apps
+                .enter(app_id, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Control measurements.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Single measurement.
+    /// - `2`: Continuous measurement.
+    /// - `3`: Stop. The signal is still measured for other processes that
+    ///        asked for it.
+    fn command(&self, command_num: usize, _: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self.start(Request::Single, appid),
+            2 => self.start(Request::Continuous, appid),
+            3 => self.stop(appid),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
@@ -17,28 +17,36 @@ pub mod button;
 pub mod console;
 pub mod crc;
 pub mod dac;
+pub mod debounce;
 pub mod fm25cl;
 pub mod fxos8700cq;
 pub mod gpio;
 pub mod gpio_async;
 pub mod i2c_master_slave_driver;
+pub mod i2c_register_file;
+pub mod input_capture;
 pub mod isl29035;
 pub mod led;
 pub mod lps25hb;
 pub mod ltc294x;
 pub mod max17205;
 pub mod mcp23008;
+pub mod memory_usage;
 pub mod ninedof;
 pub mod nonvolatile_storage_driver;
 pub mod nonvolatile_to_pages;
 pub mod nrf51822_serialization;
 pub mod pca9544a;
+pub mod process_console;
+pub mod pwm;
 pub mod rf233;
 pub mod rf233_const;
+pub mod reset;
 pub mod rng;
 pub mod sdcard;
 pub mod si7021;
 pub mod spi;
+pub mod spi_bitbang;
 pub mod tmp006;
 pub mod tsl2561;
 pub mod usb;
@@ -47,7 +55,9 @@ pub mod usbc_client;
 pub mod virtual_alarm;
 pub mod virtual_flash;
 pub mod virtual_i2c;
+pub mod virtual_rng;
 pub mod virtual_spi;
+pub mod virtual_uart;
 #[macro_use]
 pub mod net;
 pub mod aes_ccm;
@@ -0,0 +1,149 @@
+//! Report how much flash and RAM the kernel and the applications use.
+//!
+//! The board fills in a `MemoryUsage` from the symbols of the linker script
+//! and the loaded processes, prints it at boot with `print`, and gives it to
+//! the `MemoryUsageDriver` so applications can read it too. On parts with
+//! 128 or 256 kB of flash and 16 or 32 kB of RAM this shows how much room is
+//! left for the kernel and for applications without external tools.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let memory_usage = static_init!(
+//!     capsules::memory_usage::MemoryUsageDriver,
+//!     capsules::memory_usage::MemoryUsageDriver::new()
+//! );
+//! ...
+//! kernel::process::load_processes(...);
+//! let usage = capsules::memory_usage::MemoryUsage {
+//!     kernel_flash: &_etext as *const u8 as usize - &_stext as *const u8 as usize
+//!         + data,
+//!     ...
+//! };
+//! usage.print();
+//! memory_usage.set(usage);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Command
+//!
+//! All sizes are in bytes.
+//!
+//! #### `command_num`
+//!
+//! - `0`: Driver check.
+//! - `1`: Flash used by the kernel, including the initial values of its data.
+//! - `2`: Flash reserved for the kernel.
+//! - `3`: RAM of the kernel stack.
+//! - `4`: RAM of the initialized kernel data.
+//! - `5`: RAM of the zero-initialized kernel data.
+//! - `6`: RAM reserved for the kernel.
+//! - `7`: Flash used by the loaded applications.
+//! - `8`: Flash reserved for applications.
+//! - `9`: RAM given to the loaded applications.
+//! - `10`: RAM reserved for applications.
+
+use core::cell::Cell;
+use kernel::{AppId, Driver, ReturnCode};
+
+/// Syscall driver number.
+pub const DRIVER_NUM: usize = 0x90001;
+
+/// Memory usage, in bytes
+#[derive(Copy, Clone, Default)]
+pub struct MemoryUsage {
+    pub kernel_flash: usize,
+    pub kernel_flash_size: usize,
+    pub stack: usize,
+    pub data: usize,
+    pub bss: usize,
+    /// RAM outside of the application memory
+    pub kernel_ram_size: usize,
+    pub app_flash: usize,
+    pub app_flash_size: usize,
+    pub app_ram: usize,
+    pub app_ram_size: usize,
+}
+
+impl MemoryUsage {
+    /// Print the usage with `debug!`
+    pub fn print(&self) {
+        let kernel_ram = self.stack + self.data + self.bss;
+        debug!(
+            "Kernel flash: {} of {} bytes, {} free",
+            self.kernel_flash,
+            self.kernel_flash_size,
+            self.kernel_flash_size.saturating_sub(self.kernel_flash)
+        );
+        debug!(
+            "Kernel RAM: {} of {} bytes (stack {}, data {}, bss {}), {} free",
+            kernel_ram,
+            self.kernel_ram_size,
+            self.stack,
+            self.data,
+            self.bss,
+            self.kernel_ram_size.saturating_sub(kernel_ram)
+        );
+        debug!(
+            "App flash: {} of {} bytes, {} free",
+            self.app_flash,
+            self.app_flash_size,
+            self.app_flash_size.saturating_sub(self.app_flash)
+        );
+        debug!(
+            "App RAM: {} of {} bytes, {} free",
+            self.app_ram,
+            self.app_ram_size,
+            self.app_ram_size.saturating_sub(self.app_ram)
+        );
+    }
+}
+
+pub struct MemoryUsageDriver {
+    usage: Cell<MemoryUsage>,
+}
+
+impl MemoryUsageDriver {
+    pub fn new() -> MemoryUsageDriver {
+        MemoryUsageDriver {
+            usage: Cell::new(MemoryUsage::default()),
+        }
+    }
+
+    /// Set the usage reported to applications, once the processes are
+    /// loaded.
+    pub fn set(&self, usage: MemoryUsage) {
+        self.usage.set(usage);
+    }
+}
+
+impl Driver for MemoryUsageDriver {
+    /// Read the memory usage.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`-`10`: One of the sizes of `MemoryUsage`, see the module
+    ///   documentation.
+    fn command(&self, command_num: usize, _: usize, _: usize, _: AppId) -> ReturnCode {
+        let usage = self.usage.get();
+        let value = match command_num {
+            0 => return ReturnCode::SUCCESS,
+            1 => usage.kernel_flash,
+            2 => usage.kernel_flash_size,
+            3 => usage.stack,
+            4 => usage.data,
+            5 => usage.bss,
+            6 => usage.kernel_ram_size,
+            7 => usage.app_flash,
+            8 => usage.app_flash_size,
+            9 => usage.app_ram,
+            10 => usage.app_ram_size,
+            _ => return ReturnCode::ENOSUPPORT,
+        };
+        ReturnCode::SuccessWithValue { value: value }
+    }
+}
@@ -0,0 +1,330 @@
+//! Text console for pausing and inspecting processes.
+//!
+//! A primitive debugger for app developers without SWD access. It reads
+//! commands, one per line, from a UART of its own:
+//!
+//! - `list`: the processes, with their number, whether they are paused and
+//!   whether they were stopped by a fault
+//! - `stop <process>`: stop scheduling a process
+//! - `start <process>`: schedule a stopped process again
+//! - `break <process>`: stop a process at its next syscall and print the
+//!   syscall and its arguments
+//! - `memory <process>`: the RAM and flash of a process, and how much of its
+//!   RAM the process and the kernel grants use
+//! - `help`: the list of commands
+//!
+//! A process is named by its package name or its number. Callbacks for a
+//! stopped process stay queued and run once it is started again. A process
+//! that faulted, with the board's fault response set to `Stop`, cannot be
+//! started again.
+//!
+//! On a board with a single serial port the process console shares it with
+//! the console through a `MuxUart`. Both receive what is typed, so input
+//! meant for an application reading from the console also reaches the
+//! process console, which echoes it and reports it as unknown commands.
+//! Such boards should only set up the process console when asked to, as the
+//! nRF52 DKs do with their `process_console` feature.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let process_console = static_init!(
+//!     capsules::process_console::ProcessConsole<usart::USART>,
+//!     capsules::process_console::ProcessConsole::new(
+//!         &usart::USART3,
+//!         115200,
+//!         &mut capsules::process_console::WRITE_BUF,
+//!         &mut capsules::process_console::QUEUE_BUF,
+//!         &mut capsules::process_console::READ_BUF,
+//!         &mut capsules::process_console::COMMAND_BUF
+//!     )
+//! );
+//! hil::uart::UART::set_client(&usart::USART3, process_console);
+//! kernel::process::set_debug_client(process_console);
+//! process_console.start();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use core::fmt;
+use core::fmt::Write;
+use core::str;
+use kernel::common::take_cell::TakeCell;
+use kernel::hil::uart::{self, Client, UART};
+use kernel::process;
+use kernel::{AppId, ReturnCode};
+
+pub static mut WRITE_BUF: [u8; 128] = [0; 128];
+pub static mut QUEUE_BUF: [u8; 256] = [0; 256];
+pub static mut READ_BUF: [u8; 1] = [0; 1];
+pub static mut COMMAND_BUF: [u8; 32] = [0; 32];
+
+const PROMPT: &'static str = "tock$ ";
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7F;
+
+/// Appends formatted text to the output queue, dropping what does not fit.
+struct QueueWriter<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+}
+
+impl<'b> fmt::Write for QueueWriter<'b> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = cmp::min(s.len(), self.buf.len() - self.len);
+        self.buf[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+pub struct ProcessConsole<'a, U: UART + 'a> {
+    uart: &'a U,
+    baud_rate: u32,
+    /// Held by the UART while a transmission is in progress
+    tx_buffer: TakeCell<'static, [u8]>,
+    /// Output waiting to be transmitted
+    queue: TakeCell<'static, [u8]>,
+    queue_len: Cell<usize>,
+    rx_buffer: TakeCell<'static, [u8]>,
+    /// The line being typed
+    command: TakeCell<'static, [u8]>,
+    command_len: Cell<usize>,
+}
+
+impl<'a, U: UART> ProcessConsole<'a, U> {
+    pub fn new(
+        uart: &'a U,
+        baud_rate: u32,
+        tx_buffer: &'static mut [u8],
+        queue_buffer: &'static mut [u8],
+        rx_buffer: &'static mut [u8],
+        command_buffer: &'static mut [u8],
+    ) -> ProcessConsole<'a, U> {
+        ProcessConsole {
+            uart: uart,
+            baud_rate: baud_rate,
+            tx_buffer: TakeCell::new(tx_buffer),
+            queue: TakeCell::new(queue_buffer),
+            queue_len: Cell::new(0),
+            rx_buffer: TakeCell::new(rx_buffer),
+            command: TakeCell::new(command_buffer),
+            command_len: Cell::new(0),
+        }
+    }
+
+    pub fn start(&self) {
+        self.uart.init(uart::UARTParams {
+            baud_rate: self.baud_rate,
+            stop_bits: uart::StopBits::One,
+            parity: uart::Parity::None,
+            hw_flow_control: false,
+        });
+        self.print(format_args!("\r\n{}", PROMPT));
+        self.rx_buffer.take().map(|buffer| {
+            self.uart.receive(buffer, 1);
+        });
+    }
+
+    fn print(&self, args: fmt::Arguments) {
+        self.queue.map(|queue| {
+            let mut writer = QueueWriter {
+                buf: queue,
+                len: self.queue_len.get(),
+            };
+            let _ = writer.write_fmt(args);
+            self.queue_len.set(writer.len);
+        });
+        self.flush();
+    }
+
+    /// Start transmitting the queued output, unless a transmission is
+    /// already in progress.
+    fn flush(&self) {
+        let queued = self.queue_len.get();
+        if queued == 0 {
+            return;
+        }
+        self.tx_buffer.take().map(|buffer| {
+            self.queue.map(move |queue| {
+                let len = cmp::min(queued, buffer.len());
+                buffer[..len].copy_from_slice(&queue[..len]);
+                // Move what did not fit to the front of the queue
+                for i in len..queued {
+                    queue[i - len] = queue[i];
+                }
+                self.queue_len.set(queued - len);
+                self.uart.transmit(buffer, len);
+            });
+        });
+    }
+
+    /// The process named by `name`, either its package name or its number.
+    fn find_process(name: &str) -> Option<AppId> {
+        (0..process::num_processes())
+            .map(AppId::new)
+            .find(|&appid| match process::package_name(appid) {
+                Some(package_name) => {
+                    package_name == name || name.parse::<usize>() == Ok(appid.idx())
+                }
+                None => false,
+            })
+    }
+
+    fn list(&self) {
+        for i in 0..process::num_processes() {
+            let appid = AppId::new(i);
+            process::package_name(appid).map(|name| {
+                let state = if process::state(appid) == Some(process::State::Fault) {
+                    " (faulted)"
+                } else if process::is_paused(appid) {
+                    " (stopped)"
+                } else {
+                    ""
+                };
+                self.print(format_args!("  {} {}{}\r\n", i, name, state));
+            });
+        }
+    }
+
+    fn memory(&self, appid: AppId) {
+        match process::memory_usage(appid) {
+            Some(usage) => self.print(format_args!(
+                "  RAM {} bytes: app {}, grants {}, free {}\r\n  Flash {} bytes\r\n",
+                usage.ram,
+                usage.app,
+                usage.grants,
+                usage.ram - usage.app - usage.grants,
+                usage.flash
+            )),
+            None => self.print(format_args!("No such process\r\n")),
+        }
+    }
+
+    fn execute(&self, line: &str) {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return,
+        };
+        let target = words.next();
+
+        let action: fn(AppId) -> ReturnCode = match command {
+            "list" => return self.list(),
+            "stop" => process::pause,
+            "start" => process::resume,
+            "break" => process::break_on_next_syscall,
+            "memory" => {
+                match target.and_then(ProcessConsole::<U>::find_process) {
+                    Some(appid) => self.memory(appid),
+                    None => self.print(format_args!("No such process\r\n")),
+                }
+                return;
+            }
+            "help" => {
+                self.print(format_args!(
+                    "Commands: list, stop <process>, start <process>, break <process>, \
+                     memory <process>\r\n"
+                ));
+                return;
+            }
+            _ => {
+                self.print(format_args!("Unknown command: {}\r\n", command));
+                return;
+            }
+        };
+
+        match target.and_then(ProcessConsole::<U>::find_process) {
+            Some(appid) if command == "start"
+                && process::state(appid) == Some(process::State::Fault) =>
+            {
+                self.print(format_args!("Process faulted, cannot be started\r\n"));
+            }
+            Some(appid) => match action(appid) {
+                ReturnCode::SUCCESS => {}
+                ReturnCode::EALREADY => {
+                    self.print(format_args!("Nothing to do\r\n"));
+                }
+                rc => self.print(format_args!("Failed: {:?}\r\n", rc)),
+            },
+            None => self.print(format_args!("No such process\r\n")),
+        }
+    }
+
+    fn handle_char(&self, c: u8) {
+        match c {
+            b'\r' | b'\n' => {
+                self.print(format_args!("\r\n"));
+                let len = self.command_len.get();
+                self.command_len.set(0);
+                // Copied out of the buffer so that `execute` may print
+                let mut line = [0; 32];
+                let len = self.command.map_or(0, |command| {
+                    let len = cmp::min(len, line.len());
+                    line[..len].copy_from_slice(&command[..len]);
+                    len
+                });
+                if let Ok(line) = str::from_utf8(&line[..len]) {
+                    self.execute(line);
+                }
+                self.print(format_args!("{}", PROMPT));
+            }
+            BACKSPACE | DELETE => {
+                let len = self.command_len.get();
+                if len > 0 {
+                    self.command_len.set(len - 1);
+                    self.print(format_args!("\x08 \x08"));
+                }
+            }
+            0x20...0x7E => {
+                let len = self.command_len.get();
+                let stored = self.command.map_or(false, |command| {
+                    if len < command.len() {
+                        command[len] = c;
+                        true
+                    } else {
+                        false
+                    }
+                });
+                if stored {
+                    self.command_len.set(len + 1);
+                    self.print(format_args!("{}", c as char));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<'a, U: UART> Client for ProcessConsole<'a, U> {
+    fn transmit_complete(&self, buffer: &'static mut [u8], _error: uart::Error) {
+        self.tx_buffer.replace(buffer);
+        self.flush();
+    }
+
+    fn receive_complete(&self, buffer: &'static mut [u8], rx_len: usize, error: uart::Error) {
+        if rx_len > 0 && error == uart::Error::CommandComplete {
+            self.handle_char(buffer[0]);
+        }
+        self.uart.receive(buffer, 1);
+    }
+}
+
+impl<'a, U: UART> process::ProcessDebugClient for ProcessConsole<'a, U> {
+    fn syscall_break(&self, appid: AppId, syscall: process::SyscallBreak) {
+        let name = process::package_name(appid).unwrap_or("?");
+        self.print(format_args!(
+            "\r\n{} stopped at {:?}: r0 {:#x} r1 {:#x} r2 {:#x} r3 {:#x} pc {:#x}\r\n{}",
+            name,
+            syscall.syscall,
+            syscall.r0,
+            syscall.r1,
+            syscall.r2,
+            syscall.r3,
+            syscall.pc,
+            PROMPT
+        ));
+    }
+}
@@ -0,0 +1,121 @@
+//! Provides userspace access to PWM outputs, to dim LEDs or drive servos.
+//!
+//! All channels share a frequency, set with command 1, as the channels of a
+//! PWM peripheral commonly do in hardware. Changing it while channels are
+//! running fails with `EBUSY` until they are all stopped.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let pwm = static_init!(
+//!     capsules::pwm::PwmDriver<'static, nrf52::pwm::Pwm>,
+//!     capsules::pwm::PwmDriver::new(&nrf52::pwm::PWM0));
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Command
+//!
+//! All PWM operations are synchronous, so this capsule only uses the
+//! `command` syscall.
+//!
+//! #### `command_num`
+//!
+//! - `0`: Return the number of PWM channels on this platform.
+//! - `1`: Set the frequency channels are started at, `data` in Hz. The
+//!   default is 1 kHz; a servo typically wants 50 Hz.
+//!   - Return: `EINVAL` if above the maximum frequency, `EBUSY` if channels
+//!     are running at another frequency.
+//! - `2`: Start channel `data`, or change its duty cycle if already running.
+//!   The output is high for the second argument out of the maximum duty
+//!   cycle (see command 4) of each period.
+//!   - Return: `SUCCESS` if the channel was started, `EINVAL` if the channel
+//!     or duty cycle is invalid.
+//! - `3`: Stop channel `data`, its output going back to its idle level.
+//!   - Return: `SUCCESS`, or `EALREADY` if the channel was not running.
+//! - `4`: Return the maximum duty cycle, for an output that is always high.
+//! - `5`: Return the maximum frequency in Hz.
+
+use core::cell::Cell;
+use kernel::hil;
+use kernel::{AppId, Driver, ReturnCode};
+
+/// Syscall driver number.
+pub const DRIVER_NUM: usize = 0x00000009;
+
+const DEFAULT_FREQUENCY_HZ: usize = 1000;
+
+pub struct PwmDriver<'a, P: hil::pwm::Pwm + 'a> {
+    pwm: &'a P,
+    frequency_hz: Cell<usize>,
+    /// Channels started by apps, as a bitmask
+    running: Cell<usize>,
+}
+
+impl<'a, P: hil::pwm::Pwm> PwmDriver<'a, P> {
+    pub fn new(pwm: &'a P) -> PwmDriver<'a, P> {
+        PwmDriver {
+            pwm: pwm,
+            frequency_hz: Cell::new(DEFAULT_FREQUENCY_HZ),
+            running: Cell::new(0),
+        }
+    }
+
+    fn set_frequency(&self, frequency_hz: usize) -> ReturnCode {
+        if frequency_hz == 0 || frequency_hz > self.pwm.get_maximum_frequency_hz() {
+            ReturnCode::EINVAL
+        } else if self.running.get() != 0 && frequency_hz != self.frequency_hz.get() {
+            ReturnCode::EBUSY
+        } else {
+            self.frequency_hz.set(frequency_hz);
+            ReturnCode::SUCCESS
+        }
+    }
+
+    fn start(&self, channel: usize, duty_cycle: usize) -> ReturnCode {
+        if channel >= self.pwm.channels() {
+            return ReturnCode::EINVAL;
+        }
+        let result = self.pwm.start(channel, self.frequency_hz.get(), duty_cycle);
+        if result == ReturnCode::SUCCESS {
+            self.running.set(self.running.get() | 1 << channel);
+        }
+        result
+    }
+
+    fn stop(&self, channel: usize) -> ReturnCode {
+        if channel >= self.pwm.channels() {
+            return ReturnCode::EINVAL;
+        }
+        self.running.set(self.running.get() & !(1 << channel));
+        self.pwm.stop(channel)
+    }
+}
+
+impl<'a, P: hil::pwm::Pwm> Driver for PwmDriver<'a, P> {
+    fn command(&self, command_num: usize, data: usize, data2: usize, _: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SuccessWithValue {
+                value: self.pwm.channels(),
+            },
+
+            1 => self.set_frequency(data),
+
+            2 => self.start(data, data2),
+
+            3 => self.stop(data),
+
+            4 => ReturnCode::SuccessWithValue {
+                value: self.pwm.get_maximum_duty_cycle(),
+            },
+
+            5 => ReturnCode::SuccessWithValue {
+                value: self.pwm.get_maximum_frequency_hz(),
+            },
+
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
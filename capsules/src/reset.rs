@@ -0,0 +1,60 @@
+//! Provides userspace with a way to reset the chip.
+//!
+//! Resetting restarts the kernel and every application, so a board should
+//! only expose this driver when its applications are trusted with it.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let reset = static_init!(
+//!     capsules::reset::Reset<'static, nrf5x::power::Power>,
+//!     capsules::reset::Reset::new(&nrf5x::power::POWER)
+//! );
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Command
+//!
+//! #### `command_num`
+//!
+//! - `0`: Driver check.
+//! - `1`: Reset the chip. Does not return.
+//! - `2`: Reset the chip into its bootloader, to load new firmware. Does not
+//!   return.
+
+use kernel::hil;
+use kernel::{AppId, Driver, ReturnCode};
+
+/// Syscall driver number.
+pub const DRIVER_NUM: usize = 0x90000;
+
+pub struct Reset<'a, R: hil::reset::Reset + 'a> {
+    reset: &'a R,
+}
+
+impl<'a, R: hil::reset::Reset> Reset<'a, R> {
+    pub fn new(reset: &'a R) -> Reset<'a, R> {
+        Reset { reset: reset }
+    }
+}
+
+impl<'a, R: hil::reset::Reset> Driver for Reset<'a, R> {
+    /// Reset the chip.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Reset the chip.
+    /// - `2`: Reset the chip into its bootloader.
+    fn command(&self, command_num: usize, _: usize, _: usize, _: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self.reset.reset(),
+            2 => self.reset.reset_to_bootloader(),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
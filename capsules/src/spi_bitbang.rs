@@ -0,0 +1,367 @@
+//! SPI master over GPIO pins.
+//!
+//! Drives SCK and MOSI and samples MISO in software, for SPI devices wired to
+//! pins the SPI peripherals cannot be routed to. `read_write_bytes` is paced
+//! by an alarm, one alarm per clock edge, so the CPU is free between edges
+//! and the clock rate is at most half the alarm frequency: 16 kHz on a
+//! 32 kHz RTC. Set a lower rate with `set_rate`. The single byte operations
+//! (`write_byte`, `read_byte` and `read_write_byte`) block and clock the bits
+//! out as fast as the pins toggle, ignoring the rate.
+//!
+//! Bits are sent most significant first. The chip select is any GPIO pin,
+//! driven low for the duration of a transfer, see `hold_low`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let spi_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, Rtc>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let spi = static_init!(
+//!     capsules::spi_bitbang::SpiMasterBitBang<'static, VirtualMuxAlarm<'static, Rtc>>,
+//!     capsules::spi_bitbang::SpiMasterBitBang::new(
+//!         &nrf5x::gpio::PORT[12], // SCK
+//!         &nrf5x::gpio::PORT[13], // MOSI
+//!         &nrf5x::gpio::PORT[14], // MISO
+//!         spi_alarm
+//!     )
+//! );
+//! spi_alarm.set_client(spi);
+//! spi.init();
+//! spi.specify_chip_select(&nrf5x::gpio::PORT[15]);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::take_cell::TakeCell;
+use kernel::hil::gpio;
+use kernel::hil::spi::{self, ClockPhase, ClockPolarity, SpiMasterClient};
+use kernel::hil::time::{self, Alarm, Frequency};
+use kernel::ReturnCode;
+
+/// Clock rate until `set_rate` is called
+const DEFAULT_RATE: u32 = 1000;
+
+#[derive(Copy, Clone, PartialEq)]
+enum Edge {
+    Leading,
+    Trailing,
+}
+
+pub struct SpiMasterBitBang<'a, A: Alarm + 'a> {
+    sck: &'a gpio::Pin,
+    mosi: &'a gpio::Pin,
+    miso: &'a gpio::Pin,
+    alarm: &'a A,
+    chip_select: Cell<Option<&'a gpio::Pin>>,
+    client: Cell<Option<&'static SpiMasterClient>>,
+
+    polarity: Cell<ClockPolarity>,
+    phase: Cell<ClockPhase>,
+    /// Alarm ticks between clock edges
+    half_period: Cell<u32>,
+    hold_low: Cell<bool>,
+
+    /// Transfer in progress
+    busy: Cell<bool>,
+    write_buffer: TakeCell<'static, [u8]>,
+    read_buffer: TakeCell<'static, [u8]>,
+    len: Cell<usize>,
+    /// Byte and bit, from the most significant, being transferred
+    index: Cell<usize>,
+    bit: Cell<u8>,
+    /// Bits of the current byte read so far
+    read_byte: Cell<u8>,
+    next_edge: Cell<Edge>,
+}
+
+impl<'a, A: Alarm + 'a> SpiMasterBitBang<'a, A> {
+    pub fn new(
+        sck: &'a gpio::Pin,
+        mosi: &'a gpio::Pin,
+        miso: &'a gpio::Pin,
+        alarm: &'a A,
+    ) -> SpiMasterBitBang<'a, A> {
+        let spi = SpiMasterBitBang {
+            sck: sck,
+            mosi: mosi,
+            miso: miso,
+            alarm: alarm,
+            chip_select: Cell::new(None),
+            client: Cell::new(None),
+
+            polarity: Cell::new(ClockPolarity::IdleLow),
+            phase: Cell::new(ClockPhase::SampleLeading),
+            half_period: Cell::new(1),
+            hold_low: Cell::new(false),
+
+            busy: Cell::new(false),
+            write_buffer: TakeCell::empty(),
+            read_buffer: TakeCell::empty(),
+            len: Cell::new(0),
+            index: Cell::new(0),
+            bit: Cell::new(0),
+            read_byte: Cell::new(0),
+            next_edge: Cell::new(Edge::Leading),
+        };
+        spi.set_half_period(DEFAULT_RATE);
+        spi
+    }
+
+    /// Set the time between edges for the clock rate closest to, and not
+    /// above, `rate`. Returns the rate set.
+    fn set_half_period(&self, rate: u32) -> u32 {
+        let freq = <A::Frequency>::frequency() as u64;
+        let edges = cmp::max(1, 2 * rate as u64);
+        let half_period = cmp::max(1, (freq + edges - 1) / edges);
+        self.half_period.set(half_period as u32);
+        (freq / (2 * half_period)) as u32
+    }
+
+    fn sck_idle(&self) {
+        match self.polarity.get() {
+            ClockPolarity::IdleLow => self.sck.clear(),
+            ClockPolarity::IdleHigh => self.sck.set(),
+        }
+    }
+
+    fn sck_active(&self) {
+        match self.polarity.get() {
+            ClockPolarity::IdleLow => self.sck.set(),
+            ClockPolarity::IdleHigh => self.sck.clear(),
+        }
+    }
+
+    fn select(&self) {
+        self.chip_select.get().map(|cs| cs.clear());
+    }
+
+    fn deselect(&self) {
+        if !self.hold_low.get() {
+            self.chip_select.get().map(|cs| cs.set());
+        }
+    }
+
+    fn output_bit(&self, byte: u8, bit: u8) {
+        if byte & (0x80 >> bit) != 0 {
+            self.mosi.set();
+        } else {
+            self.mosi.clear();
+        }
+    }
+
+    /// Clock one byte in and out without pausing between edges
+    fn transfer_byte(&self, val: u8) -> u8 {
+        let mut read = 0;
+        for bit in 0..8 {
+            if self.phase.get() == ClockPhase::SampleLeading {
+                self.output_bit(val, bit);
+                self.sck_active();
+                read = (read << 1) | self.miso.read() as u8;
+                self.sck_idle();
+            } else {
+                self.sck_active();
+                self.output_bit(val, bit);
+                self.sck_idle();
+                read = (read << 1) | self.miso.read() as u8;
+            }
+        }
+        read
+    }
+
+    fn schedule_edge(&self) {
+        let now = self.alarm.now();
+        self.alarm
+            .set_alarm(now.wrapping_add(self.half_period.get()));
+    }
+
+    /// Put the current bit of the write buffer on MOSI
+    fn output_current_bit(&self) {
+        let index = self.index.get();
+        let bit = self.bit.get();
+        self.write_buffer.map(|buffer| self.output_bit(buffer[index], bit));
+    }
+
+    fn sample_bit(&self) {
+        self.read_byte
+            .set((self.read_byte.get() << 1) | self.miso.read() as u8);
+    }
+
+    /// Move on to the next bit. Returns false once all bits have been
+    /// transferred.
+    fn advance(&self) -> bool {
+        let bit = self.bit.get() + 1;
+        if bit < 8 {
+            self.bit.set(bit);
+            return true;
+        }
+
+        let index = self.index.get();
+        let read_byte = self.read_byte.get();
+        self.read_buffer.map(|buffer| buffer[index] = read_byte);
+        self.bit.set(0);
+        self.read_byte.set(0);
+        self.index.set(index + 1);
+        index + 1 < self.len.get()
+    }
+
+    fn finish(&self) {
+        self.deselect();
+        self.busy.set(false);
+        let len = self.len.get();
+        self.write_buffer.take().map(|write_buffer| {
+            let read_buffer = self.read_buffer.take();
+            self.client.get().map(move |client| {
+                client.read_write_done(write_buffer, read_buffer, len);
+            });
+        });
+    }
+}
+
+impl<'a, A: Alarm + 'a> time::Client for SpiMasterBitBang<'a, A> {
+    fn fired(&self) {
+        if !self.busy.get() {
+            return;
+        }
+
+        let sample_leading = self.phase.get() == ClockPhase::SampleLeading;
+        match self.next_edge.get() {
+            Edge::Leading => {
+                self.sck_active();
+                if sample_leading {
+                    self.sample_bit();
+                } else {
+                    self.output_current_bit();
+                }
+                self.next_edge.set(Edge::Trailing);
+            }
+            Edge::Trailing => {
+                self.sck_idle();
+                if !sample_leading {
+                    self.sample_bit();
+                }
+                if !self.advance() {
+                    self.finish();
+                    return;
+                }
+                if sample_leading {
+                    self.output_current_bit();
+                }
+                self.next_edge.set(Edge::Leading);
+            }
+        }
+        self.schedule_edge();
+    }
+}
+
+impl<'a, A: Alarm + 'a> spi::SpiMaster for SpiMasterBitBang<'a, A> {
+    type ChipSelect = &'a gpio::Pin;
+
+    fn set_client(&self, client: &'static SpiMasterClient) {
+        self.client.set(Some(client));
+    }
+
+    fn init(&self) {
+        self.sck.make_output();
+        self.sck_idle();
+        self.mosi.make_output();
+        self.mosi.clear();
+        self.miso.make_input();
+    }
+
+    fn is_busy(&self) -> bool {
+        self.busy.get()
+    }
+
+    fn read_write_bytes(
+        &self,
+        write_buffer: &'static mut [u8],
+        read_buffer: Option<&'static mut [u8]>,
+        len: usize,
+    ) -> ReturnCode {
+        if self.busy.get() {
+            return ReturnCode::EBUSY;
+        }
+
+        let mut count = cmp::min(len, write_buffer.len());
+        read_buffer
+            .as_ref()
+            .map(|buffer| count = cmp::min(count, buffer.len()));
+        if count == 0 {
+            return ReturnCode::EINVAL;
+        }
+
+        self.busy.set(true);
+        self.write_buffer.replace(write_buffer);
+        read_buffer.map(|buffer| self.read_buffer.replace(buffer));
+        self.len.set(count);
+        self.index.set(0);
+        self.bit.set(0);
+        self.read_byte.set(0);
+        self.next_edge.set(Edge::Leading);
+
+        self.sck_idle();
+        self.select();
+        if self.phase.get() == ClockPhase::SampleLeading {
+            self.output_current_bit();
+        }
+        self.schedule_edge();
+        ReturnCode::SUCCESS
+    }
+
+    fn write_byte(&self, val: u8) {
+        self.read_write_byte(val);
+    }
+
+    fn read_byte(&self) -> u8 {
+        self.read_write_byte(0)
+    }
+
+    fn read_write_byte(&self, val: u8) -> u8 {
+        self.select();
+        let read = self.transfer_byte(val);
+        self.deselect();
+        read
+    }
+
+    fn specify_chip_select(&self, cs: Self::ChipSelect) {
+        cs.make_output();
+        cs.set();
+        self.chip_select.set(Some(cs));
+    }
+
+    fn set_rate(&self, rate: u32) -> u32 {
+        self.set_half_period(rate)
+    }
+
+    fn get_rate(&self) -> u32 {
+        (<A::Frequency>::frequency() as u64 / (2 * self.half_period.get() as u64)) as u32
+    }
+
+    fn set_clock(&self, polarity: ClockPolarity) {
+        self.polarity.set(polarity);
+        self.sck_idle();
+    }
+
+    fn get_clock(&self) -> ClockPolarity {
+        self.polarity.get()
+    }
+
+    fn set_phase(&self, phase: ClockPhase) {
+        self.phase.set(phase);
+    }
+
+    fn get_phase(&self) -> ClockPhase {
+        self.phase.get()
+    }
+
+    fn hold_low(&self) {
+        self.hold_low.set(true);
+    }
+
+    fn release_low(&self) {
+        self.hold_low.set(false);
+    }
+}
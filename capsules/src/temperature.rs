@@ -42,7 +42,7 @@
 //! let temp = static_init!(
 //!        capsules::temperature::TemperatureSensor<'static>,
 //!        capsules::temperature::TemperatureSensor::new(si7021,
-//!                                                 kernel::Grant::create()), 96/8);
+//!                                                 kernel::Grant::create()));
 //! kernel::hil::sensors::TemperatureDriver::set_client(si7021, temp);
 //! ```
 
@@ -0,0 +1,195 @@
+//! Test the accuracy of an alarm
+//!
+//! Sets `count` one-shot alarms, one after the other, each `min_ms` to
+//! `max_ms` milliseconds after the alarm's current time. The interval is
+//! drawn from a pseudo-random sequence so the alarms land on every phase of
+//! the underlying counter. When an alarm fires, the time elapsed since it was
+//! set is read from a reference clock, a free running counter at a higher
+//! rate than the alarm (such as a hardware timer captured on the spot), and
+//! compared with the requested interval.
+//!
+//! The error of each alarm is how much later it fired than asked for. It
+//! includes the rounding of the interval to whole alarm ticks, the offset of
+//! the alarm counter within its current tick, interrupt latency and the time
+//! spent in other clients of a shared alarm, so a spread of one alarm tick is
+//! expected. Alarms that fire before their time on the alarm's own counter
+//! are counted separately. When all alarms have fired, the minimum, maximum
+//! and mean error and a histogram are printed with `debug!`.
+//!
+//! Several tests on virtual alarms of the same `MuxAlarm` can run at the same
+//! time to exercise the multiplexing.
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::hil::time::{self, Alarm, Frequency};
+
+/// A counter to measure an alarm against. It should count faster than the
+/// alarm and be read without delay.
+pub trait ReferenceClock {
+    type Frequency: Frequency;
+
+    /// Current value of the counter. Wraps around at 2^32.
+    fn now(&self) -> u32;
+}
+
+/// Upper bounds of the histogram buckets, in microseconds. The last bucket
+/// holds everything from 1024 us.
+const BUCKETS_US: [i32; 7] = [0, 32, 64, 128, 256, 512, 1024];
+
+#[derive(Copy, Clone)]
+struct Statistics {
+    count: u32,
+    min_us: i32,
+    max_us: i32,
+    sum_us: i64,
+    early: u32,
+    histogram: [u32; 8],
+}
+
+impl Statistics {
+    fn new() -> Statistics {
+        Statistics {
+            count: 0,
+            min_us: i32::max_value(),
+            max_us: i32::min_value(),
+            sum_us: 0,
+            early: 0,
+            histogram: [0; 8],
+        }
+    }
+
+    fn record(&mut self, error_us: i32, early: bool) {
+        self.count += 1;
+        self.min_us = cmp::min(self.min_us, error_us);
+        self.max_us = cmp::max(self.max_us, error_us);
+        self.sum_us += error_us as i64;
+        if early {
+            self.early += 1;
+        }
+        let bucket = BUCKETS_US
+            .iter()
+            .position(|bound| error_us < *bound)
+            .unwrap_or(BUCKETS_US.len());
+        self.histogram[bucket] += 1;
+    }
+}
+
+pub struct TestAlarmJitter<'a, A: Alarm + 'a, R: ReferenceClock + 'a> {
+    alarm: &'a A,
+    reference: &'a R,
+    count: u32,
+    min_ms: u32,
+    max_ms: u32,
+
+    /// Alarms left to set
+    remaining: Cell<u32>,
+    /// State of the pseudo-random interval sequence
+    seed: Cell<u32>,
+    /// Reference clock and alarm time of the alarm in progress
+    set_at: Cell<u32>,
+    when: Cell<u32>,
+    interval_us: Cell<u32>,
+    statistics: Cell<Statistics>,
+}
+
+impl<'a, A: Alarm + 'a, R: ReferenceClock + 'a> TestAlarmJitter<'a, A, R> {
+    pub fn new(
+        alarm: &'a A,
+        reference: &'a R,
+        count: u32,
+        min_ms: u32,
+        max_ms: u32,
+    ) -> TestAlarmJitter<'a, A, R> {
+        TestAlarmJitter {
+            alarm: alarm,
+            reference: reference,
+            count: count,
+            min_ms: min_ms,
+            max_ms: max_ms,
+
+            remaining: Cell::new(0),
+            seed: Cell::new(0),
+            set_at: Cell::new(0),
+            when: Cell::new(0),
+            interval_us: Cell::new(0),
+            statistics: Cell::new(Statistics::new()),
+        }
+    }
+
+    pub fn run(&self) {
+        debug!(
+            "Alarm jitter: {} alarms of {} to {} ms",
+            self.count, self.min_ms, self.max_ms
+        );
+        if self.count == 0 || self.max_ms < self.min_ms {
+            return;
+        }
+        self.remaining.set(self.count);
+        self.seed.set(self.min_ms ^ (self.max_ms << 16) ^ 0x5eed);
+        self.statistics.set(Statistics::new());
+        self.set_next();
+    }
+
+    fn next_interval_tics(&self) -> u32 {
+        // Numerical Recipes linear congruential generator
+        let seed = self.seed
+            .get()
+            .wrapping_mul(1664525)
+            .wrapping_add(1013904223);
+        self.seed.set(seed);
+
+        let freq = <A::Frequency>::frequency() as u64;
+        let min = self.min_ms as u64 * freq / 1000;
+        let max = self.max_ms as u64 * freq / 1000;
+        // The high bits of the generator are the random ones
+        (min + (seed >> 8) as u64 % (max - min + 1)) as u32
+    }
+
+    fn set_next(&self) {
+        let tics = self.next_interval_tics();
+        self.interval_us
+            .set((tics as u64 * 1000000 / <A::Frequency>::frequency() as u64) as u32);
+
+        let when = self.alarm.now().wrapping_add(tics);
+        self.set_at.set(self.reference.now());
+        self.when.set(when);
+        self.alarm.set_alarm(when);
+    }
+
+    fn report(&self) {
+        let statistics = self.statistics.get();
+        let h = statistics.histogram;
+        debug!(
+            "Alarm jitter: error min {} us, max {} us, mean {} us, {} fired early",
+            statistics.min_us,
+            statistics.max_us,
+            statistics.sum_us / statistics.count as i64,
+            statistics.early
+        );
+        debug!(
+            "Alarm jitter: <0: {}, <32: {}, <64: {}, <128: {}, <256: {}, <512: {}, <1024: {}, more: {}",
+            h[0], h[1], h[2], h[3], h[4], h[5], h[6], h[7]
+        );
+    }
+}
+
+impl<'a, A: Alarm + 'a, R: ReferenceClock + 'a> time::Client for TestAlarmJitter<'a, A, R> {
+    fn fired(&self) {
+        let elapsed = self.reference.now().wrapping_sub(self.set_at.get());
+        let elapsed_us = (elapsed as u64 * 1000000 / <R::Frequency>::frequency() as u64) as u32;
+        let error_us = elapsed_us.wrapping_sub(self.interval_us.get()) as i32;
+        let early = (self.alarm.now().wrapping_sub(self.when.get()) as i32) < 0;
+
+        let mut statistics = self.statistics.get();
+        statistics.record(error_us, early);
+        self.statistics.set(statistics);
+
+        let remaining = self.remaining.get() - 1;
+        self.remaining.set(remaining);
+        if remaining > 0 {
+            self.set_next();
+        } else {
+            self.report();
+        }
+    }
+}
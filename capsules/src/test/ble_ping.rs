@@ -0,0 +1,351 @@
+//! Measure the round-trip time of BLE packets between two boards
+//!
+//! One board runs a `BlePing` as the `Initiator`, the other as the
+//! `Responder`, on the same channel. Every `interval_ms` the initiator
+//! sends a ping, a non-connectable advertisement carrying a sequence number,
+//! and listens for the echo until the next ping is due. The responder
+//! listens all the time and answers every ping with an echo carrying the
+//! same sequence number and the time it took to turn the packet around, from
+//! its receive event to its start of transmission.
+//!
+//! Both ends timestamp their events with a `ReferenceClock` in microseconds.
+//! The round-trip time is the time from the start of the ping transmission
+//! to the reception of the echo on the initiator, less the turnaround time
+//! of the responder. It includes the time on air of both packets, the radio
+//! ramp-up on both sides and the interrupt latency of both kernels, which is
+//! what link-layer scheduling changes move.
+//!
+//! Pings without an echo before the next one are counted as lost, echoes
+//! with a bad CRC separately. Every `report_every` pings, and when all
+//! `count` pings have been sent, the initiator prints the statistics with
+//! `debug!`.
+//!
+//! The test takes over the radio: it must be set as the receive and transmit
+//! client of the radio in place of the BLE advertising driver, and no
+//! application should use the advertising driver while it runs.
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::take_cell::TakeCell;
+use kernel::hil::ble_advertising::{self, BleAdvertisementDriver, RadioChannel};
+use kernel::hil::time::{self, Alarm, Frequency};
+use kernel::ReturnCode;
+use test::alarm_jitter::ReferenceClock;
+
+pub const PACKET_LENGTH: usize = 19;
+
+/// Buffer for the pings or echoes
+pub static mut BUF: [u8; PACKET_LENGTH] = [0; PACKET_LENGTH];
+
+// ADV_NONCONN_IND with a random address (TxAdd)
+const PDU_HEADER: u8 = 0x02 | 1 << 6;
+const ADV_ADDRESS_LEN: usize = 6;
+// Manufacturer specific data of the company identifier reserved for tests
+const AD_LENGTH: u8 = 10;
+const AD_TYPE_MANUFACTURER: u8 = 0xff;
+const COMPANY_ID: u16 = 0xffff;
+const KIND_PING: u8 = b'P';
+const KIND_ECHO: u8 = b'E';
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Role {
+    Initiator,
+    Responder,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum State {
+    Idle,
+    Sending,
+    Listening,
+}
+
+#[derive(Copy, Clone)]
+struct Statistics {
+    sent: u32,
+    received: u32,
+    crc_errors: u32,
+    min_us: u32,
+    max_us: u32,
+    sum_us: u64,
+    sum_turnaround_us: u64,
+}
+
+impl Statistics {
+    fn new() -> Statistics {
+        Statistics {
+            sent: 0,
+            received: 0,
+            crc_errors: 0,
+            min_us: u32::max_value(),
+            max_us: 0,
+            sum_us: 0,
+            sum_turnaround_us: 0,
+        }
+    }
+
+    fn record(&mut self, rtt_us: u32, turnaround_us: u32) {
+        self.received += 1;
+        self.min_us = cmp::min(self.min_us, rtt_us);
+        self.max_us = cmp::max(self.max_us, rtt_us);
+        self.sum_us += rtt_us as u64;
+        self.sum_turnaround_us += turnaround_us as u64;
+    }
+}
+
+pub struct BlePing<'a, B, A, R>
+where
+    B: BleAdvertisementDriver + 'a,
+    A: Alarm + 'a,
+    R: ReferenceClock + 'a,
+{
+    radio: &'a B,
+    alarm: &'a A,
+    reference: &'a R,
+    buffer: TakeCell<'static, [u8]>,
+    role: Role,
+    channel: RadioChannel,
+    address: [u8; ADV_ADDRESS_LEN],
+    interval_ms: u32,
+    count: u32,
+    report_every: u32,
+
+    state: Cell<State>,
+    sequence: Cell<u16>,
+    /// Reference clock at the start of the last ping
+    sent_at: Cell<u32>,
+    /// An echo of the last ping arrived
+    answered: Cell<bool>,
+    statistics: Cell<Statistics>,
+}
+
+impl<'a, B, A, R> BlePing<'a, B, A, R>
+where
+    B: BleAdvertisementDriver + 'a,
+    A: Alarm + 'a,
+    R: ReferenceClock + 'a,
+{
+    pub fn new(
+        radio: &'a B,
+        alarm: &'a A,
+        reference: &'a R,
+        buffer: &'static mut [u8],
+        role: Role,
+        channel: RadioChannel,
+        address: [u8; ADV_ADDRESS_LEN],
+        interval_ms: u32,
+        count: u32,
+        report_every: u32,
+    ) -> BlePing<'a, B, A, R> {
+        BlePing {
+            radio: radio,
+            alarm: alarm,
+            reference: reference,
+            buffer: TakeCell::new(buffer),
+            role: role,
+            channel: channel,
+            address: address,
+            interval_ms: interval_ms,
+            count: count,
+            report_every: report_every,
+
+            state: Cell::new(State::Idle),
+            sequence: Cell::new(0),
+            sent_at: Cell::new(0),
+            answered: Cell::new(true),
+            statistics: Cell::new(Statistics::new()),
+        }
+    }
+
+    pub fn run(&self) {
+        debug!(
+            "BLE ping: {:?} on channel {}",
+            self.role,
+            self.channel.get_channel_index()
+        );
+        self.statistics.set(Statistics::new());
+        match self.role {
+            Role::Initiator => {
+                if self.count > 0 {
+                    self.set_next_alarm();
+                }
+            }
+            Role::Responder => self.listen(),
+        }
+    }
+
+    fn set_next_alarm(&self) {
+        let tics = self.interval_ms as u64 * <A::Frequency>::frequency() as u64 / 1000;
+        self.alarm
+            .set_alarm(self.alarm.now().wrapping_add(cmp::max(tics, 1) as u32));
+    }
+
+    fn elapsed_us(&self, since: u32) -> u32 {
+        let elapsed = self.reference.now().wrapping_sub(since);
+        (elapsed as u64 * 1000000 / <R::Frequency>::frequency() as u64) as u32
+    }
+
+    fn listen(&self) {
+        self.state.set(State::Listening);
+        self.radio.receive_advertisement(self.channel);
+    }
+
+    fn send(&self, kind: u8, sequence: u16, turnaround_us: u32) -> ReturnCode {
+        self.buffer
+            .take()
+            .map_or(ReturnCode::EBUSY, |buf| {
+                buf[0] = PDU_HEADER;
+                buf[1] = (PACKET_LENGTH - 2) as u8;
+                buf[2..8].copy_from_slice(&self.address);
+                buf[8] = AD_LENGTH;
+                buf[9] = AD_TYPE_MANUFACTURER;
+                buf[10] = COMPANY_ID as u8;
+                buf[11] = (COMPANY_ID >> 8) as u8;
+                buf[12] = kind;
+                buf[13] = sequence as u8;
+                buf[14] = (sequence >> 8) as u8;
+                for i in 0..4 {
+                    buf[15 + i] = (turnaround_us >> (8 * i)) as u8;
+                }
+                self.state.set(State::Sending);
+                let buf = self.radio
+                    .transmit_advertisement(buf, PACKET_LENGTH, self.channel);
+                self.buffer.replace(buf);
+                ReturnCode::SUCCESS
+            })
+    }
+
+    /// The kind, sequence number and turnaround time of a packet of this
+    /// test
+    fn parse(buf: &[u8], len: usize) -> Option<(u8, u16, u32)> {
+        if len < PACKET_LENGTH || buf.len() < PACKET_LENGTH
+            || buf[0] & 0x0f != PDU_HEADER & 0x0f
+            || buf[1] as usize != PACKET_LENGTH - 2 || buf[8] != AD_LENGTH
+            || buf[9] != AD_TYPE_MANUFACTURER
+            || (buf[10] as u16 | (buf[11] as u16) << 8) != COMPANY_ID
+        {
+            return None;
+        }
+        let sequence = buf[13] as u16 | (buf[14] as u16) << 8;
+        let turnaround_us = buf[15] as u32 | (buf[16] as u32) << 8 | (buf[17] as u32) << 16
+            | (buf[18] as u32) << 24;
+        Some((buf[12], sequence, turnaround_us))
+    }
+
+    fn report(&self) {
+        let statistics = self.statistics.get();
+        let lost = statistics.sent - statistics.received;
+        if statistics.received == 0 {
+            debug!(
+                "BLE ping: {} sent, none answered, {} CRC errors",
+                statistics.sent, statistics.crc_errors
+            );
+            return;
+        }
+        debug!(
+            "BLE ping: {} sent, {} lost, {} CRC errors, RTT min {} us, max {} us, mean {} us, \
+             turnaround mean {} us",
+            statistics.sent,
+            lost,
+            statistics.crc_errors,
+            statistics.min_us,
+            statistics.max_us,
+            statistics.sum_us / statistics.received as u64,
+            statistics.sum_turnaround_us / statistics.received as u64
+        );
+    }
+}
+
+impl<'a, B, A, R> time::Client for BlePing<'a, B, A, R>
+where
+    B: BleAdvertisementDriver + 'a,
+    A: Alarm + 'a,
+    R: ReferenceClock + 'a,
+{
+    fn fired(&self) {
+        let statistics = self.statistics.get();
+        let done = statistics.sent >= self.count;
+        if statistics.sent > 0
+            && (done || self.report_every > 0 && statistics.sent % self.report_every == 0)
+        {
+            self.report();
+        }
+        if done {
+            self.state.set(State::Idle);
+            return;
+        }
+
+        // The radio is still listening for the last echo, sending the next
+        // ping starts it over
+        let sequence = self.sequence.get().wrapping_add(1);
+        self.sequence.set(sequence);
+        self.answered.set(false);
+        self.sent_at.set(self.reference.now());
+        if self.send(KIND_PING, sequence, 0) == ReturnCode::SUCCESS {
+            let mut statistics = statistics;
+            statistics.sent += 1;
+            self.statistics.set(statistics);
+        }
+        self.set_next_alarm();
+    }
+}
+
+impl<'a, B, A, R> ble_advertising::TxClient for BlePing<'a, B, A, R>
+where
+    B: BleAdvertisementDriver + 'a,
+    A: Alarm + 'a,
+    R: ReferenceClock + 'a,
+{
+    fn transmit_event(&self, _result: ReturnCode) {
+        if self.state.get() == State::Sending {
+            self.listen();
+        }
+    }
+}
+
+impl<'a, B, A, R> ble_advertising::RxClient for BlePing<'a, B, A, R>
+where
+    B: BleAdvertisementDriver + 'a,
+    A: Alarm + 'a,
+    R: ReferenceClock + 'a,
+{
+    fn receive_event(&self, buf: &'static mut [u8], len: u8, result: ReturnCode) {
+        if self.state.get() != State::Listening {
+            return;
+        }
+        let received_at = self.reference.now();
+        let packet = BlePing::<B, A, R>::parse(buf, len as usize);
+
+        match (self.role, packet) {
+            (Role::Initiator, Some((KIND_ECHO, sequence, turnaround_us)))
+                if sequence == self.sequence.get() && !self.answered.get() =>
+            {
+                if result != ReturnCode::SUCCESS {
+                    let mut statistics = self.statistics.get();
+                    statistics.crc_errors += 1;
+                    self.statistics.set(statistics);
+                } else {
+                    let elapsed =
+                        received_at.wrapping_sub(self.sent_at.get()) as u64 * 1000000
+                            / <R::Frequency>::frequency() as u64;
+                    let rtt_us = (elapsed as u32).saturating_sub(turnaround_us);
+                    self.answered.set(true);
+                    let mut statistics = self.statistics.get();
+                    statistics.record(rtt_us, turnaround_us);
+                    self.statistics.set(statistics);
+                    self.state.set(State::Idle);
+                    return;
+                }
+            }
+            (Role::Responder, Some((KIND_PING, sequence, _))) if result == ReturnCode::SUCCESS => {
+                let turnaround_us = self.elapsed_us(received_at);
+                if self.send(KIND_ECHO, sequence, turnaround_us) == ReturnCode::SUCCESS {
+                    return;
+                }
+            }
+            _ => {}
+        }
+        // Not for us, or damaged: keep listening
+        self.listen();
+    }
+}
@@ -0,0 +1,166 @@
+//! Alarm with injected clock drift and jitter
+//!
+//! `DriftAlarm` sits between an alarm and its client and makes the client
+//! see a clock that runs `ppm` parts per million fast (or slow, if negative)
+//! compared with the hardware, and whose readings are off by up to `jitter`
+//! ticks either way. The hardware is not touched: `now` is scaled on the way
+//! up and alarm intervals are scaled back on the way down, so alarms fire at
+//! the right time on the drifted clock. The jitter is drawn again from a
+//! pseudo-random sequence every time the alarm fires.
+//!
+//! This is for robustness testing of protocols that have to cope with the
+//! sleep clock accuracy of their peers, such as the window widening and the
+//! supervision timeout of BLE connections, on a board whose own clock is far
+//! better than the worst case of +-500 ppm.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let ble_radio_drift_alarm = static_init!(
+//!     capsules::test::drift_alarm::DriftAlarm<'static, VirtualMuxAlarm<'static, Rtc>>,
+//!     capsules::test::drift_alarm::DriftAlarm::new(ble_radio_virtual_alarm)
+//! );
+//! ble_radio_virtual_alarm.set_client(ble_radio_drift_alarm);
+//! ble_radio_drift_alarm.set_drift(-500);
+//! ble_radio_drift_alarm.set_jitter(2);
+//!
+//! // Then pass `ble_radio_drift_alarm` to the BLE driver in place of
+//! // `ble_radio_virtual_alarm` and set the driver as its client.
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::hil::time::{self, Alarm, Time};
+
+const PPM: i64 = 1_000_000;
+
+pub struct DriftAlarm<'a, A: Alarm + 'a> {
+    alarm: &'a A,
+    client: Cell<Option<&'static time::Client>>,
+    ppm: Cell<i32>,
+    jitter: Cell<u32>,
+
+    /// Hardware time at the last reading
+    origin_real: Cell<u32>,
+    /// Drifted time at the last reading, without jitter
+    origin_reported: Cell<u32>,
+    /// Drift not yet a whole tick, in millionths of a tick
+    drift_remainder: Cell<i64>,
+    jitter_offset: Cell<i32>,
+    seed: Cell<u32>,
+    target: Cell<u32>,
+}
+
+impl<'a, A: Alarm + 'a> DriftAlarm<'a, A> {
+    pub fn new(alarm: &'a A) -> DriftAlarm<'a, A> {
+        let now = alarm.now();
+        DriftAlarm {
+            alarm: alarm,
+            client: Cell::new(None),
+            ppm: Cell::new(0),
+            jitter: Cell::new(0),
+            origin_real: Cell::new(now),
+            origin_reported: Cell::new(now),
+            drift_remainder: Cell::new(0),
+            jitter_offset: Cell::new(0),
+            seed: Cell::new(0x2545F491),
+            target: Cell::new(0),
+        }
+    }
+
+    pub fn set_client(&self, client: &'static time::Client) {
+        self.client.set(Some(client));
+    }
+
+    /// Make the clock run `ppm` parts per million fast, or slow if negative
+    pub fn set_drift(&self, ppm: i32) {
+        self.update();
+        self.ppm.set(ppm);
+    }
+
+    /// Offset every reading by up to `ticks` either way
+    pub fn set_jitter(&self, ticks: u32) {
+        self.jitter.set(ticks);
+        self.draw_jitter();
+    }
+
+    /// Move the drifted time forward to the current hardware time
+    fn update(&self) -> u32 {
+        let real = self.alarm.now();
+        let elapsed = real.wrapping_sub(self.origin_real.get()) as i64;
+        let drift = self.drift_remainder.get() + elapsed * self.ppm.get() as i64;
+        let whole_ticks = drift / PPM;
+        self.drift_remainder.set(drift - whole_ticks * PPM);
+        self.origin_real.set(real);
+        self.origin_reported.set(
+            self.origin_reported
+                .get()
+                .wrapping_add((elapsed + whole_ticks) as u32),
+        );
+        real
+    }
+
+    fn draw_jitter(&self) {
+        let jitter = self.jitter.get();
+        if jitter == 0 {
+            self.jitter_offset.set(0);
+            return;
+        }
+        // xorshift32
+        let mut seed = self.seed.get();
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        self.seed.set(seed);
+        let offset = (seed % (2 * jitter + 1)) as i32 - jitter as i32;
+        self.jitter_offset.set(offset);
+    }
+}
+
+impl<'a, A: Alarm + 'a> Time for DriftAlarm<'a, A> {
+    type Frequency = A::Frequency;
+
+    fn disable(&self) {
+        self.alarm.disable();
+    }
+
+    fn is_armed(&self) -> bool {
+        self.alarm.is_armed()
+    }
+}
+
+impl<'a, A: Alarm + 'a> Alarm for DriftAlarm<'a, A> {
+    fn now(&self) -> u32 {
+        self.update();
+        self.origin_reported
+            .get()
+            .wrapping_add(self.jitter_offset.get() as u32)
+    }
+
+    fn set_alarm(&self, tics: u32) {
+        self.target.set(tics);
+        let now = self.now();
+        let real = self.origin_real.get();
+        let delta = tics.wrapping_sub(now) as i32;
+        // An interval on the drifted clock is longer or shorter on the
+        // hardware one. A time already passed fires right away.
+        let real_delta = if delta <= 0 {
+            1
+        } else {
+            cmp::max(1, delta as i64 * PPM / (PPM + self.ppm.get() as i64)) as u32
+        };
+        self.alarm.set_alarm(real.wrapping_add(real_delta));
+    }
+
+    fn get_alarm(&self) -> u32 {
+        self.target.get()
+    }
+}
+
+impl<'a, A: Alarm + 'a> time::Client for DriftAlarm<'a, A> {
+    fn fired(&self) {
+        self.draw_jitter();
+        self.client.get().map(|client| client.fired());
+    }
+}
@@ -1,2 +1,5 @@
 pub mod aes;
 pub mod aes_ccm;
+pub mod alarm_jitter;
+pub mod ble_ping;
+pub mod drift_alarm;
@@ -0,0 +1,111 @@
+//! Virtualize a random number generator.
+//!
+//! `MuxRng` provides shared access to a single RNG for multiple users.
+//! `RngDevice` is one user of the RNG and implements `hil::rng::RNG` itself,
+//! so it can be handed to any RNG client, such as `capsules::rng::SimpleRng`.
+//!
+//! Randomness is handed out to the devices that asked for it in turn: a
+//! device is given the iterator of available random numbers until it
+//! answers `Continue::Done`, and the RNG keeps running as long as a device
+//! is waiting for more.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let mux_rng = static_init!(
+//!     capsules::virtual_rng::MuxRng<'static>,
+//!     capsules::virtual_rng::MuxRng::new(&nrf5x::trng::TRNG)
+//! );
+//! nrf5x::trng::TRNG.set_client(mux_rng);
+//!
+//! let rng_device = static_init!(
+//!     capsules::virtual_rng::RngDevice<'static>,
+//!     capsules::virtual_rng::RngDevice::new(mux_rng)
+//! );
+//! let rng = static_init!(
+//!     capsules::rng::SimpleRng<'static, capsules::virtual_rng::RngDevice<'static>>,
+//!     capsules::rng::SimpleRng::new(rng_device, kernel::Grant::create())
+//! );
+//! rng_device.set_client(rng);
+//! ```
+
+use core::cell::Cell;
+use kernel::common::{List, ListLink, ListNode};
+use kernel::hil::rng::{self, Continue};
+
+pub struct MuxRng<'a> {
+    rng: &'a rng::RNG,
+    devices: List<'a, RngDevice<'a>>,
+}
+
+impl<'a> rng::Client for MuxRng<'a> {
+    fn randomness_available(&self, randomness: &mut Iterator<Item = u32>) -> Continue {
+        for device in self.devices.iter().filter(|device| device.requested.get()) {
+            device.requested.set(false);
+            let next = device
+                .client
+                .get()
+                .map_or(Continue::Done, |client| client.randomness_available(randomness));
+            if next == Continue::More {
+                // The randomness available ran out before the device had
+                // enough, the other devices wait for the next batch
+                device.requested.set(true);
+                return Continue::More;
+            }
+        }
+        if self.devices.iter().any(|device| device.requested.get()) {
+            Continue::More
+        } else {
+            Continue::Done
+        }
+    }
+}
+
+impl<'a> MuxRng<'a> {
+    pub const fn new(rng: &'a rng::RNG) -> MuxRng<'a> {
+        MuxRng {
+            rng: rng,
+            devices: List::new(),
+        }
+    }
+}
+
+pub struct RngDevice<'a> {
+    mux: &'a MuxRng<'a>,
+    requested: Cell<bool>,
+    next: ListLink<'a, RngDevice<'a>>,
+    client: Cell<Option<&'a rng::Client>>,
+}
+
+impl<'a> RngDevice<'a> {
+    pub const fn new(mux: &'a MuxRng<'a>) -> RngDevice<'a> {
+        RngDevice {
+            mux: mux,
+            requested: Cell::new(false),
+            next: ListLink::empty(),
+            client: Cell::new(None),
+        }
+    }
+
+    pub fn set_client(&'a self, client: &'a rng::Client) {
+        self.mux.devices.push_head(self);
+        self.client.set(Some(client));
+    }
+}
+
+impl<'a> rng::RNG for RngDevice<'a> {
+    fn get(&self) {
+        let busy = self.mux.devices.iter().any(|device| device.requested.get());
+        self.requested.set(true);
+        if !busy {
+            self.mux.rng.get();
+        }
+    }
+}
+
+impl<'a> ListNode<'a, RngDevice<'a>> for RngDevice<'a> {
+    fn next(&'a self) -> &'a ListLink<'a, RngDevice<'a>> {
+        &self.next
+    }
+}
@@ -0,0 +1,300 @@
+//! Virtualize a UART bus.
+//!
+//! `MuxUart` shares a single UART between several users, such as the console
+//! and the process console. Each user gets a `UartDevice`, which provides the
+//! `hil::uart::UART` interface with its own buffers. Transmissions from
+//! different devices are queued and go out one buffer at a time, in full.
+//! Several devices can receive at the same time. The mux receives one byte
+//! at a time into a buffer of its own and copies every byte to each device
+//! that is receiving, so all of them see the same input. A device's receive
+//! completes once its buffer is full. The UART is configured by the first
+//! device calling `init`, the parameters of later calls are ignored.
+//! `reconfigure` changes the parameters for all devices, and fails with
+//! `EBUSY` while any of them is transmitting. If devices are receiving, the
+//! mux aborts the byte being received and applies the new parameters once
+//! the UART hands its buffer back. The receives of the devices are not cut
+//! short: they go on with the new parameters, and transmissions wait until
+//! the UART is reconfigured.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let uart_mux = static_init!(
+//!     MuxUart<'static>,
+//!     MuxUart::new(&nrf51::uart::UART0, &mut capsules::virtual_uart::RX_BUF)
+//! );
+//! hil::uart::UART::set_client(&nrf51::uart::UART0, uart_mux);
+//!
+//! let console_uart = static_init!(UartDevice, UartDevice::new(uart_mux));
+//! console_uart.setup();
+//! let console = static_init!(
+//!     capsules::console::Console<UartDevice>,
+//!     capsules::console::Console::new(
+//!         console_uart,
+//!         115200,
+//!         &mut capsules::console::WRITE_BUF,
+//!         &mut capsules::console::READ_BUF,
+//!         kernel::Grant::create()
+//!     )
+//! );
+//! hil::uart::UART::set_client(console_uart, console);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::take_cell::TakeCell;
+use kernel::common::{List, ListLink, ListNode};
+use kernel::hil::uart::{self, Client, UARTParams, UART};
+use kernel::ReturnCode;
+
+pub static mut RX_BUF: [u8; 1] = [0; 1];
+
+pub struct MuxUart<'a> {
+    uart: &'a UART,
+    devices: List<'a, UartDevice<'a>>,
+    initialized: Cell<bool>,
+    inflight: Cell<Option<&'a UartDevice<'a>>>,
+    /// Held by the UART while a byte is being received
+    rx_buffer: TakeCell<'static, [u8]>,
+    /// Parameters waiting for the aborted receive to complete
+    reconfiguration: Cell<Option<UARTParams>>,
+}
+
+impl<'a> Client for MuxUart<'a> {
+    fn transmit_complete(&self, tx_buffer: &'static mut [u8], error: uart::Error) {
+        // Start the next device's transmission first, so a device that
+        // transmits again from its callback goes after the others
+        let device = self.inflight.get();
+        self.inflight.set(None);
+        self.do_next_op();
+        device.map(move |device| {
+            device.transmit_complete(tx_buffer, error);
+        });
+    }
+
+    fn receive_complete(&self, rx_buffer: &'static mut [u8], rx_len: usize, error: uart::Error) {
+        let byte = if rx_len > 0 { Some(rx_buffer[0]) } else { None };
+        self.rx_buffer.replace(rx_buffer);
+        self.reconfiguration.take().map(|params| {
+            self.uart.reconfigure(params);
+            self.do_next_op();
+        });
+        // An aborted receive only ends the receives of the devices that
+        // asked for it, the others go on with the next byte
+        for device in self.devices.iter() {
+            device.receive_byte(byte, error);
+        }
+        self.start_receive();
+    }
+}
+
+impl<'a> MuxUart<'a> {
+    pub fn new(uart: &'a UART, rx_buffer: &'static mut [u8]) -> MuxUart<'a> {
+        MuxUart {
+            uart: uart,
+            devices: List::new(),
+            initialized: Cell::new(false),
+            inflight: Cell::new(None),
+            rx_buffer: TakeCell::new(rx_buffer),
+            reconfiguration: Cell::new(None),
+        }
+    }
+
+    fn init(&self, params: UARTParams) {
+        if !self.initialized.get() {
+            self.initialized.set(true);
+            self.uart.init(params);
+        }
+    }
+
+    fn reconfigure(&self, params: UARTParams) -> ReturnCode {
+        if self.inflight.get().is_some() || self.reconfiguration.get().is_some() {
+            return ReturnCode::EBUSY;
+        }
+        // The UART rejects parameters it does not support before checking
+        // whether it is busy, so the parameters are valid if only the
+        // receive is in the way
+        let result = self.uart.reconfigure(params);
+        if result == ReturnCode::EBUSY && self.rx_buffer.is_none() {
+            self.reconfiguration.set(Some(params));
+            self.uart.abort_receive();
+            ReturnCode::SUCCESS
+        } else {
+            result
+        }
+    }
+
+    /// Receive the next byte if any device is waiting for one and no byte
+    /// is being received already
+    fn start_receive(&self) {
+        if self.devices.iter().any(|node| node.rx_buffer.is_some()) {
+            self.rx_buffer.take().map(|buf| {
+                self.uart.receive(buf, 1);
+            });
+        }
+    }
+
+    fn do_next_op(&self) {
+        if self.inflight.get().is_none() && self.reconfiguration.get().is_none() {
+            let mnode = self.devices.iter().find(|node| node.tx_buffer.is_some());
+            mnode.map(|node| {
+                node.tx_buffer.take().map(|buf| {
+                    self.uart.transmit(buf, node.tx_len.get());
+                });
+                self.inflight.set(Some(node));
+            });
+        }
+    }
+}
+
+pub struct UartDevice<'a> {
+    mux: &'a MuxUart<'a>,
+    /// Buffer waiting for the UART
+    tx_buffer: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    /// A transmission is queued or in progress
+    transmitting: Cell<bool>,
+    /// Buffer being filled with received bytes
+    rx_buffer: TakeCell<'static, [u8]>,
+    rx_len: Cell<usize>,
+    rx_position: Cell<usize>,
+    /// `abort_receive` was called, the receive ends with the next
+    /// completion from the UART
+    rx_aborting: Cell<bool>,
+    next: ListLink<'a, UartDevice<'a>>,
+    client: Cell<Option<&'static Client>>,
+}
+
+impl<'a> UartDevice<'a> {
+    pub const fn new(mux: &'a MuxUart<'a>) -> UartDevice<'a> {
+        UartDevice {
+            mux: mux,
+            tx_buffer: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            transmitting: Cell::new(false),
+            rx_buffer: TakeCell::empty(),
+            rx_len: Cell::new(0),
+            rx_position: Cell::new(0),
+            rx_aborting: Cell::new(false),
+            next: ListLink::empty(),
+            client: Cell::new(None),
+        }
+    }
+
+    /// Attach the device to its mux. Must be called once, before the device
+    /// is used.
+    pub fn setup(&'a self) {
+        self.mux.devices.push_head(self);
+    }
+
+    /// Hand a byte the mux received to the device, and complete its receive
+    /// if the buffer is full, it was aborted or the UART reported an error
+    fn receive_byte(&self, byte: Option<u8>, error: uart::Error) {
+        let position = self.rx_buffer.map_or(None, |buffer| {
+            let mut position = self.rx_position.get();
+            byte.map(|byte| {
+                buffer[position] = byte;
+                position += 1;
+            });
+            self.rx_position.set(position);
+            Some(position)
+        });
+        let position = match position {
+            Some(position) => position,
+            None => return,
+        };
+        let error = if self.rx_aborting.get() {
+            uart::Error::Aborted
+        } else if error == uart::Error::Aborted {
+            // Another device aborted its receive
+            if position < self.rx_len.get() {
+                return;
+            }
+            uart::Error::CommandComplete
+        } else if error == uart::Error::CommandComplete && position < self.rx_len.get() {
+            return;
+        } else {
+            error
+        };
+        self.rx_aborting.set(false);
+        self.rx_buffer.take().map(|buffer| {
+            self.receive_complete(buffer, position, error);
+        });
+    }
+}
+
+impl<'a> Client for UartDevice<'a> {
+    fn transmit_complete(&self, tx_buffer: &'static mut [u8], error: uart::Error) {
+        self.transmitting.set(false);
+        self.client.get().map(move |client| {
+            client.transmit_complete(tx_buffer, error);
+        });
+    }
+
+    fn receive_complete(&self, rx_buffer: &'static mut [u8], rx_len: usize, error: uart::Error) {
+        self.client.get().map(move |client| {
+            client.receive_complete(rx_buffer, rx_len, error);
+        });
+    }
+}
+
+impl<'a> ListNode<'a, UartDevice<'a>> for UartDevice<'a> {
+    fn next(&'a self) -> &'a ListLink<'a, UartDevice<'a>> {
+        &self.next
+    }
+}
+
+impl<'a> UART for UartDevice<'a> {
+    fn set_client(&self, client: &'static Client) {
+        self.client.set(Some(client));
+    }
+
+    fn init(&self, params: UARTParams) {
+        self.mux.init(params);
+    }
+
+    fn transmit(&self, tx_data: &'static mut [u8], tx_len: usize) {
+        if self.transmitting.get() {
+            self.client.get().map(move |client| {
+                client.transmit_complete(tx_data, uart::Error::RepeatCallError);
+            });
+        } else {
+            self.transmitting.set(true);
+            self.tx_buffer.replace(tx_data);
+            self.tx_len.set(tx_len);
+            self.mux.do_next_op();
+        }
+    }
+
+    fn receive(&self, rx_buffer: &'static mut [u8], rx_len: usize) {
+        if self.rx_buffer.is_some() {
+            self.client.get().map(move |client| {
+                client.receive_complete(rx_buffer, 0, uart::Error::RepeatCallError);
+            });
+        } else if rx_len == 0 {
+            self.client.get().map(move |client| {
+                client.receive_complete(rx_buffer, 0, uart::Error::CommandComplete);
+            });
+        } else {
+            self.rx_len.set(cmp::min(rx_len, rx_buffer.len()));
+            self.rx_position.set(0);
+            self.rx_aborting.set(false);
+            self.rx_buffer.replace(rx_buffer);
+            self.mux.start_receive();
+        }
+    }
+
+    fn reconfigure(&self, params: UARTParams) -> ReturnCode {
+        self.mux.reconfigure(params)
+    }
+
+    /// Only aborts a reception of this device, the other devices keep
+    /// receiving
+    fn abort_receive(&self) {
+        if self.rx_buffer.is_some() && !self.rx_aborting.get() {
+            self.rx_aborting.set(true);
+            self.mux.uart.abort_receive();
+        }
+    }
+}
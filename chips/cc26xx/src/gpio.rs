@@ -9,6 +9,7 @@ use core::ops::{Index, IndexMut};
 use ioc;
 use kernel::common::regs::{ReadWrite, WriteOnly};
 use kernel::hil;
+use kernel::ReturnCode;
 
 const NUM_PINS: usize = 32;
 const GPIO_BASE: *const GpioRegisters = 0x4002_2000 as *const GpioRegisters;
@@ -67,6 +68,13 @@ impl hil::gpio::PinCtl for GPIOPin {
     fn set_input_mode(&self, mode: hil::gpio::InputMode) {
         ioc::IOCFG[self.pin].set_input_mode(mode);
     }
+    /// Only the standard push-pull drive is supported
+    fn set_drive_mode(&self, mode: hil::gpio::DriveMode) -> ReturnCode {
+        match mode {
+            hil::gpio::DriveMode::PushPull => ReturnCode::SUCCESS,
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
 }
 
 impl hil::gpio::Pin for GPIOPin {
@@ -6,6 +6,7 @@ use kernel;
 use kernel::common::regs::{ReadOnly, ReadWrite, WriteOnly};
 use kernel::hil::gpio::Pin;
 use kernel::hil::uart;
+use kernel::ReturnCode;
 use prcm;
 
 const UART_BASE: usize = 0x4000_1000;
@@ -55,6 +56,7 @@ register_bitfields![
         DIVISOR OFFSET(0) NUMBITS(6) []
     ],
     Flags [
+        UART_BUSY OFFSET(3) NUMBITS(1) [],
         TX_FIFO_FULL OFFSET(5) NUMBITS(1) []
     ],
     Interrupts [
@@ -217,4 +219,20 @@ impl kernel::hil::uart::UART for UART {
 
     #[allow(unused)]
     fn receive(&self, rx_buffer: &'static mut [u8], rx_len: usize) {}
+
+    /// Only the baud rate can be changed, the UART is always 8N1
+    fn reconfigure(&self, params: kernel::hil::uart::UARTParams) -> ReturnCode {
+        match (params.parity, params.stop_bits, params.hw_flow_control) {
+            (uart::Parity::None, uart::StopBits::One, false) => {}
+            _ => return ReturnCode::EINVAL,
+        }
+        // Transmissions complete before `transmit` returns, but the last
+        // bytes may still be in the FIFO, which disabling the UART flushes
+        let regs = unsafe { &*self.regs };
+        while regs.fr.is_set(Flags::UART_BUSY) {}
+        self.configure(params);
+        ReturnCode::SUCCESS
+    }
+
+    fn abort_receive(&self) {}
 }
@@ -0,0 +1,327 @@
+//! ADC driver, nRF51
+//!
+//! The nRF51 ADC is a 10-bit successive approximation converter with a single
+//! conversion at a time. Each `AdcChannel` selects one of the eight analog
+//! inputs (AIN0-AIN7) together with the input prescaling and the reference
+//! the conversion is made against, so different channels can be measured in
+//! different ranges. Samples are right justified.
+//!
+//! Only single samples are supported. Continuous and high-speed sampling
+//! return `ENOSUPPORT`.
+
+use core::cell::Cell;
+use kernel::common::regs::{ReadOnly, ReadWrite, WriteOnly};
+use kernel::hil;
+use kernel::ReturnCode;
+
+const ADC_BASE: usize = 0x40007000;
+
+#[repr(C)]
+struct AdcRegisters {
+    /// Start an ADC conversion
+    /// Address: 0x000 - 0x004
+    task_start: WriteOnly<u32, Task::Register>,
+    /// Stop the ADC
+    /// Address: 0x004 - 0x008
+    task_stop: WriteOnly<u32, Task::Register>,
+    _reserved0: [u32; 62],
+    /// Conversion complete
+    /// Address: 0x100 - 0x104
+    event_end: ReadWrite<u32, Event::Register>,
+    _reserved1: [u32; 128],
+    /// Enable interrupt
+    /// Address: 0x304 - 0x308
+    intenset: ReadWrite<u32, Interrupt::Register>,
+    /// Disable interrupt
+    /// Address: 0x308 - 0x30C
+    intenclr: ReadWrite<u32, Interrupt::Register>,
+    _reserved2: [u32; 61],
+    /// ADC busy
+    /// Address: 0x400 - 0x404
+    busy: ReadOnly<u32, Busy::Register>,
+    _reserved3: [u32; 63],
+    /// Enable the ADC
+    /// Address: 0x500 - 0x504
+    enable: ReadWrite<u32, Enable::Register>,
+    /// Conversion configuration
+    /// Address: 0x504 - 0x508
+    config: ReadWrite<u32, Config::Register>,
+    /// Result of the last conversion
+    /// Address: 0x508 - 0x50C
+    result: ReadOnly<u32, Value::Register>,
+}
+
+register_bitfields! [u32,
+    Task [
+        ENABLE OFFSET(0) NUMBITS(1)
+    ],
+
+    Event [
+        READY OFFSET(0) NUMBITS(1)
+    ],
+
+    Interrupt [
+        END OFFSET(0) NUMBITS(1)
+    ],
+
+    Busy [
+        BUSY OFFSET(0) NUMBITS(1)
+    ],
+
+    Enable [
+        ENABLE OFFSET(0) NUMBITS(2) [
+            Disabled = 0,
+            Enabled = 1
+        ]
+    ],
+
+    Config [
+        /// Resolution
+        RES OFFSET(0) NUMBITS(2) [
+            Bit8 = 0,
+            Bit9 = 1,
+            Bit10 = 2
+        ],
+        /// Input selection
+        INPSEL OFFSET(2) NUMBITS(3) [],
+        /// Reference selection
+        REFSEL OFFSET(5) NUMBITS(2) [],
+        /// Analog input pin, one bit per AIN
+        PSEL OFFSET(8) NUMBITS(8) [],
+        /// External reference pin
+        EXTREFSEL OFFSET(16) NUMBITS(2) []
+    ],
+
+    Value [
+        RESULT OFFSET(0) NUMBITS(10)
+    ]
+];
+
+/// Analog input pins
+#[derive(Copy, Clone, Debug)]
+pub enum AnalogInput {
+    AIN0 = 0,
+    AIN1 = 1,
+    AIN2 = 2,
+    AIN3 = 3,
+    AIN4 = 4,
+    AIN5 = 5,
+    AIN6 = 6,
+    AIN7 = 7,
+}
+
+/// What is fed to the converter, and how it is scaled
+#[derive(Copy, Clone, Debug)]
+pub enum InputSelect {
+    /// Analog input without prescaling
+    AnalogNoPrescaling = 0,
+    /// Analog input scaled by 2/3
+    AnalogTwoThirdsPrescaling = 1,
+    /// Analog input scaled by 1/3
+    AnalogOneThirdPrescaling = 2,
+    /// Supply voltage scaled by 2/3, the analog input is ignored
+    SupplyTwoThirdsPrescaling = 5,
+    /// Supply voltage scaled by 1/3, the analog input is ignored
+    SupplyOneThirdPrescaling = 6,
+}
+
+/// Conversion reference
+#[derive(Copy, Clone, Debug)]
+pub enum Reference {
+    /// Internal 1.2V band gap
+    BandGap = 0,
+    /// External reference selected by `ExternalReference`
+    External = 1,
+    /// Supply voltage scaled by 1/2
+    SupplyOneHalfPrescaling = 2,
+    /// Supply voltage scaled by 1/3
+    SupplyOneThirdPrescaling = 3,
+}
+
+/// Pin used when `Reference::External` is selected
+#[derive(Copy, Clone, Debug)]
+pub enum ExternalReference {
+    None = 0,
+    AREF0 = 1,
+    AREF1 = 2,
+}
+
+/// Representation of an ADC channel on the nRF51.
+pub struct AdcChannel {
+    input: AnalogInput,
+    inpsel: InputSelect,
+    refsel: Reference,
+    extrefsel: ExternalReference,
+}
+
+impl AdcChannel {
+    /// Create a channel sampling `input` with the given scaling and
+    /// reference.
+    pub const fn new(
+        input: AnalogInput,
+        inpsel: InputSelect,
+        refsel: Reference,
+        extrefsel: ExternalReference,
+    ) -> AdcChannel {
+        AdcChannel {
+            input: input,
+            inpsel: inpsel,
+            refsel: refsel,
+            extrefsel: extrefsel,
+        }
+    }
+
+    /// Create a channel measuring `input` over the full 0 - 3.6V range,
+    /// with 1/3 prescaling against the internal band gap.
+    pub const fn with_band_gap(input: AnalogInput) -> AdcChannel {
+        AdcChannel::new(
+            input,
+            InputSelect::AnalogOneThirdPrescaling,
+            Reference::BandGap,
+            ExternalReference::None,
+        )
+    }
+}
+
+/// Statically allocated ADC channels using the band gap reference. Boards
+/// needing another range can create their own `AdcChannel`.
+pub static mut CHANNEL_AIN0: AdcChannel = AdcChannel::with_band_gap(AnalogInput::AIN0);
+pub static mut CHANNEL_AIN1: AdcChannel = AdcChannel::with_band_gap(AnalogInput::AIN1);
+pub static mut CHANNEL_AIN2: AdcChannel = AdcChannel::with_band_gap(AnalogInput::AIN2);
+pub static mut CHANNEL_AIN3: AdcChannel = AdcChannel::with_band_gap(AnalogInput::AIN3);
+pub static mut CHANNEL_AIN4: AdcChannel = AdcChannel::with_band_gap(AnalogInput::AIN4);
+pub static mut CHANNEL_AIN5: AdcChannel = AdcChannel::with_band_gap(AnalogInput::AIN5);
+pub static mut CHANNEL_AIN6: AdcChannel = AdcChannel::with_band_gap(AnalogInput::AIN6);
+pub static mut CHANNEL_AIN7: AdcChannel = AdcChannel::with_band_gap(AnalogInput::AIN7);
+
+pub struct Adc {
+    regs: *const AdcRegisters,
+    active: Cell<bool>,
+    client: Cell<Option<&'static hil::adc::Client>>,
+}
+
+pub static mut ADC: Adc = Adc::new();
+
+impl Adc {
+    const fn new() -> Adc {
+        Adc {
+            regs: ADC_BASE as *const AdcRegisters,
+            active: Cell::new(false),
+            client: Cell::new(None),
+        }
+    }
+
+    pub fn set_client(&self, client: &'static hil::adc::Client) {
+        self.client.set(Some(client));
+    }
+
+    pub fn handle_interrupt(&self) {
+        let regs = unsafe { &*self.regs };
+
+        regs.event_end.write(Event::READY::CLEAR);
+        regs.intenclr.write(Interrupt::END::SET);
+
+        if !self.active.get() {
+            return;
+        }
+        self.active.set(false);
+
+        let val = regs.result.read(Value::RESULT) as u16;
+
+        // Power the converter down between samples
+        regs.enable.write(Enable::ENABLE::Disabled);
+
+        self.client.get().map(|client| client.sample_ready(val));
+    }
+}
+
+impl hil::adc::Adc for Adc {
+    type Channel = AdcChannel;
+
+    fn initialize(&self) -> ReturnCode {
+        let regs = unsafe { &*self.regs };
+        regs.intenclr.write(Interrupt::END::SET);
+        regs.enable.write(Enable::ENABLE::Disabled);
+        ReturnCode::SUCCESS
+    }
+
+    fn sample(&self, channel: &Self::Channel) -> ReturnCode {
+        let regs = unsafe { &*self.regs };
+
+        if self.active.get() || regs.busy.is_set(Busy::BUSY) {
+            return ReturnCode::EBUSY;
+        }
+        self.active.set(true);
+
+        regs.config.write(
+            Config::RES::Bit10
+                + Config::INPSEL.val(channel.inpsel as u32)
+                + Config::REFSEL.val(channel.refsel as u32)
+                + Config::PSEL.val(1 << (channel.input as u32))
+                + Config::EXTREFSEL.val(channel.extrefsel as u32),
+        );
+        regs.enable.write(Enable::ENABLE::Enabled);
+
+        regs.event_end.write(Event::READY::CLEAR);
+        regs.intenset.write(Interrupt::END::SET);
+        regs.task_start.write(Task::ENABLE::SET);
+
+        ReturnCode::SUCCESS
+    }
+
+    fn sample_continuous(&self, _channel: &Self::Channel, _frequency: u32) -> ReturnCode {
+        ReturnCode::ENOSUPPORT
+    }
+
+    fn stop_sampling(&self) -> ReturnCode {
+        let regs = unsafe { &*self.regs };
+
+        if !self.active.get() {
+            return ReturnCode::SUCCESS;
+        }
+        self.active.set(false);
+
+        regs.intenclr.write(Interrupt::END::SET);
+        regs.task_stop.write(Task::ENABLE::SET);
+        regs.event_end.write(Event::READY::CLEAR);
+        regs.enable.write(Enable::ENABLE::Disabled);
+        ReturnCode::SUCCESS
+    }
+}
+
+/// The nRF51 has no DMA for the ADC, so buffered sampling is not provided.
+impl hil::adc::AdcHighSpeed for Adc {
+    fn sample_highspeed(
+        &self,
+        _channel: &Self::Channel,
+        _frequency: u32,
+        buffer1: &'static mut [u16],
+        _length1: usize,
+        buffer2: &'static mut [u16],
+        _length2: usize,
+    ) -> (
+        ReturnCode,
+        Option<&'static mut [u16]>,
+        Option<&'static mut [u16]>,
+    ) {
+        (ReturnCode::ENOSUPPORT, Some(buffer1), Some(buffer2))
+    }
+
+    fn provide_buffer(
+        &self,
+        buf: &'static mut [u16],
+        _length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u16]>) {
+        (ReturnCode::ENOSUPPORT, Some(buf))
+    }
+
+    fn retrieve_buffers(
+        &self,
+    ) -> (
+        ReturnCode,
+        Option<&'static mut [u16]>,
+        Option<&'static mut [u16]>,
+    ) {
+        (ReturnCode::SUCCESS, None, None)
+    }
+}
@@ -1,45 +1,81 @@
+use adc;
+use clock;
 use cortexm0::nvic;
+use i2c;
 use kernel;
 use kernel::support;
 use nrf5x;
+use nrf5x::helpers::{DeferredCall, Task};
 use nrf5x::peripheral_interrupts::*;
 use radio;
+use serial_box::{self, Personality};
+use spi;
+use systick;
 use uart;
 
+/// Interrupts serviced before any other pending one. The radio has to be
+/// set up again within T_IFS of the end of a packet, and TIMER0 and the RTC
+/// drive the alarms its events are scheduled with.
+const PRIORITY_INTERRUPTS: [u32; 3] = [RADIO, TIMER0, RTC1];
+
 pub struct NRF51(());
 
 impl NRF51 {
     pub unsafe fn new() -> NRF51 {
+        // Let the regulators and clocks without a user turn off while the
+        // CPU sleeps
+        nrf5x::power::POWER.set_sub_power_mode(nrf5x::power::SubPowerMode::LowPower);
         NRF51(())
     }
 }
 
 impl kernel::Chip for NRF51 {
     type MPU = ();
-    type SysTick = ();
+    type SysTick = systick::SysTick;
 
     fn mpu(&self) -> &Self::MPU {
         &self.0
     }
 
     fn systick(&self) -> &Self::SysTick {
-        &self.0
+        unsafe { &systick::SYSTICK }
     }
 
     fn service_pending_interrupts(&mut self) {
         unsafe {
-            while let Some(interrupt) = nvic::next_pending() {
+            let stats = &mut nrf5x::interrupt_statistics::INTERRUPT_STATISTICS;
+            stats.record_pending(nvic::pending_count());
+            while let Some(task) = DeferredCall::next_pending() {
+                match task {
+                    Task::Nvmc => nrf5x::nvmc::NVMC.handle_interrupt(),
+                }
+            }
+            while let Some(interrupt) = nvic::next_pending_with_priority(&PRIORITY_INTERRUPTS) {
+                stats.record_serviced(interrupt);
                 match interrupt {
+                    ADC => adc::ADC.handle_interrupt(),
+                    CCM_AAR => nrf5x::ccm::CCM.handle_interrupt(),
                     ECB => nrf5x::aes::AESECB.handle_interrupt(),
                     GPIOTE => nrf5x::gpio::PORT.handle_interrupt(),
                     RADIO => radio::RADIO.handle_interrupt(),
                     RNG => nrf5x::trng::TRNG.handle_interrupt(),
+                    RTC0 => systick::SYSTICK.handle_interrupt(),
                     RTC1 => nrf5x::rtc::RTC.handle_interrupt(),
                     TEMP => nrf5x::temperature::TEMP.handle_interrupt(),
                     TIMER0 => nrf5x::timer::TIMER0.handle_interrupt(),
                     TIMER1 => nrf5x::timer::ALARM1.handle_interrupt(),
                     TIMER2 => nrf5x::timer::TIMER2.handle_interrupt(),
                     UART0 => uart::UART0.handle_interrupt(),
+                    // SPI0 and SPI1 share their interrupts with the TWIs, the
+                    // serial box knows which of them is using the block
+                    SPI0_TWI0 => match serial_box::SERIAL_BOX0.personality() {
+                        Personality::Spi => spi::SPI0.handle_interrupt(),
+                        _ => i2c::TWI0.handle_interrupt(),
+                    },
+                    SPI1_TWI1 => match serial_box::SERIAL_BOX1.personality() {
+                        Personality::Spi => spi::SPI1.handle_interrupt(),
+                        _ => i2c::TWI1.handle_interrupt(),
+                    },
                     _ => debug!("NvicIdx not supported by Tock"),
                 }
                 let n = nvic::Nvic::new(interrupt);
@@ -50,11 +86,19 @@ impl kernel::Chip for NRF51 {
     }
 
     fn has_pending_interrupts(&self) -> bool {
-        unsafe { nvic::has_pending() }
+        unsafe { nvic::has_pending() || DeferredCall::has_tasks() }
     }
 
     fn sleep(&self) {
         unsafe {
+            let power = &nrf5x::power::POWER;
+            let port = &nrf5x::gpio::PORT;
+            // With no wake source only a reset would end System OFF
+            if power.system_off_requested() && port.wake_sources() != 0 {
+                port.arm_wakeup();
+                power.system_off();
+            }
+            clock::CLOCK.idle();
             support::wfi();
         }
     }
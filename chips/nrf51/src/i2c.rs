@@ -0,0 +1,451 @@
+//! Implementation of I2C master for the nRF51 `TWI` peripherals.
+//!
+//! The nRF51 has two TWI instances, sharing their interrupt lines and
+//! register space with SPI0 and SPI1. Unlike the nRF52 `TWIM` there is no
+//! EasyDMA, so every byte is moved through the `TXD`/`RXD` registers from
+//! the interrupt handler.
+//!
+//! Reads use the byte-boundary (`BB`) shortcuts: the peripheral suspends after
+//! each received byte until the driver has read `RXD` and resumes it, and the
+//! `BB_STOP` shortcut is armed before the last byte so the bus is released
+//! with a NACK + STOP. A `write_read` issues a repeated start by starting RX
+//! directly after the last byte is sent instead of triggering `STOP`.
+//!
+//! Each transfer acquires the serial box of the TWI, see `serial_box`. A
+//! transfer refused because SPI owns the block completes at once with
+//! `ArbitrationLost`. The pins and the speed are written again at the start
+//! of every transfer, in case SPI used the block in between.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! nrf51::i2c::TWI0.configure(Pinmux::new(7), Pinmux::new(30));
+//! nrf51::i2c::TWI0.set_speed(nrf51::i2c::Speed::K400);
+//! let mux_i2c = static_init!(MuxI2C<'static>, MuxI2C::new(&nrf51::i2c::TWI0));
+//! nrf51::i2c::TWI0.set_client(mux_i2c);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::regs::{ReadOnly, ReadWrite, WriteOnly};
+use kernel::common::take_cell::TakeCell;
+use kernel::hil;
+use kernel::ReturnCode;
+use nrf5x::pinmux::Pinmux;
+use serial_box::{self, Personality};
+
+const INSTANCES: [usize; 2] = [0x40003000, 0x40004000];
+
+#[repr(C)]
+struct TwiRegisters {
+    /// Start TWI receive sequence
+    /// Address: 0x000 - 0x004
+    tasks_startrx: WriteOnly<u32, Task::Register>,
+    _reserved0: [u32; 1],
+    /// Start TWI transmit sequence
+    /// Address: 0x008 - 0x00C
+    tasks_starttx: WriteOnly<u32, Task::Register>,
+    _reserved1: [u32; 2],
+    /// Stop TWI transaction
+    /// Address: 0x014 - 0x018
+    tasks_stop: WriteOnly<u32, Task::Register>,
+    _reserved2: [u32; 1],
+    /// Suspend TWI transaction
+    /// Address: 0x01C - 0x020
+    tasks_suspend: WriteOnly<u32, Task::Register>,
+    /// Resume TWI transaction
+    /// Address: 0x020 - 0x024
+    tasks_resume: WriteOnly<u32, Task::Register>,
+    _reserved3: [u32; 56],
+    /// TWI stopped
+    /// Address: 0x104 - 0x108
+    events_stopped: ReadWrite<u32, Event::Register>,
+    /// TWI RXD byte received
+    /// Address: 0x108 - 0x10C
+    events_rxdready: ReadWrite<u32, Event::Register>,
+    _reserved4: [u32; 4],
+    /// TWI TXD byte sent
+    /// Address: 0x11C - 0x120
+    events_txdsent: ReadWrite<u32, Event::Register>,
+    _reserved5: [u32; 1],
+    /// TWI error
+    /// Address: 0x124 - 0x128
+    events_error: ReadWrite<u32, Event::Register>,
+    _reserved6: [u32; 4],
+    /// TWI byte boundary, generated before each byte that is sent or received
+    /// Address: 0x138 - 0x13C
+    events_bb: ReadWrite<u32, Event::Register>,
+    _reserved7: [u32; 3],
+    /// TWI entered the suspended state
+    /// Address: 0x148 - 0x14C
+    events_suspended: ReadWrite<u32, Event::Register>,
+    _reserved8: [u32; 45],
+    /// Shortcut register
+    /// Address: 0x200 - 0x204
+    shorts: ReadWrite<u32, Shorts::Register>,
+    _reserved9: [u32; 64],
+    /// Enable interrupt
+    /// Address: 0x304 - 0x308
+    intenset: ReadWrite<u32, Interrupt::Register>,
+    /// Disable interrupt
+    /// Address: 0x308 - 0x30C
+    intenclr: ReadWrite<u32, Interrupt::Register>,
+    _reserved10: [u32; 110],
+    /// Error source
+    /// Address: 0x4C4 - 0x4C8
+    errorsrc: ReadWrite<u32, ErrorSrc::Register>,
+    _reserved11: [u32; 14],
+    /// Enable TWI
+    /// Address: 0x500 - 0x504
+    enable: ReadWrite<u32, Enable::Register>,
+    _reserved12: [u32; 1],
+    /// Pin select for SCL
+    /// Address: 0x508 - 0x50C
+    pselscl: ReadWrite<u32>,
+    /// Pin select for SDA
+    /// Address: 0x50C - 0x510
+    pselsda: ReadWrite<u32>,
+    _reserved13: [u32; 2],
+    /// RXD register
+    /// Address: 0x518 - 0x51C
+    rxd: ReadOnly<u32>,
+    /// TXD register
+    /// Address: 0x51C - 0x520
+    txd: ReadWrite<u32>,
+    _reserved14: [u32; 1],
+    /// TWI frequency
+    /// Address: 0x524 - 0x528
+    frequency: ReadWrite<u32>,
+    _reserved15: [u32; 24],
+    /// Address used in the TWI transfer
+    /// Address: 0x588 - 0x58C
+    address: ReadWrite<u32>,
+}
+
+register_bitfields! [u32,
+    /// Start task
+    Task [
+        ENABLE OFFSET(0) NUMBITS(1)
+    ],
+
+    /// Read event
+    Event [
+        READY OFFSET(0) NUMBITS(1)
+    ],
+
+    /// Shortcut register
+    Shorts [
+        /// Shortcut between BB event and SUSPEND task
+        BB_SUSPEND OFFSET(0) NUMBITS(1),
+        /// Shortcut between BB event and STOP task
+        BB_STOP OFFSET(1) NUMBITS(1)
+    ],
+
+    /// Enable and disable interrupts
+    Interrupt [
+        STOPPED OFFSET(1) NUMBITS(1),
+        RXDREADY OFFSET(2) NUMBITS(1),
+        TXDSENT OFFSET(7) NUMBITS(1),
+        ERROR OFFSET(9) NUMBITS(1),
+        BB OFFSET(14) NUMBITS(1),
+        SUSPENDED OFFSET(18) NUMBITS(1)
+    ],
+
+    /// Error source, cleared by writing '1' to the set bits
+    ErrorSrc [
+        OVERRUN OFFSET(0) NUMBITS(1),
+        ANACK OFFSET(1) NUMBITS(1),
+        DNACK OFFSET(2) NUMBITS(1)
+    ],
+
+    /// Enable TWI
+    Enable [
+        ENABLE OFFSET(0) NUMBITS(3) [
+            Disabled = 0,
+            Enabled = 5
+        ]
+    ]
+];
+
+/// I2C bus speed.
+#[derive(Copy, Clone)]
+#[repr(u32)]
+pub enum Speed {
+    K100 = 0x01980000,
+    K250 = 0x04000000,
+    K400 = 0x06680000,
+}
+
+/// What the driver is doing with the bus, used by the interrupt handler to
+/// decide the next step of a transfer.
+#[derive(Copy, Clone, PartialEq)]
+enum Operation {
+    Idle,
+    Write,
+    Read,
+    WriteRead,
+}
+
+/// An I2C master device.
+pub struct TWI {
+    registers: *const TwiRegisters,
+    instance: usize,
+    scl: Cell<u32>,
+    sda: Cell<u32>,
+    speed: Cell<Speed>,
+    client: Cell<Option<&'static hil::i2c::I2CHwMasterClient>>,
+    buf: TakeCell<'static, [u8]>,
+    operation: Cell<Operation>,
+    write_len: Cell<usize>,
+    read_len: Cell<usize>,
+    index: Cell<usize>,
+    error: Cell<hil::i2c::Error>,
+}
+
+impl TWI {
+    const fn new(instance: usize) -> TWI {
+        TWI {
+            registers: INSTANCES[instance] as *const TwiRegisters,
+            instance: instance,
+            // Disconnected, the reset value of the pin selection
+            scl: Cell::new(0xFFFFFFFF),
+            sda: Cell::new(0xFFFFFFFF),
+            speed: Cell::new(Speed::K250),
+            client: Cell::new(None),
+            buf: TakeCell::empty(),
+            operation: Cell::new(Operation::Idle),
+            write_len: Cell::new(0),
+            read_len: Cell::new(0),
+            index: Cell::new(0),
+            error: Cell::new(hil::i2c::Error::CommandComplete),
+        }
+    }
+
+    pub fn set_client(&self, client: &'static hil::i2c::I2CHwMasterClient) {
+        self.client.set(Some(client));
+    }
+
+    fn regs(&self) -> &TwiRegisters {
+        unsafe { &*self.registers }
+    }
+
+    /// Configures the SCL and SDA pins of the TWI.
+    pub fn configure(&self, scl: Pinmux, sda: Pinmux) {
+        self.scl.set(scl.into());
+        self.sda.set(sda.into());
+        if self.owns_serial_box() {
+            self.apply_settings();
+        }
+    }
+
+    /// Sets the I2C bus speed to one of the values enumerated in `Speed`.
+    pub fn set_speed(&self, speed: Speed) {
+        self.speed.set(speed);
+        if self.owns_serial_box() {
+            self.apply_settings();
+        }
+    }
+
+    /// Whether SPI has left the registers shared with it alone
+    fn owns_serial_box(&self) -> bool {
+        unsafe { serial_box::get(self.instance) }.personality() != Personality::Spi
+    }
+
+    fn apply_settings(&self) {
+        let regs = self.regs();
+        regs.pselscl.set(self.scl.get());
+        regs.pselsda.set(self.sda.get());
+        regs.frequency.set(self.speed.get() as u32);
+    }
+
+    /// Enables hardware TWI peripheral, unless SPI owns it.
+    pub fn enable(&self) {
+        if self.owns_serial_box() {
+            self.regs().enable.write(Enable::ENABLE::Enabled);
+        }
+    }
+
+    /// Disables hardware TWI peripheral, unless SPI owns it.
+    pub fn disable(&self) {
+        if self.owns_serial_box() {
+            self.regs().enable.write(Enable::ENABLE::Disabled);
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.regs().enable.matches_all(Enable::ENABLE::Enabled)
+    }
+
+    fn start(&self, addr: u8, data: &'static mut [u8], operation: Operation) {
+        // Only the bytes the buffer holds are written. A transfer left with
+        // nothing to write or read completes right away, without the bus.
+        let write_len = cmp::min(self.write_len.get(), data.len());
+        self.write_len.set(write_len);
+        let nothing_to_do = match operation {
+            Operation::Read => self.read_len.get() == 0,
+            _ => write_len == 0,
+        };
+        if nothing_to_do {
+            self.client.get().map(move |client| {
+                client.command_complete(data, hil::i2c::Error::CommandComplete);
+            });
+            return;
+        }
+
+        let serial_box = unsafe { serial_box::get(self.instance) };
+        if serial_box.acquire(Personality::Twi) != ReturnCode::SUCCESS {
+            self.client.get().map(move |client| {
+                client.command_complete(data, hil::i2c::Error::ArbitrationLost);
+            });
+            return;
+        }
+        self.apply_settings();
+
+        let regs = self.regs();
+        // Addresses are passed in the 8-bit form with the R/W bit cleared.
+        regs.address.set((addr >> 1) as u32);
+        regs.events_stopped.write(Event::READY::CLEAR);
+        regs.events_rxdready.write(Event::READY::CLEAR);
+        regs.events_txdsent.write(Event::READY::CLEAR);
+        regs.events_error.write(Event::READY::CLEAR);
+        regs.intenset.write(
+            Interrupt::STOPPED::SET
+                + Interrupt::RXDREADY::SET
+                + Interrupt::TXDSENT::SET
+                + Interrupt::ERROR::SET,
+        );
+        self.operation.set(operation);
+        self.index.set(0);
+        self.error.set(hil::i2c::Error::CommandComplete);
+
+        match operation {
+            Operation::Read => self.start_rx(),
+            _ => {
+                regs.shorts.set(0);
+                regs.tasks_starttx.write(Task::ENABLE::SET);
+                regs.txd.set(data[0] as u32);
+            }
+        }
+        self.buf.replace(data);
+    }
+
+    /// Start (or restart, for `write_read`) the receive phase. The byte
+    /// boundary shortcut suspends the bus after each byte, and stops it after
+    /// the last one.
+    fn start_rx(&self) {
+        let regs = self.regs();
+        self.index.set(0);
+        if self.read_len.get() <= 1 {
+            regs.shorts.write(Shorts::BB_STOP::SET);
+        } else {
+            regs.shorts.write(Shorts::BB_SUSPEND::SET);
+        }
+        regs.tasks_startrx.write(Task::ENABLE::SET);
+    }
+
+    pub fn handle_interrupt(&self) {
+        let regs = self.regs();
+
+        if regs.events_error.is_set(Event::READY) {
+            regs.events_error.write(Event::READY::CLEAR);
+            let errorsrc = regs.errorsrc.extract();
+            regs.errorsrc.set(errorsrc.get());
+            self.error.set(if errorsrc.is_set(ErrorSrc::ANACK) {
+                hil::i2c::Error::AddressNak
+            } else {
+                hil::i2c::Error::DataNak
+            });
+            regs.tasks_stop.write(Task::ENABLE::SET);
+        }
+
+        if regs.events_txdsent.is_set(Event::READY) {
+            regs.events_txdsent.write(Event::READY::CLEAR);
+            let index = self.index.get() + 1;
+            self.index.set(index);
+            if index < self.write_len.get() {
+                self.buf.map(|buf| regs.txd.set(buf[index] as u32));
+            } else if self.operation.get() == Operation::WriteRead {
+                // Repeated start: switch direction without a STOP condition
+                self.start_rx();
+            } else {
+                regs.tasks_stop.write(Task::ENABLE::SET);
+            }
+        }
+
+        if regs.events_rxdready.is_set(Event::READY) {
+            regs.events_rxdready.write(Event::READY::CLEAR);
+            let index = self.index.get();
+            let byte = regs.rxd.get() as u8;
+            self.buf.map(|buf| {
+                if index < buf.len() {
+                    buf[index] = byte;
+                }
+            });
+            self.index.set(index + 1);
+            let remaining = self.read_len.get().saturating_sub(index + 1);
+            if remaining > 0 {
+                if remaining == 1 {
+                    // NACK and STOP after the final byte
+                    regs.shorts.write(Shorts::BB_STOP::SET);
+                }
+                regs.tasks_resume.write(Task::ENABLE::SET);
+            }
+        }
+
+        if regs.events_stopped.is_set(Event::READY) {
+            regs.events_stopped.write(Event::READY::CLEAR);
+            regs.shorts.set(0);
+            regs.intenclr.set(0xffffffff);
+            self.operation.set(Operation::Idle);
+            unsafe { serial_box::get(self.instance) }.release(Personality::Twi);
+            let error = self.error.get();
+            self.client.get().map(|client| {
+                self.buf
+                    .take()
+                    .map(|buf| client.command_complete(buf, error));
+            });
+        }
+
+        // These events are only used through shortcuts
+        regs.events_bb.write(Event::READY::CLEAR);
+        regs.events_suspended.write(Event::READY::CLEAR);
+    }
+}
+
+impl hil::i2c::I2CMaster for TWI {
+    fn enable(&self) {
+        self.enable();
+    }
+
+    fn disable(&self) {
+        self.disable();
+    }
+
+    fn write_read(&self, addr: u8, data: &'static mut [u8], write_len: u8, read_len: u8) {
+        self.write_len.set(write_len as usize);
+        self.read_len.set(read_len as usize);
+        if write_len == 0 {
+            self.start(addr, data, Operation::Read);
+        } else {
+            self.start(addr, data, Operation::WriteRead);
+        }
+    }
+
+    fn write(&self, addr: u8, data: &'static mut [u8], len: u8) {
+        self.write_len.set(len as usize);
+        self.read_len.set(0);
+        self.start(addr, data, Operation::Write);
+    }
+
+    fn read(&self, addr: u8, buffer: &'static mut [u8], len: u8) {
+        self.write_len.set(0);
+        self.read_len.set(len as usize);
+        self.start(addr, buffer, Operation::Read);
+    }
+}
+
+/// I2C master instance 0.
+pub static mut TWI0: TWI = TWI::new(0);
+/// I2C master instance 1.
+pub static mut TWI1: TWI = TWI::new(1);
@@ -7,13 +7,18 @@ extern crate cortexm0;
 extern crate nrf5x;
 
 #[allow(unused_imports)]
-#[macro_use(debug, debug_verbose, debug_gpio)]
+#[macro_use(debug, debug_verbose, debug_gpio, register_bitfields, register_bitmasks)]
 extern crate kernel;
 
+pub mod adc;
 pub mod chip;
-pub mod clock;
 pub mod crt1;
+pub mod i2c;
 pub mod radio;
+pub mod serial_box;
+pub mod spi;
+pub mod systick;
 pub mod uart;
 
 pub use crt1::init;
+pub use nrf5x::clock;
@@ -10,6 +10,7 @@
 //! * Fredrik Nilsson <frednils@student.chalmers.se>
 //! * Date: June 22, 2017
 
+use clock;
 use core::cell::Cell;
 use core::convert::TryFrom;
 use kernel;
@@ -19,6 +20,7 @@ use kernel::hil::ble_advertising::RadioChannel;
 use kernel::ReturnCode;
 use nrf5x;
 use nrf5x::constants::TxPower;
+use nrf5x::radio_crc::{self, CrcConfig, RadioCrc};
 
 const RADIO_BASE: usize = 0x40001000;
 
@@ -115,6 +117,8 @@ pub struct RadioRegisters {
 pub struct Radio {
     regs: *const RadioRegisters,
     tx_power: Cell<TxPower>,
+    /// Whether the radio holds a request on the high frequency crystal
+    hfclk_requested: Cell<bool>,
     rx_client: Cell<Option<&'static ble_advertising::RxClient>>,
     tx_client: Cell<Option<&'static ble_advertising::TxClient>>,
 }
@@ -124,6 +128,7 @@ impl Radio {
         Radio {
             regs: RADIO_BASE as *const RadioRegisters,
             tx_power: Cell::new(TxPower::ZerodBm),
+            hfclk_requested: Cell::new(false),
             rx_client: Cell::new(None),
             tx_client: Cell::new(None),
         }
@@ -173,14 +178,7 @@ impl Radio {
     }
 
     fn set_crc_config(&self) {
-        let regs = unsafe { &*self.regs };
-        regs.crccnf.set(
-            nrf5x::constants::RADIO_CRCCNF_LEN_3BYTES
-                | nrf5x::constants::RADIO_CRCCNF_SKIPADDR
-                    << nrf5x::constants::RADIO_CRCCNF_SKIPADDR_POS,
-        );
-        regs.crcinit.set(nrf5x::constants::RADIO_CRCINIT_BLE);
-        regs.crcpoly.set(nrf5x::constants::RADIO_CRCPOLY_BLE);
+        self.set_crc(radio_crc::BLE);
     }
 
     // Packet configuration
@@ -236,6 +234,12 @@ impl Radio {
 
     fn radio_on(&self) {
         let regs = unsafe { &*self.regs };
+        // The radio needs the crystal for an accurate carrier, but only while
+        // it is powered, so the clock can be stopped between packets
+        if !self.hfclk_requested.get() {
+            self.hfclk_requested.set(true);
+            unsafe { clock::CLOCK.request(clock::ClockDomain::High) };
+        }
         // reset and enable power
         regs.power.set(0);
         regs.power.set(1);
@@ -244,6 +248,10 @@ impl Radio {
     fn radio_off(&self) {
         let regs = unsafe { &*self.regs };
         regs.power.set(0);
+        if self.hfclk_requested.get() {
+            self.hfclk_requested.set(false);
+            unsafe { clock::CLOCK.release(clock::ClockDomain::High) };
+        }
     }
 
     // pre-condition validated before arriving here
@@ -390,3 +398,21 @@ impl ble_advertising::BleConfig for Radio {
         }
     }
 }
+
+impl RadioCrc for Radio {
+    fn set_crc(&self, config: CrcConfig) -> ReturnCode {
+        let res = config.validate();
+        if res == ReturnCode::SUCCESS {
+            let regs = unsafe { &*self.regs };
+            regs.crccnf.set(config.crccnf());
+            regs.crcpoly.set(config.polynomial);
+            regs.crcinit.set(config.init);
+        }
+        res
+    }
+
+    fn crc(&self) -> CrcConfig {
+        let regs = unsafe { &*self.regs };
+        CrcConfig::from_registers(regs.crccnf.get(), regs.crcpoly.get(), regs.crcinit.get())
+    }
+}
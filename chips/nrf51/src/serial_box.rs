@@ -0,0 +1,153 @@
+//! Arbitration of the nRF51 serial boxes
+//!
+//! SPI0 and TWI0, and SPI1 and TWI1, are two personalities of the same
+//! hardware block: they share the register space, the pin selection
+//! registers and the interrupt line, and only the one selected by the
+//! `ENABLE` register works at a time. A `SerialBox` keeps track of which
+//! personality owns each block.
+//!
+//! A board either gives a block a fixed personality with `configure`, after
+//! which the other personality is refused, or lets the drivers share it with
+//! `allow_switching`. A shared block is switched to the personality of the
+//! driver that starts a transfer, if no transfer of the other one is in
+//! progress. The drivers write their pins and settings again at the start of
+//! every transfer, since the other personality may have changed them, so the
+//! same pins can be used for I2C and SPI on boards that need both.
+//!
+//! A transfer started while the other personality is busy, or on a block
+//! fixed to the other personality, is refused and counted in `conflicts`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! nrf51::serial_box::SERIAL_BOX0.configure(nrf51::serial_box::Personality::Twi);
+//! nrf51::serial_box::SERIAL_BOX1.allow_switching();
+//! ```
+
+use core::cell::Cell;
+use kernel::common::VolatileCell;
+use kernel::ReturnCode;
+
+const INSTANCES: [usize; 2] = [0x40003000, 0x40004000];
+/// Offset of the `ENABLE` register, shared by both personalities
+const ENABLE_OFFSET: usize = 0x500;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Personality {
+    /// Not used yet
+    Unassigned,
+    /// Two-wire interface, I2C master
+    Twi,
+    /// SPI master
+    Spi,
+}
+
+impl Personality {
+    /// Value of `ENABLE` that selects the personality
+    fn enable_value(&self) -> u32 {
+        match *self {
+            Personality::Unassigned => 0,
+            Personality::Twi => 5,
+            Personality::Spi => 1,
+        }
+    }
+}
+
+pub struct SerialBox {
+    enable: *const VolatileCell<u32>,
+    personality: Cell<Personality>,
+    /// The board allows the drivers to switch the personality
+    switching: Cell<bool>,
+    /// A transfer of the current personality is in progress
+    busy: Cell<bool>,
+    conflicts: Cell<u32>,
+}
+
+pub static mut SERIAL_BOX0: SerialBox = SerialBox::new(0);
+pub static mut SERIAL_BOX1: SerialBox = SerialBox::new(1);
+
+/// The serial box of SPI and TWI instance `instance`
+pub unsafe fn get(instance: usize) -> &'static SerialBox {
+    match instance {
+        0 => &SERIAL_BOX0,
+        _ => &SERIAL_BOX1,
+    }
+}
+
+impl SerialBox {
+    const fn new(instance: usize) -> SerialBox {
+        SerialBox {
+            enable: (INSTANCES[instance] + ENABLE_OFFSET) as *const VolatileCell<u32>,
+            personality: Cell::new(Personality::Unassigned),
+            switching: Cell::new(false),
+            busy: Cell::new(false),
+            conflicts: Cell::new(0),
+        }
+    }
+
+    /// Give the block a fixed personality. Fails with `EBUSY` during a
+    /// transfer of the other personality.
+    pub fn configure(&self, personality: Personality) -> ReturnCode {
+        if self.busy.get() && self.personality.get() != personality {
+            return ReturnCode::EBUSY;
+        }
+        self.switching.set(false);
+        self.personality.set(personality);
+        ReturnCode::SUCCESS
+    }
+
+    /// Let the drivers of both personalities use the block, switching it
+    /// between transfers.
+    pub fn allow_switching(&self) {
+        self.switching.set(true);
+    }
+
+    pub fn personality(&self) -> Personality {
+        self.personality.get()
+    }
+
+    /// Transfers refused because the block was fixed to, or busy with, the
+    /// other personality.
+    pub fn conflicts(&self) -> u32 {
+        self.conflicts.get()
+    }
+
+    /// Called by a driver before it starts a transfer. Selects the block for
+    /// `personality`, switching it if it is unassigned or shared and idle,
+    /// and marks it busy until `release`.
+    pub fn acquire(&self, personality: Personality) -> ReturnCode {
+        let current = self.personality.get();
+        if current != personality {
+            let switchable = current == Personality::Unassigned
+                || (self.switching.get() && !self.busy.get());
+            if !switchable {
+                self.conflicts.set(self.conflicts.get() + 1);
+                return if self.busy.get() {
+                    ReturnCode::EBUSY
+                } else {
+                    ReturnCode::ENOSUPPORT
+                };
+            }
+            self.personality.set(personality);
+        } else if self.busy.get() {
+            return ReturnCode::EBUSY;
+        }
+
+        let enable = unsafe { &*self.enable };
+        if enable.get() != personality.enable_value() {
+            // Disabled first so the other personality lets go of the pins
+            enable.set(0);
+            enable.set(personality.enable_value());
+        }
+        self.busy.set(true);
+        ReturnCode::SUCCESS
+    }
+
+    /// Called by a driver once its transfer has completed.
+    pub fn release(&self, personality: Personality) {
+        if self.personality.get() == personality {
+            self.busy.set(false);
+        }
+    }
+}
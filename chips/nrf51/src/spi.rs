@@ -0,0 +1,384 @@
+//! Implementation of SPI master for the nRF51 `SPI` peripherals.
+//!
+//! SPI0 and SPI1 are the other personality of the serial boxes of TWI0 and
+//! TWI1, see `serial_box`. There is no EasyDMA on the nRF51: the driver
+//! writes each byte to `TXD` and reads the byte clocked in at the same time
+//! from `RXD` on the `READY` event. `TXD` is double buffered, so the next
+//! byte is always queued while the current one is on the wire.
+//!
+//! Each transfer acquires the serial box, and `read_write_bytes` returns
+//! `EBUSY` or `ENOSUPPORT` when the TWI holds it. The pins, the rate, the
+//! clock polarity and the phase are written again at the start of every
+//! transfer, in case the TWI used the block in between.
+//!
+//! The single byte operations wait for the byte to be clocked out, and do
+//! nothing if the serial box is not available.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! nrf51::spi::SPI1.configure(Pinmux::new(29), Pinmux::new(25), Pinmux::new(28));
+//! let mux_spi = static_init!(MuxSpiMaster<'static, nrf51::spi::SPI>,
+//!                            MuxSpiMaster::new(&nrf51::spi::SPI1));
+//! hil::spi::SpiMaster::set_client(&nrf51::spi::SPI1, mux_spi);
+//! hil::spi::SpiMaster::init(&nrf51::spi::SPI1);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::regs::{ReadOnly, ReadWrite, WriteOnly};
+use kernel::common::take_cell::TakeCell;
+use kernel::hil;
+use kernel::ReturnCode;
+use nrf5x::pinmux::Pinmux;
+use serial_box::{self, Personality};
+
+const INSTANCES: [usize; 2] = [0x40003000, 0x40004000];
+
+#[repr(C)]
+struct SpiRegisters {
+    _reserved0: [u32; 66],
+    /// TXD byte sent and RXD byte received
+    /// Address: 0x108 - 0x10C
+    events_ready: ReadWrite<u32, Event::Register>,
+    _reserved1: [u32; 126],
+    /// Enable interrupt
+    /// Address: 0x304 - 0x308
+    intenset: ReadWrite<u32, Interrupt::Register>,
+    /// Disable interrupt
+    /// Address: 0x308 - 0x30C
+    intenclr: WriteOnly<u32, Interrupt::Register>,
+    _reserved2: [u32; 125],
+    /// Enable SPI, written by the serial box
+    /// Address: 0x500 - 0x504
+    _enable: ReadWrite<u32>,
+    _reserved3: [u32; 1],
+    /// Pin select for SCK
+    /// Address: 0x508 - 0x50C
+    pselsck: ReadWrite<u32>,
+    /// Pin select for MOSI
+    /// Address: 0x50C - 0x510
+    pselmosi: ReadWrite<u32>,
+    /// Pin select for MISO
+    /// Address: 0x510 - 0x514
+    pselmiso: ReadWrite<u32>,
+    _reserved4: [u32; 1],
+    /// RXD register
+    /// Address: 0x518 - 0x51C
+    rxd: ReadOnly<u32>,
+    /// TXD register
+    /// Address: 0x51C - 0x520
+    txd: ReadWrite<u32>,
+    _reserved5: [u32; 1],
+    /// SPI frequency
+    /// Address: 0x524 - 0x528
+    frequency: ReadWrite<u32>,
+    _reserved6: [u32; 11],
+    /// Configuration register
+    /// Address: 0x554 - 0x558
+    config: ReadWrite<u32, Config::Register>,
+}
+
+register_bitfields! [u32,
+    /// Read event
+    Event [
+        READY OFFSET(0) NUMBITS(1)
+    ],
+
+    /// Enable and disable interrupts
+    Interrupt [
+        READY OFFSET(2) NUMBITS(1)
+    ],
+
+    /// Configuration register
+    Config [
+        /// Bit order
+        ORDER OFFSET(0) NUMBITS(1) [
+            MsbFirst = 0,
+            LsbFirst = 1
+        ],
+        /// Serial clock phase
+        CPHA OFFSET(1) NUMBITS(1) [
+            Leading = 0,
+            Trailing = 1
+        ],
+        /// Serial clock polarity
+        CPOL OFFSET(2) NUMBITS(1) [
+            ActiveHigh = 0,
+            ActiveLow = 1
+        ]
+    ]
+];
+
+/// The rates the SPI supports, in Hz, and the `FREQUENCY` value of each
+const RATES: [(u32, u32); 7] = [
+    (8_000_000, 0x80000000),
+    (4_000_000, 0x40000000),
+    (2_000_000, 0x20000000),
+    (1_000_000, 0x10000000),
+    (500_000, 0x08000000),
+    (250_000, 0x04000000),
+    (125_000, 0x02000000),
+];
+
+/// An SPI master device.
+pub struct SPI {
+    registers: *const SpiRegisters,
+    instance: usize,
+    client: Cell<Option<&'static hil::spi::SpiMasterClient>>,
+    chip_select: Cell<Option<&'static hil::gpio::Pin>>,
+    /// Keep the chip select asserted after a transfer, see `hold_low`
+    hold_low: Cell<bool>,
+    sck: Cell<u32>,
+    mosi: Cell<u32>,
+    miso: Cell<u32>,
+    rate: Cell<u32>,
+    polarity: Cell<hil::spi::ClockPolarity>,
+    phase: Cell<hil::spi::ClockPhase>,
+    busy: Cell<bool>,
+    tx_buf: TakeCell<'static, [u8]>,
+    rx_buf: TakeCell<'static, [u8]>,
+    len: Cell<usize>,
+    /// Bytes written to `TXD`
+    tx_index: Cell<usize>,
+    /// Bytes read from `RXD`
+    rx_index: Cell<usize>,
+}
+
+impl SPI {
+    const fn new(instance: usize) -> SPI {
+        SPI {
+            registers: INSTANCES[instance] as *const SpiRegisters,
+            instance: instance,
+            client: Cell::new(None),
+            chip_select: Cell::new(None),
+            hold_low: Cell::new(false),
+            // Disconnected, the reset value of the pin selection
+            sck: Cell::new(0xFFFFFFFF),
+            mosi: Cell::new(0xFFFFFFFF),
+            miso: Cell::new(0xFFFFFFFF),
+            rate: Cell::new(250_000),
+            polarity: Cell::new(hil::spi::ClockPolarity::IdleLow),
+            phase: Cell::new(hil::spi::ClockPhase::SampleLeading),
+            busy: Cell::new(false),
+            tx_buf: TakeCell::empty(),
+            rx_buf: TakeCell::empty(),
+            len: Cell::new(0),
+            tx_index: Cell::new(0),
+            rx_index: Cell::new(0),
+        }
+    }
+
+    fn regs(&self) -> &SpiRegisters {
+        unsafe { &*self.registers }
+    }
+
+    /// Configures the SCK, MOSI and MISO pins of the SPI.
+    pub fn configure(&self, sck: Pinmux, mosi: Pinmux, miso: Pinmux) {
+        self.sck.set(sck.into());
+        self.mosi.set(mosi.into());
+        self.miso.set(miso.into());
+    }
+
+    /// Write the pins and the settings to the registers shared with the TWI
+    fn apply_settings(&self) {
+        let regs = self.regs();
+        regs.pselsck.set(self.sck.get());
+        regs.pselmosi.set(self.mosi.get());
+        regs.pselmiso.set(self.miso.get());
+        let frequency = RATES
+            .iter()
+            .find(|&&(rate, _)| rate == self.rate.get())
+            .map_or(RATES[RATES.len() - 1].1, |&(_, frequency)| frequency);
+        regs.frequency.set(frequency);
+        let polarity = match self.polarity.get() {
+            hil::spi::ClockPolarity::IdleLow => Config::CPOL::ActiveHigh,
+            hil::spi::ClockPolarity::IdleHigh => Config::CPOL::ActiveLow,
+        };
+        let phase = match self.phase.get() {
+            hil::spi::ClockPhase::SampleLeading => Config::CPHA::Leading,
+            hil::spi::ClockPhase::SampleTrailing => Config::CPHA::Trailing,
+        };
+        regs.config.write(Config::ORDER::MsbFirst + phase + polarity);
+    }
+
+    /// Acquire the serial box for a transfer and set it up
+    fn acquire(&self) -> ReturnCode {
+        let rc = unsafe { serial_box::get(self.instance) }.acquire(Personality::Spi);
+        if rc == ReturnCode::SUCCESS {
+            self.apply_settings();
+            self.chip_select.get().map(|cs| cs.clear());
+        }
+        rc
+    }
+
+    fn release(&self) {
+        if !self.hold_low.get() {
+            self.chip_select.get().map(|cs| cs.set());
+        }
+        unsafe { serial_box::get(self.instance) }.release(Personality::Spi);
+    }
+
+    /// Queue the next byte of the write buffer, or a zero past its end
+    fn write_next(&self) {
+        let index = self.tx_index.get();
+        if index < self.len.get() {
+            let byte = self.tx_buf.map_or(0, |buf| buf[index]);
+            self.regs().txd.set(byte as u32);
+            self.tx_index.set(index + 1);
+        }
+    }
+
+    pub fn handle_interrupt(&self) {
+        let regs = self.regs();
+        if !regs.events_ready.is_set(Event::READY) {
+            return;
+        }
+        regs.events_ready.write(Event::READY::CLEAR);
+
+        let index = self.rx_index.get();
+        let byte = regs.rxd.get() as u8;
+        self.rx_buf.map(|buf| buf[index] = byte);
+        self.rx_index.set(index + 1);
+
+        if index + 1 < self.len.get() {
+            self.write_next();
+            return;
+        }
+
+        regs.intenclr.write(Interrupt::READY::SET);
+        self.busy.set(false);
+        self.release();
+        let len = self.len.get();
+        self.client.get().map(|client| {
+            self.tx_buf.take().map(|tx_buf| {
+                client.read_write_done(tx_buf, self.rx_buf.take(), len);
+            });
+        });
+    }
+}
+
+impl hil::spi::SpiMaster for SPI {
+    type ChipSelect = &'static hil::gpio::Pin;
+
+    fn set_client(&self, client: &'static hil::spi::SpiMasterClient) {
+        self.client.set(Some(client));
+    }
+
+    fn init(&self) {}
+
+    fn is_busy(&self) -> bool {
+        self.busy.get()
+    }
+
+    fn read_write_bytes(
+        &self,
+        write_buffer: &'static mut [u8],
+        read_buffer: Option<&'static mut [u8]>,
+        len: usize,
+    ) -> ReturnCode {
+        if self.busy.get() {
+            return ReturnCode::EBUSY;
+        }
+        let len = read_buffer
+            .as_ref()
+            .map_or(cmp::min(len, write_buffer.len()), |buf| {
+                cmp::min(len, cmp::min(write_buffer.len(), buf.len()))
+            });
+        if len == 0 {
+            return ReturnCode::ESIZE;
+        }
+        let rc = self.acquire();
+        if rc != ReturnCode::SUCCESS {
+            return rc;
+        }
+
+        self.busy.set(true);
+        self.len.set(len);
+        self.tx_index.set(0);
+        self.rx_index.set(0);
+        self.tx_buf.replace(write_buffer);
+        read_buffer.map(|buf| self.rx_buf.replace(buf));
+
+        let regs = self.regs();
+        regs.events_ready.write(Event::READY::CLEAR);
+        regs.intenset.write(Interrupt::READY::SET);
+        // Two bytes in flight, the one being sent and the one in the buffer
+        self.write_next();
+        self.write_next();
+        ReturnCode::SUCCESS
+    }
+
+    fn write_byte(&self, val: u8) {
+        self.read_write_byte(val);
+    }
+
+    fn read_byte(&self) -> u8 {
+        self.read_write_byte(0)
+    }
+
+    fn read_write_byte(&self, val: u8) -> u8 {
+        if self.busy.get() || self.acquire() != ReturnCode::SUCCESS {
+            return 0;
+        }
+        let regs = self.regs();
+        regs.events_ready.write(Event::READY::CLEAR);
+        regs.txd.set(val as u32);
+        while !regs.events_ready.is_set(Event::READY) {}
+        regs.events_ready.write(Event::READY::CLEAR);
+        let byte = regs.rxd.get() as u8;
+        self.release();
+        byte
+    }
+
+    fn specify_chip_select(&self, cs: Self::ChipSelect) {
+        cs.make_output();
+        cs.set();
+        self.chip_select.set(Some(cs));
+    }
+
+    /// Sets the highest supported rate not above `rate`, and returns it.
+    fn set_rate(&self, rate: u32) -> u32 {
+        let actual = RATES
+            .iter()
+            .map(|&(supported, _)| supported)
+            .find(|&supported| supported <= rate)
+            .unwrap_or(RATES[RATES.len() - 1].0);
+        self.rate.set(actual);
+        actual
+    }
+
+    fn get_rate(&self) -> u32 {
+        self.rate.get()
+    }
+
+    fn set_clock(&self, polarity: hil::spi::ClockPolarity) {
+        self.polarity.set(polarity);
+    }
+
+    fn get_clock(&self) -> hil::spi::ClockPolarity {
+        self.polarity.get()
+    }
+
+    fn set_phase(&self, phase: hil::spi::ClockPhase) {
+        self.phase.set(phase);
+    }
+
+    fn get_phase(&self) -> hil::spi::ClockPhase {
+        self.phase.get()
+    }
+
+    fn hold_low(&self) {
+        self.hold_low.set(true);
+    }
+
+    fn release_low(&self) {
+        self.hold_low.set(false);
+    }
+}
+
+/// SPI master instance 0.
+pub static mut SPI0: SPI = SPI::new(0);
+/// SPI master instance 1.
+pub static mut SPI1: SPI = SPI::new(1);
@@ -0,0 +1,132 @@
+//! SysTick for the nRF51, on RTC0
+//!
+//! The Cortex-M0 of the nRF51 has no SysTick, so without one the scheduler
+//! cannot take the CPU back from a process that does not yield. RTC0 counts
+//! the 32.768kHz low frequency clock, which gives a resolution of about 31
+//! us and, with its 24-bit counter, timeslices of up to 512 s. RTC1 drives
+//! the alarms and is left alone.
+//!
+//! The compare event stays set once the timeslice has run out, so
+//! `overflowed` still sees it after the interrupt has been serviced.
+
+use clock::{ClockDomain, CLOCK};
+use core::cell::Cell;
+use kernel;
+use kernel::common::VolatileCell;
+
+#[repr(C)]
+struct RtcRegisters {
+    tasks_start: VolatileCell<u32>,
+    tasks_stop: VolatileCell<u32>,
+    tasks_clear: VolatileCell<u32>,
+    tasks_trigovrflw: VolatileCell<u32>,
+    _reserved1: [u32; 60],
+    events_tick: VolatileCell<u32>,
+    events_ovrflw: VolatileCell<u32>,
+    _reserved2: [u32; 14],
+    events_compare: [VolatileCell<u32>; 4],
+    _reserved3: [u32; 109],
+    intenset: VolatileCell<u32>,
+    intenclr: VolatileCell<u32>,
+    _reserved4: [u32; 13],
+    evten: VolatileCell<u32>,
+    evtenset: VolatileCell<u32>,
+    evtenclr: VolatileCell<u32>,
+    _reserved5: [u32; 110],
+    counter: VolatileCell<u32>,
+    prescaler: VolatileCell<u32>,
+    _reserved6: [u32; 13],
+    cc: [VolatileCell<u32>; 4],
+}
+
+const RTC0_BASE: usize = 0x4000B000;
+
+const COMPARE0_EVENT: u32 = 1 << 16;
+const COUNTER_MAX: u64 = 0xFFFFFF;
+const FREQUENCY: u64 = 32768;
+
+pub struct SysTick {
+    regs: *const RtcRegisters,
+    /// Whether RTC0 holds a request on the low frequency clock
+    running: Cell<bool>,
+}
+
+pub static mut SYSTICK: SysTick = SysTick::new();
+
+impl SysTick {
+    const fn new() -> SysTick {
+        SysTick {
+            regs: RTC0_BASE as *const RtcRegisters,
+            running: Cell::new(false),
+        }
+    }
+
+    fn us_to_ticks(us: u32) -> u32 {
+        let ticks = (us as u64 * FREQUENCY + 999_999) / 1_000_000;
+        if ticks > COUNTER_MAX {
+            COUNTER_MAX as u32
+        } else {
+            ticks as u32
+        }
+    }
+
+    /// The timeslice ran out while a process was running. The interrupt
+    /// brought the CPU back to the kernel, only mask it.
+    pub fn handle_interrupt(&self) {
+        let regs = unsafe { &*self.regs };
+        regs.intenclr.set(COMPARE0_EVENT);
+    }
+}
+
+impl kernel::SysTick for SysTick {
+    fn set_timer(&self, us: u32) {
+        let regs = unsafe { &*self.regs };
+        regs.tasks_clear.set(1);
+        regs.cc[0].set(SysTick::us_to_ticks(us));
+        regs.events_compare[0].set(0);
+    }
+
+    fn greater_than(&self, us: u32) -> bool {
+        let regs = unsafe { &*self.regs };
+        if regs.events_compare[0].get() != 0 {
+            return false;
+        }
+        let left = regs.cc[0].get().saturating_sub(regs.counter.get());
+        left > SysTick::us_to_ticks(us)
+    }
+
+    fn overflowed(&self) -> bool {
+        let regs = unsafe { &*self.regs };
+        regs.events_compare[0].get() != 0
+    }
+
+    fn reset(&self) {
+        let regs = unsafe { &*self.regs };
+        regs.tasks_stop.set(1);
+        regs.intenclr.set(COMPARE0_EVENT);
+        regs.evtenclr.set(COMPARE0_EVENT);
+        regs.tasks_clear.set(1);
+        regs.cc[0].set(0);
+        regs.events_compare[0].set(0);
+        if self.running.get() {
+            self.running.set(false);
+            unsafe { CLOCK.release(ClockDomain::Low) };
+        }
+    }
+
+    fn enable(&self, with_interrupt: bool) {
+        let regs = unsafe { &*self.regs };
+        if with_interrupt {
+            regs.intenset.set(COMPARE0_EVENT);
+        } else {
+            regs.intenclr.set(COMPARE0_EVENT);
+        }
+        if !self.running.get() {
+            self.running.set(true);
+            unsafe { CLOCK.request(ClockDomain::Low) };
+            regs.prescaler.set(0);
+            regs.evtenset.set(COMPARE0_EVENT);
+            regs.tasks_start.set(1);
+        }
+    }
+}
@@ -1,12 +1,39 @@
+//! UART driver, nRF51
+//!
+//! The UART is only enabled while it is in use: while a transmission is in
+//! progress or a receive is outstanding. In between it is disabled and its
+//! request on the high frequency clock withdrawn, since an enabled UART
+//! keeps the clock running and noticeably raises the sleep current. It is
+//! enabled again transparently by the next `transmit` or `receive`.
+
+use clock;
 use core::cell::Cell;
+use core::cmp;
 use kernel::common::take_cell::TakeCell;
 use kernel::common::VolatileCell;
+use kernel::hil::gpio::Pin;
 use kernel::hil::uart;
+use kernel::ReturnCode;
+use nrf5x;
 use nrf5x::pinmux::Pinmux;
 
 pub static mut UART0: UART = UART::new();
 const UART_BASE: u32 = 0x40002000;
 
+const INTERRUPT_RXDRDY: u32 = 1 << 2;
+const INTERRUPT_TXDRDY: u32 = 1 << 7;
+const INTERRUPT_ERROR: u32 = 1 << 9;
+
+const ERRORSRC_OVERRUN: u32 = 1 << 0;
+const ERRORSRC_PARITY: u32 = 1 << 1;
+const ERRORSRC_FRAMING: u32 = 1 << 2;
+
+const CONFIG_HWFC: u32 = 1 << 0;
+const CONFIG_PARITY_INCLUDED: u32 = 0x7 << 1;
+
+const ENABLE_ON: u32 = 0b100;
+const ENABLE_OFF: u32 = 0;
+
 #[repr(C)]
 pub struct UartRegisters {
     pub task_startrx: VolatileCell<u32>,
@@ -55,6 +82,12 @@ pub struct UART {
     buffer: TakeCell<'static, [u8]>,
     len: Cell<usize>,
     index: Cell<usize>,
+    rx_buffer: TakeCell<'static, [u8]>,
+    rx_len: Cell<usize>,
+    rx_index: Cell<usize>,
+    /// Transfers keeping the UART enabled, an outstanding receive and a
+    /// transmission in progress each counting as one
+    users: Cell<usize>,
 }
 
 #[derive(Copy, Clone)]
@@ -70,6 +103,10 @@ impl UART {
             buffer: TakeCell::empty(),
             len: Cell::new(0),
             index: Cell::new(0),
+            rx_buffer: TakeCell::empty(),
+            rx_len: Cell::new(0),
+            rx_index: Cell::new(0),
+            users: Cell::new(0),
         }
     }
 
@@ -82,6 +119,15 @@ impl UART {
     pub fn configure(&self, tx: Pinmux, rx: Pinmux, cts: Pinmux, rts: Pinmux) {
         let regs = unsafe { &*self.regs };
 
+        // The pins are back under GPIO control while the UART is disabled,
+        // TXD is held at its idle level then rather than left floating
+        let tx_pin: u32 = tx.into();
+        unsafe {
+            let pin = &nrf5x::gpio::PORT[tx_pin as usize];
+            pin.set();
+            pin.make_output();
+        }
+
         regs.pseltxd.set(tx);
         regs.pselrxd.set(rx);
         regs.pselcts.set(cts);
@@ -90,53 +136,143 @@ impl UART {
 
     fn set_baud_rate(&self, baud_rate: u32) {
         let regs = unsafe { &*self.regs };
-        match baud_rate {
-            1200 => regs.baudrate.set(0x0004F000),
-            2400 => regs.baudrate.set(0x0009D000),
-            4800 => regs.baudrate.set(0x0013B000),
-            9600 => regs.baudrate.set(0x00275000),
-            14400 => regs.baudrate.set(0x003B0000),
-            19200 => regs.baudrate.set(0x004EA000),
-            28800 => regs.baudrate.set(0x0075F000),
-            38400 => regs.baudrate.set(0x009D5000),
-            57600 => regs.baudrate.set(0x00EBF000),
-            76800 => regs.baudrate.set(0x013A9000),
-            115200 => regs.baudrate.set(0x01D7E000),
-            230400 => regs.baudrate.set(0x03AFB000),
-            250000 => regs.baudrate.set(0x04000000),
-            460800 => regs.baudrate.set(0x075F7000),
-            1000000 => regs.baudrate.set(0x10000000),
-            _ => regs.baudrate.set(0x01D7E000), //setting default to 115200
+        //setting default to 115200
+        regs.baudrate
+            .set(baud_rate_register(baud_rate).unwrap_or(0x01D7E000));
+    }
+
+    /// Declare a transfer that needs the UART. The first one enables it,
+    /// after starting the high frequency clock the baud rate generator runs
+    /// from.
+    fn acquire(&self) {
+        let regs = unsafe { &*self.regs };
+        if self.users.get() == 0 {
+            unsafe { clock::CLOCK.request(clock::ClockDomain::High) };
+            regs.enable.set(ENABLE_ON);
         }
+        self.users.set(self.users.get() + 1);
     }
 
-    pub fn enable(&self) {
+    /// Withdraw a transfer declared with `acquire`. The UART is disabled,
+    /// and the high frequency clock released, after the last one.
+    fn release(&self) {
         let regs = unsafe { &*self.regs };
-        regs.enable.set(0b100);
+        match self.users.get() {
+            0 => {}
+            1 => {
+                self.users.set(0);
+                regs.enable.set(ENABLE_OFF);
+                unsafe { clock::CLOCK.release(clock::ClockDomain::High) };
+            }
+            n => self.users.set(n - 1),
+        }
+    }
+
+    /// Whether the UART is currently enabled
+    pub fn is_enabled(&self) -> bool {
+        self.users.get() > 0
     }
 
+    /// Enable interrupts for received bytes and receive errors
     pub fn enable_rx_interrupts(&self) {
         let regs = unsafe { &*self.regs };
-        regs.intenset.set(1 << 3 as u32);
+        regs.intenset.set(INTERRUPT_RXDRDY | INTERRUPT_ERROR);
     }
 
     pub fn enable_tx_interrupts(&self) {
         let regs = unsafe { &*self.regs };
-        regs.intenset.set(1 << 7 as u32);
+        regs.intenset.set(INTERRUPT_TXDRDY);
     }
 
     pub fn disable_rx_interrupts(&self) {
         let regs = unsafe { &*self.regs };
-        regs.intenclr.set(1 << 3 as u32);
+        regs.intenclr.set(INTERRUPT_RXDRDY | INTERRUPT_ERROR);
     }
 
     pub fn disable_tx_interrupts(&self) {
         let regs = unsafe { &*self.regs };
-        regs.intenclr.set(1 << 7 as u32);
+        regs.intenclr.set(INTERRUPT_TXDRDY);
+    }
+
+    /// Enable or disable RTS/CTS hardware flow control. The CTS and RTS pins
+    /// are the ones passed to `configure`.
+    pub fn set_flow_control(&self, enabled: bool) {
+        let regs = unsafe { &*self.regs };
+        let config = regs.config.get() & !CONFIG_HWFC;
+        regs.config.set(if enabled {
+            config | CONFIG_HWFC
+        } else {
+            config
+        });
+    }
+
+    /// Stop receiving and hand the buffer back to the client with the number
+    /// of bytes received so far.
+    fn finish_rx(&self, error: uart::Error) {
+        let regs = unsafe { &*self.regs };
+
+        self.disable_rx_interrupts();
+        regs.task_stoprx.set(1);
+
+        let rx_index = self.rx_index.get();
+        self.rx_buffer.take().map(|buffer| {
+            self.client
+                .get()
+                .map(move |client| client.receive_complete(buffer, rx_index, error));
+            // Released after the callback, so that a client receiving again
+            // right away does not power cycle the UART and the clock
+            self.release();
+        });
+    }
+
+    fn handle_rx(&self) {
+        let regs = unsafe { &*self.regs };
+
+        if regs.event_error.get() != 0 {
+            regs.event_error.set(0);
+            let errorsrc = regs.errorsrc.get();
+            // Error sources are cleared by writing 1s
+            regs.errorsrc.set(errorsrc);
+
+            if self.rx_buffer.is_some() {
+                let error = if errorsrc & ERRORSRC_OVERRUN != 0 {
+                    uart::Error::OverrunError
+                } else if errorsrc & ERRORSRC_PARITY != 0 {
+                    uart::Error::ParityError
+                } else if errorsrc & ERRORSRC_FRAMING != 0 {
+                    uart::Error::FramingError
+                } else {
+                    // A break condition: the line was held low
+                    uart::Error::FramingError
+                };
+                self.finish_rx(error);
+                return;
+            }
+        }
+
+        if regs.event_rxdrdy.get() != 0 {
+            regs.event_rxdrdy.set(0);
+            // Reading RXD lets the next byte move into the receive FIFO, so
+            // it is read even if no receive is in progress.
+            let byte = regs.rxd.get() as u8;
+
+            let done = self.rx_buffer.map_or(false, |buffer| {
+                let index = self.rx_index.get();
+                buffer[index] = byte;
+                self.rx_index.set(index + 1);
+                index + 1 == self.rx_len.get()
+            });
+            if done {
+                self.finish_rx(uart::Error::CommandComplete);
+            }
+        }
     }
 
     pub fn handle_interrupt(&mut self) {
         let regs = unsafe { &*self.regs };
+
+        self.handle_rx();
+
         let tx = regs.event_txdrdy.get() != 0;
 
         if tx {
@@ -146,10 +282,11 @@ impl UART {
                 regs.task_stoptx.set(1 as u32);
 
                 // Signal client write done
-                self.client.get().map(|client| {
-                    self.buffer.take().map(|buffer| {
+                self.buffer.take().map(|buffer| {
+                    self.client.get().map(move |client| {
                         client.transmit_complete(buffer, uart::Error::CommandComplete);
                     });
+                    self.release();
                 });
 
                 return;
@@ -164,9 +301,16 @@ impl UART {
         }
     }
 
+    /// Transmit one byte, the caller polling `tx_ready` for its completion.
+    /// Meant for the panic handler: the UART is enabled if it was not, and
+    /// left so.
     pub unsafe fn send_byte(&self, byte: u8) {
         let regs = &*self.regs;
 
+        if self.users.get() == 0 {
+            self.acquire();
+        }
+
         self.index.set(1);
         self.len.set(1);
 
@@ -180,11 +324,6 @@ impl UART {
         let regs = unsafe { &*self.regs };
         regs.event_txdrdy.get() & 0b1 != 0
     }
-
-    fn rx_ready(&self) -> bool {
-        let regs = unsafe { &*self.regs };
-        regs.event_rxdrdy.get() & 0b1 != 0
-    }
 }
 
 impl uart::UART for UART {
@@ -192,8 +331,15 @@ impl uart::UART for UART {
         self.client.set(Some(client));
     }
 
+    /// Settings the UART does not support fall back to its defaults: one
+    /// stop bit and no parity, 115200 baud. `reconfigure` rejects them.
     fn init(&self, params: uart::UARTParams) {
-        self.enable();
+        let regs = unsafe { &*self.regs };
+
+        regs.config.set(parity_register(&params).unwrap_or(0));
+        self.set_flow_control(params.hw_flow_control);
+
+        // The UART is only enabled once there is something to transfer
         self.set_baud_rate(params.baud_rate);
     }
 
@@ -204,6 +350,8 @@ impl uart::UART for UART {
             return;
         }
 
+        self.acquire();
+
         self.index.set(1);
         self.len.set(tx_len);
 
@@ -214,15 +362,95 @@ impl uart::UART for UART {
         self.buffer.replace(tx_data);
     }
 
-    // Blocking implementation
+    /// Receive `rx_len` bytes into `rx_buffer`. Bytes are copied out of the
+    /// receive FIFO from the RXDRDY interrupt and the client is called once
+    /// the buffer is full or a receive error occurs.
     fn receive(&self, rx_buffer: &'static mut [u8], rx_len: usize) {
         let regs = unsafe { &*self.regs };
+
+        if self.rx_buffer.is_some() {
+            self.client.get().map(move |client| {
+                client.receive_complete(rx_buffer, 0, uart::Error::RepeatCallError)
+            });
+            return;
+        }
+
+        let rx_len = cmp::min(rx_len, rx_buffer.len());
+        if rx_len == 0 {
+            self.client.get().map(move |client| {
+                client.receive_complete(rx_buffer, 0, uart::Error::CommandComplete)
+            });
+            return;
+        }
+
+        self.acquire();
+
+        self.rx_len.set(rx_len);
+        self.rx_index.set(0);
+        self.rx_buffer.replace(rx_buffer);
+
+        regs.event_rxdrdy.set(0);
+        regs.event_error.set(0);
+        self.enable_rx_interrupts();
         regs.task_startrx.set(1);
-        let mut i = 0;
-        while i < rx_len {
-            while !self.rx_ready() {}
-            rx_buffer[i] = regs.rxd.get() as u8;
-            i += 1;
+    }
+
+    fn reconfigure(&self, params: uart::UARTParams) -> ReturnCode {
+        let parity = match parity_register(&params) {
+            Some(parity) => parity,
+            None => return ReturnCode::EINVAL,
+        };
+        let baud_rate = match baud_rate_register(params.baud_rate) {
+            Some(baud_rate) => baud_rate,
+            None => return ReturnCode::EINVAL,
+        };
+        if self.buffer.is_some() || self.rx_buffer.is_some() {
+            return ReturnCode::EBUSY;
         }
+
+        // Nothing is being transferred, so the UART is disabled
+        let regs = unsafe { &*self.regs };
+        regs.config.set(parity);
+        self.set_flow_control(params.hw_flow_control);
+        regs.baudrate.set(baud_rate);
+        ReturnCode::SUCCESS
+    }
+
+    fn abort_receive(&self) {
+        if self.rx_buffer.is_some() {
+            self.finish_rx(uart::Error::Aborted);
+        }
+    }
+}
+
+/// Parity bits of the CONFIG register for `params`, if the UART supports
+/// them. It only supports one stop bit and either no parity or even parity.
+fn parity_register(params: &uart::UARTParams) -> Option<u32> {
+    match (params.parity, params.stop_bits) {
+        (uart::Parity::None, uart::StopBits::One) => Some(0),
+        (uart::Parity::Even, uart::StopBits::One) => Some(CONFIG_PARITY_INCLUDED),
+        _ => None,
+    }
+}
+
+/// Value of the BAUDRATE register for `baud_rate`, if the UART supports it
+fn baud_rate_register(baud_rate: u32) -> Option<u32> {
+    match baud_rate {
+        1200 => Some(0x0004F000),
+        2400 => Some(0x0009D000),
+        4800 => Some(0x0013B000),
+        9600 => Some(0x00275000),
+        14400 => Some(0x003B0000),
+        19200 => Some(0x004EA000),
+        28800 => Some(0x0075F000),
+        38400 => Some(0x009D5000),
+        57600 => Some(0x00EBF000),
+        76800 => Some(0x013A9000),
+        115200 => Some(0x01D7E000),
+        230400 => Some(0x03AFB000),
+        250000 => Some(0x04000000),
+        460800 => Some(0x075F7000),
+        1000000 => Some(0x10000000),
+        _ => None,
     }
 }
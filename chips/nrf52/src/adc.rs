@@ -0,0 +1,674 @@
+//! SAADC driver, nRF52
+//!
+//! The nRF52 SAADC is a 12-bit successive approximation converter that
+//! writes its results to RAM through EasyDMA. Each `AdcChannel` selects one
+//! of the eight analog inputs (AIN0-AIN7), or the supply voltage, together
+//! with the gain and reference the conversion is made against. Samples are
+//! right justified; negative results, possible because of offset error near
+//! ground, are reported as 0.
+//!
+//! Single and continuous sampling implement the ADC HIL. Continuous sampling
+//! is paced in hardware: TIMER2 compare events trigger the SAMPLE task
+//! through a PPI channel, and the END event restarts the converter through
+//! another one, so the CPU is only involved to pass samples on.
+//!
+//! The limits mode, started with `monitor`, samples the same way but does
+//! not interrupt for each sample. The SAADC compares every result against a
+//! low and a high limit in hardware and raises its CH[0].LIMITL and
+//! CH[0].LIMITH events. Only the event for the limit not yet crossed is
+//! enabled as an interrupt, so the `LimitClient` is called once per
+//! crossing, and the band between the two limits acts as hysteresis.
+//!
+//! TIMER2 and two PPI channels are owned by this driver while sampling
+//! continuously or monitoring, so neither mode can be used together with
+//! `nrf5x::input_capture`. The driver claims CC[0] of TIMER2 with
+//! `reserve_cc` meanwhile, and both modes fail with `EBUSY` if another
+//! driver holds it.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! nrf52::adc::ADC.set_limit_client(battery_monitor);
+//! // Tell when AIN0 leaves 1.5V - 3V, sampling every 10ms
+//! nrf52::adc::ADC.monitor(&nrf52::adc::CHANNEL_AIN0, 1706, 3413, 10000);
+//! ```
+
+use core::cell::Cell;
+use kernel::common::regs::{ReadOnly, ReadWrite, WriteOnly};
+use kernel::common::VolatileCell;
+use kernel::hil;
+use kernel::ReturnCode;
+use nrf5x::timer::{BitmodeValue, Location, Timer};
+use ppi;
+
+const SAADC_BASE: usize = 0x40007000;
+
+/// 16MHz divided by 2^4
+const TIMER_PRESCALER: u8 = 4;
+
+/// Clear the pacing timer on COMPARE[0]
+const TIMER_SHORT_COMPARE0_CLEAR: u32 = 1;
+
+/// Highest continuous sampling rate, in Hz
+const MAX_FREQUENCY: u32 = 100000;
+
+#[repr(C)]
+struct LimitEvents {
+    /// Last result is equal to or above the high limit
+    limith: ReadWrite<u32, Event::Register>,
+    /// Last result is equal to or below the low limit
+    limitl: ReadWrite<u32, Event::Register>,
+}
+
+#[repr(C)]
+struct ChannelRegisters {
+    /// Positive input
+    pselp: ReadWrite<u32, Psel::Register>,
+    /// Negative input, for differential mode
+    pseln: ReadWrite<u32, Psel::Register>,
+    /// Gain, reference and acquisition time
+    config: ReadWrite<u32, Config::Register>,
+    /// High and low limits
+    limit: ReadWrite<u32, Limit::Register>,
+}
+
+#[repr(C)]
+struct SaadcRegisters {
+    /// Start the ADC and prepare the result buffer in RAM
+    /// Address: 0x000 - 0x004
+    task_start: WriteOnly<u32, Task::Register>,
+    /// Take one sample of each enabled channel
+    /// Address: 0x004 - 0x008
+    task_sample: WriteOnly<u32, Task::Register>,
+    /// Stop the ADC and terminate any ongoing conversion
+    /// Address: 0x008 - 0x00C
+    task_stop: WriteOnly<u32, Task::Register>,
+    /// Start offset auto-calibration
+    /// Address: 0x00C - 0x010
+    task_calibrateoffset: WriteOnly<u32, Task::Register>,
+    _reserved0: [u32; 60],
+    /// The ADC has started
+    /// Address: 0x100 - 0x104
+    event_started: ReadWrite<u32, Event::Register>,
+    /// The result buffer is full
+    /// Address: 0x104 - 0x108
+    event_end: ReadWrite<u32, Event::Register>,
+    /// A conversion task has been completed
+    /// Address: 0x108 - 0x10C
+    event_done: ReadWrite<u32, Event::Register>,
+    /// A result is ready to get transferred to RAM
+    /// Address: 0x10C - 0x110
+    event_resultdone: ReadWrite<u32, Event::Register>,
+    /// Calibration is complete
+    /// Address: 0x110 - 0x114
+    event_calibratedone: ReadWrite<u32, Event::Register>,
+    /// The ADC has stopped
+    /// Address: 0x114 - 0x118
+    event_stopped: ReadWrite<u32, Event::Register>,
+    /// Limit events of each channel
+    /// Address: 0x118 - 0x158
+    event_ch: [LimitEvents; 8],
+    _reserved1: [u32; 107],
+    /// Enable interrupt
+    /// Address: 0x304 - 0x308
+    intenset: ReadWrite<u32, Interrupt::Register>,
+    /// Disable interrupt
+    /// Address: 0x308 - 0x30C
+    intenclr: ReadWrite<u32, Interrupt::Register>,
+    _reserved2: [u32; 61],
+    /// ADC busy
+    /// Address: 0x400 - 0x404
+    status: ReadOnly<u32, Status::Register>,
+    _reserved3: [u32; 63],
+    /// Enable the ADC
+    /// Address: 0x500 - 0x504
+    enable: ReadWrite<u32, Enable::Register>,
+    _reserved4: [u32; 3],
+    /// Channel configuration
+    /// Address: 0x510 - 0x590
+    ch: [ChannelRegisters; 8],
+    _reserved5: [u32; 24],
+    /// Resolution
+    /// Address: 0x5F0 - 0x5F4
+    resolution: ReadWrite<u32, Resolution::Register>,
+    /// Oversampling
+    /// Address: 0x5F4 - 0x5F8
+    oversample: ReadWrite<u32>,
+    /// Sample rate of the internal timer
+    /// Address: 0x5F8 - 0x5FC
+    samplerate: ReadWrite<u32, SampleRate::Register>,
+    _reserved6: [u32; 12],
+    /// Address of the result buffer
+    /// Address: 0x62C - 0x630
+    result_ptr: ReadWrite<u32>,
+    /// Size of the result buffer, in samples
+    /// Address: 0x630 - 0x634
+    result_maxcnt: ReadWrite<u32>,
+    /// Number of samples transferred in the last transaction
+    /// Address: 0x634 - 0x638
+    result_amount: ReadOnly<u32>,
+}
+
+register_bitfields! [u32,
+    Task [
+        ENABLE OFFSET(0) NUMBITS(1)
+    ],
+
+    Event [
+        READY OFFSET(0) NUMBITS(1)
+    ],
+
+    Interrupt [
+        STARTED OFFSET(0) NUMBITS(1),
+        END OFFSET(1) NUMBITS(1),
+        DONE OFFSET(2) NUMBITS(1),
+        RESULTDONE OFFSET(3) NUMBITS(1),
+        CALIBRATEDONE OFFSET(4) NUMBITS(1),
+        STOPPED OFFSET(5) NUMBITS(1),
+        CH0LIMITH OFFSET(6) NUMBITS(1),
+        CH0LIMITL OFFSET(7) NUMBITS(1)
+    ],
+
+    Status [
+        BUSY OFFSET(0) NUMBITS(1)
+    ],
+
+    Enable [
+        ENABLE OFFSET(0) NUMBITS(1) [
+            Disabled = 0,
+            Enabled = 1
+        ]
+    ],
+
+    Psel [
+        /// Analog input, 0 for not connected
+        PSEL OFFSET(0) NUMBITS(5) []
+    ],
+
+    Config [
+        /// Positive input resistor ladder
+        RESP OFFSET(0) NUMBITS(2) [
+            Bypass = 0
+        ],
+        /// Negative input resistor ladder
+        RESN OFFSET(4) NUMBITS(2) [
+            Bypass = 0
+        ],
+        /// Gain
+        GAIN OFFSET(8) NUMBITS(3) [],
+        /// Reference
+        REFSEL OFFSET(12) NUMBITS(1) [],
+        /// Acquisition time
+        TACQ OFFSET(16) NUMBITS(3) [
+            us10 = 2
+        ],
+        /// Single ended or differential
+        MODE OFFSET(20) NUMBITS(1) [
+            SingleEnded = 0
+        ],
+        /// Oversample each SAMPLE task in one burst
+        BURST OFFSET(24) NUMBITS(1) [
+            Disabled = 0
+        ]
+    ],
+
+    Limit [
+        /// Low limit, two's complement
+        LOW OFFSET(0) NUMBITS(16) [],
+        /// High limit, two's complement
+        HIGH OFFSET(16) NUMBITS(16) []
+    ],
+
+    Resolution [
+        VAL OFFSET(0) NUMBITS(3) [
+            Bit8 = 0,
+            Bit10 = 1,
+            Bit12 = 2,
+            Bit14 = 3
+        ]
+    ],
+
+    SampleRate [
+        /// Capture compare value of the internal timer
+        CC OFFSET(0) NUMBITS(11) [],
+        /// Sample on the SAMPLE task or on the internal timer
+        MODE OFFSET(12) NUMBITS(1) [
+            Task = 0,
+            Timers = 1
+        ]
+    ]
+];
+
+/// Analog inputs
+#[derive(Copy, Clone, Debug)]
+pub enum AnalogInput {
+    AIN0 = 1,
+    AIN1 = 2,
+    AIN2 = 3,
+    AIN3 = 4,
+    AIN4 = 5,
+    AIN5 = 6,
+    AIN6 = 7,
+    AIN7 = 8,
+    VDD = 9,
+}
+
+/// Gain applied to the input
+#[derive(Copy, Clone, Debug)]
+pub enum Gain {
+    Gain1_6 = 0,
+    Gain1_5 = 1,
+    Gain1_4 = 2,
+    Gain1_3 = 3,
+    Gain1_2 = 4,
+    Gain1 = 5,
+    Gain2 = 6,
+    Gain4 = 7,
+}
+
+/// Conversion reference
+#[derive(Copy, Clone, Debug)]
+pub enum Reference {
+    /// Internal 0.6V reference
+    Internal = 0,
+    /// Supply voltage scaled by 1/4
+    VddOneQuarter = 1,
+}
+
+/// Representation of an ADC channel on the nRF52.
+pub struct AdcChannel {
+    input: AnalogInput,
+    gain: Gain,
+    reference: Reference,
+}
+
+impl AdcChannel {
+    /// Create a channel sampling `input` with the given gain and reference.
+    pub const fn new(input: AnalogInput, gain: Gain, reference: Reference) -> AdcChannel {
+        AdcChannel {
+            input: input,
+            gain: gain,
+            reference: reference,
+        }
+    }
+
+    /// Create a channel measuring `input` over the full 0 - 3.6V range,
+    /// with a gain of 1/6 against the internal reference.
+    pub const fn with_internal_reference(input: AnalogInput) -> AdcChannel {
+        AdcChannel::new(input, Gain::Gain1_6, Reference::Internal)
+    }
+}
+
+/// Statically allocated ADC channels using the internal reference. Boards
+/// needing another range can create their own `AdcChannel`.
+pub static mut CHANNEL_AIN0: AdcChannel = AdcChannel::with_internal_reference(AnalogInput::AIN0);
+pub static mut CHANNEL_AIN1: AdcChannel = AdcChannel::with_internal_reference(AnalogInput::AIN1);
+pub static mut CHANNEL_AIN2: AdcChannel = AdcChannel::with_internal_reference(AnalogInput::AIN2);
+pub static mut CHANNEL_AIN3: AdcChannel = AdcChannel::with_internal_reference(AnalogInput::AIN3);
+pub static mut CHANNEL_AIN4: AdcChannel = AdcChannel::with_internal_reference(AnalogInput::AIN4);
+pub static mut CHANNEL_AIN5: AdcChannel = AdcChannel::with_internal_reference(AnalogInput::AIN5);
+pub static mut CHANNEL_AIN6: AdcChannel = AdcChannel::with_internal_reference(AnalogInput::AIN6);
+pub static mut CHANNEL_AIN7: AdcChannel = AdcChannel::with_internal_reference(AnalogInput::AIN7);
+pub static mut CHANNEL_VDD: AdcChannel = AdcChannel::with_internal_reference(AnalogInput::VDD);
+
+pub trait LimitClient {
+    /// The monitored input crossed a limit: it rose to or above the high
+    /// limit if `above`, or fell to or below the low limit otherwise.
+    /// `sample` is the conversion result that crossed it.
+    fn limit_crossed(&self, above: bool, sample: u16);
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Mode {
+    Idle,
+    Single,
+    Continuous,
+    Monitor,
+}
+
+pub struct Adc {
+    regs: *const SaadcRegisters,
+    /// Pacing timer for continuous sampling and monitoring
+    timer: Timer,
+    mode: Cell<Mode>,
+    /// EasyDMA target of the conversions
+    sample: VolatileCell<i16>,
+    /// PPI channels connecting the timer to SAMPLE and END to START
+    ppi_channels: Cell<Option<(usize, usize)>>,
+    client: Cell<Option<&'static hil::adc::Client>>,
+    limit_client: Cell<Option<&'static LimitClient>>,
+}
+
+pub static mut ADC: Adc = Adc::new();
+
+impl Adc {
+    const fn new() -> Adc {
+        Adc {
+            regs: SAADC_BASE as *const SaadcRegisters,
+            timer: Timer::new(Location::TIMER2),
+            mode: Cell::new(Mode::Idle),
+            sample: VolatileCell::new(0),
+            ppi_channels: Cell::new(None),
+            client: Cell::new(None),
+            limit_client: Cell::new(None),
+        }
+    }
+
+    pub fn set_client(&self, client: &'static hil::adc::Client) {
+        self.client.set(Some(client));
+    }
+
+    pub fn set_limit_client(&self, client: &'static LimitClient) {
+        self.limit_client.set(Some(client));
+    }
+
+    /// Sample `channel` every `period_us` microseconds and tell the limit
+    /// client whenever a result crosses `high` from below or `low` from
+    /// above. The limits are 12-bit results, as reported by `sample`.
+    pub fn monitor(&self, channel: &AdcChannel, low: u16, high: u16, period_us: u32) -> ReturnCode {
+        if self.mode.get() != Mode::Idle {
+            return ReturnCode::EBUSY;
+        }
+        if low > high || high > 0xFFF || period_us == 0 {
+            return ReturnCode::EINVAL;
+        }
+        let regs = unsafe { &*self.regs };
+
+        self.configure(channel);
+        regs.ch[0]
+            .limit
+            .write(Limit::LOW.val(low as u32) + Limit::HIGH.val(high as u32));
+
+        // Either limit may already be crossed by the first sample
+        regs.event_ch[0].limith.write(Event::READY::CLEAR);
+        regs.event_ch[0].limitl.write(Event::READY::CLEAR);
+        regs.intenset
+            .write(Interrupt::CH0LIMITH::SET + Interrupt::CH0LIMITL::SET);
+
+        let rc = self.start_paced(period_us);
+        if rc == ReturnCode::SUCCESS {
+            self.mode.set(Mode::Monitor);
+        } else {
+            self.stop();
+        }
+        rc
+    }
+
+    /// Stop monitoring started with `monitor`.
+    pub fn stop_monitoring(&self) -> ReturnCode {
+        if self.mode.get() == Mode::Monitor {
+            self.stop();
+        }
+        ReturnCode::SUCCESS
+    }
+
+    fn configure(&self, channel: &AdcChannel) {
+        let regs = unsafe { &*self.regs };
+
+        regs.resolution.write(Resolution::VAL::Bit12);
+        regs.oversample.set(0);
+        regs.samplerate.write(SampleRate::MODE::Task);
+
+        for ch in regs.ch.iter().skip(1) {
+            ch.pselp.write(Psel::PSEL.val(0));
+        }
+        regs.ch[0].pselp.write(Psel::PSEL.val(channel.input as u32));
+        regs.ch[0].pseln.write(Psel::PSEL.val(0));
+        regs.ch[0].config.write(
+            Config::RESP::Bypass
+                + Config::RESN::Bypass
+                + Config::GAIN.val(channel.gain as u32)
+                + Config::REFSEL.val(channel.reference as u32)
+                + Config::TACQ::us10
+                + Config::MODE::SingleEnded
+                + Config::BURST::Disabled,
+        );
+
+        regs.result_ptr.set(&self.sample as *const _ as u32);
+        regs.result_maxcnt.set(1);
+        regs.enable.write(Enable::ENABLE::Enabled);
+    }
+
+    // Start the converter and wait until it is ready for SAMPLE tasks
+    fn start_converter(&self) {
+        let regs = unsafe { &*self.regs };
+        regs.event_end.write(Event::READY::CLEAR);
+        regs.event_started.write(Event::READY::CLEAR);
+        regs.task_start.write(Task::ENABLE::SET);
+        while !regs.event_started.is_set(Event::READY) {}
+        regs.event_started.write(Event::READY::CLEAR);
+    }
+
+    // Sample every `period_us` without the CPU: COMPARE[0] of the timer
+    // triggers SAMPLE, and END restarts the converter for the next sample.
+    fn start_paced(&self, period_us: u32) -> ReturnCode {
+        let regs = unsafe { &*self.regs };
+
+        let rc = self.timer.reserve_cc(0);
+        if rc != ReturnCode::SUCCESS {
+            return rc;
+        }
+
+        let (sample, end, start) = unsafe {
+            (
+                ppi::Task::from_address(&regs.task_sample as *const _ as u32),
+                ppi::Event::from_address(&regs.event_end as *const _ as u32),
+                ppi::Task::from_address(&regs.task_start as *const _ as u32),
+            )
+        };
+
+        let sample_channel =
+            match unsafe { ppi::PPI.connect(ppi::Event::timer_compare(&self.timer, 0), sample) } {
+                Ok(channel) => channel,
+                Err(rc) => {
+                    self.timer.release_cc(0);
+                    return rc;
+                }
+            };
+        let restart_channel = match unsafe { ppi::PPI.connect(end, start) } {
+            Ok(channel) => channel,
+            Err(rc) => {
+                unsafe { ppi::PPI.release(sample_channel) };
+                self.timer.release_cc(0);
+                return rc;
+            }
+        };
+        self.ppi_channels
+            .set(Some((sample_channel, restart_channel)));
+
+        self.start_converter();
+
+        unsafe {
+            ppi::PPI.enable_channel(sample_channel);
+            ppi::PPI.enable_channel(restart_channel);
+        }
+
+        self.timer.stop();
+        self.timer.set_bitmode(BitmodeValue::Size32Bits);
+        self.timer.set_prescaler(TIMER_PRESCALER);
+        self.timer.set_cc0(period_us);
+        self.timer.set_shortcuts(TIMER_SHORT_COMPARE0_CLEAR);
+        self.timer.clear();
+        self.timer.start();
+        ReturnCode::SUCCESS
+    }
+
+    fn stop(&self) {
+        let regs = unsafe { &*self.regs };
+
+        self.mode.set(Mode::Idle);
+        regs.intenclr.set(0xFFFFFFFF);
+
+        // The channels are only connected while the timer paces sampling
+        self.ppi_channels.take().map(|(sample, restart)| {
+            unsafe {
+                ppi::PPI.release(sample);
+                ppi::PPI.release(restart);
+            }
+            self.timer.set_shortcuts(0);
+            self.timer.shutdown();
+            self.timer.release_cc(0);
+        });
+
+        regs.task_stop.write(Task::ENABLE::SET);
+        while regs.status.is_set(Status::BUSY) {}
+        regs.event_end.write(Event::READY::CLEAR);
+        regs.event_stopped.write(Event::READY::CLEAR);
+        regs.event_ch[0].limith.write(Event::READY::CLEAR);
+        regs.event_ch[0].limitl.write(Event::READY::CLEAR);
+
+        // Power the converter down between uses
+        regs.enable.write(Enable::ENABLE::Disabled);
+    }
+
+    fn last_sample(&self) -> u16 {
+        let sample = self.sample.get();
+        if sample < 0 {
+            0
+        } else {
+            sample as u16
+        }
+    }
+
+    pub fn handle_interrupt(&self) {
+        let regs = unsafe { &*self.regs };
+
+        if regs.event_end.is_set(Event::READY) {
+            regs.event_end.write(Event::READY::CLEAR);
+            let sample = self.last_sample();
+            match self.mode.get() {
+                Mode::Single => {
+                    self.stop();
+                    self.client.get().map(|client| client.sample_ready(sample));
+                }
+                Mode::Continuous => {
+                    self.client.get().map(|client| client.sample_ready(sample));
+                }
+                _ => {}
+            }
+        }
+
+        if regs.event_ch[0].limith.is_set(Event::READY) {
+            regs.event_ch[0].limith.write(Event::READY::CLEAR);
+            if self.mode.get() == Mode::Monitor {
+                // Above the high limit until the low limit is crossed
+                regs.intenclr.write(Interrupt::CH0LIMITH::SET);
+                regs.event_ch[0].limitl.write(Event::READY::CLEAR);
+                regs.intenset.write(Interrupt::CH0LIMITL::SET);
+                let sample = self.last_sample();
+                self.limit_client
+                    .get()
+                    .map(|client| client.limit_crossed(true, sample));
+            }
+        }
+
+        if regs.event_ch[0].limitl.is_set(Event::READY) {
+            regs.event_ch[0].limitl.write(Event::READY::CLEAR);
+            if self.mode.get() == Mode::Monitor {
+                // Below the low limit until the high limit is crossed
+                regs.intenclr.write(Interrupt::CH0LIMITL::SET);
+                regs.event_ch[0].limith.write(Event::READY::CLEAR);
+                regs.intenset.write(Interrupt::CH0LIMITH::SET);
+                let sample = self.last_sample();
+                self.limit_client
+                    .get()
+                    .map(|client| client.limit_crossed(false, sample));
+            }
+        }
+    }
+}
+
+impl hil::adc::Adc for Adc {
+    type Channel = AdcChannel;
+
+    fn initialize(&self) -> ReturnCode {
+        let regs = unsafe { &*self.regs };
+        regs.intenclr.set(0xFFFFFFFF);
+        regs.enable.write(Enable::ENABLE::Disabled);
+        ReturnCode::SUCCESS
+    }
+
+    fn sample(&self, channel: &Self::Channel) -> ReturnCode {
+        let regs = unsafe { &*self.regs };
+
+        if self.mode.get() != Mode::Idle {
+            return ReturnCode::EBUSY;
+        }
+        self.mode.set(Mode::Single);
+
+        self.configure(channel);
+        self.start_converter();
+        regs.intenset.write(Interrupt::END::SET);
+        regs.task_sample.write(Task::ENABLE::SET);
+
+        ReturnCode::SUCCESS
+    }
+
+    fn sample_continuous(&self, channel: &Self::Channel, frequency: u32) -> ReturnCode {
+        let regs = unsafe { &*self.regs };
+
+        if self.mode.get() != Mode::Idle {
+            return ReturnCode::EBUSY;
+        }
+        if frequency == 0 || frequency > MAX_FREQUENCY {
+            return ReturnCode::EINVAL;
+        }
+
+        self.configure(channel);
+        regs.intenset.write(Interrupt::END::SET);
+
+        let rc = self.start_paced(1000000 / frequency);
+        if rc == ReturnCode::SUCCESS {
+            self.mode.set(Mode::Continuous);
+        } else {
+            self.stop();
+        }
+        rc
+    }
+
+    fn stop_sampling(&self) -> ReturnCode {
+        match self.mode.get() {
+            Mode::Single | Mode::Continuous => self.stop(),
+            _ => {}
+        }
+        ReturnCode::SUCCESS
+    }
+}
+
+/// Buffered sampling is not provided yet.
+impl hil::adc::AdcHighSpeed for Adc {
+    fn sample_highspeed(
+        &self,
+        _channel: &Self::Channel,
+        _frequency: u32,
+        buffer1: &'static mut [u16],
+        _length1: usize,
+        buffer2: &'static mut [u16],
+        _length2: usize,
+    ) -> (
+        ReturnCode,
+        Option<&'static mut [u16]>,
+        Option<&'static mut [u16]>,
+    ) {
+        (ReturnCode::ENOSUPPORT, Some(buffer1), Some(buffer2))
+    }
+
+    fn provide_buffer(
+        &self,
+        buf: &'static mut [u16],
+        _length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u16]>) {
+        (ReturnCode::ENOSUPPORT, Some(buf))
+    }
+
+    fn retrieve_buffers(
+        &self,
+    ) -> (
+        ReturnCode,
+        Option<&'static mut [u16]>,
+        Option<&'static mut [u16]>,
+    ) {
+        (ReturnCode::SUCCESS, None, None)
+    }
+}
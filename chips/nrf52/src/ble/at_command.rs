@@ -0,0 +1,608 @@
+//! AT command interface to the BLE radio
+//!
+//! A modem-style control plane for boards used as a radio module by another
+//! MCU. Commands are read one per line from a UART of its own:
+//!
+//! - `AT`: answers `OK`
+//! - `AT+ADVDATA=<hex>`: the AD structures to advertise, at most 31 bytes
+//!   written as hex digits. Empty to advertise no data.
+//! - `AT+ADVSTART` or `AT+ADVSTART=<ms>`: start advertising, every 200 ms or
+//!   every `ms` milliseconds (20 - 10240)
+//! - `AT+ADVSTOP`: stop advertising
+//! - `AT+SCAN=<ms>`: scan passively for `ms` milliseconds (at most 60000),
+//!   `AT+SCAN=0` stops a scan early
+//! - `AT+LLSTATS`: print the link-layer conformance counters, as a line
+//!   starting with `llq:`, see `ble::conformance`
+//! - `AT+LLRESET`: clear the conformance counters and start a new test run
+//!
+//! Every command is answered with `OK` or `ERROR`. While scanning, each
+//! advertisement heard is reported as
+//! `+SCAN:<address>,<rssi in dBm>,<advertising data in hex>`, and the end of
+//! the scan as `+SCANDONE`. Reports that do not fit in the output queue are
+//! dropped.
+//!
+//! The radio either advertises or scans. It advertises ADV_NONCONN_IND PDUs,
+//! so nobody can connect or send scan requests, from the static random
+//! address the board gives. This is an alternative to the BLE advertising
+//! system call driver, a board uses one or the other as the radio's client.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let at_command = static_init!(
+//!     nrf52::ble::at_command::AtCommand<'static, nrf52::ble::radio::Radio,
+//!         VirtualMuxAlarm<'static, Rtc>, nrf52::uart::Uarte>,
+//!     nrf52::ble::at_command::AtCommand::new(
+//!         &nrf52::ble::radio::RADIO,
+//!         at_command_virtual_alarm,
+//!         &nrf52::uart::UARTE0,
+//!         115200,
+//!         DeviceAddress([0x01, 0x02, 0x03, 0x04, 0x05, 0xc0]),
+//!         &mut nrf52::ble::at_command::PDU_BUF,
+//!         &mut nrf52::ble::at_command::WRITE_BUF,
+//!         &mut nrf52::ble::at_command::QUEUE_BUF,
+//!         &mut nrf52::ble::at_command::READ_BUF,
+//!         &mut nrf52::ble::at_command::COMMAND_BUF
+//!     )
+//! );
+//! nrf52::ble::radio::RADIO.ble_initialize();
+//! BleAdvertisementDriver::set_receive_client(&nrf52::ble::radio::RADIO, at_command);
+//! BleAdvertisementDriver::set_transmit_client(&nrf52::ble::radio::RADIO, at_command);
+//! BleAdvertisementDriver::set_advertisement_client(&nrf52::ble::radio::RADIO, at_command);
+//! at_command_virtual_alarm.set_client(at_command);
+//! hil::uart::UART::set_client(&nrf52::uart::UARTE0, at_command);
+//! at_command.start();
+//! ```
+
+use ble::ble_advertising_hil::{self, DelayStartPoint, PhyTransition, RadioChannel, RadioContext,
+                               ReadAction, ReceivedPdu, TxImmediate, TxInfo};
+use ble::ble_pdu_parser::{split_advertising_data, BLEAdvertisementType, DeviceAddress,
+                          ADV_DATA_MAX_LEN, PACKET_ADDR_START, PACKET_HDR_LEN, PACKET_HDR_PDU,
+                          PACKET_LENGTH, PACKET_PAYLOAD_START};
+use ble::conformance::CONFORMANCE;
+use core::cell::Cell;
+use core::cmp;
+use core::fmt;
+use core::fmt::Write;
+use core::str;
+use kernel::common::take_cell::TakeCell;
+use kernel::hil::time::{self, Frequency};
+use kernel::hil::uart::{self, UART};
+use kernel::ReturnCode;
+
+pub static mut PDU_BUF: [u8; PACKET_LENGTH] = [0; PACKET_LENGTH];
+pub static mut WRITE_BUF: [u8; 128] = [0; 128];
+pub static mut QUEUE_BUF: [u8; 512] = [0; 512];
+pub static mut READ_BUF: [u8; 1] = [0; 1];
+pub static mut COMMAND_BUF: [u8; 80] = [0; 80];
+
+const SCAN_WINDOW: u32 = 10000; // time spent listening on each channel in usec
+const EVENT_GAP_MS: u32 = 1; // delay before an event that had to wait for the radio
+const DEFAULT_INTERVAL_MS: u32 = 200;
+const MIN_INTERVAL_MS: u32 = 20;
+const MAX_INTERVAL_MS: u32 = 10240;
+const MAX_SCAN_MS: u32 = 60000;
+
+// Longest scan report: prefix, address, RSSI, data in hex and line end
+const REPORT_MAX_LEN: usize = 6 + 17 + 5 + 2 * ADV_DATA_MAX_LEN + 2;
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7F;
+
+/// Appends formatted text to the output queue, dropping what does not fit.
+struct QueueWriter<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+}
+
+impl<'b> fmt::Write for QueueWriter<'b> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = cmp::min(s.len(), self.buf.len() - self.len);
+        self.buf[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+/// Bytes written as hex digits
+struct Hex<'b>(&'b [u8]);
+
+impl<'b> fmt::Display for Hex<'b> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in self.0.iter() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Idle,
+    Advertising,
+    Scanning,
+}
+
+pub struct AtCommand<'a, B, A, U>
+where
+    B: ble_advertising_hil::BleAdvertisementDriver + ble_advertising_hil::BleConfig + 'a,
+    A: time::Alarm + 'a,
+    U: UART + 'a,
+{
+    radio: &'a B,
+    alarm: &'a A,
+    uart: &'a U,
+    baud_rate: u32,
+    address: DeviceAddress,
+    state: Cell<State>,
+    /// Advertising channel of the event in progress
+    channel: Cell<Option<RadioChannel>>,
+    interval_ms: Cell<u32>,
+    adv_data: Cell<[u8; ADV_DATA_MAX_LEN]>,
+    adv_data_len: Cell<usize>,
+    /// Alarm time the scan ends at
+    scan_end: Cell<u32>,
+    /// Advertising PDU, held by the radio while advertising
+    pdu: TakeCell<'static, [u8]>,
+    /// Held by the UART while a transmission is in progress
+    tx_buffer: TakeCell<'static, [u8]>,
+    /// Output waiting to be transmitted
+    queue: TakeCell<'static, [u8]>,
+    queue_len: Cell<usize>,
+    rx_buffer: TakeCell<'static, [u8]>,
+    /// The line being received
+    command: TakeCell<'static, [u8]>,
+    command_len: Cell<usize>,
+}
+
+impl<'a, B, A, U> AtCommand<'a, B, A, U>
+where
+    B: ble_advertising_hil::BleAdvertisementDriver + ble_advertising_hil::BleConfig + 'a,
+    A: time::Alarm + 'a,
+    U: UART + 'a,
+{
+    pub fn new(
+        radio: &'a B,
+        alarm: &'a A,
+        uart: &'a U,
+        baud_rate: u32,
+        address: DeviceAddress,
+        pdu_buffer: &'static mut [u8],
+        tx_buffer: &'static mut [u8],
+        queue_buffer: &'static mut [u8],
+        rx_buffer: &'static mut [u8],
+        command_buffer: &'static mut [u8],
+    ) -> AtCommand<'a, B, A, U> {
+        AtCommand {
+            radio: radio,
+            alarm: alarm,
+            uart: uart,
+            baud_rate: baud_rate,
+            address: address,
+            state: Cell::new(State::Idle),
+            channel: Cell::new(None),
+            interval_ms: Cell::new(DEFAULT_INTERVAL_MS),
+            adv_data: Cell::new([0; ADV_DATA_MAX_LEN]),
+            adv_data_len: Cell::new(0),
+            scan_end: Cell::new(0),
+            pdu: TakeCell::new(pdu_buffer),
+            tx_buffer: TakeCell::new(tx_buffer),
+            queue: TakeCell::new(queue_buffer),
+            queue_len: Cell::new(0),
+            rx_buffer: TakeCell::new(rx_buffer),
+            command: TakeCell::new(command_buffer),
+            command_len: Cell::new(0),
+        }
+    }
+
+    pub fn start(&self) {
+        self.uart.init(uart::UARTParams {
+            baud_rate: self.baud_rate,
+            stop_bits: uart::StopBits::One,
+            parity: uart::Parity::None,
+            hw_flow_control: false,
+        });
+        self.rx_buffer.take().map(|buffer| {
+            self.uart.receive(buffer, 1);
+        });
+    }
+
+    fn print(&self, args: fmt::Arguments) {
+        self.queue.map(|queue| {
+            let mut writer = QueueWriter {
+                buf: queue,
+                len: self.queue_len.get(),
+            };
+            let _ = writer.write_fmt(args);
+            self.queue_len.set(writer.len);
+        });
+        self.flush();
+    }
+
+    /// Start transmitting the queued output, unless a transmission is
+    /// already in progress.
+    fn flush(&self) {
+        let queued = self.queue_len.get();
+        if queued == 0 {
+            return;
+        }
+        self.tx_buffer.take().map(|buffer| {
+            self.queue.map(move |queue| {
+                let len = cmp::min(queued, buffer.len());
+                buffer[..len].copy_from_slice(&queue[..len]);
+                // Move what did not fit to the front of the queue
+                for i in len..queued {
+                    queue[i - len] = queue[i];
+                }
+                self.queue_len.set(queued - len);
+                self.uart.transmit(buffer, len);
+            });
+        });
+    }
+
+    fn ms_to_tics(ms: u32) -> u32 {
+        ((ms as u64 * A::Frequency::frequency() as u64) / 1000) as u32
+    }
+
+    /// Start the next event after `ms` milliseconds.
+    fn schedule_event(&self, ms: u32) {
+        let now = self.alarm.now();
+        self.alarm
+            .set_alarm(now.wrapping_add(AtCommand::<B, A, U>::ms_to_tics(ms)));
+    }
+
+    fn start_event(&self) {
+        let channel = RadioChannel::AdvertisingChannel37;
+        self.channel.set(Some(channel));
+        self.radio.set_context(RadioContext::advertising(channel));
+        self.radio.set_address_filtering(false);
+
+        match self.state.get() {
+            State::Advertising => {
+                self.pdu.take().map(|pdu| {
+                    let len = self.adv_data_len.get();
+                    // TxAdd set, the address is a random address
+                    pdu[PACKET_HDR_PDU] =
+                        (0x04 << 4) | (BLEAdvertisementType::NonConnectUndirected as u8);
+                    pdu[PACKET_HDR_LEN] = (PACKET_PAYLOAD_START - PACKET_ADDR_START + len) as u8;
+                    pdu[PACKET_ADDR_START..PACKET_PAYLOAD_START].copy_from_slice(&self.address.0);
+                    pdu[PACKET_PAYLOAD_START..PACKET_PAYLOAD_START + len]
+                        .copy_from_slice(&self.adv_data.get()[..len]);
+                    let pdu = self.radio.set_advertisement_data(pdu, PACKET_LENGTH);
+                    self.pdu.replace(pdu);
+                });
+                self.radio.transmit_advertisement();
+            }
+            State::Scanning => self.radio.receive_advertisement(SCAN_WINDOW),
+            State::Idle => self.channel.set(None),
+        }
+    }
+
+    // The event is over, schedule the next one
+    fn end_event(&self) {
+        self.channel.set(None);
+        match self.state.get() {
+            State::Advertising => self.schedule_event(self.interval_ms.get()),
+            State::Scanning => {
+                let now = self.alarm.now();
+                if (now.wrapping_sub(self.scan_end.get()) as i32) >= 0 {
+                    self.state.set(State::Idle);
+                    self.print(format_args!("+SCANDONE\r\n"));
+                } else {
+                    self.schedule_event(EVENT_GAP_MS);
+                }
+            }
+            State::Idle => {}
+        }
+    }
+
+    // Move a scanning event on to the next advertising channel, starting
+    // when the window on the current one would have ended. The event ends
+    // after channel 39.
+    fn continue_scanning(&self) -> PhyTransition {
+        let next = self.channel
+            .get()
+            .and_then(|channel| channel.get_next_advertising_channel());
+        match next {
+            Some(channel) if self.state.get() == State::Scanning => {
+                self.channel.set(Some(channel));
+                self.radio.set_context(RadioContext::advertising(channel));
+                PhyTransition::MoveToRX(
+                    DelayStartPoint::PreviousPacketStartUsecDelay(SCAN_WINDOW),
+                    SCAN_WINDOW,
+                )
+            }
+            _ => {
+                self.end_event();
+                PhyTransition::None
+            }
+        }
+    }
+
+    fn report(&self, buf: &[u8], rssi: i8) {
+        let pdu_type = BLEAdvertisementType::from_u8(buf[PACKET_HDR_PDU] & 0x0f);
+        let len = buf[PACKET_HDR_LEN];
+        let advertisement = match pdu_type {
+            Some(BLEAdvertisementType::ConnectUndirected)
+            | Some(BLEAdvertisementType::NonConnectUndirected)
+            | Some(BLEAdvertisementType::ScanUndirected) => {
+                pdu_type.map_or(false, |pdu_type| pdu_type.validate_pdu(len))
+            }
+            _ => false,
+        };
+        if !advertisement || self.queue.map_or(0, |queue| queue.len()) - self.queue_len.get()
+            < REPORT_MAX_LEN
+        {
+            return;
+        }
+
+        let end = cmp::min(PACKET_ADDR_START + len as usize, buf.len());
+        let address = &buf[PACKET_ADDR_START..PACKET_PAYLOAD_START];
+        self.print(format_args!(
+            "+SCAN:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x},{},{}\r\n",
+            address[5],
+            address[4],
+            address[3],
+            address[2],
+            address[1],
+            address[0],
+            rssi,
+            Hex(&buf[PACKET_PAYLOAD_START..end])
+        ));
+    }
+
+    fn set_advertising_data(&self, hex: &str) -> ReturnCode {
+        let hex = hex.as_bytes();
+        if hex.len() % 2 != 0 || hex.len() / 2 > ADV_DATA_MAX_LEN {
+            return ReturnCode::ESIZE;
+        }
+        let mut data = [0; ADV_DATA_MAX_LEN];
+        for (i, pair) in hex.chunks(2).enumerate() {
+            let digits = str::from_utf8(pair).ok();
+            match digits.and_then(|digits| u8::from_str_radix(digits, 16).ok()) {
+                Some(byte) => data[i] = byte,
+                None => return ReturnCode::EINVAL,
+            }
+        }
+        let len = hex.len() / 2;
+        match split_advertising_data(&data[..len]) {
+            // All of it fits in the advertisement
+            Ok((split, total)) if split == total => {
+                self.adv_data.set(data);
+                self.adv_data_len.set(total);
+                ReturnCode::SUCCESS
+            }
+            Ok(_) => ReturnCode::ESIZE,
+            Err(error) => error,
+        }
+    }
+
+    fn start_advertising(&self, interval: Option<&str>) -> ReturnCode {
+        let interval_ms = match interval {
+            Some(interval) => match interval.parse::<u32>() {
+                Ok(ms) if ms >= MIN_INTERVAL_MS && ms <= MAX_INTERVAL_MS => ms,
+                _ => return ReturnCode::EINVAL,
+            },
+            None => DEFAULT_INTERVAL_MS,
+        };
+        if self.state.get() == State::Scanning {
+            return ReturnCode::EBUSY;
+        }
+        self.interval_ms.set(interval_ms);
+        if self.state.get() == State::Idle {
+            self.state.set(State::Advertising);
+            self.schedule_event(EVENT_GAP_MS);
+        }
+        ReturnCode::SUCCESS
+    }
+
+    fn scan(&self, duration: &str) -> ReturnCode {
+        let duration_ms = match duration.parse::<u32>() {
+            Ok(ms) if ms <= MAX_SCAN_MS => ms,
+            _ => return ReturnCode::EINVAL,
+        };
+        match self.state.get() {
+            State::Advertising => ReturnCode::EBUSY,
+            // The event in progress ends the scan
+            State::Scanning if duration_ms == 0 => {
+                self.scan_end.set(self.alarm.now());
+                ReturnCode::SUCCESS
+            }
+            _ if duration_ms == 0 => ReturnCode::SUCCESS,
+            _ => {
+                let now = self.alarm.now();
+                self.scan_end.set(now.wrapping_add(AtCommand::<B, A, U>::ms_to_tics(duration_ms)));
+                if self.state.get() == State::Idle {
+                    self.state.set(State::Scanning);
+                    self.schedule_event(EVENT_GAP_MS);
+                }
+                ReturnCode::SUCCESS
+            }
+        }
+    }
+
+    fn execute(&self, line: &str) {
+        let (command, argument) = match line.find('=') {
+            Some(i) => (&line[..i], Some(&line[i + 1..])),
+            None => (line, None),
+        };
+
+        let result = match (command, argument) {
+            ("AT", None) => ReturnCode::SUCCESS,
+            ("AT+ADVDATA", Some(hex)) => self.set_advertising_data(hex),
+            ("AT+ADVSTART", interval) => self.start_advertising(interval),
+            ("AT+ADVSTOP", None) => {
+                if self.state.get() == State::Advertising {
+                    // The event in progress, if any, is finished
+                    self.state.set(State::Idle);
+                }
+                ReturnCode::SUCCESS
+            }
+            ("AT+SCAN", Some(duration)) => self.scan(duration),
+            ("AT+LLSTATS", None) => {
+                self.print(format_args!("{}\r\n", unsafe { &CONFORMANCE }));
+                ReturnCode::SUCCESS
+            }
+            ("AT+LLRESET", None) => {
+                unsafe { CONFORMANCE.reset() };
+                ReturnCode::SUCCESS
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        };
+
+        if result == ReturnCode::SUCCESS {
+            self.print(format_args!("OK\r\n"));
+        } else {
+            self.print(format_args!("ERROR\r\n"));
+        }
+    }
+
+    fn handle_char(&self, c: u8) {
+        match c {
+            b'\r' | b'\n' => {
+                let len = self.command_len.get();
+                self.command_len.set(0);
+                if len == 0 {
+                    return;
+                }
+                // Copied out of the buffer so that `execute` may print
+                let mut line = [0; 80];
+                let len = self.command.map_or(0, |command| {
+                    let len = cmp::min(len, line.len());
+                    line[..len].copy_from_slice(&command[..len]);
+                    len
+                });
+                match str::from_utf8(&line[..len]) {
+                    Ok(line) => self.execute(line.trim()),
+                    Err(_) => self.print(format_args!("ERROR\r\n")),
+                }
+            }
+            BACKSPACE | DELETE => {
+                let len = self.command_len.get();
+                if len > 0 {
+                    self.command_len.set(len - 1);
+                }
+            }
+            0x20...0x7E => {
+                let len = self.command_len.get();
+                let stored = self.command.map_or(false, |command| {
+                    if len < command.len() {
+                        command[len] = c;
+                        true
+                    } else {
+                        false
+                    }
+                });
+                if stored {
+                    self.command_len.set(len + 1);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<'a, B, A, U> time::Client for AtCommand<'a, B, A, U>
+where
+    B: ble_advertising_hil::BleAdvertisementDriver + ble_advertising_hil::BleConfig + 'a,
+    A: time::Alarm + 'a,
+    U: UART + 'a,
+{
+    fn fired(&self) {
+        if self.channel.get().is_some() {
+            // The radio is still busy with the last event
+            self.schedule_event(EVENT_GAP_MS);
+        } else {
+            self.start_event();
+        }
+    }
+}
+
+impl<'a, B, A, U> ble_advertising_hil::RxClient for AtCommand<'a, B, A, U>
+where
+    B: ble_advertising_hil::BleAdvertisementDriver + ble_advertising_hil::BleConfig + 'a,
+    A: time::Alarm + 'a,
+    U: UART + 'a,
+{
+    fn receive_start(&self, _buf: &'static mut [u8], _len: u8) -> ReadAction {
+        if self.state.get() == State::Scanning {
+            ReadAction::ReadFrame
+        } else {
+            ReadAction::SkipFrame
+        }
+    }
+
+    fn receive_end(&self, pdu: ReceivedPdu) -> PhyTransition {
+        if pdu.crc_ok {
+            self.report(pdu.buf, pdu.rssi);
+        }
+        self.continue_scanning()
+    }
+}
+
+impl<'a, B, A, U> ble_advertising_hil::TxClient for AtCommand<'a, B, A, U>
+where
+    B: ble_advertising_hil::BleAdvertisementDriver + ble_advertising_hil::BleConfig + 'a,
+    A: time::Alarm + 'a,
+    U: UART + 'a,
+{
+    // Nobody may answer ADV_NONCONN_IND, go straight on to the next
+    // advertising channel
+    fn transmit_end(&self, _info: TxInfo) -> PhyTransition {
+        PhyTransition::None
+    }
+}
+
+impl<'a, B, A, U> ble_advertising_hil::AdvertisementClient for AtCommand<'a, B, A, U>
+where
+    B: ble_advertising_hil::BleAdvertisementDriver + ble_advertising_hil::BleConfig + 'a,
+    A: time::Alarm + 'a,
+    U: UART + 'a,
+{
+    fn advertisement_done(&self) -> TxImmediate {
+        // A scanning event has already ended in `continue_scanning`
+        if self.channel.get().is_none() {
+            return TxImmediate::GoToSleep;
+        }
+
+        let next = self.channel
+            .get()
+            .and_then(|channel| channel.get_next_advertising_channel());
+        match next {
+            Some(channel) if self.state.get() == State::Advertising => {
+                self.channel.set(Some(channel));
+                self.radio.set_context(RadioContext::advertising(channel));
+                TxImmediate::TX
+            }
+            _ => {
+                self.end_event();
+                TxImmediate::GoToSleep
+            }
+        }
+    }
+
+    fn timer_expired(&self) -> PhyTransition {
+        // Nothing heard on the channel before its window closed
+        self.continue_scanning()
+    }
+}
+
+impl<'a, B, A, U> uart::Client for AtCommand<'a, B, A, U>
+where
+    B: ble_advertising_hil::BleAdvertisementDriver + ble_advertising_hil::BleConfig + 'a,
+    A: time::Alarm + 'a,
+    U: UART + 'a,
+{
+    fn transmit_complete(&self, buffer: &'static mut [u8], _error: uart::Error) {
+        self.tx_buffer.replace(buffer);
+        self.flush();
+    }
+
+    fn receive_complete(&self, buffer: &'static mut [u8], rx_len: usize, error: uart::Error) {
+        if rx_len > 0 && error == uart::Error::CommandComplete {
+            self.handle_char(buffer[0]);
+        }
+        self.uart.receive(buffer, 1);
+    }
+}
@@ -1,24 +1,48 @@
 //! Bluetooth Low Energy Advertising Driver
 //!
 //! A system call driver that exposes the Bluetooth Low Energy advertising
-//! channel. The driver generates a unique static address for each process,
-//! allowing each process to act as its own device and send or scan for
-//! advertisements. Timing of advertising or scanning events is handled by the
+//! channel. Processes send and scan for advertisements with the address of
+//! the device, which they all share: the random static address programmed
+//! in the FICR, unless a process the board allows to has set another one
+//! with command 21. Timing of advertising or scanning events is handled by the
 //! driver but processes can request an advertising or scanning interval.
 //! Processes can also control the TX power used for their advertisements.
 //!
+//! Each advertising event is delayed past the advertising interval by a
+//! pseudo-random `advDelay` of 0 to 10 ms, so that advertisers with the same
+//! interval do not keep colliding. The delays are drawn from the hardware RNG
+//! given with `set_rng`, and from a software generator without one.
+//!
+//! Several processes can advertise or scan at the same time, each with its own
+//! interval, payload, PDU type and TX power. They share the radio one event at
+//! a time: a process whose interval elapses while another one's event is
+//! under way is served as soon as the radio is free, and processes due at the
+//! same time take turns in round-robin order. A process in a connection keeps
+//! the radio until the connection is lost.
+//!
+//! A central can encrypt the connection of a process that has allowed its
+//! long term key with allow 55. The session key is derived from it with the
+//! AES block given with `set_aes` and random numbers of the RNG given with
+//! `set_rng`, and the radio then encrypts and decrypts the data PDUs. The
+//! LL_ENC_RSP waits until the random numbers are available. Without a long
+//! term key, or if its Rand and EDIV do not match the central's, the
+//! central's request is rejected, as it is without an RNG or AES block.
+//!
 //! Data payloads are limited to 31 bytes since the maximum advertising channel
-//! protocol data unit (PDU) is 37 bytes and includes a 6-byte header.
+//! protocol data unit (PDU) is 37 bytes and includes a 6-byte header. Up to
+//! 62 bytes of AD structures can be split between an advertisement and its
+//! scan response with allow 53.
 //!
 //! ### Allow system call
 //! The allow systems calls are used for buffers from allocated by userland
 //!
 //!
-//! There are three different buffers:
+//! There are four different buffers:
 //!
 //! * Bluetooth Low Energy Gap Types
 //! * Passive Scanner
 //! * Advertisement
+//! * Scan Response
 //!
 //!
 //! The following allow numbers are supported:
@@ -54,8 +78,28 @@
 //! Bluetooth Core Specification:Core Specification Supplement, Part A, section 1.12
 //! * 26: «Advertising Interval»
 //! Bluetooth Core Specification:Core Specification Supplement, Part A, section 1.15
-//! * 49: Passive Scanning
+//! * 49: Scanning, received advertisements are copied here. With active
+//! scanning a scannable advertisement is followed in the buffer by the
+//! SCAN_RSP it was answered with, if any.
 //! * 50: Advertising
+//! * 51: Scan response data, the AD structures (0-31 bytes) sent in the
+//! SCAN_RSP to scanners requesting more data. Without it, SCAN_REQs are
+//! answered with an empty scan response.
+//! * 52: Connection data, read by command 10 when sending a data PDU
+//! * 53: Advertising data longer than an advertisement can hold, up to 62
+//! bytes of AD structures. They are split between the advertisement and the
+//! scan response, whole and in order: those that fit go into the
+//! advertisement, replacing its AD structures, and the rest make up the
+//! scan response, replacing the buffer of allow 51. Returns ESIZE if the rest
+//! does not fit in a scan response or the advertising PDU type is not
+//! scannable, and EINVAL if the buffer does not hold AD structures.
+//! * 54: Device name, UTF-8, set as the name advertised by command 18
+//! * 55: Long term key of the connection, 16 bytes least significant octet
+//! first as in HCI, with which the central may encrypt it. The key may be
+//! followed by the 8 bytes of Rand and the 2 bytes of EDIV identifying it,
+//! least significant octet first: centrals that ask for another key are then
+//! turned down. Without them, every central gets the key whatever Rand and
+//! EDIV it sends. ESIZE if the buffer is neither 16 nor 26 bytes long.
 //! * 255: «Manufacturer Specific Data» Bluetooth Core Specification:Vol. 3, Part C, section 8.1.4
 //!
 //! The possible return codes from the 'allow' system call indicate the following:
@@ -72,7 +116,27 @@
 //! 'subscribe' is used to specify the specific operation, currently:
 //!
 //! * 0: provides a callback user-space when a device scanning for advertisements
-//!      and the callback is used to invoke user-space processes.
+//!      and the callback is used to invoke user-space processes. The callback
+//!      gets the signal strength the advertisement was received with, in dBm
+//!      (an `i32`, 0 if it could not be measured), the length of the
+//!      advertisement and of the scan response that follows it in the
+//!      scanning buffer, which is 0 unless active scanning.
+//! * 1: called whenever data PDUs sent with command 10 have been acknowledged
+//!      by the central, with SUCCESS and the number acknowledged. When the
+//!      connection is lost, the PDUs still waiting are dropped and it is
+//!      called with ECANCEL and the number dropped.
+//! * 2: called when a connection is lost, with the reason as an HCI error
+//!      code (0x08 for a supervision timeout, 0x3D when the MIC of an
+//!      encrypted packet did not match). Advertising resumes afterwards.
+//! * 3: called at the end of each of the process' advertising events.
+//! * 4: called when the TX power is throttled because the chip runs hot, or
+//!      restored once it has cooled down. The callback gets 1 while
+//!      throttled and 0 once restored, the TX power limit in dBm (as an `i8`)
+//!      and the die temperature in hundredths of a degree Celsius.
+//! * 5: called when the central answers a connection parameter request of
+//!      command 22, with SUCCESS if it scheduled an update of the
+//!      connection, ENOSUPPORT if it does not support the procedure or FAIL
+//!      if it turned the request down.
 //!
 //! The possible return codes from the 'allow' system call indicate the following:
 //!
@@ -87,10 +151,85 @@
 //! * 0: start advertisement
 //! * 1: stop advertisement
 //! * 2: configure tx power
-//! * 3: configure advertisement interval
+//! * 3: configure advertisement interval, `data` in ms (20 - 10240, EINVAL
+//!      otherwise). A random advDelay of 0 - 10 ms is added to each interval
 //! * 4: clear the advertisement payload
-//! * 5: start scanning
+//! * 5: start scanning, passive if `data` is 0. Otherwise active: a SCAN_REQ
+//!      is sent to every scannable advertiser heard, T_IFS after its
+//!      advertisement
 //! * 6: initialize driver
+//! * 7: print the radio state and the radio event trace (see `ble::trace`) to
+//!      the debug console
+//! * 8: configure the advertising PDU type, `data` is one of
+//!      0x00 (ADV_IND, the default), 0x01 (ADV_DIRECT_IND),
+//!      0x02 (ADV_NONCONN_IND) or 0x06 (ADV_SCAN_IND)
+//! * 9: configure the initiator addressed by ADV_DIRECT_IND, `data` holds the
+//!      low 4 bytes of the address and the second argument the high 2 bytes,
+//!      with bit 16 set if the address is a random address
+//! * 10: send the first `data` bytes (at most 27) of the connection data
+//!      buffer to the central as an L2CAP data PDU. The bytes are copied to a
+//!      queue in the kernel, so the buffer can be written again right away.
+//!      Returns ENOMEM if too many PDUs are waiting to be sent.
+//! * 11: read a parameter of the process' connection, for diagnostics.
+//!      `data` selects the number of data channels in use (0), the hop
+//!      increment (1), the connection event counter (2) or the last data
+//!      channel index (3), the connection interval in microseconds (4), the
+//!      slave latency in connection events (5) or the supervision timeout in
+//!      microseconds (6). Returns EOFF if there is no connection. The
+//!      processes in the slots the board names with `set_diagnostics_apps`
+//!      read the connection currently up, whichever process it belongs to.
+//! * 12: add an advertiser to the scanning whitelist, the address is passed
+//!      as for command 9. Once the whitelist holds an address, scanning only
+//!      reports advertisements (and scan responses) from the advertisers in
+//!      it, others are dropped as soon as their AdvA is received. Returns
+//!      ENOMEM if the whitelist is full, and EBUSY while scanning.
+//! * 13: clear the scanning whitelist, advertisements from all advertisers
+//!      are reported again. Returns EBUSY while scanning.
+//! * 14: ask the central to move the connection to one of the PHYs in
+//!      `data`, a bitmask of 0x01 (LE 1M), 0x02 (LE 2M) and 0x04 (LE Coded,
+//!      nRF52840 only). The second argument is the coding to transmit with
+//!      on LE Coded, 2 or 8 symbols per bit. The central picks the PHYs and
+//!      when to switch. Until then the connection offers LE 1M only.
+//!      Returns ENOSUPPORT for PHYs the radio lacks, EINVAL without a
+//!      connection.
+//! * 15: read the process' advertising statistics: `data` selects the number
+//!      of advertising packets (scan responses included) sent on time (0),
+//!      sent late because the CPU missed their deadline (1) or aborted (2),
+//!      or the radio timer value in microseconds when the last packet ended
+//!      on air (3).
+//! * 16: set the «Flags» of the advertisement to `data` (0-255)
+//! * 17: set the «Appearance» of the advertisement to `data` (0-65535)
+//! * 18: set the name in the advertisement to the first `data` bytes of the
+//!      buffer of allow 54. Returns EINVAL if they are not valid UTF-8. A
+//!      name without room for all of it in the advertisement is cut short,
+//!      between two characters, and sent as the «Shortened Local Name».
+//!
+//!      Commands 16 to 18 replace the AD structure of the same type in the
+//!      advertisement, if there is one, and add it after the others
+//!      otherwise. They return ESIZE if it does not fit and EBUSY while
+//!      advertising.
+//! * 19: print the link-layer conformance counters of the test run to the
+//!      debug console, see `ble::conformance`
+//! * 20: clear the conformance counters and start a new test run
+//! * 21: set the address of the device, passed as for command 9. It must be
+//!      a random address: a static address has its two most significant
+//!      bits set, a private one its most significant bit cleared, and the
+//!      other bits are neither all 0 nor all 1. An address of 0 restores
+//!      the address of the FICR. Processes use the new address from their
+//!      next command 6 or scan on. Only allowed to the processes the board
+//!      names with `set_address_apps`, returns EBUSY while a process
+//!      advertises or scans. Apps pick their package names themselves, in
+//!      their TBF headers, so this is not a security boundary.
+//! * 22: ask the central for new connection parameters, to save power with
+//!      a longer interval or a higher slave latency. `data` holds the
+//!      minimum connection interval in its low 16 bits and the maximum in
+//!      its high 16 bits, in units of 1.25 ms, and the second argument the
+//!      slave latency in connection events in its low 16 bits and the
+//!      supervision timeout in units of 10 ms in its high 16 bits. Returns
+//!      EINVAL for parameters out of range or without a connection and
+//!      EBUSY while a request is pending. The outcome is reported to
+//!      callback 5. Whatever the parameters, the connection skips events as
+//!      the slave latency allows while it has no data to send.
 //!
 //! The possible return codes from the 'command' system call indicate the following:
 //!
@@ -188,6 +327,10 @@
 //!    nrf5x::ble_advertising_hil::BleAdvertisementDriver::set_tx_client(&nrf52::radio::RADIO,
 //!                                                                      ble_radio);
 //!    ble_radio_virtual_alarm.set_client(ble_radio);
+//!    ble_rng.set_client(ble_radio);
+//!    ble_radio.set_rng(ble_rng);
+//!    kernel::hil::symmetric_encryption::AES128::set_client(&nrf5x::aes::AESECB, ble_radio);
+//!    ble_radio.set_aes(&nrf5x::aes::AESECB, &mut nrf52::ble::ble_advertising_driver::AES_BUF);
 //! ```
 //!
 //! ### Authors
@@ -200,36 +343,66 @@ use ble::ble_advertising_hil::ActionAfterTimerExpire;
 use ble::ble_advertising_hil::PhyTransition;
 use ble::ble_advertising_hil::ResponseAction;
 use ble::ble_advertising_hil::TxImmediate;
-use ble::ble_advertising_hil::{DelayStartPoint, RadioChannel, ReadAction};
+use ble::ble_advertising_hil::{DelayStartPoint, RadioChannel, RadioContext, ReadAction,
+                               ReceivedPdu, TxInfo, TxStatus};
 use ble::ble_connection_driver::ConnectionData;
+use ble::ble_link_layer::EncryptionRequest;
 use ble::ble_link_layer::LinkLayer;
 use ble::ble_link_layer::TxNextChannelType;
 use ble::ble_pdu_parser::BLEAdvertisementType;
 use ble::ble_pdu_parser::BLEPduType;
+use ble::ble_pdu_parser::CH_SEL;
 use ble::ble_pdu_parser::DeviceAddress;
+use ble::ble_pdu_parser::PACKET_ADDR_END;
 use ble::ble_pdu_parser::PACKET_ADDR_START;
 use ble::ble_pdu_parser::PACKET_HDR_LEN;
 use ble::ble_pdu_parser::PACKET_HDR_PDU;
 use ble::ble_pdu_parser::PACKET_LENGTH;
 use ble::ble_pdu_parser::PACKET_PAYLOAD_START;
 use ble::ble_pdu_parser::PACKET_START;
+use ble::ble_pdu_parser::{append_ad_structure, remove_ad_structures};
+use ble::conformance::CONFORMANCE;
+use ble::ble_pdu_parser::{split_advertising_data, ADV_DATA_MAX_LEN};
+use ble::trace;
+use ble::tx_power_throttle::TxPowerThrottleClient;
 use core::cell::Cell;
 use core::cmp;
+use core::str;
+use ficr;
 use kernel;
+use kernel::hil::rng;
+use kernel::hil::symmetric_encryption::{self, AES128, AES128Ecb};
 use kernel::hil::time::Frequency;
 use kernel::returncode::ReturnCode;
-use nrf5x::constants;
-use ble::ble_connection_driver::DataHeader;
-use ble::ble_link_layer::ChannelMap;
+use nrf5x::aes::AesECB;
 
 /// Syscall Number
 pub const DRIVER_NUM: usize = 0x03_00_00;
 
 pub static mut BUF: [u8; PACKET_LENGTH] = [0; PACKET_LENGTH];
 
+/// Block the session keys are derived in, see `set_aes`
+pub static mut AES_BUF: [u8; symmetric_encryption::AES128_BLOCK_SIZE] =
+    [0; symmetric_encryption::AES128_BLOCK_SIZE];
+
 //Blutooth Specification Volume 6, Part B, Section 4.5.3
 const TRANSMIT_WINDOW_DELAY_CONN_IND: u32 = 1000 * 5 / 4; // 1.25ms in us
 const STANDARD_TIMEOUT: u32 = 8000; //in usec
+const SCAN_WINDOW: u32 = 10000; // time spent listening on each channel in usec
+const EVENT_GAP_MS: u32 = 1; // delay before starting an event that had to wait for the radio
+const WHITELIST_LEN: usize = 8; // advertisers a scanning process can filter on
+const ADV_INTERVAL_MIN_MS: usize = 20;
+const ADV_INTERVAL_MAX_MS: usize = 10240;
+const ADV_DELAY_MAX_US: u32 = 10000; // advDelay is drawn from 0 - 10ms
+
+// Bluetooth Core Specification:Vol. 2, Part D, sections 2.9 and 2.54
+const MIC_FAILURE: usize = 0x3D;
+
+const LONG_TERM_KEY_LEN: usize = 16;
+/// Rand and EDIV, which may follow the long term key in allow 55
+const LONG_TERM_KEY_ID_LEN: usize = 10;
+/// Random numbers in an LL_ENC_RSP, for the SKDs and IVs
+const ENCRYPTION_RANDOM_WORDS: usize = 3;
 
 #[allow(unused)]
 struct BLEGap(BLEGapType);
@@ -238,6 +411,11 @@ enum AllowType {
     BLEGap(BLEGapType),
     PassiveScanning,
     InitAdvertisementBuffer,
+    ScanResponseData,
+    ConnectionData,
+    AdvertisingData,
+    DeviceName,
+    LongTermKey,
 }
 
 impl AllowType {
@@ -264,6 +442,11 @@ impl AllowType {
             0x1A => Some(AllowType::BLEGap(BLEGapType::AdvertisingInterval)),
             0x31 => Some(AllowType::PassiveScanning),
             0x32 => Some(AllowType::InitAdvertisementBuffer),
+            0x33 => Some(AllowType::ScanResponseData),
+            0x34 => Some(AllowType::ConnectionData),
+            0x35 => Some(AllowType::AdvertisingData),
+            0x36 => Some(AllowType::DeviceName),
+            0x37 => Some(AllowType::LongTermKey),
             0xFF => Some(AllowType::BLEGap(BLEGapType::ManufacturerSpecificData)),
             _ => None,
         }
@@ -398,22 +581,60 @@ pub enum BleLinkLayerState {
 
 pub struct App {
     advertising_address: Option<DeviceAddress>,
+    /// `advertising_address` is a random address, TxAdd of the PDUs sent
+    address_random: bool,
     advertisement_buf: Option<kernel::AppSlice<kernel::Shared, u8>>,
+    scan_response_buf: Option<kernel::AppSlice<kernel::Shared, u8>>,
+    /// Part of `scan_response_buf` holding the scan response data
+    scan_response_start: usize,
+    scan_response_len: usize,
     app_write: Option<kernel::AppSlice<kernel::Shared, u8>>,
     app_read: Option<kernel::AppSlice<kernel::Shared, u8>>,
     scan_callback: Option<kernel::Callback>,
+    connection_buf: Option<kernel::AppSlice<kernel::Shared, u8>>,
+    /// Device name, UTF-8, read by command 18
+    name_buf: Option<kernel::AppSlice<kernel::Shared, u8>>,
+    /// Long term key the central may encrypt the connection with
+    long_term_key_buf: Option<kernel::AppSlice<kernel::Shared, u8>>,
+    connection_callback: Option<kernel::Callback>,
+    disconnect_callback: Option<kernel::Callback>,
+    advertising_callback: Option<kernel::Callback>,
+    tx_power_callback: Option<kernel::Callback>,
+    param_request_callback: Option<kernel::Callback>,
     idx: usize,
     pub process_status: Option<AppBLEState>,
     advertisement_interval_ms: u32,
+    advertisement_type: BLEAdvertisementType,
+    /// Initiator addressed by ADV_DIRECT_IND, and whether it is random
+    direct_address: Option<(DeviceAddress, bool)>,
+    /// Advertisers reported while scanning, all of them if empty
+    whitelist: [Option<(DeviceAddress, bool)>; WHITELIST_LEN],
     alarm_data: AlarmData,
     tx_power: u8,
+    /// Send SCAN_REQs to scannable advertisers while scanning
+    active_scanning: bool,
+    /// AdvA a SCAN_REQ was sent to, while its SCAN_RSP is awaited
+    scan_request_target: Option<DeviceAddress>,
+    /// Length of the advertisement at the start of `app_read`
+    scan_report_len: usize,
+    /// RSSI of the advertisement at the start of `app_read`, in dBm
+    scan_report_rssi: i8,
+    /// Advertising packets sent on time, sent late and aborted, see
+    /// command 15
+    advertising_sent: u32,
+    advertising_late: u32,
+    advertising_aborted: u32,
+    /// Time the last advertising packet ended on air
+    advertising_timestamp: u32,
+    /// Alarm time the last advertising event started, while advertising
+    advertising_event_start: Option<u32>,
     pub state: Option<BleLinkLayerState>,
     pub channel: Option<RadioChannel>,
     /// The state of an app-specific pseudo random number.
     ///
-    /// For example, it can be used for the pseudo-random `advDelay` parameter.
-    /// It should be read using the `random_number` method, which updates it as
-    /// well.
+    /// Used for the pseudo-random `advDelay` parameter when no hardware
+    /// randomness is at hand. It should be read using the `random_nonce`
+    /// method, which updates it as well.
     random_nonce: u32,
 }
 
@@ -421,17 +642,41 @@ impl Default for App {
     fn default() -> App {
         App {
             advertising_address: None,
+            address_random: true,
             advertisement_buf: None,
+            scan_response_buf: None,
+            scan_response_start: 0,
+            scan_response_len: 0,
             alarm_data: AlarmData::new(),
             app_write: None,
             app_read: None,
             scan_callback: None,
+            connection_buf: None,
+            name_buf: None,
+            long_term_key_buf: None,
+            connection_callback: None,
+            disconnect_callback: None,
+            advertising_callback: None,
+            tx_power_callback: None,
+            param_request_callback: None,
             idx: PACKET_PAYLOAD_START,
             process_status: Some(AppBLEState::NotInitialized),
             tx_power: 0,
+            active_scanning: false,
+            scan_request_target: None,
+            scan_report_len: 0,
+            scan_report_rssi: 0,
+            advertising_sent: 0,
+            advertising_late: 0,
+            advertising_aborted: 0,
+            advertising_timestamp: 0,
+            advertising_event_start: None,
             state: None,
             channel: None,
             advertisement_interval_ms: 200,
+            advertisement_type: BLEAdvertisementType::ConnectUndirected,
+            direct_address: None,
+            whitelist: [None; WHITELIST_LEN],
             // Just use any non-zero starting value by default
             random_nonce: 0xdeadbeef,
         }
@@ -451,54 +696,30 @@ impl App {
             .unwrap_or_else(|| ReturnCode::EINVAL)
     }
 
-    // Bluetooth Core Specification:Vol. 6, Part B, section 1.3.2.1 Static Device Address
-    //
-    // A static address is a 48-bit randomly generated address and shall meet the following
-    // requirements:
-    // • The two most significant bits of the address shall be equal to 1
-    // • At least one bit of the random part of the address shall be 0
-    // • At least one bit of the random part of the address shall be 1
-    //
-    // Note that endianness is a potential problem here as this is suppose to be platform
-    // independent therefore use 0xf0 as both byte 1 and byte 6 i.e., the two most significant bits
-    // are equal to one regardless of endianness
-    //
-    // Byte 1            0xf0
-    // Byte 2-5          random
-    // Byte 6            0xf0
-    // FIXME: For now use AppId as "randomness"
-    fn generate_random_address(&mut self, appid: kernel::AppId) -> ReturnCode {
-        /*let random_address: [u8; 6] = [
-            0xf0,
-            0x11,
-            0x11,
-            ((appid.idx() << 16) as u8 & 0xff),
-            ((appid.idx() << 24) as u8 & 0xff),
-            0xf0,
-        ];*/
-        let random_address: [u8; 6] = [
-            0xf0,
-            0x0f,
-            0x0f,
-            ((appid.idx() << 16) as u8 & 0xff),
-            ((appid.idx() << 24) as u8 & 0xff),
-            0xf0,
-        ];
-        self.advertising_address = Some(DeviceAddress::new(&random_address));
-
-        debug!("random address!, {:?}", self.advertising_address);
+    // Advertise and scan with `address`, the address of the device
+    fn set_advertising_address(&mut self, address: DeviceAddress, random: bool) -> ReturnCode {
+        self.advertising_address = Some(address);
+        self.address_random = random;
 
         self.advertisement_buf
             .as_mut()
             .map_or(ReturnCode::ESIZE, |data| {
                 data.as_mut()[PACKET_HDR_LEN] = 6;
-                for i in 0..6 {
-                    data.as_mut()[PACKET_ADDR_START + i] = random_address[i];
-                }
+                data.as_mut()[PACKET_ADDR_START..PACKET_PAYLOAD_START]
+                    .copy_from_slice(&address.0);
                 ReturnCode::SUCCESS
             })
     }
 
+    // First byte of the header of a PDU sent with the process' address,
+    // with TxAdd set for a random address. Connectable advertisements set
+    // ChSel, connections may use channel selection algorithm #2.
+    fn pdu_header(&self, pdu_type: BLEAdvertisementType) -> u8 {
+        let tx_add = if self.address_random { 1 << 6 } else { 0 };
+        let ch_sel = if pdu_type.is_connectable() { CH_SEL } else { 0 };
+        tx_add | ch_sel | (pdu_type as u8)
+    }
+
     pub fn make_adv_pdu(&self, buffer: &mut [u8], header: &mut u8) -> u8 {
         self.advertisement_buf.as_ref().map(|data| {
             for i in 0..PACKET_LENGTH {
@@ -506,7 +727,7 @@ impl App {
             }
         });
 
-        *header = (0x04 << 4) | (BLEAdvertisementType::ConnectUndirected as u8);
+        *header = self.pdu_header(self.advertisement_type);
 
         self.idx as u8
     }
@@ -532,13 +753,12 @@ impl App {
         }
     }
 
-    // Hard-coded to ADV_NONCONN_IND
     fn configure_advertisement_pdu(&mut self) -> ReturnCode {
+        let header = self.pdu_header(self.advertisement_type);
         self.advertisement_buf
             .as_mut()
             .map(|slice| {
-                slice.as_mut()[PACKET_HDR_PDU] =
-                    (0x04 << 4) | (BLEAdvertisementType::ConnectUndirected as u8);
+                slice.as_mut()[PACKET_HDR_PDU] = header;
                 ReturnCode::SUCCESS
             })
             .unwrap_or_else(|| ReturnCode::ESIZE)
@@ -586,17 +806,248 @@ impl App {
             .unwrap_or_else(|| ReturnCode::EINVAL)
     }
 
-    fn prepare_advertisement<'a, B, A>(
+    // See allow 53
+    fn set_advertising_data(
         &mut self,
-        ble: &BLE<'a, B, A>,
-        advertisement_type: BLEAdvertisementType,
-    ) -> ReturnCode
+        slice: Option<kernel::AppSlice<kernel::Shared, u8>>,
+    ) -> ReturnCode {
+        let (split, end) = match slice
+            .as_ref()
+            .map_or(Ok((0, 0)), |slice| split_advertising_data(slice.as_ref()))
+        {
+            Ok(lengths) => lengths,
+            Err(err) => return err,
+        };
+        if end > split && !self.advertisement_type.is_scannable() {
+            return ReturnCode::ESIZE;
+        }
+
+        let result = self.reset_payload();
+        if result != ReturnCode::SUCCESS {
+            return result;
+        }
+
+        if let (Some(data), Some(slice)) = (self.advertisement_buf.as_mut(), slice.as_ref()) {
+            data.as_mut()[PACKET_PAYLOAD_START..PACKET_PAYLOAD_START + split]
+                .copy_from_slice(&slice.as_ref()[..split]);
+            data.as_mut()[PACKET_HDR_LEN] = (PACKET_PAYLOAD_START - PACKET_ADDR_START + split) as u8;
+        }
+        self.idx = PACKET_PAYLOAD_START + split;
+
+        self.scan_response_buf = slice;
+        self.scan_response_start = split;
+        self.scan_response_len = end - split;
+        ReturnCode::SUCCESS
+    }
+
+    // Rewrite the AD structures of the advertisement with `edit`, which gets
+    // a copy of them and their length and returns the new length. Nothing
+    // changes if it fails.
+    fn edit_ad_structures(
+        &mut self,
+        edit: &Fn(&mut [u8], usize) -> Result<usize, ReturnCode>,
+    ) -> ReturnCode {
+        if self.process_status == Some(AppBLEState::Advertising) {
+            return ReturnCode::EBUSY;
+        }
+        let len = self.idx - PACKET_PAYLOAD_START;
+        let result = self.advertisement_buf
+            .as_mut()
+            .map_or(Err(ReturnCode::EINVAL), |buf| {
+                if buf.len() < PACKET_LENGTH {
+                    return Err(ReturnCode::ESIZE);
+                }
+                let mut ad_structures = [0; ADV_DATA_MAX_LEN];
+                ad_structures.copy_from_slice(&buf.as_ref()[PACKET_PAYLOAD_START..PACKET_LENGTH]);
+                let len = edit(&mut ad_structures, len)?;
+                buf.as_mut()[PACKET_PAYLOAD_START..PACKET_LENGTH].copy_from_slice(&ad_structures);
+                buf.as_mut()[PACKET_HDR_LEN] =
+                    (PACKET_PAYLOAD_START - PACKET_ADDR_START + len) as u8;
+                Ok(len)
+            });
+        match result {
+            Ok(len) => {
+                self.idx = PACKET_PAYLOAD_START + len;
+                ReturnCode::SUCCESS
+            }
+            Err(err) => err,
+        }
+    }
+
+    // See commands 16 and 17
+    fn set_ad_structure(&mut self, ad_type: BLEGapType, value: &[u8]) -> ReturnCode {
+        let ad_type = ad_type as u8;
+        self.edit_ad_structures(&|data, len| {
+            let len = remove_ad_structures(data, len, &[ad_type])?;
+            append_ad_structure(data, len, ad_type, value)
+        })
+    }
+
+    // See command 18. A name longer than the room left in the advertisement
+    // is cut short, between two characters, and sent as the shortened local
+    // name instead.
+    fn set_device_name(&mut self, len: usize) -> ReturnCode {
+        // The longest name that fits, plus the byte after it to tell where
+        // the character it ends in stops
+        let mut name = [0; ADV_DATA_MAX_LEN - 1];
+        let result = self.name_buf.as_ref().map_or(ReturnCode::EINVAL, |slice| {
+            if len == 0 || len > slice.len() || str::from_utf8(&slice.as_ref()[..len]).is_err() {
+                ReturnCode::EINVAL
+            } else {
+                let copied = cmp::min(len, name.len());
+                name[..copied].copy_from_slice(&slice.as_ref()[..copied]);
+                ReturnCode::SUCCESS
+            }
+        });
+        if result != ReturnCode::SUCCESS {
+            return result;
+        }
+
+        self.edit_ad_structures(&|data, ad_len| {
+            let ad_len = remove_ad_structures(
+                data,
+                ad_len,
+                &[
+                    BLEGapType::ShortedLocalName as u8,
+                    BLEGapType::CompleteLocalName as u8,
+                ],
+            )?;
+            let room = (ADV_DATA_MAX_LEN - ad_len).saturating_sub(2);
+            if len <= room {
+                return append_ad_structure(
+                    data,
+                    ad_len,
+                    BLEGapType::CompleteLocalName as u8,
+                    &name[..len],
+                );
+            }
+            // Continuation bytes of UTF-8 are 0b10xxxxxx. The name is longer
+            // than `room`, which is shorter than `name`, so the byte at
+            // `room` was copied.
+            let mut cut = room;
+            while cut > 0 && name[cut] & 0xc0 == 0x80 {
+                cut -= 1;
+            }
+            if cut == 0 {
+                return Err(ReturnCode::ESIZE);
+            }
+            append_ad_structure(
+                data,
+                ad_len,
+                BLEGapType::ShortedLocalName as u8,
+                &name[..cut],
+            )
+        })
+    }
+
+    fn set_advertisement_type(&mut self, pdu_type: usize) -> ReturnCode {
+        if self.process_status == Some(AppBLEState::Advertising) {
+            return ReturnCode::EBUSY;
+        }
+        if pdu_type > 0xff {
+            return ReturnCode::EINVAL;
+        }
+        let advertisement_type = match BLEAdvertisementType::from_u8(pdu_type as u8) {
+            Some(t @ BLEAdvertisementType::ConnectUndirected)
+            | Some(t @ BLEAdvertisementType::ConnectDirected)
+            | Some(t @ BLEAdvertisementType::NonConnectUndirected)
+            | Some(t @ BLEAdvertisementType::ScanUndirected) => t,
+            _ => return ReturnCode::EINVAL,
+        };
+        self.advertisement_type = advertisement_type;
+        self.configure_advertisement_pdu();
+        ReturnCode::SUCCESS
+    }
+
+    // An address passed to a command: the low 4 bytes in `low`, the high 2
+    // bytes in `high`, with bit 16 set if it is a random address
+    fn address_from_command(low: usize, high: usize) -> (DeviceAddress, bool) {
+        let address = [
+            low as u8,
+            (low >> 8) as u8,
+            (low >> 16) as u8,
+            (low >> 24) as u8,
+            high as u8,
+            (high >> 8) as u8,
+        ];
+        let random = high & (1 << 16) != 0;
+        (DeviceAddress::new(&address), random)
+    }
+
+    fn set_direct_address(&mut self, low: usize, high: usize) -> ReturnCode {
+        if self.process_status == Some(AppBLEState::Advertising) {
+            return ReturnCode::EBUSY;
+        }
+        self.direct_address = Some(App::address_from_command(low, high));
+        ReturnCode::SUCCESS
+    }
+
+    fn add_to_whitelist(&mut self, low: usize, high: usize) -> ReturnCode {
+        if self.process_status == Some(AppBLEState::Scanning) {
+            return ReturnCode::EBUSY;
+        }
+        let entry = App::address_from_command(low, high);
+        if self.whitelist.contains(&Some(entry)) {
+            return ReturnCode::SUCCESS;
+        }
+        match self.whitelist.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(entry);
+                ReturnCode::SUCCESS
+            }
+            None => ReturnCode::ENOMEM,
+        }
+    }
+
+    fn clear_whitelist(&mut self) -> ReturnCode {
+        if self.process_status == Some(AppBLEState::Scanning) {
+            return ReturnCode::EBUSY;
+        }
+        self.whitelist = [None; WHITELIST_LEN];
+        ReturnCode::SUCCESS
+    }
+
+    fn is_filtering(&self) -> bool {
+        self.whitelist.iter().any(|entry| entry.is_some())
+    }
+
+    /// Whether the advertising PDU starting in `buf`, of which the header and
+    /// AdvA are in, comes from an advertiser on the whitelist
+    fn is_whitelisted(&self, buf: &[u8]) -> bool {
+        if !self.is_filtering() {
+            return true;
+        }
+        let adv_address = DeviceAddress::new(&buf[PACKET_ADDR_START..PACKET_ADDR_END + 1]);
+        let adv_random = buf[PACKET_HDR_PDU] & (1 << 6) != 0;
+        self.whitelist.contains(&Some((adv_address, adv_random)))
+    }
+
+    /// Whether `address` is the initiator ADV_DIRECT_IND is addressed to
+    pub fn is_direct_address(&self, address: &DeviceAddress) -> bool {
+        self.direct_address
+            .map_or(false, |(direct_address, _)| direct_address == *address)
+    }
+
+    pub fn advertisement_type(&self) -> BLEAdvertisementType {
+        self.advertisement_type
+    }
+
+    fn prepare_advertisement<'a, B, A>(&mut self, ble: &BLE<'a, B, A>) -> ReturnCode
     where
         B: ble_advertising_hil::BleAdvertisementDriver + ble_advertising_hil::BleConfig + 'a,
         A: kernel::hil::time::Alarm + 'a,
     {
         self.state = None;
 
+        let advertisement_type = self.advertisement_type;
+        let header = self.pdu_header(advertisement_type);
+        let direct_address = self.direct_address;
+
+        if advertisement_type == BLEAdvertisementType::ConnectDirected && direct_address.is_none()
+        {
+            return ReturnCode::EINVAL;
+        }
+
         self.advertisement_buf
             .as_ref()
             .map_or(ReturnCode::EINVAL, |slice| {
@@ -607,60 +1058,129 @@ impl App {
                     {
                         *out = *inp;
                     }
-                    data.as_mut()[PACKET_HDR_PDU] = (0x04 << 4) | (advertisement_type as u8);
+                    data.as_mut()[PACKET_HDR_PDU] = header;
+
+                    // ADV_DIRECT_IND carries the initiator's address instead of
+                    // advertising data
+                    if let Some((address, random)) = direct_address {
+                        if advertisement_type == BLEAdvertisementType::ConnectDirected {
+                            data.as_mut()[PACKET_HDR_LEN] = 12;
+                            data.as_mut()[PACKET_PAYLOAD_START..PACKET_PAYLOAD_START + 6]
+                                .copy_from_slice(&address.0);
+                            if random {
+                                data.as_mut()[PACKET_HDR_PDU] |= 1 << 7;
+                            }
+                        }
+                    }
                 });
                 ReturnCode::SUCCESS
             })
     }
 
+    // Stage the SCAN_RSP with the radio at the start of an advertising
+    // event, so the radio can answer SCAN_REQs on its own within T_IFS
     fn prepare_scan_response<'a, B, A>(&mut self, ble: &BLE<'a, B, A>) -> ReturnCode
     where
         B: ble_advertising_hil::BleAdvertisementDriver + ble_advertising_hil::BleConfig + 'a,
         A: kernel::hil::time::Alarm + 'a,
     {
-        self.state = Some(BleLinkLayerState::RespondingToScanRequest);
+        let address = match self.advertising_address {
+            Some(address) => address,
+            None => return ReturnCode::EINVAL,
+        };
+        let header = self.pdu_header(BLEAdvertisementType::ScanResponse);
+        let scan_response_buf = &self.scan_response_buf;
+        let start = self.scan_response_start;
+        let len = self.scan_response_len;
+
+        ble.kernel_tx.take().map_or(ReturnCode::EBUSY, |buffer| {
+            let len = scan_response_buf.as_ref().map_or(0, |slice| {
+                let len = cmp::min(
+                    cmp::min(len, slice.len().saturating_sub(start)),
+                    ADV_DATA_MAX_LEN,
+                );
+                for (out, inp) in buffer[PACKET_PAYLOAD_START..PACKET_PAYLOAD_START + len]
+                    .iter_mut()
+                    .zip(slice.as_ref()[start..start + len].iter())
+                {
+                    *out = *inp;
+                }
+                len
+            });
 
-        self.advertisement_buf
-            .as_ref()
-            .map(|slice| {
-                ble.replace_buffer(&|data: &mut [u8]| {
-                    for (out, inp) in data.as_mut()[PACKET_HDR_PDU..PACKET_LENGTH]
-                        .iter_mut()
-                        .zip(slice.as_ref()[PACKET_HDR_PDU..PACKET_LENGTH].iter())
-                    {
-                        *out = *inp;
-                    }
-                    data.as_mut()[PACKET_HDR_PDU] =
-                        (0x04 << 4) | (BLEAdvertisementType::ScanResponse as u8);
-                });
+            buffer[PACKET_HDR_PDU] = header;
+            buffer[PACKET_HDR_LEN] = (PACKET_PAYLOAD_START - PACKET_ADDR_START + len) as u8;
+            buffer[PACKET_ADDR_START..PACKET_PAYLOAD_START].copy_from_slice(&address.0);
 
-                ReturnCode::SUCCESS
-            })
-            .unwrap_or_else(|| ReturnCode::EINVAL)
+            let res = ble.radio
+                .set_scan_response_data(buffer, PACKET_PAYLOAD_START + len);
+            ble.kernel_tx.replace(res);
+            ReturnCode::SUCCESS
+        })
     }
 
-    fn set_empty_conn_pdu<'a, B, A>(
+    // SCAN_REQ addressed to an advertiser heard while active scanning
+    fn prepare_scan_request<'a, B, A>(
         &mut self,
         ble: &BLE<'a, B, A>,
-        transmit_sequence_number: u8,
-        next_expected_sequence_number: u8,
+        adv_addr: DeviceAddress,
+        adv_random: bool,
     ) -> ReturnCode
     where
         B: ble_advertising_hil::BleAdvertisementDriver + ble_advertising_hil::BleConfig + 'a,
         A: kernel::hil::time::Alarm + 'a,
     {
-        // debug!("Sending ConnectRequest to {:?} on channel {:?}", adv_addr, channel);
+        let address = match self.advertising_address {
+            Some(address) => address,
+            None => return ReturnCode::EINVAL,
+        };
+
+        let header = self.pdu_header(BLEAdvertisementType::ScanRequest);
+        ble.replace_buffer(&|data: &mut [u8]| {
+            data.as_mut()[PACKET_HDR_PDU] = header;
+            if adv_random {
+                data.as_mut()[PACKET_HDR_PDU] |= 1 << 7;
+            }
+            data.as_mut()[PACKET_HDR_LEN] = 12;
+            data.as_mut()[PACKET_ADDR_START..PACKET_PAYLOAD_START].copy_from_slice(&address.0);
+            data.as_mut()[PACKET_PAYLOAD_START..PACKET_PAYLOAD_START + 6]
+                .copy_from_slice(&adv_addr.0);
+        });
+        ReturnCode::SUCCESS
+    }
 
+    // Copy a received PDU into the scanning buffer at `offset`, truncated to
+    // what fits. Returns the number of bytes copied.
+    fn copy_to_scan_buffer(&mut self, offset: usize, pdu: &[u8]) -> usize {
+        self.app_read.as_mut().map_or(0, |slice| {
+            let len = cmp::min(pdu.len(), slice.len().saturating_sub(offset));
+            slice.as_mut()[offset..offset + len].copy_from_slice(&pdu[0..len]);
+            len
+        })
+    }
+
+    // Tell the app about the advertisement in the scanning buffer and the
+    // `scan_response_len` bytes of scan response following it
+    fn report_scan(&mut self, scan_response_len: usize) {
+        let adv_len = self.scan_report_len;
+        let rssi = self.scan_report_rssi;
+        self.scan_report_len = 0;
+        self.scan_callback.as_mut().map(|cb| {
+            cb.schedule(rssi as isize as usize, adv_len, scan_response_len)
+        });
+    }
+
+    fn set_conn_pdu<'a, B, A>(&mut self, ble: &BLE<'a, B, A>, pdu: &[u8]) -> ReturnCode
+    where
+        B: ble_advertising_hil::BleAdvertisementDriver + ble_advertising_hil::BleConfig + 'a,
+        A: kernel::hil::time::Alarm + 'a,
+    {
         self.advertisement_buf
             .as_ref()
             .map(|_| {
                 ble.replace_buffer(&|data: &mut [u8]| {
-                    // LLID == 0x01 Empty PDU
-                    data.as_mut()[PACKET_HDR_PDU] = 0x01 | (next_expected_sequence_number & 0b1)
-                        << 2
-                        | (transmit_sequence_number & 0b1) << 3;
-
-                    data.as_mut()[PACKET_HDR_LEN] = 0;
+                    data.as_mut()[PACKET_HDR_PDU..PACKET_LENGTH]
+                        .copy_from_slice(&pdu[PACKET_HDR_PDU..PACKET_LENGTH]);
                 });
 
                 ReturnCode::SUCCESS
@@ -668,6 +1188,128 @@ impl App {
             .unwrap_or_else(|| ReturnCode::EINVAL)
     }
 
+    // The long term key of allow 55, most significant octet first as the AES
+    // block takes it, unless allow 55 also holds a Rand and EDIV other than
+    // those of `request`
+    fn long_term_key(&self, request: &EncryptionRequest) -> Option<[u8; LONG_TERM_KEY_LEN]> {
+        self.long_term_key_buf.as_ref().and_then(|buf| {
+            let buf = buf.as_ref();
+            if buf.len() == LONG_TERM_KEY_LEN + LONG_TERM_KEY_ID_LEN {
+                let id = &buf[LONG_TERM_KEY_LEN..];
+                let ediv = (id[9] as u16) << 8 | id[8] as u16;
+                if id[..8] != request.rand || ediv != request.ediv {
+                    return None;
+                }
+            }
+            let mut key = [0; LONG_TERM_KEY_LEN];
+            for (i, byte) in buf[..LONG_TERM_KEY_LEN].iter().rev().enumerate() {
+                key[i] = *byte;
+            }
+            Some(key)
+        })
+    }
+
+    // Queue the first `len` bytes of the connection data buffer as an L2CAP
+    // data PDU
+    fn send_connection_data(&mut self, len: usize) -> ReturnCode {
+        let connection_buf = &self.connection_buf;
+        match self.process_status {
+            Some(AppBLEState::Connection(ref mut conndata)) => {
+                connection_buf.as_ref().map_or(ReturnCode::EINVAL, |slice| {
+                    if len > slice.len() {
+                        ReturnCode::EINVAL
+                    } else {
+                        conndata.send(0x02, &slice.as_ref()[0..len])
+                    }
+                })
+            }
+            _ => ReturnCode::EINVAL,
+        }
+    }
+
+    // See command 14
+    fn request_phy(&mut self, phys: usize, coding: usize, supported: u8) -> ReturnCode {
+        let coded = match coding {
+            2 => ble_advertising_hil::Phy::LeCodedS2,
+            8 => ble_advertising_hil::Phy::LeCodedS8,
+            _ => return ReturnCode::EINVAL,
+        };
+        if phys == 0 || phys > 0xff {
+            return ReturnCode::EINVAL;
+        }
+        if phys as u8 & !supported != 0 {
+            return ReturnCode::ENOSUPPORT;
+        }
+        match self.process_status {
+            Some(AppBLEState::Connection(ref mut conndata)) => {
+                conndata.request_phy(phys as u8, coded)
+            }
+            _ => ReturnCode::EINVAL,
+        }
+    }
+
+    // See command 22
+    fn request_connection_parameters(&mut self, intervals: usize, latency_timeout: usize)
+        -> ReturnCode {
+        match self.process_status {
+            Some(AppBLEState::Connection(ref mut conndata)) => conndata
+                .request_connection_parameters(
+                    intervals as u16,
+                    (intervals >> 16) as u16,
+                    latency_timeout as u16,
+                    (latency_timeout >> 16) as u16,
+                ),
+            _ => ReturnCode::EINVAL,
+        }
+    }
+
+    fn record_advertising_tx(&mut self, info: TxInfo) {
+        match info.status {
+            TxStatus::Sent => self.advertising_sent += 1,
+            TxStatus::DeadlineMissed => self.advertising_late += 1,
+            TxStatus::Aborted => self.advertising_aborted += 1,
+        }
+        if info.status != TxStatus::Aborted {
+            self.advertising_timestamp = info.timestamp;
+            if let Some(channel) = info.channel {
+                unsafe { CONFORMANCE.advertising_packet(channel) };
+            }
+        }
+    }
+
+    // See command 11, EOFF without a connection
+    fn connection_parameter(&self, field: usize) -> ReturnCode {
+        let conndata = match self.process_status {
+            Some(AppBLEState::Connection(ref conndata)) => conndata,
+            _ => return ReturnCode::EOFF,
+        };
+        let value = match field {
+            0 => conndata.number_used_channels() as usize,
+            1 => conndata.hop_increment() as usize,
+            2 => conndata.conn_event_counter as usize,
+            3 => self.channel.map_or(0, |channel| channel.get_channel_index() as usize),
+            4 => conndata.lldata.connection_interval() as usize,
+            5 => conndata.lldata.latency as usize,
+            6 => conndata.calculate_conn_supervision_timeout() as usize,
+            _ => return ReturnCode::EINVAL,
+        };
+        ReturnCode::SuccessWithValue { value }
+    }
+
+    // See command 15
+    fn advertising_statistic(&self, field: usize) -> ReturnCode {
+        let value = match field {
+            0 => self.advertising_sent,
+            1 => self.advertising_late,
+            2 => self.advertising_aborted,
+            3 => self.advertising_timestamp,
+            _ => return ReturnCode::EINVAL,
+        };
+        ReturnCode::SuccessWithValue {
+            value: value as usize,
+        }
+    }
+
     // Returns a new pseudo-random number and updates the randomness state.
     //
     // Uses the [Xorshift](https://en.wikipedia.org/wiki/Xorshift) algorithm to
@@ -682,14 +1324,27 @@ impl App {
         self.random_nonce
     }
 
-    // Set the next alarm for this app using the period and provided start time.
-    fn set_next_alarm<F: Frequency>(&mut self, now: u32) {
+    // Set the next alarm for this app using the period, delayed by `delay_us`,
+    // and provided start time.
+    fn set_next_alarm<F: Frequency>(&mut self, now: u32, delay_us: u32) {
         self.alarm_data.t0 = now;
-        let nonce = self.random_nonce() % 10;
 
-        let period_ms = (self.advertisement_interval_ms + nonce) * F::frequency() / 1000;
+        let period = self.advertisement_interval_ms * F::frequency() / 1000
+            + (delay_us as u64 * F::frequency() as u64 / 1_000_000) as u32;
 
-        self.alarm_data.expiration = Expiration::Abs(now.wrapping_add(period_ms));
+        self.alarm_data.expiration = Expiration::Abs(now.wrapping_add(period));
+    }
+
+    // Whether this app's timer has expired and it is waiting for the radio to
+    // start an advertising or scanning event.
+    fn is_due(&self, now: u32) -> bool {
+        match (self.process_status.as_ref(), self.alarm_data.expiration) {
+            (Some(&AppBLEState::Advertising), Expiration::Abs(exp))
+            | (Some(&AppBLEState::Scanning), Expiration::Abs(exp)) => {
+                now.wrapping_sub(self.alarm_data.t0) >= exp.wrapping_sub(self.alarm_data.t0)
+            }
+            _ => false,
+        }
     }
 
     pub fn is_my_address(&self, address: &DeviceAddress) -> bool {
@@ -709,7 +1364,33 @@ where
     alarm: &'a A,
     sending_app: Cell<Option<kernel::AppId>>,
     receiving_app: Cell<Option<kernel::AppId>>,
+    /// App whose event was started last, apps due at the same time are
+    /// served round-robin from it
+    last_served: Cell<Option<kernel::AppId>>,
     link_layer: LinkLayer,
+    /// Package names of the processes allowed to inspect connections
+    diagnostics_apps: Cell<&'static [usize]>,
+    /// Address set by command 21, in place of the one of the FICR
+    address: Cell<Option<DeviceAddress>>,
+    /// Source of the `advDelay`s and of the random parts of session keys,
+    /// see `set_rng`
+    rng: Cell<Option<&'a rng::RNG>>,
+    /// Hardware random number not used for an `advDelay` yet
+    randomness: Cell<Option<u32>>,
+    randomness_requested: Cell<bool>,
+    /// AES block the session keys are derived with, see `set_aes`
+    aes: Cell<Option<&'a AesECB<'a>>>,
+    aes_buf: kernel::common::take_cell::TakeCell<'a, [u8]>,
+    /// App whose session key is being derived
+    encrypting_app: Cell<Option<kernel::AppId>>,
+    /// Package names of the processes allowed to set the address
+    address_apps: Cell<&'static [&'static str]>,
+    /// LL_ENC_REQ waiting for the random numbers of its LL_ENC_RSP, and the
+    /// app whose connection it came on
+    encryption_request: Cell<Option<(kernel::AppId, EncryptionRequest)>>,
+    /// Random numbers collected for the LL_ENC_RSP
+    encryption_randomness: Cell<[u32; ENCRYPTION_RANDOM_WORDS]>,
+    encryption_randomness_len: Cell<usize>,
 }
 
 impl<'a, B, A> BLE<'a, B, A>
@@ -731,12 +1412,255 @@ where
             alarm,
             sending_app: Cell::new(None),
             receiving_app: Cell::new(None),
+            last_served: Cell::new(None),
             link_layer: LinkLayer,
+            diagnostics_apps: Cell::new(&[]),
+            address: Cell::new(None),
+            address_apps: Cell::new(&[]),
+            rng: Cell::new(None),
+            randomness: Cell::new(None),
+            randomness_requested: Cell::new(false),
+            aes: Cell::new(None),
+            aes_buf: kernel::common::take_cell::TakeCell::empty(),
+            encrypting_app: Cell::new(None),
+            encryption_request: Cell::new(None),
+            encryption_randomness: Cell::new([0; ENCRYPTION_RANDOM_WORDS]),
+            encryption_randomness_len: Cell::new(0),
         }
     }
 
+    /// Derive the session keys of encrypted connections with `aes`, using
+    /// `buf` of one block. The driver must also be set as the client of
+    /// `aes`. Without it, centrals asking to encrypt are turned down.
+    pub fn set_aes(&self, aes: &'a AesECB<'a>, buf: &'a mut [u8]) {
+        aes.enable();
+        self.aes.set(Some(aes));
+        self.aes_buf.replace(buf);
+    }
+
+    /// Draw the `advDelay`s and the random parts of session keys from `rng`.
+    /// The driver must also be set as the client of `rng`. Without it,
+    /// centrals asking to encrypt are turned down.
+    pub fn set_rng(&self, rng: &'a rng::RNG) {
+        self.rng.set(Some(rng));
+        self.request_randomness();
+    }
+
+    fn request_randomness(&self) {
+        let needed = self.randomness.get().is_none() || self.encryption_request.get().is_some();
+        if needed && !self.randomness_requested.get() {
+            self.rng.get().map(|rng| {
+                self.randomness_requested.set(true);
+                rng.get();
+            });
+        }
+    }
+
+    // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 4.4.2.2
+    //
+    // advDelay is a pseudo-random value in the range of 0 ms to 10 ms, drawn
+    // anew for each advertising event. Random numbers are fetched from the
+    // RNG one event ahead, `app`'s software generator stands in while none
+    // is available.
+    fn adv_delay_us(&self, app: &mut App) -> u32 {
+        self.random(app) % (ADV_DELAY_MAX_US + 1)
+    }
+
+    // A random number from the RNG if one is at hand, from `app`'s software
+    // generator otherwise
+    fn random(&self, app: &mut App) -> u32 {
+        let random = self.randomness
+            .take()
+            .unwrap_or_else(|| app.random_nonce());
+        self.request_randomness();
+        random
+    }
+
+    // Fetch the random numbers of the LL_ENC_RSP answering `request`, which
+    // is sent once they are available, see `randomness_available`. Without
+    // an RNG the request is turned down right away.
+    fn defer_encryption_request(
+        &self,
+        appid: kernel::AppId,
+        app: &mut App,
+        request: EncryptionRequest,
+    ) {
+        if self.rng.get().is_some() {
+            self.encryption_request.set(Some((appid, request)));
+            self.encryption_randomness_len.set(0);
+            self.request_randomness();
+        } else {
+            self.answer_encryption_request(appid, app, &request, None);
+        }
+    }
+
+    // BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 5.1.3.1
+    //
+    // Answer the central's LL_ENC_REQ with our SKDs and IVs, taken from
+    // `random`, and derive the session key from the SKD and `app`'s long
+    // term key. The encryption is started once it is ready, see
+    // `crypt_done`, and rejected right away if there is no key or random
+    // numbers, or the AES block is busy.
+    fn answer_encryption_request(
+        &self,
+        appid: kernel::AppId,
+        app: &mut App,
+        request: &EncryptionRequest,
+        random: Option<[u32; ENCRYPTION_RANDOM_WORDS]>,
+    ) {
+        let mut skds = [0; 8];
+        let mut ivs = [0; 4];
+        let words = random.unwrap_or([0; ENCRYPTION_RANDOM_WORDS]);
+        for i in 0..8 {
+            skds[i] = (words[i / 4] >> (8 * (i % 4))) as u8;
+        }
+        for i in 0..4 {
+            ivs[i] = (words[2] >> (8 * i)) as u8;
+        }
+
+        let long_term_key = random.and_then(|_| app.long_term_key(request));
+        if let Some(AppBLEState::Connection(ref mut conndata)) = app.process_status {
+            // The connection the request came on may be gone
+            let skd = match conndata.respond_encryption_request(request, skds, ivs) {
+                Some(skd) => skd,
+                None => return,
+            };
+            let started = match (long_term_key, self.aes.get()) {
+                (Some(key), Some(aes)) => self.aes_buf.take().map_or(false, |buf| {
+                    buf[..skd.len()].copy_from_slice(&skd);
+                    aes.set_mode_aes128ecb(true);
+                    aes.set_key(&key);
+                    match aes.crypt(None, buf, 0, symmetric_encryption::AES128_BLOCK_SIZE) {
+                        None => {
+                            self.encrypting_app.set(Some(appid));
+                            true
+                        }
+                        Some((_, _, buf)) => {
+                            self.aes_buf.replace(buf);
+                            false
+                        }
+                    }
+                }),
+                _ => false,
+            };
+            if !started {
+                conndata.reject_encryption();
+            }
+        }
+    }
+
+    // Back to advertising from the next advertising event on, after the
+    // connection was lost for `reason`, an HCI error code
+    fn end_connection(&self, app: &mut App, reason: usize) {
+        if let Some(AppBLEState::Connection(ref mut conndata)) = app.process_status {
+            let unsent = conndata.take_unsent();
+            if unsent > 0 {
+                app.connection_callback
+                    .as_mut()
+                    .map(|cb| cb.schedule(usize::from(ReturnCode::ECANCEL), unsent, 0));
+            }
+        }
+        app.process_status = Some(AppBLEState::Advertising);
+        app.state = None;
+        self.radio.set_encryption(None);
+        self.end_event(app);
+        app.disconnect_callback
+            .as_mut()
+            .map(|cb| cb.schedule(reason, 0, 0));
+    }
+
+    /// Allow the processes in these slots, numbered in the order the kernel
+    /// loads them from flash, to read the parameters of other processes'
+    /// connections with command 11.
+    pub fn set_diagnostics_apps(&self, processes: &'static [usize]) {
+        self.diagnostics_apps.set(processes);
+    }
+
+    fn is_diagnostics_app(&self, appid: kernel::AppId) -> bool {
+        self.diagnostics_apps.get().contains(&appid.idx())
+    }
+
+    /// Allow the processes with these package names to set the address of
+    /// the device.
+    pub fn set_address_apps(&self, package_names: &'static [&'static str]) {
+        self.address_apps.set(package_names);
+    }
+
+    fn is_address_app(&self, appid: kernel::AppId) -> bool {
+        kernel::process::package_name(appid).map_or(false, |name| {
+            self.address_apps.get().iter().any(|allowed| *allowed == name)
+        })
+    }
+
+    /// The address of the device and whether it is random: the one set by
+    /// command 21, or else the one programmed in the FICR. A random address
+    /// of the FICR is a static address, so its two most significant bits are
+    /// set (Vol 6, Part B, section 1.3.2.1).
+    fn device_address(&self) -> (DeviceAddress, bool) {
+        if let Some(address) = self.address.get() {
+            return (address, true);
+        }
+        let ficr = unsafe { &ficr::FICR_INSTANCE };
+        let mut address = ficr.device_address();
+        let random = ficr.device_address_type() == ficr::AddressType::Random;
+        if random {
+            address[5] |= 0xc0;
+        }
+        (DeviceAddress(address), random)
+    }
+
+    // Command 21
+    fn set_device_address(&self, low: usize, high: usize) -> ReturnCode {
+        let mut busy = false;
+        for app in self.app.iter() {
+            app.enter(|app, _| match app.process_status {
+                Some(AppBLEState::Advertising) | Some(AppBLEState::Scanning) => busy = true,
+                _ => {}
+            });
+        }
+        if busy {
+            return ReturnCode::EBUSY;
+        }
+        if low == 0 && high == 0 {
+            self.address.set(None);
+            return ReturnCode::SUCCESS;
+        }
+
+        let (address, random) = App::address_from_command(low, high);
+        let kind = address.0[5] >> 6;
+        let random_part = address.0[..5].iter().fold(address.0[5] & 0x3f, |acc, b| acc | *b);
+        let random_part_ones = address.0[..5].iter().fold(address.0[5] | 0xc0, |acc, b| acc & *b);
+        // 0b10 is reserved, 0b11 is static and 0b0x private
+        if !random || kind == 0b10 || random_part == 0 || random_part_ones == 0xff {
+            return ReturnCode::EINVAL;
+        }
+        self.address.set(Some(address));
+        ReturnCode::SUCCESS
+    }
+
+    // Parameter `field` of the first connection found, see command 11
+    /// Command 11: `field` of the connection of `appid`, or of the
+    /// connection currently up for a diagnostics app
+    fn connection_parameter(&self, appid: kernel::AppId, field: usize) -> ReturnCode {
+        if !self.is_diagnostics_app(appid) {
+            return self.app
+                .enter(appid, |app, _| app.connection_parameter(field))
+                .unwrap_or_else(|err| err.into());
+        }
+        let mut result = ReturnCode::EOFF;
+        for app in self.app.iter() {
+            app.enter(|app, _| {
+                if result == ReturnCode::EOFF {
+                    result = app.connection_parameter(field);
+                }
+            });
+        }
+        result
+    }
+
     // Determines which app timer will expire next and sets the underlying alarm
-    // to it.
+    // to it. Apps already due, which had to wait for the radio, are started
+    // shortly after it is free again.
     //
     // This method iterates through all grants so it should be used somewhat
     // sparringly. Moreover, it should _not_ be called from within a grant,
@@ -744,23 +1668,112 @@ where
     // likely be chosen.
     fn reset_active_alarm(&self) {
         let now = self.alarm.now();
-        let mut next_alarm = u32::max_value();
+        let radio_free = self.busy.get() == BusyState::Free;
         let mut next_dist = u32::max_value();
         for app in self.app.iter() {
             app.enter(|app, _| match app.alarm_data.expiration {
                 Expiration::Abs(exp) => {
-                    let t_dist = exp.wrapping_sub(now);
+                    let t_dist = if !app.is_due(now) {
+                        exp.wrapping_sub(now)
+                    } else if radio_free {
+                        EVENT_GAP_MS * A::Frequency::frequency() / 1000
+                    } else {
+                        // Started when the event in progress ends
+                        return;
+                    };
                     if next_dist > t_dist {
-                        next_alarm = exp;
                         next_dist = t_dist;
                     }
                 }
                 Expiration::Disabled => {}
             });
         }
-        if next_alarm != u32::max_value() {
-            self.alarm.set_alarm(next_alarm);
+        if next_dist != u32::max_value() {
+            self.alarm.set_alarm(now.wrapping_add(next_dist));
+        }
+    }
+
+    // The app to start an event for next among those that are due: the first
+    // after the one served last, in process order.
+    fn next_due_app(&self, now: u32) -> Option<kernel::AppId> {
+        let last = self.last_served.get().map(|appid| appid.idx());
+        let turn = |appid: kernel::AppId| (last.map_or(false, |last| appid.idx() <= last), appid.idx());
+        let mut next: Option<kernel::AppId> = None;
+        for app in self.app.iter() {
+            app.enter(|app, _| {
+                if app.is_due(now) {
+                    let appid = app.appid();
+                    if next.map_or(true, |next| turn(appid) < turn(next)) {
+                        next = Some(appid);
+                    }
+                }
+            });
+        }
+        next
+    }
+
+    // Give the radio to `appid` for an advertising or scanning event,
+    // starting on channel 37.
+    fn start_event(&self, appid: kernel::AppId) {
+        self.busy.set(BusyState::Busy(appid));
+        self.last_served.set(Some(appid));
+        self.receiving_app.set(Some(appid));
+        self.sending_app.set(Some(appid));
+        self.radio.set_context(RadioContext::advertising(
+            RadioChannel::AdvertisingChannel37,
+        ));
+
+        let _ = self.app.enter(appid, |app, _| {
+            // Armed again when the event ends
+            app.alarm_data.expiration = Expiration::Disabled;
+            app.channel = Some(RadioChannel::AdvertisingChannel37);
+            self.radio.set_tx_power(app.tx_power);
+
+            let scanning = app.process_status == Some(AppBLEState::Scanning);
+            self.radio.set_address_filtering(scanning && app.is_filtering());
+
+            if let Some(AppBLEState::Scanning) = app.process_status {
+                app.scan_request_target = None;
+                self.radio.receive_advertisement(SCAN_WINDOW);
+            } else {
+                let now = self.alarm.now();
+                let since_last_us = app.advertising_event_start.map(|start| {
+                    (now.wrapping_sub(start) as u64 * 1000000
+                        / <A::Frequency>::frequency() as u64) as u32
+                });
+                app.advertising_event_start = Some(now);
+                unsafe {
+                    CONFORMANCE
+                        .advertising_event(since_last_us, app.advertisement_interval_ms * 1000)
+                };
+
+                if app.advertisement_type.is_scannable() {
+                    app.prepare_scan_response(self);
+                }
+                app.prepare_advertisement(self);
+                self.transmit_buffer(appid);
+            }
+        });
+    }
+
+    // The event of `app` is over: schedule its next one and free the radio
+    // for the others. The caller resets the alarm once out of the grant.
+    fn end_event(&self, app: &mut App) {
+        app.channel = None;
+        match app.process_status {
+            Some(AppBLEState::Advertising) => {
+                let delay_us = self.adv_delay_us(app);
+                app.set_next_alarm::<A::Frequency>(self.alarm.now(), delay_us);
+                app.advertising_callback
+                    .as_mut()
+                    .map(|cb| cb.schedule(usize::from(ReturnCode::SUCCESS), 0, 0));
+            }
+            Some(AppBLEState::Scanning) => {
+                app.set_next_alarm::<A::Frequency>(self.alarm.now(), 0);
+            }
+            _ => {}
         }
+        self.busy.set(BusyState::Free);
     }
 
     fn transmit_buffer(&self, appid: kernel::AppId) {
@@ -779,6 +1792,86 @@ where
             self.kernel_tx.replace(res);
         });
     }
+
+    // Move a scanning event on to the next advertising channel, starting
+    // when the window on the current one would have ended. The event ends
+    // after channel 39.
+    fn continue_scanning(&self, app: &mut App) -> PhyTransition {
+        match app.channel.and_then(|channel| channel.get_next_advertising_channel()) {
+            Some(channel) => {
+                app.channel = Some(channel);
+                self.radio.set_context(RadioContext::advertising(channel));
+                PhyTransition::MoveToRX(
+                    DelayStartPoint::PreviousPacketStartUsecDelay(SCAN_WINDOW),
+                    SCAN_WINDOW,
+                )
+            }
+            None => {
+                self.end_event(app);
+                PhyTransition::None
+            }
+        }
+    }
+
+    // Handle a packet received while scanning. Advertisements are copied to
+    // the app, and when active scanning a scannable one is answered with a
+    // SCAN_REQ T_IFS after it ends. The report then waits for the SCAN_RSP.
+    fn scan_receive_end(&self, app: &mut App, received: &ReceivedPdu) -> PhyTransition {
+        let buf: &[u8] = received.buf;
+        let crc_ok = received.crc_ok;
+        let rssi = received.rssi;
+        let pdu_type = BLEAdvertisementType::from_u8(buf[PACKET_HDR_PDU] & 0x0f);
+        let len = buf[PACKET_HDR_LEN];
+        let pdu_len = cmp::min(PACKET_ADDR_START + len as usize, buf.len());
+
+        let pdu = if crc_ok && pdu_type.as_ref().map_or(false, |pdu| pdu.validate_pdu(len)) {
+            pdu_type.and_then(|pdu_type| BLEPduType::from_buffer(pdu_type, buf))
+        } else {
+            None
+        };
+
+        match pdu {
+            Some(BLEPduType::ScanResponse(adv_addr, _))
+                if app.scan_request_target == Some(adv_addr) =>
+            {
+                app.scan_request_target = None;
+                let offset = app.scan_report_len;
+                let scan_response_len = app.copy_to_scan_buffer(offset, &buf[0..pdu_len]);
+                app.report_scan(scan_response_len);
+                self.continue_scanning(app)
+            }
+            Some(BLEPduType::ConnectUndirected(adv_addr, _))
+            | Some(BLEPduType::NonConnectUndirected(adv_addr, _))
+            | Some(BLEPduType::ScanUndirected(adv_addr, _))
+                if app.scan_request_target.is_none() =>
+            {
+                app.scan_report_len = app.copy_to_scan_buffer(0, &buf[0..pdu_len]);
+                app.scan_report_rssi = rssi;
+
+                let scannable = pdu_type.map_or(false, |pdu_type| pdu_type.is_scannable());
+                if app.active_scanning && scannable {
+                    // TxAdd of the advertisement is RxAdd of the request
+                    let adv_random = buf[PACKET_HDR_PDU] & (1 << 6) != 0;
+                    if app.prepare_scan_request(self, adv_addr, adv_random) == ReturnCode::SUCCESS
+                    {
+                        app.scan_request_target = Some(adv_addr);
+                        return PhyTransition::MoveToTX(DelayStartPoint::PacketEndBLEStandardDelay);
+                    }
+                }
+
+                app.report_scan(0);
+                self.continue_scanning(app)
+            }
+            _ => {
+                // The advertiser did not answer the SCAN_REQ, report the
+                // advertisement on its own
+                if app.scan_request_target.take().is_some() {
+                    app.report_scan(0);
+                }
+                self.continue_scanning(app)
+            }
+        }
+    }
 }
 
 // Timer alarm
@@ -792,53 +1885,99 @@ where
     // advertising or scanning event). We know which operation based on the
     // current app's state.
     //
-    // In case of collision---if there is already an event happening---we'll
-    // just delay the operation for next time and hope for the best. Since some
-    // randomness is added for each period in an app's timer, collisions should
-    // be rare in practice.
-    //
-    // TODO: perhaps break ties more fairly by prioritizing apps that have least
-    // recently performed an operation.
+    // Only one event runs at a time. If the radio is busy, expired apps wait
+    // and are started one after the other once it is free again, taking turns
+    // when several of them are due.
     fn fired(&self) {
-        let now = self.alarm.now();
-        //debug!("Timer fired!");
-
-        self.app.each(|app| {
-            if let Expiration::Abs(exp) = app.alarm_data.expiration {
-                let expired =
-                    now.wrapping_sub(app.alarm_data.t0) >= exp.wrapping_sub(app.alarm_data.t0);
-                if expired {
-                    let appid = app.appid();
+        if self.busy.get() == BusyState::Free {
+            if let Some(appid) = self.next_due_app(self.alarm.now()) {
+                self.start_event(appid);
+            }
+        }
+        self.reset_active_alarm();
+    }
+}
 
-                    if let BusyState::Busy(busy_app_id) = self.busy.get() {
-                        if busy_app_id != appid {
-                            // The radio is currently busy, so we won't be able to start the
-                            // operation at the appropriate time. Instead, reschedule the
-                            // operation for later. This is _kind_ of simulating actual
-                            // on-air interference
-                            debug!("BLE: operationg delayed for app {:?}", appid);
-                            app.set_next_alarm::<A::Frequency>(self.alarm.now());
-                            return;
-                        }
+impl<'a, B, A> rng::Client for BLE<'a, B, A>
+where
+    B: ble_advertising_hil::BleAdvertisementDriver + ble_advertising_hil::BleConfig + 'a,
+    A: kernel::hil::time::Alarm + 'a,
+{
+    fn randomness_available(&self, randomness: &mut Iterator<Item = u32>) -> rng::Continue {
+        // The LL_ENC_RSP is served first, `advDelay`s can fall back on the
+        // software generator meanwhile
+        if let Some((appid, request)) = self.encryption_request.get() {
+            let mut words = self.encryption_randomness.get();
+            let mut len = self.encryption_randomness_len.get();
+            while len < ENCRYPTION_RANDOM_WORDS {
+                match randomness.next() {
+                    Some(random) => {
+                        words[len] = random;
+                        len += 1;
                     }
+                    None => break,
+                }
+            }
+            self.encryption_randomness.set(words);
+            self.encryption_randomness_len.set(len);
+            if len < ENCRYPTION_RANDOM_WORDS {
+                return rng::Continue::More;
+            }
+            self.encryption_request.set(None);
+            let _ = self.app.enter(appid, |app, _| {
+                self.answer_encryption_request(appid, app, &request, Some(words));
+            });
+        }
 
-                    self.receiving_app.set(Some(appid));
-                    self.sending_app.set(Some(appid));
-                    self.radio.set_channel(
-                        RadioChannel::AdvertisingChannel37,
-                        constants::ADV_ACCESS_ADDRESS_BLE,
-                        constants::RADIO_CRCINIT_BLE,
-                    );
-
-                    //TODO - for now, let the advertiser always set MoveToRX, change later
-                    app.channel = Some(RadioChannel::AdvertisingChannel37);
+        if self.randomness.get().is_none() {
+            match randomness.next() {
+                Some(random) => self.randomness.set(Some(random)),
+                None => return rng::Continue::More,
+            }
+        }
+        self.randomness_requested.set(false);
+        rng::Continue::Done
+    }
+}
 
-                    app.prepare_advertisement(self, BLEAdvertisementType::ConnectUndirected);
-                    self.transmit_buffer(appid);
+// The session key of an encrypted connection is ready
+impl<'a, B, A> symmetric_encryption::Client<'a> for BLE<'a, B, A>
+where
+    B: ble_advertising_hil::BleAdvertisementDriver + ble_advertising_hil::BleConfig + 'a,
+    A: kernel::hil::time::Alarm + 'a,
+{
+    fn crypt_done(&'a self, _source: Option<&'a mut [u8]>, dest: &'a mut [u8]) {
+        if let Some(appid) = self.encrypting_app.take() {
+            let mut session_key = [0; symmetric_encryption::AES128_BLOCK_SIZE];
+            session_key.copy_from_slice(&dest[..symmetric_encryption::AES128_BLOCK_SIZE]);
+            let _ = self.app.enter(appid, |app, _| {
+                if let Some(AppBLEState::Connection(ref mut conndata)) = app.process_status {
+                    conndata.start_encryption(session_key);
                 }
-            }
-        });
-        self.reset_active_alarm();
+            });
+        }
+        self.aes_buf.replace(dest);
+    }
+}
+
+// The TX power limit changed with the die temperature
+impl<'a, B, A> TxPowerThrottleClient for BLE<'a, B, A>
+where
+    B: ble_advertising_hil::BleAdvertisementDriver + ble_advertising_hil::BleConfig + 'a,
+    A: kernel::hil::time::Alarm + 'a,
+{
+    fn tx_power_limit_changed(&self, limit: Option<i8>, temperature: i32) {
+        let (throttled, dbm) = match limit {
+            Some(dbm) => (1, dbm),
+            None => (0, 0),
+        };
+        for app in self.app.iter() {
+            app.enter(|app, _| {
+                app.tx_power_callback.map(|mut cb| {
+                    cb.schedule(throttled, dbm as u8 as usize, temperature as usize);
+                });
+            });
+        }
     }
 }
 
@@ -856,31 +1995,38 @@ where
 
         if let Some(appid) = self.receiving_app.get() {
             let _ = self.app.enter(appid, |app, _| {
-                read_action = self.link_layer.handle_rx_start(app, pdu_type)
+                // Drop advertisers off the whitelist before receiving the rest
+                let scanning = app.process_status == Some(AppBLEState::Scanning);
+                read_action = if scanning && !app.is_whitelisted(buf) {
+                    ReadAction::SkipFrame
+                } else {
+                    self.link_layer.handle_rx_start(app, pdu_type)
+                };
             });
         }
 
         read_action
     }
 
-    fn receive_end(
-        &self,
-        buf: &'static mut [u8],
-        len: u8,
-        result: ReturnCode,
-        rx_timestamp: u32,
-    ) -> PhyTransition {
+    fn receive_end(&self, received: ReceivedPdu) -> PhyTransition {
         let mut transition = PhyTransition::None;
 
         if let Some(appid) = self.sending_app.get() {
             let _ = self.app.enter(appid, |app, _| {
+                if let Some(AppBLEState::Scanning) = app.process_status {
+                    transition = self.scan_receive_end(app, &received);
+                    return;
+                }
+
+                let buf: &[u8] = received.buf;
+
                 let pdu_type = BLEAdvertisementType::from_u8(buf[0] & 0x0f);
 
                 // Validate PDU type
                 // TODO Move into separate module
                 let len: u8 = buf[1];
 
-                let crc_match = result == ReturnCode::SUCCESS;
+                let crc_match = received.crc_ok;
                 let mut valid_pkt = false;
 
                 match app.process_status {
@@ -907,7 +2053,10 @@ where
 
                                 match response_action {
                                     Some(ResponseAction::ScanResponse) => {
-                                        app.prepare_scan_response(&self);
+                                        // The radio has already scheduled the
+                                        // response staged at the start of the event
+                                        app.state =
+                                            Some(BleLinkLayerState::RespondingToScanRequest);
 
                                         PhyTransition::MoveToTX(
                                             DelayStartPoint::PacketEndBLEStandardDelay,
@@ -916,11 +2065,10 @@ where
                                     Some(ResponseAction::Connection(mut conndata)) => {
                                         let channel = conndata.next_channel();
                                         app.channel = Some(channel);
-                                        self.radio.set_channel(
-                                            channel,
-                                            conndata.aa,
-                                            conndata.crcinit,
-                                        );
+                                        self.radio.set_context(conndata.radio_context(channel));
+                                        let (tx_phy, rx_phy) = conndata.phys();
+                                        self.radio.set_phy(tx_phy, rx_phy);
+                                        self.radio.set_encryption(None);
 
                                         let delay_until_rx = TRANSMIT_WINDOW_DELAY_CONN_IND
                                             + conndata.lldata.window_offset();
@@ -943,70 +2091,67 @@ where
                             }
                         }
                         Some(AppBLEState::Connection(_)) => {
-                            let (sn, nesn, interval_ended, interval_end_time) = if let Some(
-                                AppBLEState::Connection(ref mut conndata),
-                            ) =
+                            let mut response = [0; PACKET_LENGTH];
+                            let (
+                                next_anchor,
+                                acknowledged,
+                                mic_failed,
+                                termination,
+                                encryption_request,
+                                param_request_result,
+                            ) = if let Some(AppBLEState::Connection(ref mut conndata)) =
                                 app.process_status
                             {
-                                // debug!("{:?} {}", channel, conndata.conn_event_counter);
-
-                                let (sn, nesn, retransmit) = conndata.next_sequence_number(buf[0]);
-                                let DataHeader { more_data, llid, .. } = ConnectionData::get_data_pdu_header(buf[0]);
-
-
-
-                                if crc_match { // Only read the data in the pkt if crc matches.
-                                    match llid {
-                                        0x03 => { // 0x03 == Control PDU
-
-                                            match buf[2] {
-                                                0x01 => {
-                                                    let instant: u16 = ((buf[9] as u16) << 8) | buf[8] as u16;
-                                                    conndata.update_channelmap(ChannelMap::read_from_buffer(&buf[3..]), instant);
-                                                    debug_gpio!(0, clear);
-                                                },
-                                                _ => {
-                                                    // Ignore other LL Control Opcodes
-                                                }
-                                            }
-                                        },
-                                        _ => {
-                                            // Ignore other packets, just respond with empty pdu
-                                        }
-                                    }
-                                }
-
-                                let (interval_ended, interval_end_time) =
-                                    conndata.connection_interval_ended(rx_timestamp);
+                                let next_anchor = conndata.receive_data_pdu(&received);
+                                (
+                                    next_anchor,
+                                    conndata.take_acknowledged(),
+                                    conndata.mic_failed(),
+                                    conndata.termination(),
+                                    conndata.take_encryption_request(),
+                                    conndata.take_param_request_result(),
+                                )
+                            } else {
+                                panic!("Process status is not Connection in Connection!");
+                            };
 
-                                // If more_data is set, stay on the channel and listen
-                                // If more_data is false, skip to next channel even if current interval has time left
+                            if mic_failed {
+                                self.end_connection(app, MIC_FAILURE);
+                                return;
+                            }
+                            if let Some(reason) = termination {
+                                self.end_connection(app, reason as usize);
+                                return;
+                            }
 
-                                let skip_to_next_channel = interval_ended || !more_data;
+                            if let Some(request) = encryption_request {
+                                self.defer_encryption_request(appid, app, request);
+                            }
 
-                                if skip_to_next_channel {
-                                    conndata.conn_interval_start = None;
-                                }
+                            if let Some(AppBLEState::Connection(ref mut conndata)) =
+                                app.process_status
+                            {
+                                conndata.prepare_response(&mut response);
+                                self.radio.set_encryption(conndata.encryption());
+                            }
 
-                                if !more_data {
-                                    conndata.increment_conn_event();
-                                }
+                            if let Some(anchor) = next_anchor {
+                                app.state = Some(BleLinkLayerState::EndOfConnectionEvent(anchor));
+                            }
 
-                                (sn, nesn, skip_to_next_channel, interval_end_time)
-                            } else {
-                                panic!("Process status is not Connection in Connection!");
-                            };
+                            if acknowledged > 0 {
+                                app.connection_callback.as_mut().map(|cb| {
+                                    cb.schedule(usize::from(ReturnCode::SUCCESS), acknowledged, 0)
+                                });
+                            }
 
-                            match interval_end_time {
-                                Some(interval_end_time) if interval_ended => {
-                                    app.state = Some(BleLinkLayerState::EndOfConnectionEvent(
-                                        interval_end_time,
-                                    ));
-                                }
-                                _ => {}
+                            if let Some(result) = param_request_result {
+                                app.param_request_callback
+                                    .as_mut()
+                                    .map(|cb| cb.schedule(usize::from(result), 0, 0));
                             }
 
-                            app.set_empty_conn_pdu(&self, sn, nesn);
+                            app.set_conn_pdu(&self, &response);
 
                             // Respond to Data PDU just received
                             PhyTransition::MoveToTX(DelayStartPoint::PacketEndBLEStandardDelay)
@@ -1019,6 +2164,11 @@ where
                     PhyTransition::None
                 }
             });
+
+            // A scanning event may have ended
+            if self.busy.get() == BusyState::Free {
+                self.reset_active_alarm();
+            }
         }
 
         transition
@@ -1031,23 +2181,36 @@ where
     B: ble_advertising_hil::BleAdvertisementDriver + ble_advertising_hil::BleConfig + 'a,
     A: kernel::hil::time::Alarm + 'a,
 {
-    // The ReturnCode indicates valid CRC or not, not used yet but could be used for
-    // re-tranmissions for invalid CRCs
-    fn transmit_end(&self, _crc_ok: ReturnCode) -> PhyTransition {
+    fn transmit_end(&self, info: TxInfo) -> PhyTransition {
         let mut transition = PhyTransition::None;
 
         if let Some(appid) = self.sending_app.get() {
             let _ = self.app.enter(appid, |app, _| {
+                if let Some(AppBLEState::Advertising) = app.process_status {
+                    app.record_advertising_tx(info);
+                }
                 transition = if let Some(AppBLEState::Advertising) = app.process_status {
                     if let Some(BleLinkLayerState::RespondingToScanRequest) = app.state {
-                        app.prepare_advertisement(self, BLEAdvertisementType::ConnectUndirected);
+                        app.prepare_advertisement(self);
                         PhyTransition::MoveToTX(DelayStartPoint::PacketEndBLEStandardDelay)
+                    } else if !app.advertisement_type.is_connectable()
+                        && !app.advertisement_type.is_scannable()
+                    {
+                        // Nobody may answer ADV_NONCONN_IND, go straight on
+                        // to the next advertising channel
+                        PhyTransition::None
                     } else {
                         PhyTransition::MoveToRX(
                             DelayStartPoint::PacketEndBLEStandardDelay,
                             STANDARD_TIMEOUT,
                         )
                     }
+                } else if let Some(AppBLEState::Scanning) = app.process_status {
+                    // A SCAN_REQ went out, listen for the SCAN_RSP
+                    PhyTransition::MoveToRX(
+                        DelayStartPoint::PacketEndBLEStandardDelay,
+                        STANDARD_TIMEOUT,
+                    )
                 } else if let Some(AppBLEState::Connection(_)) = app.process_status {
                     let start_time = if let Some(BleLinkLayerState::EndOfConnectionEvent(
                         delay_time,
@@ -1057,8 +2220,9 @@ where
                         app.channel = if let Some(AppBLEState::Connection(ref mut conndata)) = app.process_status
                         {
                             let channel = conndata.next_channel();
-                            self.radio
-                                .set_channel(channel, conndata.aa, conndata.crcinit);
+                            self.radio.set_context(conndata.radio_context(channel));
+                            let (tx_phy, rx_phy) = conndata.phys();
+                            self.radio.set_phy(tx_phy, rx_phy);
                             Some(channel)
 
                         } else { None };
@@ -1070,7 +2234,7 @@ where
 
                     let timeout =
                         if let Some(AppBLEState::Connection(ref conndata)) = app.process_status {
-                            conndata.lldata.window_size()
+                            conndata.receive_window()
                         } else {
                             panic!("We are not in connection???");
                         };
@@ -1096,16 +2260,20 @@ where
         if let Some(appid) = self.sending_app.get() {
             let _ = self.app.enter(appid, |app, _| {
                 if app.state == Some(BleLinkLayerState::RespondingToScanRequest) {
-                    app.prepare_advertisement(self, BLEAdvertisementType::ConnectUndirected);
+                    app.prepare_advertisement(self);
                 }
 
-                let (tx_immediate, channel_triple): TxNextChannelType =
+                let (tx_immediate, next_context): TxNextChannelType =
                     self.link_layer.handle_event_done(app);
 
-                app.channel = if let Some((channel, adv_addr, crcinit)) = channel_triple {
-                    self.radio.set_channel(channel, adv_addr, crcinit);
+                app.channel = if let Some(context) = next_context {
+                    self.radio.set_context(context);
+                    if let Some(AppBLEState::Connection(ref conndata)) = app.process_status {
+                        let (tx_phy, rx_phy) = conndata.phys();
+                        self.radio.set_phy(tx_phy, rx_phy);
+                    }
 
-                    Some(channel)
+                    Some(context.channel)
                 } else {
                     None
                 };
@@ -1113,7 +2281,7 @@ where
                 match tx_immediate {
                     TxImmediate::GoToSleep => {
                         // TODO: Shut down radio when sleeping
-                        app.set_next_alarm::<A::Frequency>(self.alarm.now());
+                        self.end_event(app);
                     }
                     _ => {}
                 }
@@ -1135,34 +2303,44 @@ where
 
                 match state {
                     ActionAfterTimerExpire::ContinueAdvertising => {
-                        app.prepare_advertisement(self, BLEAdvertisementType::ConnectUndirected);
+                        app.prepare_advertisement(self);
 
                         if Some(RadioChannel::AdvertisingChannel39) != app.channel {
                             //TODO - we should start tx:ing as soon as possible, is this the best way of saying that?
                             result =
                                 PhyTransition::MoveToTX(DelayStartPoint::PacketEndUsecDelay(0));
                         }
+
+                        //Called to set new channel
+                        self.advertisement_done();
+                    }
+                    ActionAfterTimerExpire::ContinueScanning => {
+                        // Nothing heard in the window, or the SCAN_RSP never
+                        // came, in which case the advertisement is reported
+                        // on its own
+                        if app.scan_request_target.take().is_some() {
+                            app.report_scan(0);
+                        }
+                        result = self.continue_scanning(app);
                     }
                     ActionAfterTimerExpire::ContinueConnection(
                         conn_interval_length,
                         timeout,
                     ) => {
-                        if let Some(AppBLEState::Connection(ref mut conndata)) = app.process_status {
-                            conndata.increment_conn_event();
-                        }
-
                         //We should stay in the connection, but no more data should be sent on this channel
-                        //TODO - check if we have reached supervision time out. If so, kill connection.
 
                         result = PhyTransition::MoveToRX(
                             DelayStartPoint::PreviousPacketStartUsecDelay(conn_interval_length),
                             timeout,
                         );
+
+                        //Called to set new channel
+                        self.advertisement_done();
+                    }
+                    ActionAfterTimerExpire::EndConnection(reason) => {
+                        self.end_connection(app, reason as usize);
                     }
                 }
-
-                //Called to set new channel
-                self.advertisement_done();
             });
 
             self.reset_active_alarm();
@@ -1194,6 +2372,41 @@ where
                     _ => ReturnCode::EINVAL,
                 })
                 .unwrap_or_else(|err| err.into()),
+            // Callback for acknowledged connection data
+            1 => self.app
+                .enter(app_id, |app, _| {
+                    app.connection_callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            // Callback for lost connections
+            2 => self.app
+                .enter(app_id, |app, _| {
+                    app.disconnect_callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            // Callback for finished advertising events
+            3 => self.app
+                .enter(app_id, |app, _| {
+                    app.advertising_callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            // Callback for TX power throttling
+            4 => self.app
+                .enter(app_id, |app, _| {
+                    app.tx_power_callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            // Callback for connection parameter requests
+            5 => self.app
+                .enter(app_id, |app, _| {
+                    app.param_request_callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
             _ => ReturnCode::ENOSUPPORT,
         }
     }
@@ -1202,7 +2415,7 @@ where
         &self,
         command_num: usize,
         data: usize,
-        _: usize,
+        data2: usize,
         appid: kernel::AppId,
     ) -> ReturnCode {
         match command_num {
@@ -1211,9 +2424,11 @@ where
                 .enter(appid, |app, _| {
                     if let Some(AppBLEState::Initialized) = app.process_status {
                         app.process_status = Some(AppBLEState::Advertising);
+                        app.advertising_event_start = None;
                         app.channel = Some(RadioChannel::AdvertisingChannel37);
-                        app.random_nonce = self.alarm.now();
-                        app.set_next_alarm::<A::Frequency>(self.alarm.now());
+                        app.random_nonce = self.alarm.now() | 1;
+                        let delay_us = self.adv_delay_us(app);
+                        app.set_next_alarm::<A::Frequency>(self.alarm.now(), delay_us);
                         self.reset_active_alarm();
                         ReturnCode::SUCCESS
                     } else {
@@ -1226,7 +2441,9 @@ where
             1 => self.app
                 .enter(appid, |app, _| match app.process_status {
                     Some(AppBLEState::Advertising) | Some(AppBLEState::Scanning) => {
+                        // An event in progress still runs to its end
                         app.process_status = Some(AppBLEState::Initialized);
+                        app.alarm_data.expiration = Expiration::Disabled;
                         ReturnCode::SUCCESS
                     }
                     _ => ReturnCode::EBUSY,
@@ -1267,14 +2484,15 @@ where
             // The advertising interval shall an integer multiple of 0.625ms in the range of
             // 20ms to 10240 ms!
             //
-            // data - advertisement interval in ms
+            // data - advertisement interval in ms, takes effect from the
+            // next advertising event on
             // FIXME: add check that data is a multiple of 0.625
             3 => self.app
-                .enter(appid, |app, _| match self.busy.get() {
-                    BusyState::Busy(appid) if app.appid() == appid => ReturnCode::EBUSY,
-                    _ => {
-                        //app.advertisement_interval_ms = cmp::max(20, cmp::min(10240, data as u32));
-                        app.advertisement_interval_ms = cmp::max(20, cmp::min(10240, 280 as u32));
+                .enter(appid, |app, _| {
+                    if data < ADV_INTERVAL_MIN_MS || data > ADV_INTERVAL_MAX_MS {
+                        ReturnCode::EINVAL
+                    } else {
+                        app.advertisement_interval_ms = data as u32;
                         ReturnCode::SUCCESS
                     }
                 })
@@ -1287,13 +2505,19 @@ where
                 .enter(appid, |app, _| app.reset_payload())
                 .unwrap_or_else(|err| err.into()),
 
-            // Passive scanning mode
+            // Passive or active scanning mode
             5 => self.app
                 .enter(appid, |app, _| {
                     if let Some(AppBLEState::Initialized) = app.process_status {
+                        app.active_scanning = data != 0;
+                        if app.active_scanning {
+                            // SCAN_REQs carry the scanner's address
+                            let (address, random) = self.device_address();
+                            app.set_advertising_address(address, random);
+                        }
                         app.process_status = Some(AppBLEState::Scanning);
                         app.channel = Some(RadioChannel::AdvertisingChannel37);
-                        app.set_next_alarm::<A::Frequency>(self.alarm.now());
+                        app.set_next_alarm::<A::Frequency>(self.alarm.now(), 0);
                         self.reset_active_alarm();
                         ReturnCode::SUCCESS
                     } else {
@@ -1309,7 +2533,8 @@ where
             6 => self.app
                 .enter(appid, |app, _| {
                     if let Some(AppBLEState::Initialized) = app.process_status {
-                        let status = app.generate_random_address(appid);
+                        let (address, random) = self.device_address();
+                        let status = app.set_advertising_address(address, random);
                         if status == ReturnCode::SUCCESS {
                             debug!("Initialize!");
                             app.configure_advertisement_pdu()
@@ -1323,6 +2548,103 @@ where
                 })
                 .unwrap_or_else(|err| err.into()),
 
+            // Dump the radio state and the radio event trace, for debugging
+            // stuck link-layer exchanges
+            7 => {
+                self.radio.dump_state();
+                unsafe { trace::TRACE.dump() };
+                ReturnCode::SUCCESS
+            }
+
+            // Configure the advertising PDU type
+            8 => self.app
+                .enter(appid, |app, _| app.set_advertisement_type(data))
+                .unwrap_or_else(|err| err.into()),
+
+            // Configure the initiator addressed by directed advertising
+            9 => self.app
+                .enter(appid, |app, _| app.set_direct_address(data, data2))
+                .unwrap_or_else(|err| err.into()),
+
+            // Add an advertiser to the scanning whitelist
+            12 => self.app
+                .enter(appid, |app, _| app.add_to_whitelist(data, data2))
+                .unwrap_or_else(|err| err.into()),
+
+            // Clear the scanning whitelist
+            13 => self.app
+                .enter(appid, |app, _| app.clear_whitelist())
+                .unwrap_or_else(|err| err.into()),
+
+            // Send data to the central during a connection
+            10 => self.app
+                .enter(appid, |app, _| app.send_connection_data(data))
+                .unwrap_or_else(|err| err.into()),
+
+            // Inspect the connection hopping parameters
+            11 => self.connection_parameter(appid, data),
+
+            // Negotiate the PHYs of the connection
+            14 => {
+                let supported = self.radio.supported_phys();
+                self.app
+                    .enter(appid, |app, _| app.request_phy(data, data2, supported))
+                    .unwrap_or_else(|err| err.into())
+            }
+
+            // Read the advertising statistics
+            15 => self.app
+                .enter(appid, |app, _| app.advertising_statistic(data))
+                .unwrap_or_else(|err| err.into()),
+
+            // Set the flags, appearance or device name
+            16 => self.app
+                .enter(appid, |app, _| {
+                    if data > 0xff {
+                        ReturnCode::EINVAL
+                    } else {
+                        app.set_ad_structure(BLEGapType::Flags, &[data as u8])
+                    }
+                })
+                .unwrap_or_else(|err| err.into()),
+            17 => self.app
+                .enter(appid, |app, _| {
+                    if data > 0xffff {
+                        ReturnCode::EINVAL
+                    } else {
+                        let appearance = [data as u8, (data >> 8) as u8];
+                        app.set_ad_structure(BLEGapType::Appearance, &appearance)
+                    }
+                })
+                .unwrap_or_else(|err| err.into()),
+            18 => self.app
+                .enter(appid, |app, _| app.set_device_name(data))
+                .unwrap_or_else(|err| err.into()),
+
+            // Print the conformance counters, or start a new test run
+            19 => {
+                debug!("{}", unsafe { &CONFORMANCE });
+                ReturnCode::SUCCESS
+            }
+            20 => {
+                unsafe { CONFORMANCE.reset() };
+                ReturnCode::SUCCESS
+            }
+
+            // Set the address of the device
+            21 => {
+                if self.is_address_app(appid) {
+                    self.set_device_address(data, data2)
+                } else {
+                    ReturnCode::ENOSUPPORT
+                }
+            }
+
+            // Ask the central for new connection parameters
+            22 => self.app
+                .enter(appid, |app, _| app.request_connection_parameters(data, data2))
+                .unwrap_or_else(|err| err.into()),
+
             _ => ReturnCode::ENOSUPPORT,
         }
     }
@@ -1356,6 +2678,56 @@ where
                 })
                 .unwrap_or_else(|err| err.into()),
 
+            Some(AllowType::ScanResponseData) => self.app
+                .enter(appid, |app, _| match app.process_status {
+                    Some(AppBLEState::Advertising) => ReturnCode::EBUSY,
+                    _ => {
+                        let len = slice.as_ref().map_or(0, |slice| slice.len());
+                        if len > ADV_DATA_MAX_LEN {
+                            ReturnCode::ESIZE
+                        } else {
+                            app.scan_response_buf = slice;
+                            app.scan_response_start = 0;
+                            app.scan_response_len = len;
+                            ReturnCode::SUCCESS
+                        }
+                    }
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            Some(AllowType::AdvertisingData) => self.app
+                .enter(appid, |app, _| match app.process_status {
+                    Some(AppBLEState::Advertising) => ReturnCode::EBUSY,
+                    _ => app.set_advertising_data(slice),
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            Some(AllowType::DeviceName) => self.app
+                .enter(appid, |app, _| {
+                    app.name_buf = slice;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            Some(AllowType::LongTermKey) => self.app
+                .enter(appid, |app, _| {
+                    let len = slice.as_ref().map_or(LONG_TERM_KEY_LEN, |slice| slice.len());
+                    if len != LONG_TERM_KEY_LEN && len != LONG_TERM_KEY_LEN + LONG_TERM_KEY_ID_LEN {
+                        ReturnCode::ESIZE
+                    } else {
+                        app.long_term_key_buf = slice;
+                        ReturnCode::SUCCESS
+                    }
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            Some(AllowType::ConnectionData) => self.app
+                .enter(appid, |app, _| {
+                    app.connection_buf = slice;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+
             Some(AllowType::InitAdvertisementBuffer) => self.app
                 .enter(appid, |app, _| {
                     if let Some(AppBLEState::NotInitialized) = app.process_status {
@@ -49,13 +49,20 @@
 
 use ble::ble_connection_driver::ConnectionData;
 use kernel::ReturnCode;
-use nrf5x::constants::BLE_T_IFS;
+use nrf5x::constants::{ADV_ACCESS_ADDRESS_BLE, BLE_T_IFS, RADIO_CRCINIT_BLE};
 use core;
 
 pub trait BleAdvertisementDriver {
     fn transmit_advertisement(&self);
     fn set_advertisement_data(&self, buf: &'static mut [u8], len: usize) -> &'static mut [u8];
-    fn receive_advertisement(&self);
+    /// Stage the SCAN_RSP PDU answered to SCAN_REQs read with
+    /// `ReadAction::ReadFrameAndMoveToTX`. `buf` holds the full PDU, header
+    /// and AdvA included.
+    fn set_scan_response_data(&self, buf: &'static mut [u8], len: usize) -> &'static mut [u8];
+    /// Listen on the current channel for up to `window` microseconds.
+    /// `AdvertisementClient::timer_expired` is called if no packet starts
+    /// within the window.
+    fn receive_advertisement(&self, window: u32);
 
     fn set_receive_client(&self, client: &'static RxClient);
     fn set_transmit_client(&self, client: &'static TxClient);
@@ -64,8 +71,75 @@ pub trait BleAdvertisementDriver {
 
 pub trait BleConfig {
     fn set_tx_power(&self, power: u8) -> ReturnCode;
-    fn set_channel(&self, channel: RadioChannel, address: u32, crcinit: u32);
-    fn set_access_address(&self, aa: u32);
+    /// Send and receive with `context` from the next packet on, replacing
+    /// the whole configuration of the previous one. Returns `EINVAL`, and
+    /// leaves the radio as it was, if the context is not valid.
+    fn set_context(&self, context: RadioContext) -> ReturnCode;
+    /// The context last set, if any
+    fn context(&self) -> Option<RadioContext>;
+    /// Filter advertisements on their AdvA. While enabled, `receive_start`
+    /// is only called once the header and AdvA are in, and a frame skipped
+    /// with `ReadAction::SkipFrame` does not end the receive window: the
+    /// radio goes on listening on the same channel until the window times
+    /// out.
+    fn set_address_filtering(&self, enabled: bool);
+    /// PHYs to transmit and receive data channel packets with, from the
+    /// next packet on. Advertising channels always use the LE 1M PHY.
+    /// Returns `ENOSUPPORT` for PHYs the radio does not have.
+    fn set_phy(&self, tx: Phy, rx: Phy) -> ReturnCode;
+    /// The PHYs the radio supports, as a bitmask of `PHY_1M`, `PHY_2M` and
+    /// `PHY_CODED`
+    fn supported_phys(&self) -> u8;
+    /// Encrypt and decrypt data channel PDUs with `encryption` from the
+    /// next packet on, or stop with `None`. Called again whenever the packet
+    /// counters change. Returns `ENOSUPPORT` from radios that cannot encrypt.
+    fn set_encryption(&self, _encryption: Option<Encryption>) -> ReturnCode {
+        ReturnCode::ENOSUPPORT
+    }
+
+    /// Print the radio's internal state to the debug console. Meant for
+    /// debugging stuck link-layer exchanges, radios without such
+    /// introspection print nothing.
+    fn dump_state(&self) {}
+}
+
+// Bits standing for each PHY in the PHY fields of LL Control PDUs
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 2.4.2.22
+pub const PHY_1M: u8 = 0x01;
+pub const PHY_2M: u8 = 0x02;
+pub const PHY_CODED: u8 = 0x04;
+
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part A], section 2
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum Phy {
+    Le1M,
+    Le2M,
+    /// LE Coded, 500 kb/s
+    LeCodedS2,
+    /// LE Coded, 125 kb/s
+    LeCodedS8,
+}
+
+impl Phy {
+    /// The bit standing for this PHY in the PHY fields of LL Control PDUs
+    pub fn bit(self) -> u8 {
+        match self {
+            Phy::Le1M => PHY_1M,
+            Phy::Le2M => PHY_2M,
+            Phy::LeCodedS2 | Phy::LeCodedS8 => PHY_CODED,
+        }
+    }
+
+    /// The PHY of a PHY field with a single bit set, `coded` being the
+    /// coding used for the LE Coded PHY
+    pub fn from_bit(bit: u8, coded: Phy) -> Option<Phy> {
+        match bit {
+            PHY_1M => Some(Phy::Le1M),
+            PHY_2M => Some(Phy::Le2M),
+            PHY_CODED => Some(coded),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -89,6 +163,7 @@ impl DelayStartPoint {
     }
 }
 
+#[derive(Debug, Copy, Clone)]
 pub enum PhyTransition {
     None,
     MoveToTX(DelayStartPoint),
@@ -102,33 +177,100 @@ pub enum ResponseAction {
 
 pub enum ActionAfterTimerExpire {
     ContinueAdvertising,
+    ContinueScanning,
     ContinueConnection(u32, u32),
+    /// The connection is over, for the HCI error code, see
+    /// `ConnectionData::termination`
+    EndConnection(u8),
 }
 
+#[derive(Debug, Copy, Clone)]
 pub enum ReadAction {
     SkipFrame,
     ReadFrame,
+    /// Read the frame and, if it is a SCAN_REQ for the AdvA of the staged
+    /// scan response, transmit that response T_IFS after the request ends.
+    /// The response is scheduled before `receive_end` is called, which is
+    /// expected to return `PhyTransition::MoveToTX` for it.
+    ReadFrameAndMoveToTX,
 }
 
+#[derive(Debug, Copy, Clone)]
 pub enum TxImmediate {
     GoToSleep,
     RespondAfterTifs,
     TX,
 }
 
+/// Keys and packet counters of an encrypted connection, see
+/// `BleConfig::set_encryption`
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part E], section 2
+#[derive(Copy, Clone)]
+pub struct Encryption {
+    /// Session key, most significant octet first as in AES blocks
+    pub session_key: [u8; 16],
+    /// IVm followed by IVs, in the order they are sent
+    pub iv: [u8; 8],
+    /// packetCounter of the data PDU sent next
+    pub tx_counter: u64,
+    /// packetCounter of the next new data PDU received
+    pub rx_counter: u64,
+    /// Whether data PDUs sent are encrypted
+    pub tx: bool,
+    /// Whether data PDUs received are decrypted
+    pub rx: bool,
+}
+
+/// A packet received by the radio, passed to `RxClient::receive_end`
+pub struct ReceivedPdu {
+    /// The packet, from its header on
+    pub buf: &'static mut [u8],
+    /// Length of the packet, header included
+    pub len: u8,
+    /// The CRC of the packet matched
+    pub crc_ok: bool,
+    /// Received signal strength in dBm, sampled after the access address,
+    /// or 0 if the radio could not measure it
+    pub rssi: i8,
+    /// Channel the packet was received on
+    pub channel: Option<RadioChannel>,
+    /// Time the access address was received, in microseconds of the radio
+    /// timer
+    pub timestamp: u32,
+    /// False if the packet was decrypted and its MIC did not match
+    pub mic_ok: bool,
+}
+
 pub trait RxClient {
     fn receive_start(&self, buf: &'static mut [u8], len: u8) -> ReadAction;
-    fn receive_end(
-        &self,
-        buf: &'static mut [u8],
-        len: u8,
-        result: ReturnCode,
-        rx_timestamp: u32,
-    ) -> PhyTransition;
+    fn receive_end(&self, pdu: ReceivedPdu) -> PhyTransition;
+}
+
+/// How a transmission ended, see `TxInfo`
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum TxStatus {
+    /// The packet went out when it was scheduled to
+    Sent,
+    /// The packet went out, but the CPU was late to schedule it and it was
+    /// started by hand after its deadline had passed
+    DeadlineMissed,
+    /// The radio was disabled before the packet was out
+    Aborted,
+}
+
+/// Completion of a transmission, passed to `TxClient::transmit_end`
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct TxInfo {
+    pub status: TxStatus,
+    /// Channel the packet was sent on
+    pub channel: Option<RadioChannel>,
+    /// Time the packet ended on air, or the radio was disabled if it was
+    /// aborted, in microseconds of the radio timer
+    pub timestamp: u32,
 }
 
 pub trait TxClient {
-    fn transmit_end(&self, result: ReturnCode) -> PhyTransition;
+    fn transmit_end(&self, info: TxInfo) -> PhyTransition;
 }
 
 pub trait AdvertisementClient {
@@ -136,8 +278,76 @@ pub trait AdvertisementClient {
     fn timer_expired(&self) -> PhyTransition;
 }
 
+/// What the radio sends and receives with in an advertising event or a
+/// connection: the access address, the CRC initial value and the channel,
+/// whose index also seeds the data whitening. All advertising events share
+/// one context but for the channel, each connection has its own.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub struct RadioContext {
+    pub access_address: u32,
+    /// Only the 24 least significant bits are used
+    pub crcinit: u32,
+    pub channel: RadioChannel,
+}
+
+impl RadioContext {
+    /// The context of advertising events on `channel`
+    pub fn advertising(channel: RadioChannel) -> RadioContext {
+        RadioContext {
+            access_address: ADV_ACCESS_ADDRESS_BLE,
+            crcinit: RADIO_CRCINIT_BLE,
+            channel: channel,
+        }
+    }
+
+    /// The context of a connection with access address `access_address`
+    /// and CRC initial value `crcinit`, on `channel`
+    pub fn connection(access_address: u32, crcinit: u32, channel: RadioChannel) -> RadioContext {
+        RadioContext {
+            access_address: access_address,
+            crcinit: crcinit,
+            channel: channel,
+        }
+    }
+
+    /// Initial value of the data whitening LFSR
+    // BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 3.2
+    pub fn whitening_iv(&self) -> u32 {
+        self.channel.get_channel_index()
+    }
+
+    /// The context can be given to the radio: the CRC initial value fits in
+    /// 24 bits, and the access address is the advertising one or one a
+    /// connection can use
+    pub fn is_valid(&self) -> bool {
+        self.crcinit <= 0xffffff
+            && (self.access_address == ADV_ACCESS_ADDRESS_BLE
+                || is_valid_data_access_address(self.access_address))
+    }
+}
+
+/// Whether `aa` meets the requirements for the access address of a
+/// connection
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 2.1.2
+pub fn is_valid_data_access_address(aa: u32) -> bool {
+    // Bit n of `transitions` is set where bits n and n + 1 of `aa` differ
+    let transitions = (aa ^ (aa >> 1)) & 0x7fffffff;
+    // No more than six consecutive zeros or ones
+    let long_run = (0..26).any(|i| {
+        let bits = (aa >> i) & 0x7f;
+        bits == 0 || bits == 0x7f
+    });
+    // The advertising access address, or one bit away from it
+    let near_advertising = (aa ^ ADV_ACCESS_ADDRESS_BLE).count_ones() <= 1;
+    // Four equal octets
+    let repeated_octet = aa == (aa & 0xff) * 0x01010101;
+
+    !long_run && !near_advertising && !repeated_octet && transitions.count_ones() <= 24
+        && (transitions >> 26).count_ones() >= 2
+}
+
 // Bluetooth Core Specification:Vol. 6. Part B, section 1.4.1 Advertising and Data Channel Indices
-#[derive(PartialEq, Debug, Copy, Clone)]
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
 pub enum RadioChannel {
     DataChannel0 = 4,
     DataChannel1 = 6,
@@ -1,21 +1,197 @@
-use ble::ble_advertising_hil::RadioChannel;
+use ble::ble_advertising_hil::{self, Encryption, Phy, RadioChannel, RadioContext, ReceivedPdu};
 use ble::ble_link_layer::LLData;
 use core::fmt;
 use core::convert::TryInto;
-use ble::ble_link_layer::ChannelMap;
+use ble::ble_link_layer::{ChannelMap, ConnectionParameters, ConnectionUpdate, EncryptionRequest,
+                          PhyUpdate};
+use ble::ble_pdu_parser::LLControlPdu;
+use ble::trace::{self, Event};
+use kernel::ReturnCode;
+use nrf5x::constants::BLE_T_IFS;
 
 const NUMBER_CHANNELS: usize = 40;
 const NUMBER_DATA_CHANNELS: usize = NUMBER_CHANNELS - 3;
 
+/// Largest LL data PDU payload
+pub const MAX_DATA_PAYLOAD: usize = 27;
+/// Data PDUs that can be queued for transmission
+const TX_QUEUE_LEN: usize = 4;
+/// Queue slots L2CAP data cannot take, so that the answers of LL control
+/// procedures never wait for the process' data to drain
+const TX_QUEUE_CONTROL_SLOTS: usize = 1;
+
 type ChannelMapBuffer = [u8; NUMBER_CHANNELS];
 
+/// Link layer features reported in LL_FEATURE_RSP: LE Encryption,
+/// Connection Parameters Request Procedure, Extended Reject Indication, LE 2M
+/// PHY, LE Coded PHY on the nRF52840 and Channel Selection Algorithm #2
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 4.6
+#[cfg(not(feature = "nrf52840"))]
+const LL_FEATURES: u64 = 1 << 0 | 1 << 1 | 1 << 2 | 1 << 8 | 1 << 14;
+#[cfg(feature = "nrf52840")]
+const LL_FEATURES: u64 = 1 << 0 | 1 << 1 | 1 << 2 | 1 << 8 | 1 << 11 | 1 << 14;
+
+/// How long before the anchor point, widened for the drift of both clocks,
+/// the receiver is started, in usec
+const EARLY_LISTENING: u32 = 1000;
+/// Worst case accuracy of our clock, in ppm
+const CLOCK_ACCURACY_PPM: u32 = 50;
+/// Worst case accuracy of the central's sleep clock for each value of the
+/// SCA field of the CONNECT_IND, in ppm
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 2.3.3.1
+const SCA_PPM: [u32; 8] = [500, 250, 150, 100, 75, 50, 30, 20];
+/// How long the central has to answer a procedure, in usec
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 5.2
+const PROCEDURE_RESPONSE_TIMEOUT: u32 = 40_000_000;
+
+/// HCI error codes a connection ends with, see `ConnectionData::termination`
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 2, Part D], section 2
+pub const CONNECTION_TIMEOUT: u8 = 0x08;
+pub const LL_RESPONSE_TIMEOUT: u8 = 0x22;
+pub const INSTANT_PASSED: u8 = 0x28;
+
+/// How the data channel of each connection event is picked, set by the ChSel
+/// bits of the advertisement and of the CONNECT_IND
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ChannelSelection {
+    /// Algorithm #1, hopping by the hop increment
+    // BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 4.5.8.2
+    Algorithm1,
+    /// Algorithm #2, a pseudo random number derived from the connection
+    /// event counter and the channel identifier
+    // BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 4.5.8.3
+    Algorithm2,
+}
+
+/// The permutation of channel selection algorithm #2: the bits of each
+/// octet reversed
+fn csa2_perm(v: u16) -> u16 {
+    let mut perm = 0;
+    for bit in 0..8 {
+        perm |= (v >> bit & 0x0101) << (7 - bit);
+    }
+    perm
+}
+
+/// The multiply, add and modulo operation of channel selection algorithm #2
+fn csa2_mam(a: u16, b: u16) -> u16 {
+    a.wrapping_mul(17).wrapping_add(b)
+}
+
+/// The pseudo random number of channel selection algorithm #2 for
+/// connection event `counter`
+fn csa2_prn_e(counter: u16, channel_identifier: u16) -> u16 {
+    let mut prn = counter ^ channel_identifier;
+    for _ in 0..3 {
+        prn = csa2_mam(csa2_perm(prn), channel_identifier);
+    }
+    prn ^ channel_identifier
+}
+
+/// Counters and instants are compared modulo 65536, an instant this many
+/// events or more ahead of the counter having passed (Bluetooth Core
+/// Specification v5.0, Vol 6, Part B, section 5.5.1).
+const INSTANT_HALF_RANGE: u16 = 32767;
+
+/// Where connection event `counter` stands relative to an instant
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Instant {
+    /// The instant is less than `INSTANT_HALF_RANGE` events ahead
+    Pending,
+    /// `counter` is the instant
+    Reached,
+    /// The instant is `INSTANT_HALF_RANGE` events or more ahead, that is
+    /// at least one event behind
+    Passed,
+}
+
+/// Compare connection event `counter` with `instant`, taking the counter
+/// wrapping around into account. A procedure received in an event at or
+/// after its instant ends the connection with `INSTANT_PASSED` (Bluetooth
+/// Core Specification v5.0, Vol 6, Part B, section 5.5.1), the others take
+/// effect once their instant is reached.
+pub fn instant_status(counter: u16, instant: u16) -> Instant {
+    let ahead = instant.wrapping_sub(counter);
+    if ahead == 0 {
+        Instant::Reached
+    } else if ahead < INSTANT_HALF_RANGE {
+        Instant::Pending
+    } else {
+        Instant::Passed
+    }
+}
+
+#[derive(Copy, Clone)]
+struct DataPdu {
+    llid: u8,
+    len: u8,
+    payload: [u8; MAX_DATA_PAYLOAD],
+}
+
+impl DataPdu {
+    const fn empty() -> DataPdu {
+        DataPdu {
+            llid: 0x01,
+            len: 0,
+            payload: [0; MAX_DATA_PAYLOAD],
+        }
+    }
+}
+
+/// Progress of the encryption start procedure
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 5.1.3.1
+#[derive(Copy, Clone, PartialEq)]
+enum EncryptionState {
+    /// Data PDUs go in the clear
+    Off,
+    /// An LL_ENC_REQ was received, see `take_encryption_request`
+    Requested,
+    /// LL_ENC_RSP queued, waiting for the session key
+    KeyRequested,
+    /// LL_START_ENC_REQ queued, received PDUs are decrypted once it is sent
+    Starting,
+    /// Data PDUs are encrypted both ways
+    On,
+}
+
+/// The last data PDU sent, until the central acknowledges it
+#[derive(Copy, Clone, PartialEq)]
+enum InFlight {
+    Nothing,
+    /// An empty PDU
+    Empty,
+    /// The data PDU at the head of the queue
+    Data,
+}
+
 pub struct ConnectionData {
+    channel_selection: ChannelSelection,
+    /// The access address' upper and lower 16 bits XORed, for algorithm #2
+    channel_identifier: u16,
     last_unmapped_channel: u8,
     channels: ChannelMapBuffer,
     pub conn_event_counter: u16,
     hop_increment: u8,
     number_used_channels: u8,
     next_channel_map: Option<(ChannelMap, u16)>,
+    next_connection_update: Option<(ConnectionUpdate, u16)>,
+    next_phy_update: Option<(PhyUpdate, u16)>,
+    /// PHYs in use, toward and from the central
+    tx_phy: Phy,
+    rx_phy: Phy,
+    /// PHYs offered to the central in LL_PHY_REQ and LL_PHY_RSP, a bitmask
+    /// of `PHY_1M`, `PHY_2M` and `PHY_CODED`
+    preferred_phys: u8,
+    /// Coding to transmit with on the LE Coded PHY
+    coded_phy: Phy,
+    /// Time since the last connection event in which a packet was received
+    /// with a valid CRC, in usec
+    supervision_elapsed: u32,
+    /// A packet with a valid CRC was received in the current event
+    valid_packet_in_event: bool,
+    /// Delay of the next anchor point past the end of the connection
+    /// interval, the window offset of a connection update taking effect
+    anchor_offset: u32,
     pub aa: u32,
     pub crcinit: u32,
     pub transmit_seq_nbr: u8,
@@ -23,6 +199,35 @@ pub struct ConnectionData {
     pub conn_interval_start: Option<u32>,
     pub conn_interval_length_usec: Option<u32>,
     pub lldata: LLData,
+    /// Data PDUs to send, oldest first
+    tx_queue: [DataPdu; TX_QUEUE_LEN],
+    tx_head: usize,
+    tx_count: usize,
+    /// Sent and not acknowledged yet, retransmitted as is until it is
+    tx_in_flight: InFlight,
+    /// Data PDUs acknowledged since `take_acknowledged` was last called
+    tx_acknowledged: usize,
+    encryption_state: EncryptionState,
+    /// LL_ENC_REQ not answered yet
+    encryption_request: Option<EncryptionRequest>,
+    /// Keys and packet counters, once the session key is known
+    encryption: Option<Encryption>,
+    /// The PDU in flight was encrypted and is not empty, its acknowledgement
+    /// moves the transmit packet counter on
+    tx_in_flight_encrypted: bool,
+    /// A PDU was received with a valid CRC and a MIC that did not match
+    mic_failed: bool,
+    /// An LL_CONNECTION_PARAM_REQ was sent and the central has not answered
+    param_request_pending: bool,
+    /// Time since the pending LL_CONNECTION_PARAM_REQ was sent, in usec
+    param_request_elapsed: u32,
+    /// Time from the last anchor point heard to the next one, which the
+    /// receive window is widened for, in usec
+    anchor_elapsed: u32,
+    /// Why the connection is over, an HCI error code
+    termination: Option<u8>,
+    /// How the last connection parameter request ended, until taken
+    param_request_result: Option<ReturnCode>,
 }
 
 impl PartialEq for ConnectionData {
@@ -35,7 +240,8 @@ impl Eq for ConnectionData {}
 
 impl fmt::Debug for ConnectionData {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "ConnectionData {{ last_unmapped_channel: {}, conn_event_counter: {}, hop_increment: {}, number_used_channels: {}, aa: {}, crcinit {} }}",
+        write!(f, "ConnectionData {{ channel_selection: {:?}, last_unmapped_channel: {}, conn_event_counter: {}, hop_increment: {}, number_used_channels: {}, aa: {}, crcinit {} }}",
+            self.channel_selection,
             self.last_unmapped_channel,
             self.conn_event_counter,
             self.hop_increment,
@@ -49,16 +255,32 @@ impl fmt::Debug for ConnectionData {
 impl ConnectionData {
     pub fn new(lldata: LLData) -> ConnectionData {
         let (channels, number_used_channels) = ConnectionData::expand_channel_map(lldata.chm.0);
+        let aa = (lldata.aa[0] as u32) << 24 | (lldata.aa[1] as u32) << 16
+            | (lldata.aa[2] as u32) << 8 | (lldata.aa[3] as u32);
 
         ConnectionData {
+            channel_selection: if lldata.ch_sel {
+                ChannelSelection::Algorithm2
+            } else {
+                ChannelSelection::Algorithm1
+            },
+            channel_identifier: (aa >> 16) as u16 ^ aa as u16,
             last_unmapped_channel: 0,
             channels,
             number_used_channels,
             next_channel_map: None,
+            next_connection_update: None,
+            next_phy_update: None,
+            tx_phy: Phy::Le1M,
+            rx_phy: Phy::Le1M,
+            preferred_phys: ble_advertising_hil::PHY_1M,
+            coded_phy: Phy::LeCodedS8,
+            supervision_elapsed: 0,
+            valid_packet_in_event: false,
+            anchor_offset: 0,
             hop_increment: lldata.hop_and_sca & 0b11111,
             conn_event_counter: 0,
-            aa: (lldata.aa[0] as u32) << 24 | (lldata.aa[1] as u32) << 16
-                | (lldata.aa[2] as u32) << 8 | (lldata.aa[3] as u32),
+            aa,
             crcinit: (lldata.crc_init[0] as u32) << 16 | (lldata.crc_init[1] as u32) << 8
                 | (lldata.crc_init[2] as u32),
             transmit_seq_nbr: 0,
@@ -66,15 +288,247 @@ impl ConnectionData {
             conn_interval_start: None,
             conn_interval_length_usec: None,
             lldata,
+            tx_queue: [DataPdu::empty(); TX_QUEUE_LEN],
+            tx_head: 0,
+            tx_count: 0,
+            tx_in_flight: InFlight::Nothing,
+            tx_acknowledged: 0,
+            encryption_state: EncryptionState::Off,
+            encryption_request: None,
+            encryption: None,
+            tx_in_flight_encrypted: false,
+            mic_failed: false,
+            param_request_pending: false,
+            param_request_elapsed: 0,
+            anchor_elapsed: 0,
+            termination: None,
+            param_request_result: None,
         }
     }
 
+    /// Number of data channels in the channel map in use
+    pub fn number_used_channels(&self) -> u8 {
+        self.number_used_channels
+    }
+
+    pub fn hop_increment(&self) -> u8 {
+        self.hop_increment
+    }
+
+    /// The channel selection algorithm of the connection
+    pub fn channel_selection(&self) -> ChannelSelection {
+        self.channel_selection
+    }
+
+    /// PHYs to transmit and receive with in the next connection event
+    pub fn phys(&self) -> (Phy, Phy) {
+        (self.tx_phy, self.rx_phy)
+    }
+
+    /// Move on to the next connection event. A connection update whose
+    /// instant it is takes effect: the next anchor point is pushed back by
+    /// the update's window offset, and from then on the new interval and
+    /// window size are used. So does a PHY update, the next event using the
+    /// new PHYs.
     pub fn increment_conn_event(&mut self) {
         self.conn_event_counter = self.conn_event_counter.wrapping_add(1);
+
+        if let Some((update, instant)) = self.next_connection_update.take() {
+            if self.instant_reached(instant) {
+                self.lldata.apply_connection_update(&update);
+                self.anchor_offset = self.lldata.window_offset();
+            } else {
+                self.next_connection_update = Some((update, instant));
+            }
+        }
+
+        if let Some((update, instant)) = self.next_phy_update.take() {
+            if self.instant_reached(instant) {
+                // A coded receiver decodes both codings, the one the central
+                // picked only shows in its packets
+                if let Some(phy) = Phy::from_bit(update.m_to_s, Phy::LeCodedS8) {
+                    self.rx_phy = phy;
+                }
+                if let Some(phy) = Phy::from_bit(update.s_to_m, self.coded_phy) {
+                    self.tx_phy = phy;
+                }
+            } else {
+                self.next_phy_update = Some((update, instant));
+            }
+        }
+    }
+
+    /// Move on to the next connection event after one in which nothing was
+    /// received. Returns the delay from the start of the receive window of
+    /// the missed event to that of the next one, which opens earlier
+    /// relative to its anchor point as the clocks drift further apart.
+    pub fn skip_conn_event(&mut self) -> u32 {
+        let interval = self.lldata.connection_interval();
+        self.end_supervision_interval(interval);
+        self.increment_conn_event();
+        let delay = interval + self.take_anchor_offset();
+        let widening = self.window_widening(self.anchor_elapsed);
+        self.anchor_elapsed = self.anchor_elapsed.saturating_add(delay);
+        delay - (self.window_widening(self.anchor_elapsed) - widening)
+    }
+
+    /// How long to listen for the central in the next connection event,
+    /// from the start of the receive window: the transmit window, widened
+    /// on both sides for the drift of the clocks
+    pub fn receive_window(&self) -> u32 {
+        self.lldata.window_size() + 2 * self.window_widening(self.anchor_elapsed)
+    }
+
+    // How much earlier or later than its anchor point the central may send,
+    // `elapsed` after the last anchor point heard, the clocks of both sides
+    // drifting at worst case
+    // BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 4.5.7
+    fn window_widening(&self, elapsed: u32) -> u32 {
+        let drift_ppm = SCA_PPM[(self.lldata.hop_and_sca >> 5) as usize] + CLOCK_ACCURACY_PPM;
+        ((elapsed as u64 * drift_ppm as u64 + 999_999) / 1_000_000) as u32
+    }
+
+    // Supervision and procedure timers bookkeeping at the end of a
+    // connection event lasting `interval`
+    fn end_supervision_interval(&mut self, interval: u32) {
+        if self.valid_packet_in_event {
+            self.supervision_elapsed = 0;
+        } else {
+            self.supervision_elapsed = self.supervision_elapsed.saturating_add(interval);
+        }
+        self.valid_packet_in_event = false;
+
+        if self.param_request_pending {
+            self.param_request_elapsed = self.param_request_elapsed.saturating_add(interval);
+            if self.param_request_elapsed >= PROCEDURE_RESPONSE_TIMEOUT {
+                self.param_request_pending = false;
+                self.terminate(LL_RESPONSE_TIMEOUT);
+            }
+        }
+    }
+
+    // End the connection for `reason`, unless it already is
+    fn terminate(&mut self, reason: u8) {
+        if self.termination.is_none() {
+            self.termination = Some(reason);
+        }
+    }
+
+    /// Why the connection is over, an HCI error code, or `None` while it
+    /// goes on: the supervision timeout expired or the central did not
+    /// answer a procedure in time
+    pub fn termination(&self) -> Option<u8> {
+        if self.supervision_timed_out() {
+            Some(CONNECTION_TIMEOUT)
+        } else {
+            self.termination
+        }
+    }
+
+    /// Whether nothing was received from the central for the supervision
+    /// timeout, in which case the connection is considered lost
+    pub fn supervision_timed_out(&self) -> bool {
+        self.supervision_elapsed >= self.calculate_conn_supervision_timeout()
+    }
+
+    fn take_anchor_offset(&mut self) -> u32 {
+        let anchor_offset = self.anchor_offset;
+        self.anchor_offset = 0;
+        anchor_offset
     }
 
     pub fn update_channelmap(&mut self, channel_map: ChannelMap, instant: u16) {
-        self.next_channel_map = Some((channel_map, instant));
+        if self.instant_received(instant) {
+            self.next_channel_map = Some((channel_map, instant));
+        }
+    }
+
+    pub fn update_connection(&mut self, update: ConnectionUpdate, instant: u16) {
+        if self.instant_received(instant) {
+            self.next_connection_update = Some((update, instant));
+        }
+    }
+
+    /// Whether a procedure received in the current connection event with
+    /// `instant` can still take effect. If not, the connection is over, see
+    /// `termination`.
+    fn instant_received(&mut self, instant: u16) -> bool {
+        match instant_status(self.conn_event_counter, instant) {
+            Instant::Pending => true,
+            Instant::Reached | Instant::Passed => {
+                self.terminate(INSTANT_PASSED);
+                false
+            }
+        }
+    }
+
+    /// Whether a pending procedure with `instant` takes effect in the
+    /// current connection event. An instant only accepted while it was
+    /// ahead is never behind by more than the events skipped at once.
+    fn instant_reached(&self, instant: u16) -> bool {
+        instant_status(self.conn_event_counter, instant) != Instant::Pending
+    }
+
+    /// Ask the central to move the connection to one of the PHYs in
+    /// `phys`, a bitmask of `PHY_1M`, `PHY_2M` and `PHY_CODED`, by sending
+    /// an LL_PHY_REQ. `coded` is the coding to transmit with should the
+    /// central pick the LE Coded PHY. The central answers with an
+    /// LL_PHY_UPDATE_IND, its choice taking effect at the instant therein.
+    pub fn request_phy(&mut self, phys: u8, coded: Phy) -> ReturnCode {
+        self.preferred_phys = phys;
+        self.coded_phy = coded;
+        self.send(0x03, &[0x16, phys, phys])
+    }
+
+    /// Ask the central for new connection parameters with an
+    /// LL_CONNECTION_PARAM_REQ, in the units of `LLData`: typically a longer
+    /// interval or a higher slave latency to save power. The central answers
+    /// with an LL_CONNECTION_UPDATE_IND, applied at its instant, or turns the
+    /// request down, see `take_param_request_result`. Returns EINVAL for
+    /// parameters out of range and EBUSY while a request is pending.
+    pub fn request_connection_parameters(
+        &mut self,
+        interval_min: u16,
+        interval_max: u16,
+        latency: u16,
+        timeout: u16,
+    ) -> ReturnCode {
+        let parameters = ConnectionParameters::new(
+            interval_min,
+            interval_max,
+            latency,
+            timeout,
+            self.conn_event_counter,
+        );
+        if !parameters.is_valid() {
+            return ReturnCode::EINVAL;
+        }
+        if self.param_request_pending {
+            return ReturnCode::EBUSY;
+        }
+        let mut request = [0; 24];
+        request[0] = 0x0F;
+        parameters.write_to_buffer(&mut request[1..]);
+        let result = self.send(0x03, &request);
+        if result == ReturnCode::SUCCESS {
+            self.param_request_pending = true;
+            self.param_request_elapsed = 0;
+        }
+        result
+    }
+
+    /// How the last connection parameter request ended, once the central
+    /// answered: SUCCESS if it scheduled a connection update, ENOSUPPORT if
+    /// it does not know the procedure and FAIL if it rejected the request
+    pub fn take_param_request_result(&mut self) -> Option<ReturnCode> {
+        self.param_request_result.take()
+    }
+
+    fn end_param_request(&mut self, result: ReturnCode) {
+        if self.param_request_pending {
+            self.param_request_pending = false;
+            self.param_request_result = Some(result);
+        }
     }
 
     fn expand_channel_map(chm: [u8; 5]) -> (ChannelMapBuffer, u8) {
@@ -100,10 +554,15 @@ impl ConnectionData {
         (channels, number_used_channels)
     }
 
+    /// The radio context of the connection on `channel`
+    pub fn radio_context(&self, channel: RadioChannel) -> RadioContext {
+        RadioContext::connection(self.aa, self.crcinit, channel)
+    }
+
     pub fn next_channel(&mut self) -> RadioChannel {
         if let Some((channel_map, instant)) = self.next_channel_map.take() {
-            if instant == self.conn_event_counter {
-                debug_gpio!(1, clear);
+            if self.instant_reached(instant) {
+                trace::record(Event::ChannelMapApplied);
                 let (channels, number_used_channels) = ConnectionData::expand_channel_map(channel_map.0);
                 self.channels = channels;
                 self.number_used_channels = number_used_channels;
@@ -112,17 +571,26 @@ impl ConnectionData {
             }
         }
 
-        let unmapped_channel =
-            (self.last_unmapped_channel + self.hop_increment) % (NUMBER_DATA_CHANNELS as u8);
+        let (unmapped_channel, remapping_index) = match self.channel_selection {
+            ChannelSelection::Algorithm1 => {
+                let unmapped_channel = (self.last_unmapped_channel + self.hop_increment)
+                    % (NUMBER_DATA_CHANNELS as u8);
+                self.last_unmapped_channel = unmapped_channel;
+                (unmapped_channel, unmapped_channel % self.number_used_channels)
+            }
+            ChannelSelection::Algorithm2 => {
+                let prn_e = csa2_prn_e(self.conn_event_counter, self.channel_identifier);
+                let unmapped_channel = (prn_e % NUMBER_DATA_CHANNELS as u16) as u8;
+                let remapping_index = (self.number_used_channels as u32 * prn_e as u32) >> 16;
+                (unmapped_channel, remapping_index as u8)
+            }
+        };
         let used = self.channels[unmapped_channel as usize] == 1;
 
-        self.last_unmapped_channel = unmapped_channel;
-
         let channel = if used {
             unmapped_channel
         } else {
             let mut table: ChannelMapBuffer = [0; NUMBER_CHANNELS];
-            let remapping_index = unmapped_channel % self.number_used_channels;
 
             let mut idx = 0;
 
@@ -141,7 +609,12 @@ impl ConnectionData {
         channel.try_into().unwrap()
     }
 
-    pub fn next_sequence_number(&mut self, buf_head_flags: u8) -> (u8, u8, bool) {
+    /// Update the sequence numbers for a data PDU received with a valid CRC.
+    /// A new PDU is acknowledged with our next NESN, and an acknowledgement
+    /// of our last PDU lets the next one be sent. Returns whether the PDU
+    /// carries new data, as opposed to being a retransmission of data
+    /// already received.
+    pub fn next_sequence_number(&mut self, buf_head_flags: u8) -> bool {
         let DataHeader { sequence_number: sn, next_expected_sequence_number: nesn, .. } = ConnectionData::get_data_pdu_header(buf_head_flags);
 
         //Does the packet carry the sequence number that I expected?
@@ -156,13 +629,390 @@ impl ConnectionData {
         let resend_last_data_pdu: bool = nesn == self.transmit_seq_nbr;
         if !resend_last_data_pdu {
             self.transmit_seq_nbr = (self.transmit_seq_nbr + 1) % 2; //flip the bit
+
+            if self.tx_in_flight == InFlight::Data {
+                if self.tx_queue[self.tx_head].llid != 0x03 {
+                    self.tx_acknowledged += 1;
+                }
+                self.tx_head = (self.tx_head + 1) % TX_QUEUE_LEN;
+                self.tx_count -= 1;
+            }
+            if self.tx_in_flight_encrypted {
+                self.encryption.as_mut().map(|encryption| encryption.tx_counter += 1);
+            }
+            self.tx_in_flight = InFlight::Nothing;
+            self.tx_in_flight_encrypted = false;
         }
 
-        (
-            self.transmit_seq_nbr,
-            self.next_seq_nbr,
-            resend_last_data_pdu,
-        )
+        received_new_data_pdu
+    }
+
+    /// Act on an LL Control PDU. `buf` holds the whole PDU, header included.
+    pub fn handle_control_pdu(&mut self, buf: &[u8]) {
+        match LLControlPdu::from_buffer(buf) {
+            Some(LLControlPdu::ConnectionUpdate(update, instant)) => {
+                // Also the central's answer to our LL_CONNECTION_PARAM_REQ
+                self.end_param_request(ReturnCode::SUCCESS);
+                self.update_connection(update, instant);
+            }
+            Some(LLControlPdu::ChannelMap(channel_map, instant)) => {
+                self.update_channelmap(channel_map, instant);
+                trace::record(Event::ChannelMapReceived);
+            }
+            Some(LLControlPdu::EncryptionRequest(request)) => {
+                if self.encryption_state == EncryptionState::Off {
+                    self.encryption_request = Some(request);
+                    self.encryption_state = EncryptionState::Requested;
+                }
+            }
+            Some(LLControlPdu::StartEncryptionResponse) => {
+                // The central's LL_START_ENC_RSP, answered with ours, the
+                // first PDU sent encrypted
+                if self.encryption_state == EncryptionState::Starting {
+                    self.encryption.as_mut().map(|encryption| encryption.tx = true);
+                    self.encryption_state = EncryptionState::On;
+                    self.send(0x03, &[0x06]);
+                }
+            }
+            Some(LLControlPdu::FeatureRequest(features)) => {
+                // LL_FEATURE_RSP, the first octet is what both sides support
+                let mut response = [0; 9];
+                response[0] = 0x09;
+                for i in 0..8 {
+                    response[i + 1] = (LL_FEATURES >> (8 * i)) as u8;
+                }
+                response[1] &= features[0];
+                self.send(0x03, &response);
+            }
+            Some(LLControlPdu::PhyRequest(_, _)) => {
+                // LL_PHY_RSP, the central picks from both sides' preferences
+                let phys = self.preferred_phys;
+                self.send(0x03, &[0x17, phys, phys]);
+            }
+            Some(LLControlPdu::ConnectionParamRequest(parameters)) => {
+                // Parameters in range are accepted as proposed, the central
+                // following up with an LL_CONNECTION_UPDATE_IND
+                if parameters.is_valid() {
+                    let mut response = [0; 24];
+                    response[0] = 0x10;
+                    parameters.write_to_buffer(&mut response[1..]);
+                    self.send(0x03, &response);
+                } else {
+                    // LL_REJECT_EXT_IND, Invalid LL Parameters
+                    self.send(0x03, &[0x11, 0x0F, 0x1E]);
+                }
+            }
+            Some(LLControlPdu::UnknownResponse(0x0F)) => {
+                self.end_param_request(ReturnCode::ENOSUPPORT);
+            }
+            Some(LLControlPdu::RejectExtended(0x0F, _)) => {
+                self.end_param_request(ReturnCode::FAIL);
+            }
+            Some(LLControlPdu::UnknownResponse(_)) | Some(LLControlPdu::RejectExtended(_, _)) => {}
+            Some(LLControlPdu::PhyUpdate(update, instant)) => {
+                // Both fields zero: the PHYs stay as they are, no instant
+                if (update.m_to_s != 0 || update.s_to_m != 0) && self.instant_received(instant) {
+                    self.next_phy_update = Some((update, instant));
+                }
+            }
+            None => {
+                // Ignore other LL Control Opcodes
+            }
+        }
+    }
+
+    /// The LL_ENC_REQ received from the central, if it has not been
+    /// answered yet. It must be answered with `respond_encryption_request`.
+    pub fn take_encryption_request(&mut self) -> Option<EncryptionRequest> {
+        self.encryption_request.take()
+    }
+
+    /// Answer the LL_ENC_REQ with an LL_ENC_RSP holding our random parts of
+    /// the session key diversifier and of the IV. Returns the SKD, most
+    /// significant octet first, from which the session key is derived with
+    /// the long term key, or `None` if no LL_ENC_REQ waits for an answer.
+    pub fn respond_encryption_request(
+        &mut self,
+        request: &EncryptionRequest,
+        skds: [u8; 8],
+        ivs: [u8; 4],
+    ) -> Option<[u8; 16]> {
+        if self.encryption_state != EncryptionState::Requested {
+            return None;
+        }
+        let mut response = [0; 13];
+        response[0] = 0x04;
+        response[1..9].copy_from_slice(&skds);
+        response[9..13].copy_from_slice(&ivs);
+        self.send(0x03, &response);
+
+        let mut iv = [0; 8];
+        iv[..4].copy_from_slice(&request.ivm);
+        iv[4..].copy_from_slice(&ivs);
+        self.encryption = Some(Encryption {
+            session_key: [0; 16],
+            iv: iv,
+            tx_counter: 0,
+            rx_counter: 0,
+            tx: false,
+            rx: false,
+        });
+        self.encryption_state = EncryptionState::KeyRequested;
+
+        // SKD = SKDm || SKDs, SKDs being the most significant half
+        let mut skd = [0; 16];
+        for i in 0..8 {
+            skd[i] = skds[7 - i];
+            skd[8 + i] = request.skdm[7 - i];
+        }
+        Some(skd)
+    }
+
+    /// Start encrypting with `session_key`, most significant octet first,
+    /// by sending an LL_START_ENC_REQ
+    pub fn start_encryption(&mut self, session_key: [u8; 16]) {
+        if self.encryption_state != EncryptionState::KeyRequested {
+            return;
+        }
+        self.encryption
+            .as_mut()
+            .map(|encryption| encryption.session_key = session_key);
+        self.encryption_state = EncryptionState::Starting;
+        self.send(0x03, &[0x05]);
+    }
+
+    /// Turn the encryption down because the host has no long term key for
+    /// the central, with an LL_REJECT_IND (PIN or Key Missing)
+    pub fn reject_encryption(&mut self) {
+        if self.encryption_state != EncryptionState::KeyRequested {
+            return;
+        }
+        self.encryption = None;
+        self.encryption_state = EncryptionState::Off;
+        self.send(0x03, &[0x0D, 0x06]);
+    }
+
+    /// Keys and packet counters the radio should use for the next packets,
+    /// `None` while the connection is in the clear
+    pub fn encryption(&self) -> Option<Encryption> {
+        match self.encryption_state {
+            EncryptionState::Starting | EncryptionState::On => self.encryption,
+            _ => None,
+        }
+    }
+
+    /// Whether a PDU was received whose MIC did not match, which ends the
+    /// connection
+    pub fn mic_failed(&self) -> bool {
+        self.mic_failed
+    }
+
+    /// Queue a data PDU to send to the central, `llid` being 0x01 or 0x02
+    /// for L2CAP data or 0x03 for an LL Control PDU. The payload is copied,
+    /// it goes out in response to the central's next packet and is
+    /// retransmitted until acknowledged.
+    pub fn send(&mut self, llid: u8, payload: &[u8]) -> ReturnCode {
+        if llid == 0 || llid > 0x03 {
+            return ReturnCode::EINVAL;
+        }
+        if payload.len() > MAX_DATA_PAYLOAD {
+            return ReturnCode::ESIZE;
+        }
+        let slots = if llid == 0x03 {
+            TX_QUEUE_LEN
+        } else {
+            TX_QUEUE_LEN - TX_QUEUE_CONTROL_SLOTS
+        };
+        if self.tx_count >= slots {
+            return ReturnCode::ENOMEM;
+        }
+
+        let pdu = &mut self.tx_queue[(self.tx_head + self.tx_count) % TX_QUEUE_LEN];
+        pdu.llid = llid;
+        pdu.len = payload.len() as u8;
+        pdu.payload[..payload.len()].copy_from_slice(payload);
+        self.tx_count += 1;
+        ReturnCode::SUCCESS
+    }
+
+    /// Number of queued L2CAP data PDUs acknowledged by the central since
+    /// the last call
+    pub fn take_acknowledged(&mut self) -> usize {
+        let acknowledged = self.tx_acknowledged;
+        self.tx_acknowledged = 0;
+        acknowledged
+    }
+
+    /// Drop the data PDUs waiting to be sent or acknowledged, once the
+    /// connection is over. Returns the number of L2CAP data PDUs dropped.
+    pub fn take_unsent(&mut self) -> usize {
+        let unsent = (0..self.tx_count)
+            .filter(|i| self.tx_queue[(self.tx_head + i) % TX_QUEUE_LEN].llid != 0x03)
+            .count();
+        self.tx_count = 0;
+        self.tx_in_flight = InFlight::Nothing;
+        unsent
+    }
+
+    /// The PDU to send next: the one not acknowledged yet if there is one,
+    /// otherwise the oldest queued data PDU, or an empty PDU if there is none.
+    fn next_to_send(&self) -> InFlight {
+        match self.tx_in_flight {
+            InFlight::Nothing if self.tx_count > 0 => InFlight::Data,
+            InFlight::Nothing => InFlight::Empty,
+            in_flight => in_flight,
+        }
+    }
+
+    /// Whether more data than the next PDU is waiting to be sent, the MD bit
+    /// of the next PDU
+    fn more_data_to_send(&self) -> bool {
+        match self.next_to_send() {
+            InFlight::Data => self.tx_count > 1,
+            _ => self.tx_count > 0,
+        }
+    }
+
+    /// Write the response to the packet just received into `buf`. The same
+    /// PDU, with the same SN, is sent until the central acknowledges it, so
+    /// an empty PDU is retransmitted as such even if data was queued since.
+    pub fn prepare_response(&mut self, buf: &mut [u8]) {
+        let header = (self.next_seq_nbr & 0b1) << 2 | (self.transmit_seq_nbr & 0b1) << 3
+            | (self.more_data_to_send() as u8) << 4;
+
+        let next = self.next_to_send();
+        let tx_encrypted = self.encryption().map_or(false, |encryption| encryption.tx);
+        if next == InFlight::Data {
+            let pdu = &self.tx_queue[self.tx_head];
+            let len = pdu.len as usize;
+            buf[0] = header | pdu.llid;
+            buf[1] = pdu.len;
+            buf[2..2 + len].copy_from_slice(&pdu.payload[..len]);
+            self.tx_in_flight_encrypted = tx_encrypted && len > 0;
+
+            // The central encrypts its PDUs from the one answering our
+            // LL_START_ENC_REQ on
+            if self.encryption_state == EncryptionState::Starting && pdu.llid == 0x03
+                && len > 0 && pdu.payload[0] == 0x05
+            {
+                self.encryption.as_mut().map(|encryption| encryption.rx = true);
+            }
+        } else {
+            // LLID == 0x01 Empty PDU
+            buf[0] = header | 0x01;
+            buf[1] = 0;
+        }
+        self.tx_in_flight = next;
+    }
+
+    /// Bookkeeping for a data PDU received in a connection event: sequence
+    /// numbers, LL Control procedures and whether the event is over. `buf`
+    /// holds the whole PDU, header included. Returns the start of the
+    /// receive window of the next connection event if this one ended, see
+    /// `receive_window` for its length.
+    ///
+    /// The event goes on as long as either side has more data, and at the
+    /// latest until the next anchor point.
+    ///
+    /// The header of a PDU with a bad CRC cannot be trusted, so it changes
+    /// neither sequence number: the response NAKs it and retransmits our
+    /// last PDU.
+    pub fn receive_data_pdu(&mut self, pdu: &ReceivedPdu) -> Option<u32> {
+        let buf: &[u8] = pdu.buf;
+        let crc_ok = pdu.crc_ok;
+        let DataHeader { more_data, llid, .. } = ConnectionData::get_data_pdu_header(buf[0]);
+        let more_data = crc_ok && more_data;
+
+        if crc_ok && !pdu.mic_ok {
+            // BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section
+            // 5.1.3.1: the connection is over, the PDU is not acknowledged
+            self.mic_failed = true;
+        } else if crc_ok {
+            self.valid_packet_in_event = true;
+
+            // Only read the data in the pkt if it is new, a retransmission
+            // was already acted on
+            let new_data = self.next_sequence_number(buf[0]);
+            if new_data && buf[1] > 0 {
+                if let Some(ref mut encryption) = self.encryption {
+                    if encryption.rx {
+                        encryption.rx_counter += 1;
+                    }
+                }
+            }
+            if new_data && llid == 0x03 {
+                // 0x03 == Control PDU
+                self.handle_control_pdu(buf);
+            }
+        }
+
+        let (interval_ended, interval_end_time) = self.connection_interval_ended(pdu.timestamp);
+
+        // If either side has more data, stay on the channel and listen
+        // Otherwise skip to next channel even if current interval has time left
+        let skip_to_next_channel = interval_ended || !(more_data || self.more_data_to_send());
+
+        // The event that ended lasted the interval from before any
+        // connection update taking effect in the next one
+        let ended_interval = self.lldata.connection_interval();
+        if skip_to_next_channel {
+            self.end_supervision_interval(ended_interval);
+            self.conn_interval_start = None;
+            self.increment_conn_event();
+        }
+
+        match interval_end_time {
+            Some(interval_end_time) if skip_to_next_channel => {
+                let skipped = if crc_ok && pdu.mic_ok && !more_data {
+                    self.apply_slave_latency()
+                } else {
+                    0
+                };
+                let interval = self.lldata.connection_interval();
+                let delay = skipped * interval + self.take_anchor_offset();
+                self.anchor_elapsed = ended_interval + delay;
+                Some(interval_end_time + delay - self.window_widening(self.anchor_elapsed))
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the slave may sleep through the next connection event: not
+    /// while a PDU waits to be sent or acknowledged, as the central only
+    /// hears it in an event the slave listens to, nor while a procedure is
+    /// under way or an update waits for its instant, which the slave must
+    /// listen at
+    // BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 4.5.1
+    fn may_skip_event(&self) -> bool {
+        let idle = self.tx_count == 0 && self.tx_in_flight == InFlight::Nothing;
+        let procedure = self.param_request_pending || self.encryption_request.is_some()
+            || self.encryption_state == EncryptionState::KeyRequested
+            || self.encryption_state == EncryptionState::Starting;
+        let update_pending = self.next_connection_update.is_some()
+            || self.next_channel_map.is_some() || self.next_phy_update.is_some();
+        idle && !procedure && !update_pending
+    }
+
+    // Skip up to `connSlaveLatency` connection events after the one that
+    // just ended, moving the event counter, the channel hopping and the
+    // supervision timer past them as if nothing had been received. The
+    // receive window is widened for the time slept, which must stay under
+    // half the interval less T_IFS. Returns the number of events skipped.
+    // BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 4.5.7
+    fn apply_slave_latency(&mut self) -> u32 {
+        let interval = self.lldata.connection_interval();
+        let max_widening = (interval / 2).saturating_sub(BLE_T_IFS);
+
+        let mut skipped = 0;
+        while skipped < self.lldata.latency as u32 && self.may_skip_event()
+            && self.window_widening((skipped + 2).saturating_mul(interval)) < max_widening
+        {
+            self.end_supervision_interval(interval);
+            self.next_channel();
+            self.increment_conn_event();
+            skipped += 1;
+        }
+        skipped
     }
 
     pub fn get_data_pdu_header(buf_head_flags: u8) -> DataHeader {
@@ -183,8 +1033,7 @@ impl ConnectionData {
     pub fn connection_interval_ended(&mut self, rx_timestamp: u32) -> (bool, Option<u32>) {
         //TODO - Perhaps add jitter in the comparison?
 
-        //-1000 usec for earlier listening
-        let interval = self.lldata.connection_interval() - 1000;
+        let interval = self.lldata.connection_interval() - EARLY_LISTENING;
 
         match self.conn_interval_start {
             Some(start_time) => {
@@ -199,8 +1048,10 @@ impl ConnectionData {
         }
     }
 
+    /// Supervision timeout in usec, `connSupervisionTimeout` is in units of
+    /// 10 ms
     pub fn calculate_conn_supervision_timeout(&self) -> u32 {
-        ((self.lldata.timeout as u32) * 1000 * 5 / 4) * 10
+        (self.lldata.timeout as u32) * 10 * 1000
     }
 }
 
@@ -1,13 +1,12 @@
 use ble::ble_advertising_driver::{App, AppBLEState};
 use ble::ble_advertising_hil::ActionAfterTimerExpire;
-use ble::ble_advertising_hil::{RadioChannel, ReadAction, ResponseAction, TxImmediate};
+use ble::ble_advertising_hil::{self, RadioContext, ReadAction, ResponseAction, TxImmediate};
 use ble::ble_connection_driver::ConnectionData;
-use ble::ble_pdu_parser::PACKET_ADDR_START;
+use ble::ble_pdu_parser::{CH_SEL, PACKET_ADDR_START, PACKET_HDR_PDU};
 use ble::ble_pdu_parser::{BLEAdvertisementType, BLEPduType};
 use core::fmt;
-use nrf5x::constants;
 
-pub type TxNextChannelType = (TxImmediate, Option<(RadioChannel, u32, u32)>);
+pub type TxNextChannelType = (TxImmediate, Option<RadioContext>);
 
 pub struct LinkLayer;
 
@@ -19,8 +18,16 @@ impl LinkLayer {
     ) -> ReadAction {
         match app.process_status {
             Some(AppBLEState::Advertising) => match pdu_type {
-                Some(BLEAdvertisementType::ScanRequest) => ReadAction::ReadFrame,
-                Some(BLEAdvertisementType::ConnectRequest) => ReadAction::ReadFrame,
+                Some(BLEAdvertisementType::ScanRequest)
+                    if app.advertisement_type().is_scannable() =>
+                {
+                    ReadAction::ReadFrameAndMoveToTX
+                }
+                Some(BLEAdvertisementType::ConnectRequest)
+                    if app.advertisement_type().is_connectable() =>
+                {
+                    ReadAction::ReadFrame
+                }
                 _ => ReadAction::SkipFrame,
             },
             Some(AppBLEState::Connection(_)) => ReadAction::ReadFrame,
@@ -39,9 +46,19 @@ impl LinkLayer {
                     None
                 }
             }
-            BLEPduType::ConnectRequest(_init_addr, adv_addr, lldata) => {
-                if app.is_my_address(&adv_addr) {
-                    Some(ResponseAction::Connection(ConnectionData::new(lldata)))
+            BLEPduType::ConnectRequest(init_addr, adv_addr, lldata) => {
+                // Directed advertising only accepts the initiator addressed
+                let initiator_allowed = app.advertisement_type()
+                    != BLEAdvertisementType::ConnectDirected
+                    || app.is_direct_address(&init_addr);
+                if !app.is_my_address(&adv_addr) || !initiator_allowed {
+                    return None;
+                }
+                // A CONNECT_IND with an access address the specification
+                // rules out is ignored
+                let conndata = ConnectionData::new(lldata);
+                if ble_advertising_hil::is_valid_data_access_address(conndata.aa) {
+                    Some(ResponseAction::Connection(conndata))
                 } else {
                     None
                 }
@@ -60,11 +77,7 @@ impl LinkLayer {
                     if let Some(next_channel) = channel.get_next_advertising_channel() {
                         (
                             TxImmediate::TX,
-                            Some((
-                                next_channel,
-                                constants::ADV_ACCESS_ADDRESS_BLE,
-                                constants::RADIO_CRCINIT_BLE,
-                            )),
+                            Some(RadioContext::advertising(next_channel)),
                         )
                     } else {
                         (TxImmediate::GoToSleep, None)
@@ -77,7 +90,7 @@ impl LinkLayer {
                 let channel = conn_data.next_channel();
                 (
                     TxImmediate::RespondAfterTifs,
-                    Some((channel, conn_data.aa, conn_data.crcinit)),
+                    Some(conn_data.radio_context(channel)),
                 )
             }
             _ => (TxImmediate::GoToSleep, None),
@@ -87,11 +100,16 @@ impl LinkLayer {
     pub fn handle_timer_expire(&self, app: &mut App) -> ActionAfterTimerExpire {
         match app.process_status {
             Some(AppBLEState::Advertising) => ActionAfterTimerExpire::ContinueAdvertising,
-            Some(AppBLEState::Connection(ref conndata)) => {
-                ActionAfterTimerExpire::ContinueConnection(
-                    conndata.lldata.connection_interval(),
-                    conndata.lldata.window_size(),
-                )
+            Some(AppBLEState::Scanning) => ActionAfterTimerExpire::ContinueScanning,
+            Some(AppBLEState::Connection(ref mut conndata)) => {
+                let delay = conndata.skip_conn_event();
+                match conndata.termination() {
+                    Some(reason) => ActionAfterTimerExpire::EndConnection(reason),
+                    None => ActionAfterTimerExpire::ContinueConnection(
+                        delay,
+                        conndata.receive_window(),
+                    ),
+                }
             }
             _ => {
                 panic!("Timer expired but app has no state\n");
@@ -114,6 +132,143 @@ impl ChannelMap {
     }
 }
 
+/// Parameters of an LL_CONNECTION_UPDATE_IND, in the units of `LLData`
+pub struct ConnectionUpdate {
+    win_size: u8,
+    win_offset: u16,
+    interval: u16,
+    latency: u16,
+    timeout: u16,
+}
+
+impl ConnectionUpdate {
+    /// `buffer` starts with the CtrData of the PDU
+    pub fn read_from_buffer(buffer: &[u8]) -> ConnectionUpdate {
+        ConnectionUpdate {
+            win_size: buffer[0],
+            win_offset: (buffer[2] as u16) << 8 | buffer[1] as u16,
+            interval: (buffer[4] as u16) << 8 | buffer[3] as u16,
+            latency: (buffer[6] as u16) << 8 | buffer[5] as u16,
+            timeout: (buffer[8] as u16) << 8 | buffer[7] as u16,
+        }
+    }
+}
+
+/// Parameters of an LL_CONNECTION_PARAM_REQ or LL_CONNECTION_PARAM_RSP, in
+/// the units of `LLData`
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 2.4.2.16
+#[derive(Copy, Clone)]
+pub struct ConnectionParameters {
+    pub interval_min: u16,
+    pub interval_max: u16,
+    pub latency: u16,
+    pub timeout: u16,
+    /// PreferredPeriodicity, ReferenceConnEventCount and Offset0 to Offset5,
+    /// as sent
+    anchors: [u8; 15],
+}
+
+impl ConnectionParameters {
+    /// Parameters to propose, with no preference on the anchor points
+    pub fn new(
+        interval_min: u16,
+        interval_max: u16,
+        latency: u16,
+        timeout: u16,
+        conn_event_counter: u16,
+    ) -> ConnectionParameters {
+        let mut anchors = [0xff; 15];
+        anchors[0] = 0;
+        anchors[1] = conn_event_counter as u8;
+        anchors[2] = (conn_event_counter >> 8) as u8;
+        ConnectionParameters {
+            interval_min,
+            interval_max,
+            latency,
+            timeout,
+            anchors,
+        }
+    }
+
+    /// `buffer` starts with the CtrData of the PDU
+    pub fn read_from_buffer(buffer: &[u8]) -> ConnectionParameters {
+        let mut anchors = [0; 15];
+        anchors.copy_from_slice(&buffer[8..23]);
+        ConnectionParameters {
+            interval_min: (buffer[1] as u16) << 8 | buffer[0] as u16,
+            interval_max: (buffer[3] as u16) << 8 | buffer[2] as u16,
+            latency: (buffer[5] as u16) << 8 | buffer[4] as u16,
+            timeout: (buffer[7] as u16) << 8 | buffer[6] as u16,
+            anchors,
+        }
+    }
+
+    /// Write the 23 octets of CtrData to `buffer`
+    pub fn write_to_buffer(&self, buffer: &mut [u8]) {
+        let fields = [self.interval_min, self.interval_max, self.latency, self.timeout];
+        for (i, field) in fields.iter().enumerate() {
+            buffer[2 * i] = *field as u8;
+            buffer[2 * i + 1] = (*field >> 8) as u8;
+        }
+        buffer[8..23].copy_from_slice(&self.anchors);
+    }
+
+    /// Whether the parameters are in range: an interval of 7.5 ms to 4 s, a
+    /// latency below 500 events and a supervision timeout of 100 ms to 32 s,
+    /// longer than twice the time the slave may sleep
+    pub fn is_valid(&self) -> bool {
+        self.interval_min >= 6 && self.interval_min <= self.interval_max
+            && self.interval_max <= 3200 && self.latency < 500 && self.timeout >= 10
+            && self.timeout <= 3200
+            && (self.timeout as u32) * 4 > (self.latency as u32 + 1) * self.interval_max as u32
+    }
+}
+
+/// PHYs of an LL_PHY_UPDATE_IND, as PHY field bitmasks. A field of zero
+/// leaves the PHY of that direction unchanged.
+pub struct PhyUpdate {
+    pub m_to_s: u8,
+    pub s_to_m: u8,
+}
+
+impl PhyUpdate {
+    /// `buffer` starts with the CtrData of the PDU
+    pub fn read_from_buffer(buffer: &[u8]) -> PhyUpdate {
+        PhyUpdate {
+            m_to_s: buffer[0],
+            s_to_m: buffer[1],
+        }
+    }
+}
+
+/// Parameters of an LL_ENC_REQ, the multi-octet fields least significant
+/// octet first as sent
+#[derive(Copy, Clone)]
+pub struct EncryptionRequest {
+    /// Rand and EDIV identify the long term key to the host
+    pub rand: [u8; 8],
+    pub ediv: u16,
+    /// The central's part of the session key diversifier and of the IV
+    pub skdm: [u8; 8],
+    pub ivm: [u8; 4],
+}
+
+impl EncryptionRequest {
+    /// `buffer` starts with the CtrData of the PDU
+    pub fn read_from_buffer(buffer: &[u8]) -> EncryptionRequest {
+        let mut request = EncryptionRequest {
+            rand: [0; 8],
+            ediv: (buffer[9] as u16) << 8 | buffer[8] as u16,
+            skdm: [0; 8],
+            ivm: [0; 4],
+        };
+        request.rand.copy_from_slice(&buffer[0..8]);
+        request.skdm.copy_from_slice(&buffer[10..18]);
+        request.ivm.copy_from_slice(&buffer[18..22]);
+        request
+    }
+}
+
 pub struct LLData {
     pub aa: [u8; 4],
     pub crc_init: [u8; 3],
@@ -124,6 +279,9 @@ pub struct LLData {
     pub timeout: u16,
     pub chm: ChannelMap,
     pub hop_and_sca: u8, // hops 5 bits, sca 3 bits
+    /// ChSel of the CONNECT_IND header: the initiator supports channel
+    /// selection algorithm #2
+    pub ch_sel: bool,
 }
 
 impl fmt::Debug for LLData {
@@ -155,6 +313,7 @@ impl LLData {
             timeout: 0x4800, // TODO .to_be() or .to_le()
             chm: ChannelMap([0x00, 0xf0, 0x1f, 0x00, 0x18]),
             hop_and_sca: (1 << 5) | 15, // = 0010 1111
+            ch_sel: false,
         }
     }
 
@@ -182,9 +341,18 @@ impl LLData {
                 | buffer[PACKET_ADDR_START + 26] as u16,
             chm: ChannelMap::read_from_buffer(&buffer[PACKET_ADDR_START + 28 ..]),
             hop_and_sca: buffer[PACKET_ADDR_START + 33],
+            ch_sel: buffer[PACKET_HDR_PDU] & CH_SEL != 0,
         }
     }
 
+    pub fn apply_connection_update(&mut self, update: &ConnectionUpdate) {
+        self.win_size = update.win_size;
+        self.win_offset = update.win_offset;
+        self.interval = update.interval;
+        self.latency = update.latency;
+        self.timeout = update.timeout;
+    }
+
     #[inline(always)]
     fn msec_to_usec(msec: u32) -> u32 {
         msec * 1000
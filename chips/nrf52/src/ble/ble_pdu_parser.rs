@@ -1,5 +1,8 @@
-use ble::ble_link_layer::LLData;
+use ble::ble_link_layer::{ChannelMap, ConnectionParameters, ConnectionUpdate, EncryptionRequest,
+                          LLData, PhyUpdate};
+use core::cmp;
 use core::fmt;
+use kernel::ReturnCode;
 
 #[derive(Debug)]
 pub enum BLEPduType<'a> {
@@ -76,11 +79,104 @@ const CONNECT_REQ_LEN: u8 = 34;
 pub const PACKET_START: usize = 0;
 pub const PACKET_HDR_PDU: usize = 0;
 pub const PACKET_HDR_LEN: usize = 1;
+/// ChSel bit of the advertising PDU header: set in connectable advertising
+/// PDUs and CONNECT_IND by devices supporting channel selection algorithm #2
+pub const CH_SEL: u8 = 1 << 5;
 pub const PACKET_ADDR_START: usize = 2;
 pub const PACKET_ADDR_END: usize = 7;
 pub const PACKET_PAYLOAD_START: usize = 8;
 pub const PACKET_LENGTH: usize = 39;
 
+/// Longest advertising data, the AD structures of an advertising PDU or a
+/// SCAN_RSP
+pub const ADV_DATA_MAX_LEN: usize = PACKET_LENGTH - PACKET_PAYLOAD_START;
+
+// Bluetooth Core Specification:Vol. 3, Part C, section 11
+//
+// Split AD structures too long for one advertising PDU between the
+// advertisement and its scan response. The AD structures are kept whole and
+// in order, those that fit going into the advertisement and the rest into
+// the scan response. An AD structure of length 0 ends the data, what follows
+// is padding.
+//
+// Returns the length of the advertisement part and the total length, or
+// EINVAL if `data` is not a sequence of AD structures and ESIZE if the rest
+// does not fit in a scan response.
+pub fn split_advertising_data(data: &[u8]) -> Result<(usize, usize), ReturnCode> {
+    let mut split = None;
+    let mut idx = 0;
+
+    while idx < data.len() && data[idx] != 0 {
+        let end = idx + 1 + data[idx] as usize;
+        if end > data.len() {
+            return Err(ReturnCode::EINVAL);
+        }
+        if split.is_none() && end > ADV_DATA_MAX_LEN {
+            split = Some(idx);
+        }
+        idx = end;
+    }
+
+    let split = split.unwrap_or(idx);
+    if idx - split > ADV_DATA_MAX_LEN {
+        Err(ReturnCode::ESIZE)
+    } else {
+        Ok((split, idx))
+    }
+}
+
+// Remove the AD structures of the `types` from the first `len` bytes of
+// `data`, moving the ones after them forward.
+//
+// Returns the length of what is left, or EINVAL if `data` is not a sequence
+// of AD structures.
+pub fn remove_ad_structures(
+    data: &mut [u8],
+    len: usize,
+    types: &[u8],
+) -> Result<usize, ReturnCode> {
+    let mut idx = 0;
+    let mut kept = 0;
+
+    while idx < len && data[idx] != 0 {
+        let end = idx + 1 + data[idx] as usize;
+        if end > len {
+            return Err(ReturnCode::EINVAL);
+        }
+        if !types.contains(&data[idx + 1]) {
+            for i in idx..end {
+                data[kept + i - idx] = data[i];
+            }
+            kept += end - idx;
+        }
+        idx = end;
+    }
+    for byte in data[kept..len].iter_mut() {
+        *byte = 0;
+    }
+    Ok(kept)
+}
+
+// Append an AD structure of type `ad_type` holding `value` after the first
+// `len` bytes of `data`, which holds `ADV_DATA_MAX_LEN` at most.
+//
+// Returns the new length, or ESIZE if it does not fit.
+pub fn append_ad_structure(
+    data: &mut [u8],
+    len: usize,
+    ad_type: u8,
+    value: &[u8],
+) -> Result<usize, ReturnCode> {
+    let end = len + 2 + value.len();
+    if end > cmp::min(data.len(), ADV_DATA_MAX_LEN) {
+        return Err(ReturnCode::ESIZE);
+    }
+    data[len] = (value.len() + 1) as u8;
+    data[len + 1] = ad_type;
+    data[len + 2..end].copy_from_slice(value);
+    Ok(end)
+}
+
 #[repr(u8)]
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum BLEAdvertisementType {
@@ -107,6 +203,22 @@ impl BLEAdvertisementType {
         }
     }
 
+    /// Advertising PDUs that may be answered with a CONNECT_REQ
+    pub fn is_connectable(&self) -> bool {
+        match *self {
+            BLEAdvertisementType::ConnectUndirected | BLEAdvertisementType::ConnectDirected => true,
+            _ => false,
+        }
+    }
+
+    /// Advertising PDUs that may be answered with a SCAN_REQ
+    pub fn is_scannable(&self) -> bool {
+        match *self {
+            BLEAdvertisementType::ConnectUndirected | BLEAdvertisementType::ScanUndirected => true,
+            _ => false,
+        }
+    }
+
     pub fn validate_pdu(&self, len: u8) -> bool {
         match self {
             &BLEAdvertisementType::ScanRequest | &BLEAdvertisementType::ConnectDirected => {
@@ -125,6 +237,84 @@ impl BLEAdvertisementType {
     }
 }
 
+// LL Control PDUs acted on by the link layer, with their instant if they have
+// one
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 2.4.2
+//
+//   PDU     +-----------+      +--------------+
+//           | Opcode    |  -   | CtrData      |
+//           | (1 byte)  |      | (0-26 bytes) |
+//           +-----------+      +--------------+
+//
+pub enum LLControlPdu {
+    ConnectionUpdate(ConnectionUpdate, u16),
+    ChannelMap(ChannelMap, u16),
+    EncryptionRequest(EncryptionRequest),
+    StartEncryptionResponse,
+    /// The TX_PHYS and RX_PHYS preferences of the central
+    PhyRequest(u8, u8),
+    PhyUpdate(PhyUpdate, u16),
+    /// The FeatureSet of the central
+    FeatureRequest([u8; 8]),
+    /// Connection parameters the central proposes
+    ConnectionParamRequest(ConnectionParameters),
+    /// The UnknownType of an LL_UNKNOWN_RSP, the opcode of ours the central
+    /// does not know
+    UnknownResponse(u8),
+    /// The RejectOpcode and ErrorCode of an LL_REJECT_EXT_IND
+    RejectExtended(u8, u8),
+}
+
+impl LLControlPdu {
+    /// Parse an LL Control PDU, header included. Returns `None` for opcodes
+    /// the link layer ignores and PDUs too short for their opcode.
+    pub fn from_buffer(buf: &[u8]) -> Option<LLControlPdu> {
+        let len = buf[PACKET_HDR_LEN] as usize;
+        let instant = |offset: usize| (buf[offset + 1] as u16) << 8 | buf[offset] as u16;
+
+        match buf[PACKET_ADDR_START] {
+            // LL_CONNECTION_UPDATE_IND
+            0x00 if len >= 12 => Some(LLControlPdu::ConnectionUpdate(
+                ConnectionUpdate::read_from_buffer(&buf[3..]),
+                instant(12),
+            )),
+            // LL_CHANNEL_MAP_IND
+            0x01 if len >= 8 => Some(LLControlPdu::ChannelMap(
+                ChannelMap::read_from_buffer(&buf[3..]),
+                instant(8),
+            )),
+            // LL_ENC_REQ
+            0x03 if len >= 23 => Some(LLControlPdu::EncryptionRequest(
+                EncryptionRequest::read_from_buffer(&buf[3..]),
+            )),
+            // LL_START_ENC_RSP
+            0x06 => Some(LLControlPdu::StartEncryptionResponse),
+            // LL_FEATURE_REQ
+            0x08 if len >= 9 => {
+                let mut features = [0; 8];
+                features.copy_from_slice(&buf[3..11]);
+                Some(LLControlPdu::FeatureRequest(features))
+            }
+            // LL_UNKNOWN_RSP
+            0x07 if len >= 2 => Some(LLControlPdu::UnknownResponse(buf[3])),
+            // LL_CONNECTION_PARAM_REQ
+            0x0F if len >= 24 => Some(LLControlPdu::ConnectionParamRequest(
+                ConnectionParameters::read_from_buffer(&buf[3..]),
+            )),
+            // LL_REJECT_EXT_IND
+            0x11 if len >= 3 => Some(LLControlPdu::RejectExtended(buf[3], buf[4])),
+            // LL_PHY_REQ
+            0x16 if len >= 3 => Some(LLControlPdu::PhyRequest(buf[3], buf[4])),
+            // LL_PHY_UPDATE_IND
+            0x18 if len >= 5 => Some(LLControlPdu::PhyUpdate(
+                PhyUpdate::read_from_buffer(&buf[3..]),
+                instant(5),
+            )),
+            _ => None,
+        }
+    }
+}
+
 #[derive(PartialEq, Eq, Clone, Copy)]
 pub struct DeviceAddress(pub [u8; 6]);
 
@@ -0,0 +1,215 @@
+//! Link-layer counters for qualification testing
+//!
+//! Counts what the Bluetooth qualification test cases of the link layer
+//! check, so that a test run on a tester can be backed by numbers from the
+//! device itself:
+//!
+//! - Advertising events, and the time between the starts of two events of
+//!   the same advertiser, which must be the advertising interval plus an
+//!   advDelay of 0 to 10 ms (Vol 6, Part B, section 4.4.2.2). Events outside
+//!   of it, with a tolerance of two ticks of the advertising alarm, are
+//!   counted as violations.
+//! - Advertising packets sent on each of the three advertising channels.
+//! - T_IFS from the end of a received packet to the start of the packet sent
+//!   in response, from the radio timer captures, which must be 150 us with a
+//!   tolerance of 2 us (Vol 6, Part B, section 4.1.1). Only measured on the
+//!   uncoded PHYs.
+//! - Packets received with a good and a bad CRC.
+//!
+//! `reset` starts a new test run. The counters of the run are printed on one
+//! line, as `key=value` pairs separated by spaces and starting with `llq:`,
+//! by command 19 of the BLE advertising driver and `AT+LLSTATS` of the AT
+//! command interface, so a script on the host can collect them.
+
+use ble::ble_advertising_hil::{Phy, RadioChannel};
+use core::cell::Cell;
+use core::cmp;
+use core::fmt;
+
+/// T_IFS, Vol 6, Part B, section 4.1.1
+pub const T_IFS_US: u32 = 150;
+const T_IFS_TOLERANCE_US: u32 = 2;
+
+/// Longest advDelay, Vol 6, Part B, section 4.4.2.2
+pub const ADV_DELAY_MAX_US: u32 = 10000;
+/// Two ticks of the 32.768 kHz advertising alarm
+const ADV_INTERVAL_TOLERANCE_US: u32 = 62;
+
+/// Time on air of a packet with a `payload_len` byte payload, from the
+/// first bit of the preamble to the last bit of the CRC. `None` on the coded
+/// PHY, where it depends on the coding of each part of the packet.
+pub fn packet_air_time_us(phy: Phy, payload_len: u32) -> Option<u32> {
+    // Access address, header and CRC
+    let bytes = 4 + 2 + payload_len + 3;
+    match phy {
+        Phy::Le1M => Some((1 + bytes) * 8),
+        Phy::Le2M => Some((2 + bytes) * 4),
+        Phy::LeCodedS2 | Phy::LeCodedS8 => None,
+    }
+}
+
+/// Minimum, maximum and sum of a series of samples
+#[derive(Copy, Clone)]
+struct Samples {
+    count: u32,
+    min: u32,
+    max: u32,
+    sum: u64,
+}
+
+impl Samples {
+    const fn new() -> Samples {
+        Samples {
+            count: 0,
+            min: 0,
+            max: 0,
+            sum: 0,
+        }
+    }
+
+    fn add(&mut self, sample: u32) {
+        if self.count == 0 {
+            self.min = sample;
+            self.max = sample;
+        } else {
+            self.min = cmp::min(self.min, sample);
+            self.max = cmp::max(self.max, sample);
+        }
+        self.count += 1;
+        self.sum += sample as u64;
+    }
+
+    fn mean(&self) -> u64 {
+        if self.count == 0 {
+            0
+        } else {
+            self.sum / self.count as u64
+        }
+    }
+}
+
+pub struct Conformance {
+    run: Cell<u32>,
+    advertising_events: Cell<u32>,
+    advertising_intervals: Cell<Samples>,
+    advertising_interval_violations: Cell<u32>,
+    /// Advertising packets sent on channels 37, 38 and 39
+    advertising_packets: [Cell<u32>; 3],
+    tifs: Cell<Samples>,
+    tifs_violations: Cell<u32>,
+    crc_ok: Cell<u32>,
+    crc_fail: Cell<u32>,
+}
+
+pub static mut CONFORMANCE: Conformance = Conformance::new();
+
+impl Conformance {
+    const fn new() -> Conformance {
+        Conformance {
+            run: Cell::new(0),
+            advertising_events: Cell::new(0),
+            advertising_intervals: Cell::new(Samples::new()),
+            advertising_interval_violations: Cell::new(0),
+            advertising_packets: [Cell::new(0), Cell::new(0), Cell::new(0)],
+            tifs: Cell::new(Samples::new()),
+            tifs_violations: Cell::new(0),
+            crc_ok: Cell::new(0),
+            crc_fail: Cell::new(0),
+        }
+    }
+
+    /// Clear the counters and start a new test run
+    pub fn reset(&self) {
+        self.run.set(self.run.get() + 1);
+        self.advertising_events.set(0);
+        self.advertising_intervals.set(Samples::new());
+        self.advertising_interval_violations.set(0);
+        for packets in self.advertising_packets.iter() {
+            packets.set(0);
+        }
+        self.tifs.set(Samples::new());
+        self.tifs_violations.set(0);
+        self.crc_ok.set(0);
+        self.crc_fail.set(0);
+    }
+
+    /// An advertising event started, `since_last_us` after the previous one
+    /// of the same advertiser if there was one, which advertises every
+    /// `interval_us`.
+    pub fn advertising_event(&self, since_last_us: Option<u32>, interval_us: u32) {
+        self.advertising_events.set(self.advertising_events.get() + 1);
+        if let Some(delta) = since_last_us {
+            let mut intervals = self.advertising_intervals.get();
+            intervals.add(delta);
+            self.advertising_intervals.set(intervals);
+
+            if delta + ADV_INTERVAL_TOLERANCE_US < interval_us
+                || delta > interval_us + ADV_DELAY_MAX_US + ADV_INTERVAL_TOLERANCE_US
+            {
+                self.advertising_interval_violations
+                    .set(self.advertising_interval_violations.get() + 1);
+            }
+        }
+    }
+
+    /// An advertising packet was sent
+    pub fn advertising_packet(&self, channel: RadioChannel) {
+        let index = match channel {
+            RadioChannel::AdvertisingChannel37 => 0,
+            RadioChannel::AdvertisingChannel38 => 1,
+            RadioChannel::AdvertisingChannel39 => 2,
+            _ => return,
+        };
+        let packets = &self.advertising_packets[index];
+        packets.set(packets.get() + 1);
+    }
+
+    /// A response was sent `tifs_us` after the end of the packet it answers
+    pub fn tifs_sample(&self, tifs_us: u32) {
+        let mut tifs = self.tifs.get();
+        tifs.add(tifs_us);
+        self.tifs.set(tifs);
+        if tifs_us + T_IFS_TOLERANCE_US < T_IFS_US || tifs_us > T_IFS_US + T_IFS_TOLERANCE_US {
+            self.tifs_violations.set(self.tifs_violations.get() + 1);
+        }
+    }
+
+    /// A packet was received
+    pub fn crc(&self, ok: bool) {
+        if ok {
+            self.crc_ok.set(self.crc_ok.get() + 1);
+        } else {
+            self.crc_fail.set(self.crc_fail.get() + 1);
+        }
+    }
+}
+
+impl fmt::Display for Conformance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let intervals = self.advertising_intervals.get();
+        let tifs = self.tifs.get();
+        write!(
+            f,
+            "llq: run={} adv_events={} adv_interval_min_us={} adv_interval_max_us={} \
+             adv_interval_mean_us={} adv_interval_violations={} adv_tx_37={} adv_tx_38={} \
+             adv_tx_39={} tifs_samples={} tifs_min_us={} tifs_max_us={} tifs_mean_us={} \
+             tifs_violations={} crc_ok={} crc_fail={}",
+            self.run.get(),
+            self.advertising_events.get(),
+            intervals.min,
+            intervals.max,
+            intervals.mean(),
+            self.advertising_interval_violations.get(),
+            self.advertising_packets[0].get(),
+            self.advertising_packets[1].get(),
+            self.advertising_packets[2].get(),
+            tifs.count,
+            tifs.min,
+            tifs.max,
+            tifs.mean(),
+            self.tifs_violations.get(),
+            self.crc_ok.get(),
+            self.crc_fail.get()
+        )
+    }
+}
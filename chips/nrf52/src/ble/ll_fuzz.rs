@@ -0,0 +1,71 @@
+//! Link-layer fuzzing hooks
+//!
+//! Feeds arbitrary byte sequences through the code the radio interrupt path
+//! runs on received packets: advertising PDU validation and parsing,
+//! connection setup from a CONNECT_REQ, and data channel PDU handling
+//! including LL Control procedures. Nothing here touches the radio, so a
+//! harness can drive the link layer with malformed packets directly.
+//!
+//! Only built with the `ll_fuzz` feature.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! if let Some(mut connection) = nrf52::ble::ll_fuzz::inject_advertising_pdu(&input[..39]) {
+//!     for pdu in input[39..].chunks(27) {
+//!         nrf52::ble::ll_fuzz::inject_data_pdu(&mut connection, pdu);
+//!     }
+//! }
+//! ```
+
+use ble::ble_connection_driver::ConnectionData;
+use ble::ble_pdu_parser::{BLEAdvertisementType, BLEPduType};
+use core::cmp;
+use nrf5x::constants::RADIO_PAYLOAD_LENGTH;
+
+/// Copy `pdu` into a buffer laid out like the radio's RX buffer: always full
+/// length, zero filled past what was received.
+fn rx_buffer(pdu: &[u8]) -> [u8; RADIO_PAYLOAD_LENGTH] {
+    let mut buf = [0; RADIO_PAYLOAD_LENGTH];
+    let len = cmp::min(pdu.len(), RADIO_PAYLOAD_LENGTH);
+    buf[..len].copy_from_slice(&pdu[..len]);
+    buf
+}
+
+/// Handle `pdu` as if it was received on an advertising channel, header
+/// first. Packets that are not accepted are dropped, as with a CRC error.
+/// A valid CONNECT_REQ sets up a connection, which is returned after
+/// selecting its first data channel.
+pub fn inject_advertising_pdu(pdu: &[u8]) -> Option<ConnectionData> {
+    let buf = rx_buffer(pdu);
+
+    let pdu_type = BLEAdvertisementType::from_u8(buf[0] & 0x0f)?;
+    if !pdu_type.validate_pdu(buf[1]) {
+        return None;
+    }
+
+    match BLEPduType::from_buffer(pdu_type, &buf)? {
+        BLEPduType::ConnectRequest(_init_addr, _adv_addr, lldata) => {
+            let mut connection = ConnectionData::new(lldata);
+            connection.next_channel();
+            Some(connection)
+        }
+        _ => None,
+    }
+}
+
+/// Handle `pdu` as if it was received in a connection event of
+/// `connection`, header first, then move to the next connection event.
+pub fn inject_data_pdu(connection: &mut ConnectionData, pdu: &[u8]) {
+    let buf = rx_buffer(pdu);
+
+    let new_data = connection.next_sequence_number(buf[0]);
+    let header = ConnectionData::get_data_pdu_header(buf[0]);
+    if new_data && header.llid == 0x03 {
+        connection.handle_control_pdu(&buf);
+    }
+
+    connection.increment_conn_event();
+    connection.next_channel();
+}
@@ -0,0 +1,470 @@
+//! Deterministic replay of connection event scheduling
+//!
+//! Runs a scripted connection through the same `ConnectionData` code the
+//! radio interrupt path uses, without the radio: each step is either a data
+//! PDU received at a given time, with or without a valid CRC, or a
+//! connection event in which nothing was received. After every step the
+//! anchor point and data channel the scheduler picked for the next
+//! connection event, if it moved on to one, are compared with the script.
+//!
+//! Times are in microseconds from the first anchor point, as seen by the
+//! central. `drift_ppm` skews them onto the local clock, so the same script
+//! can be replayed against a peer whose sleep clock runs fast or slow.
+//!
+//! `check_all` runs the scripted scenarios: a connection update and a
+//! channel map taking effect at their instant, the supervision timeout
+//! with and without packets heard in between, and channel maps whose
+//! instant lies across the wrap of the connection event counter, ahead of
+//! it or behind it. Each one panics on the first mismatch.
+//!
+//! Only built with the `ll_replay` feature. The nRF52 DK has a test kernel
+//! running them, see its `tests::ll_replay`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! use nrf52::ble::ble_link_layer::LLData;
+//! use nrf52::ble::ll_replay::{Event, Replay};
+//!
+//! let interval = LLData::new().connection_interval();
+//! let mut replay = Replay::new(LLData::new(), 0);
+//!
+//! // An empty PDU without MD ends the event, the next one is scheduled a
+//! // connection interval, less the early listening margin, later. The
+//! // receive window opens earlier still, widened for 300 ppm of drift.
+//! let next = replay.step(&Event::Packet { at: 0, pdu: &[0x01, 0x00], crc_ok: true });
+//! assert_eq!(next.map(|anchor| anchor.at), Some(interval - 1000 - 2304));
+//!
+//! // Nothing heard, the window after that one opens an interval later,
+//! // widened for the two intervals since the last anchor point heard
+//! let next = replay.step(&Event::Missed);
+//! assert_eq!(next.map(|anchor| anchor.at), Some(2 * interval - 1000 - 2 * 2304));
+//! ```
+
+use ble::ble_advertising_hil::{RadioChannel, ReceivedPdu};
+use ble::ble_connection_driver::{ConnectionData, CONNECTION_TIMEOUT, INSTANT_PASSED};
+use ble::ble_link_layer::LLData;
+use core::cmp;
+use nrf5x::constants::RADIO_PAYLOAD_LENGTH;
+
+/// Stands in for the radio's receive buffer
+static mut PDU: [u8; RADIO_PAYLOAD_LENGTH] = [0; RADIO_PAYLOAD_LENGTH];
+
+/// One step of a replayed connection
+#[derive(Debug, Clone, Copy)]
+pub enum Event<'a> {
+    /// `pdu`, header first, was received at central time `at`
+    Packet {
+        at: u32,
+        pdu: &'a [u8],
+        crc_ok: bool,
+    },
+    /// Nothing was received before the receive window closed
+    Missed,
+}
+
+/// Start of a connection event as scheduled by the peripheral, local time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Anchor {
+    pub at: u32,
+    pub channel: RadioChannel,
+}
+
+pub struct Replay {
+    connection: ConnectionData,
+    drift_ppm: i32,
+    channel: RadioChannel,
+    /// Start of the receive window currently open, where the radio
+    /// schedules the window of a missed event from
+    window_start: u32,
+}
+
+impl Replay {
+    /// A connection whose first connection event starts at time 0, on the
+    /// data channel selected after the CONNECT_REQ.
+    pub fn new(lldata: LLData, drift_ppm: i32) -> Replay {
+        let mut connection = ConnectionData::new(lldata);
+        let channel = connection.next_channel();
+        Replay {
+            connection,
+            drift_ppm,
+            channel,
+            window_start: 0,
+        }
+    }
+
+    pub fn connection(&self) -> &ConnectionData {
+        &self.connection
+    }
+
+    pub fn channel(&self) -> RadioChannel {
+        self.channel
+    }
+
+    /// The counter of the current connection event
+    pub fn event_counter(&self) -> u16 {
+        self.connection.conn_event_counter
+    }
+
+    /// Go on as if the connection had already been through `counter`
+    /// events, to replay what happens when the counter wraps around
+    pub fn set_event_counter(&mut self, counter: u16) {
+        self.connection.conn_event_counter = counter;
+    }
+
+    /// Central time `at` on the local clock
+    fn local_time(&self, at: u32) -> u32 {
+        let skew = at as i64 * self.drift_ppm as i64 / 1_000_000;
+        (at as i64 + skew) as u32
+    }
+
+    /// Replay one step. Returns where the next connection event was
+    /// scheduled, or `None` if the current one goes on.
+    pub fn step(&mut self, event: &Event) -> Option<Anchor> {
+        let next = match *event {
+            Event::Packet { at, pdu, crc_ok } => {
+                let len = cmp::min(pdu.len(), RADIO_PAYLOAD_LENGTH);
+                let buf = unsafe { &mut PDU };
+                for byte in buf.iter_mut() {
+                    *byte = 0;
+                }
+                buf[..len].copy_from_slice(&pdu[..len]);
+
+                let received = ReceivedPdu {
+                    len: len as u8,
+                    buf: buf,
+                    crc_ok: crc_ok,
+                    rssi: 0,
+                    channel: Some(self.channel),
+                    timestamp: self.local_time(at),
+                    mic_ok: true,
+                };
+                self.connection.receive_data_pdu(&received)
+            }
+            Event::Missed => {
+                // As the radio does on a receive timeout: the next window
+                // opens a connection interval after the one that closed
+                Some(self.window_start + self.connection.skip_conn_event())
+            }
+        };
+
+        next.map(|at| {
+            self.channel = self.connection.next_channel();
+            self.window_start = at;
+            Anchor {
+                at,
+                channel: self.channel,
+            }
+        })
+    }
+}
+
+/// Replay `script` and panic at the first step where the scheduler does not
+/// pick the expected next connection event.
+pub fn assert_replay(lldata: LLData, drift_ppm: i32, script: &[(Event, Option<Anchor>)]) {
+    let mut replay = Replay::new(lldata, drift_ppm);
+    for (step, &(ref event, expected)) in script.iter().enumerate() {
+        let actual = replay.step(event);
+        assert!(
+            actual == expected,
+            "step {}: {:?} scheduled {:?}, expected {:?}",
+            step,
+            event,
+            actual,
+            expected
+        );
+    }
+}
+
+/// First octet of the header of a data PDU with `llid` and sequence number
+/// `sn`, acknowledging nothing
+fn data_header(llid: u8, sn: u8) -> u8 {
+    llid | sn << 3
+}
+
+/// LL_CHANNEL_MAP_IND for the data channels set in `map`, taking effect at
+/// `instant`
+fn channel_map_ind(sn: u8, map: [u8; 5], instant: u16) -> [u8; 10] {
+    [
+        data_header(0x03, sn),
+        8,
+        0x01,
+        map[0],
+        map[1],
+        map[2],
+        map[3],
+        map[4],
+        instant as u8,
+        (instant >> 8) as u8,
+    ]
+}
+
+/// LL_CONNECTION_UPDATE_IND with the parameters in the units of `LLData`,
+/// taking effect at `instant`
+fn connection_update_ind(
+    sn: u8,
+    win_size: u8,
+    win_offset: u16,
+    interval: u16,
+    timeout: u16,
+    instant: u16,
+) -> [u8; 14] {
+    [
+        data_header(0x03, sn),
+        12,
+        0x00,
+        win_size,
+        win_offset as u8,
+        (win_offset >> 8) as u8,
+        interval as u8,
+        (interval >> 8) as u8,
+        0,
+        0,
+        timeout as u8,
+        (timeout >> 8) as u8,
+        instant as u8,
+        (instant >> 8) as u8,
+    ]
+}
+
+/// How much earlier than the anchor point the receive window opens for a
+/// connection with `LLData::new()`, `elapsed` after the last anchor point
+/// heard: the central's 250 ppm sleep clock accuracy and our 50 ppm
+fn widening(elapsed: u32) -> u32 {
+    ((elapsed as u64 * 300 + 999_999) / 1_000_000) as u32
+}
+
+/// Data channel index of `anchor`
+fn channel_index(anchor: Option<Anchor>) -> u32 {
+    anchor.map_or(u32::max_value(), |anchor| anchor.channel.get_channel_index())
+}
+
+/// Receive an LL_CHANNEL_MAP_IND for the first eight data channels, with
+/// `instant`, in connection event `counter`, then an empty PDU in each of
+/// the `events` events after it. Returns the replay and the event from
+/// which the new channel map was used, if it was.
+fn replay_channel_map(counter: u16, instant: u16, events: u32) -> (Replay, Option<u16>) {
+    let lldata = LLData::new();
+    let interval = lldata.connection_interval();
+    let mut replay = Replay::new(lldata, 0);
+    replay.set_event_counter(counter);
+
+    let map = channel_map_ind(0, [0xFF, 0, 0, 0, 0], instant);
+    let mut applied = None;
+    for event in 0..events + 1 {
+        let empty = [data_header(0x01, (event % 2) as u8), 0];
+        let pdu: &[u8] = if event == 0 { &map } else { &empty };
+        replay.step(&Event::Packet {
+            at: event * interval,
+            pdu: pdu,
+            crc_ok: true,
+        });
+        if applied.is_none() && replay.connection().number_used_channels() == 8 {
+            applied = Some(replay.event_counter());
+        }
+    }
+    (replay, applied)
+}
+
+/// An LL_CONNECTION_UPDATE_IND received in event 0 with instant 2: event 1
+/// keeps the old interval, event 2 is pushed back by the window offset and
+/// widened for the time since event 1, and the new interval is used from
+/// event 2 on
+pub fn check_connection_update() {
+    let lldata = LLData::new();
+    let old_interval = lldata.connection_interval();
+    // 2.5 ms window 5 ms after the end of event 1, 100 ms interval, 2 s
+    // supervision timeout
+    let (win_offset, new_interval) = (5_000, 100_000);
+    let update = connection_update_ind(0, 2, 4, 80, 200, 2);
+    let empty = |sn: u8| [data_header(0x01, sn), 0];
+
+    let mut replay = Replay::new(lldata, 0);
+    let next = replay.step(&Event::Packet {
+        at: 0,
+        pdu: &update,
+        crc_ok: true,
+    });
+    assert_eq!(
+        next.map(|anchor| anchor.at),
+        Some(old_interval - 1000 - widening(old_interval)),
+        "event 1 before the instant"
+    );
+    assert_eq!(replay.connection().lldata.connection_interval(), old_interval);
+
+    let next = replay.step(&Event::Packet {
+        at: old_interval,
+        pdu: &empty(1),
+        crc_ok: true,
+    });
+    assert_eq!(
+        next.map(|anchor| anchor.at),
+        Some(2 * old_interval - 1000 + win_offset - widening(old_interval + win_offset)),
+        "event 2 at the instant"
+    );
+    assert_eq!(replay.event_counter(), 2);
+    assert_eq!(replay.connection().lldata.connection_interval(), new_interval);
+    assert_eq!(replay.connection().lldata.window_size(), 2_500);
+    assert_eq!(replay.connection().calculate_conn_supervision_timeout(), 2_000_000);
+
+    let anchor = 2 * old_interval + win_offset;
+    let next = replay.step(&Event::Packet {
+        at: anchor,
+        pdu: &empty(0),
+        crc_ok: true,
+    });
+    assert_eq!(
+        next.map(|anchor| anchor.at),
+        Some(anchor + new_interval - 1000 - widening(new_interval)),
+        "event 3 after the instant"
+    );
+    assert_eq!(replay.connection().termination(), None);
+}
+
+/// An LL_CHANNEL_MAP_IND for the first eight data channels received in
+/// event 0 with instant 3. `LLData::new()` only uses channels from 12 on,
+/// so events 1 and 2 still hop over those, and from event 3 on only the
+/// new ones are used.
+pub fn check_channel_map() {
+    let lldata = LLData::new();
+    let interval = lldata.connection_interval();
+    let used_channels = ConnectionData::new(LLData::new()).number_used_channels();
+    let map = channel_map_ind(0, [0xFF, 0, 0, 0, 0], 3);
+
+    let mut replay = Replay::new(lldata, 0);
+    for event in 0..7 {
+        let empty = [data_header(0x01, (event % 2) as u8), 0];
+        let pdu: &[u8] = if event == 0 { &map } else { &empty };
+        let next = replay.step(&Event::Packet {
+            at: event * interval,
+            pdu: pdu,
+            crc_ok: true,
+        });
+        // `next` is the anchor of event `event + 1`
+        if event + 1 < 3 {
+            assert!(channel_index(next) >= 12, "event {} on the old map", event + 1);
+            assert_eq!(replay.connection().number_used_channels(), used_channels);
+        } else {
+            assert!(channel_index(next) < 8, "event {} on the new map", event + 1);
+            assert_eq!(replay.connection().number_used_channels(), 8);
+        }
+    }
+    assert_eq!(replay.connection().termination(), None);
+}
+
+/// Nothing heard for the supervision timeout of `LLData::new()`, 24
+/// connection intervals, ends the connection with Connection Timeout. A
+/// packet with a valid CRC restarts the timer, one with a bad CRC does not.
+pub fn check_supervision_timeout() {
+    let lldata = LLData::new();
+    let interval = lldata.connection_interval();
+    let events = ConnectionData::new(LLData::new()).calculate_conn_supervision_timeout() / interval;
+    assert_eq!(events, 24);
+
+    let missed = |replay: &mut Replay, events: u32| {
+        for _ in 0..events {
+            replay.step(&Event::Missed);
+        }
+    };
+
+    let mut replay = Replay::new(LLData::new(), 0);
+    replay.step(&Event::Packet {
+        at: 0,
+        pdu: &[data_header(0x01, 0), 0],
+        crc_ok: true,
+    });
+    missed(&mut replay, events - 1);
+    assert_eq!(replay.connection().termination(), None, "one interval left");
+    missed(&mut replay, 1);
+    assert_eq!(
+        replay.connection().termination(),
+        Some(CONNECTION_TIMEOUT),
+        "nothing heard"
+    );
+
+    // Halfway, a valid packet restarts the timer
+    let mut replay = Replay::new(LLData::new(), 0);
+    replay.step(&Event::Packet {
+        at: 0,
+        pdu: &[data_header(0x01, 0), 0],
+        crc_ok: true,
+    });
+    missed(&mut replay, events / 2);
+    replay.step(&Event::Packet {
+        at: (events / 2 + 1) * interval,
+        pdu: &[data_header(0x01, 1), 0],
+        crc_ok: true,
+    });
+    missed(&mut replay, events - 1);
+    assert_eq!(replay.connection().termination(), None, "heard halfway");
+    missed(&mut replay, 1);
+    assert_eq!(
+        replay.connection().termination(),
+        Some(CONNECTION_TIMEOUT),
+        "nothing heard since halfway"
+    );
+
+    // Halfway, a packet with a bad CRC does not
+    let mut replay = Replay::new(LLData::new(), 0);
+    replay.step(&Event::Packet {
+        at: 0,
+        pdu: &[data_header(0x01, 0), 0],
+        crc_ok: true,
+    });
+    missed(&mut replay, events / 2);
+    replay.step(&Event::Packet {
+        at: (events / 2 + 1) * interval,
+        pdu: &[data_header(0x01, 1), 0],
+        crc_ok: false,
+    });
+    missed(&mut replay, events / 2 - 2);
+    assert_eq!(replay.connection().termination(), None, "one interval left");
+    missed(&mut replay, 1);
+    assert_eq!(
+        replay.connection().termination(),
+        Some(CONNECTION_TIMEOUT),
+        "only a bad CRC heard"
+    );
+}
+
+/// Instants compared across the wrap of the connection event counter: an
+/// instant a few events ahead takes effect once the counter has wrapped, one
+/// that is behind, or the current event, ends the connection with Instant
+/// Passed, and the edges of the half range fall on either side
+pub fn check_instant_wrap_around() {
+    // 65534 -> 65535 -> 0 -> 1
+    let (replay, applied) = replay_channel_map(65534, 1, 4);
+    assert_eq!(applied, Some(1), "instant ahead across the wrap");
+    assert_eq!(replay.connection().termination(), None);
+
+    let (replay, applied) = replay_channel_map(1, 65535, 4);
+    assert_eq!(applied, None, "instant behind across the wrap");
+    assert_eq!(replay.connection().termination(), Some(INSTANT_PASSED));
+
+    let (replay, applied) = replay_channel_map(65535, 65535, 4);
+    assert_eq!(applied, None, "instant in the current event");
+    assert_eq!(replay.connection().termination(), Some(INSTANT_PASSED));
+
+    // Up to 32766 events ahead is still to come, 32767 ahead or more has
+    // passed
+    let (replay, applied) = replay_channel_map(65535, 32765, 4);
+    assert_eq!(applied, None, "instant at the far end of the range");
+    assert_eq!(replay.connection().termination(), None);
+
+    let (replay, applied) = replay_channel_map(65535, 32766, 4);
+    assert_eq!(applied, None, "instant just past the range");
+    assert_eq!(replay.connection().termination(), Some(INSTANT_PASSED));
+
+    let (replay, applied) = replay_channel_map(65535, 32768, 4);
+    assert_eq!(applied, None, "instant 32769 events ahead");
+    assert_eq!(replay.connection().termination(), Some(INSTANT_PASSED));
+}
+
+/// Run every scenario, panicking at the first one that fails
+pub fn check_all() {
+    check_connection_update();
+    check_channel_map();
+    check_supervision_timeout();
+    check_instant_wrap_around();
+}
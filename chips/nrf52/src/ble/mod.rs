@@ -1,6 +1,16 @@
+pub mod at_command;
 pub mod ble_advertising_driver;
 pub mod ble_advertising_hil;
 pub mod ble_connection_driver;
 pub mod ble_link_layer;
 pub mod ble_pdu_parser;
+pub mod conformance;
+#[cfg(feature = "ll_fuzz")]
+pub mod ll_fuzz;
+#[cfg(feature = "ll_replay")]
+pub mod ll_replay;
 pub mod radio;
+#[cfg(feature = "radio_mock")]
+pub mod radio_mock;
+pub mod trace;
+pub mod tx_power_throttle;
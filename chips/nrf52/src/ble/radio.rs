@@ -17,7 +17,7 @@
 //! +----------+------+--------+----+--------+----+---------+-----+
 //! ```
 //!
-//! * Premable - 1 byte
+//! * Premable - 1 byte on LE 1M, 2 bytes on LE 2M and 10 bytes on LE Coded
 //!
 //! * Base and prefix forms together the access address
 //!
@@ -27,31 +27,82 @@
 //! * Length, an optional parameter that is configured to indicate how many bits of the
 //! payload is the length field. Configured as 8 bits!
 //!
-//! * S1, Not used
+//! * S1, Not used. On encrypted connections a byte is still left for it in
+//! RAM, the layout the CCM works on
 //!
 //! * Payload - 2 to 255 bytes
 //!
 //! * CRC - 3 bytes
+//!
+//! ### Clock
+//! The carrier is only accurate enough for BLE with the HFXO. The radio
+//! requests the high frequency clock when it sets up a transmission or a
+//! receive window and releases it when the link layer lets it sleep, so the
+//! crystal is stopped between advertising and connection events. The first
+//! packet of an event waits for the crystal to start.
+//!
+//! ### Encryption
+//! Once the link layer has set the keys of an encrypted connection with
+//! `BleConfig::set_encryption`, data channel packets go through the CCM:
+//! packets to send are encrypted before the transmission is started, and
+//! received packets are decrypted on the fly, the CCM being started by the
+//! radio through PPI, then copied to the receive buffer in the usual layout.
 
 use ble::ble_advertising_hil;
-use ble::ble_advertising_hil::{DelayStartPoint, PhyTransition, RadioChannel,
-                                          ReadAction, TxImmediate};
+use ble::ble_advertising_hil::{DelayStartPoint, Encryption, Phy, PhyTransition,
+                                          RadioChannel, RadioContext, ReadAction, ReceivedPdu,
+                                          TxImmediate, TxInfo, TxStatus};
+use ble::ble_pdu_parser::{BLEAdvertisementType, PACKET_ADDR_START, PACKET_PAYLOAD_START};
+use ble::conformance::{self, CONFORMANCE};
+use ble::trace::{self, Event};
+use clock;
 use core::cell::Cell;
+use core::cmp;
 use core::convert::TryFrom;
 use kernel;
 use kernel::ReturnCode;
 use nrf5x;
-use nrf5x::constants::TxPower;
+use nrf5x::ccm::{self, DataRate, Direction};
+use nrf5x::constants::{ADV_ACCESS_ADDRESS_BLE, TxPower};
 use ppi;
-use radio::{RadioRegisters, RADIO_BASE};
+use radio::{RadioRegisters, RssiSample, RADIO_BASE};
 use kernel::common::regs::FieldValue;
 use nrf5x::timer::BitmodeValue;
 
+// Pre-programmed PPI channels the radio drives by number rather than through
+// the allocator in `nrf5x::ppi`. They are reserved there so that a driver
+// asking for one of them gets EBUSY instead of silently rewiring the radio.
+// The radio itself currently uses:
+//
+// * CH20: TIMER0.EVENTS_COMPARE[0] -> RADIO.TASKS_TXEN
+// * CH21: TIMER0.EVENTS_COMPARE[0] -> RADIO.TASKS_RXEN
+// * CH22: TIMER0.EVENTS_COMPARE[1] -> RADIO.TASKS_DISABLE
+// * CH24: RADIO.EVENTS_READY -> CCM.TASKS_KSGEN
+// * CH25: RADIO.EVENTS_ADDRESS -> CCM.TASKS_CRYPT
+// * CH26: RADIO.EVENTS_ADDRESS -> TIMER0.TASKS_CAPTURE[1]
+// * CH27: RADIO.EVENTS_END -> TIMER0.TASKS_CAPTURE[2]
+//
+// CH23 and CH31 drive other radio, TIMER0 and RTC0 tasks the BLE stack
+// relies on being left alone.
+const RADIO_PPI_CHANNELS: [usize; 9] = [20, 21, 22, 23, 24, 25, 26, 27, 31];
+
 // NRF52 Specific Radio Constants
 const NRF52_RADIO_PCNF0_S1INCL_MSK: u32 = 0;
+const NRF52_RADIO_PCNF0_S1INCL_INCLUDE: u32 = 1;
 const NRF52_RADIO_PCNFO_S1INCL_POS: u32 = 20;
 const NRF52_RADIO_PCNF0_PLEN_POS: u32 = 24;
 const NRF52_RADIO_PCNF0_PLEN_8BITS: u32 = 0;
+const NRF52_RADIO_PCNF0_PLEN_16BITS: u32 = 1;
+#[cfg(feature = "nrf52840")]
+const NRF52_RADIO_PCNF0_PLEN_LONG_RANGE: u32 = 3;
+#[cfg(feature = "nrf52840")]
+const NRF52_RADIO_PCNF0_CILEN_POS: u32 = 22;
+#[cfg(feature = "nrf52840")]
+const NRF52_RADIO_PCNF0_CILEN_2BITS: u32 = 2;
+#[cfg(feature = "nrf52840")]
+const NRF52_RADIO_PCNF0_TERMLEN_POS: u32 = 29;
+#[cfg(feature = "nrf52840")]
+const NRF52_RADIO_PCNF0_TERMLEN_3BITS: u32 = 3;
 
 #[allow(unused)]
 const NRF52_RADIO_MODECNF0_RU_DEFAULT: u32 = 0;
@@ -59,33 +110,320 @@ const NRF52_RADIO_MODECNF0_RU_FAST: u32 = 1;
 
 const NRF52_FAST_RAMPUP_TIME_TX: u32 = 40;
 const NRF52_TX_DELAY: u32 = 3;
-const NRF52_TX_END_DELAY: u32 = 3;
-const NRF52_RX_END_DELAY: u32 = 7;
 
-const NRF52_DISABLE_TX_DELAY: u32 = NRF52_RX_END_DELAY + NRF52_FAST_RAMPUP_TIME_TX + NRF52_TX_DELAY;
-const NRF52_DISABLE_RX_DELAY: u32 = NRF52_TX_END_DELAY + NRF52_FAST_RAMPUP_TIME_TX;
+// Delay from the last bit on air to the END event, which grows with the
+// decoding the PHY needs
+fn tx_end_delay(phy: Phy) -> u32 {
+    match phy {
+        Phy::Le1M => 3,
+        Phy::Le2M => 2,
+        Phy::LeCodedS2 | Phy::LeCodedS8 => 3,
+    }
+}
 
-static mut TX_PAYLOAD: [u8; nrf5x::constants::RADIO_PAYLOAD_LENGTH] =
-    [0x00; nrf5x::constants::RADIO_PAYLOAD_LENGTH];
+fn rx_end_delay(phy: Phy) -> u32 {
+    match phy {
+        Phy::Le1M => 7,
+        Phy::Le2M => 4,
+        Phy::LeCodedS2 => 24,
+        Phy::LeCodedS8 => 30,
+    }
+}
+
+// Bits received before `receive_start` is called: the first header byte, or
+// the whole header and the AdvA when filtering on addresses
+const RX_START_BITS: u32 = 8;
+const RX_START_BITS_ADDRESS_FILTERING: u32 = 8 * 8;
+
+// Double buffered TX payload. The radio transmits from one buffer while new
+// content is staged in the other, and the two are only swapped at the start
+// of an advertising event, so a packet is never sent half updated and all
+// the packets of an event carry the same advertisement. In a connection the
+// link layer stages the PDU of every response, which is taken T_IFS after
+// the packet it answers.
+static mut TX_PAYLOAD: [[u8; nrf5x::constants::RADIO_PAYLOAD_LENGTH]; 2] =
+    [[0x00; nrf5x::constants::RADIO_PAYLOAD_LENGTH]; 2];
 
 static mut RX_PAYLOAD: [u8; nrf5x::constants::RADIO_PAYLOAD_LENGTH] =
     [0x00; nrf5x::constants::RADIO_PAYLOAD_LENGTH];
 
-pub struct Radio {
+// SCAN_RSP sent in reply to a matching SCAN_REQ. Kept apart from TX_PAYLOAD
+// so the advertising payload does not have to be swapped out and back
+// within the T_IFS after the request.
+static mut SCAN_RSP_PAYLOAD: [u8; nrf5x::constants::RADIO_PAYLOAD_LENGTH] =
+    [0x00; nrf5x::constants::RADIO_PAYLOAD_LENGTH];
+
+// Packets of encrypted connections in the layout of the CCM: header, length,
+// a byte for S1 and the payload. The ciphertext has a MIC after the payload.
+const CCM_HEADER_LENGTH: usize = 3;
+const CCM_MAX_PAYLOAD: usize = 27;
+static mut CCM_TX_IN: [u8; CCM_HEADER_LENGTH + CCM_MAX_PAYLOAD] =
+    [0x00; CCM_HEADER_LENGTH + CCM_MAX_PAYLOAD];
+static mut CCM_TX_OUT: [u8; CCM_HEADER_LENGTH + CCM_MAX_PAYLOAD + ccm::MIC_LENGTH] =
+    [0x00; CCM_HEADER_LENGTH + CCM_MAX_PAYLOAD + ccm::MIC_LENGTH];
+static mut CCM_RX_IN: [u8; CCM_HEADER_LENGTH + nrf5x::constants::RADIO_PAYLOAD_LENGTH] =
+    [0x00; CCM_HEADER_LENGTH + nrf5x::constants::RADIO_PAYLOAD_LENGTH];
+static mut CCM_RX_OUT: [u8; CCM_HEADER_LENGTH + nrf5x::constants::RADIO_PAYLOAD_LENGTH] =
+    [0x00; CCM_HEADER_LENGTH + nrf5x::constants::RADIO_PAYLOAD_LENGTH];
+
+fn data_rate(phy: Phy) -> DataRate {
+    match phy {
+        Phy::Le1M => DataRate::Rate1Mbit,
+        Phy::Le2M => DataRate::Rate2Mbit,
+        Phy::LeCodedS2 => DataRate::Rate500Kbps,
+        Phy::LeCodedS8 => DataRate::Rate125Kbps,
+    }
+}
+
+/// Everything the radio state machine touches outside of its own memory:
+/// the radio registers, the TIMER0 compares transitions are scheduled and
+/// timestamped with, the PPI channels wiring the two, the high frequency
+/// clock and the CCM.
+///
+/// `Nrf52RadioHardware` drives the peripherals of the chip. With the
+/// `radio_mock` feature, `radio_mock::MockRadioHardware` stands in for them
+/// with a register block in RAM, so the state machine can be run against
+/// scripted radio events.
+pub trait RadioHardware {
+    fn regs(&self) -> &RadioRegisters;
+
+    /// Enable the radio interrupts in `mask`
+    fn enable_interrupts(&self, mask: u32);
+    /// Disable the radio interrupts in `mask`
+    fn disable_interrupts(&self, mask: u32);
+    /// Bitmask of the radio interrupts enabled
+    fn enabled_interrupts(&self) -> u32;
+
+    /// Current time of the radio timer, in µs
+    fn now(&self) -> u32;
+    fn compare(&self, cc: usize) -> u32;
+    fn set_compare(&self, cc: usize, time: u32);
+    fn compare_fired(&self, cc: usize) -> bool;
+    fn clear_compare_event(&self, cc: usize);
+    fn reserve_compare(&self, cc: usize) -> ReturnCode;
+    /// Run the radio timer in µs over the full 32 bits
+    fn start_timer(&self);
+
+    fn enable_ppi(&self, channels: FieldValue<u32, ppi::Channel::Register>);
+    fn disable_ppi(&self, channels: FieldValue<u32, ppi::Channel::Register>);
+    /// Bitmask of the PPI channels enabled, bit `n` for channel `n`
+    fn enabled_ppi(&self) -> u32;
+    fn reserve_ppi(&self, channel: usize) -> ReturnCode;
+
+    /// Start the high frequency crystal, blocking until it runs
+    fn hfclk_request(&self);
+    fn hfclk_release(&self);
+
+    fn ccm_enable(&self, key: &[u8; 16], iv: &[u8; 8]);
+    fn ccm_disable(&self);
+    /// Encrypt `input`, in the CCM layout, to `output`. False if the CCM
+    /// failed.
+    fn ccm_encrypt(
+        &self,
+        input: &[u8],
+        output: &mut [u8],
+        counter: u64,
+        direction: Direction,
+        rate: DataRate,
+    ) -> bool;
+    /// Have the CCM decrypt the packet the radio receives to `input`, once
+    /// started through PPI
+    fn ccm_prepare_decrypt(
+        &self,
+        input: &[u8],
+        output: &mut [u8],
+        counter: u64,
+        direction: Direction,
+        rate: DataRate,
+    );
+    /// Wait for the decryption to end. False if the MIC did not match.
+    fn ccm_decrypt_done(&self) -> bool;
+}
+
+/// The radio, TIMER0, PPI, clock and CCM peripherals of the nRF52
+pub struct Nrf52RadioHardware {
     regs: *const RadioRegisters,
+}
+
+impl Nrf52RadioHardware {
+    pub const fn new() -> Nrf52RadioHardware {
+        Nrf52RadioHardware {
+            regs: RADIO_BASE as *const RadioRegisters,
+        }
+    }
+}
+
+impl RadioHardware for Nrf52RadioHardware {
+    fn regs(&self) -> &RadioRegisters {
+        unsafe { &*self.regs }
+    }
+
+    fn enable_interrupts(&self, mask: u32) {
+        self.regs().intenset.set(mask);
+    }
+
+    fn disable_interrupts(&self, mask: u32) {
+        self.regs().intenclr.set(mask);
+    }
+
+    fn enabled_interrupts(&self) -> u32 {
+        self.regs().intenclr.get()
+    }
+
+    fn now(&self) -> u32 {
+        unsafe { nrf5x::timer::TIMER0.capture(3) }
+    }
+
+    fn compare(&self, cc: usize) -> u32 {
+        unsafe { nrf5x::timer::TIMER0.get_cc(cc as u8) }
+    }
+
+    fn set_compare(&self, cc: usize, time: u32) {
+        unsafe {
+            match cc {
+                0 => nrf5x::timer::TIMER0.set_cc0(time),
+                1 => nrf5x::timer::TIMER0.set_cc1(time),
+                2 => nrf5x::timer::TIMER0.set_cc2(time),
+                _ => nrf5x::timer::TIMER0.set_cc3(time),
+            }
+        }
+    }
+
+    fn compare_fired(&self, cc: usize) -> bool {
+        unsafe { nrf5x::timer::TIMER0.events_compare()[cc].get() != 0 }
+    }
+
+    fn clear_compare_event(&self, cc: usize) {
+        unsafe { nrf5x::timer::TIMER0.events_compare()[cc].set(0) }
+    }
+
+    fn reserve_compare(&self, cc: usize) -> ReturnCode {
+        unsafe { nrf5x::timer::TIMER0.reserve_cc(cc) }
+    }
+
+    fn start_timer(&self) {
+        unsafe {
+            nrf5x::timer::TIMER0.set_prescaler(4);
+            nrf5x::timer::TIMER0.set_bitmode(BitmodeValue::Size32Bits);
+            nrf5x::timer::TIMER0.start();
+        }
+    }
+
+    fn enable_ppi(&self, channels: FieldValue<u32, ppi::Channel::Register>) {
+        unsafe { ppi::PPI.enable(channels) }
+    }
+
+    fn disable_ppi(&self, channels: FieldValue<u32, ppi::Channel::Register>) {
+        unsafe { ppi::PPI.disable(channels) }
+    }
+
+    fn enabled_ppi(&self) -> u32 {
+        unsafe { ppi::PPI.enabled_channels() }
+    }
+
+    fn reserve_ppi(&self, channel: usize) -> ReturnCode {
+        unsafe { nrf5x::ppi::PPI.reserve(channel) }
+    }
+
+    fn hfclk_request(&self) {
+        unsafe { clock::CLOCK.request(clock::ClockDomain::High) }
+    }
+
+    fn hfclk_release(&self) {
+        unsafe { clock::CLOCK.release(clock::ClockDomain::High) }
+    }
+
+    fn ccm_enable(&self, key: &[u8; 16], iv: &[u8; 8]) {
+        unsafe {
+            ccm::CCM.enable();
+            ccm::CCM.set_key(key, iv);
+        }
+    }
+
+    fn ccm_disable(&self) {
+        unsafe { ccm::CCM.disable() }
+    }
+
+    fn ccm_encrypt(
+        &self,
+        input: &[u8],
+        output: &mut [u8],
+        counter: u64,
+        direction: Direction,
+        rate: DataRate,
+    ) -> bool {
+        unsafe { ccm::CCM.encrypt(input, output, counter, direction, rate) }
+    }
+
+    fn ccm_prepare_decrypt(
+        &self,
+        input: &[u8],
+        output: &mut [u8],
+        counter: u64,
+        direction: Direction,
+        rate: DataRate,
+    ) {
+        unsafe { ccm::CCM.prepare_decrypt(input, output, counter, direction, rate) }
+    }
+
+    fn ccm_decrypt_done(&self) -> bool {
+        unsafe { ccm::CCM.decrypt_done() }
+    }
+}
+
+pub struct Radio<H = Nrf52RadioHardware> {
+    hw: H,
     tx_power: Cell<TxPower>,
+    /// Ceiling on `tx_power`, set while the chip runs hot
+    tx_power_limit: Cell<Option<TxPower>>,
+    /// Wait for the AdvA before `receive_start`, and keep listening after a
+    /// skipped frame
+    address_filtering: Cell<bool>,
+    /// Time the current receive window closes
+    rx_window_end: Cell<u32>,
     rx_client: Cell<Option<&'static ble_advertising_hil::RxClient>>,
     tx_client: Cell<Option<&'static ble_advertising_hil::TxClient>>,
     advertisement_client: Cell<Option<&'static ble_advertising_hil::AdvertisementClient>>,
     state: Cell<RadioState>,
-    channel: Cell<Option<RadioChannel>>,
+    context: Cell<Option<RadioContext>>,
     prev_rx_t0: Cell<u32>,
     debug_bit: Cell<bool>,
     debug_value: Cell<u8>,
     address_receive_time: Cell<Option<u32>>,
+    last_transition: Cell<PhyTransition>,
+    late_transitions: Cell<usize>,
+    /// Value written to CC[2] when the radio was last started, CC[2] still
+    /// holding it means the END event did not capture
+    end_capture_mark: Cell<u32>,
+    /// Time of the END event of the last packet, captured or not
+    packet_end_time: Cell<u32>,
+    stale_end_captures: Cell<usize>,
+    /// End on air of the packet the pending transmission answers within
+    /// T_IFS, for the conformance counters
+    tifs_from: Cell<Option<u32>>,
+    /// The pending transmission missed its deadline and was started by hand
+    tx_late: Cell<bool>,
+    /// Index of the `TX_PAYLOAD` buffer the radio transmits from
+    tx_payload: Cell<usize>,
+    /// New content is waiting in the other `TX_PAYLOAD` buffer
+    tx_payload_staged: Cell<bool>,
+    /// The frame being received may be a SCAN_REQ to answer
+    scan_request_pending: Cell<bool>,
+    /// The next or current transmission is the scan response
+    tx_scan_response: Cell<bool>,
+    /// PHYs used on data channels
+    tx_phy: Cell<Phy>,
+    rx_phy: Cell<Phy>,
+    /// Whether the radio holds a request on the high frequency crystal
+    hfclk_requested: Cell<bool>,
+    /// Keys and packet counters of the connection, if it is encrypted
+    encryption: Cell<Option<Encryption>>,
+    /// The packet being received goes to `CCM_RX_IN`, and is decrypted if
+    /// true
+    rx_ccm: Cell<Option<bool>>,
 }
 
-#[derive(PartialEq, Copy, Clone)]
+#[derive(PartialEq, Debug, Copy, Clone)]
 enum RadioState {
     TX,
     RX,
@@ -97,40 +435,111 @@ pub static mut RADIO: Radio = Radio::new();
 
 impl Radio {
     pub const fn new() -> Radio {
+        Radio::with_hardware(Nrf52RadioHardware::new())
+    }
+}
+
+impl<H> Radio<H> {
+    pub const fn with_hardware(hw: H) -> Radio<H> {
         Radio {
-            regs: RADIO_BASE as *const RadioRegisters,
+            hw: hw,
             tx_power: Cell::new(TxPower::ZerodBm),
+            tx_power_limit: Cell::new(None),
+            address_filtering: Cell::new(false),
+            rx_window_end: Cell::new(0),
             rx_client: Cell::new(None),
             tx_client: Cell::new(None),
             advertisement_client: Cell::new(None),
             state: Cell::new(RadioState::Uninitialized),
-            channel: Cell::new(None),
+            context: Cell::new(None),
             prev_rx_t0: Cell::new(0),
             debug_bit: Cell::new(false),
             debug_value: Cell::new(0),
             address_receive_time: Cell::new(None),
+            last_transition: Cell::new(PhyTransition::None),
+            late_transitions: Cell::new(0),
+            end_capture_mark: Cell::new(0),
+            packet_end_time: Cell::new(0),
+            stale_end_captures: Cell::new(0),
+            tifs_from: Cell::new(None),
+            tx_late: Cell::new(false),
+            tx_payload: Cell::new(0),
+            tx_payload_staged: Cell::new(false),
+            scan_request_pending: Cell::new(false),
+            tx_scan_response: Cell::new(false),
+            tx_phy: Cell::new(Phy::Le1M),
+            rx_phy: Cell::new(Phy::Le1M),
+            hfclk_requested: Cell::new(false),
+            encryption: Cell::new(None),
+            rx_ccm: Cell::new(None),
         }
     }
 
+    /// The peripherals the radio drives
+    pub fn hardware(&self) -> &H {
+        &self.hw
+    }
+}
+
+impl<H: RadioHardware> Radio<H> {
+    /// Print the driver and hardware state of the radio, the PPI channels and
+    /// the TIMER0 compares it schedules transitions with, in one go.
+    pub fn dump_state(&self) {
+        let regs = self.hw.regs();
+        let fired = |cc| if self.hw.compare_fired(cc) { "fired" } else { "pending" };
+
+        debug!(
+            "radio: state {:?} hw state {} context {:?} last transition {:?} late {} stale {}",
+            self.state.get(),
+            regs.state.get(),
+            self.context.get(),
+            self.last_transition.get(),
+            self.late_transitions.get(),
+            self.stale_end_captures.get()
+        );
+        debug!(
+            "radio: intenset {:#x} shorts {:#x} ppi chen {:#010x}",
+            self.hw.enabled_interrupts(),
+            regs.shorts.get(),
+            self.hw.enabled_ppi()
+        );
+        debug!(
+            "radio: timer0 now {} cc0 {} ({}) cc1 {} ({}) cc2 {} ({})",
+            self.hw.now(),
+            self.hw.compare(0),
+            fired(0),
+            self.hw.compare(1),
+            fired(1),
+            self.hw.compare(2),
+            fired(2)
+        );
+    }
+
     pub fn tx(&self) {
-        let regs = unsafe { &*self.regs };
+        let regs = self.hw.regs();
 
         self.wait_until_disabled();
 
         self.setup_tx();
+        self.tx_late.set(false);
 
         regs.task_txen.set(1);
     }
 
     fn setup_tx(&self) {
-        let regs = unsafe { &*self.regs };
+        let regs = self.hw.regs();
+
+        self.hfclk_request();
 
         self.set_dma_ptr_tx();
         self.state.set(RadioState::TX);
+        trace::record(Event::TxStart);
+        self.ble_set_phy(self.phy(self.tx_phy.get()));
 
         regs.event_ready.set(0);
         regs.event_end.set(0);
         regs.event_disabled.set(0);
+        self.invalidate_end_capture();
 
         regs.shorts.set(
             nrf5x::constants::RADIO_SHORTS_END_DISABLE | nrf5x::constants::RADIO_SHORTS_READY_START,
@@ -140,7 +549,9 @@ impl Radio {
     }
 
     fn setup_rx(&self) {
-        let regs = unsafe { &*self.regs };
+        let regs = self.hw.regs();
+
+        self.hfclk_request();
 
         self.set_dma_ptr_rx();
 
@@ -148,25 +559,34 @@ impl Radio {
         self.disable_ppi(ppi::Channel::CH20::SET);
 
         self.state.set(RadioState::RX);
+        trace::record(Event::RxStart);
+        self.ble_set_phy(self.phy(self.rx_phy.get()));
 
-        regs.bcc.set(8); // count one byte
+        regs.bcc.set(if self.address_filtering.get() {
+            RX_START_BITS_ADDRESS_FILTERING
+        } else {
+            RX_START_BITS
+        });
 
         regs.event_address.set(0);
         regs.event_devmatch.set(0);
         regs.event_bcmatch.set(0);
         regs.event_rssiend.set(0);
         regs.event_crcok.set(0);
+        self.invalidate_end_capture();
 
+        // The RSSI is sampled once, right after the access address
         regs.shorts.set(
             nrf5x::constants::RADIO_SHORTS_END_DISABLE | nrf5x::constants::RADIO_SHORTS_READY_START
-                | nrf5x::constants::RADIO_SHORTS_ADDRESS_BCSTART,
+                | nrf5x::constants::RADIO_SHORTS_ADDRESS_BCSTART
+                | nrf5x::constants::RADIO_SHORTS_ADDRESS_RSSISTART,
         );
 
         self.enable_interrupt(nrf5x::constants::RADIO_INTENSET_ADDRESS);
     }
 
     fn wait_until_disabled(&self) {
-        let regs = unsafe { &*self.regs };
+        let regs = self.hw.regs();
 
         let state = regs.state.get();
 
@@ -182,7 +602,7 @@ impl Radio {
     }
 
     pub fn rx(&self) {
-        let regs = unsafe { &*self.regs };
+        let regs = self.hw.regs();
 
         self.wait_until_disabled();
         self.disable_all_interrupts();
@@ -196,49 +616,190 @@ impl Radio {
     }
 
     fn set_rx_address(&self) {
-        let regs = unsafe { &*self.regs };
+        let regs = self.hw.regs();
         regs.rxaddresses.set(0x01);
     }
 
     fn set_tx_address(&self) {
-        let regs = unsafe { &*self.regs };
+        let regs = self.hw.regs();
         regs.txaddress.set(0x00);
     }
 
     fn radio_on(&self) {
-        let regs = unsafe { &*self.regs };
+        let regs = self.hw.regs();
         // reset and enable power
         regs.power.set(0);
         regs.power.set(1);
     }
 
     fn radio_off(&self) {
-        let regs = unsafe { &*self.regs };
+        let regs = self.hw.regs();
         regs.shorts.set(0);
         regs.power.set(0);
     }
 
+    // Start the crystal, blocking until it runs, unless the radio already
+    // holds it
+    fn hfclk_request(&self) {
+        if !self.hfclk_requested.get() {
+            self.hfclk_requested.set(true);
+            self.hw.hfclk_request();
+        }
+    }
+
+    // Let the crystal stop until the next event
+    fn hfclk_release(&self) {
+        if self.hfclk_requested.get() {
+            self.hfclk_requested.set(false);
+            self.hw.hfclk_release();
+        }
+    }
+
     fn set_tx_power(&self) {
-        let regs = unsafe { &*self.regs };
-        regs.txpower.set(self.tx_power.get() as u32);
+        let regs = self.hw.regs();
+        regs.txpower.set(self.effective_tx_power() as u32);
+    }
+
+    /// TX power requested by the BLE driver, before any limit
+    pub fn tx_power(&self) -> TxPower {
+        self.tx_power.get()
+    }
+
+    /// The requested TX power, lowered to the limit if there is one
+    pub fn effective_tx_power(&self) -> TxPower {
+        let tx_power = self.tx_power.get();
+        match self.tx_power_limit.get() {
+            Some(limit) if limit.dbm() < tx_power.dbm() => limit,
+            _ => tx_power,
+        }
+    }
+
+    /// Cap the TX power at `limit` whatever the BLE driver asks for, or lift
+    /// the cap. Takes effect from the next transmission on.
+    pub fn set_tx_power_limit(&self, limit: Option<TxPower>) {
+        self.tx_power_limit.set(limit);
+        if self.state.get() != RadioState::Uninitialized {
+            self.set_tx_power();
+        }
     }
 
     fn set_tifs(&self) {
-        let regs = unsafe { &*self.regs };
+        let regs = self.hw.regs();
         regs.tifs.set(150 as u32);
     }
 
     fn set_dma_ptr_tx(&self) {
-        let regs = unsafe { &*self.regs };
+        let regs = self.hw.regs();
+        unsafe {
+            if self.tx_scan_response.get() {
+                regs.packetptr.set((&SCAN_RSP_PAYLOAD as *const u8) as u32);
+            } else if let Some(encryption) = self.ccm_encryption() {
+                self.set_dma_ptr_ccm_tx(encryption);
+            } else {
+                regs.packetptr
+                    .set((&TX_PAYLOAD[self.tx_payload.get()] as *const u8) as u32);
+            }
+        }
+    }
+
+    // Lay the packet to send out for the CCM, and encrypt it if the
+    // connection is. Empty PDUs are never encrypted. Should the CCM fail, an
+    // empty PDU goes out instead so that nothing is sent in the clear.
+    fn set_dma_ptr_ccm_tx(&self, encryption: Encryption) {
+        let regs = self.hw.regs();
         unsafe {
-            regs.packetptr.set((&TX_PAYLOAD as *const u8) as u32);
+            let packet = &TX_PAYLOAD[self.tx_payload.get()];
+            let len = cmp::min(packet[1] as usize, CCM_MAX_PAYLOAD);
+            CCM_TX_IN[0] = packet[0];
+            CCM_TX_IN[1] = len as u8;
+            CCM_TX_IN[2] = 0;
+            CCM_TX_IN[CCM_HEADER_LENGTH..CCM_HEADER_LENGTH + len]
+                .copy_from_slice(&packet[2..2 + len]);
+
+            if encryption.tx && len > 0 {
+                let rate = data_rate(self.phy(self.tx_phy.get()));
+                let counter = encryption.tx_counter;
+                let direction = Direction::SlaveToMaster;
+                if self.hw
+                    .ccm_encrypt(&CCM_TX_IN, &mut CCM_TX_OUT, counter, direction, rate) {
+                    regs.packetptr.set((&CCM_TX_OUT as *const u8) as u32);
+                    return;
+                }
+                CCM_TX_IN[0] = (CCM_TX_IN[0] & !0b11) | 0x01;
+                CCM_TX_IN[1] = 0;
+            }
+            regs.packetptr.set((&CCM_TX_IN as *const u8) as u32);
         }
     }
 
     fn set_dma_ptr_rx(&self) {
-        let regs = unsafe { &*self.regs };
+        let regs = self.hw.regs();
+        // CH24: RADIO.EVENTS_READY -> CCM.TASKS_KSGEN
+        // CH25: RADIO.EVENTS_ADDRESS -> CCM.TASKS_CRYPT
+        self.disable_ppi(ppi::Channel::CH24::SET + ppi::Channel::CH25::SET);
+        unsafe {
+            match self.ccm_encryption() {
+                Some(encryption) => {
+                    regs.packetptr.set((&CCM_RX_IN as *const u8) as u32);
+                    if encryption.rx {
+                        let rate = data_rate(self.phy(self.rx_phy.get()));
+                        let counter = encryption.rx_counter;
+                        self.hw.ccm_prepare_decrypt(
+                            &CCM_RX_IN,
+                            &mut CCM_RX_OUT,
+                            counter,
+                            Direction::MasterToSlave,
+                            rate,
+                        );
+                        self.enable_ppi(ppi::Channel::CH24::SET + ppi::Channel::CH25::SET);
+                    }
+                    self.rx_ccm.set(Some(encryption.rx));
+                }
+                None => {
+                    regs.packetptr.set((&RX_PAYLOAD as *const u8) as u32);
+                    self.rx_ccm.set(None);
+                }
+            }
+        }
+    }
+
+    // Copy a packet received in the CCM layout to RX_PAYLOAD, decrypted if
+    // the connection is encrypted. Returns false if it was decrypted and its
+    // MIC did not match, in which case it is copied as received.
+    fn unpack_ccm_rx(&self, crc_ok: bool) -> bool {
+        let decrypting = match self.rx_ccm.take() {
+            Some(decrypting) => decrypting,
+            None => return true,
+        };
+        self.disable_ppi(ppi::Channel::CH24::SET + ppi::Channel::CH25::SET);
         unsafe {
-            regs.packetptr.set((&RX_PAYLOAD as *const u8) as u32);
+            let encrypted = decrypting && CCM_RX_IN[1] > 0;
+            let mic_ok = !encrypted || self.hw.ccm_decrypt_done();
+            let packet = if encrypted && mic_ok {
+                &CCM_RX_OUT
+            } else {
+                &CCM_RX_IN
+            };
+            let len = cmp::min(
+                packet[1] as usize,
+                nrf5x::constants::RADIO_PAYLOAD_LENGTH - 2,
+            );
+            RX_PAYLOAD[0] = packet[0];
+            RX_PAYLOAD[1] = len as u8;
+            RX_PAYLOAD[2..2 + len]
+                .copy_from_slice(&packet[CCM_HEADER_LENGTH..CCM_HEADER_LENGTH + len]);
+            // The MIC of a packet with a bad CRC is meaningless
+            mic_ok || !crc_ok
+        }
+    }
+
+    // The keys of the connection, if packets on the current channel go
+    // through the CCM
+    fn ccm_encryption(&self) -> Option<Encryption> {
+        if self.on_data_channel() {
+            self.encryption.get()
+        } else {
+            None
         }
     }
 
@@ -258,37 +819,65 @@ impl Radio {
     }
 
     fn set_cc0(&self, usec: u32) {
-        unsafe {
-            nrf5x::timer::TIMER0.set_cc0(usec);
-            nrf5x::timer::TIMER0.events_compare()[0].set(0);
-        }
+        self.hw.set_compare(0, usec);
+        self.hw.clear_compare_event(0);
     }
 
     fn schedule_tx_after_us(&self, delay: DelayStartPoint) {
+        let regs = self.hw.regs();
+        // The response of a connection was staged for this exchange. A
+        // scan response has a buffer of its own and the advertisement stays.
+        if self.in_connection() && !self.tx_scan_response.get() {
+            self.swap_staged_payload();
+        }
         self.setup_tx();
 
+        // T_IFS runs from the end of the packet received on air, which the
+        // END event lags by the decoding delay of the receiving PHY
+        self.tifs_from.set(match delay {
+            DelayStartPoint::PacketEndBLEStandardDelay => Some(
+                self.get_packet_end_time_value()
+                    .wrapping_sub(rx_end_delay(self.phy(self.rx_phy.get()))),
+            ),
+            _ => None,
+        });
         let t0 = self.get_packet_time_value_with_delay(delay);
-        let time = t0 - NRF52_DISABLE_TX_DELAY;
+        let time = t0 - rx_end_delay(self.phy(self.rx_phy.get())) - NRF52_FAST_RAMPUP_TIME_TX
+            - NRF52_TX_DELAY;
 
         self.set_cc0(time);
 
         // CH20: CC[0] => TXEN
         self.enable_ppi(ppi::Channel::CH20::SET);
+
+        let late = self.deadline_missed(time);
+        self.tx_late.set(late);
+        if late {
+            self.disable_ppi(ppi::Channel::CH20::SET);
+            regs.task_txen.set(1);
+        }
     }
 
     fn schedule_rx_after_us(&self, delay: DelayStartPoint, timeout: u32) {
+        let regs = self.hw.regs();
         self.setup_rx();
 
         let earlier_listen: u32 = 2;
         let t0 = self.get_packet_time_value_with_delay(delay.clone());
         self.prev_rx_t0.set(t0);
-        let time = t0 - NRF52_DISABLE_RX_DELAY - earlier_listen;
+        let time = t0 - tx_end_delay(self.phy(self.tx_phy.get())) - NRF52_FAST_RAMPUP_TIME_TX
+            - earlier_listen;
 
         self.set_cc0(time);
 
         // CH21: CC[0] => RXEN
         self.enable_ppi(ppi::Channel::CH21::SET);
 
+        if self.deadline_missed(time) {
+            self.disable_ppi(ppi::Channel::CH21::SET);
+            regs.task_rxen.set(1);
+        }
+
         self.set_rx_timeout(t0 + timeout);
 
         if self.debug_bit.get() {
@@ -296,11 +885,30 @@ impl Radio {
         }
     }
 
-    fn set_rx_timeout(&self, usec: u32) {
-        unsafe {
-            nrf5x::timer::TIMER0.set_cc1(usec);
-            nrf5x::timer::TIMER0.events_compare()[1].set(0);
+    // If the CPU was delayed and TIMER0 already passed `deadline` (CC[0])
+    // before the PPI channel was enabled, the compare event never reaches
+    // the radio and the exchange hangs. The radio still being disabled after
+    // the deadline means the transition was missed; the caller then starts
+    // it by hand, late, instead of waiting forever.
+    fn deadline_missed(&self, deadline: u32) -> bool {
+        let regs = self.hw.regs();
+        let now = self.hw.now();
+
+        // Timestamps wrap, the deadline has passed if it is less than half
+        // the timer range behind `now`
+        let passed = (now.wrapping_sub(deadline) as i32) >= 0;
+        let missed = passed && regs.state.get() == nrf5x::constants::RADIO_STATE_DISABLE;
+        if missed {
+            self.late_transitions.set(self.late_transitions.get() + 1);
+            trace::record(Event::DeadlineMissed);
         }
+        missed
+    }
+
+    fn set_rx_timeout(&self, usec: u32) {
+        self.rx_window_end.set(usec);
+        self.hw.set_compare(1, usec);
+        self.hw.clear_compare_event(1);
 
         // CH22: CC[0] => TASK_DISABLE
         // CH26: EVENTS_ADDRESS -> CC[1]
@@ -309,7 +917,7 @@ impl Radio {
     }
 
     fn disable_radio(&self) {
-        let regs = unsafe { &*self.regs };
+        let regs = self.hw.regs();
 
         self.disable_all_interrupts();
 
@@ -318,16 +926,18 @@ impl Radio {
         self.disable_ppi(
             ppi::Channel::CH20::SET + ppi::Channel::CH21::SET
         );
+        self.invalidate_end_capture();
         self.state.set(RadioState::Initialized);
     }
 
     fn handle_address_event(&self) -> bool {
-        let regs = unsafe { &*self.regs };
+        let regs = self.hw.regs();
         regs.event_address.set(0);
         self.disable_ppi(ppi::Channel::CH22::SET);
+        trace::record(Event::Address);
 
         self.address_receive_time
-            .set(Some(unsafe { nrf5x::timer::TIMER0.get_cc1() }));
+            .set(Some(self.hw.compare(1)));
 
         self.clear_interrupt(
             nrf5x::constants::RADIO_INTENSET_DISABLED | nrf5x::constants::RADIO_INTENSET_ADDRESS,
@@ -347,6 +957,14 @@ impl Radio {
             }
         }
 
+        if self.rx_ccm.get().is_some() {
+            // The header of a packet in the CCM layout is never encrypted
+            unsafe {
+                RX_PAYLOAD[0] = CCM_RX_IN[0];
+                RX_PAYLOAD[1] = CCM_RX_IN[1];
+            }
+        }
+
         if let Some(client) = self.rx_client.get() {
             let result = unsafe { client.receive_start(&mut RX_PAYLOAD, RX_PAYLOAD[1] + 2) };
 
@@ -355,6 +973,14 @@ impl Radio {
                     // We want to read packet, enable interrupt on EVENT_END
                     self.enable_interrupt(nrf5x::constants::RADIO_INTENSET_END);
                 }
+                ReadAction::ReadFrameAndMoveToTX => {
+                    // Whether to respond is decided once the AdvA is in
+                    self.scan_request_pending.set(true);
+                    self.enable_interrupt(nrf5x::constants::RADIO_INTENSET_END);
+                }
+                ReadAction::SkipFrame if self.address_filtering.get() => {
+                    self.resume_rx();
+                }
                 ReadAction::SkipFrame => {
                     self.disable_radio();
 
@@ -368,29 +994,85 @@ impl Radio {
         return true;
     }
 
+    // Drop the frame being received and listen on the same channel until the
+    // receive window closes.
+    fn resume_rx(&self) {
+        let regs = self.hw.regs();
+        self.disable_radio();
+        self.rx();
+
+        let window_end = self.rx_window_end.get();
+        self.set_rx_timeout(window_end);
+        let now = self.hw.now();
+        if (now.wrapping_sub(window_end) as i32) >= 0 {
+            // The compare event went by before CH22 was enabled, close the
+            // window by hand
+            regs.task_disable.set(1);
+        }
+    }
+
+    // Signal strength of the packet just received, in dBm. 0 if the sample
+    // was not taken.
+    fn rssi(&self) -> i8 {
+        let regs = self.hw.regs();
+        if regs.event_rssiend.get() == 0 {
+            return 0;
+        }
+        regs.event_rssiend.set(0);
+        -(regs.rssisample.read(RssiSample::RSSISAMPLE) as i8)
+    }
+
     fn handle_rx_end_event(&self) {
-        let regs = unsafe { &*self.regs };
+        let regs = self.hw.regs();
         regs.event_end.set(0);
+        self.latch_packet_end_time();
 
         self.clear_interrupt(nrf5x::constants::RADIO_INTENSET_END);
 
         // CH21: TIMER0.EVENTS_COMPARE[0] -> RADIO.RXEN
         self.disable_ppi(ppi::Channel::CH21::SET);
-        let crc_ok = if regs.event_crcok.get() == 1 {
-            ReturnCode::SUCCESS
-        } else {
-            ReturnCode::FAIL
-        };
+        let crc_ok = regs.event_crcok.get() == 1;
+        unsafe { CONFORMANCE.crc(crc_ok) };
+        trace::record(Event::RxEnd { crc_ok });
+        let mic_ok = self.unpack_ccm_rx(crc_ok);
+
+        // Answer a SCAN_REQ for our AdvA right away, the client is only told
+        // afterwards so its bookkeeping does not eat into the T_IFS
+        let responding =
+            self.scan_request_pending.get() && crc_ok && self.scan_request_matches();
+        self.scan_request_pending.set(false);
+        if responding {
+            self.tx_scan_response.set(true);
+            self.schedule_tx_after_us(DelayStartPoint::PacketEndBLEStandardDelay);
+        }
+
+        let rssi = self.rssi();
 
         if let Some(client) = self.rx_client.get() {
-            let result = unsafe {
-                client.receive_end(
-                    &mut RX_PAYLOAD,
-                    RX_PAYLOAD[1] + 2,
-                    crc_ok,
-                    self.get_packet_address_time_value(),
-                )
+            let pdu = unsafe {
+                ReceivedPdu {
+                    len: RX_PAYLOAD[1] + 2,
+                    buf: &mut RX_PAYLOAD,
+                    crc_ok: crc_ok,
+                    rssi: rssi,
+                    channel: self.channel(),
+                    timestamp: self.get_packet_address_time_value(),
+                    mic_ok: mic_ok,
+                }
             };
+            let result = client.receive_end(pdu);
+            self.last_transition.set(result);
+
+            if responding {
+                if let PhyTransition::MoveToTX(_) = result {
+                    // Already scheduled
+                    return;
+                }
+                // The client turned the request down after all
+                self.tx_scan_response.set(false);
+                self.disable_radio();
+                self.wait_until_disabled();
+            }
 
             match result {
                 PhyTransition::MoveToTX(delay) => {
@@ -427,7 +1109,7 @@ impl Radio {
                 TxImmediate::RespondAfterTifs => {
                     self.schedule_tx_after_us(DelayStartPoint::PacketEndBLEStandardDelay)
                 }
-                TxImmediate::GoToSleep => {}
+                TxImmediate::GoToSleep => self.hfclk_release(),
             }
         } else {
             panic!("No advertisement client?");
@@ -435,20 +1117,45 @@ impl Radio {
     }
 
     fn handle_tx_end_event(&self) {
-        let regs = unsafe { &*self.regs };
+        let regs = self.hw.regs();
 
         regs.event_disabled.set(0);
         self.clear_interrupt(nrf5x::constants::RADIO_INTENSET_DISABLED);
+        // Without END the radio was disabled before the packet was out
+        let sent = regs.event_end.get() == 1;
         regs.event_end.set(0);
-
-        let crc_ok = if regs.event_crcok.get() == 1 {
-            ReturnCode::SUCCESS
+        trace::record(Event::TxEnd { sent });
+        if sent {
+            self.latch_packet_end_time();
+            if let Some(from) = self.tifs_from.get() {
+                self.record_tifs(from);
+            }
+        }
+        self.tifs_from.set(None);
+        self.tx_scan_response.set(false);
+
+        let info = if sent {
+            TxInfo {
+                status: if self.tx_late.get() {
+                    TxStatus::DeadlineMissed
+                } else {
+                    TxStatus::Sent
+                },
+                channel: self.channel(),
+                timestamp: self.get_packet_end_time_value(),
+            }
         } else {
-            ReturnCode::FAIL
+            TxInfo {
+                status: TxStatus::Aborted,
+                channel: self.channel(),
+                timestamp: self.hw.now(),
+            }
         };
+        self.tx_late.set(false);
 
         if let Some(client) = self.tx_client.get() {
-            let result = client.transmit_end(crc_ok);
+            let result = client.transmit_end(info);
+            self.last_transition.set(result);
 
             match result {
                 PhyTransition::MoveToTX(delay) => {
@@ -470,11 +1177,11 @@ impl Radio {
 
     #[inline(never)]
     pub fn handle_interrupt(&self) {
-        let regs = unsafe { &*self.regs };
+        let regs = self.hw.regs();
 
         // let current_time = unsafe {nrf5x::timer::TIMER0.capture(4) };
 
-        let mut enabled_interrupts = regs.intenclr.get();
+        let mut enabled_interrupts = self.hw.enabled_interrupts();
 
         if (enabled_interrupts & nrf5x::constants::RADIO_INTENSET_ADDRESS) > 0
             && regs.event_address.get() == 1
@@ -489,11 +1196,13 @@ impl Radio {
         {
             if self.state.get() == RadioState::RX {
                 regs.event_disabled.set(0);
+                trace::record(Event::RxTimeout);
 
                 //if self.debug_value.get() != 1 {
                 let transition = self.advertisement_client
                     .get()
                     .map_or(PhyTransition::None, |client| client.timer_expired());
+                self.last_transition.set(transition);
 
                 self.wait_until_disabled();
 
@@ -506,7 +1215,8 @@ impl Radio {
                         self.schedule_rx_after_us(delay, timeout);
                     }
                     PhyTransition::None => {
-                        //Do nothing, the device should sleep and wait for timer to fire in BLE
+                        // The device should sleep and wait for timer to fire in BLE
+                        self.hfclk_release();
                     }
                 }
             // }
@@ -525,36 +1235,78 @@ impl Radio {
     }
 
     pub fn enable_interrupts(&self) {
-        let regs = unsafe { &*self.regs };
-        regs.intenset.set(nrf5x::constants::RADIO_INTENSET_ADDRESS);
+        self.hw
+            .enable_interrupts(nrf5x::constants::RADIO_INTENSET_ADDRESS);
     }
 
     pub fn enable_interrupt(&self, intr: u32) {
-        let regs = unsafe { &*self.regs };
-        regs.intenset.set(intr);
+        self.hw.enable_interrupts(intr);
     }
 
     pub fn clear_interrupt(&self, intr: u32) {
-        let regs = unsafe { &*self.regs };
-        regs.intenclr.set(intr);
+        self.hw.disable_interrupts(intr);
     }
 
     pub fn disable_all_interrupts(&self) {
-        let regs = unsafe { &*self.regs };
         // disable all possible interrupts
-        regs.intenclr.set(0xffffffff);
+        self.hw.disable_interrupts(0xffffffff);
     }
 
+    /// Stage a new payload. It is copied to the buffer the radio is not
+    /// transmitting from and used from the next transmission on.
     pub fn replace_radio_buffer(&self, buf: &'static mut [u8], len: usize) -> &'static mut [u8] {
+        let staging = 1 - self.tx_payload.get();
         // set payload
         for (i, c) in buf.as_ref()[0..len].iter().enumerate() {
             unsafe {
-                TX_PAYLOAD[i] = *c;
+                TX_PAYLOAD[staging][i] = *c;
+            }
+        }
+        self.tx_payload_staged.set(true);
+        buf
+    }
+
+    /// Stage the SCAN_RSP PDU sent in reply to SCAN_REQs for its AdvA.
+    pub fn replace_scan_response_buffer(
+        &self,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> &'static mut [u8] {
+        for (i, c) in buf.as_ref()[0..len].iter().enumerate() {
+            unsafe {
+                SCAN_RSP_PAYLOAD[i] = *c;
             }
         }
         buf
     }
 
+    // A valid SCAN_REQ whose AdvA is the AdvA of the staged scan response.
+    // In a SCAN_REQ the AdvA follows the 6 byte ScanA.
+    fn scan_request_matches(&self) -> bool {
+        unsafe {
+            BLEAdvertisementType::from_u8(RX_PAYLOAD[0] & 0x0f)
+                == Some(BLEAdvertisementType::ScanRequest)
+                && BLEAdvertisementType::ScanRequest.validate_pdu(RX_PAYLOAD[1])
+                && RX_PAYLOAD[PACKET_PAYLOAD_START..PACKET_PAYLOAD_START + 6]
+                    == SCAN_RSP_PAYLOAD[PACKET_ADDR_START..PACKET_ADDR_START + 6]
+        }
+    }
+
+    fn in_connection(&self) -> bool {
+        self.context
+            .get()
+            .map_or(false, |context| context.access_address != ADV_ACCESS_ADDRESS_BLE)
+    }
+
+    // Only called while the radio is not transmitting: when an advertising
+    // event starts, and before the T_IFS response of a connection
+    fn swap_staged_payload(&self) {
+        if self.tx_payload_staged.get() {
+            self.tx_payload_staged.set(false);
+            self.tx_payload.set(1 - self.tx_payload.get());
+        }
+    }
+
     fn get_packet_address_time_value(&self) -> u32 {
         match self.address_receive_time.get() {
             Some(time) => time,
@@ -564,31 +1316,84 @@ impl Radio {
         }
     }
 
+    // CC[2] is only overwritten when CH27 captures an END event. After a
+    // radio reset, or a packet the radio was disabled in the middle of, it
+    // still holds the END time of an older packet, and scheduling T_IFS from
+    // it would put the next transition in the past or far in the future. So
+    // CC[2] is set to a mark whenever the radio is started, and an END event
+    // that finds the mark in place is timestamped in software instead, late
+    // by the interrupt latency but close.
+    fn invalidate_end_capture(&self) {
+        let now = self.hw.now();
+        self.hw.set_compare(2, now);
+        self.end_capture_mark.set(now);
+    }
+
+    // Called on the END event, before the radio is started again
+    fn latch_packet_end_time(&self) {
+        let captured = self.hw.compare(2);
+        if captured != self.end_capture_mark.get() {
+            self.packet_end_time.set(captured);
+        } else {
+            self.stale_end_captures
+                .set(self.stale_end_captures.get() + 1);
+            self.packet_end_time
+                .set(self.hw.now());
+        }
+    }
+
+    // The radio timer captures the END of the packet sent, its start is
+    // worked out from its length
+    fn record_tifs(&self, from: u32) {
+        let regs = self.hw.regs();
+        let phy = self.phy(self.tx_phy.get());
+        let payload_len = unsafe { *((regs.packetptr.get() as usize + 1) as *const u8) };
+        if let Some(air_time) = conformance::packet_air_time_us(phy, payload_len as u32) {
+            let start = self.get_packet_end_time_value()
+                .wrapping_sub(tx_end_delay(phy) + air_time);
+            unsafe { CONFORMANCE.tifs_sample(start.wrapping_sub(from)) };
+        }
+    }
+
     fn get_packet_end_time_value(&self) -> u32 {
-        unsafe { nrf5x::timer::TIMER0.get_cc2() }
+        self.packet_end_time.get()
     }
 
     fn enable_ppi(&self, pins: FieldValue<u32, ppi::Channel::Register>) {
-        unsafe {
-            ppi::PPI.enable(pins);
-        }
+        self.hw.enable_ppi(pins);
     }
 
     fn disable_ppi(&self, pins: FieldValue<u32, ppi::Channel::Register>) {
-        unsafe {
-            ppi::PPI.disable(pins);
+        self.hw.disable_ppi(pins);
+    }
+
+    fn reserve_ppi_channels(&self) {
+        for &channel in RADIO_PPI_CHANNELS.iter() {
+            if self.hw.reserve_ppi(channel) != ReturnCode::SUCCESS {
+                panic!("PPI channel {} used by the radio is already taken", channel);
+            }
+        }
+    }
+
+    fn reserve_timer_channels(&self) {
+        // CC0 starts the transmitter or receiver, CC1 times out receptions
+        // and captures ADDRESS, CC2 captures END and CC3 reads the time
+        for cc in 0..nrf5x::timer::NUM_CC {
+            if self.hw.reserve_compare(cc) != ReturnCode::SUCCESS {
+                panic!("TIMER0 CC{} used by the radio is already taken", cc);
+            }
         }
     }
 
     pub fn ble_initialize(&self) {
         if self.state.get() == RadioState::Uninitialized {
+            self.reserve_ppi_channels();
+            self.reserve_timer_channels();
             self.radio_on();
 
             self.ble_set_tx_power();
             self.set_tifs();
 
-            self.ble_set_channel_rate();
-
             self.set_tx_address();
             self.set_rx_address();
 
@@ -602,33 +1407,34 @@ impl Radio {
             // CH27: RADIO.EVENTS_END -> TIMER0.TASKS_CAPTURE[2]
             self.enable_ppi(ppi::Channel::CH26::SET + ppi::Channel::CH27::SET);
         }
-        unsafe {
-            nrf5x::timer::TIMER0.set_prescaler(4);
-            nrf5x::timer::TIMER0.set_bitmode(BitmodeValue::Size32Bits);
-            nrf5x::timer::TIMER0.start();
-        }
+        self.hw.start_timer();
     }
 
     // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 3.1.1 CRC Generation
     fn ble_set_crc_config(&self) {
-        let regs = unsafe { &*self.regs };
+        let regs = self.hw.regs();
         regs.crccnf.set(
             nrf5x::constants::RADIO_CRCCNF_SKIPADDR << nrf5x::constants::RADIO_CRCCNF_SKIPADDR_POS
                 | nrf5x::constants::RADIO_CRCCNF_LEN_3BYTES,
         );
         self.ble_set_crcinit(nrf5x::constants::RADIO_CRCINIT_BLE);
         regs.crcpoly.set(nrf5x::constants::RADIO_CRCPOLY_BLE);
+
+        // A context set before the radio was initialized still holds
+        if let Some(context) = self.context.get() {
+            self.ble_set_context(context);
+        }
     }
 
     fn ble_set_crcinit(&self, crcinit: u32) {
-        let regs = unsafe { &*self.regs };
+        let regs = self.hw.regs();
         regs.crcinit.set(crcinit);
     }
 
     // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.1.2 Access Address
     // Set access address to 0x8E89BED6
-    pub fn ble_set_access_address(&self, aa: u32) {
-        let regs = unsafe { &*self.regs };
+    fn ble_set_access_address(&self, aa: u32) {
+        let regs = self.hw.regs();
 
         regs.prefix0
             .set((regs.prefix0.get() & 0xffffff00) | (aa >> 24));
@@ -645,18 +1451,9 @@ impl Radio {
     // +----------+   +----------------+   +---------------+   +------------+
     //
     fn ble_set_packet_config(&self) {
-        let regs = unsafe { &*self.regs };
-
-        // sets the header of PDU TYPE to 1 byte
-        // sets the header length to 1 byte
-        regs.pcnf0.set(
-            (nrf5x::constants::RADIO_PCNF0_LFLEN_1BYTE << nrf5x::constants::RADIO_PCNF0_LFLEN_POS)
-                | (nrf5x::constants::RADIO_PCNF0_S0_LEN_1BYTE
-                    << nrf5x::constants::RADIO_PCNF0_S0LEN_POS)
-                | (nrf5x::constants::RADIO_PCNF0_S1_ZERO << nrf5x::constants::RADIO_PCNF0_S1LEN_POS)
-                | (NRF52_RADIO_PCNF0_S1INCL_MSK << NRF52_RADIO_PCNFO_S1INCL_POS)
-                | (NRF52_RADIO_PCNF0_PLEN_8BITS << NRF52_RADIO_PCNF0_PLEN_POS),
-        );
+        let regs = self.hw.regs();
+
+        self.ble_set_phy(Phy::Le1M);
 
         regs.pcnf1.set(
             (nrf5x::constants::RADIO_PCNF1_WHITEEN_ENABLED
@@ -674,32 +1471,106 @@ impl Radio {
         regs.modecnf0.set(NRF52_RADIO_MODECNF0_RU_FAST);
     }
 
-    // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part A], 4.6 REFERENCE SIGNAL DEFINITION
-    // Bit Rate = 1 Mb/s ±1 ppm
-    fn ble_set_channel_rate(&self) {
-        let regs = unsafe { &*self.regs };
-        regs.mode.set(nrf5x::constants::RadioMode::Ble1Mbit as u32);
+    fn channel(&self) -> Option<RadioChannel> {
+        self.context.get().map(|context| context.channel)
+    }
+
+    fn on_data_channel(&self) -> bool {
+        match self.channel() {
+            Some(RadioChannel::AdvertisingChannel37)
+            | Some(RadioChannel::AdvertisingChannel38)
+            | Some(RadioChannel::AdvertisingChannel39)
+            | None => false,
+            Some(_) => true,
+        }
+    }
+
+    // The PHY to use on the current channel, `phy` on data channels and
+    // LE 1M on advertising channels
+    fn phy(&self, phy: Phy) -> Phy {
+        if self.on_data_channel() {
+            phy
+        } else {
+            Phy::Le1M
+        }
+    }
+
+    // BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part A], 4.6 REFERENCE SIGNAL DEFINITION
+    // and [Vol 6, Part B], section 2.1 Packet Format
+    //
+    // Bit Rate = 1 Mb/s, 8 bit preamble on LE 1M
+    // Bit Rate = 2 Mb/s, 16 bit preamble on LE 2M
+    // Bit Rate = 1 Mb/s, 80 bit preamble followed by the coding indicator
+    // and a TERM1 field on LE Coded, with a 125 or 500 kb/s data rate
+    //
+    // sets the header of PDU TYPE to 1 byte
+    // sets the header length to 1 byte
+    fn ble_set_phy(&self, phy: Phy) {
+        let regs = self.hw.regs();
+
+        // Encrypted connections keep a byte for S1 in RAM for the CCM
+        let s1incl = if self.ccm_encryption().is_some() {
+            NRF52_RADIO_PCNF0_S1INCL_INCLUDE
+        } else {
+            NRF52_RADIO_PCNF0_S1INCL_MSK
+        };
+        let pcnf0 = (nrf5x::constants::RADIO_PCNF0_LFLEN_1BYTE
+            << nrf5x::constants::RADIO_PCNF0_LFLEN_POS)
+            | (nrf5x::constants::RADIO_PCNF0_S0_LEN_1BYTE << nrf5x::constants::RADIO_PCNF0_S0LEN_POS)
+            | (nrf5x::constants::RADIO_PCNF0_S1_ZERO << nrf5x::constants::RADIO_PCNF0_S1LEN_POS)
+            | (s1incl << NRF52_RADIO_PCNFO_S1INCL_POS);
+
+        let (mode, pcnf0) = match phy {
+            Phy::Le1M => (
+                nrf5x::constants::RadioMode::Ble1Mbit,
+                pcnf0 | (NRF52_RADIO_PCNF0_PLEN_8BITS << NRF52_RADIO_PCNF0_PLEN_POS),
+            ),
+            Phy::Le2M => (
+                nrf5x::constants::RadioMode::Ble2Mbit,
+                pcnf0 | (NRF52_RADIO_PCNF0_PLEN_16BITS << NRF52_RADIO_PCNF0_PLEN_POS),
+            ),
+            #[cfg(feature = "nrf52840")]
+            Phy::LeCodedS2 | Phy::LeCodedS8 => (
+                if phy == Phy::LeCodedS2 {
+                    nrf5x::constants::RadioMode::BleLr500Kbit
+                } else {
+                    nrf5x::constants::RadioMode::BleLr125Kbit
+                },
+                pcnf0 | (NRF52_RADIO_PCNF0_PLEN_LONG_RANGE << NRF52_RADIO_PCNF0_PLEN_POS)
+                    | (NRF52_RADIO_PCNF0_CILEN_2BITS << NRF52_RADIO_PCNF0_CILEN_POS)
+                    | (NRF52_RADIO_PCNF0_TERMLEN_3BITS << NRF52_RADIO_PCNF0_TERMLEN_POS),
+            ),
+            // Refused by `BleConfig::set_phy`
+            #[cfg(not(feature = "nrf52840"))]
+            Phy::LeCodedS2 | Phy::LeCodedS8 => return,
+        };
+
+        regs.mode.set(mode as u32);
+        regs.pcnf0.set(pcnf0);
     }
 
     // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 3.2 Data Whitening
     // Configure channel index to the LFSR and the hardware solves the rest
-    fn ble_set_data_whitening(&self, channel: RadioChannel) {
-        let regs = unsafe { &*self.regs };
-        regs.datawhiteiv.set(channel.get_channel_index());
+    fn ble_set_data_whitening(&self, context: RadioContext) {
+        let regs = self.hw.regs();
+        regs.datawhiteiv.set(context.whitening_iv());
     }
 
     // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 1.4.1
     // RF Channels:     0 - 39
     // Data:            0 - 36
     // Advertising:     37, 38, 39
-    fn ble_set_channel(&self, channel: RadioChannel) {
-        let regs = unsafe { &*self.regs };
-
-        // assert_eq!(nrf5x::constants::RADIO_STATE_DISABLE, regs.state.get());
-
-        self.channel.set(Some(channel));
-        regs.frequency.set(channel as u32);
-        self.ble_set_data_whitening(channel);
+    //
+    // Every register the context covers is written, whatever the previous
+    // context was
+    fn ble_set_context(&self, context: RadioContext) {
+        let regs = self.hw.regs();
+
+        self.context.set(Some(context));
+        regs.frequency.set(context.channel as u32);
+        self.ble_set_data_whitening(context);
+        self.ble_set_access_address(context.access_address);
+        self.ble_set_crcinit(context.crcinit);
     }
 
     // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 3 TRANSMITTER CHARACTERISTICS
@@ -713,9 +1584,12 @@ impl Radio {
     }
 }
 
-impl ble_advertising_hil::BleAdvertisementDriver for Radio {
+impl<H: RadioHardware> ble_advertising_hil::BleAdvertisementDriver for Radio<H> {
     fn transmit_advertisement(&self) {
         self.ble_initialize();
+        // Start of an advertising event, the other packets of the event, on
+        // the next channels, carry the same payload
+        self.swap_staged_payload();
         self.tx();
     }
 
@@ -723,9 +1597,18 @@ impl ble_advertising_hil::BleAdvertisementDriver for Radio {
         self.replace_radio_buffer(buf, len) // TODO replace signature to accommodate for a more flexible format of packets
     }
 
-    fn receive_advertisement(&self) {
+    fn set_scan_response_data(&self, buf: &'static mut [u8], len: usize) -> &'static mut [u8] {
+        self.replace_scan_response_buffer(buf, len)
+    }
+
+    fn receive_advertisement(&self, window: u32) {
         self.ble_initialize();
         self.rx();
+
+        // Windows on the following channels are scheduled relative to this one
+        let now = self.hw.now();
+        self.prev_rx_t0.set(now);
+        self.set_rx_timeout(now + window);
     }
 
     fn set_receive_client(&self, client: &'static ble_advertising_hil::RxClient) {
@@ -740,7 +1623,7 @@ impl ble_advertising_hil::BleAdvertisementDriver for Radio {
     }
 }
 
-impl ble_advertising_hil::BleConfig for Radio {
+impl<H: RadioHardware> ble_advertising_hil::BleConfig for Radio<H> {
     // The BLE Advertising Driver validates that the `tx_power` is between -20 to 10 dBm but then
     // underlying chip must validate if the current `tx_power` is supported as well
     fn set_tx_power(&self, tx_power: u8) -> kernel::ReturnCode {
@@ -751,18 +1634,62 @@ impl ble_advertising_hil::BleConfig for Radio {
             // Valid transmitting power, propogate success
             Ok(res) => {
                 self.tx_power.set(res);
+                if self.state.get() != RadioState::Uninitialized {
+                    self.set_tx_power();
+                }
                 kernel::ReturnCode::SUCCESS
             }
         }
     }
 
-    fn set_channel(&self, channel: RadioChannel, address: u32, crcinit: u32) {
-        self.ble_set_channel(channel);
-        self.ble_set_access_address(address);
-        self.ble_set_crcinit(crcinit);
+    fn set_context(&self, context: RadioContext) -> kernel::ReturnCode {
+        if !context.is_valid() {
+            return kernel::ReturnCode::EINVAL;
+        }
+        self.ble_set_context(context);
+        kernel::ReturnCode::SUCCESS
+    }
+
+    fn context(&self) -> Option<RadioContext> {
+        self.context.get()
+    }
+
+    fn set_address_filtering(&self, enabled: bool) {
+        self.address_filtering.set(enabled);
+    }
+
+    fn set_phy(&self, tx: Phy, rx: Phy) -> kernel::ReturnCode {
+        let supported = self.supported_phys();
+        if tx.bit() & supported == 0 || rx.bit() & supported == 0 {
+            return kernel::ReturnCode::ENOSUPPORT;
+        }
+        self.tx_phy.set(tx);
+        self.rx_phy.set(rx);
+        kernel::ReturnCode::SUCCESS
+    }
+
+    fn supported_phys(&self) -> u8 {
+        if cfg!(feature = "nrf52840") {
+            ble_advertising_hil::PHY_1M | ble_advertising_hil::PHY_2M
+                | ble_advertising_hil::PHY_CODED
+        } else {
+            ble_advertising_hil::PHY_1M | ble_advertising_hil::PHY_2M
+        }
+    }
+
+    fn set_encryption(&self, encryption: Option<Encryption>) -> kernel::ReturnCode {
+        match encryption {
+            Some(ref encryption) => {
+                self.hw.ccm_enable(&encryption.session_key, &encryption.iv)
+            }
+            None if self.encryption.get().is_some() => self.hw.ccm_disable(),
+            None => {}
+        }
+        self.encryption.set(encryption);
+        kernel::ReturnCode::SUCCESS
     }
 
-    fn set_access_address(&self, aa: u32) {
-        self.ble_set_access_address(aa)
+    fn dump_state(&self) {
+        Radio::<H>::dump_state(self)
     }
 }
@@ -0,0 +1,655 @@
+//! The radio state machine against a mock register block
+//!
+//! `MockRadioHardware` implements `RadioHardware` with the radio registers
+//! in RAM, and TIMER0, the PPI channels, the high frequency clock and the
+//! CCM in software. The radio events are raised by hand with `address`,
+//! `end` and `advance_to`, and the tasks the driver starts, directly or
+//! through the PPI channels wired to the timer, move the mock radio between
+//! the disabled, RX and TX states. A `Radio` built on it can then be driven
+//! one interrupt at a time, and what it scheduled checked after each one.
+//!
+//! `check_all` runs the scripted scenarios: a TX answered by a reception
+//! and a reception answered T_IFS later, a packet with a bad CRC, frames
+//! skipped with and without address filtering, and switches between the
+//! advertising context and a connection context. Each one panics on the
+//! first mismatch.
+//!
+//! The tasks the driver writes are carried out the next time the mock is
+//! used, DISABLE first, then TXEN or RXEN.
+//!
+//! No peripheral is touched, but the driver shares its packet buffers and
+//! conformance counters with `RADIO`. The module must never be linked into
+//! a kernel that uses the real radio: the scenarios would overwrite the
+//! buffers of a packet in flight and the counters the BLE driver reports.
+//! The nRF52 DK has a test kernel for them, see its `tests::radio_mock`.
+//!
+//! Only built with the `radio_mock` feature.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! use nrf52::ble::radio_mock;
+//!
+//! radio_mock::check_all();
+//! debug!("radio scenarios passed");
+//! ```
+
+use ble::ble_advertising_hil::{AdvertisementClient, BleAdvertisementDriver, BleConfig,
+                               DelayStartPoint, PhyTransition, RadioChannel, RadioContext,
+                               ReadAction, ReceivedPdu, RxClient, TxClient, TxImmediate, TxInfo,
+                               TxStatus};
+use ble::radio::{Radio, RadioHardware};
+use core::cell::Cell;
+use core::mem;
+use core::ptr;
+use kernel::ReturnCode;
+use kernel::common::regs::FieldValue;
+use nrf5x::ccm::{DataRate, Direction};
+use nrf5x::constants::{RADIO_SHORTS_END_DISABLE, RADIO_STATE_DISABLE, RADIO_STATE_RX,
+                       RADIO_STATE_TX};
+use ppi;
+use radio::RadioRegisters;
+
+const BLE_T_IFS: u32 = 150;
+
+// Pre-programmed PPI channels between TIMER0 and the radio, see `radio`
+const CH20_CC0_TXEN: u32 = 1 << 20;
+const CH21_CC0_RXEN: u32 = 1 << 21;
+const CH22_CC1_DISABLE: u32 = 1 << 22;
+const CH26_ADDRESS_CAPTURE1: u32 = 1 << 26;
+const CH27_END_CAPTURE2: u32 = 1 << 27;
+
+// Task and read-only registers are written and read through their address,
+// the way the radio would
+fn peek<T>(register: &T) -> u32 {
+    unsafe { ptr::read_volatile(register as *const T as *const u32) }
+}
+
+fn poke<T>(register: &T, value: u32) {
+    unsafe { ptr::write_volatile(register as *const T as *mut u32, value) }
+}
+
+// The register block is kept out of `MockRadioHardware`, and off the stack
+static mut REGISTERS: [u32; 1024] = [0; 1024];
+
+pub struct MockRadioHardware {
+    regs: *const RadioRegisters,
+    interrupts: Cell<u32>,
+    now: Cell<u32>,
+    cc: [Cell<u32>; 4],
+    compare_fired: [Cell<bool>; 4],
+    ppi: Cell<u32>,
+    hfclk: Cell<bool>,
+}
+
+impl MockRadioHardware {
+    /// Take over the mock register block, back to its reset state: all
+    /// registers 0 and the radio disabled
+    pub fn new() -> MockRadioHardware {
+        unsafe {
+            assert_eq!(mem::size_of::<RadioRegisters>(), mem::size_of_val(&REGISTERS));
+            for register in REGISTERS.iter_mut() {
+                *register = 0;
+            }
+        }
+        MockRadioHardware {
+            regs: unsafe { &REGISTERS as *const [u32; 1024] as *const RadioRegisters },
+            interrupts: Cell::new(0),
+            now: Cell::new(0),
+            cc: [Cell::new(0), Cell::new(0), Cell::new(0), Cell::new(0)],
+            compare_fired: [
+                Cell::new(false),
+                Cell::new(false),
+                Cell::new(false),
+                Cell::new(false),
+            ],
+            ppi: Cell::new(0),
+            hfclk: Cell::new(false),
+        }
+    }
+
+    /// Whether PPI channel `channel` is enabled
+    pub fn ppi_enabled(&self, channel: usize) -> bool {
+        self.ppi.get() & (1 << channel) != 0
+    }
+
+    pub fn hfclk_running(&self) -> bool {
+        self.hfclk.get()
+    }
+
+    /// The STATE register, once the tasks started so far have run
+    pub fn state(&self) -> u32 {
+        self.run_tasks();
+        peek(&self.regs().state)
+    }
+
+    // Carry out the tasks triggered since the last call. The driver always
+    // disables the radio before starting it again, so DISABLE goes first.
+    fn run_tasks(&self) {
+        let regs = self.regs();
+        if peek(&regs.task_disable) != 0 {
+            poke(&regs.task_disable, 0);
+            self.disable();
+        }
+        if peek(&regs.task_txen) != 0 {
+            poke(&regs.task_txen, 0);
+            poke(&regs.state, RADIO_STATE_TX);
+        }
+        if peek(&regs.task_rxen) != 0 {
+            poke(&regs.task_rxen, 0);
+            poke(&regs.state, RADIO_STATE_RX);
+        }
+    }
+
+    fn disable(&self) {
+        let regs = self.regs();
+        if peek(&regs.state) != RADIO_STATE_DISABLE {
+            poke(&regs.state, RADIO_STATE_DISABLE);
+            regs.event_disabled.set(1);
+        }
+    }
+
+    /// Let the radio timer run to `time`, firing the compares it passes and
+    /// the radio tasks wired to them
+    pub fn advance_to(&self, time: u32) {
+        self.run_tasks();
+        self.now.set(time);
+        for cc in 0..2 {
+            let passed = (time.wrapping_sub(self.cc[cc].get()) as i32) >= 0;
+            if passed && !self.compare_fired[cc].get() {
+                self.compare_fired[cc].set(true);
+                let ppi = self.ppi.get();
+                if cc == 0 && ppi & CH20_CC0_TXEN != 0 {
+                    poke(&self.regs().task_txen, 1);
+                }
+                if cc == 0 && ppi & CH21_CC0_RXEN != 0 {
+                    poke(&self.regs().task_rxen, 1);
+                }
+                if cc == 1 && ppi & CH22_CC1_DISABLE != 0 {
+                    poke(&self.regs().task_disable, 1);
+                }
+            }
+        }
+        self.run_tasks();
+    }
+
+    /// The access address of a packet is received, and enough of it for
+    /// the bit counter to match
+    pub fn address(&self) {
+        let regs = self.regs();
+        self.run_tasks();
+        assert_eq!(peek(&regs.state), RADIO_STATE_RX, "ADDRESS while not receiving");
+        if self.ppi.get() & CH26_ADDRESS_CAPTURE1 != 0 {
+            self.cc[1].set(self.now.get());
+        }
+        regs.event_address.set(1);
+        regs.event_bcmatch.set(1);
+    }
+
+    /// The packet being sent or received ends, received with a CRC match if
+    /// `crc_ok`
+    pub fn end(&self, crc_ok: bool) {
+        let regs = self.regs();
+        self.run_tasks();
+        let state = peek(&regs.state);
+        assert!(state == RADIO_STATE_RX || state == RADIO_STATE_TX, "END while disabled");
+        if self.ppi.get() & CH27_END_CAPTURE2 != 0 {
+            self.cc[2].set(self.now.get());
+        }
+        if state == RADIO_STATE_RX {
+            regs.event_crcok.set(crc_ok as u32);
+        }
+        regs.event_end.set(1);
+        if regs.shorts.get() & RADIO_SHORTS_END_DISABLE != 0 {
+            self.disable();
+        }
+    }
+}
+
+impl RadioHardware for MockRadioHardware {
+    fn regs(&self) -> &RadioRegisters {
+        unsafe { &*self.regs }
+    }
+
+    fn enable_interrupts(&self, mask: u32) {
+        self.interrupts.set(self.interrupts.get() | mask);
+    }
+
+    fn disable_interrupts(&self, mask: u32) {
+        self.interrupts.set(self.interrupts.get() & !mask);
+    }
+
+    fn enabled_interrupts(&self) -> u32 {
+        self.interrupts.get()
+    }
+
+    fn now(&self) -> u32 {
+        self.now.get()
+    }
+
+    fn compare(&self, cc: usize) -> u32 {
+        self.cc[cc].get()
+    }
+
+    fn set_compare(&self, cc: usize, time: u32) {
+        self.cc[cc].set(time);
+    }
+
+    fn compare_fired(&self, cc: usize) -> bool {
+        self.compare_fired[cc].get()
+    }
+
+    fn clear_compare_event(&self, cc: usize) {
+        self.compare_fired[cc].set(false);
+    }
+
+    fn reserve_compare(&self, _cc: usize) -> ReturnCode {
+        ReturnCode::SUCCESS
+    }
+
+    fn start_timer(&self) {}
+
+    fn enable_ppi(&self, channels: FieldValue<u32, ppi::Channel::Register>) {
+        self.ppi.set(self.ppi.get() | u32::from(channels));
+    }
+
+    fn disable_ppi(&self, channels: FieldValue<u32, ppi::Channel::Register>) {
+        self.ppi.set(self.ppi.get() & !u32::from(channels));
+    }
+
+    fn enabled_ppi(&self) -> u32 {
+        self.ppi.get()
+    }
+
+    fn reserve_ppi(&self, _channel: usize) -> ReturnCode {
+        ReturnCode::SUCCESS
+    }
+
+    fn hfclk_request(&self) {
+        self.hfclk.set(true);
+    }
+
+    fn hfclk_release(&self) {
+        self.hfclk.set(false);
+    }
+
+    fn ccm_enable(&self, _key: &[u8; 16], _iv: &[u8; 8]) {}
+
+    fn ccm_disable(&self) {}
+
+    // Packets go through in the clear, with a zero MIC
+    fn ccm_encrypt(
+        &self,
+        input: &[u8],
+        output: &mut [u8],
+        _counter: u64,
+        _direction: Direction,
+        _rate: DataRate,
+    ) -> bool {
+        for byte in output.iter_mut() {
+            *byte = 0;
+        }
+        output[..input.len()].copy_from_slice(input);
+        true
+    }
+
+    fn ccm_prepare_decrypt(
+        &self,
+        _input: &[u8],
+        _output: &mut [u8],
+        _counter: u64,
+        _direction: Direction,
+        _rate: DataRate,
+    ) {
+    }
+
+    fn ccm_decrypt_done(&self) -> bool {
+        true
+    }
+}
+
+/// What `ScriptedClient::receive_end` was given
+#[derive(Debug, Copy, Clone)]
+pub struct Received {
+    pub crc_ok: bool,
+    pub timestamp: u32,
+    pub channel: Option<RadioChannel>,
+}
+
+/// Link layer answering the radio with the transitions it is scripted with,
+/// and keeping what the radio reported for the scenario to check
+pub struct ScriptedClient {
+    read_action: Cell<ReadAction>,
+    rx_transition: Cell<PhyTransition>,
+    tx_transition: Cell<PhyTransition>,
+    timeout_transition: Cell<PhyTransition>,
+    done: Cell<TxImmediate>,
+    received: Cell<Option<Received>>,
+    transmitted: Cell<Option<TxInfo>>,
+    advertisements_done: Cell<usize>,
+    timeouts: Cell<usize>,
+}
+
+pub static mut CLIENT: ScriptedClient = ScriptedClient::new();
+
+impl ScriptedClient {
+    pub const fn new() -> ScriptedClient {
+        ScriptedClient {
+            read_action: Cell::new(ReadAction::ReadFrame),
+            rx_transition: Cell::new(PhyTransition::None),
+            tx_transition: Cell::new(PhyTransition::None),
+            timeout_transition: Cell::new(PhyTransition::None),
+            done: Cell::new(TxImmediate::GoToSleep),
+            received: Cell::new(None),
+            transmitted: Cell::new(None),
+            advertisements_done: Cell::new(0),
+            timeouts: Cell::new(0),
+        }
+    }
+
+    /// Forget what was reported and answer with the defaults: read frames,
+    /// then go to sleep
+    pub fn reset(&self) {
+        self.read_action.set(ReadAction::ReadFrame);
+        self.rx_transition.set(PhyTransition::None);
+        self.tx_transition.set(PhyTransition::None);
+        self.timeout_transition.set(PhyTransition::None);
+        self.done.set(TxImmediate::GoToSleep);
+        self.received.set(None);
+        self.transmitted.set(None);
+        self.advertisements_done.set(0);
+        self.timeouts.set(0);
+    }
+
+    pub fn set_read_action(&self, action: ReadAction) {
+        self.read_action.set(action);
+    }
+
+    pub fn set_rx_transition(&self, transition: PhyTransition) {
+        self.rx_transition.set(transition);
+    }
+
+    pub fn set_tx_transition(&self, transition: PhyTransition) {
+        self.tx_transition.set(transition);
+    }
+
+    pub fn set_timeout_transition(&self, transition: PhyTransition) {
+        self.timeout_transition.set(transition);
+    }
+
+    pub fn take_received(&self) -> Option<Received> {
+        self.received.take()
+    }
+
+    pub fn take_transmitted(&self) -> Option<TxInfo> {
+        self.transmitted.take()
+    }
+
+    pub fn advertisements_done(&self) -> usize {
+        self.advertisements_done.get()
+    }
+
+    pub fn timeouts(&self) -> usize {
+        self.timeouts.get()
+    }
+}
+
+impl RxClient for ScriptedClient {
+    fn receive_start(&self, _buf: &'static mut [u8], _len: u8) -> ReadAction {
+        self.read_action.get()
+    }
+
+    fn receive_end(&self, pdu: ReceivedPdu) -> PhyTransition {
+        self.received.set(Some(Received {
+            crc_ok: pdu.crc_ok,
+            timestamp: pdu.timestamp,
+            channel: pdu.channel,
+        }));
+        self.rx_transition.get()
+    }
+}
+
+impl TxClient for ScriptedClient {
+    fn transmit_end(&self, info: TxInfo) -> PhyTransition {
+        self.transmitted.set(Some(info));
+        self.tx_transition.get()
+    }
+}
+
+impl AdvertisementClient for ScriptedClient {
+    fn advertisement_done(&self) -> TxImmediate {
+        self.advertisements_done
+            .set(self.advertisements_done.get() + 1);
+        self.done.get()
+    }
+
+    fn timer_expired(&self) -> PhyTransition {
+        self.timeouts.set(self.timeouts.get() + 1);
+        self.timeout_transition.get()
+    }
+}
+
+fn mock_radio(client: &'static ScriptedClient) -> Radio<MockRadioHardware> {
+    client.reset();
+    let radio = Radio::with_hardware(MockRadioHardware::new());
+    radio.set_receive_client(client);
+    radio.set_transmit_client(client);
+    radio.set_advertisement_client(client);
+    radio
+}
+
+fn client() -> &'static ScriptedClient {
+    unsafe { &CLIENT }
+}
+
+/// An advertisement is sent and a reply awaited, as after a CONNECT_IND,
+/// then the packet received is answered T_IFS after it ended
+pub fn check_tx_to_rx() {
+    let client = client();
+    let radio = mock_radio(client);
+    let hw = radio.hardware();
+    radio.set_context(RadioContext::advertising(RadioChannel::AdvertisingChannel37));
+
+    radio.transmit_advertisement();
+    assert_eq!(hw.state(), RADIO_STATE_TX, "advertisement not started");
+    assert!(hw.hfclk_running(), "HFCLK not requested for the TX");
+
+    client.set_tx_transition(PhyTransition::MoveToRX(
+        DelayStartPoint::PacketEndBLEStandardDelay,
+        1000,
+    ));
+    hw.advance_to(376);
+    hw.end(true);
+    radio.handle_interrupt();
+
+    let info = client.take_transmitted().expect("TX end not reported");
+    assert_eq!(info.status, TxStatus::Sent);
+    assert_eq!(info.timestamp, 376, "TX end not timestamped with the END capture");
+
+    // The receiver is started T_IFS after the END, less the ramp up and
+    // a margin, and the window closes the timeout after T_IFS
+    assert!(hw.ppi_enabled(21), "RXEN not wired to CC[0]");
+    assert!(hw.ppi_enabled(22), "DISABLE not wired to CC[1]");
+    assert!(!hw.ppi_enabled(20), "TXEN still wired to CC[0]");
+    assert_eq!(hw.compare(1), 376 + BLE_T_IFS + 1000, "receive window end");
+    let rx_start = hw.compare(0);
+    assert!(rx_start > 376 && rx_start < 376 + BLE_T_IFS, "RX scheduled at {}", rx_start);
+
+    hw.advance_to(rx_start);
+    assert_eq!(hw.state(), RADIO_STATE_RX, "RXEN not triggered by CC[0]");
+
+    client.set_rx_transition(PhyTransition::MoveToTX(
+        DelayStartPoint::PacketEndBLEStandardDelay,
+    ));
+    hw.advance_to(376 + BLE_T_IFS + 40);
+    hw.address();
+    radio.handle_interrupt();
+    assert!(!hw.ppi_enabled(22), "receive window still closing after ADDRESS");
+
+    hw.advance_to(376 + BLE_T_IFS + 120);
+    hw.end(true);
+    radio.handle_interrupt();
+
+    let received = client.take_received().expect("RX end not reported");
+    assert!(received.crc_ok);
+    assert_eq!(received.timestamp, 376 + BLE_T_IFS + 40, "ADDRESS capture");
+    assert_eq!(received.channel, Some(RadioChannel::AdvertisingChannel37));
+
+    let end = 376 + BLE_T_IFS + 120;
+    assert!(hw.ppi_enabled(20), "TXEN not wired to CC[0]");
+    assert!(!hw.ppi_enabled(21), "RXEN still wired to CC[0]");
+    let tx_start = hw.compare(0);
+    assert!(tx_start > end && tx_start < end + BLE_T_IFS, "TX scheduled at {}", tx_start);
+    hw.advance_to(tx_start);
+    assert_eq!(hw.state(), RADIO_STATE_TX, "TXEN not triggered by CC[0]");
+}
+
+/// A packet received with a bad CRC is still reported, flagged, and the
+/// transition the client picks for it is carried out
+pub fn check_crc_failure() {
+    let client = client();
+    let radio = mock_radio(client);
+    let hw = radio.hardware();
+    radio.set_context(RadioContext::connection(0x50654A17, 0x123456, RadioChannel::DataChannel5));
+
+    radio.receive_advertisement(10000);
+    assert_eq!(hw.state(), RADIO_STATE_RX, "receiver not started");
+    assert_eq!(hw.compare(1), 10000, "receive window end");
+
+    hw.advance_to(2000);
+    hw.address();
+    radio.handle_interrupt();
+    hw.advance_to(2080);
+    hw.end(false);
+    radio.handle_interrupt();
+
+    let received = client.take_received().expect("RX end not reported");
+    assert!(!received.crc_ok, "CRC failure not reported");
+    assert_eq!(received.timestamp, 2000, "ADDRESS capture");
+    assert_eq!(client.advertisements_done(), 1);
+    assert_eq!(hw.state(), RADIO_STATE_DISABLE, "radio left running");
+    assert!(!hw.hfclk_running(), "HFCLK not released");
+
+    // In a connection the packet is still answered, to ask for it again
+    client.set_rx_transition(PhyTransition::MoveToTX(
+        DelayStartPoint::PacketEndBLEStandardDelay,
+    ));
+    radio.receive_advertisement(10000);
+    hw.advance_to(4000);
+    hw.address();
+    radio.handle_interrupt();
+    hw.advance_to(4080);
+    hw.end(false);
+    radio.handle_interrupt();
+
+    let received = client.take_received().expect("RX end not reported");
+    assert!(!received.crc_ok, "CRC failure not reported");
+    assert!(hw.ppi_enabled(20), "reply to a bad CRC not scheduled");
+    assert!(hw.compare(0) > 4080, "reply scheduled in the past");
+}
+
+/// A frame the client skips stops the radio, unless addresses are filtered
+/// in which case the radio keeps listening until the window closes
+pub fn check_skip_frame() {
+    let client = client();
+    let radio = mock_radio(client);
+    let hw = radio.hardware();
+    radio.set_context(RadioContext::advertising(RadioChannel::AdvertisingChannel38));
+    client.set_read_action(ReadAction::SkipFrame);
+
+    radio.receive_advertisement(5000);
+    hw.advance_to(1000);
+    hw.address();
+    radio.handle_interrupt();
+    assert!(client.take_received().is_none(), "skipped frame reported");
+    assert_eq!(client.advertisements_done(), 1);
+    assert_eq!(hw.state(), RADIO_STATE_DISABLE, "radio left running");
+
+    client.reset();
+    client.set_read_action(ReadAction::SkipFrame);
+    radio.set_address_filtering(true);
+
+    radio.receive_advertisement(5000);
+    let window_end = hw.compare(1);
+    hw.advance_to(2000);
+    hw.address();
+    radio.handle_interrupt();
+    assert_eq!(client.advertisements_done(), 0, "filtered frame ended the window");
+    assert_eq!(hw.state(), RADIO_STATE_RX, "receiver not restarted");
+    assert_eq!(hw.compare(1), window_end, "receive window moved");
+    assert!(hw.ppi_enabled(22), "DISABLE not wired to CC[1]");
+
+    // Nothing else is heard, the window closes on time
+    hw.advance_to(window_end);
+    radio.handle_interrupt();
+    assert_eq!(client.timeouts(), 1, "receive window did not time out");
+    assert!(client.take_received().is_none());
+}
+
+// The registers of the radio hold `context`
+fn check_registers(hw: &MockRadioHardware, context: RadioContext) {
+    let regs = hw.regs();
+    assert_eq!(peek(&regs.frequency), context.channel as u32, "FREQUENCY");
+    assert_eq!(peek(&regs.datawhiteiv), context.whitening_iv(), "DATAWHITEIV");
+    assert_eq!(peek(&regs.base0), context.access_address << 8, "BASE0");
+    assert_eq!(peek(&regs.prefix0) & 0xff, context.access_address >> 24, "PREFIX0");
+    assert_eq!(peek(&regs.crcinit), context.crcinit, "CRCINIT");
+}
+
+/// A connection context set before the radio is first used survives its
+/// initialization, moving to advertising and back to the connection
+/// rewrites the whole configuration, and invalid contexts are refused
+pub fn check_context_switch() {
+    let client = client();
+    let radio = mock_radio(client);
+    let hw = radio.hardware();
+    let connection = RadioContext::connection(0x71764129, 0xabcdef, RadioChannel::DataChannel12);
+    let advertising = RadioContext::advertising(RadioChannel::AdvertisingChannel39);
+
+    assert_eq!(radio.set_context(connection), ReturnCode::SUCCESS);
+    radio.receive_advertisement(10000);
+    check_registers(hw, connection);
+    hw.advance_to(2000);
+    hw.address();
+    radio.handle_interrupt();
+    hw.advance_to(2080);
+    hw.end(true);
+    radio.handle_interrupt();
+    let received = client.take_received().expect("RX end not reported");
+    assert_eq!(received.channel, Some(RadioChannel::DataChannel12));
+
+    assert_eq!(radio.set_context(advertising), ReturnCode::SUCCESS);
+    check_registers(hw, advertising);
+    radio.transmit_advertisement();
+    hw.advance_to(3000);
+    hw.end(true);
+    radio.handle_interrupt();
+    let info = client.take_transmitted().expect("TX end not reported");
+    assert_eq!(info.channel, Some(RadioChannel::AdvertisingChannel39));
+
+    // One bit away from the advertising access address, then a CRC
+    // initial value wider than 24 bits
+    let near_advertising = RadioContext {
+        access_address: 0x8E89BED7,
+        ..connection
+    };
+    let wide_crcinit = RadioContext {
+        crcinit: 0x1abcdef,
+        ..connection
+    };
+    assert_eq!(radio.set_context(near_advertising), ReturnCode::EINVAL);
+    assert_eq!(radio.set_context(wide_crcinit), ReturnCode::EINVAL);
+    assert_eq!(radio.context(), Some(advertising), "refused context kept");
+    check_registers(hw, advertising);
+
+    assert_eq!(radio.set_context(connection), ReturnCode::SUCCESS);
+    check_registers(hw, connection);
+}
+
+pub fn check_all() {
+    check_tx_to_rx();
+    check_crc_failure();
+    check_skip_frame();
+    check_context_switch();
+}
@@ -0,0 +1,180 @@
+//! Radio event trace
+//!
+//! A ring buffer of the last `TRACE_LEN` radio events, each stamped with the
+//! TIMER0 time it was recorded at, in µs. The radio and the connection driver
+//! record events from the interrupt path, and `dump` prints them to the debug
+//! console, oldest first, so the BLE state machine can be followed after the
+//! fact without a logic analyzer. Events recorded once the buffer is full
+//! overwrite the oldest ones.
+//!
+//! Tracing is off by default and an event costs a single check then. The
+//! trace points can in addition toggle the debug GPIOs assigned with
+//! `kernel::debug::assign_gpios`, for timing them on a scope: the start of a
+//! transmission or reception toggles GPIO 0, the end of a packet or of a
+//! reception window GPIO 1, and connection channel map updates GPIO 2.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! nrf52::ble::trace::TRACE.enable(true);
+//! nrf52::ble::trace::TRACE.set_gpio_toggling(true);
+//! // ...
+//! nrf52::ble::trace::TRACE.dump();
+//! ```
+
+use core::cell::{Cell, UnsafeCell};
+use nrf5x;
+
+/// Number of events kept
+pub const TRACE_LEN: usize = 64;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Event {
+    /// The radio was set up to transmit
+    TxStart,
+    /// The radio was set up to receive
+    RxStart,
+    /// An access address was received
+    Address,
+    /// A packet was received
+    RxEnd { crc_ok: bool },
+    /// A transmission ended, `sent` is false if the radio was disabled
+    /// before the packet was out
+    TxEnd { sent: bool },
+    /// A reception window ended without a packet
+    RxTimeout,
+    /// A transition was scheduled after its deadline had passed
+    DeadlineMissed,
+    /// A connection channel map update was received
+    ChannelMapReceived,
+    /// A connection channel map update took effect at its instant
+    ChannelMapApplied,
+}
+
+impl Event {
+    /// The debug GPIO toggled by the event
+    fn gpio(&self) -> usize {
+        match *self {
+            Event::TxStart | Event::RxStart => 0,
+            Event::Address
+            | Event::RxEnd { .. }
+            | Event::TxEnd { .. }
+            | Event::RxTimeout
+            | Event::DeadlineMissed => 1,
+            Event::ChannelMapReceived | Event::ChannelMapApplied => 2,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Entry {
+    timestamp: u32,
+    event: Event,
+}
+
+pub struct Trace {
+    // Events are recorded and dumped from the kernel loop, never concurrently
+    entries: UnsafeCell<[Entry; TRACE_LEN]>,
+    /// Index the next event is recorded at
+    next: Cell<usize>,
+    /// Events recorded since the last `clear`, including overwritten ones
+    recorded: Cell<u32>,
+    enabled: Cell<bool>,
+    gpio_toggling: Cell<bool>,
+}
+
+pub static mut TRACE: Trace = Trace::new();
+
+/// Record `event` in `TRACE`
+pub fn record(event: Event) {
+    unsafe { TRACE.record(event) }
+}
+
+impl Trace {
+    const fn new() -> Trace {
+        Trace {
+            entries: UnsafeCell::new(
+                [Entry {
+                    timestamp: 0,
+                    event: Event::TxStart,
+                }; TRACE_LEN],
+            ),
+            next: Cell::new(0),
+            recorded: Cell::new(0),
+            enabled: Cell::new(false),
+            gpio_toggling: Cell::new(false),
+        }
+    }
+
+    /// Start or stop recording events
+    pub fn enable(&self, enabled: bool) {
+        self.enabled.set(enabled);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.get()
+    }
+
+    /// Toggle the debug GPIOs at the trace points, whether or not events are
+    /// recorded.
+    pub fn set_gpio_toggling(&self, toggling: bool) {
+        self.gpio_toggling.set(toggling);
+    }
+
+    pub fn record(&self, event: Event) {
+        if self.gpio_toggling.get() {
+            match event.gpio() {
+                0 => debug_gpio!(0, toggle),
+                1 => debug_gpio!(1, toggle),
+                _ => debug_gpio!(2, toggle),
+            }
+        }
+        if !self.enabled.get() {
+            return;
+        }
+
+        let timestamp = unsafe { nrf5x::timer::TIMER0.capture(3) };
+        let next = self.next.get();
+        unsafe {
+            (*self.entries.get())[next] = Entry { timestamp, event };
+        }
+        self.next.set((next + 1) % TRACE_LEN);
+        self.recorded.set(self.recorded.get().wrapping_add(1));
+    }
+
+    /// Forget the recorded events
+    pub fn clear(&self) {
+        self.next.set(0);
+        self.recorded.set(0);
+    }
+
+    /// Print the recorded events to the debug console, oldest first, with
+    /// their timestamps and the time since the previous event.
+    pub fn dump(&self) {
+        let recorded = self.recorded.get();
+        let kept = if recorded as usize > TRACE_LEN {
+            TRACE_LEN
+        } else {
+            recorded as usize
+        };
+        debug!(
+            "radio trace: {} events, {} lost",
+            kept,
+            recorded as usize - kept
+        );
+
+        let first = (self.next.get() + TRACE_LEN - kept) % TRACE_LEN;
+        let mut previous = None;
+        for i in 0..kept {
+            let entry = unsafe { (*self.entries.get())[(first + i) % TRACE_LEN] };
+            debug!(
+                "  {:10} +{:8} {:?}",
+                entry.timestamp,
+                previous.map_or(0, |previous: u32| entry.timestamp.wrapping_sub(previous)),
+                entry.event
+            );
+            previous = Some(entry.timestamp);
+        }
+    }
+}
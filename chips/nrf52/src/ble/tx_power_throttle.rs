@@ -0,0 +1,200 @@
+//! Thermal throttling of the radio TX power
+//!
+//! Transmitting at the highest output powers, +8 dBm on the nRF52840, heats
+//! up the die. `TxPowerThrottle` samples the die temperature periodically
+//! while the radio is asked for more than a floor power. Each reading above
+//! the threshold lowers the radio's TX power limit by one level, down to the
+//! floor. Each reading a hysteresis below the threshold raises it again by
+//! one level, until the limit is lifted. The client is told of every change,
+//! which the BLE driver passes on to processes.
+//!
+//! The throttle owns the temperature sensor and forwards readings requested
+//! through its own `TemperatureDriver` implementation, so the temperature
+//! capsule keeps working on top of it.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let throttle = static_init!(
+//!     nrf52::ble::tx_power_throttle::TxPowerThrottle<'static, VirtualMuxAlarm<'static, Rtc>>,
+//!     nrf52::ble::tx_power_throttle::TxPowerThrottle::new(
+//!         &nrf52::ble::radio::RADIO,
+//!         &nrf5x::temperature::TEMP,
+//!         throttle_virtual_alarm
+//!     )
+//! );
+//! throttle_virtual_alarm.set_client(throttle);
+//! kernel::hil::sensors::TemperatureDriver::set_client(&nrf5x::temperature::TEMP, throttle);
+//! throttle.set_client(ble_radio);
+//! throttle.start();
+//! ```
+
+use ble::radio::Radio;
+use core::cell::Cell;
+use kernel::hil;
+use kernel::hil::time::{Alarm, Frequency};
+use kernel::ReturnCode;
+use nrf5x::constants::TxPower;
+
+/// Default threshold, in hundredths of a degree Celsius
+pub const DEFAULT_THRESHOLD: i32 = 7000;
+/// Default hysteresis, in hundredths of a degree Celsius
+pub const DEFAULT_HYSTERESIS: i32 = 500;
+/// Default time between temperature readings, in ms
+pub const DEFAULT_PERIOD_MS: u32 = 1000;
+
+pub trait TxPowerThrottleClient {
+    /// The TX power limit changed to `limit` dBm, or was lifted. `temperature`
+    /// is the reading that caused it, in hundredths of a degree Celsius.
+    fn tx_power_limit_changed(&self, limit: Option<i8>, temperature: i32);
+}
+
+pub struct TxPowerThrottle<'a, A: Alarm + 'a> {
+    radio: &'a Radio,
+    temperature: &'a hil::sensors::TemperatureDriver,
+    alarm: &'a A,
+    client: Cell<Option<&'static TxPowerThrottleClient>>,
+    temperature_client: Cell<Option<&'static hil::sensors::TemperatureClient>>,
+    threshold: Cell<i32>,
+    hysteresis: Cell<i32>,
+    period_ms: Cell<u32>,
+    /// Lowest level the TX power is throttled to
+    floor: Cell<TxPower>,
+    /// Limit currently applied to the radio
+    limit: Cell<Option<TxPower>>,
+    /// A reading was started for the throttle
+    own_read: Cell<bool>,
+    /// A reading was requested by `temperature_client`
+    client_read: Cell<bool>,
+}
+
+impl<'a, A: Alarm + 'a> TxPowerThrottle<'a, A> {
+    pub fn new(
+        radio: &'a Radio,
+        temperature: &'a hil::sensors::TemperatureDriver,
+        alarm: &'a A,
+    ) -> TxPowerThrottle<'a, A> {
+        TxPowerThrottle {
+            radio,
+            temperature,
+            alarm,
+            client: Cell::new(None),
+            temperature_client: Cell::new(None),
+            threshold: Cell::new(DEFAULT_THRESHOLD),
+            hysteresis: Cell::new(DEFAULT_HYSTERESIS),
+            period_ms: Cell::new(DEFAULT_PERIOD_MS),
+            floor: Cell::new(TxPower::ZerodBm),
+            limit: Cell::new(None),
+            own_read: Cell::new(false),
+            client_read: Cell::new(false),
+        }
+    }
+
+    pub fn set_client(&self, client: &'static TxPowerThrottleClient) {
+        self.client.set(Some(client));
+    }
+
+    /// Throttle above `threshold` and restore below `threshold - hysteresis`,
+    /// both in hundredths of a degree Celsius.
+    pub fn set_threshold(&self, threshold: i32, hysteresis: i32) {
+        self.threshold.set(threshold);
+        self.hysteresis.set(hysteresis);
+    }
+
+    pub fn set_period(&self, period_ms: u32) {
+        self.period_ms.set(period_ms);
+    }
+
+    /// Never throttle below `floor`.
+    pub fn set_floor(&self, floor: TxPower) {
+        self.floor.set(floor);
+    }
+
+    /// Start monitoring the temperature.
+    pub fn start(&self) {
+        self.schedule_next_reading();
+    }
+
+    fn schedule_next_reading(&self) {
+        let period = self.period_ms.get() * A::Frequency::frequency() / 1000;
+        self.alarm.set_alarm(self.alarm.now().wrapping_add(period));
+    }
+
+    fn read(&self) {
+        if !self.own_read.get() && !self.client_read.get() {
+            self.temperature.read_temperature();
+        }
+    }
+
+    // The limit after a reading of `temperature`
+    fn next_limit(&self, temperature: i32) -> Option<TxPower> {
+        let limit = self.limit.get();
+        let requested = self.radio.tx_power();
+        if temperature > self.threshold.get() {
+            let current = limit.unwrap_or(requested);
+            if current.dbm() > self.floor.get().dbm() {
+                current.step_down()
+            } else {
+                limit
+            }
+        } else if temperature < self.threshold.get() - self.hysteresis.get() {
+            match limit.and_then(|limit| limit.step_up()) {
+                Some(raised) if raised.dbm() < requested.dbm() => Some(raised),
+                _ => None,
+            }
+        } else {
+            limit
+        }
+    }
+
+    fn evaluate(&self, temperature: i32) {
+        let limit = self.next_limit(temperature);
+        if limit != self.limit.get() {
+            self.limit.set(limit);
+            self.radio.set_tx_power_limit(limit);
+            self.client.get().map(|client| {
+                client.tx_power_limit_changed(limit.map(|limit| limit.dbm()), temperature)
+            });
+        }
+    }
+}
+
+impl<'a, A: Alarm + 'a> hil::time::Client for TxPowerThrottle<'a, A> {
+    fn fired(&self) {
+        // Only worth a reading if there is something to throttle or restore
+        let throttling = self.limit.get().is_some();
+        if throttling || self.radio.tx_power().dbm() > self.floor.get().dbm() {
+            self.read();
+            self.own_read.set(true);
+        }
+        self.schedule_next_reading();
+    }
+}
+
+impl<'a, A: Alarm + 'a> hil::sensors::TemperatureClient for TxPowerThrottle<'a, A> {
+    fn callback(&self, value: usize) {
+        if self.own_read.get() {
+            self.own_read.set(false);
+            self.evaluate(value as i32);
+        }
+        if self.client_read.get() {
+            self.client_read.set(false);
+            self.temperature_client
+                .get()
+                .map(|client| client.callback(value));
+        }
+    }
+}
+
+impl<'a, A: Alarm + 'a> hil::sensors::TemperatureDriver for TxPowerThrottle<'a, A> {
+    fn set_client(&self, client: &'static hil::sensors::TemperatureClient) {
+        self.temperature_client.set(Some(client));
+    }
+
+    fn read_temperature(&self) -> ReturnCode {
+        self.read();
+        self.client_read.set(true);
+        ReturnCode::SUCCESS
+    }
+}
@@ -1,10 +1,17 @@
+use adc;
 use ble;
 use cortexm4::{self, nvic};
 use i2c;
+#[cfg(feature = "nrf52840")]
+use ieee802154_radio;
 use kernel;
 use kernel::support;
 use nrf5x;
+use nrf5x::helpers::{DeferredCall, Task};
 use nrf5x::peripheral_interrupts::*;
+use pwm;
+#[cfg(feature = "nrf52840")]
+use qspi;
 use spi;
 use uart;
 
@@ -38,11 +45,34 @@ impl kernel::Chip for NRF52 {
 
     fn service_pending_interrupts(&mut self) {
         unsafe {
+            let stats = &mut nrf5x::interrupt_statistics::INTERRUPT_STATISTICS;
+            stats.record_pending(nvic::pending_count());
+            while let Some(task) = DeferredCall::next_pending() {
+                match task {
+                    Task::Nvmc => nrf5x::nvmc::NVMC.handle_interrupt(),
+                }
+            }
             while let Some(interrupt) = nvic::next_pending() {
+                stats.record_serviced(interrupt);
                 match interrupt {
+                    ADC => adc::ADC.handle_interrupt(),
+                    CCM_AAR => nrf5x::ccm::CCM.handle_interrupt(),
                     ECB => nrf5x::aes::AESECB.handle_interrupt(),
                     GPIOTE => nrf5x::gpio::PORT.handle_interrupt(),
+                    #[cfg(not(feature = "nrf52840"))]
                     RADIO => ble::radio::RADIO.handle_interrupt(),
+                    #[cfg(feature = "nrf52840")]
+                    RADIO => {
+                        // The board runs the radio in either mode
+                        if ieee802154_radio::RADIO.is_enabled() {
+                            ieee802154_radio::RADIO.handle_interrupt()
+                        } else {
+                            ble::radio::RADIO.handle_interrupt()
+                        }
+                    }
+                    PWM0 => pwm::PWM0.handle_interrupt(),
+                    PWM1 => pwm::PWM1.handle_interrupt(),
+                    PWM2 => pwm::PWM2.handle_interrupt(),
                     RNG => nrf5x::trng::TRNG.handle_interrupt(),
                     RTC1 => nrf5x::rtc::RTC.handle_interrupt(),
                     TEMP => nrf5x::temperature::TEMP.handle_interrupt(),
@@ -51,34 +81,56 @@ impl kernel::Chip for NRF52 {
                     TIMER2 => nrf5x::timer::TIMER2.handle_interrupt(),
                     UART0 => uart::UARTE0.handle_interrupt(),
                     SPI0_TWI0 => {
-                        // SPI0 and TWI0 share interrupts.
+                        // SPI0, TWIM0 and TWIS0 share interrupts.
                         // Dispatch the correct handler.
-                        match (spi::SPIM0.is_enabled(), i2c::TWIM0.is_enabled()) {
-                            (false, false) => (),
-                            (true, false) => spi::SPIM0.handle_interrupt(),
-                            (false, true) => i2c::TWIM0.handle_interrupt(),
-                            (true, true) => debug_assert!(
+                        match (
+                            spi::SPIM0.is_enabled(),
+                            spi::SPIS0.is_enabled(),
+                            i2c::TWIM0.is_enabled(),
+                            i2c::TWIS0.is_enabled(),
+                        ) {
+                            (false, false, false, false) => (),
+                            (true, false, false, false) => spi::SPIM0.handle_interrupt(),
+                            (false, true, false, false) => spi::SPIS0.handle_interrupt(),
+                            (false, false, true, false) => i2c::TWIM0.handle_interrupt(),
+                            (false, false, false, true) => i2c::TWIS0.handle_interrupt(),
+                            _ => debug_assert!(
                                 false,
-                                "SPIM0 and TWIM0 cannot be \
-                                 enabled at the same time."
+                                "Only one of SPIM0, SPIS0, TWIM0 and TWIS0 \
+                                 can be enabled at a time."
                             ),
                         }
                     }
                     SPI1_TWI1 => {
-                        // SPI1 and TWI1 share interrupts.
+                        // SPI1, TWIM1 and TWIS1 share interrupts.
                         // Dispatch the correct handler.
-                        match (spi::SPIM1.is_enabled(), i2c::TWIM1.is_enabled()) {
-                            (false, false) => (),
-                            (true, false) => spi::SPIM1.handle_interrupt(),
-                            (false, true) => i2c::TWIM1.handle_interrupt(),
-                            (true, true) => debug_assert!(
+                        match (
+                            spi::SPIM1.is_enabled(),
+                            spi::SPIS1.is_enabled(),
+                            i2c::TWIM1.is_enabled(),
+                            i2c::TWIS1.is_enabled(),
+                        ) {
+                            (false, false, false, false) => (),
+                            (true, false, false, false) => spi::SPIM1.handle_interrupt(),
+                            (false, true, false, false) => spi::SPIS1.handle_interrupt(),
+                            (false, false, true, false) => i2c::TWIM1.handle_interrupt(),
+                            (false, false, false, true) => i2c::TWIS1.handle_interrupt(),
+                            _ => debug_assert!(
                                 false,
-                                "SPIM1 and TWIM1 cannot be \
-                                 enabled at the same time."
+                                "Only one of SPIM1, SPIS1, TWIM1 and TWIS1 \
+                                 can be enabled at a time."
                             ),
                         }
                     }
-                    SPIM2_SPIS2_SPI2 => spi::SPIM2.handle_interrupt(),
+                    SPIM2_SPIS2_SPI2 => {
+                        if spi::SPIS2.is_enabled() {
+                            spi::SPIS2.handle_interrupt()
+                        } else {
+                            spi::SPIM2.handle_interrupt()
+                        }
+                    }
+                    #[cfg(feature = "nrf52840")]
+                    QSPI => qspi::QSPI.handle_interrupt(),
                     _ => debug!("NvicIdx not supported by Tock"),
                 }
                 let n = nvic::Nvic::new(interrupt);
@@ -89,11 +141,18 @@ impl kernel::Chip for NRF52 {
     }
 
     fn has_pending_interrupts(&self) -> bool {
-        unsafe { nvic::has_pending() }
+        unsafe { nvic::has_pending() || DeferredCall::has_tasks() }
     }
 
     fn sleep(&self) {
         unsafe {
+            let power = &nrf5x::power::POWER;
+            let port = &nrf5x::gpio::PORT;
+            // With no wake source only a reset would end System OFF
+            if power.system_off_requested() && port.wake_sources() != 0 {
+                port.arm_wakeup();
+                power.system_off();
+            }
             support::wfi();
         }
     }
@@ -1,4 +1,4 @@
-use cortexm4::{generic_isr, nvic, svc_handler, systick_handler};
+use cortexm4::{generic_isr, nvic, scb, svc_handler, systick_handler};
 
 /*
  * Adapted from crt1.c which was relicensed by the original author from
@@ -40,7 +40,7 @@ unsafe extern "C" fn unhandled_interrupt() {
 }
 
 unsafe extern "C" fn hard_fault_handler() {
-    use {core, core::intrinsics::offset, kernel};
+    use {core::intrinsics::offset, kernel};
 
     let faulting_stack: *mut u32;
     let kernel_stack: bool;
@@ -70,11 +70,13 @@ unsafe extern "C" fn hard_fault_handler() {
 
         let mode_str = "Kernel";
 
-        let shcsr: u32 = core::ptr::read_volatile(0xE000ED24 as *const u32);
-        let cfsr: u32 = core::ptr::read_volatile(0xE000ED28 as *const u32);
-        let hfsr: u32 = core::ptr::read_volatile(0xE000ED2C as *const u32);
-        let mmfar: u32 = core::ptr::read_volatile(0xE000ED34 as *const u32);
-        let bfar: u32 = core::ptr::read_volatile(0xE000ED38 as *const u32);
+        let scb::FaultStatus {
+            shcsr,
+            cfsr,
+            hfsr,
+            mmfar,
+            bfar,
+        } = scb::fault_status();
 
         let iaccviol = (cfsr & 0x01) == 0x01;
         let daccviol = (cfsr & 0x02) == 0x02;
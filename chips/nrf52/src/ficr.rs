@@ -247,6 +247,13 @@ enum Flash {
     Unspecified = 0xffffffff,
 }
 
+/// Whether the device address is a public or a random address
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum AddressType {
+    Public,
+    Random,
+}
+
 pub struct Ficr {
     registers: *const FicrRegisters,
 }
@@ -258,6 +265,34 @@ impl Ficr {
         }
     }
 
+    /// The 48-bit device address programmed in the factory, least
+    /// significant byte first, in the order it is sent on air
+    pub fn device_address(&self) -> [u8; 6] {
+        let regs = unsafe { &*self.registers };
+        let low = regs.deviceaddr0.read(DeviceAddress0::DEVICEADDRESS);
+        let high = regs.deviceaddr1.read(DeviceAddress1::DEVICEADDRESS);
+        [
+            low as u8,
+            (low >> 8) as u8,
+            (low >> 16) as u8,
+            (low >> 24) as u8,
+            high as u8,
+            (high >> 8) as u8,
+        ]
+    }
+
+    pub fn device_address_type(&self) -> AddressType {
+        let regs = unsafe { &*self.registers };
+        if regs
+            .deviceaddrtype
+            .matches_all(DeviceAddressType::DEVICEADDRESSTYPE::RANDOM)
+        {
+            AddressType::Random
+        } else {
+            AddressType::Public
+        }
+    }
+
     fn part(&self) -> Part {
         let regs = unsafe { &*self.registers };
         match regs.info_part.get() {
@@ -1,13 +1,22 @@
 //! Implementation of I2C for nRF52 using EasyDMA.
 //!
-//! This module supports nRF52's two I2C master (`TWIM`) peripherals,
-//! but not I2C slave (`TWIS`).
+//! This module supports nRF52's two I2C master (`TWIM`) and two I2C slave
+//! (`TWIS`) peripherals. Master and slave instances with the same number
+//! share their registers and interrupt, so only one of them can be enabled
+//! at a time.
+//!
+//! The slave suspends every transfer the master addresses it with until a
+//! buffer has been handed to it for that direction, holding the clock low in
+//! the meantime. A `TWIS` therefore never NACKs its master for lack of a
+//! buffer, it asks its client for one with `read_expected` and
+//! `write_expected` instead.
 //!
 //! - Author: Jay Kickliter
 //! - Author: Andrew Thompson
 //! - Date: Nov 4, 2017
 
 use core::cell::Cell;
+use core::cmp;
 use kernel::common::take_cell::TakeCell;
 use kernel::hil;
 use nrf5x::pinmux::Pinmux;
@@ -222,6 +231,239 @@ pub static mut TWIM0: TWIM = TWIM::new(0);
 /// I2C master instace 1.
 pub static mut TWIM1: TWIM = TWIM::new(1);
 
+/// Direction of a slave transfer that is waiting for a buffer.
+#[derive(Copy, Clone, PartialEq)]
+enum Pending {
+    None,
+    Write,
+    Read,
+}
+
+/// An I2C slave device.
+///
+/// Received bytes are written to the buffer given with `write_receive`,
+/// bytes read by the master come from the buffer given with `read_send`.
+/// Each buffer is returned to the client once the transfer using it ends.
+pub struct TWIS {
+    registers: *const registers::TWIS,
+    client: Cell<Option<&'static hil::i2c::I2CHwSlaveClient>>,
+    rx_buf: TakeCell<'static, [u8]>,
+    rx_len: Cell<u8>,
+    tx_buf: TakeCell<'static, [u8]>,
+    tx_len: Cell<u8>,
+    /// Transfer suspended until a buffer is provided
+    pending: Cell<Pending>,
+    /// Transfer whose buffer is in use by EasyDMA
+    active: Cell<Option<hil::i2c::SlaveTransmissionType>>,
+}
+
+impl TWIS {
+    const fn new(instance: usize) -> TWIS {
+        TWIS {
+            registers: registers::SLAVE_INSTANCES[instance],
+            client: Cell::new(None),
+            rx_buf: TakeCell::empty(),
+            rx_len: Cell::new(0),
+            tx_buf: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            pending: Cell::new(Pending::None),
+            active: Cell::new(None),
+        }
+    }
+
+    pub fn set_client(&self, client: &'static hil::i2c::I2CHwSlaveClient) {
+        debug_assert!(self.client.get().is_none());
+        self.client.set(Some(client));
+    }
+
+    fn regs(&self) -> &registers::TWIS {
+        unsafe { &*self.registers }
+    }
+
+    /// Configures an already constructed `TWIS`.
+    pub fn configure(&self, scl: Pinmux, sda: Pinmux) {
+        let regs = self.regs();
+        regs.psel_scl.set(scl);
+        regs.psel_sda.set(sda);
+    }
+
+    /// Enables hardware TWIS peripheral.
+    pub fn enable(&self) {
+        self.regs().enable.set(9);
+    }
+
+    /// Disables hardware TWIS peripheral.
+    pub fn disable(&self) {
+        self.regs().enable.set(0);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.regs().enable.get() == 9
+    }
+
+    /// Hand the receive buffer to EasyDMA and let the suspended write go on.
+    fn start_write(&self) {
+        self.rx_buf.map(|buf| {
+            self.regs().rxd_ptr.set(buf.as_mut_ptr());
+            self.regs().rxd_maxcnt.set(self.rx_len.get() as u32);
+        });
+        self.active.set(Some(hil::i2c::SlaveTransmissionType::Write));
+        self.pending.set(Pending::None);
+        self.regs().tasks_preparerx.set(1);
+        self.regs().tasks_resume.set(1);
+    }
+
+    /// Hand the transmit buffer to EasyDMA and let the suspended read go on.
+    fn start_read(&self) {
+        self.tx_buf.map(|buf| {
+            self.regs().txd_ptr.set(buf.as_mut_ptr());
+            self.regs().txd_maxcnt.set(self.tx_len.get() as u32);
+        });
+        self.active.set(Some(hil::i2c::SlaveTransmissionType::Read));
+        self.pending.set(Pending::None);
+        self.regs().tasks_preparetx.set(1);
+        self.regs().tasks_resume.set(1);
+    }
+
+    pub fn handle_interrupt(&self) {
+        if self.regs().events_write.get() == 1 {
+            self.regs().events_write.set(0);
+            if self.rx_buf.is_some() {
+                self.start_write();
+            } else {
+                self.pending.set(Pending::Write);
+                self.client.get().map(|client| client.write_expected());
+            }
+        }
+
+        if self.regs().events_read.get() == 1 {
+            self.regs().events_read.set(0);
+            if self.tx_buf.is_some() {
+                self.start_read();
+            } else {
+                self.pending.set(Pending::Read);
+                self.client.get().map(|client| client.read_expected());
+            }
+        }
+
+        if self.regs().events_error.get() == 1 {
+            self.regs().events_error.set(0);
+            // Overflows and overreads are not reported: the master is given
+            // the overread character, or the extra bytes are dropped, and
+            // the transfer ends with `STOPPED` as usual.
+            let errorsrc = self.regs().errorsrc.get();
+            self.regs().errorsrc.set(errorsrc);
+        }
+
+        if self.regs().events_stopped.get() == 1 {
+            self.regs().events_stopped.set(0);
+            match self.active.take() {
+                Some(hil::i2c::SlaveTransmissionType::Write) => {
+                    let amount = self.regs().rxd_amount.get() as u8;
+                    self.rx_buf.take().map(|buf| {
+                        self.client.get().map(move |client| {
+                            client.command_complete(
+                                buf,
+                                amount,
+                                hil::i2c::SlaveTransmissionType::Write,
+                            );
+                        });
+                    });
+                }
+                Some(hil::i2c::SlaveTransmissionType::Read) => {
+                    let amount = self.regs().txd_amount.get() as u8;
+                    self.tx_buf.take().map(|buf| {
+                        self.client.get().map(move |client| {
+                            client.command_complete(
+                                buf,
+                                amount,
+                                hil::i2c::SlaveTransmissionType::Read,
+                            );
+                        });
+                    });
+                }
+                None => (),
+            }
+        }
+
+        // We can blindly clear the following events since we're not using them.
+        self.regs().events_rxstarted.set(0);
+        self.regs().events_txstarted.set(0);
+    }
+}
+
+impl hil::i2c::I2CSlave for TWIS {
+    fn enable(&self) {
+        self.enable();
+    }
+
+    fn disable(&self) {
+        self.regs().intenclr.set({
+            let mut intenclr = registers::SlaveInterruptEnable(0);
+            intenclr.set_stopped(1);
+            intenclr.set_error(1);
+            intenclr.set_write(1);
+            intenclr.set_read(1);
+            intenclr
+        });
+        self.disable();
+        self.pending.set(Pending::None);
+        self.active.set(None);
+    }
+
+    fn set_address(&self, addr: u8) {
+        self.regs().address0.set(addr as u32);
+        self.regs().config.set({
+            let mut config = registers::SlaveConfig(0);
+            config.set_address0(1);
+            config
+        });
+    }
+
+    fn write_receive(&self, data: &'static mut [u8], max_len: u8) {
+        let len = cmp::min(data.len(), max_len as usize) as u8;
+        self.rx_buf.replace(data);
+        self.rx_len.set(len);
+        if self.pending.get() == Pending::Write {
+            self.start_write();
+        }
+    }
+
+    fn read_send(&self, data: &'static mut [u8], max_len: u8) {
+        let len = cmp::min(data.len(), max_len as usize) as u8;
+        self.tx_buf.replace(data);
+        self.tx_len.set(len);
+        if self.pending.get() == Pending::Read {
+            self.start_read();
+        }
+    }
+
+    fn listen(&self) {
+        self.regs().shorts.set({
+            let mut shorts = registers::SlaveShorts(0);
+            // Suspend every transfer once addressed so the buffer for it
+            // can be set up from the interrupt handler, or by the client if
+            // there is none yet.
+            shorts.set_write_suspend(1);
+            shorts.set_read_suspend(1);
+            shorts
+        });
+        self.regs().intenset.set({
+            let mut intenset = registers::SlaveInterruptEnable(0);
+            intenset.set_stopped(1);
+            intenset.set_error(1);
+            intenset.set_write(1);
+            intenset.set_read(1);
+            intenset
+        });
+    }
+}
+
+/// I2C slave instance 0.
+pub static mut TWIS0: TWIS = TWIS::new(0);
+/// I2C slave instance 1.
+pub static mut TWIS1: TWIS = TWIS::new(1);
+
 // SPI0_TWI0_Handler and SPI1_TWI1_Handler live in
 // `spi.rs`. `service_pending_interrupts` dispatches the correct
 // handler based on which peripheral is enabled.
@@ -419,4 +661,181 @@ mod registers {
         /// addr = base + 0x588
         pub address: VolatileCell<u32>,
     }
+
+    bitfield!{
+        /// Represents bitfields in the TWIS `shorts` register.
+        #[derive(Copy, Clone)]
+        pub struct SlaveShorts(u32);
+        impl Debug;
+        pub write_suspend, set_write_suspend: 13, 13;
+        pub read_suspend,  set_read_suspend:  14, 14;
+    }
+
+    bitfield!{
+        /// Represents bitfields in the TWIS `intenset` and `intenclr`
+        /// registers.
+        #[derive(Copy, Clone)]
+        pub struct SlaveInterruptEnable(u32);
+        impl Debug;
+        pub stopped,   set_stopped:    1,  1;
+        pub error,     set_error:      9,  9;
+        pub rxstarted, set_rxstarted: 19, 19;
+        pub txstarted, set_txstarted: 20, 20;
+        pub write,     set_write:     25, 25;
+        pub read,      set_read:      26, 26;
+    }
+
+    bitfield!{
+        /// Represents bitfields in the TWIS `config` register.
+        #[derive(Copy, Clone)]
+        pub struct SlaveConfig(u32);
+        impl Debug;
+        pub address0, set_address0: 0, 0;
+        pub address1, set_address1: 1, 1;
+    }
+
+    /// Uninitialized `TWIS` instances, sharing their registers with the
+    /// `TWIM` instances.
+    pub const SLAVE_INSTANCES: [*const TWIS; 2] =
+        [0x40003000 as *const TWIS, 0x40004000 as *const TWIS];
+
+    pub struct TWIS {
+        _reserved_0: [u32; 5],
+        /// Stop TWI transaction
+        ///
+        /// addr = base + 0x014
+        pub tasks_stop: VolatileCell<u32>,
+        _reserved_1: [u32; 1],
+        /// Suspend TWI transaction
+        ///
+        /// addr = base + 0x01C
+        pub tasks_suspend: VolatileCell<u32>,
+        /// Resume TWI transaction
+        ///
+        /// addr = base + 0x020
+        pub tasks_resume: VolatileCell<u32>,
+        _reserved_2: [u32; 3],
+        /// Prepare the TWI slave to respond to a write command
+        ///
+        /// addr = base + 0x030
+        pub tasks_preparerx: VolatileCell<u32>,
+        /// Prepare the TWI slave to respond to a read command
+        ///
+        /// addr = base + 0x034
+        pub tasks_preparetx: VolatileCell<u32>,
+        _reserved_3: [u32; 51],
+        /// TWI stopped
+        ///
+        /// addr = base + 0x104
+        pub events_stopped: VolatileCell<u32>,
+        _reserved_4: [u32; 7],
+        /// TWI error
+        ///
+        /// addr = base + 0x124
+        pub events_error: VolatileCell<u32>,
+        _reserved_5: [u32; 9],
+        /// Receive sequence started
+        ///
+        /// addr = base + 0x14C
+        pub events_rxstarted: VolatileCell<u32>,
+        /// Transmit sequence started
+        ///
+        /// addr = base + 0x150
+        pub events_txstarted: VolatileCell<u32>,
+        _reserved_6: [u32; 4],
+        /// Write command received
+        ///
+        /// addr = base + 0x164
+        pub events_write: VolatileCell<u32>,
+        /// Read command received
+        ///
+        /// addr = base + 0x168
+        pub events_read: VolatileCell<u32>,
+        _reserved_7: [u32; 37],
+        /// Shortcut register
+        ///
+        /// addr = base + 0x200
+        pub shorts: VolatileCell<SlaveShorts>,
+        _reserved_8: [u32; 63],
+        /// Enable or disable interrupt
+        ///
+        /// addr = base + 0x300
+        pub inten: VolatileCell<SlaveInterruptEnable>,
+        /// Enable interrupt
+        ///
+        /// addr = base + 0x304
+        pub intenset: VolatileCell<SlaveInterruptEnable>,
+        /// Disable interrupt
+        ///
+        /// addr = base + 0x308
+        pub intenclr: VolatileCell<SlaveInterruptEnable>,
+        _reserved_9: [u32; 113],
+        /// Error source, cleared by writing 1 to the set bits
+        ///
+        /// addr = base + 0x4D0
+        pub errorsrc: VolatileCell<u32>,
+        /// Status register indicating which address had a match
+        ///
+        /// addr = base + 0x4D4
+        pub match_: VolatileCell<u32>,
+        _reserved_10: [u32; 10],
+        /// Enable TWIS
+        ///
+        /// addr = base + 0x500
+        pub enable: VolatileCell<u32>,
+        _reserved_11: [u32; 1],
+        /// Pin select for SCL signal
+        ///
+        /// addr = base + 0x508
+        pub psel_scl: VolatileCell<Pinmux>,
+        /// Pin select for SDA signal
+        ///
+        /// addr = base + 0x50C
+        pub psel_sda: VolatileCell<Pinmux>,
+        _reserved_12: [u32; 9],
+        /// RXD data pointer
+        ///
+        /// addr = base + 0x534
+        pub rxd_ptr: VolatileCell<*mut u8>,
+        /// Maximum number of bytes in RXD buffer
+        ///
+        /// addr = base + 0x538
+        pub rxd_maxcnt: VolatileCell<u32>,
+        /// Number of bytes transferred in the last RXD transaction
+        ///
+        /// addr = base + 0x53C
+        pub rxd_amount: VolatileCell<u32>,
+        _reserved_13: [u32; 1],
+        /// TXD data pointer
+        ///
+        /// addr = base + 0x544
+        pub txd_ptr: VolatileCell<*mut u8>,
+        /// Maximum number of bytes in TXD buffer
+        ///
+        /// addr = base + 0x548
+        pub txd_maxcnt: VolatileCell<u32>,
+        /// Number of bytes transferred in the last TXD transaction
+        ///
+        /// addr = base + 0x54C
+        pub txd_amount: VolatileCell<u32>,
+        _reserved_14: [u32; 14],
+        /// TWI slave address 0
+        ///
+        /// addr = base + 0x588
+        pub address0: VolatileCell<u32>,
+        /// TWI slave address 1
+        ///
+        /// addr = base + 0x58C
+        pub address1: VolatileCell<u32>,
+        _reserved_15: [u32; 1],
+        /// Configuration register for the address match mechanism
+        ///
+        /// addr = base + 0x594
+        pub config: VolatileCell<SlaveConfig>,
+        _reserved_16: [u32; 10],
+        /// Over-read character, sent when the TXD buffer runs out
+        ///
+        /// addr = base + 0x5C0
+        pub orc: VolatileCell<u32>,
+    }
 }
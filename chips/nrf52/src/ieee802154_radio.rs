@@ -0,0 +1,901 @@
+//! Radio driver, IEEE 802.15.4, nRF52840
+//!
+//! The nRF52840 radio has a 250 kbit/s IEEE 802.15.4 mode next to the BLE
+//! modes used by `ble::radio`. This driver runs the radio in that mode and
+//! implements `hil::radio::Radio`, so the 802.15.4 MAC, framer and 6LoWPAN
+//! capsules can use it the way they use the RF233 on other boards.
+//!
+//! The radio runs either BLE or 802.15.4, chosen by the board: calling
+//! `initialize` on this driver selects 802.15.4 and routes the RADIO
+//! interrupt here. Both drivers use TIMER0 and the pre-programmed PPI
+//! channels 20, 21, 22 and 27, and reserve them, so initializing both
+//! panics.
+//!
+//! While on, the radio listens on the configured channel. A transmission
+//! goes through unslotted CSMA-CA: after a random backoff, a clear channel
+//! assessment is done in hardware, the CCAIDLE event enabling the
+//! transmitter through a shortcut, and a busy channel is assessed again
+//! after a random backoff drawn from a range twice as large. The backoffs
+//! are drawn from the RNG given with `set_rng`, and from a software
+//! generator without one. Frames requesting an acknowledgement are
+//! followed by a wait for the ACK, ended by TIMER0 through PPI.
+//!
+//! Received frames are filtered on their destination PAN ID and address in
+//! software. Frames addressed to this node that request an acknowledgement
+//! are acknowledged aTurnaroundTime after their end: RADIO.END captures
+//! TIMER0 through PPI, and a TIMER0 compare starts the transmitter through
+//! another PPI channel, so the timing does not depend on interrupt latency.
+//!
+//! Frame buffers have the layout of `hil::radio`: one unused byte, the PHY
+//! header and the PSDU. The radio reads and writes the PHY header and PSDU
+//! directly with EasyDMA.
+//!
+//! The radio holds a request on the high frequency clock from `start` to
+//! `stop`, since it listens all the while.
+//!
+//! Only built with the `nrf52840` feature, the nRF52832 has no 802.15.4
+//! mode.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let radio = &nrf52::ieee802154_radio::RADIO;
+//! radio.initialize(&mut RADIO_BUF, &mut RADIO_REG_WRITE, &mut RADIO_REG_READ);
+//! let mac = static_init!(
+//!     capsules::ieee802154::mac::AwakeMac<'static, nrf52::ieee802154_radio::Radio>,
+//!     capsules::ieee802154::mac::AwakeMac::new(radio)
+//! );
+//! radio.set_transmit_client(mac);
+//! radio.set_receive_client(mac, &mut RADIO_RX_BUF);
+//! // An RngDevice of the board's MuxRng on the TRNG
+//! radio_rng.set_client(radio);
+//! radio.set_rng(radio_rng);
+//! radio.start();
+//! ```
+
+use clock;
+use core::cell::Cell;
+use core::cmp;
+use core::convert::TryFrom;
+use kernel::common::regs::FieldValue;
+use kernel::common::take_cell::TakeCell;
+use kernel::hil::radio::{self, RadioConfig};
+use kernel::hil::rng;
+use kernel::ReturnCode;
+use nrf5x;
+use nrf5x::constants::TxPower;
+use nrf5x::timer::BitmodeValue;
+use ppi;
+use radio::{
+    CcaControl, CrcConfiguration, Event, Interrupt, Mode, PacketConfiguration0,
+    PacketConfiguration1, RadioModeConfig, RadioRegisters, Shortcut, StartOfFrameDelimiter, Task,
+    RADIO_BASE,
+};
+
+// Pre-programmed PPI channels, see `ble::radio`:
+//
+// * CH20: TIMER0.EVENTS_COMPARE[0] -> RADIO.TASKS_TXEN, sends ACKs
+// * CH21: TIMER0.EVENTS_COMPARE[0] -> RADIO.TASKS_RXEN, ends CCA backoffs
+// * CH22: TIMER0.EVENTS_COMPARE[1] -> RADIO.TASKS_DISABLE, ends ACK waits
+// * CH27: RADIO.EVENTS_END -> TIMER0.TASKS_CAPTURE[2]
+const RADIO_PPI_CHANNELS: [usize; 4] = [20, 21, 22, 27];
+
+/// Time from the end of a frame to the start of its ACK, 12 symbols
+const TURNAROUND_US: u32 = 192;
+/// Ramp-up time of the transmitter in fast mode
+const TX_RAMPUP_US: u32 = 40;
+/// Time from the end of a frame to the end of its ACK: macAckWaitDuration,
+/// 54 symbols, plus the 11 bytes of an ACK on air
+const ACK_TIMEOUT_US: u32 = 864 + 11 * 32;
+/// aUnitBackoffPeriod, 20 symbols
+const UNIT_BACKOFF_US: u32 = 320;
+/// macMinBE and macMaxBE: a backoff lasts 0 to 2^BE - 1 unit backoff
+/// periods, BE starting at macMinBE and growing by one with every busy CCA
+const MIN_BACKOFF_EXPONENT: usize = 3;
+const MAX_BACKOFF_EXPONENT: usize = 5;
+/// Clear channel assessments before a transmission fails with EBUSY
+const MAX_CCA_ATTEMPTS: usize = 5;
+/// Energy level above which the channel is busy, in ED units
+const CCA_ED_THRESHOLD: u32 = 0x14;
+
+/// IEEE 802.15.4 start of frame delimiter
+const SFD: u32 = 0xA7;
+/// CRC-16/CCITT polynomial x^16 + x^12 + x^5 + 1
+const CRC_POLYNOMIAL: u32 = 0x11021;
+
+// Frame control field
+const FRAME_TYPE_MASK: u8 = 0x07;
+const FRAME_TYPE_ACK: u8 = 0x02;
+const ACK_REQUEST: u16 = 1 << 5;
+const PAN_ID_COMPRESSION: u16 = 1 << 6;
+const DST_MODE_POS: u16 = 10;
+const FRAME_VERSION_POS: u16 = 12;
+const SRC_MODE_POS: u16 = 14;
+const ADDR_MODE_NONE: u16 = 0;
+const ADDR_MODE_SHORT: u16 = 2;
+const ADDR_MODE_LONG: u16 = 3;
+const FRAME_VERSION_2015: u16 = 2;
+
+const BROADCAST: u16 = 0xFFFF;
+
+/// Length of an ACK PSDU: frame control, sequence number and FCS
+const ACK_PSDU_LEN: usize = 5;
+
+// ACKs sent and received, and frames received while no receive buffer is
+// available, which are dropped
+static mut ACK_BUF: [u8; radio::MAX_BUF_SIZE] = [0; radio::MAX_BUF_SIZE];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum State {
+    Off,
+    /// Listening for frames
+    Rx,
+    /// Listening is being stopped to do something else
+    Aborting,
+    /// Assessing the channel, backing off or transmitting `tx_buf`
+    Cca,
+    /// Waiting for the ACK of `tx_buf`
+    AckWait,
+    /// Acknowledging the frame in `rx_buf`
+    AckTx,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Destination {
+    /// Addressed to this node
+    Us,
+    /// Broadcast, or without destination address
+    All,
+    /// For another node or PAN
+    Other,
+}
+
+pub struct Radio {
+    regs: *const RadioRegisters,
+    state: Cell<State>,
+    /// The board selected this driver
+    enabled: Cell<bool>,
+    /// The radio listens into `rx_buf`, rather than `ACK_BUF`
+    rx_into_buffer: Cell<bool>,
+    rx_crc_valid: Cell<bool>,
+    tx_buf: TakeCell<'static, [u8]>,
+    rx_buf: TakeCell<'static, [u8]>,
+    cca_attempts: Cell<usize>,
+    /// Source of the backoffs, see `set_rng`
+    rng: Cell<Option<&'static rng::RNG>>,
+    /// Random bits from the RNG not used for a backoff yet, and how many
+    randomness: Cell<u32>,
+    randomness_bits: Cell<usize>,
+    randomness_requested: Cell<bool>,
+    /// State of the software generator standing in while the RNG has no
+    /// bits at hand
+    random_nonce: Cell<u32>,
+    config_pending: Cell<bool>,
+    power_pending: Cell<bool>,
+    addr: Cell<u16>,
+    addr_long: Cell<[u8; 8]>,
+    pan: Cell<u16>,
+    tx_power: Cell<TxPower>,
+    channel: Cell<u8>,
+    tx_client: Cell<Option<&'static radio::TxClient>>,
+    rx_client: Cell<Option<&'static radio::RxClient>>,
+    config_client: Cell<Option<&'static radio::ConfigClient>>,
+    power_client: Cell<Option<&'static radio::PowerClient>>,
+}
+
+pub static mut RADIO: Radio = Radio::new();
+
+impl Radio {
+    pub const fn new() -> Radio {
+        Radio {
+            regs: RADIO_BASE as *const RadioRegisters,
+            state: Cell::new(State::Off),
+            enabled: Cell::new(false),
+            rx_into_buffer: Cell::new(false),
+            rx_crc_valid: Cell::new(false),
+            tx_buf: TakeCell::empty(),
+            rx_buf: TakeCell::empty(),
+            cca_attempts: Cell::new(0),
+            rng: Cell::new(None),
+            randomness: Cell::new(0),
+            randomness_bits: Cell::new(0),
+            randomness_requested: Cell::new(false),
+            random_nonce: Cell::new(0x2545_f491),
+            config_pending: Cell::new(false),
+            power_pending: Cell::new(false),
+            addr: Cell::new(0),
+            addr_long: Cell::new([0; 8]),
+            pan: Cell::new(0),
+            tx_power: Cell::new(TxPower::ZerodBm),
+            channel: Cell::new(26),
+            tx_client: Cell::new(None),
+            rx_client: Cell::new(None),
+            config_client: Cell::new(None),
+            power_client: Cell::new(None),
+        }
+    }
+
+    /// The board runs the radio in 802.15.4 mode.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.get()
+    }
+
+    /// Draw the CSMA-CA backoffs from `rng`. The driver must also be set as
+    /// the client of `rng`.
+    pub fn set_rng(&self, rng: &'static rng::RNG) {
+        self.rng.set(Some(rng));
+        self.request_randomness();
+    }
+
+    fn request_randomness(&self) {
+        if self.randomness_bits.get() < MAX_BACKOFF_EXPONENT && !self.randomness_requested.get() {
+            self.rng.get().map(|rng| {
+                self.randomness_requested.set(true);
+                rng.get();
+            });
+        }
+    }
+
+    // `bits` random bits, from the RNG if it has enough at hand, from the
+    // software generator otherwise
+    fn random_bits(&self, bits: usize) -> u32 {
+        let available = self.randomness_bits.get();
+        let random = if available >= bits {
+            let random = self.randomness.get();
+            self.randomness.set(random >> bits);
+            self.randomness_bits.set(available - bits);
+            random
+        } else {
+            // Xorshift, stirred with the time so that nodes booted alike
+            // do not back off alike
+            let mut nonce = self.random_nonce.get() ^ self.now();
+            nonce ^= nonce << 13;
+            nonce ^= nonce >> 17;
+            nonce ^= nonce << 5;
+            // Xorshift never leaves 0
+            self.random_nonce.set(if nonce == 0 { 1 } else { nonce });
+            nonce
+        };
+        self.request_randomness();
+        random & ((1 << bits) - 1)
+    }
+
+    fn configure(&self) {
+        let regs = unsafe { &*self.regs };
+
+        // Power cycling resets the configuration
+        regs.power.set(0);
+        regs.power.set(1);
+
+        regs.mode.write(Mode::MODE::IEEE802154_250KBIT);
+        regs.modecnf0
+            .write(RadioModeConfig::RU::FAST + RadioModeConfig::DTX::CENTER);
+
+        // 32 zero bits of preamble, the PHY header counting the FCS
+        regs.pcnf0.write(
+            PacketConfiguration0::LFLEN.val(8)
+                + PacketConfiguration0::PLEN::THIRTYTWOZEROS
+                + PacketConfiguration0::CRCINC::INCLUDE,
+        );
+        regs.pcnf1
+            .write(PacketConfiguration1::MAXLEN.val(radio::MAX_MTU as u32));
+        regs.sfd.write(StartOfFrameDelimiter::SFD.val(SFD));
+
+        regs.crccnf
+            .write(CrcConfiguration::LEN::TWO + CrcConfiguration::SKIPADDR::IEEE802154);
+        regs.crcpoly.set(CRC_POLYNOMIAL);
+        regs.crcinit.set(0);
+
+        regs.ccactrl
+            .write(CcaControl::CCAMODE::EDMODE + CcaControl::CCAEDTHRES.val(CCA_ED_THRESHOLD));
+
+        self.apply_config();
+    }
+
+    fn apply_config(&self) {
+        let regs = unsafe { &*self.regs };
+        // Channels 11 to 26 are 2405 MHz to 2480 MHz, 5 MHz apart
+        regs.frequency.set(5 + 5 * (self.channel.get() as u32 - 11));
+        regs.txpower.set(self.tx_power.get() as u32);
+    }
+
+    fn enable_ppi(&self, channels: FieldValue<u32, ppi::Channel::Register>) {
+        unsafe {
+            ppi::PPI.enable(channels);
+        }
+    }
+
+    fn disable_ppi(&self, channels: FieldValue<u32, ppi::Channel::Register>) {
+        unsafe {
+            ppi::PPI.disable(channels);
+        }
+    }
+
+    fn set_cc(&self, which: usize, usec: u32) {
+        unsafe {
+            match which {
+                0 => nrf5x::timer::TIMER0.set_cc0(usec),
+                _ => nrf5x::timer::TIMER0.set_cc1(usec),
+            }
+            nrf5x::timer::TIMER0.events_compare()[which].set(0);
+        }
+    }
+
+    fn now(&self) -> u32 {
+        unsafe { nrf5x::timer::TIMER0.capture(3) }
+    }
+
+    /// Time the last frame sent or received ended, captured through CH27
+    fn frame_end_time(&self) -> u32 {
+        unsafe { nrf5x::timer::TIMER0.get_cc2() }
+    }
+
+    // Timestamps wrap, the deadline has passed if it is less than half the
+    // timer range behind now
+    fn deadline_passed(&self, deadline: u32) -> bool {
+        (self.now().wrapping_sub(deadline) as i32) >= 0
+    }
+
+    fn frame_incoming(&self) -> bool {
+        let regs = unsafe { &*self.regs };
+        regs.event_framestart.is_set(Event::READY)
+    }
+
+    fn listen(&self) {
+        let regs = unsafe { &*self.regs };
+
+        self.state.set(State::Rx);
+        let into_buffer = self.rx_buf.is_some();
+        self.rx_into_buffer.set(into_buffer);
+        let ptr = self
+            .rx_buf
+            .map_or(unsafe { &ACK_BUF[1] as *const u8 as u32 }, |buf| {
+                &buf[1] as *const u8 as u32
+            });
+        regs.packetptr.set(ptr);
+
+        regs.event_framestart.write(Event::READY::CLEAR);
+        regs.event_end.write(Event::READY::CLEAR);
+        regs.shorts
+            .write(Shortcut::RXREADY_START::SET + Shortcut::END_DISABLE::SET);
+        regs.task_rxen.write(Task::ENABLE::SET);
+    }
+
+    /// Stop listening to do something else. A frame being received is
+    /// finished first, its DISABLED event then gets there.
+    fn interrupt_rx(&self) {
+        let regs = unsafe { &*self.regs };
+        if self.state.get() == State::Rx && !self.frame_incoming() {
+            self.state.set(State::Aborting);
+            regs.task_disable.write(Task::ENABLE::SET);
+        }
+    }
+
+    /// Continue with whatever is pending once the radio is disabled.
+    fn next(&self) {
+        self.state.set(State::Aborting);
+        if self.config_pending.get() {
+            self.config_pending.set(false);
+            self.apply_config();
+            self.config_client
+                .get()
+                .map(|client| client.config_done(ReturnCode::SUCCESS));
+        }
+        // The config client may have stopped the radio or queued a
+        // transmission
+        if self.state.get() == State::Off {
+            return;
+        }
+        if self.tx_buf.is_some() {
+            self.backoff(0);
+        } else {
+            self.listen();
+        }
+    }
+
+    fn setup_cca(&self) {
+        let regs = unsafe { &*self.regs };
+
+        self.state.set(State::Cca);
+        self.tx_buf.map(|buf| {
+            regs.packetptr.set(&buf[1] as *const u8 as u32);
+        });
+
+        regs.event_ccabusy.write(Event::READY::CLEAR);
+        regs.event_end.write(Event::READY::CLEAR);
+        regs.shorts.write(
+            Shortcut::RXREADY_CCASTART::SET
+                + Shortcut::CCAIDLE_TXEN::SET
+                + Shortcut::CCABUSY_DISABLE::SET
+                + Shortcut::TXREADY_START::SET
+                + Shortcut::END_DISABLE::SET,
+        );
+    }
+
+    fn start_cca(&self) {
+        let regs = unsafe { &*self.regs };
+        self.setup_cca();
+        regs.task_rxen.write(Task::ENABLE::SET);
+    }
+
+    /// Assess the channel after a random backoff, `busy` CCAs of the frame
+    /// having found it busy so far
+    fn backoff(&self, busy: usize) {
+        let exponent = cmp::min(MIN_BACKOFF_EXPONENT + busy, MAX_BACKOFF_EXPONENT);
+        let periods = self.random_bits(exponent);
+        if periods == 0 {
+            self.start_cca();
+            return;
+        }
+        self.setup_cca();
+        // CH21: CC[0] => RXEN
+        self.set_cc(0, self.now().wrapping_add(UNIT_BACKOFF_US * periods));
+        self.enable_ppi(ppi::Channel::CH21::SET);
+    }
+
+    fn cca_done(&self) {
+        let regs = unsafe { &*self.regs };
+
+        if regs.event_ccabusy.is_set(Event::READY) {
+            let attempts = self.cca_attempts.get() + 1;
+            self.cca_attempts.set(attempts);
+            if attempts < MAX_CCA_ATTEMPTS {
+                self.backoff(attempts);
+            } else {
+                self.finish_tx(false, ReturnCode::EBUSY);
+            }
+            return;
+        }
+
+        let ack_requested = self.tx_buf.map_or(false, |buf| {
+            frame_control(&buf[radio::PSDU_OFFSET..]) & ACK_REQUEST != 0
+        });
+        if ack_requested {
+            self.wait_for_ack();
+        } else {
+            self.finish_tx(false, ReturnCode::SUCCESS);
+        }
+    }
+
+    fn wait_for_ack(&self) {
+        let regs = unsafe { &*self.regs };
+
+        self.state.set(State::AckWait);
+        unsafe {
+            regs.packetptr.set(&ACK_BUF[1] as *const u8 as u32);
+        }
+        regs.event_end.write(Event::READY::CLEAR);
+        regs.shorts
+            .write(Shortcut::RXREADY_START::SET + Shortcut::END_DISABLE::SET);
+
+        // CH22: CC[1] => DISABLE
+        self.set_cc(1, self.frame_end_time().wrapping_add(ACK_TIMEOUT_US));
+        self.enable_ppi(ppi::Channel::CH22::SET);
+
+        regs.task_rxen.write(Task::ENABLE::SET);
+    }
+
+    fn ack_wait_done(&self) {
+        let regs = unsafe { &*self.regs };
+
+        // Anything else than the ACK ends the wait as well
+        let received = regs.event_end.is_set(Event::READY) && regs.crcstatus.is_set(Event::READY);
+        let sequence = self
+            .tx_buf
+            .map_or(None, |buf| Some(buf[radio::PSDU_OFFSET + 2]));
+        let acked = received
+            && unsafe {
+                ACK_BUF[1] as usize == ACK_PSDU_LEN
+                    && ACK_BUF[radio::PSDU_OFFSET] & FRAME_TYPE_MASK == FRAME_TYPE_ACK
+                    && Some(ACK_BUF[radio::PSDU_OFFSET + 2]) == sequence
+            };
+        self.finish_tx(acked, ReturnCode::SUCCESS);
+    }
+
+    fn finish_tx(&self, acked: bool, result: ReturnCode) {
+        let buf = self.tx_buf.take();
+        self.next();
+        buf.map(|buf| {
+            self.tx_client
+                .get()
+                .map(move |client| client.send_done(buf, acked, result));
+        });
+    }
+
+    fn destination(&self, psdu: &[u8]) -> Destination {
+        let fc = frame_control(psdu);
+        let dst_mode = (fc >> DST_MODE_POS) & 0x3;
+        let src_mode = (fc >> SRC_MODE_POS) & 0x3;
+        if dst_mode == ADDR_MODE_NONE {
+            return Destination::All;
+        }
+
+        // Only 2015 frames may omit the destination PAN ID of a destination
+        // address
+        let mut offset = 3;
+        let version = (fc >> FRAME_VERSION_POS) & 0x3;
+        if version != FRAME_VERSION_2015
+            || src_mode != ADDR_MODE_NONE
+            || fc & PAN_ID_COMPRESSION == 0
+        {
+            let pan = psdu[offset] as u16 | (psdu[offset + 1] as u16) << 8;
+            if pan != self.pan.get() && pan != BROADCAST {
+                return Destination::Other;
+            }
+            offset += 2;
+        }
+
+        match dst_mode {
+            ADDR_MODE_SHORT => {
+                let addr = psdu[offset] as u16 | (psdu[offset + 1] as u16) << 8;
+                if addr == BROADCAST {
+                    Destination::All
+                } else if addr == self.addr.get() {
+                    Destination::Us
+                } else {
+                    Destination::Other
+                }
+            }
+            ADDR_MODE_LONG => {
+                // Long addresses go over the air least significant byte first
+                let addr_long = self.addr_long.get();
+                let matches = psdu[offset..offset + 8]
+                    .iter()
+                    .rev()
+                    .zip(addr_long.iter())
+                    .all(|(a, b)| a == b);
+                if matches {
+                    Destination::Us
+                } else {
+                    Destination::Other
+                }
+            }
+            _ => Destination::Other,
+        }
+    }
+
+    fn rx_done(&self) {
+        let regs = unsafe { &*self.regs };
+
+        if !regs.event_end.is_set(Event::READY) || !self.rx_into_buffer.get() {
+            // Nothing received, or no buffer to deliver the frame in
+            self.next();
+            return;
+        }
+
+        let crc_valid = regs.crcstatus.is_set(Event::READY);
+        let (destination, ack_requested, sequence) =
+            self.rx_buf.map_or((Destination::Other, false, 0), |buf| {
+                let psdu_len = buf[1] as usize;
+                if psdu_len < radio::MIN_FRAME_SIZE {
+                    return (Destination::Other, false, 0);
+                }
+                let psdu = &buf[radio::PSDU_OFFSET..radio::PSDU_OFFSET + psdu_len];
+                (
+                    self.destination(psdu),
+                    frame_control(psdu) & ACK_REQUEST != 0,
+                    psdu[2],
+                )
+            });
+
+        self.rx_crc_valid.set(crc_valid);
+        if destination == Destination::Other {
+            self.next();
+        } else if destination == Destination::Us && ack_requested && crc_valid {
+            // The frame is delivered once acknowledged
+            self.send_ack(sequence);
+        } else {
+            self.deliver_frame();
+        }
+    }
+
+    fn send_ack(&self, sequence: u8) {
+        let regs = unsafe { &*self.regs };
+
+        self.state.set(State::AckTx);
+        unsafe {
+            ACK_BUF[1] = ACK_PSDU_LEN as u8;
+            ACK_BUF[radio::PSDU_OFFSET] = FRAME_TYPE_ACK;
+            ACK_BUF[radio::PSDU_OFFSET + 1] = 0;
+            ACK_BUF[radio::PSDU_OFFSET + 2] = sequence;
+            regs.packetptr.set(&ACK_BUF[1] as *const u8 as u32);
+        }
+        regs.shorts
+            .write(Shortcut::TXREADY_START::SET + Shortcut::END_DISABLE::SET);
+
+        // CH20: CC[0] => TXEN
+        let time = self
+            .frame_end_time()
+            .wrapping_add(TURNAROUND_US - TX_RAMPUP_US);
+        self.set_cc(0, time);
+        self.enable_ppi(ppi::Channel::CH20::SET);
+
+        // If the interrupt came too late for the compare, send the ACK late
+        // rather than never
+        if self.deadline_passed(time) && regs.state.get() == nrf5x::constants::RADIO_STATE_DISABLE {
+            self.disable_ppi(ppi::Channel::CH20::SET);
+            regs.task_txen.write(Task::ENABLE::SET);
+        }
+    }
+
+    fn deliver_frame(&self) {
+        let crc_valid = self.rx_crc_valid.get();
+        let buf = self.rx_buf.take();
+        self.next();
+        buf.map(|buf| {
+            let frame_len = buf[1] as usize - radio::MFR_SIZE;
+            self.rx_client
+                .get()
+                .map(move |client| client.receive(buf, frame_len, crc_valid, ReturnCode::SUCCESS));
+        });
+    }
+
+    pub fn handle_interrupt(&self) {
+        let regs = unsafe { &*self.regs };
+
+        if self.power_pending.get() && regs.event_ready.is_set(Event::READY) {
+            regs.event_ready.write(Event::READY::CLEAR);
+            regs.intenclr.write(Interrupt::READY::SET);
+            self.power_pending.set(false);
+            self.power_client.get().map(|client| client.changed(true));
+            // The configuration was applied when the radio was turned on
+            if self.config_pending.get() {
+                self.config_pending.set(false);
+                self.config_client
+                    .get()
+                    .map(|client| client.config_done(ReturnCode::SUCCESS));
+            }
+        }
+
+        if regs.event_disabled.is_set(Event::READY) {
+            regs.event_disabled.write(Event::READY::CLEAR);
+            self.disable_ppi(
+                ppi::Channel::CH20::SET + ppi::Channel::CH21::SET + ppi::Channel::CH22::SET,
+            );
+
+            match self.state.get() {
+                State::Rx => self.rx_done(),
+                State::Aborting => self.next(),
+                State::Cca => self.cca_done(),
+                State::AckWait => self.ack_wait_done(),
+                State::AckTx => self.deliver_frame(),
+                State::Off => {}
+            }
+        }
+    }
+}
+
+fn frame_control(psdu: &[u8]) -> u16 {
+    psdu[0] as u16 | (psdu[1] as u16) << 8
+}
+
+impl radio::Radio for Radio {}
+
+impl radio::RadioConfig for Radio {
+    /// The buffers are only needed by radios behind a SPI bus, and unused.
+    fn initialize(
+        &self,
+        _spi_buf: &'static mut [u8],
+        _reg_write: &'static mut [u8],
+        _reg_read: &'static mut [u8],
+    ) -> ReturnCode {
+        if self.enabled.get() {
+            return ReturnCode::EALREADY;
+        }
+        for &channel in RADIO_PPI_CHANNELS.iter() {
+            if unsafe { nrf5x::ppi::PPI.reserve(channel) } != ReturnCode::SUCCESS {
+                panic!("PPI channel {} used by the radio is already taken", channel);
+            }
+        }
+        // CC0 and CC1 are compared against for ACKs and backoffs, CC2
+        // captures END and CC3 reads the time
+        for cc in 0..nrf5x::timer::NUM_CC {
+            if unsafe { nrf5x::timer::TIMER0.reserve_cc(cc) } != ReturnCode::SUCCESS {
+                panic!("TIMER0 CC{} used by the radio is already taken", cc);
+            }
+        }
+        self.enabled.set(true);
+
+        // CH27: RADIO.EVENTS_END -> TIMER0.TASKS_CAPTURE[2]
+        self.enable_ppi(ppi::Channel::CH27::SET);
+        unsafe {
+            nrf5x::timer::TIMER0.set_prescaler(4);
+            nrf5x::timer::TIMER0.set_bitmode(BitmodeValue::Size32Bits);
+            nrf5x::timer::TIMER0.start();
+        }
+        ReturnCode::SUCCESS
+    }
+
+    fn reset(&self) -> ReturnCode {
+        if self.busy() {
+            return ReturnCode::EBUSY;
+        }
+        if self.is_on() {
+            self.stop()
+        } else {
+            ReturnCode::SUCCESS
+        }
+    }
+
+    fn start(&self) -> ReturnCode {
+        let regs = unsafe { &*self.regs };
+
+        if !self.enabled.get() {
+            return ReturnCode::EOFF;
+        } else if self.is_on() {
+            return ReturnCode::EALREADY;
+        }
+
+        unsafe { clock::CLOCK.request(clock::ClockDomain::High) };
+        self.configure();
+        regs.event_ready.write(Event::READY::CLEAR);
+        regs.event_disabled.write(Event::READY::CLEAR);
+        self.power_pending.set(true);
+        regs.intenset
+            .write(Interrupt::READY::SET + Interrupt::DISABLED::SET);
+        self.listen();
+        ReturnCode::SUCCESS
+    }
+
+    fn stop(&self) -> ReturnCode {
+        let regs = unsafe { &*self.regs };
+
+        if !self.is_on() {
+            return ReturnCode::EALREADY;
+        } else if self.busy() {
+            return ReturnCode::EBUSY;
+        }
+
+        self.state.set(State::Off);
+        self.power_pending.set(false);
+        regs.intenclr.set(0xffffffff);
+        self.disable_ppi(
+            ppi::Channel::CH20::SET + ppi::Channel::CH21::SET + ppi::Channel::CH22::SET,
+        );
+        regs.shorts.set(0);
+        regs.task_disable.write(Task::ENABLE::SET);
+        while regs.state.get() != nrf5x::constants::RADIO_STATE_DISABLE {}
+        regs.power.set(0);
+        unsafe { clock::CLOCK.release(clock::ClockDomain::High) };
+
+        self.power_client.get().map(|client| client.changed(false));
+        ReturnCode::SUCCESS
+    }
+
+    fn is_on(&self) -> bool {
+        self.state.get() != State::Off
+    }
+
+    fn busy(&self) -> bool {
+        self.tx_buf.is_some() || self.state.get() == State::AckTx
+    }
+
+    fn set_power_client(&self, client: &'static radio::PowerClient) {
+        self.power_client.set(Some(client));
+    }
+
+    fn config_commit(&self) {
+        self.config_pending.set(true);
+        // Otherwise applied once the radio is on or the current operation
+        // is done
+        self.interrupt_rx();
+    }
+
+    fn set_config_client(&self, client: &'static radio::ConfigClient) {
+        self.config_client.set(Some(client));
+    }
+
+    fn get_address(&self) -> u16 {
+        self.addr.get()
+    }
+
+    fn get_address_long(&self) -> [u8; 8] {
+        self.addr_long.get()
+    }
+
+    fn get_pan(&self) -> u16 {
+        self.pan.get()
+    }
+
+    fn get_tx_power(&self) -> i8 {
+        self.tx_power.get().dbm()
+    }
+
+    fn get_channel(&self) -> u8 {
+        self.channel.get()
+    }
+
+    fn set_address(&self, addr: u16) {
+        self.addr.set(addr);
+    }
+
+    fn set_address_long(&self, addr: [u8; 8]) {
+        self.addr_long.set(addr);
+    }
+
+    fn set_pan(&self, id: u16) {
+        self.pan.set(id);
+    }
+
+    fn set_tx_power(&self, power: i8) -> ReturnCode {
+        match TxPower::try_from(power as u8) {
+            Ok(tx_power) => {
+                self.tx_power.set(tx_power);
+                ReturnCode::SUCCESS
+            }
+            Err(_) => ReturnCode::EINVAL,
+        }
+    }
+
+    fn set_channel(&self, chan: u8) -> ReturnCode {
+        if chan >= 11 && chan <= 26 {
+            self.channel.set(chan);
+            ReturnCode::SUCCESS
+        } else {
+            ReturnCode::EINVAL
+        }
+    }
+}
+
+impl radio::RadioData for Radio {
+    fn set_transmit_client(&self, client: &'static radio::TxClient) {
+        self.tx_client.set(Some(client));
+    }
+
+    fn set_receive_client(&self, client: &'static radio::RxClient, buffer: &'static mut [u8]) {
+        self.rx_client.set(Some(client));
+        self.set_receive_buffer(buffer);
+    }
+
+    fn set_receive_buffer(&self, buffer: &'static mut [u8]) {
+        self.rx_buf.replace(buffer);
+        // Frames are dropped while listening without a buffer
+        if !self.rx_into_buffer.get() {
+            self.interrupt_rx();
+        }
+    }
+
+    // The frame length is the length of the MHR and MAC payload, without
+    // the FCS
+    fn transmit(
+        &self,
+        buf: &'static mut [u8],
+        frame_len: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        let psdu_len = frame_len + radio::MFR_SIZE;
+
+        if !self.is_on() {
+            return (ReturnCode::EOFF, Some(buf));
+        } else if self.tx_buf.is_some() {
+            return (ReturnCode::EBUSY, Some(buf));
+        } else if psdu_len > radio::MAX_MTU || radio::PSDU_OFFSET + psdu_len > buf.len() {
+            return (ReturnCode::ESIZE, Some(buf));
+        }
+
+        buf[1] = psdu_len as u8;
+        self.tx_buf.replace(buf);
+        self.cca_attempts.set(0);
+
+        // Otherwise sent once the current operation is done
+        self.interrupt_rx();
+        (ReturnCode::SUCCESS, None)
+    }
+}
+
+impl rng::Client for Radio {
+    fn randomness_available(&self, randomness: &mut Iterator<Item = u32>) -> rng::Continue {
+        self.randomness_requested.set(false);
+        match randomness.next() {
+            Some(random) => {
+                self.randomness.set(random);
+                self.randomness_bits.set(32);
+                rng::Continue::Done
+            }
+            None => {
+                self.randomness_requested.set(true);
+                rng::Continue::More
+            }
+        }
+    }
+}
@@ -14,17 +14,22 @@ extern crate bitfield;
 #[macro_use(debug, debug_verbose, debug_gpio, register_bitfields, register_bitmasks)]
 extern crate kernel;
 
+pub mod adc;
 pub mod ble;
 pub mod chip;
-pub mod clock;
 pub mod crt1;
 pub mod ficr;
 pub mod i2c;
-pub mod nvmc;
+#[cfg(feature = "nrf52840")]
+pub mod ieee802154_radio;
 pub mod ppi;
+pub mod pwm;
+#[cfg(feature = "nrf52840")]
+pub mod qspi;
 pub mod radio;
 pub mod spi;
 pub mod uart;
 pub mod uicr;
 
 pub use crt1::init;
+pub use nrf5x::clock;
@@ -27,30 +27,63 @@
 //!     * 30        RTC0->EVENTS_COMPARE[0]         TIMER0->TASKS_CLEAR
 //!     * 31        RTC0->EVENTS_COMPARE[0]         TIMER0->TASKS_START
 //!
+//! Programmable channels 0-19 are handed out at runtime by the allocator in
+//! `nrf5x::ppi`. Drivers should use the typed interface here rather than
+//! register addresses: an `Event` and a `Task` end point are wired together
+//! with `PPI.connect`, which allocates the channel, and a second task can be
+//! forked off the same channel. Channels can be put in one of six channel
+//! groups, enabled and disabled together by the group's tasks, which are
+//! `Task` end points themselves so that events can switch groups in
+//! hardware.
+//!
+//! The pre-programmed channels are wired to the radio in hardware, the radio
+//! drivers reserve them with the allocator and enable them with
+//! `PPI.enable`.
+//!
+//! Event end points for `nrf5x::timestamp`:
+//!
+//!     * SAADC->EVENTS_END             `SAADC_EVENTS_END`
+//!     * COMP->EVENTS_DOWN             `COMP_EVENTS_DOWN`
+//!     * COMP->EVENTS_UP               `COMP_EVENTS_UP`
+//!     * COMP->EVENTS_CROSS            `COMP_EVENTS_CROSS`
+//!
 //! Authors
 //! ---------
 //! * Johan Lindskogen
 //! * Francine Mäkelä
 //! * Date: May 04, 2018
 
+use core::cell::Cell;
 use kernel::common::regs::{FieldValue, ReadWrite};
+use kernel::ReturnCode;
+use nrf5x;
+use nrf5x::gpio::GPIOPin;
+use nrf5x::timer::Timer;
 
 pub const PPI_BASE: usize = 0x4001F000;
 
+/// The SAADC filled its result buffer
+pub const SAADC_EVENTS_END: u32 = 0x40007104;
+/// The COMP input went below the threshold
+pub const COMP_EVENTS_DOWN: u32 = 0x40013104;
+/// The COMP input went above the threshold
+pub const COMP_EVENTS_UP: u32 = 0x40013108;
+/// The COMP input crossed the threshold in either direction
+pub const COMP_EVENTS_CROSS: u32 = 0x4001310C;
+
+/// Number of channel groups
+pub const NUM_GROUPS: usize = 6;
+
+#[repr(C)]
+pub struct GroupTasks {
+    pub en: ReadWrite<u32, Control::Register>,
+    pub dis: ReadWrite<u32, Control::Register>,
+}
+
+#[repr(C)]
 pub struct PPIRegs {
-    pub tasks_chg0_en: ReadWrite<u32, Control::Register>, //0x000
-    pub tasks_chg0_dis: ReadWrite<u32, Control::Register>, //0x004
-    pub tasks_chg1_en: ReadWrite<u32, Control::Register>, //0x008
-    pub tasks_chg1_dis: ReadWrite<u32, Control::Register>, //0x00C
-    pub tasks_chg2_en: ReadWrite<u32, Control::Register>, //0x010
-    pub tasks_chg2_dis: ReadWrite<u32, Control::Register>, //0x014
-    pub tasks_chg3_en: ReadWrite<u32, Control::Register>, //0x018
-    pub tasks_chg3_dis: ReadWrite<u32, Control::Register>, //0x01C
-    pub tasks_chg4_en: ReadWrite<u32, Control::Register>, //0x020
-    pub tasks_chg4_dis: ReadWrite<u32, Control::Register>, //0x024
-    pub tasks_chg5_en: ReadWrite<u32, Control::Register>, //0x028
-    pub tasks_chg5_dis: ReadWrite<u32, Control::Register>, //0x02C
-    _reserved1: [u32; 308],                               //0x02C - 0x500
+    pub tasks_chg: [GroupTasks; NUM_GROUPS],              //0x000 - 0x030
+    _reserved1: [u32; 308],                               //0x030 - 0x500
     pub chen: ReadWrite<u32, Channel::Register>,          //0x500
     pub chenset: ReadWrite<u32, Channel::Register>,       //0x504
     pub chenclr: ReadWrite<u32, Channel::Register>,       //0x508
@@ -146,8 +179,67 @@ register_bitfields! [u32,
     ]
 ];
 
+/// An event end point, the address of a peripheral's event register
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Event(u32);
+
+impl Event {
+    /// `address` must be that of an event register.
+    pub unsafe fn from_address(address: u32) -> Event {
+        Event(address)
+    }
+
+    /// `EVENTS_COMPARE[which]` of `timer`
+    pub fn timer_compare(timer: &Timer, which: u8) -> Event {
+        Event(timer.compare_event_address(which))
+    }
+
+    /// `EVENTS_IN` of the GPIOTE channel bound to `pin`, if it has one
+    pub fn gpiote(pin: &GPIOPin) -> Option<Event> {
+        pin.gpiote_event_address().map(Event)
+    }
+
+    pub fn address(&self) -> u32 {
+        self.0
+    }
+}
+
+/// A task end point, the address of a peripheral's task register
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Task(u32);
+
+impl Task {
+    /// `address` must be that of a task register.
+    pub unsafe fn from_address(address: u32) -> Task {
+        Task(address)
+    }
+
+    /// `TASKS_CAPTURE[which]` of `timer`
+    pub fn timer_capture(timer: &Timer, which: u8) -> Task {
+        Task(timer.capture_task_address(which))
+    }
+
+    /// `TASKS_CHG[group].EN`, enables the channels of `group`
+    pub fn group_enable(group: usize) -> Task {
+        let regs = unsafe { &*(PPI_BASE as *const PPIRegs) };
+        Task(&regs.tasks_chg[group].en as *const _ as u32)
+    }
+
+    /// `TASKS_CHG[group].DIS`, disables the channels of `group`
+    pub fn group_disable(group: usize) -> Task {
+        let regs = unsafe { &*(PPI_BASE as *const PPIRegs) };
+        Task(&regs.tasks_chg[group].dis as *const _ as u32)
+    }
+
+    pub fn address(&self) -> u32 {
+        self.0
+    }
+}
+
 pub struct PPIStruct {
     regs: *const PPIRegs,
+    /// Allocated channel groups, bit `n` for group `n`
+    groups_in_use: Cell<u8>,
 }
 
 pub static mut PPI: PPIStruct = PPIStruct::new();
@@ -156,9 +248,101 @@ impl PPIStruct {
     pub const fn new() -> PPIStruct {
         PPIStruct {
             regs: PPI_BASE as *const PPIRegs,
+            groups_in_use: Cell::new(0),
+        }
+    }
+
+    /// Allocate a programmable channel and wire `event` to `task` on it. The
+    /// channel is returned disabled.
+    pub fn connect(&self, event: Event, task: Task) -> Result<usize, ReturnCode> {
+        let channel = unsafe { nrf5x::ppi::PPI.allocate() }?;
+        unsafe { nrf5x::ppi::PPI.connect(channel, event.0, task.0) };
+        Ok(channel)
+    }
+
+    /// Trigger a second task, `task`, on the events of `channel`. Returns
+    /// `EINVAL` if `channel` is not a programmable channel.
+    pub fn fork(&self, channel: usize, task: Task) -> ReturnCode {
+        if channel >= nrf5x::ppi::NUM_PROGRAMMABLE_CHANNELS {
+            return ReturnCode::EINVAL;
+        }
+        let regs = unsafe { &*self.regs };
+        regs.fork_tep[channel].set(task.0);
+        ReturnCode::SUCCESS
+    }
+
+    /// Disable a channel allocated by `connect`, unwire its fork and remove
+    /// it from all groups, and return it to the allocator. Returns `EINVAL`
+    /// if there is no such channel.
+    pub fn release(&self, channel: usize) -> ReturnCode {
+        if channel >= nrf5x::ppi::NUM_CHANNELS {
+            return ReturnCode::EINVAL;
+        }
+        let regs = unsafe { &*self.regs };
+        if channel < nrf5x::ppi::NUM_PROGRAMMABLE_CHANNELS {
+            regs.fork_tep[channel].set(0);
+        }
+        for group in regs.chg.iter() {
+            group.set(group.get() & !(1 << channel));
+        }
+        unsafe { nrf5x::ppi::PPI.release(channel) }
+    }
+
+    pub fn enable_channel(&self, channel: usize) {
+        let regs = unsafe { &*self.regs };
+        regs.chenset.set(1 << channel);
+    }
+
+    pub fn disable_channel(&self, channel: usize) {
+        let regs = unsafe { &*self.regs };
+        regs.chenclr.set(1 << channel);
+    }
+
+    /// Claim a free channel group, with no channels in it
+    pub fn allocate_group(&self) -> Result<usize, ReturnCode> {
+        let regs = unsafe { &*self.regs };
+        match (0..NUM_GROUPS).find(|&group| self.groups_in_use.get() & (1 << group) == 0) {
+            Some(group) => {
+                self.groups_in_use
+                    .set(self.groups_in_use.get() | (1 << group));
+                regs.chg[group].set(0);
+                Ok(group)
+            }
+            None => Err(ReturnCode::ENOMEM),
         }
     }
 
+    /// Return a group to the allocator. Its channels are left as they are.
+    pub fn release_group(&self, group: usize) {
+        let regs = unsafe { &*self.regs };
+        regs.chg[group].set(0);
+        self.groups_in_use
+            .set(self.groups_in_use.get() & !(1 << group));
+    }
+
+    /// Make `channel` part of `group`. Returns `EINVAL` if there is no such
+    /// group or channel.
+    pub fn group_include(&self, group: usize, channel: usize) -> ReturnCode {
+        if group >= NUM_GROUPS || channel >= nrf5x::ppi::NUM_CHANNELS {
+            return ReturnCode::EINVAL;
+        }
+        let regs = unsafe { &*self.regs };
+        regs.chg[group].set(regs.chg[group].get() | (1 << channel));
+        ReturnCode::SUCCESS
+    }
+
+    /// Enable all the channels of `group`
+    pub fn enable_group(&self, group: usize) {
+        let regs = unsafe { &*self.regs };
+        regs.tasks_chg[group].en.write(Control::ENABLE::SET);
+    }
+
+    /// Disable all the channels of `group`
+    pub fn disable_group(&self, group: usize) {
+        let regs = unsafe { &*self.regs };
+        regs.tasks_chg[group].dis.write(Control::ENABLE::SET);
+    }
+
     pub fn enable(&self, channels: FieldValue<u32, Channel::Register>) {
         let regs = unsafe { &*self.regs };
         regs.chenset.write(channels);
@@ -168,4 +352,10 @@ impl PPIStruct {
         let regs = unsafe { &*self.regs };
         regs.chenclr.write(channels);
     }
+
+    /// Bitmask of the channels currently enabled, bit `n` for channel `n`.
+    pub fn enabled_channels(&self) -> u32 {
+        let regs = unsafe { &*self.regs };
+        regs.chen.get()
+    }
 }
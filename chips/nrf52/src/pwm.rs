@@ -0,0 +1,401 @@
+//! Pulse width modulation (PWM), nRF52
+//!
+//! Each of the three PWM peripherals drives up to four output pins from a
+//! counter shared by all of them, so the channels of a peripheral run at the
+//! same frequency. The duty cycles are read by EasyDMA from RAM, either from
+//! the values set with `hil::pwm::Pwm::start` or from a sequence of values
+//! played back with `play_sequence`, four values (one per channel) to a step.
+//! Once a sequence ends, the outputs keep the last step until stopped.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! nrf52::pwm::PWM0.configure(0, nrf5x::pinmux::Pinmux::new(LED1_PIN as u32));
+//! kernel::hil::pwm::Pwm::start(&nrf52::pwm::PWM0, 0, 1000, 0x8000);
+//! ```
+
+use core::cell::Cell;
+use kernel::common::regs::{ReadWrite, WriteOnly};
+use kernel::common::take_cell::TakeCell;
+use kernel::common::VolatileCell;
+use kernel::hil;
+use kernel::hil::gpio::Pin;
+use kernel::ReturnCode;
+use nrf5x;
+use nrf5x::pinmux::Pinmux;
+
+/// Output channels of each PWM peripheral
+pub const NUM_CHANNELS: usize = 4;
+
+/// Frequency of the PWM clock before the prescaler
+const PWM_CLOCK_HZ: usize = 16_000_000;
+/// Range of the counter
+const COUNTERTOP_MIN: usize = 3;
+const COUNTERTOP_MAX: usize = 32767;
+/// Largest prescaler, dividing the clock by 2^7
+const PRESCALER_MAX: u32 = 7;
+/// Polarity bit of a duty cycle value, set for the output to start the
+/// period high and fall at the compare value
+const POLARITY_FALLING_EDGE: u16 = 1 << 15;
+
+#[repr(C)]
+struct SequenceRegisters {
+    /// Beginning address in RAM of this sequence
+    ptr: ReadWrite<u32, Pointer::Register>,
+    /// Number of values (duty cycles) in this sequence
+    cnt: ReadWrite<u32, Count::Register>,
+    /// Number of additional PWM periods between samples loaded into compare
+    /// register
+    refresh: ReadWrite<u32, Count::Register>,
+    /// Time added after the sequence
+    enddelay: ReadWrite<u32, Count::Register>,
+    _reserved: [u32; 4],
+}
+
+#[repr(C)]
+struct PwmRegisters {
+    _reserved0: u32,                                      // 0x000-0x004
+    task_stop: WriteOnly<u32, Task::Register>,            // 0x004-0x008
+    task_seqstart: [WriteOnly<u32, Task::Register>; 2],   // 0x008-0x010
+    task_nextstep: WriteOnly<u32, Task::Register>,        // 0x010-0x014
+    _reserved1: [u32; 60],                                // 0x014-0x104
+    event_stopped: ReadWrite<u32, Event::Register>,       // 0x104-0x108
+    event_seqstarted: [ReadWrite<u32, Event::Register>; 2], // 0x108-0x110
+    event_seqend: [ReadWrite<u32, Event::Register>; 2],   // 0x110-0x118
+    event_pwmperiodend: ReadWrite<u32, Event::Register>,  // 0x118-0x11C
+    event_loopsdone: ReadWrite<u32, Event::Register>,     // 0x11C-0x120
+    _reserved2: [u32; 56],                                // 0x120-0x200
+    shorts: ReadWrite<u32>,                               // 0x200-0x204
+    _reserved3: [u32; 63],                                // 0x204-0x300
+    inten: ReadWrite<u32, Interrupt::Register>,           // 0x300-0x304
+    intenset: ReadWrite<u32, Interrupt::Register>,        // 0x304-0x308
+    intenclr: ReadWrite<u32, Interrupt::Register>,        // 0x308-0x30C
+    _reserved4: [u32; 125],                               // 0x30C-0x500
+    enable: ReadWrite<u32, Enable::Register>,             // 0x500-0x504
+    mode: ReadWrite<u32, Mode::Register>,                 // 0x504-0x508
+    countertop: ReadWrite<u32, Count::Register>,          // 0x508-0x50C
+    prescaler: ReadWrite<u32, Prescaler::Register>,       // 0x50C-0x510
+    decoder: ReadWrite<u32, Decoder::Register>,           // 0x510-0x514
+    loop_: ReadWrite<u32, Count::Register>,               // 0x514-0x518
+    _reserved5: [u32; 2],                                 // 0x518-0x520
+    seq: [SequenceRegisters; 2],                          // 0x520-0x560
+    psel_out: [ReadWrite<u32, Psel::Register>; NUM_CHANNELS], // 0x560-0x570
+}
+
+register_bitfields! [u32,
+    /// Start task
+    Task [
+        ENABLE OFFSET(0) NUMBITS(1)
+    ],
+
+    /// Read event
+    Event [
+        READY OFFSET(0) NUMBITS(1)
+    ],
+
+    /// PWM interrupts
+    Interrupt [
+        STOPPED OFFSET(1) NUMBITS(1),
+        SEQSTARTED0 OFFSET(2) NUMBITS(1),
+        SEQSTARTED1 OFFSET(3) NUMBITS(1),
+        SEQEND0 OFFSET(4) NUMBITS(1),
+        SEQEND1 OFFSET(5) NUMBITS(1),
+        PWMPERIODEND OFFSET(6) NUMBITS(1),
+        LOOPSDONE OFFSET(7) NUMBITS(1)
+    ],
+
+    /// Enable PWM
+    Enable [
+        ENABLE OFFSET(0) NUMBITS(1) [
+            Disabled = 0,
+            Enabled = 1
+        ]
+    ],
+
+    /// Counter mode
+    Mode [
+        UPDOWN OFFSET(0) NUMBITS(1) [
+            Up = 0,
+            UpAndDown = 1
+        ]
+    ],
+
+    /// Clock prescaler, the clock is divided by 2^PRESCALER
+    Prescaler [
+        PRESCALER OFFSET(0) NUMBITS(3)
+    ],
+
+    /// How values are read from RAM and loaded into the compare registers
+    Decoder [
+        LOAD OFFSET(0) NUMBITS(2) [
+            Common = 0,
+            Grouped = 1,
+            Individual = 2,
+            WaveForm = 3
+        ],
+        MODE OFFSET(8) NUMBITS(1) [
+            RefreshCount = 0,
+            NextStep = 1
+        ]
+    ],
+
+    /// DMA pointer
+    Pointer [
+        POINTER OFFSET(0) NUMBITS(32)
+    ],
+
+    /// Counter values
+    Count [
+        VALUE OFFSET(0) NUMBITS(24)
+    ],
+
+    /// Pin select
+    Psel [
+        // Pin number
+        PIN OFFSET(0) NUMBITS(5),
+        // Connect/Disconnect
+        CONNECT OFFSET(31) NUMBITS(1)
+    ]
+];
+
+/// Notified when a sequence started with `play_sequence` has been played
+pub trait SequenceClient {
+    /// The sequence has ended, the outputs now hold its last step
+    fn sequence_done(&self, values: &'static mut [u16]);
+}
+
+pub struct Pwm {
+    regs: *const PwmRegisters,
+    /// Pins connected to each channel
+    pins: [Cell<Option<u8>>; NUM_CHANNELS],
+    /// Duty cycles read by EasyDMA while no sequence plays
+    duty: [VolatileCell<u16>; NUM_CHANNELS],
+    /// Channels started, as a bitmask
+    running: Cell<u8>,
+    /// Frequency the running channels were started at
+    frequency_hz: Cell<usize>,
+    sequence: TakeCell<'static, [u16]>,
+    sequence_client: Cell<Option<&'static SequenceClient>>,
+}
+
+/// PWM instance 0
+pub static mut PWM0: Pwm = Pwm::new(0x4001C000);
+/// PWM instance 1
+pub static mut PWM1: Pwm = Pwm::new(0x40021000);
+/// PWM instance 2
+pub static mut PWM2: Pwm = Pwm::new(0x40022000);
+
+impl Pwm {
+    const fn new(base: usize) -> Pwm {
+        Pwm {
+            regs: base as *const PwmRegisters,
+            pins: [
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+            ],
+            duty: [
+                VolatileCell::new(0),
+                VolatileCell::new(0),
+                VolatileCell::new(0),
+                VolatileCell::new(0),
+            ],
+            running: Cell::new(0),
+            frequency_hz: Cell::new(0),
+            sequence: TakeCell::empty(),
+            sequence_client: Cell::new(None),
+        }
+    }
+
+    /// Drive `pin` from `channel`. The pin is made an output, and while the
+    /// channel is stopped it is driven from the GPIO `OUT` register as usual.
+    pub fn configure(&self, channel: usize, pin: Pinmux) {
+        let pin: u32 = pin.into();
+        unsafe {
+            nrf5x::gpio::PORT[pin as usize].make_output();
+        }
+        self.pins[channel].set(Some(pin as u8));
+    }
+
+    pub fn set_sequence_client(&self, client: &'static SequenceClient) {
+        self.sequence_client.set(Some(client));
+    }
+
+    /// Play a sequence of duty cycles from RAM at `frequency_hz`, four
+    /// values to a step, one for each channel. Bit 15 of a value selects the
+    /// polarity: set, the output is high from the start of the period until
+    /// the counter reaches the value in bits 0-14, out of `countertop`. Each
+    /// step is held for `refresh + 1` periods.
+    ///
+    /// The sequence takes over all the channels with a pin, whether started
+    /// or not, and each is running until stopped. Stopping the last one
+    /// cuts the sequence short, handing it back to the client. Returns `EBUSY` while another sequence plays, and `EINVAL` if
+    /// the frequency cannot be generated or the sequence is not whole steps.
+    pub fn play_sequence(
+        &self,
+        values: &'static mut [u16],
+        frequency_hz: usize,
+        refresh: u32,
+    ) -> ReturnCode {
+        if self.sequence.is_some() {
+            return ReturnCode::EBUSY;
+        }
+        if values.len() == 0 || values.len() % NUM_CHANNELS != 0 || values.len() > 0x7fff {
+            return ReturnCode::EINVAL;
+        }
+        if !self.set_frequency(frequency_hz) {
+            return ReturnCode::EINVAL;
+        }
+        let regs = unsafe { &*self.regs };
+        regs.seq[0].ptr.set(values.as_ptr() as u32);
+        regs.seq[0].cnt.write(Count::VALUE.val(values.len() as u32));
+        regs.seq[0].refresh.write(Count::VALUE.val(refresh));
+        self.sequence.replace(values);
+        for channel in 0..NUM_CHANNELS {
+            self.connect(channel);
+            if self.pins[channel].get().is_some() {
+                self.running.set(self.running.get() | 1 << channel);
+            }
+        }
+        regs.event_seqend[0].write(Event::READY::CLEAR);
+        regs.intenset.write(Interrupt::SEQEND0::SET);
+        regs.enable.write(Enable::ENABLE::Enabled);
+        regs.task_seqstart[0].write(Task::ENABLE::SET);
+        ReturnCode::SUCCESS
+    }
+
+    /// The counter value a period ends at, for `play_sequence`
+    pub fn countertop(&self) -> usize {
+        let regs = unsafe { &*self.regs };
+        regs.countertop.read(Count::VALUE) as usize
+    }
+
+    /// Set the prescaler and counter top for `frequency_hz`, the smallest
+    /// prescaler giving the finest duty cycle resolution
+    fn set_frequency(&self, frequency_hz: usize) -> bool {
+        if frequency_hz == 0 {
+            return false;
+        }
+        let regs = unsafe { &*self.regs };
+        for prescaler in 0..PRESCALER_MAX + 1 {
+            let countertop = (PWM_CLOCK_HZ >> prescaler) / frequency_hz;
+            if countertop < COUNTERTOP_MIN {
+                return false;
+            }
+            if countertop <= COUNTERTOP_MAX {
+                regs.mode.write(Mode::UPDOWN::Up);
+                regs.decoder
+                    .write(Decoder::LOAD::Individual + Decoder::MODE::RefreshCount);
+                regs.prescaler.write(Prescaler::PRESCALER.val(prescaler));
+                regs.countertop.write(Count::VALUE.val(countertop as u32));
+                regs.loop_.set(0);
+                self.frequency_hz.set(frequency_hz);
+                return true;
+            }
+        }
+        false
+    }
+
+    fn connect(&self, channel: usize) {
+        let regs = unsafe { &*self.regs };
+        match self.pins[channel].get() {
+            Some(pin) => regs.psel_out[channel].write(Psel::PIN.val(pin as u32)),
+            None => regs.psel_out[channel].write(Psel::CONNECT::SET),
+        }
+    }
+
+    fn disconnect(&self, channel: usize) {
+        let regs = unsafe { &*self.regs };
+        regs.psel_out[channel].write(Psel::CONNECT::SET);
+    }
+
+    /// Load the duty cycles of the started channels
+    fn load_duty_cycles(&self) {
+        let regs = unsafe { &*self.regs };
+        regs.seq[0].ptr.set(self.duty.as_ptr() as u32);
+        regs.seq[0].cnt.write(Count::VALUE.val(NUM_CHANNELS as u32));
+        regs.seq[0].refresh.set(0);
+        regs.enable.write(Enable::ENABLE::Enabled);
+        regs.task_seqstart[0].write(Task::ENABLE::SET);
+    }
+
+    fn sequence_done(&self) {
+        let regs = unsafe { &*self.regs };
+        regs.intenclr.write(Interrupt::SEQEND0::SET);
+        self.sequence.take().map(|values| {
+            self.sequence_client
+                .get()
+                .map(move |client| client.sequence_done(values));
+        });
+    }
+
+    pub fn handle_interrupt(&self) {
+        let regs = unsafe { &*self.regs };
+        if regs.event_seqend[0].is_set(Event::READY) {
+            regs.event_seqend[0].write(Event::READY::CLEAR);
+            self.sequence_done();
+        }
+    }
+}
+
+impl hil::pwm::Pwm for Pwm {
+    fn channels(&self) -> usize {
+        self.pins.iter().filter(|pin| pin.get().is_some()).count()
+    }
+
+    fn start(&self, channel: usize, frequency_hz: usize, duty_cycle: usize) -> ReturnCode {
+        if channel >= NUM_CHANNELS || self.pins[channel].get().is_none() {
+            return ReturnCode::EINVAL;
+        }
+        if duty_cycle > self.get_maximum_duty_cycle() {
+            return ReturnCode::EINVAL;
+        }
+        if self.sequence.is_some() {
+            return ReturnCode::EBUSY;
+        }
+        let others = self.running.get() & !(1 << channel);
+        if others != 0 && frequency_hz != self.frequency_hz.get() {
+            return ReturnCode::EBUSY;
+        }
+        if frequency_hz != self.frequency_hz.get() || self.running.get() == 0 {
+            if !self.set_frequency(frequency_hz) {
+                return ReturnCode::EINVAL;
+            }
+        }
+        let compare = duty_cycle * self.countertop() / self.get_maximum_duty_cycle();
+        self.duty[channel].set(compare as u16 | POLARITY_FALLING_EDGE);
+        self.running.set(self.running.get() | 1 << channel);
+        self.connect(channel);
+        self.load_duty_cycles();
+        ReturnCode::SUCCESS
+    }
+
+    fn stop(&self, channel: usize) -> ReturnCode {
+        if channel >= NUM_CHANNELS {
+            return ReturnCode::EINVAL;
+        }
+        if self.running.get() & 1 << channel == 0 {
+            return ReturnCode::EALREADY;
+        }
+        self.running.set(self.running.get() & !(1 << channel));
+        self.duty[channel].set(0);
+        self.disconnect(channel);
+        if self.running.get() == 0 {
+            let regs = unsafe { &*self.regs };
+            regs.task_stop.write(Task::ENABLE::SET);
+            regs.enable.write(Enable::ENABLE::Disabled);
+            self.sequence_done();
+        }
+        ReturnCode::SUCCESS
+    }
+
+    fn get_maximum_frequency_hz(&self) -> usize {
+        PWM_CLOCK_HZ / COUNTERTOP_MIN
+    }
+
+    fn get_maximum_duty_cycle(&self) -> usize {
+        0xffff
+    }
+}
@@ -0,0 +1,453 @@
+//! Quad SPI flash controller, nRF52840
+//!
+//! Drives an external serial NOR flash over up to four data lines, moving
+//! data between RAM and the flash with EasyDMA. Execute in place is not used:
+//! the flash is only accessed through the read, write and erase operations
+//! of `hil::flash::Flash`, one 4 kB erase sector at a time, so it can back a
+//! `capsules::nonvolatile_to_pages::NonvolatileToPages` like the internal
+//! flash of other chips does.
+//!
+//! The controller issues the write enable, page program splitting and busy
+//! polling on its own, a page write or erase completes with a single
+//! interrupt. EasyDMA needs word aligned buffers, which `QspiPage` is.
+//!
+//! Only built with the `nrf52840` feature, the nRF52832 has no QSPI.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! nrf52::qspi::QSPI.configure(
+//!     nrf52::qspi::Pins {
+//!         sck: Pinmux::new(19),
+//!         csn: Pinmux::new(17),
+//!         io: [Pinmux::new(20), Pinmux::new(21), Pinmux::new(22), Pinmux::new(23)],
+//!     },
+//!     nrf52::qspi::Frequency::M32,
+//! );
+//! nrf52::qspi::QSPI.activate();
+//! ```
+
+use core::cell::Cell;
+use core::ops::{Index, IndexMut};
+use kernel::common::regs::{ReadOnly, ReadWrite, WriteOnly};
+use kernel::common::take_cell::TakeCell;
+use kernel::hil;
+use kernel::ReturnCode;
+use nrf5x::pinmux::Pinmux;
+
+const QSPI_BASE: usize = 0x40029000;
+
+/// Erase sector size of the external flash, the page size of
+/// `hil::flash::Flash`
+pub const PAGE_SIZE: usize = 4096;
+
+#[repr(C)]
+struct QspiRegisters {
+    /// Activate QSPI interface
+    /// Address: 0x000 - 0x004
+    task_activate: WriteOnly<u32, Task::Register>,
+    /// Start transfer from external flash memory to internal RAM
+    /// Address: 0x004 - 0x008
+    task_readstart: WriteOnly<u32, Task::Register>,
+    /// Start transfer from internal RAM to external flash memory
+    /// Address: 0x008 - 0x00C
+    task_writestart: WriteOnly<u32, Task::Register>,
+    /// Start external flash memory erase operation
+    /// Address: 0x00C - 0x010
+    task_erasestart: WriteOnly<u32, Task::Register>,
+    /// Deactivate QSPI interface
+    /// Address: 0x010 - 0x014
+    task_deactivate: WriteOnly<u32, Task::Register>,
+    _reserved0: [u32; 59],
+    /// QSPI peripheral is ready
+    /// Address: 0x100 - 0x104
+    event_ready: ReadWrite<u32, Event::Register>,
+    _reserved1: [u32; 127],
+    /// Enable or disable interrupt
+    /// Address: 0x300 - 0x304
+    inten: ReadWrite<u32, Interrupt::Register>,
+    /// Enable interrupt
+    /// Address: 0x304 - 0x308
+    intenset: ReadWrite<u32, Interrupt::Register>,
+    /// Disable interrupt
+    /// Address: 0x308 - 0x30C
+    intenclr: ReadWrite<u32, Interrupt::Register>,
+    _reserved2: [u32; 125],
+    /// Enable QSPI peripheral
+    /// Address: 0x500 - 0x504
+    enable: ReadWrite<u32, Enable::Register>,
+    /// Flash memory source address of a read
+    /// Address: 0x504 - 0x508
+    read_src: ReadWrite<u32>,
+    /// RAM destination address of a read
+    /// Address: 0x508 - 0x50C
+    read_dst: ReadWrite<u32>,
+    /// Read transfer length in bytes, a multiple of 4
+    /// Address: 0x50C - 0x510
+    read_cnt: ReadWrite<u32>,
+    /// Flash destination address of a write
+    /// Address: 0x510 - 0x514
+    write_dst: ReadWrite<u32>,
+    /// RAM source address of a write
+    /// Address: 0x514 - 0x518
+    write_src: ReadWrite<u32>,
+    /// Write transfer length in bytes, a multiple of 4
+    /// Address: 0x518 - 0x51C
+    write_cnt: ReadWrite<u32>,
+    /// Start address of the flash block to be erased
+    /// Address: 0x51C - 0x520
+    erase_ptr: ReadWrite<u32>,
+    /// Size of the block to be erased
+    /// Address: 0x520 - 0x524
+    erase_len: ReadWrite<u32, EraseLen::Register>,
+    /// Pin select for serial clock SCK
+    /// Address: 0x524 - 0x528
+    psel_sck: ReadWrite<u32>,
+    /// Pin select for chip select signal CSN
+    /// Address: 0x528 - 0x52C
+    psel_csn: ReadWrite<u32>,
+    _reserved3: [u32; 1],
+    /// Pin select for serial data IO0 to IO3
+    /// Address: 0x530 - 0x540
+    psel_io: [ReadWrite<u32>; 4],
+    /// Address offset into the external memory for execute in place
+    /// Address: 0x540 - 0x544
+    xipoffset: ReadWrite<u32>,
+    /// Interface configuration
+    /// Address: 0x544 - 0x548
+    ifconfig0: ReadWrite<u32, IfConfig0::Register>,
+    _reserved4: [u32; 46],
+    /// Interface configuration
+    /// Address: 0x600 - 0x604
+    ifconfig1: ReadWrite<u32, IfConfig1::Register>,
+    /// Status register
+    /// Address: 0x604 - 0x608
+    status: ReadOnly<u32, Status::Register>,
+}
+
+register_bitfields! [u32,
+    Task [
+        ENABLE OFFSET(0) NUMBITS(1)
+    ],
+
+    Event [
+        READY OFFSET(0) NUMBITS(1)
+    ],
+
+    Interrupt [
+        READY OFFSET(0) NUMBITS(1)
+    ],
+
+    Enable [
+        ENABLE OFFSET(0) NUMBITS(1) [
+            Disable = 0,
+            Enable = 1
+        ]
+    ],
+
+    EraseLen [
+        LEN OFFSET(0) NUMBITS(2) [
+            /// Erase a 4 kB sector
+            Sector4KB = 0,
+            /// Erase a 64 kB block
+            Block64KB = 1,
+            /// Erase the whole chip
+            All = 2
+        ]
+    ],
+
+    IfConfig0 [
+        /// Opcode used for reads
+        READOC OFFSET(0) NUMBITS(3) [
+            FastRead = 0,
+            Read2O = 1,
+            Read2IO = 2,
+            Read4O = 3,
+            Read4IO = 4
+        ],
+        /// Opcode used for writes
+        WRITEOC OFFSET(3) NUMBITS(3) [
+            PP = 0,
+            PP2O = 1,
+            PP4O = 2,
+            PP4IO = 3
+        ],
+        ADDRMODE OFFSET(6) NUMBITS(1) [
+            Bit24 = 0,
+            Bit32 = 1
+        ],
+        DPMENABLE OFFSET(7) NUMBITS(1) [],
+        /// Page size of the flash, writes are split into page programs of
+        /// this size
+        PPSIZE OFFSET(12) NUMBITS(1) [
+            Bytes256 = 0,
+            Bytes512 = 1
+        ]
+    ],
+
+    IfConfig1 [
+        /// Minimum time CSN is held high between transfers, in 62.5 ns
+        /// steps
+        SCKDELAY OFFSET(0) NUMBITS(8) [],
+        DPMEN OFFSET(24) NUMBITS(1) [],
+        SPIMODE OFFSET(25) NUMBITS(1) [
+            Mode0 = 0,
+            Mode3 = 1
+        ],
+        /// SCK frequency is 32 MHz / (SCKFREQ + 1)
+        SCKFREQ OFFSET(28) NUMBITS(4) []
+    ],
+
+    Status [
+        DPM OFFSET(2) NUMBITS(1) [],
+        READY OFFSET(3) NUMBITS(1) [],
+        /// Value of the external flash status register
+        SREG OFFSET(24) NUMBITS(8) []
+    ]
+];
+
+/// Pins connected to the external flash
+pub struct Pins {
+    pub sck: Pinmux,
+    pub csn: Pinmux,
+    /// IO0 to IO3
+    pub io: [Pinmux; 4],
+}
+
+/// SCK frequency
+#[derive(Copy, Clone)]
+pub enum Frequency {
+    M32 = 0,
+    M16 = 1,
+    M8 = 3,
+    M4 = 7,
+    M2 = 15,
+}
+
+/// A buffer of one erase sector, aligned for EasyDMA.
+///
+/// ```
+/// static mut PAGEBUFFER: QspiPage = QspiPage::new();
+/// ```
+#[repr(align(4))]
+pub struct QspiPage(pub [u8; PAGE_SIZE]);
+
+impl QspiPage {
+    pub const fn new() -> QspiPage {
+        QspiPage([0; PAGE_SIZE])
+    }
+}
+
+impl Index<usize> for QspiPage {
+    type Output = u8;
+
+    fn index(&self, idx: usize) -> &u8 {
+        &self.0[idx]
+    }
+}
+
+impl IndexMut<usize> for QspiPage {
+    fn index_mut(&mut self, idx: usize) -> &mut u8 {
+        &mut self.0[idx]
+    }
+}
+
+impl AsMut<[u8]> for QspiPage {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Operation {
+    Idle,
+    Read,
+    Write,
+    Erase,
+}
+
+pub struct Qspi {
+    regs: *const QspiRegisters,
+    client: Cell<Option<&'static hil::flash::Client<Qspi>>>,
+    operation: Cell<Operation>,
+    buffer: TakeCell<'static, QspiPage>,
+    /// Number of erase sectors of the external flash
+    pages: Cell<usize>,
+}
+
+pub static mut QSPI: Qspi = Qspi::new();
+
+impl Qspi {
+    const fn new() -> Qspi {
+        Qspi {
+            regs: QSPI_BASE as *const QspiRegisters,
+            client: Cell::new(None),
+            operation: Cell::new(Operation::Idle),
+            buffer: TakeCell::empty(),
+            pages: Cell::new(0),
+        }
+    }
+
+    /// Set up the pins and interface for a flash with 24-bit addresses,
+    /// 256 byte program pages and quad I/O read and program commands. The
+    /// flash must already have its quad enable bit set.
+    pub fn configure(&self, pins: Pins, frequency: Frequency) {
+        let regs = unsafe { &*self.regs };
+        regs.psel_sck.set(pins.sck.into());
+        regs.psel_csn.set(pins.csn.into());
+        for (psel, pin) in regs.psel_io.iter().zip(pins.io.iter()) {
+            psel.set((*pin).into());
+        }
+        regs.ifconfig0.write(
+            IfConfig0::READOC::Read4IO
+                + IfConfig0::WRITEOC::PP4IO
+                + IfConfig0::ADDRMODE::Bit24
+                + IfConfig0::PPSIZE::Bytes256,
+        );
+        regs.ifconfig1.write(
+            IfConfig1::SCKDELAY.val(1)
+                + IfConfig1::SPIMODE::Mode0
+                + IfConfig1::SCKFREQ.val(frequency as u32),
+        );
+    }
+
+    /// Set the size of the external flash, in bytes. Pages past it are
+    /// rejected with `EINVAL`.
+    pub fn set_size(&self, size: usize) {
+        self.pages.set(size / PAGE_SIZE);
+    }
+
+    /// Enable the controller and connect to the flash. Blocks until the
+    /// interface is up, which takes a few microseconds.
+    pub fn activate(&self) {
+        let regs = unsafe { &*self.regs };
+        regs.enable.write(Enable::ENABLE::Enable);
+        regs.event_ready.write(Event::READY::CLEAR);
+        regs.task_activate.write(Task::ENABLE::SET);
+        while !regs.event_ready.is_set(Event::READY) {}
+        regs.event_ready.write(Event::READY::CLEAR);
+        regs.intenset.write(Interrupt::READY::SET);
+    }
+
+    /// Disconnect from the flash and disable the controller.
+    pub fn deactivate(&self) {
+        let regs = unsafe { &*self.regs };
+        regs.intenclr.write(Interrupt::READY::SET);
+        regs.task_deactivate.write(Task::ENABLE::SET);
+        regs.enable.write(Enable::ENABLE::Disable);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        let regs = unsafe { &*self.regs };
+        regs.enable.is_set(Enable::ENABLE)
+    }
+
+    /// Check an operation on `page_number` can be started now.
+    fn check_request(&self, page_number: usize) -> ReturnCode {
+        if !self.is_enabled() {
+            ReturnCode::EOFF
+        } else if self.operation.get() != Operation::Idle {
+            ReturnCode::EBUSY
+        } else if page_number >= self.pages.get() {
+            ReturnCode::EINVAL
+        } else {
+            ReturnCode::SUCCESS
+        }
+    }
+
+    fn read_page(&self, page_number: usize, buf: &'static mut QspiPage) -> ReturnCode {
+        let regs = unsafe { &*self.regs };
+        let status = self.check_request(page_number);
+        if status != ReturnCode::SUCCESS {
+            return status;
+        }
+
+        regs.read_src.set((page_number * PAGE_SIZE) as u32);
+        regs.read_dst.set(buf.0.as_mut_ptr() as u32);
+        regs.read_cnt.set(PAGE_SIZE as u32);
+        self.buffer.replace(buf);
+        self.operation.set(Operation::Read);
+        regs.task_readstart.write(Task::ENABLE::SET);
+        ReturnCode::SUCCESS
+    }
+
+    fn write_page(&self, page_number: usize, buf: &'static mut QspiPage) -> ReturnCode {
+        let regs = unsafe { &*self.regs };
+        let status = self.check_request(page_number);
+        if status != ReturnCode::SUCCESS {
+            return status;
+        }
+
+        regs.write_dst.set((page_number * PAGE_SIZE) as u32);
+        regs.write_src.set(buf.0.as_mut_ptr() as u32);
+        regs.write_cnt.set(PAGE_SIZE as u32);
+        self.buffer.replace(buf);
+        self.operation.set(Operation::Write);
+        regs.task_writestart.write(Task::ENABLE::SET);
+        ReturnCode::SUCCESS
+    }
+
+    fn erase_page(&self, page_number: usize) -> ReturnCode {
+        let regs = unsafe { &*self.regs };
+        let status = self.check_request(page_number);
+        if status != ReturnCode::SUCCESS {
+            return status;
+        }
+
+        regs.erase_ptr.set((page_number * PAGE_SIZE) as u32);
+        regs.erase_len.write(EraseLen::LEN::Sector4KB);
+        self.operation.set(Operation::Erase);
+        regs.task_erasestart.write(Task::ENABLE::SET);
+        ReturnCode::SUCCESS
+    }
+
+    pub fn handle_interrupt(&self) {
+        let regs = unsafe { &*self.regs };
+        if !regs.event_ready.is_set(Event::READY) {
+            return;
+        }
+        regs.event_ready.write(Event::READY::CLEAR);
+
+        let operation = self.operation.get();
+        self.operation.set(Operation::Idle);
+        self.client.get().map(|client| match operation {
+            Operation::Read => {
+                self.buffer.take().map(|buffer| {
+                    client.read_complete(buffer, hil::flash::Error::CommandComplete);
+                });
+            }
+            Operation::Write => {
+                self.buffer.take().map(|buffer| {
+                    client.write_complete(buffer, hil::flash::Error::CommandComplete);
+                });
+            }
+            Operation::Erase => {
+                client.erase_complete(hil::flash::Error::CommandComplete);
+            }
+            Operation::Idle => {}
+        });
+    }
+}
+
+impl<C: hil::flash::Client<Self>> hil::flash::HasClient<'static, C> for Qspi {
+    fn set_client(&self, client: &'static C) {
+        self.client.set(Some(client));
+    }
+}
+
+impl hil::flash::Flash for Qspi {
+    type Page = QspiPage;
+
+    fn read_page(&self, page_number: usize, buf: &'static mut Self::Page) -> ReturnCode {
+        self.read_page(page_number, buf)
+    }
+
+    fn write_page(&self, page_number: usize, buf: &'static mut Self::Page) -> ReturnCode {
+        self.write_page(page_number, buf)
+    }
+
+    fn erase_page(&self, page_number: usize) -> ReturnCode {
+        self.erase_page(page_number)
+    }
+}
@@ -33,6 +33,7 @@
 //!
 //! * CRC - 3 bytes
 
+use clock;
 use core::cell::Cell;
 use core::convert::TryFrom;
 use kernel;
@@ -42,6 +43,7 @@ use kernel::hil::ble_advertising::RadioChannel;
 use kernel::ReturnCode;
 use nrf5x;
 use nrf5x::constants::TxPower;
+use nrf5x::radio_crc::{self, CrcConfig, RadioCrc};
 
 pub const RADIO_BASE: usize = 0x40001000;
 
@@ -82,8 +84,24 @@ pub struct RadioRegisters {
     /// Address: 0x020 - 0x024
     pub task_bcstop: WriteOnly<u32, Task::Register>,
 
+    /// Start the energy detect measurement used in IEEE 802.15.4 mode
+    /// Address: 0x024 - 0x028
+    pub task_edstart: WriteOnly<u32, Task::Register>,
+
+    /// Stop the energy detect measurement
+    /// Address: 0x028 - 0x02c
+    pub task_edstop: WriteOnly<u32, Task::Register>,
+
+    /// Start the clear channel assessment used in IEEE 802.15.4 mode
+    /// Address: 0x02c - 0x030
+    pub task_ccastart: WriteOnly<u32, Task::Register>,
+
+    /// Stop the clear channel assessment
+    /// Address: 0x030 - 0x034
+    pub task_ccastop: WriteOnly<u32, Task::Register>,
+
     /// Reserved
-    _reserved1: [u32; 55],
+    _reserved1: [u32; 51],
 
     /// Radio has ramped up and is ready to be started
     /// Address: 0x100 - 0x104
@@ -135,8 +153,32 @@ pub struct RadioRegisters {
     /// Address: 0x134 - 0x138
     pub crcerror: ReadWrite<u32, Event::Register>,
 
+    /// IEEE 802.15.4 length field received
+    /// Address: 0x138 - 0x13c
+    pub event_framestart: ReadWrite<u32, Event::Register>,
+
+    /// Sampling of energy detection complete
+    /// Address: 0x13c - 0x140
+    pub event_edend: ReadWrite<u32, Event::Register>,
+
+    /// The sampling of energy detection has stopped
+    /// Address: 0x140 - 0x144
+    pub event_edstopped: ReadWrite<u32, Event::Register>,
+
+    /// Wireless medium in idle, clear to send
+    /// Address: 0x144 - 0x148
+    pub event_ccaidle: ReadWrite<u32, Event::Register>,
+
+    /// Wireless medium busy, do not send
+    /// Address: 0x148 - 0x14c
+    pub event_ccabusy: ReadWrite<u32, Event::Register>,
+
+    /// The CCA has stopped
+    /// Address: 0x14c - 0x150
+    pub event_ccastopped: ReadWrite<u32, Event::Register>,
+
     /// Reserved
-    _reserved4: [u32; 50],
+    _reserved4: [u32; 44],
 
     /// Shortcut register
     /// Address: 0x200 - 0x204
@@ -290,7 +332,26 @@ pub struct RadioRegisters {
     pub modecnf0: ReadWrite<u32, RadioModeConfig::Register>,
 
     /// Reserved
-    _reserved14: [u32; 618],
+    _reserved14: [u32; 3],
+
+    /// IEEE 802.15.4 start of frame delimiter
+    /// Address: 0x660 - 0x664
+    pub sfd: ReadWrite<u32, StartOfFrameDelimiter::Register>,
+
+    /// IEEE 802.15.4 energy detect loop count
+    /// Address: 0x664 - 0x668
+    pub edcnt: ReadWrite<u32>,
+
+    /// IEEE 802.15.4 energy detect level
+    /// Address: 0x668 - 0x66c
+    pub edsample: ReadOnly<u32>,
+
+    /// IEEE 802.15.4 clear channel assessment control
+    /// Address: 0x66c - 0x670
+    pub ccactrl: ReadWrite<u32, CcaControl::Register>,
+
+    /// Reserved
+    _reserved15: [u32; 611],
 
     /// Peripheral power control
     /// Address: 0xFFC - 0x1000
@@ -325,7 +386,17 @@ register_bitfields! [u32,
         /// Shortcut between ADDRESS event and BCSTART task
         ADDRESS_BCSTART OFFSET(6) NUMBITS(1),
         /// Shortcut between DISABLED event and RSSISTOP task
-        DISABLED_RSSISTOP OFFSET(8) NUMBITS(1)
+        DISABLED_RSSISTOP OFFSET(8) NUMBITS(1),
+        /// Shortcut between RXREADY event and CCASTART task
+        RXREADY_CCASTART OFFSET(11) NUMBITS(1),
+        /// Shortcut between CCAIDLE event and TXEN task
+        CCAIDLE_TXEN OFFSET(12) NUMBITS(1),
+        /// Shortcut between CCABUSY event and DISABLE task
+        CCABUSY_DISABLE OFFSET(13) NUMBITS(1),
+        /// Shortcut between TXREADY event and START task
+        TXREADY_START OFFSET(18) NUMBITS(1),
+        /// Shortcut between RXREADY event and START task
+        RXREADY_START OFFSET(19) NUMBITS(1)
     ],
     /// Interrupt register
     Interrupt [
@@ -350,7 +421,13 @@ register_bitfields! [u32,
         /// CRCOK event
         CRCOK OFFSET(12) NUMBITS(1),
         /// CRCERROR event
-        CRCERROR OFFSET(13) NUMBITS(1)
+        CRCERROR OFFSET(13) NUMBITS(1),
+        /// FRAMESTART event
+        FRAMESTART OFFSET(14) NUMBITS(1),
+        /// CCAIDLE event
+        CCAIDLE OFFSET(17) NUMBITS(1),
+        /// CCABUSY event
+        CCABUSY OFFSET(18) NUMBITS(1)
     ],
     /// Receive match register
     ReceiveMatch [
@@ -411,7 +488,8 @@ register_bitfields! [u32,
             NRF_1MBIT = 0,
             NRF_2MBIT = 1,
             NRF_250KBIT = 2,
-            BLE_1MBIT = 3
+            BLE_1MBIT = 3,
+            IEEE802154_250KBIT = 15
         ]
     ],
     /// Packet configuration register 0
@@ -428,9 +506,15 @@ register_bitfields! [u32,
             INCLUDE = 1
         ],
         /// Length of preamble on air. Decision point: TASKS_START task
-        PLEN OFFSET(24) NUMBITS(1) [
+        PLEN OFFSET(24) NUMBITS(2) [
             EIGHT = 0,
-            SIXTEEN = 1
+            SIXTEEN = 1,
+            THIRTYTWOZEROS = 2
+        ],
+        /// Whether the LENGTH field counts the CRC
+        CRCINC OFFSET(26) NUMBITS(1) [
+            EXCLUDE = 0,
+            INCLUDE = 1
         ]
     ],
     /// Packet configuration register 1
@@ -499,9 +583,10 @@ register_bitfields! [u32,
             THREE = 3
         ],
         /// Include or exclude packet field from CRC calculation
-        SKIPADDR OFFSET(8) NUMBITS(1) [
+        SKIPADDR OFFSET(8) NUMBITS(2) [
             INCLUDE = 0,
-            EXCLUDE = 1
+            EXCLUDE = 1,
+            IEEE802154 = 2
         ]
     ],
     /// CRC polynomial register 
@@ -585,6 +670,26 @@ register_bitfields! [u32,
             B0 = 1,
             CENTER = 2
         ]
+    ],
+    /// IEEE 802.15.4 start of frame delimiter register
+    StartOfFrameDelimiter [
+        SFD OFFSET(0) NUMBITS(8)
+    ],
+    /// IEEE 802.15.4 clear channel assessment control register
+    CcaControl [
+        /// CCA mode of operation
+        CCAMODE OFFSET(0) NUMBITS(3) [
+            EDMODE = 0,
+            CARRIERMODE = 1,
+            CARRIERANDEDMODE = 2,
+            CARRIEROREDMODE = 3
+        ],
+        /// Energy detect threshold, in ED levels
+        CCAEDTHRES OFFSET(8) NUMBITS(8) [],
+        /// Carrier detect threshold
+        CCACORRTHRES OFFSET(16) NUMBITS(8) [],
+        /// Number of carrier detections before the medium is reported busy
+        CCACORRCNT OFFSET(24) NUMBITS(8) []
     ]
 ];
 
@@ -594,6 +699,8 @@ static mut PAYLOAD: [u8; nrf5x::constants::RADIO_PAYLOAD_LENGTH] =
 pub struct Radio {
     regs: *const RadioRegisters,
     tx_power: Cell<TxPower>,
+    /// Whether the radio holds a request on the high frequency crystal
+    hfclk_requested: Cell<bool>,
     rx_client: Cell<Option<&'static ble_advertising::RxClient>>,
     tx_client: Cell<Option<&'static ble_advertising::TxClient>>,
 }
@@ -605,6 +712,7 @@ impl Radio {
         Radio {
             regs: RADIO_BASE as *const RadioRegisters,
             tx_power: Cell::new(TxPower::ZerodBm),
+            hfclk_requested: Cell::new(false),
             rx_client: Cell::new(None),
             tx_client: Cell::new(None),
         }
@@ -634,6 +742,12 @@ impl Radio {
 
     fn radio_on(&self) {
         let regs = unsafe { &*self.regs };
+        // The radio needs the crystal for an accurate carrier, but only while
+        // it is powered, so the clock can be stopped between packets
+        if !self.hfclk_requested.get() {
+            self.hfclk_requested.set(true);
+            unsafe { clock::CLOCK.request(clock::ClockDomain::High) };
+        }
         // reset and enable power
         regs.power.write(Task::ENABLE::CLEAR);
         regs.power.write(Task::ENABLE::SET);
@@ -642,6 +756,10 @@ impl Radio {
     fn radio_off(&self) {
         let regs = unsafe { &*self.regs };
         regs.power.write(Task::ENABLE::CLEAR);
+        if self.hfclk_requested.get() {
+            self.hfclk_requested.set(false);
+            unsafe { clock::CLOCK.release(clock::ClockDomain::High) };
+        }
     }
 
     fn set_tx_power(&self) {
@@ -772,11 +890,7 @@ impl Radio {
 
     // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 3.1.1 CRC Generation
     fn ble_set_crc_config(&self) {
-        let regs = unsafe { &*self.regs };
-        regs.crccnf
-            .write(CrcConfiguration::LEN::THREE + CrcConfiguration::SKIPADDR::EXCLUDE);
-        regs.crcinit.set(nrf5x::constants::RADIO_CRCINIT_BLE);
-        regs.crcpoly.set(nrf5x::constants::RADIO_CRCPOLY_BLE);
+        self.set_crc(radio_crc::BLE);
     }
 
     // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.1.2 Access Address
@@ -896,3 +1010,21 @@ impl ble_advertising::BleConfig for Radio {
         }
     }
 }
+
+impl RadioCrc for Radio {
+    fn set_crc(&self, config: CrcConfig) -> ReturnCode {
+        let res = config.validate();
+        if res == ReturnCode::SUCCESS {
+            let regs = unsafe { &*self.regs };
+            regs.crccnf.set(config.crccnf());
+            regs.crcpoly.set(config.polynomial);
+            regs.crcinit.set(config.init);
+        }
+        res
+    }
+
+    fn crc(&self) -> CrcConfig {
+        let regs = unsafe { &*self.regs };
+        CrcConfig::from_registers(regs.crccnf.get(), regs.crcpoly.get(), regs.crcinit.get())
+    }
+}
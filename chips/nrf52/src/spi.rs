@@ -1,7 +1,9 @@
 //! Implementation of SPI for NRF52 using EasyDMA.
 //!
-//! This file only implements support for the three SPI master (`SPIM`)
-//! peripherals, and not SPI slave (`SPIS`).
+//! This file implements support for the three SPI master (`SPIM`) and the
+//! three SPI slave (`SPIS`) peripherals. Master and slave instances with the
+//! same number share their registers and interrupt, so only one of them can
+//! be enabled at a time.
 //!
 //! Although `kernel::hil::spi::SpiMaster` is implemented for `SPIM`,
 //! only the functions marked with `x` are fully defined:
@@ -23,6 +25,10 @@
 //! * [] hold_low
 //! * [] release_low
 //!
+//! `kernel::hil::spi::SpiSlave` is implemented for `SPIS`. The `SPIS` has no
+//! event for its chip select being asserted, so `SpiSlaveClient::chip_selected`
+//! is never called.
+//!
 //! Author
 //! -------------------
 //!
@@ -44,6 +50,13 @@ pub static mut SPIM1: SPIM = SPIM::new(1);
 /// SPI master instance 2.
 pub static mut SPIM2: SPIM = SPIM::new(2);
 
+/// SPI slave instance 0.
+pub static mut SPIS0: SPIS = SPIS::new(0);
+/// SPI slave instance 1.
+pub static mut SPIS1: SPIS = SPIS::new(1);
+/// SPI slave instance 2.
+pub static mut SPIS2: SPIS = SPIS::new(2);
+
 mod registers {
     pub mod spim {
         //! NRF52 `SPIM` registers and utility types.
@@ -254,6 +267,158 @@ mod registers {
             pub orc: VolatileCell<u32>,
         }
     }
+
+    pub mod spis {
+        //! NRF52 `SPIS` registers and utility types.
+        #![allow(dead_code)]
+        pub use super::spim::Config;
+        use kernel::common::VolatileCell;
+        use nrf5x::pinmux::Pinmux;
+
+        /// Uninitialized `SPIS` instances.
+        pub const INSTANCES: [*const SPIS; 3] = [
+            0x40003000 as *const SPIS,
+            0x40004000 as *const SPIS,
+            0x40023000 as *const SPIS,
+        ];
+
+        bitfield!{
+            /// Represents bitfields in `intenset` and `intenclr` registers.
+            #[derive(Copy, Clone)]
+            pub struct InterruptEnable(u32);
+            impl Debug;
+            pub end,      set_end:       1,  1;
+            pub end_rx,   set_end_rx:    4,  4;
+            pub acquired, set_acquired: 10, 10;
+        }
+
+        /// `shorts` bit acquiring the semaphore at the END event
+        pub const SHORTS_END_ACQUIRE: u32 = 1 << 2;
+
+        /// `semstat` value while the CPU holds the semaphore
+        pub const SEMSTAT_CPU: u32 = 1;
+
+        /// `status` bits, cleared by writing them back
+        pub const STATUS_OVERREAD: u32 = 1 << 0;
+        pub const STATUS_OVERFLOW: u32 = 1 << 1;
+
+        /// Represents one of NRF52's three `SPIS` instances.
+        #[repr(C)]
+        pub struct SPIS {
+            _reserved0: [u32; 9],
+            /// Acquire the semaphore
+            ///
+            /// addr = base + 0x024
+            pub tasks_acquire: VolatileCell<u32>,
+            /// Release the semaphore, handing the buffers to the SPIS
+            ///
+            /// addr = base + 0x028
+            pub tasks_release: VolatileCell<u32>,
+            _reserved1: [u32; 54],
+            /// Granted transaction completed
+            ///
+            /// addr = base + 0x104
+            pub events_end: VolatileCell<u32>,
+            _reserved2: [u32; 2],
+            /// End of RXD buffer reached
+            ///
+            /// addr = base + 0x110
+            pub events_endrx: VolatileCell<u32>,
+            _reserved3: [u32; 5],
+            /// Semaphore acquired
+            ///
+            /// addr = base + 0x128
+            pub events_acquired: VolatileCell<u32>,
+            _reserved4: [u32; 53],
+            /// Shortcut register
+            ///
+            /// addr = base + 0x200
+            pub shorts: VolatileCell<u32>,
+            _reserved5: [u32; 64],
+            /// Enable interrupt
+            ///
+            /// addr = base + 0x304
+            pub intenset: VolatileCell<InterruptEnable>,
+            /// Disable interrupt
+            ///
+            /// addr = base + 0x308
+            pub intenclr: VolatileCell<InterruptEnable>,
+            _reserved6: [u32; 61],
+            /// Semaphore status
+            ///
+            /// addr = base + 0x400
+            pub semstat: VolatileCell<u32>,
+            _reserved7: [u32; 15],
+            /// Overread and overflow of the last transaction
+            ///
+            /// addr = base + 0x440
+            pub status: VolatileCell<u32>,
+            _reserved8: [u32; 47],
+            /// Enable SPIS
+            ///
+            /// addr = base + 0x500
+            pub enable: VolatileCell<u32>,
+            _reserved9: u32,
+            /// Pin select for SCK
+            ///
+            /// addr = base + 0x508
+            pub psel_sck: VolatileCell<Pinmux>,
+            /// Pin select for MISO signal
+            ///
+            /// addr = base + 0x50C
+            pub psel_miso: VolatileCell<Pinmux>,
+            /// Pin select for MOSI signal
+            ///
+            /// addr = base + 0x510
+            pub psel_mosi: VolatileCell<Pinmux>,
+            /// Pin select for CSN signal
+            ///
+            /// addr = base + 0x514
+            pub psel_csn: VolatileCell<Pinmux>,
+            _reserved10: [u32; 7],
+            /// Data pointer
+            ///
+            /// addr = base + 0x534
+            pub rxd_ptr: VolatileCell<*mut u8>,
+            /// Maximum number of bytes in receive buffer
+            ///
+            /// addr = base + 0x538
+            pub rxd_maxcnt: VolatileCell<u32>,
+            /// Number of bytes received in the last transaction
+            ///
+            /// addr = base + 0x53C
+            pub rxd_amount: VolatileCell<u32>,
+            _reserved11: u32,
+            /// Data pointer
+            ///
+            /// addr = base + 0x544
+            pub txd_ptr: VolatileCell<*const u8>,
+            /// Maximum number of bytes in transmit buffer
+            ///
+            /// addr = base + 0x548
+            pub txd_maxcnt: VolatileCell<u32>,
+            /// Number of bytes transmitted in the last transaction
+            ///
+            /// addr = base + 0x54C
+            pub txd_amount: VolatileCell<u32>,
+            _reserved12: u32,
+            /// Configuration register
+            ///
+            /// addr = base + 0x554
+            pub config: VolatileCell<Config>,
+            _reserved13: u32,
+            /// Default character. Character clocked out while the CPU holds
+            /// the semaphore.
+            ///
+            /// addr = base + 0x55C
+            pub def: VolatileCell<u32>,
+            _reserved14: [u32; 24],
+            /// Over-read character. Character clocked out after an over-read of the TXD buffer.
+            ///
+            /// addr = base + 0x5C0
+            pub orc: VolatileCell<u32>,
+        }
+    }
 }
 
 /// A SPI master device.
@@ -522,3 +687,234 @@ impl hil::spi::SpiMaster for SPIM {
         unimplemented!("SPI: Use `read_write_bytes()` instead.");
     }
 }
+
+/// A SPI slave device.
+///
+/// The `SPIS` and the CPU share the EasyDMA buffers through a semaphore. The
+/// CPU acquires it to point the `SPIS` at new buffers and releases it to hand
+/// them over. The `SPIS` holds the semaphore for the next transaction and
+/// gives it back to the CPU at its END through a shortcut. While the CPU
+/// holds the semaphore, the master is answered with the DEF character.
+pub struct SPIS {
+    registers: *const registers::spis::SPIS,
+    client: Cell<Option<&'static hil::spi::SpiSlaveClient>>,
+    initialized: Cell<bool>,
+    /// Buffers are waiting for the semaphore
+    pending: Cell<bool>,
+    /// Buffers are handed to the `SPIS` for the next transaction
+    active: Cell<bool>,
+    tx_buf: TakeCell<'static, [u8]>,
+    rx_buf: TakeCell<'static, [u8]>,
+    transfer_len: Cell<usize>,
+}
+
+impl SPIS {
+    const fn new(instance: usize) -> SPIS {
+        SPIS {
+            registers: registers::spis::INSTANCES[instance],
+            client: Cell::new(None),
+            initialized: Cell::new(false),
+            pending: Cell::new(false),
+            active: Cell::new(false),
+            tx_buf: TakeCell::empty(),
+            rx_buf: TakeCell::empty(),
+            transfer_len: Cell::new(0),
+        }
+    }
+
+    fn regs(&self) -> &registers::spis::SPIS {
+        unsafe { &*self.registers }
+    }
+
+    #[inline(never)]
+    pub fn handle_interrupt(&self) {
+        if self.regs().events_acquired.get() == 1 {
+            // The CPU holds the semaphore
+            self.regs().events_acquired.set(0);
+            if self.pending.get() {
+                self.hand_over_buffers();
+            }
+        }
+
+        if self.regs().events_end.get() == 1 {
+            // Transaction completed, the shortcut has acquired the semaphore
+            // for the CPU again
+            self.regs().events_end.set(0);
+            self.regs().status.set(
+                registers::spis::STATUS_OVERREAD | registers::spis::STATUS_OVERFLOW,
+            );
+
+            if self.active.get() {
+                self.active.set(false);
+                let tx_buf = self.tx_buf.take();
+                let rx_buf = self.rx_buf.take();
+                let len = self.transfer_len.get();
+                match self.client.get() {
+                    None => (),
+                    Some(client) => client.read_write_done(tx_buf, rx_buf, len),
+                }
+            }
+        }
+
+        // Although we only configured the chip interrupt on the above
+        // events, the chip also sets ENDRX. Let's clear that flag.
+        if self.regs().events_endrx.get() == 1 {
+            // End of RXD buffer reached
+            self.regs().events_endrx.set(0);
+        }
+    }
+
+    /// Point EasyDMA at the pending buffers and release the semaphore to
+    /// the `SPIS`. The CPU must hold the semaphore.
+    fn hand_over_buffers(&self) {
+        let regs = self.regs();
+        match self.tx_buf.map(|buf| buf.as_ptr()) {
+            Some(ptr) => {
+                regs.txd_ptr.set(ptr);
+                regs.txd_maxcnt.set(self.transfer_len.get() as u32);
+            }
+            None => {
+                regs.txd_ptr.set(ptr::null());
+                regs.txd_maxcnt.set(0);
+            }
+        }
+        match self.rx_buf.map(|buf| buf.as_mut_ptr()) {
+            Some(ptr) => {
+                regs.rxd_ptr.set(ptr);
+                regs.rxd_maxcnt.set(self.transfer_len.get() as u32);
+            }
+            None => {
+                regs.rxd_ptr.set(ptr::null_mut());
+                regs.rxd_maxcnt.set(0);
+            }
+        }
+        self.pending.set(false);
+        self.active.set(true);
+        regs.tasks_release.set(1);
+    }
+
+    /// Configures an already constructed `SPIS`.
+    pub fn configure(&self, mosi: Pinmux, miso: Pinmux, sck: Pinmux, csn: Pinmux) {
+        let regs = self.regs();
+        regs.psel_mosi.set(mosi);
+        regs.psel_miso.set(miso);
+        regs.psel_sck.set(sck);
+        regs.psel_csn.set(csn);
+        self.enable();
+    }
+
+    /// Enables `SPIS` peripheral.
+    pub fn enable(&self) {
+        self.regs().enable.set(2);
+    }
+
+    /// Disables `SPIS` peripheral.
+    pub fn disable(&self) {
+        self.regs().enable.set(0);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.regs().enable.get() == 2
+    }
+}
+
+impl hil::spi::SpiSlave for SPIS {
+    fn init(&self) {
+        use self::registers::spis::InterruptEnable;
+        let mut enabled_ints = InterruptEnable(0);
+        enabled_ints.set_end(1);
+        enabled_ints.set_acquired(1);
+        self.regs().intenset.set(enabled_ints);
+        self.regs().shorts.set(registers::spis::SHORTS_END_ACQUIRE);
+        // Hold the semaphore until there are buffers, the master gets the
+        // DEF character in the meantime
+        self.regs().tasks_acquire.set(1);
+        self.initialized.set(true);
+    }
+
+    fn has_client(&self) -> bool {
+        self.client.get().is_some()
+    }
+
+    fn set_client(&self, client: Option<&'static hil::spi::SpiSlaveClient>) {
+        self.client.set(client);
+    }
+
+    fn set_write_byte(&self, write_byte: u8) {
+        // Sent when the CPU holds the semaphore as well as past the end of
+        // the transmit buffer
+        self.regs().def.set(write_byte as u32);
+        self.regs().orc.set(write_byte as u32);
+    }
+
+    fn read_write_bytes(
+        &self,
+        write_buffer: Option<&'static mut [u8]>,
+        read_buffer: Option<&'static mut [u8]>,
+        len: usize,
+    ) -> ReturnCode {
+        debug_assert!(self.initialized.get());
+        if self.pending.get() || self.active.get() {
+            return ReturnCode::EBUSY;
+        }
+
+        let mut len = len;
+        write_buffer.as_ref().map(|buf| len = cmp::min(len, buf.len()));
+        read_buffer.as_ref().map(|buf| len = cmp::min(len, buf.len()));
+        self.transfer_len.set(len);
+        self.tx_buf.put(write_buffer);
+        self.rx_buf.put(read_buffer);
+        self.pending.set(true);
+
+        if self.regs().semstat.get() == registers::spis::SEMSTAT_CPU {
+            self.hand_over_buffers();
+        } else {
+            self.regs().tasks_acquire.set(1);
+        }
+        ReturnCode::SUCCESS
+    }
+
+    fn set_clock(&self, polarity: hil::spi::ClockPolarity) {
+        debug_assert!(self.initialized.get());
+        use self::hil::spi::ClockPolarity;
+        let mut config = self.regs().config.get();
+        config.set_clock_polarity(match polarity {
+            ClockPolarity::IdleLow => 0,
+            ClockPolarity::IdleHigh => 1,
+        });
+        self.regs().config.set(config);
+    }
+
+    fn get_clock(&self) -> hil::spi::ClockPolarity {
+        debug_assert!(self.initialized.get());
+        use self::hil::spi::ClockPolarity;
+        let config = self.regs().config.get();
+        match config.clock_polarity() {
+            0 => ClockPolarity::IdleLow,
+            1 => ClockPolarity::IdleHigh,
+            _ => unreachable!(),
+        }
+    }
+
+    fn set_phase(&self, phase: hil::spi::ClockPhase) {
+        debug_assert!(self.initialized.get());
+        use self::hil::spi::ClockPhase;
+        let mut config = self.regs().config.get();
+        config.set_clock_phase(match phase {
+            ClockPhase::SampleLeading => 0,
+            ClockPhase::SampleTrailing => 1,
+        });
+        self.regs().config.set(config);
+    }
+
+    fn get_phase(&self) -> hil::spi::ClockPhase {
+        debug_assert!(self.initialized.get());
+        use self::hil::spi::ClockPhase;
+        let config = self.regs().config.get();
+        match config.clock_phase() {
+            0 => ClockPhase::SampleLeading,
+            1 => ClockPhase::SampleTrailing,
+            _ => unreachable!(),
+        }
+    }
+}
@@ -1,6 +1,13 @@
 //! Universal asynchronous receiver/transmitter with EasyDMA (UARTE)
 //!
-//! The driver provides only transmission functionality
+//! Whole buffers are transmitted and received by DMA, the CPU only being
+//! interrupted when a transfer ends. EasyDMA moves at most 255 bytes at a
+//! time, so longer buffers are split into chunks. On receive the next chunk
+//! is queued as soon as the current one starts, and the `ENDRX_STARTRX`
+//! shortcut moves on to it without a gap in which bytes could be lost.
+//!
+//! Hardware flow control is used if requested in `init`, with the CTS and RTS
+//! pins passed to `configure`.
 //!
 //! Author
 //! -------------------
@@ -12,7 +19,8 @@ use core;
 use core::cell::Cell;
 use core::cmp::min;
 use kernel;
-use kernel::common::regs::{ReadOnly, ReadWrite, WriteOnly};
+use kernel::common::regs::{FieldValue, ReadOnly, ReadWrite, WriteOnly};
+use kernel::ReturnCode;
 use nrf5x::pinmux;
 
 const UARTE_BASE: u32 = 0x40002000;
@@ -144,8 +152,11 @@ register_bitfields! [u32,
 
     /// Configuration of parity and flow control
     Config [
-        HWFC OFFSET(0) NUMBITS(1),
-        PARITY OFFSET(1) NUMBITS(3)
+        HWFC OFFSET(0) NUMBITS(1) [],
+        PARITY OFFSET(1) NUMBITS(3) [
+            Excluded = 0,
+            Included = 7
+        ]
     ]
 ];
 
@@ -157,9 +168,17 @@ pub struct Uarte {
     client: Cell<Option<&'static kernel::hil::uart::Client>>,
     tx_buffer: kernel::common::take_cell::TakeCell<'static, [u8]>,
     tx_remaining_bytes: Cell<usize>,
+    /// Position in `tx_buffer` of the chunk being transmitted
+    tx_offset: Cell<usize>,
     rx_buffer: kernel::common::take_cell::TakeCell<'static, [u8]>,
-    rx_remaining_bytes: Cell<usize>,
-    offset: Cell<usize>,
+    /// Bytes requested by `receive`
+    rx_len: Cell<usize>,
+    /// Bytes received so far
+    rx_done: Cell<usize>,
+    /// Bytes handed to the DMA so far, by the current and the queued chunk
+    rx_queued: Cell<usize>,
+    /// The reception in progress was stopped by `abort_receive`
+    rx_aborted: Cell<bool>,
 }
 
 #[derive(Copy, Clone)]
@@ -179,9 +198,12 @@ impl Uarte {
             client: Cell::new(None),
             tx_buffer: kernel::common::take_cell::TakeCell::empty(),
             tx_remaining_bytes: Cell::new(0),
+            tx_offset: Cell::new(0),
             rx_buffer: kernel::common::take_cell::TakeCell::empty(),
-            rx_remaining_bytes: Cell::new(0),
-            offset: Cell::new(0),
+            rx_len: Cell::new(0),
+            rx_done: Cell::new(0),
+            rx_queued: Cell::new(0),
+            rx_aborted: Cell::new(false),
         }
     }
 
@@ -202,25 +224,9 @@ impl Uarte {
 
     fn set_baud_rate(&self, baud_rate: u32) {
         let regs = unsafe { &*self.regs };
-        match baud_rate {
-            1200 => regs.baudrate.set(0x0004F000),
-            2400 => regs.baudrate.set(0x0009D000),
-            4800 => regs.baudrate.set(0x0013B000),
-            9600 => regs.baudrate.set(0x00275000),
-            14400 => regs.baudrate.set(0x003AF000),
-            19200 => regs.baudrate.set(0x004EA000),
-            28800 => regs.baudrate.set(0x0075C000),
-            38400 => regs.baudrate.set(0x009D0000),
-            57600 => regs.baudrate.set(0x00EB0000),
-            76800 => regs.baudrate.set(0x013A9000),
-            115200 => regs.baudrate.set(0x01D60000),
-            230400 => regs.baudrate.set(0x03B00000),
-            250000 => regs.baudrate.set(0x04000000),
-            460800 => regs.baudrate.set(0x07400000),
-            921600 => regs.baudrate.set(0x0F000000),
-            1000000 => regs.baudrate.set(0x10000000),
-            _ => regs.baudrate.set(0x01D60000), //setting default to 115200
-        }
+        //setting default to 115200
+        regs.baudrate
+            .set(baud_rate_register(baud_rate).unwrap_or(0x01D60000));
     }
 
     // Enable UART peripheral, this need to disabled for low power applications
@@ -229,7 +235,6 @@ impl Uarte {
         regs.enable.write(Uart::ENABLE::ON);
     }
 
-    #[allow(dead_code)]
     fn disable_uart(&self) {
         let regs = unsafe { &*self.regs };
         regs.enable.write(Uart::ENABLE::OFF);
@@ -237,7 +242,8 @@ impl Uarte {
 
     fn enable_rx_interrupts(&self) {
         let regs = unsafe { &*self.regs };
-        regs.intenset.write(Interrupt::ENDRX::SET);
+        regs.intenset
+            .write(Interrupt::ENDRX::SET + Interrupt::RXSTARTED::SET);
     }
 
     fn enable_tx_interrupts(&self) {
@@ -247,7 +253,8 @@ impl Uarte {
 
     fn disable_rx_interrupts(&self) {
         let regs = unsafe { &*self.regs };
-        regs.intenclr.write(Interrupt::ENDRX::SET);
+        regs.intenclr
+            .write(Interrupt::ENDRX::SET + Interrupt::RXSTARTED::SET);
     }
 
     fn disable_tx_interrupts(&self) {
@@ -259,11 +266,11 @@ impl Uarte {
     #[inline(never)]
     pub fn handle_interrupt(&mut self) {
         let regs = unsafe { &*self.regs };
-        // disable interrupts
-        self.disable_tx_interrupts();
 
-        if self.tx_ready() {
-            let regs = unsafe { &*self.regs };
+        // `send_byte` polls ENDTX without an interrupt, only a transfer
+        // started by `transmit` is finished here
+        if self.tx_ready() && regs.intenset.is_set(Interrupt::ENDTX) {
+            self.disable_tx_interrupts();
             regs.event_endtx.write(Event::READY::CLEAR);
             let tx_bytes = regs.txd_amount.get() as usize;
 
@@ -293,7 +300,7 @@ impl Uarte {
                 });
             } else {
                 // Not all bytes have been transmitted then update offset and continue transmitting
-                self.offset.set(self.offset.get() + tx_bytes);
+                self.tx_offset.set(self.tx_offset.get() + tx_bytes);
                 self.tx_remaining_bytes.set(rem);
                 self.set_tx_dma_pointer_to_buffer();
                 regs.txd_maxcnt
@@ -303,39 +310,74 @@ impl Uarte {
             }
         }
 
+        // The chunk that just ended must be accounted for before the one
+        // following it is queued
         if self.rx_ready() {
-            self.disable_rx_interrupts();
             regs.event_endrx.write(Event::READY::CLEAR);
-            //Get the number of bytes in the buffer
             let rx_bytes = regs.rxd_amount.get() as usize;
-            let rem = self.rx_remaining_bytes.get();
-            //should check if rx_bytes > 0 (because we'll flush the FIFO and it will flag
-            //ENDRX even if it's empty)
-            if rx_bytes > 0 {
-                self.rx_remaining_bytes.set(rem.saturating_sub(rx_bytes));
-                //check if we're waiting for more (i.e. are we done listening?)
-                if self.rx_remaining_bytes.get() == 0 {
-                    // Signal client that the read is done
-                    self.client.get().map(|client| {
-                        self.rx_buffer.take().map(|rx_buffer| {
-                            client.receive_complete(
-                                rx_buffer,
-                                rx_bytes,
-                                kernel::hil::uart::Error::CommandComplete,
-                            );
-                        });
+            let chunk = min(
+                self.rx_len.get() - self.rx_done.get(),
+                UARTE_MAX_BUFFER_SIZE as usize,
+            );
+            self.rx_done.set(self.rx_done.get() + rx_bytes);
+
+            // A chunk cut short was stopped, nothing more is coming. An
+            // empty one that was not aborted, such as the late end of the
+            // reception `receive` stopped, leaves the reception armed.
+            let aborted = self.rx_aborted.get();
+            let spurious = rx_bytes == 0 && !aborted;
+            let ended = self.rx_done.get() >= self.rx_len.get() || rx_bytes < chunk || aborted;
+            if ended && !spurious {
+                self.rx_aborted.set(false);
+                // If RXSTARTED of the last chunk was not serviced in time
+                // the shortcut may have started the receiver again
+                self.disable_rx_interrupts();
+                regs.shorts.write(Shorts::ENDRX_STARTRX::CLEAR);
+                regs.task_stoprx.write(Task::ENABLE::SET);
+                // Signal client that the read is done
+                self.client.get().map(|client| {
+                    self.rx_buffer.take().map(|rx_buffer| {
+                        client.receive_complete(
+                            rx_buffer,
+                            self.rx_done.get(),
+                            if aborted {
+                                kernel::hil::uart::Error::Aborted
+                            } else {
+                                kernel::hil::uart::Error::CommandComplete
+                            },
+                        );
                     });
-                } else {
-                    self.set_rx_dma_pointer_to_buffer();
-                    //Flush the fifo, as per the datasheet recommendations
-                    regs.task_flush_rx.write(Task::ENABLE::SET);
-                    regs.task_startrx.write(Task::ENABLE::SET);
-                    self.enable_rx_interrupts();
-                }
+                });
+            }
+        }
+
+        if regs.event_rxstarted.is_set(Event::READY) {
+            regs.event_rxstarted.write(Event::READY::CLEAR);
+            // After the last chunk has started the shortcut is removed, for
+            // the reception to stop when it ends, or right away once aborted
+            if self.rx_buffer.is_some() && !self.rx_aborted.get() && self.queue_rx_chunk() {
+                regs.shorts.write(Shorts::ENDRX_STARTRX::SET);
+            } else {
+                regs.shorts.write(Shorts::ENDRX_STARTRX::CLEAR);
+                regs.intenclr.write(Interrupt::RXSTARTED::SET);
             }
         }
     }
 
+    /// Hand the DMA the next chunk of the receive buffer, to be moved on to
+    /// once the current one ends. Returns false if the whole buffer has been
+    /// handed over already.
+    fn queue_rx_chunk(&self) -> bool {
+        let queued = self.rx_queued.get();
+        let len = min(self.rx_len.get() - queued, UARTE_MAX_BUFFER_SIZE as usize);
+        if len == 0 {
+            return false;
+        }
+        self.set_rx_dma_pointer(queued, len);
+        self.rx_queued.set(queued + len);
+        true
+    }
+
     /// Transmit one byte at the time and the client is responsible for polling
     /// This is used by the panic handler
     pub unsafe fn send_byte(&self, byte: u8) {
@@ -366,16 +408,16 @@ impl Uarte {
         let regs = unsafe { &*self.regs };
         self.tx_buffer.map(|tx_buffer| {
             regs.txd_ptr
-                .set(tx_buffer[self.offset.get()..].as_ptr() as u32);
+                .set(tx_buffer[self.tx_offset.get()..].as_ptr() as u32);
         });
     }
 
-    fn set_rx_dma_pointer_to_buffer(&self) {
+    fn set_rx_dma_pointer(&self, offset: usize, len: usize) {
         let regs = unsafe { &*self.regs };
         self.rx_buffer.map(|rx_buffer| {
-            regs.rxd_ptr
-                .set(rx_buffer[self.offset.get()..].as_ptr() as u32);
+            regs.rxd_ptr.set(rx_buffer[offset..].as_ptr() as u32);
         });
+        regs.rxd_maxcnt.write(Counter::COUNTER.val(len as u32));
     }
 }
 
@@ -384,7 +426,15 @@ impl kernel::hil::uart::UART for Uarte {
         self.client.set(Some(client));
     }
 
+    /// Odd parity and two stop bits are rejected as by `reconfigure`: the
+    /// UARTE is left disabled rather than run with other parameters
     fn init(&self, params: kernel::hil::uart::UARTParams) {
+        let regs = unsafe { &*self.regs };
+        let config = match config_register(params) {
+            Some(config) => config,
+            None => return,
+        };
+        regs.config.write(config);
         self.enable_uart();
         self.set_baud_rate(params.baud_rate);
     }
@@ -396,14 +446,14 @@ impl kernel::hil::uart::UART for Uarte {
             return;
         }
 
-        self.tx_remaining_bytes.set(tx_len);
-        self.offset.set(0);
+        self.tx_remaining_bytes.set(truncated_len);
+        self.tx_offset.set(0);
         self.tx_buffer.replace(tx_data);
         self.set_tx_dma_pointer_to_buffer();
 
         let regs = unsafe { &*self.regs };
         regs.txd_maxcnt
-            .write(Counter::COUNTER.val(min(tx_len as u32, UARTE_MAX_BUFFER_SIZE)));
+            .write(Counter::COUNTER.val(min(truncated_len as u32, UARTE_MAX_BUFFER_SIZE)));
         regs.task_starttx.write(Task::ENABLE::SET);
 
         self.enable_tx_interrupts();
@@ -415,18 +465,101 @@ impl kernel::hil::uart::UART for Uarte {
         // truncate rx_len if necessary
         let truncated_length = core::cmp::min(rx_len, rx_buf.len());
 
-        self.rx_remaining_bytes.set(truncated_length);
-        self.offset.set(0);
-        self.rx_buffer.replace(rx_buf);
-        self.set_rx_dma_pointer_to_buffer();
+        // Nothing can be received, the buffer goes back right away
+        if truncated_length == 0 {
+            self.client.get().map(move |client| {
+                client.receive_complete(rx_buf, 0, kernel::hil::uart::Error::Aborted);
+            });
+            return;
+        }
 
-        let truncated_uart_max_length = core::cmp::min(truncated_length, 255);
+        self.rx_len.set(truncated_length);
+        self.rx_done.set(0);
+        self.rx_queued.set(0);
+        self.rx_aborted.set(false);
+        self.rx_buffer.replace(rx_buf);
 
-        regs.rxd_maxcnt
-            .write(Counter::COUNTER.val(truncated_uart_max_length as u32));
         regs.task_stoprx.write(Task::ENABLE::SET);
+        regs.shorts.write(Shorts::ENDRX_STARTRX::CLEAR);
+        regs.event_endrx.write(Event::READY::CLEAR);
+        regs.event_rxstarted.write(Event::READY::CLEAR);
+        // The first chunk, the following ones are queued as each starts
+        self.queue_rx_chunk();
         regs.task_startrx.write(Task::ENABLE::SET);
 
         self.enable_rx_interrupts();
     }
+
+    fn reconfigure(&self, params: kernel::hil::uart::UARTParams) -> ReturnCode {
+        let config = match config_register(params) {
+            Some(config) => config,
+            None => return ReturnCode::EINVAL,
+        };
+        let baud_rate = match baud_rate_register(params.baud_rate) {
+            Some(baud_rate) => baud_rate,
+            None => return ReturnCode::EINVAL,
+        };
+        if self.tx_buffer.is_some() || self.rx_buffer.is_some() {
+            return ReturnCode::EBUSY;
+        }
+
+        let regs = unsafe { &*self.regs };
+        self.disable_uart();
+        regs.config.write(config);
+        regs.baudrate.set(baud_rate);
+        self.enable_uart();
+        ReturnCode::SUCCESS
+    }
+
+    /// The reception stops once the byte being received, if any, is in the
+    /// buffer. The client is called from the interrupt that follows.
+    fn abort_receive(&self) {
+        if self.rx_buffer.is_none() {
+            return;
+        }
+        let regs = unsafe { &*self.regs };
+        self.rx_aborted.set(true);
+        regs.shorts.write(Shorts::ENDRX_STARTRX::CLEAR);
+        regs.task_stoprx.write(Task::ENABLE::SET);
+    }
+}
+
+/// Value of the CONFIG register for the parity and flow control of `params`,
+/// if the UARTE supports its parity and stop bits: no or even parity, and
+/// one stop bit
+fn config_register(
+    params: kernel::hil::uart::UARTParams,
+) -> Option<FieldValue<u32, Config::Register>> {
+    let parity = match params.parity {
+        kernel::hil::uart::Parity::None => Config::PARITY::Excluded,
+        kernel::hil::uart::Parity::Even => Config::PARITY::Included,
+        kernel::hil::uart::Parity::Odd => return None,
+    };
+    if let kernel::hil::uart::StopBits::Two = params.stop_bits {
+        return None;
+    }
+    Some(Config::HWFC.val(params.hw_flow_control as u32) + parity)
+}
+
+/// Value of the BAUDRATE register for `baud_rate`, if the UARTE supports it
+fn baud_rate_register(baud_rate: u32) -> Option<u32> {
+    match baud_rate {
+        1200 => Some(0x0004F000),
+        2400 => Some(0x0009D000),
+        4800 => Some(0x0013B000),
+        9600 => Some(0x00275000),
+        14400 => Some(0x003AF000),
+        19200 => Some(0x004EA000),
+        28800 => Some(0x0075C000),
+        38400 => Some(0x009D0000),
+        57600 => Some(0x00EB0000),
+        76800 => Some(0x013A9000),
+        115200 => Some(0x01D60000),
+        230400 => Some(0x03B00000),
+        250000 => Some(0x04000000),
+        460800 => Some(0x07400000),
+        921600 => Some(0x0F000000),
+        1000000 => Some(0x10000000),
+        _ => None,
+    }
 }
@@ -3,6 +3,12 @@
 //! Provides a simple driverto encrypt and decrypt
 //! messages using aes128-ctr mode on top of aes128-ecb.
 //!
+//! Plain aes128-ecb is available as well, for callers such as the BLE link
+//! layer that build their own modes on the block cipher. The hardware can
+//! only encrypt, so ECB decryption is not supported. ECB uses the same
+//! DMA buffer slot as the CTR counter, so the IV must be set again before
+//! switching back to CTR mode.
+//!
 //! Roughly, the module three buffers with the following content:
 //!
 //! * Key
@@ -39,20 +45,19 @@ use kernel::ReturnCode;
 
 // DMA buffer that the aes chip will mutate during encryption
 // Byte 0-15   - Key
-// Byte 16-32  - Payload
-// Byte 33-47  - Ciphertext
+// Byte 16-31  - Payload
+// Byte 32-47  - Ciphertext
 static mut ECB_DATA: [u8; 48] = [0; 48];
 
 #[allow(dead_code)]
 const KEY_START: usize = 0;
 #[allow(dead_code)]
-const KEY_END: usize = 15;
+const KEY_END: usize = 16;
 const PLAINTEXT_START: usize = 16;
 const PLAINTEXT_END: usize = 32;
+const CIPHERTEXT_START: usize = 32;
 #[allow(dead_code)]
-const CIPHERTEXT_START: usize = 33;
-#[allow(dead_code)]
-const CIPHERTEXT_END: usize = 47;
+const CIPHERTEXT_END: usize = 48;
 const MAX_LENGTH: usize = 128;
 const AESECB_BASE: usize = 0x4000E000;
 
@@ -127,6 +132,10 @@ pub struct AesECB<'a> {
     current_idx: Cell<usize>,
     start_idx: Cell<usize>,
     end_idx: Cell<usize>,
+    /// Encrypt blocks directly (ECB) instead of generating a CTR keystream
+    ecb_mode: Cell<bool>,
+    /// Whether a decryption was requested, which ECB mode does not support
+    decrypting: Cell<bool>,
 }
 
 pub static mut AESECB: AesECB = AesECB::new();
@@ -142,6 +151,8 @@ impl<'a> AesECB<'a> {
             current_idx: Cell::new(0),
             start_idx: Cell::new(0),
             end_idx: Cell::new(0),
+            ecb_mode: Cell::new(false),
+            decrypting: Cell::new(false),
         }
     }
 
@@ -165,6 +176,47 @@ impl<'a> AesECB<'a> {
         }
     }
 
+    // Copy the block at `idx` in the destination buffer, or at the same
+    // offset in the source buffer if there is one, to the ECB cleartext
+    fn ecb_load_block(&self, idx: usize) {
+        let start = self.start_idx.get();
+        let block = symmetric_encryption::AES128_BLOCK_SIZE;
+        if self.input.is_some() {
+            self.input.map(|input| unsafe {
+                ECB_DATA[PLAINTEXT_START..PLAINTEXT_END]
+                    .copy_from_slice(&input[idx - start..idx - start + block]);
+            });
+        } else {
+            self.output.map(|output| unsafe {
+                ECB_DATA[PLAINTEXT_START..PLAINTEXT_END].copy_from_slice(&output[idx..idx + block]);
+            });
+        }
+    }
+
+    // Store the ciphertext of the block at `current_idx` and either start the
+    // next block or hand the buffers back to the client
+    fn ecb_block_done(&self) {
+        let idx = self.current_idx.get();
+        let block = symmetric_encryption::AES128_BLOCK_SIZE;
+        self.output.map(|output| unsafe {
+            output[idx..idx + block].copy_from_slice(&ECB_DATA[CIPHERTEXT_START..CIPHERTEXT_END]);
+        });
+
+        let next = idx + block;
+        self.current_idx.set(next);
+        if next < self.end_idx.get() {
+            self.ecb_load_block(next);
+            self.crypt();
+        } else {
+            let input = self.input.take();
+            self.output.take().map(|output| {
+                self.client
+                    .get()
+                    .map(move |client| client.crypt_done(input, output));
+            });
+        }
+    }
+
     fn crypt(&self) {
         let regs = unsafe { &*self.regs };
 
@@ -181,7 +233,10 @@ impl<'a> AesECB<'a> {
         // disable interrupts
         self.disable_interrupts();
 
-        if regs.event_endecb.get() == 1 {
+        if regs.event_endecb.get() == 1 && self.ecb_mode.get() {
+            regs.event_endecb.write(Event::READY::CLEAR);
+            self.ecb_block_done();
+        } else if regs.event_endecb.get() == 1 {
             let current_idx = self.current_idx.get();
             let end_idx = self.end_idx.get();
 
@@ -305,6 +360,9 @@ impl<'a> kernel::hil::symmetric_encryption::AES128<'a> for AesECB<'a> {
         start_index: usize,
         stop_index: usize,
     ) -> Option<(ReturnCode, Option<&'a mut [u8]>, &'a mut [u8])> {
+        if self.ecb_mode.get() {
+            return self.crypt_ecb(source, dest, start_index, stop_index);
+        }
         match source {
             None => Some((ReturnCode::EINVAL, source, dest)),
             Some(src) => {
@@ -329,9 +387,57 @@ impl<'a> kernel::hil::symmetric_encryption::AES128<'a> for AesECB<'a> {
     }
 }
 
+impl<'a> AesECB<'a> {
+    fn crypt_ecb(
+        &'a self,
+        source: Option<&'a mut [u8]>,
+        dest: &'a mut [u8],
+        start_index: usize,
+        stop_index: usize,
+    ) -> Option<(ReturnCode, Option<&'a mut [u8]>, &'a mut [u8])> {
+        let block = symmetric_encryption::AES128_BLOCK_SIZE;
+        if self.decrypting.get() {
+            return Some((ReturnCode::ENOSUPPORT, source, dest));
+        }
+        if self.output.is_some() {
+            return Some((ReturnCode::EBUSY, source, dest));
+        }
+        if stop_index < start_index
+            || stop_index > dest.len()
+            || (stop_index - start_index) % block != 0
+            || source
+                .as_ref()
+                .map_or(false, |src| src.len() != stop_index - start_index)
+        {
+            return Some((ReturnCode::EINVAL, source, dest));
+        }
+        if stop_index == start_index {
+            return Some((ReturnCode::SUCCESS, source, dest));
+        }
+
+        source.map(|src| self.input.replace(src));
+        self.output.replace(dest);
+        self.start_idx.set(start_index);
+        self.end_idx.set(stop_index);
+        self.current_idx.set(start_index);
+
+        self.ecb_load_block(start_index);
+        self.crypt();
+        None
+    }
+}
+
 impl<'a> kernel::hil::symmetric_encryption::AES128Ctr for AesECB<'a> {
-    // not needed by NRF5x (the configuration is the same for encryption and decryption)
+    // the configuration is the same for encryption and decryption in CTR mode
     fn set_mode_aes128ctr(&self, _encrypting: bool) {
-        ()
+        self.ecb_mode.set(false);
+        self.decrypting.set(false);
+    }
+}
+
+impl<'a> kernel::hil::symmetric_encryption::AES128Ecb for AesECB<'a> {
+    fn set_mode_aes128ecb(&self, encrypting: bool) {
+        self.ecb_mode.set(true);
+        self.decrypting.set(!encrypting);
     }
 }
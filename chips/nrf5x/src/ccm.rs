@@ -0,0 +1,545 @@
+//! AES-CCM for BLE packets, nRF5X-family
+//!
+//! The CCM peripheral encrypts and decrypts BLE data channel PDUs with the
+//! AES-CCM mode of the Bluetooth specification, adding the 4 byte MIC when
+//! encrypting and checking it when decrypting. It works on packets laid out
+//! the way the radio stores them with the S1 field included in RAM: the
+//! header, the length, a byte the CCM ignores and the payload.
+//!
+//! Packets are encrypted before transmission with `encrypt`, which returns
+//! once the ciphertext is ready. Received packets are decrypted on the fly:
+//! `prepare_decrypt` sets the CCM up, the radio's READY event starts the key
+//! stream generation and its ADDRESS event the decryption, through the
+//! pre-programmed PPI channels 24 and 25 the radio enables, and
+//! `decrypt_done` waits for the end of the decryption after the radio's END
+//! event and tells whether the MIC matched.
+//!
+//! The session key and IV are set with `set_key` for the whole connection,
+//! the packet counter and direction with each packet.
+//!
+//! Outside of BLE, the CCM is available through the `AES128CCM` HIL for
+//! messages shaped the way the Bluetooth flavour of AES-CCM requires:
+//!
+//! * a single byte of authenticated data, of which the CCM ignores bits 2 to
+//!   4, so they must be clear
+//! * 1 to 27 bytes of message, always encrypted
+//! * a 4 byte MIC
+//!
+//! Any 13 byte nonce can be used: its first 39 bits are the packet counter,
+//! the next one the direction bit and the last 8 bytes the IV. Other requests
+//! get `ENOSUPPORT`, those users should fall back to the software CCM of
+//! `capsules::aes_ccm`. That includes IEEE 802.15.4 frames, whose
+//! authenticated data is the MAC header and whose MIC may be 8 or 16 bytes.
+//! The message is copied to RAM the EasyDMA of the CCM reaches, along with
+//! the scratch area, and the CCM enabled for the time of the operation,
+//! which completes with the `CCM_AAR` interrupt.
+//!
+//! The radio owns the CCM from `enable` to `disable`, for the whole of an
+//! encrypted connection, and requests get `EBUSY` in the meantime. If a
+//! request is in flight when the radio claims the CCM, `enable` waits for
+//! it to end, which takes a few tens of microseconds, and its client is
+//! still called back from the interrupt.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! nrf5x::ccm::CCM.enable();
+//! nrf5x::ccm::CCM.set_key(&session_key, &iv);
+//! nrf5x::ccm::CCM.encrypt(&plaintext, &mut ciphertext, counter, Direction::SlaveToMaster,
+//!                         DataRate::Rate1Mbit);
+//! ```
+//!
+//! ```rust
+//! AES128CCM::set_client(&nrf5x::ccm::CCM, client);
+//! AES128CCM::set_key(&nrf5x::ccm::CCM, &key);
+//! AES128CCM::set_nonce(&nrf5x::ccm::CCM, &nonce);
+//! AES128CCM::crypt(&nrf5x::ccm::CCM, buf, 0, 1, len, MIC_LENGTH, true, true);
+//! ```
+
+use core::cell::Cell;
+use kernel::common::regs::{ReadOnly, ReadWrite, WriteOnly};
+use kernel::common::take_cell::TakeCell;
+use kernel::hil::symmetric_encryption::{AES128CCM, CCMClient, AES128_KEY_SIZE,
+                                        CCM_NONCE_LENGTH};
+use kernel::ReturnCode;
+
+const CCM_BASE: usize = 0x4000F000;
+
+/// Bytes the CCM writes after the payload of an encrypted packet
+pub const MIC_LENGTH: usize = 4;
+
+// Configuration read by the CCM: the key, the packet counter, of which the
+// 39 least significant bits are used, the direction bit and the IV
+const CNF_KEY: usize = 0;
+const CNF_COUNTER: usize = 16;
+const CNF_DIRECTION: usize = 24;
+const CNF_IV: usize = 25;
+static mut CNF: [u8; 33] = [0; 33];
+
+// Temporary storage of the CCM, 43 bytes for payloads up to 27 bytes
+static mut SCRATCH: [u8; 43] = [0; 43];
+
+// Messages of the `AES128CCM` HIL in the packet layout of the CCM: the
+// authenticated byte, the length, a byte the CCM ignores, the message and the
+// MIC
+const MESSAGE_OFFSET: usize = 3;
+const MAX_MESSAGE_LENGTH: usize = 27;
+static mut MESSAGE_IN: [u8; MESSAGE_OFFSET + MAX_MESSAGE_LENGTH + MIC_LENGTH] =
+    [0; MESSAGE_OFFSET + MAX_MESSAGE_LENGTH + MIC_LENGTH];
+static mut MESSAGE_OUT: [u8; MESSAGE_OFFSET + MAX_MESSAGE_LENGTH + MIC_LENGTH] =
+    [0; MESSAGE_OFFSET + MAX_MESSAGE_LENGTH + MIC_LENGTH];
+
+// Bits of the authenticated byte the CCM replaces with zeroes, the SN, NESN
+// and MD bits of a BLE data channel PDU header
+const IGNORED_HEADER_BITS: u8 = 0x1c;
+
+#[repr(C)]
+struct CcmRegisters {
+    /// Start generation of the key stream
+    /// Address: 0x000 - 0x004
+    task_ksgen: WriteOnly<u32, Task::Register>,
+    /// Start encryption or decryption
+    /// Address: 0x004 - 0x008
+    task_crypt: WriteOnly<u32, Task::Register>,
+    /// Stop encryption or decryption
+    /// Address: 0x008 - 0x00C
+    task_stop: WriteOnly<u32, Task::Register>,
+    _reserved0: [u32; 61],
+    /// Key stream generation complete
+    /// Address: 0x100 - 0x104
+    event_endksgen: ReadWrite<u32, Event::Register>,
+    /// Encryption or decryption complete
+    /// Address: 0x104 - 0x108
+    event_endcrypt: ReadWrite<u32, Event::Register>,
+    /// Encryption or decryption aborted
+    /// Address: 0x108 - 0x10C
+    event_error: ReadWrite<u32, Event::Register>,
+    _reserved1: [u32; 61],
+    /// Shortcut register
+    /// Address: 0x200 - 0x204
+    shorts: ReadWrite<u32, Shorts::Register>,
+    _reserved2: [u32; 64],
+    /// Enable interrupt
+    /// Address: 0x304 - 0x308
+    intenset: ReadWrite<u32, Interrupt::Register>,
+    /// Disable interrupt
+    /// Address: 0x308 - 0x30C
+    intenclr: ReadWrite<u32, Interrupt::Register>,
+    _reserved3: [u32; 61],
+    /// Result of the MIC check of the last decryption
+    /// Address: 0x400 - 0x404
+    micstatus: ReadOnly<u32, MicStatus::Register>,
+    _reserved4: [u32; 63],
+    /// Enable
+    /// Address: 0x500 - 0x504
+    enable: ReadWrite<u32, Enable::Register>,
+    /// Operation mode
+    /// Address: 0x504 - 0x508
+    mode: ReadWrite<u32, Mode::Register>,
+    /// Pointer to the configuration
+    /// Address: 0x508 - 0x50C
+    cnfptr: ReadWrite<u32>,
+    /// Pointer to the input packet
+    /// Address: 0x50C - 0x510
+    inptr: ReadWrite<u32>,
+    /// Pointer to the output packet
+    /// Address: 0x510 - 0x514
+    outptr: ReadWrite<u32>,
+    /// Pointer to the scratch area
+    /// Address: 0x514 - 0x518
+    scratchptr: ReadWrite<u32>,
+}
+
+register_bitfields! [u32,
+    Task [
+        ENABLE OFFSET(0) NUMBITS(1)
+    ],
+
+    Event [
+        READY OFFSET(0) NUMBITS(1)
+    ],
+
+    Shorts [
+        /// Start the encryption or decryption once the key stream is ready
+        ENDKSGEN_CRYPT OFFSET(0) NUMBITS(1)
+    ],
+
+    Interrupt [
+        ENDKSGEN OFFSET(0) NUMBITS(1),
+        ENDCRYPT OFFSET(1) NUMBITS(1),
+        ERROR OFFSET(2) NUMBITS(1)
+    ],
+
+    MicStatus [
+        CHECK_PASSED OFFSET(0) NUMBITS(1)
+    ],
+
+    Enable [
+        ENABLE OFFSET(0) NUMBITS(2) [
+            Disabled = 0,
+            Enabled = 2
+        ]
+    ],
+
+    Mode [
+        MODE OFFSET(0) NUMBITS(1) [
+            Encryption = 0,
+            Decryption = 1
+        ],
+        /// Radio data rate the decryption keeps up with, nRF52 only
+        DATARATE OFFSET(16) NUMBITS(2) [
+            Rate1Mbit = 0,
+            Rate2Mbit = 1,
+            Rate125Kbps = 2,
+            Rate500Kbps = 3
+        ]
+    ]
+];
+
+/// Direction of a packet, part of the CCM nonce
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Direction {
+    MasterToSlave,
+    SlaveToMaster,
+}
+
+/// Data rate of the radio
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DataRate {
+    Rate1Mbit,
+    Rate2Mbit,
+    Rate125Kbps,
+    Rate500Kbps,
+}
+
+// Operation of the `AES128CCM` HIL in progress: where the message is in the
+// buffer of the client, its length and whether it is being encrypted
+#[derive(Copy, Clone, Debug)]
+struct Message {
+    m_off: usize,
+    m_len: usize,
+    encrypting: bool,
+}
+
+pub struct Ccm<'a> {
+    regs: *const CcmRegisters,
+    client: Cell<Option<&'a CCMClient>>,
+    key: Cell<[u8; AES128_KEY_SIZE]>,
+    nonce: Cell<[u8; CCM_NONCE_LENGTH]>,
+    buf: TakeCell<'static, [u8]>,
+    message: Cell<Option<Message>>,
+    /// Result and tag validity of the message done, until the client is
+    /// called back
+    result: Cell<Option<(ReturnCode, bool)>>,
+    /// Between `enable` and `disable`, the radio uses the CCM
+    radio_owned: Cell<bool>,
+}
+
+pub static mut CCM: Ccm<'static> = Ccm::new();
+
+impl<'a> Ccm<'a> {
+    const fn new() -> Ccm<'a> {
+        Ccm {
+            regs: CCM_BASE as *const CcmRegisters,
+            client: Cell::new(None),
+            key: Cell::new([0; AES128_KEY_SIZE]),
+            nonce: Cell::new([0; CCM_NONCE_LENGTH]),
+            buf: TakeCell::empty(),
+            message: Cell::new(None),
+            result: Cell::new(None),
+            radio_owned: Cell::new(false),
+        }
+    }
+
+    /// Claim the CCM for the radio, once the message of the HIL in flight,
+    /// if any, is done
+    pub fn enable(&self) {
+        if self.message.get().is_some() {
+            let regs = unsafe { &*self.regs };
+            while regs.event_endcrypt.get() == 0 && regs.event_error.get() == 0 {}
+            self.finish_message();
+        }
+        self.radio_owned.set(true);
+        self.power_on();
+    }
+
+    /// Hand the CCM back from the radio
+    pub fn disable(&self) {
+        self.power_off();
+        self.radio_owned.set(false);
+    }
+
+    fn power_on(&self) {
+        let regs = unsafe { &*self.regs };
+        regs.enable.write(Enable::ENABLE::Enabled);
+        regs.intenclr.set(0xffffffff);
+        unsafe {
+            regs.cnfptr.set(&CNF as *const u8 as u32);
+            regs.scratchptr.set(&SCRATCH as *const u8 as u32);
+        }
+    }
+
+    fn power_off(&self) {
+        let regs = unsafe { &*self.regs };
+        regs.task_stop.write(Task::ENABLE::SET);
+        regs.enable.write(Enable::ENABLE::Disabled);
+    }
+
+    /// Set the session key, most significant octet first, and the IV.
+    pub fn set_key(&self, key: &[u8; 16], iv: &[u8; 8]) {
+        unsafe {
+            CNF[CNF_KEY..CNF_KEY + 16].copy_from_slice(key);
+            CNF[CNF_IV..CNF_IV + 8].copy_from_slice(iv);
+        }
+    }
+
+    fn set_counter(&self, counter: u64, direction: Direction) {
+        unsafe {
+            for i in 0..8 {
+                CNF[CNF_COUNTER + i] = (counter >> (8 * i)) as u8;
+            }
+            CNF[CNF_DIRECTION] = match direction {
+                Direction::MasterToSlave => 1,
+                Direction::SlaveToMaster => 0,
+            };
+        }
+    }
+
+    fn set_mode(&self, decrypting: bool, rate: DataRate) {
+        let regs = unsafe { &*self.regs };
+        let mode = if decrypting {
+            Mode::MODE::Decryption
+        } else {
+            Mode::MODE::Encryption
+        };
+        let rate = match rate {
+            DataRate::Rate1Mbit => Mode::DATARATE::Rate1Mbit,
+            DataRate::Rate2Mbit => Mode::DATARATE::Rate2Mbit,
+            DataRate::Rate125Kbps => Mode::DATARATE::Rate125Kbps,
+            DataRate::Rate500Kbps => Mode::DATARATE::Rate500Kbps,
+        };
+        regs.mode.write(mode + rate);
+    }
+
+    /// Encrypt the packet in `input` into `output`, which must have room
+    /// for the MIC. Returns false if the CCM gave up on the packet, which it
+    /// does with payloads longer than 27 bytes.
+    pub fn encrypt(
+        &self,
+        input: &[u8],
+        output: &mut [u8],
+        counter: u64,
+        direction: Direction,
+        rate: DataRate,
+    ) -> bool {
+        let regs = unsafe { &*self.regs };
+        self.set_counter(counter, direction);
+        self.set_mode(false, rate);
+        regs.inptr.set(input.as_ptr() as u32);
+        regs.outptr.set(output.as_mut_ptr() as u32);
+
+        regs.event_endksgen.write(Event::READY::CLEAR);
+        regs.event_endcrypt.write(Event::READY::CLEAR);
+        regs.event_error.write(Event::READY::CLEAR);
+        regs.shorts.write(Shorts::ENDKSGEN_CRYPT::SET);
+        regs.task_ksgen.write(Task::ENABLE::SET);
+
+        while regs.event_endcrypt.get() == 0 && regs.event_error.get() == 0 {}
+        regs.shorts.set(0);
+        regs.event_error.get() == 0
+    }
+
+    /// Get ready to decrypt the packet the radio is about to receive into
+    /// `input`, writing the plaintext to `output`. Both must stay in place
+    /// until `decrypt_done`.
+    pub fn prepare_decrypt(
+        &self,
+        input: &[u8],
+        output: &mut [u8],
+        counter: u64,
+        direction: Direction,
+        rate: DataRate,
+    ) {
+        let regs = unsafe { &*self.regs };
+        self.set_counter(counter, direction);
+        self.set_mode(true, rate);
+        regs.inptr.set(input.as_ptr() as u32);
+        regs.outptr.set(output.as_mut_ptr() as u32);
+
+        regs.shorts.set(0);
+        regs.event_endksgen.write(Event::READY::CLEAR);
+        regs.event_endcrypt.write(Event::READY::CLEAR);
+        regs.event_error.write(Event::READY::CLEAR);
+    }
+
+    /// Wait for the decryption started by the radio to end. Returns whether
+    /// the MIC of the packet matched.
+    pub fn decrypt_done(&self) -> bool {
+        let regs = unsafe { &*self.regs };
+        if regs.event_endksgen.get() == 0 {
+            // The radio never got to start the decryption
+            return false;
+        }
+        while regs.event_endcrypt.get() == 0 && regs.event_error.get() == 0 {}
+        regs.event_error.get() == 0 && regs.micstatus.is_set(MicStatus::CHECK_PASSED)
+    }
+
+    // Start the operation on the message already copied to `MESSAGE_IN`
+    fn start_message(&self, encrypting: bool) {
+        let regs = unsafe { &*self.regs };
+        let nonce = self.nonce.get();
+        let mut counter = 0;
+        for i in 0..5 {
+            counter |= (nonce[i] as u64) << (8 * i);
+        }
+        let direction = if nonce[4] & 0x80 != 0 {
+            Direction::MasterToSlave
+        } else {
+            Direction::SlaveToMaster
+        };
+
+        self.power_on();
+        unsafe {
+            CNF[CNF_KEY..CNF_KEY + 16].copy_from_slice(&self.key.get());
+            CNF[CNF_IV..CNF_IV + 8].copy_from_slice(&nonce[5..]);
+        }
+        self.set_counter(counter & 0x7f_ffff_ffff, direction);
+        self.set_mode(!encrypting, DataRate::Rate1Mbit);
+        unsafe {
+            regs.inptr.set(&MESSAGE_IN as *const u8 as u32);
+            regs.outptr.set(&MESSAGE_OUT as *const u8 as u32);
+        }
+
+        regs.event_endksgen.write(Event::READY::CLEAR);
+        regs.event_endcrypt.write(Event::READY::CLEAR);
+        regs.event_error.write(Event::READY::CLEAR);
+        regs.shorts.write(Shorts::ENDKSGEN_CRYPT::SET);
+        regs.intenset
+            .write(Interrupt::ENDCRYPT::SET + Interrupt::ERROR::SET);
+        regs.task_ksgen.write(Task::ENABLE::SET);
+    }
+
+    // Copy the result of the message done out of the CCM and turn it off.
+    // The client is called back from `handle_interrupt`.
+    fn finish_message(&self) {
+        let regs = unsafe { &*self.regs };
+        let message = match self.message.take() {
+            Some(message) => message,
+            None => return,
+        };
+        let failed = regs.event_error.get() != 0;
+        let mic_passed = regs.micstatus.is_set(MicStatus::CHECK_PASSED);
+        regs.event_endcrypt.write(Event::READY::CLEAR);
+        regs.event_error.write(Event::READY::CLEAR);
+        regs.shorts.set(0);
+        regs.intenclr.set(0xffffffff);
+        self.power_off();
+
+        let res = if failed {
+            ReturnCode::FAIL
+        } else {
+            let len = if message.encrypting {
+                message.m_len + MIC_LENGTH
+            } else {
+                message.m_len
+            };
+            self.buf.map(|buf| unsafe {
+                buf[message.m_off..message.m_off + len]
+                    .copy_from_slice(&MESSAGE_OUT[MESSAGE_OFFSET..MESSAGE_OFFSET + len]);
+            });
+            ReturnCode::SUCCESS
+        };
+        let tag_is_valid = !failed && (message.encrypting || mic_passed);
+        self.result.set(Some((res, tag_is_valid)));
+    }
+
+    pub fn handle_interrupt(&self) {
+        let regs = unsafe { &*self.regs };
+        // The radio drives the CCM without interrupts, the message may
+        // also have been finished by `enable` already
+        if self.message.get().is_some() {
+            if regs.event_endcrypt.get() == 0 && regs.event_error.get() == 0 {
+                return;
+            }
+            self.finish_message();
+        }
+        if let Some((res, tag_is_valid)) = self.result.take() {
+            self.buf.take().map(|buf| {
+                self.client
+                    .get()
+                    .map(move |client| client.crypt_done(buf, res, tag_is_valid));
+            });
+        }
+    }
+}
+
+impl<'a> AES128CCM<'a> for Ccm<'a> {
+    fn set_client(&'a self, client: &'a CCMClient) {
+        self.client.set(Some(client));
+    }
+
+    fn set_key(&self, key: &[u8]) -> ReturnCode {
+        if key.len() != AES128_KEY_SIZE {
+            return ReturnCode::EINVAL;
+        }
+        let mut new_key = [0; AES128_KEY_SIZE];
+        new_key.copy_from_slice(key);
+        self.key.set(new_key);
+        ReturnCode::SUCCESS
+    }
+
+    fn set_nonce(&self, nonce: &[u8]) -> ReturnCode {
+        if nonce.len() != CCM_NONCE_LENGTH {
+            return ReturnCode::EINVAL;
+        }
+        let mut new_nonce = [0; CCM_NONCE_LENGTH];
+        new_nonce.copy_from_slice(nonce);
+        self.nonce.set(new_nonce);
+        ReturnCode::SUCCESS
+    }
+
+    fn crypt(
+        &self,
+        buf: &'static mut [u8],
+        a_off: usize,
+        m_off: usize,
+        m_len: usize,
+        mic_len: usize,
+        confidential: bool,
+        encrypting: bool,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if self.message.get().is_some() || self.result.get().is_some() || self.radio_owned.get()
+        {
+            return (ReturnCode::EBUSY, Some(buf));
+        }
+        if a_off > m_off || m_off + m_len + mic_len > buf.len() {
+            return (ReturnCode::EINVAL, Some(buf));
+        }
+        if m_off - a_off != 1 || buf[a_off] & IGNORED_HEADER_BITS != 0 || m_len == 0
+            || mic_len != MIC_LENGTH || !confidential
+        {
+            return (ReturnCode::ENOSUPPORT, Some(buf));
+        }
+        if m_len > MAX_MESSAGE_LENGTH {
+            return (ReturnCode::ESIZE, Some(buf));
+        }
+
+        let in_len = if encrypting { m_len } else { m_len + MIC_LENGTH };
+        unsafe {
+            MESSAGE_IN[0] = buf[a_off];
+            MESSAGE_IN[1] = in_len as u8;
+            MESSAGE_IN[2] = 0;
+            MESSAGE_IN[MESSAGE_OFFSET..MESSAGE_OFFSET + in_len]
+                .copy_from_slice(&buf[m_off..m_off + in_len]);
+        }
+        self.message.set(Some(Message {
+            m_off: m_off,
+            m_len: m_len,
+            encrypting: encrypting,
+        }));
+        self.buf.replace(buf);
+        self.start_message(encrypting);
+        (ReturnCode::SUCCESS, None)
+    }
+}
@@ -1,11 +1,11 @@
-//! Clock peripheral driver, nRF52
+//! Clock peripheral driver, nRF5X-family
 //!
 //! Based on Phil Levis clock driver for nRF51
 //!
 //! HFCLK - High Frequency Clock:
 //!
-//!     * 64 MHz internal oscillator (HFINT)
-//!     * 64 MHz crystal oscillator, using 32 MHz external crystal (HFXO)
+//!     * 16 MHz (nRF51) or 64 MHz (nRF52) internal oscillator (HFINT)
+//!     * Crystal oscillator (HFXO), 16 or 32 MHz on the nRF51, 32 MHz on the nRF52
 //!     * The HFXO must be running to use the RADIO, NFC module or the calibration mechanism
 //!       associated with the 32.768 kHz RC oscillator.
 //!
@@ -15,6 +15,31 @@
 //!     * 32.768 kHz crystal oscillator (LFXO)
 //!     * 32.768 kHz synthesized from HFCLK (LFSYNT)
 //!
+//! The board declares the oscillators it has once, with `configure`. From
+//! then on peripherals that need a clock declare it with `request` and
+//! withdraw with `release`, and never start or stop the oscillators
+//! themselves. The clock driver counts consumers per domain, only starts a
+//! clock for its first consumer and only stops it after its last one:
+//!
+//!     * The RTC requests the low frequency clock while it counts.
+//!     * The radio requests the high frequency clock while it is active, as
+//!       the HFINT is not accurate enough for the carrier. On the nRF51 the
+//!       UART does too, for its baud rate.
+//!
+//! The HFXO is only started if the board declared a crystal: without one,
+//! requests are counted but the HFCLK stays on the HFINT. A synthesized low
+//! frequency clock holds a request on the high frequency clock while it
+//! runs, so the radio and the low power timers can share the HFXO.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! nrf5x::clock::CLOCK.configure(
+//!     nrf5x::clock::LowClockSource::XTAL,
+//!     nrf5x::clock::HighClockSource::XTAL,
+//! );
+//! ```
 
 use core::cell::Cell;
 use kernel::common::regs::{ReadOnly, ReadWrite, WriteOnly};
@@ -28,8 +53,8 @@ struct ClockRegisters {
     pub tasks_ctstart: WriteOnly<u32, Control::Register>,    // 0x014
     pub tasks_ctstop: WriteOnly<u32, Control::Register>,     // 0x018
     _reserved1: [u32; 57],                                   // 0x018 - 0x100
-    pub events_hfclkstarted: ReadOnly<u32, Status::Register>, // 0x100
-    pub events_lfclkstarted: ReadOnly<u32, Status::Register>, // 0x104
+    pub events_hfclkstarted: ReadWrite<u32, Status::Register>, // 0x100
+    pub events_lfclkstarted: ReadWrite<u32, Status::Register>, // 0x104
     _reserverd2: u32,                                        // 0x108
     pub events_done: ReadOnly<u32, Status::Register>,        // 0x10c
     pub events_ctto: ReadOnly<u32, Status::Register>,        // 0x110
@@ -47,8 +72,10 @@ struct ClockRegisters {
     pub lfclksrc: ReadWrite<u32, LfClkSrc::Register>,        // 0x518
     _reserved7: [u32; 7],                                    // 0x51c - 0x538
     pub ctiv: ReadWrite<u32, Ctiv::Register>,                // 0x538
-    _reserved8: [u32; 8],                                    // 0x53c - 0x55c
-    pub traceconfig: ReadWrite<u32, TraceConfig::Register>,  // 0x55c
+    _reserved8: [u32; 5],                                    // 0x53c - 0x550
+    pub xtalfreq: ReadWrite<u32, XtalFrequency::Register>,        // 0x550, nRF51 only
+    _reserved9: [u32; 2],                                    // 0x554 - 0x55c
+    pub traceconfig: ReadWrite<u32, TraceConfig::Register>,  // 0x55c, nRF52 only
 }
 
 register_bitfields! [u32,
@@ -100,6 +127,12 @@ register_bitfields! [u32,
     Ctiv [
         CTIV OFFSET(0) NUMBITS(7) []
     ],
+    XtalFrequency [
+        XTALFREQ OFFSET(0) NUMBITS(8) [
+            SIXTEENMHZ = 0xFF,
+            THIRTYTWOMHZ = 0
+        ]
+    ],
     TraceConfig [
         TracePortSpeed OFFSET(0) NUMBITS(2) [
             THIRTYTWO = 0,
@@ -134,15 +167,36 @@ pub enum LowClockSource {
 }
 
 /// High frequency clock source
+#[derive(Copy, Clone, PartialEq)]
 pub enum HighClockSource {
     RC = 0,
     XTAL = 1,
 }
 
+/// Frequency of the nRF51 high frequency crystal
+#[cfg(feature = "nrf51")]
+pub enum XtalFreq {
+    F16MHz = 0xFF,
+    F32MHz = 0,
+}
+
+/// Clock domains a peripheral can depend on.
+#[derive(Copy, Clone, PartialEq)]
+pub enum ClockDomain {
+    /// 32.768kHz clock, used by the RTC
+    Low,
+    /// High frequency clock, run from the HFXO for the radio
+    High,
+}
+
 /// Clock struct
 pub struct Clock {
     registers: *const ClockRegisters,
     client: Cell<Option<&'static ClockClient>>,
+    /// Source the board provides for `ClockDomain::High`
+    high_configured_source: Cell<HighClockSource>,
+    low_consumers: Cell<usize>,
+    high_consumers: Cell<usize>,
 }
 
 pub trait ClockClient {
@@ -161,9 +215,111 @@ impl Clock {
         Clock {
             registers: CLOCK_BASE as *const ClockRegisters,
             client: Cell::new(None),
+            high_configured_source: Cell::new(HighClockSource::RC),
+            low_consumers: Cell::new(0),
+            high_consumers: Cell::new(0),
+        }
+    }
+
+    /// Declare a consumer of `domain`. The clock is started, and this call
+    /// blocks until it is running, if this is the first consumer.
+    pub fn request(&self, domain: ClockDomain) {
+        let regs = unsafe { &*self.registers };
+        match domain {
+            ClockDomain::Low => {
+                if self.low_consumers.get() == 0 {
+                    if self.low_synthesized() {
+                        self.request(ClockDomain::High);
+                    }
+                    regs.events_lfclkstarted.set(0);
+                    self.low_start();
+                    while !self.low_started() {}
+                }
+                self.low_consumers.set(self.low_consumers.get() + 1);
+            }
+            ClockDomain::High => {
+                // The HFINT runs whenever the CPU does, only the HFXO has to
+                // be started
+                if self.high_consumers.get() == 0 && self.high_crystal() {
+                    regs.events_hfclkstarted.set(0);
+                    self.high_start();
+                    while !self.high_started() {}
+                }
+                self.high_consumers.set(self.high_consumers.get() + 1);
+            }
+        }
+    }
+
+    /// Withdraw a consumer of `domain` previously declared with `request`.
+    /// The clock is stopped once it has no consumers left.
+    pub fn release(&self, domain: ClockDomain) {
+        match domain {
+            ClockDomain::Low => match self.low_consumers.get() {
+                0 => {}
+                1 => {
+                    self.low_consumers.set(0);
+                    self.low_stop();
+                    if self.low_synthesized() {
+                        self.release(ClockDomain::High);
+                    }
+                }
+                n => self.low_consumers.set(n - 1),
+            },
+            ClockDomain::High => match self.high_consumers.get() {
+                0 => {}
+                1 => {
+                    self.high_consumers.set(0);
+                    if self.high_crystal() {
+                        self.high_stop();
+                    }
+                }
+                n => self.high_consumers.set(n - 1),
+            },
+        }
+    }
+
+    /// Declare the oscillators of the board, with both clocks stopped. Call
+    /// this once at boot, before any peripheral requests a clock.
+    pub fn configure(&self, low: LowClockSource, high: HighClockSource) {
+        self.low_stop();
+        self.high_stop();
+        self.low_set_source(low);
+        self.high_set_source(high);
+    }
+
+    /// Stop the high frequency crystal if it runs without a consumer, such
+    /// as after a peripheral started it directly. Called before the chip
+    /// sleeps so that only the RTC keeps a clock running while the alarms
+    /// are the only thing armed.
+    pub fn idle(&self) {
+        if self.high_consumers.get() == 0
+            && self.high_running()
+            && self.high_source() == HighClockSource::XTAL
+        {
+            self.high_stop();
+        }
+    }
+
+    /// Number of consumers currently declared for `domain`.
+    pub fn consumers(&self, domain: ClockDomain) -> usize {
+        match domain {
+            ClockDomain::Low => self.low_consumers.get(),
+            ClockDomain::High => self.high_consumers.get(),
         }
     }
 
+    /// Whether the board has a crystal for the high frequency clock.
+    fn high_crystal(&self) -> bool {
+        self.high_configured_source.get() == HighClockSource::XTAL
+    }
+
+    /// Whether the configured low frequency source is synthesized from the
+    /// high frequency clock.
+    fn low_synthesized(&self) -> bool {
+        let regs = unsafe { &*self.registers };
+        regs.lfclksrc.get() & (LowClockSource::MASK as u32) == LowClockSource::SYNTH as u32
+    }
+
     /// Client for callbacks
     pub fn set_client(&self, client: &'static ClockClient) {
         self.client.set(Some(client));
@@ -266,10 +422,28 @@ impl Clock {
         regs.lfclksrc.write(LfClkSrc::SRC.val(clock_source as u32));
     }
 
-    /// Set high frequency clock source
-    pub fn high_set_source(&self, clock_source: HighClockSource) {
+    /// Frequency of the high frequency crystal
+    #[cfg(feature = "nrf51")]
+    pub fn high_freq(&self) -> XtalFreq {
         let regs = unsafe { &*self.registers };
-        regs.hfclkstat
-            .write(HfClkStat::SRC.val(clock_source as u32));
+        match regs.xtalfreq.read(XtalFrequency::XTALFREQ) {
+            0xFF => XtalFreq::F16MHz,
+            _ => XtalFreq::F32MHz,
+        }
+    }
+
+    /// Set the frequency of the high frequency crystal, while it is stopped
+    #[cfg(feature = "nrf51")]
+    pub fn high_set_freq(&self, freq: XtalFreq) {
+        let regs = unsafe { &*self.registers };
+        regs.xtalfreq.write(XtalFrequency::XTALFREQ.val(freq as u32));
+    }
+
+    /// Declare the high frequency clock source the board provides. With
+    /// `XTAL`, requests for `ClockDomain::High` start the HFXO, with `RC`
+    /// there is no crystal and the HFCLK stays on the HFINT. Set this before
+    /// any peripheral requests the clock.
+    pub fn high_set_source(&self, clock_source: HighClockSource) {
+        self.high_configured_source.set(clock_source);
     }
 }
@@ -96,12 +96,28 @@ pub enum RadioMode {
     Nrf2Mbit = 1,
     Nrt250Kbit = 2,
     Ble1Mbit = 3,
+    #[cfg(feature = "nrf52")]
+    Ble2Mbit = 4,
+    #[cfg(feature = "nrf52840")]
+    BleLr125Kbit = 5,
+    #[cfg(feature = "nrf52840")]
+    BleLr500Kbit = 6,
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum TxPower {
+    #[cfg(feature = "nrf52840")]
+    Positive8dBm = 0x08,
+    #[cfg(feature = "nrf52840")]
+    Positive7dBm = 0x07,
+    #[cfg(feature = "nrf52840")]
+    Positive6dBm = 0x06,
+    #[cfg(feature = "nrf52840")]
+    Positive5dBm = 0x05,
     Positive4dBM = 0x04,
     Positive3dBM = 0x03,
+    #[cfg(feature = "nrf52840")]
+    Positive2dBm = 0x02,
     ZerodBm = 0x00,
     Negative4dBm = 0xFC,
     Negative8dBm = 0xF8,
@@ -111,22 +127,75 @@ pub enum TxPower {
     Negative40dBm = 0xD8,
 }
 
-//FIXME: use enum-tryfrom-derive, https://docs.rs/crate/enum-tryfrom-derive/0.1.2
+/// Supported TX power levels, highest first
+#[cfg(not(feature = "nrf52840"))]
+const TX_POWER_LEVELS: [TxPower; 9] = [
+    TxPower::Positive4dBM,
+    TxPower::Positive3dBM,
+    TxPower::ZerodBm,
+    TxPower::Negative4dBm,
+    TxPower::Negative8dBm,
+    TxPower::Negative12dBm,
+    TxPower::Negative16dBm,
+    TxPower::Negative20dBm,
+    TxPower::Negative40dBm,
+];
+
+/// Supported TX power levels, highest first
+#[cfg(feature = "nrf52840")]
+const TX_POWER_LEVELS: [TxPower; 14] = [
+    TxPower::Positive8dBm,
+    TxPower::Positive7dBm,
+    TxPower::Positive6dBm,
+    TxPower::Positive5dBm,
+    TxPower::Positive4dBM,
+    TxPower::Positive3dBM,
+    TxPower::Positive2dBm,
+    TxPower::ZerodBm,
+    TxPower::Negative4dBm,
+    TxPower::Negative8dBm,
+    TxPower::Negative12dBm,
+    TxPower::Negative16dBm,
+    TxPower::Negative20dBm,
+    TxPower::Negative40dBm,
+];
+
+impl TxPower {
+    /// Output power in dBm
+    pub fn dbm(self) -> i8 {
+        self as u8 as i8
+    }
+
+    /// Highest supported level
+    pub fn max() -> TxPower {
+        TX_POWER_LEVELS[0]
+    }
+
+    /// Next supported level below this one
+    pub fn step_down(self) -> Option<TxPower> {
+        let idx = TX_POWER_LEVELS.iter().position(|&level| level == self)?;
+        TX_POWER_LEVELS.get(idx + 1).cloned()
+    }
+
+    /// Next supported level above this one
+    pub fn step_up(self) -> Option<TxPower> {
+        let idx = TX_POWER_LEVELS.iter().position(|&level| level == self)?;
+        if idx == 0 {
+            None
+        } else {
+            Some(TX_POWER_LEVELS[idx - 1])
+        }
+    }
+}
+
 impl TryFrom<u8> for TxPower {
     type Error = ();
 
     fn try_from(val: u8) -> Result<TxPower, ()> {
-        match val {
-            4 => Ok(TxPower::Positive4dBM),
-            3 => Ok(TxPower::Positive3dBM),
-            0 => Ok(TxPower::ZerodBm),
-            0xFC => Ok(TxPower::Negative4dBm),
-            0xF8 => Ok(TxPower::Negative8dBm),
-            0xF4 => Ok(TxPower::Negative12dBm),
-            0xF0 => Ok(TxPower::Negative16dBm),
-            0xEC => Ok(TxPower::Negative20dBm),
-            0xD8 => Ok(TxPower::Negative40dBm),
-            _ => Err(()),
-        }
+        TX_POWER_LEVELS
+            .iter()
+            .find(|&&level| level as u8 == val)
+            .cloned()
+            .ok_or(())
     }
 }
@@ -1,26 +1,59 @@
 //! GPIO and GPIOTE (task and events), nRF5x-family
 //!
+//! Pin interrupts are delivered through a GPIOTE channel when one is free.
+//! Once the channels run out, further pins fall back to the GPIOTE PORT
+//! event: each such pin senses for the opposite of its last known level, and
+//! on a PORT event the pins are scanned against those latched levels to find
+//! which ones changed and in which direction. This lets any of the 32 pins
+//! take an interrupt, at the cost of missing pulses shorter than the
+//! interrupt latency.
+//!
+//! Pins marked as wake sources with `hil::gpio::Wakeup` always take the PORT
+//! event. A GPIOTE channel in event mode keeps the high frequency clock
+//! running while the CPU sleeps, sensing pins only need the DETECT signal,
+//! which also wakes the chip from System OFF. Before System OFF,
+//! `Port::arm_wakeup` makes every wake source sense for a change from its
+//! current level.
+//!
+//! The driver is shared by the nRF51 and the nRF52 crates. Pins are numbered
+//! across ports: `PORT[0]` to `PORT[31]` are P0.00 to P0.31 and, on the
+//! nRF52840, `PORT[32]` to `PORT[47]` are P1.00 to P1.15.
+//!
 //! ### Author
 //! * Philip Levis <pal@cs.stanford.edu>
 //! * Date: August 18, 2016
 
 use core::{cell::Cell,
            ops::{Index, IndexMut}};
-use kernel::{common::regs::ReadWrite, hil};
+use kernel::{common::regs::ReadWrite, hil, ReturnCode};
 
+/// Number of GPIOTE channels available for pin interrupts
 #[cfg(feature = "nrf51")]
-const NUM_GPIOTE: usize = 4;
+pub const NUM_GPIOTE: usize = 4;
+/// Number of GPIOTE channels available for pin interrupts
 #[cfg(feature = "nrf52")]
-const NUM_GPIOTE: usize = 8;
+pub const NUM_GPIOTE: usize = 8;
+
+/// Number of pins across all GPIO ports
+#[cfg(not(feature = "nrf52840"))]
+pub const NUM_PINS: usize = 32;
+/// Number of pins across all GPIO ports
+#[cfg(feature = "nrf52840")]
+pub const NUM_PINS: usize = 48;
+
+/// Number of GPIO ports, each with up to 32 pins
+const NUM_PORTS: usize = (NUM_PINS + 31) / 32;
 
 const GPIOTE_BASE: usize = 0x40006000;
 const GPIO_BASE: usize = 0x50000000;
+/// Distance between the register blocks of two GPIO ports
+const GPIO_PORT_SIZE: usize = 0x300;
 
 /// The nRF5x doesn't automatically provide GPIO interrupts. Instead, to receive
 /// interrupts from a GPIO line, you must allocate a GPIOTE (GPIO Task and
 /// Event) channel, and bind the channel to the desired pin. There are 4
-/// channels for the nrf51 and 8 channels for the nrf52. This means that
-/// requesting an interrupt can fail, if they are all already allocated.
+/// channels for the nrf51 and 8 channels for the nrf52. Pins requesting an
+/// interrupt once they are all allocated share the PORT event instead.
 #[repr(C)]
 struct GpioteRegisters {
     /// Task for writing to pin specified in CONFIG[n].PSEL.
@@ -290,8 +323,10 @@ register_bitfields! [u32,
             Task = 3
         ],
         /// GPIO number associated with SET[n], CLR[n] and OUT[n] tasks
-        /// and IN[n] event
-        PSEL OFFSET(8) NUMBITS(5) [],
+        /// and IN[n] event. The nRF52840 selects the port with the bit
+        /// above the pin number, which other chips leave reserved, so the
+        /// field holds the pin numbered across ports.
+        PSEL OFFSET(8) NUMBITS(6) [],
         /// When In task mode: Operation to be performed on output
         /// when OUT[n] task is triggered. When In event mode: Operation
         /// on input that shall trigger IN[n] event
@@ -320,9 +355,102 @@ register_bitfields! [u32,
     ]
 ];
 
+/// Pull resistor of a pin, `PIN_CNF[n].PULL`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Pull {
+    None,
+    Down,
+    Up,
+}
+
+/// Output drive of a pin, `PIN_CNF[n].DRIVE`. The first letter is the drive
+/// for '0' and the second for '1': standard (S), high (H) or disconnected (D).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Drive {
+    S0S1,
+    H0S1,
+    S0H1,
+    H0H1,
+    D0S1,
+    D0H1,
+    S0D1,
+    H0D1,
+}
+
+/// Level a pin senses for to raise the DETECT signal, `PIN_CNF[n].SENSE`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Sense {
+    Disabled,
+    High,
+    Low,
+}
+
+/// Complete configuration of a pin, see `GPIOPin::configure`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PinConfiguration {
+    /// Drive the pin from `OUT` rather than leaving it an input
+    pub output: bool,
+    /// Connect the input buffer, needed to read the pin and to sense it
+    pub input: bool,
+    pub pull: Pull,
+    pub drive: Drive,
+    pub sense: Sense,
+}
+
+impl PinConfiguration {
+    /// The configuration out of reset: a disconnected input
+    pub const fn new() -> PinConfiguration {
+        PinConfiguration {
+            output: false,
+            input: false,
+            pull: Pull::None,
+            drive: Drive::S0S1,
+            sense: Sense::Disabled,
+        }
+    }
+
+    /// A connected input with the given pull resistor
+    pub const fn input(pull: Pull) -> PinConfiguration {
+        PinConfiguration {
+            output: false,
+            input: true,
+            pull: pull,
+            drive: Drive::S0S1,
+            sense: Sense::Disabled,
+        }
+    }
+
+    /// An output with the given drive. The input buffer stays connected so
+    /// that the pin can be read back.
+    pub const fn output(drive: Drive) -> PinConfiguration {
+        PinConfiguration {
+            output: true,
+            input: true,
+            pull: Pull::None,
+            drive: drive,
+            sense: Sense::Disabled,
+        }
+    }
+}
+
+/// Edges a pin using the PORT event reports to its client
+#[derive(Copy, Clone, PartialEq)]
+enum PortEdge {
+    Rising,
+    Falling,
+    Either,
+}
+
 pub struct GPIOPin {
+    /// Pin number across ports
     pin: u8,
     client_data: Cell<usize>,
+    /// Set while the pin takes its interrupts from the PORT event
+    port_edge: Cell<Option<PortEdge>>,
+    /// Level of the pin when the PORT event last scanned it
+    port_level: Cell<bool>,
+    /// Marked as a wake source, see `hil::gpio::Wakeup`
+    wake_source: Cell<bool>,
     client: Cell<Option<&'static hil::gpio::Client>>,
     gpiote_register: *const GpioteRegisters,
     gpio_register: *const GpioRegisters,
@@ -333,8 +461,12 @@ impl GPIOPin {
         GPIOPin {
             pin: pin,
             client_data: Cell::new(0),
+            port_edge: Cell::new(None),
+            port_level: Cell::new(false),
+            wake_source: Cell::new(false),
             client: Cell::new(None),
-            gpio_register: GPIO_BASE as *const GpioRegisters,
+            gpio_register: (GPIO_BASE + (pin as usize / 32) * GPIO_PORT_SIZE)
+                as *const GpioRegisters,
             gpiote_register: GPIOTE_BASE as *const GpioteRegisters,
         }
     }
@@ -342,9 +474,87 @@ impl GPIOPin {
     pub fn set_client<C: hil::gpio::Client>(&self, client: &'static C) {
         self.client.set(Some(client));
     }
+
+    /// Number of the pin within its port
+    fn port_pin(&self) -> usize {
+        self.pin as usize % 32
+    }
+
+    /// Write the whole configuration of the pin at once. If the pin is taking
+    /// its interrupts from the PORT event its SENSE setting is kept, as the
+    /// interrupt depends on it.
+    pub fn configure(&self, config: PinConfiguration) {
+        let dir = if config.output {
+            PinConfig::DIR::Output
+        } else {
+            PinConfig::DIR::Input
+        };
+        let input = if config.input {
+            PinConfig::INPUT::Connect
+        } else {
+            PinConfig::INPUT::Disconnect
+        };
+        let pull = match config.pull {
+            Pull::None => PinConfig::PULL::Disabled,
+            Pull::Down => PinConfig::PULL::Pulldown,
+            Pull::Up => PinConfig::PULL::Pullup,
+        };
+        let drive = match config.drive {
+            Drive::S0S1 => PinConfig::DRIVE::S0S1,
+            Drive::H0S1 => PinConfig::DRIVE::H0S1,
+            Drive::S0H1 => PinConfig::DRIVE::S0H1,
+            Drive::H0H1 => PinConfig::DRIVE::H0H1,
+            Drive::D0S1 => PinConfig::DRIVE::D0S1,
+            Drive::D0H1 => PinConfig::DRIVE::D0H1,
+            Drive::S0D1 => PinConfig::DRIVE::S0D1,
+            Drive::H0D1 => PinConfig::DRIVE::H0D1,
+        };
+        let regs = unsafe { &*self.gpio_register };
+        let sense = if self.port_edge.get().is_some() {
+            PinConfig::SENSE.val(regs.pin_cnf[self.port_pin()].read(PinConfig::SENSE))
+        } else {
+            match config.sense {
+                Sense::Disabled => PinConfig::SENSE::Disabled,
+                Sense::High => PinConfig::SENSE::High,
+                Sense::Low => PinConfig::SENSE::Low,
+            }
+        };
+        regs.pin_cnf[self.port_pin()].write(dir + input + pull + drive + sense);
+    }
+
+    /// Read back the configuration of the pin
+    pub fn configuration(&self) -> PinConfiguration {
+        let regs = unsafe { &*self.gpio_register };
+        let cnf = &regs.pin_cnf[self.port_pin()];
+        PinConfiguration {
+            output: cnf.matches_all(PinConfig::DIR::Output),
+            input: cnf.matches_all(PinConfig::INPUT::Connect),
+            pull: match cnf.read(PinConfig::PULL) {
+                1 => Pull::Down,
+                3 => Pull::Up,
+                _ => Pull::None,
+            },
+            drive: match cnf.read(PinConfig::DRIVE) {
+                1 => Drive::H0S1,
+                2 => Drive::S0H1,
+                3 => Drive::H0H1,
+                4 => Drive::D0S1,
+                5 => Drive::D0H1,
+                6 => Drive::S0D1,
+                7 => Drive::H0D1,
+                _ => Drive::S0S1,
+            },
+            sense: match cnf.read(PinConfig::SENSE) {
+                2 => Sense::High,
+                3 => Sense::Low,
+                _ => Sense::Disabled,
+            },
+        }
+    }
 }
 
 impl hil::gpio::PinCtl for GPIOPin {
+    /// The direction, drive and sense of the pin are kept
     fn set_input_mode(&self, mode: hil::gpio::InputMode) {
         let pin_config = match mode {
             hil::gpio::InputMode::PullUp => PinConfig::PULL::Pullup,
@@ -352,72 +562,255 @@ impl hil::gpio::PinCtl for GPIOPin {
             hil::gpio::InputMode::PullNone => PinConfig::PULL::Disabled,
         };
         let gpio_regs = unsafe { &*self.gpio_register };
-        gpio_regs.pin_cnf[self.pin as usize].write(pin_config);
+        gpio_regs.pin_cnf[self.port_pin()].modify(pin_config);
+    }
+
+    /// Every drive mode is supported, the direction, pull and sense of the
+    /// pin are kept
+    fn set_drive_mode(&self, mode: hil::gpio::DriveMode) -> ReturnCode {
+        let drive = match mode {
+            hil::gpio::DriveMode::PushPull => PinConfig::DRIVE::S0S1,
+            hil::gpio::DriveMode::HighDrive => PinConfig::DRIVE::H0H1,
+            hil::gpio::DriveMode::OpenDrain => PinConfig::DRIVE::S0D1,
+            hil::gpio::DriveMode::OpenSource => PinConfig::DRIVE::D0S1,
+            hil::gpio::DriveMode::OpenDrainHighDrive => PinConfig::DRIVE::H0D1,
+            hil::gpio::DriveMode::OpenSourceHighDrive => PinConfig::DRIVE::D0H1,
+        };
+        let gpio_regs = unsafe { &*self.gpio_register };
+        gpio_regs.pin_cnf[self.port_pin()].modify(drive);
+        ReturnCode::SUCCESS
+    }
+}
+
+impl hil::gpio::Configure for GPIOPin {
+    fn configure_input(&self, mode: hil::gpio::InputMode) {
+        let pull = match mode {
+            hil::gpio::InputMode::PullUp => Pull::Up,
+            hil::gpio::InputMode::PullDown => Pull::Down,
+            hil::gpio::InputMode::PullNone => Pull::None,
+        };
+        self.configure(PinConfiguration::input(pull));
+    }
+
+    fn configure_output(&self, drive: hil::gpio::DriveMode) {
+        let drive = match drive {
+            hil::gpio::DriveMode::PushPull => Drive::S0S1,
+            hil::gpio::DriveMode::HighDrive => Drive::H0H1,
+            hil::gpio::DriveMode::OpenDrain => Drive::S0D1,
+            hil::gpio::DriveMode::OpenSource => Drive::D0S1,
+            hil::gpio::DriveMode::OpenDrainHighDrive => Drive::H0D1,
+            hil::gpio::DriveMode::OpenSourceHighDrive => Drive::D0H1,
+        };
+        self.configure(PinConfiguration::output(drive));
+    }
+
+    fn disconnect(&self) {
+        self.configure(PinConfiguration::new());
+    }
+
+    fn input_mode(&self) -> hil::gpio::InputMode {
+        match self.configuration().pull {
+            Pull::Up => hil::gpio::InputMode::PullUp,
+            Pull::Down => hil::gpio::InputMode::PullDown,
+            Pull::None => hil::gpio::InputMode::PullNone,
+        }
+    }
+
+    fn drive_mode(&self) -> hil::gpio::DriveMode {
+        // High drive on only one side has no generic equivalent, report the
+        // closest mode
+        match self.configuration().drive {
+            Drive::S0S1 => hil::gpio::DriveMode::PushPull,
+            Drive::H0S1 | Drive::S0H1 | Drive::H0H1 => hil::gpio::DriveMode::HighDrive,
+            Drive::D0S1 => hil::gpio::DriveMode::OpenSource,
+            Drive::S0D1 => hil::gpio::DriveMode::OpenDrain,
+            Drive::D0H1 => hil::gpio::DriveMode::OpenSourceHighDrive,
+            Drive::H0D1 => hil::gpio::DriveMode::OpenDrainHighDrive,
+        }
+    }
+
+    fn is_output(&self) -> bool {
+        self.configuration().output
     }
 }
 
 impl hil::gpio::Pin for GPIOPin {
     fn make_output(&self) {
-        unsafe { (&*self.gpio_register).dirset.set(1 << self.pin) };
+        unsafe { (&*self.gpio_register).dirset.set(1 << self.port_pin()) };
     }
 
     // Configuration constants stolen from
     // mynewt/hw/mcu/nordic/nrf51xxx/include/mcu/nrf51_bitfields.h
     fn make_input(&self) {
-        unsafe { (&*self.gpio_register).dirclr.set(1 << self.pin) };
+        unsafe { (&*self.gpio_register).dirclr.set(1 << self.port_pin()) };
     }
 
     // Not clk
+    /// The pin stops driving and pulling. Its drive mode is kept for when it
+    /// is made an output again, and its sense so that a pin using the PORT
+    /// event goes on sensing.
     fn disable(&self) {
-        hil::gpio::PinCtl::set_input_mode(self, hil::gpio::InputMode::PullNone);
+        let gpio_regs = unsafe { &*self.gpio_register };
+        gpio_regs.pin_cnf[self.port_pin()]
+            .modify(PinConfig::DIR::Input + PinConfig::PULL::Disabled);
     }
 
     fn set(&self) {
-        unsafe { (&*self.gpio_register).outset.set(1 << self.pin) };
+        unsafe { (&*self.gpio_register).outset.set(1 << self.port_pin()) };
     }
 
     fn clear(&self) {
-        unsafe { (&*self.gpio_register).outclr.set(1 << self.pin) };
+        unsafe { (&*self.gpio_register).outclr.set(1 << self.port_pin()) };
     }
 
     fn toggle(&self) {
         let gpio_regs = unsafe { &*self.gpio_register };
-        gpio_regs.out.set((1 << self.pin) ^ gpio_regs.out.get());
+        gpio_regs.out.set((1 << self.port_pin()) ^ gpio_regs.out.get());
     }
 
     fn read(&self) -> bool {
         let gpio_regs = unsafe { &*self.gpio_register };
-        gpio_regs.in_.get() & (1 << self.pin) != 0
+        gpio_regs.in_.get() & (1 << self.port_pin()) != 0
     }
 
     fn enable_interrupt(&self, client_data: usize, mode: hil::gpio::InterruptMode) {
-        if let Ok(channel) = self.allocate_channel() {
-            self.client_data.set(client_data);
-            let polarity = match mode {
-                hil::gpio::InterruptMode::EitherEdge => Config::POLARITY::Toggle,
-                hil::gpio::InterruptMode::RisingEdge => Config::POLARITY::LoToHi,
-                hil::gpio::InterruptMode::FallingEdge => Config::POLARITY::HiToLo,
-            };
-            let regs = unsafe { &*self.gpiote_register };
-            regs.config[channel]
-                .write(Config::MODE::Event + Config::PSEL.val(self.pin as u32) + polarity);
-            regs.intenset.set(1 << channel);
+        // Reuse the channel if this pin already owns one, so that changing the
+        // interrupt mode doesn't leak a channel.
+        let channel = if self.wake_source.get() {
+            Err(())
+        } else {
+            self.find_channel(self.pin)
+                .or_else(|_| self.allocate_channel())
+        };
+        if let Ok(channel) = channel {
+            self.disable_port_event();
+            self.bind_channel(channel, client_data, mode);
         } else {
-            debug!("No available GPIOTE interrupt channels");
+            if let Ok(channel) = self.find_channel(self.pin) {
+                release_channel(unsafe { &*self.gpiote_register }, channel);
+            }
+            self.enable_port_event(client_data, mode);
         }
     }
 
     fn disable_interrupt(&self) {
         if let Ok(channel) = self.find_channel(self.pin) {
-            let regs = unsafe { &*self.gpiote_register };
-            regs.config[channel]
-                .write(Config::MODE::CLEAR + Config::PSEL::CLEAR + Config::POLARITY::CLEAR);
-            regs.intenclr.set(1 << channel);
+            release_channel(unsafe { &*self.gpiote_register }, channel);
+        }
+        self.disable_port_event();
+    }
+}
+
+impl hil::gpio::Wakeup for GPIOPin {
+    fn set_wake_source(&self, wake: bool) {
+        self.wake_source.set(wake);
+        if wake {
+            // Move an interrupt delivered by a GPIOTE channel to the PORT
+            // event, keeping its edges
+            if let Ok(channel) = self.find_channel(self.pin) {
+                let regs = unsafe { &*self.gpiote_register };
+                let mode = match regs.config[channel].read(Config::POLARITY) {
+                    1 => hil::gpio::InterruptMode::RisingEdge,
+                    2 => hil::gpio::InterruptMode::FallingEdge,
+                    _ => hil::gpio::InterruptMode::EitherEdge,
+                };
+                release_channel(regs, channel);
+                self.enable_port_event(self.client_data.get(), mode);
+            }
+        } else if self.port_edge.get().is_none() {
+            // Stop sensing if `Port::arm_wakeup` armed the pin
+            let regs = unsafe { &*self.gpio_register };
+            regs.pin_cnf[self.port_pin()].modify(PinConfig::SENSE::Disabled);
         }
     }
+
+    fn is_wake_source(&self) -> bool {
+        self.wake_source.get()
+    }
+}
+
+/// Tear down a GPIOTE channel so it can be allocated by another pin.
+fn release_channel(regs: &GpioteRegisters, channel: usize) {
+    regs.intenclr.set(1 << channel);
+    regs.config[channel].write(Config::MODE::CLEAR + Config::PSEL::CLEAR + Config::POLARITY::CLEAR);
+    regs.event_in[channel].write(EventsIn::EVENT::NotReady);
 }
 
 impl GPIOPin {
+    /// Route GPIOTE `channel` to this pin and enable its interrupt
+    fn bind_channel(&self, channel: usize, client_data: usize, mode: hil::gpio::InterruptMode) {
+        self.client_data.set(client_data);
+        let polarity = match mode {
+            hil::gpio::InterruptMode::EitherEdge => Config::POLARITY::Toggle,
+            hil::gpio::InterruptMode::RisingEdge => Config::POLARITY::LoToHi,
+            hil::gpio::InterruptMode::FallingEdge => Config::POLARITY::HiToLo,
+        };
+        let regs = unsafe { &*self.gpiote_register };
+        regs.config[channel]
+            .write(Config::MODE::Event + Config::PSEL.val(self.pin as u32) + polarity);
+        regs.intenset.set(1 << channel);
+    }
+
+    /// Deliver this pin's interrupts through the PORT event
+    fn enable_port_event(&self, client_data: usize, mode: hil::gpio::InterruptMode) {
+        self.client_data.set(client_data);
+        self.port_edge.set(Some(match mode {
+            hil::gpio::InterruptMode::EitherEdge => PortEdge::Either,
+            hil::gpio::InterruptMode::RisingEdge => PortEdge::Rising,
+            hil::gpio::InterruptMode::FallingEdge => PortEdge::Falling,
+        }));
+        let level = hil::gpio::Pin::read(self);
+        self.sense_change_from(level);
+        let regs = unsafe { &*self.gpiote_register };
+        regs.intenset.write(Intenset::PORT::SET);
+    }
+
+    /// Stop sensing this pin. The PORT interrupt itself is left to
+    /// `Port::handle_interrupt` to turn off once no pin needs it.
+    fn disable_port_event(&self) {
+        if self.port_edge.get().is_some() {
+            self.port_edge.set(None);
+            let regs = unsafe { &*self.gpio_register };
+            regs.pin_cnf[self.port_pin()].modify(PinConfig::SENSE::Disabled);
+        }
+    }
+
+    /// Latch `level` and sense for the pin leaving it, so that the DETECT
+    /// signal (and with it the PORT event) is raised on the next change
+    fn sense_change_from(&self, level: bool) {
+        self.port_level.set(level);
+        let sense = if level {
+            PinConfig::SENSE::Low
+        } else {
+            PinConfig::SENSE::High
+        };
+        let regs = unsafe { &*self.gpio_register };
+        regs.pin_cnf[self.port_pin()].modify(sense);
+    }
+
+    /// Compare a pin using the PORT event against its latched level. If it
+    /// changed, sense for the next change and notify the client if the edge
+    /// is one it asked for. Returns whether the pin changed.
+    fn scan_port_event(&self, level: bool) -> bool {
+        let edge = match self.port_edge.get() {
+            Some(edge) => edge,
+            None => return false,
+        };
+        if level == self.port_level.get() {
+            return false;
+        }
+        self.sense_change_from(level);
+        let fire = match edge {
+            PortEdge::Either => true,
+            PortEdge::Rising => level,
+            PortEdge::Falling => !level,
+        };
+        if fire {
+            self.handle_interrupt();
+        }
+        true
+    }
+
     /// Allocate a GPIOTE channel
     /// If the channel couldn't be allocated return error instead
     fn allocate_channel(&self) -> Result<usize, ()> {
@@ -435,13 +828,29 @@ impl GPIOPin {
     fn find_channel(&self, pin: u8) -> Result<usize, ()> {
         let regs = unsafe { &*self.gpiote_register };
         for (i, ch) in regs.config.iter().enumerate() {
-            if ch.matches_all(Config::PSEL.val(pin as u32)) {
+            // A released channel has PSEL cleared to 0, so the mode must be
+            // checked as well or pin 0 would match every free channel
+            if ch.matches_all(Config::MODE::Event + Config::PSEL.val(pin as u32)) {
                 return Ok(i);
             }
         }
         return Err(());
     }
 
+    /// The GPIOTE channel currently delivering interrupts for this pin, if any
+    pub fn gpiote_channel(&self) -> Option<usize> {
+        self.find_channel(self.pin).ok()
+    }
+
+    /// Address of the `EVENTS_IN` register of the GPIOTE channel bound to
+    /// this pin, for use as a PPI event end point.
+    pub fn gpiote_event_address(&self) -> Option<u32> {
+        self.gpiote_channel().map(|channel| {
+            let regs = unsafe { &*self.gpiote_register };
+            &regs.event_in[channel] as *const _ as u32
+        })
+    }
+
     fn handle_interrupt(&self) {
         self.client.get().map(|client| {
             client.fired(self.client_data.get());
@@ -449,8 +858,17 @@ impl GPIOPin {
     }
 }
 
+/// Usage of a single GPIOTE channel, see `Port::gpiote_channel_usage`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct GpioteChannelUsage {
+    /// The pin bound to the channel
+    pub pin: u8,
+    /// The identifier the pin's client passed to `enable_interrupt`
+    pub client_data: usize,
+}
+
 pub struct Port {
-    pins: [GPIOPin; 32],
+    pins: [GPIOPin; NUM_PINS],
 }
 
 impl Index<usize> for Port {
@@ -468,8 +886,96 @@ impl IndexMut<usize> for Port {
 }
 
 impl Port {
+    /// Report which pin (and which client identifier) owns a GPIOTE channel.
+    /// Returns `None` if the channel is free or out of range.
+    pub fn gpiote_channel_usage(&self, channel: usize) -> Option<GpioteChannelUsage> {
+        let regs = unsafe { &*self.pins[0].gpiote_register };
+        regs.config.get(channel).and_then(|config| {
+            if config.matches_all(Config::MODE::Event) {
+                let pin = config.read(Config::PSEL) as u8;
+                Some(GpioteChannelUsage {
+                    pin: pin,
+                    client_data: self.pins[pin as usize].client_data.get(),
+                })
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Number of GPIOTE channels not bound to any pin
+    pub fn gpiote_channels_free(&self) -> usize {
+        (0..NUM_GPIOTE)
+            .filter(|&ch| self.gpiote_channel_usage(ch).is_none())
+            .count()
+    }
+
+    /// Tear down a GPIOTE channel at runtime so that it can be handed to
+    /// another pin with `enable_interrupt`. The pin that owned the channel
+    /// stops receiving interrupts and is returned so the caller can tell its
+    /// driver. Returns an error if the channel was not in use.
+    pub fn release_gpiote_channel(&self, channel: usize) -> Result<u8, ()> {
+        let usage = self.gpiote_channel_usage(channel).ok_or(())?;
+        release_channel(unsafe { &*self.pins[0].gpiote_register }, channel);
+        Ok(usage.pin)
+    }
+
+    /// Move the GPIOTE channel used by `from` over to `to`, keeping the
+    /// channel number. `to` is configured with `client_data` and `mode`
+    /// exactly as if `enable_interrupt` had been called on it.
+    pub fn reassign_gpiote_channel(
+        &self,
+        from: usize,
+        to: usize,
+        client_data: usize,
+        mode: hil::gpio::InterruptMode,
+    ) -> Result<usize, ()> {
+        if from >= self.pins.len() || to >= self.pins.len() {
+            return Err(());
+        }
+        let channel = self.pins[from].gpiote_channel().ok_or(())?;
+        if self.pins[to].gpiote_channel().is_some() {
+            return Err(());
+        }
+        self.release_gpiote_channel(channel)?;
+        // Program the freed channel directly rather than allocating, since a
+        // lower numbered channel may also be free.
+        self.pins[to].bind_channel(channel, client_data, mode);
+        Ok(channel)
+    }
+
+    /// Pins currently taking their interrupts from the PORT event rather than
+    /// a GPIOTE channel, as a bit mask
+    pub fn port_event_pins(&self) -> u64 {
+        self.pins
+            .iter()
+            .filter(|pin| pin.port_edge.get().is_some())
+            .fold(0, |mask, pin| mask | 1 << pin.pin)
+    }
+
+    /// Pins marked as wake sources, as a bit mask
+    pub fn wake_sources(&self) -> u64 {
+        self.pins
+            .iter()
+            .filter(|pin| pin.wake_source.get())
+            .fold(0, |mask, pin| mask | 1 << pin.pin)
+    }
+
+    /// Make every wake source sense for a change from its current level,
+    /// for the DETECT signal to wake the chip from System OFF. Wake sources
+    /// taking interrupts from the PORT event already do. The pins must have
+    /// their input buffer connected.
+    pub fn arm_wakeup(&self) {
+        for pin in self.pins.iter() {
+            if pin.wake_source.get() && pin.port_edge.get().is_none() {
+                pin.sense_change_from(hil::gpio::Pin::read(pin));
+            }
+        }
+    }
+
     /// GPIOTE interrupt: check each GPIOTE channel, if any has
     /// fired then trigger its corresponding pin's interrupt handler.
+    /// Then, if the PORT event fired, scan the pins sharing it.
     pub fn handle_interrupt(&self) {
         // do this just to get a pointer the memory map
         // doesn't matter which pin is used because it is the same
@@ -483,9 +989,75 @@ impl Port {
                 self.pins[pin].handle_interrupt();
             }
         }
+
+        if regs.event_port.matches_any(EventsPort::PINS::Ready) {
+            regs.event_port.write(EventsPort::PINS::NotReady);
+            // A pin may change again while the others are being scanned, and
+            // its SENSE would then match its level already, holding DETECT
+            // high without another PORT event. Scan until nothing changed.
+            // All ports share the one DETECT signal.
+            loop {
+                let mut levels = [0; NUM_PORTS];
+                for (port, level) in levels.iter_mut().enumerate() {
+                    let gpio_regs = unsafe { &*self.pins[port * 32].gpio_register };
+                    *level = gpio_regs.in_.get();
+                }
+                let mut changed = false;
+                for pin in self.pins.iter() {
+                    let level = levels[pin.pin as usize / 32] & (1 << pin.port_pin()) != 0;
+                    changed |= pin.scan_port_event(level);
+                }
+                if !changed {
+                    break;
+                }
+            }
+        }
+
+        if self.port_event_pins() == 0 {
+            regs.intenclr.write(Intenclr::PORT::SET);
+        }
     }
 }
 
+#[cfg(not(feature = "nrf52840"))]
+pub static mut PORT: Port = Port {
+    pins: [
+        GPIOPin::new(0),
+        GPIOPin::new(1),
+        GPIOPin::new(2),
+        GPIOPin::new(3),
+        GPIOPin::new(4),
+        GPIOPin::new(5),
+        GPIOPin::new(6),
+        GPIOPin::new(7),
+        GPIOPin::new(8),
+        GPIOPin::new(9),
+        GPIOPin::new(10),
+        GPIOPin::new(11),
+        GPIOPin::new(12),
+        GPIOPin::new(13),
+        GPIOPin::new(14),
+        GPIOPin::new(15),
+        GPIOPin::new(16),
+        GPIOPin::new(17),
+        GPIOPin::new(18),
+        GPIOPin::new(19),
+        GPIOPin::new(20),
+        GPIOPin::new(21),
+        GPIOPin::new(22),
+        GPIOPin::new(23),
+        GPIOPin::new(24),
+        GPIOPin::new(25),
+        GPIOPin::new(26),
+        GPIOPin::new(27),
+        GPIOPin::new(28),
+        GPIOPin::new(29),
+        GPIOPin::new(30),
+        GPIOPin::new(31),
+    ],
+};
+
+#[cfg(feature = "nrf52840")]
 pub static mut PORT: Port = Port {
     pins: [
         GPIOPin::new(0),
@@ -520,5 +1092,21 @@ pub static mut PORT: Port = Port {
         GPIOPin::new(29),
         GPIOPin::new(30),
         GPIOPin::new(31),
+        GPIOPin::new(32),
+        GPIOPin::new(33),
+        GPIOPin::new(34),
+        GPIOPin::new(35),
+        GPIOPin::new(36),
+        GPIOPin::new(37),
+        GPIOPin::new(38),
+        GPIOPin::new(39),
+        GPIOPin::new(40),
+        GPIOPin::new(41),
+        GPIOPin::new(42),
+        GPIOPin::new(43),
+        GPIOPin::new(44),
+        GPIOPin::new(45),
+        GPIOPin::new(46),
+        GPIOPin::new(47),
     ],
 };
@@ -0,0 +1,176 @@
+//! Signal period and pulse width measurement, nRF5X-family
+//!
+//! A GPIOTE channel on the measured pin generates an event on every edge,
+//! and a PPI channel routes that event to a CAPTURE task of TIMER2. The timer
+//! value latched in hardware at each edge is read from the GPIOTE interrupt,
+//! so the measurement is not affected by interrupt latency as long as edges
+//! are further apart than the time it takes to service the interrupt.
+//!
+//! TIMER2 is owned by this driver while a measurement runs, and a PPI
+//! channel and a CC channel of TIMER2 are allocated for the duration of each
+//! measurement.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let pin = &nrf5x::gpio::PORT[3];
+//! nrf5x::input_capture::INPUT_CAPTURE.set_pin(pin);
+//! pin.set_client(&nrf5x::input_capture::INPUT_CAPTURE);
+//! ```
+
+use core::cell::Cell;
+use gpio::GPIOPin;
+use kernel::hil;
+use kernel::hil::gpio::Pin;
+use kernel::ReturnCode;
+use ppi;
+use timer::{BitmodeValue, Location, Timer};
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Idle,
+    /// Waiting for the first rising edge
+    WaitRising,
+    /// Rising edge seen at the given time, waiting for the falling edge
+    WaitFalling(u32),
+    /// Rising and falling edges seen, waiting for the next rising edge
+    WaitPeriod(u32, u32),
+}
+
+pub struct InputCapture {
+    timer: Timer,
+    pin: Cell<Option<&'static GPIOPin>>,
+    state: Cell<State>,
+    /// PPI channel connecting the GPIOTE event to the timer capture task
+    ppi_channel: Cell<Option<usize>>,
+    /// CC register the edge timestamps are captured into
+    capture_cc: Cell<Option<u8>>,
+    client: Cell<Option<&'static hil::input_capture::Client>>,
+}
+
+pub static mut INPUT_CAPTURE: InputCapture = InputCapture::new();
+
+impl InputCapture {
+    const fn new() -> InputCapture {
+        InputCapture {
+            timer: Timer::new(Location::TIMER2),
+            pin: Cell::new(None),
+            state: Cell::new(State::Idle),
+            ppi_channel: Cell::new(None),
+            capture_cc: Cell::new(None),
+            client: Cell::new(None),
+        }
+    }
+
+    /// Select the pin to measure. The pin's GPIO client must be set to this
+    /// driver.
+    pub fn set_pin(&self, pin: &'static GPIOPin) {
+        self.pin.set(Some(pin));
+    }
+
+    fn finish(&self) {
+        self.state.set(State::Idle);
+        self.ppi_channel.take().map(|channel| unsafe {
+            ppi::PPI.release(channel);
+        });
+        self.capture_cc
+            .take()
+            .map(|cc| self.timer.release_cc(cc as usize));
+        self.pin.get().map(|pin| pin.disable_interrupt());
+        self.timer.stop();
+    }
+}
+
+impl hil::input_capture::InputCapture for InputCapture {
+    type Frequency = hil::time::Freq16MHz;
+
+    fn measure(&self) -> ReturnCode {
+        if self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        let pin = match self.pin.get() {
+            Some(pin) => pin,
+            None => return ReturnCode::EOFF,
+        };
+
+        pin.make_input();
+        pin.enable_interrupt(0, hil::gpio::InterruptMode::EitherEdge);
+        let event = match pin.gpiote_event_address() {
+            Some(event) => event,
+            // No GPIOTE channel was available
+            None => return ReturnCode::ENOMEM,
+        };
+        let cc = match self.timer.allocate_cc() {
+            Ok(cc) => cc,
+            Err(rc) => {
+                pin.disable_interrupt();
+                return rc;
+            }
+        };
+        let channel = match unsafe { ppi::PPI.allocate() } {
+            Ok(channel) => channel,
+            Err(rc) => {
+                self.timer.release_cc(cc as usize);
+                pin.disable_interrupt();
+                return rc;
+            }
+        };
+        self.capture_cc.set(Some(cc));
+        self.ppi_channel.set(Some(channel));
+
+        // Free running 32 bit timer at the full 16MHz
+        self.timer.stop();
+        self.timer.set_bitmode(BitmodeValue::Size32Bits);
+        self.timer.set_prescaler(0);
+        self.timer.clear();
+        self.timer.start();
+
+        unsafe {
+            ppi::PPI.connect(channel, event, self.timer.capture_task_address(cc));
+            ppi::PPI.enable(channel);
+        }
+
+        self.state.set(State::WaitRising);
+        ReturnCode::SUCCESS
+    }
+
+    fn stop(&self) -> ReturnCode {
+        if self.state.get() == State::Idle {
+            return ReturnCode::EALREADY;
+        }
+        self.finish();
+        ReturnCode::SUCCESS
+    }
+
+    fn set_client(&self, client: &'static hil::input_capture::Client) {
+        self.client.set(Some(client));
+    }
+}
+
+impl hil::gpio::Client for InputCapture {
+    fn fired(&self, _: usize) {
+        let level = self.pin.get().map_or(false, |pin| pin.read());
+        let now = self.capture_cc
+            .get()
+            .map_or(0, |cc| self.timer.get_cc(cc));
+
+        match self.state.get() {
+            State::Idle => {}
+            State::WaitRising => {
+                if level {
+                    self.state.set(State::WaitFalling(now));
+                }
+            }
+            State::WaitFalling(rise) => {
+                self.state.set(State::WaitPeriod(rise, now));
+            }
+            State::WaitPeriod(rise, fall) => {
+                self.finish();
+                self.client.get().map(|client| {
+                    client.measurement_done(now.wrapping_sub(rise), fall.wrapping_sub(rise))
+                });
+            }
+        }
+    }
+}
@@ -0,0 +1,82 @@
+//! Statistics on the interrupts serviced by the kernel
+//!
+//! Peripheral interrupt handlers only mark their interrupt pending in the
+//! NVIC; the kernel main loop later drains the pending set, one interrupt at a
+//! time, in `Chip::service_pending_interrupts`. This module keeps count of how
+//! many times each source was serviced and of the most interrupts ever found
+//! pending at once, which show an interrupt storm or a peripheral that is
+//! starving the rest.
+//!
+//! The counters are only updated from `service_pending_interrupts`, which
+//! runs in the kernel thread rather than in an interrupt handler, so they need
+//! no further protection.
+
+use core::fmt::Write;
+
+/// Number of peripheral interrupt sources
+#[cfg(feature = "nrf51")]
+pub const NUM_INTERRUPTS: usize = 32;
+/// Number of peripheral interrupt sources
+#[cfg(feature = "nrf52")]
+pub const NUM_INTERRUPTS: usize = 48;
+
+pub struct InterruptStatistics {
+    counts: [u32; NUM_INTERRUPTS],
+    max_pending: u32,
+}
+
+pub static mut INTERRUPT_STATISTICS: InterruptStatistics = InterruptStatistics::new();
+
+impl InterruptStatistics {
+    const fn new() -> InterruptStatistics {
+        InterruptStatistics {
+            counts: [0; NUM_INTERRUPTS],
+            max_pending: 0,
+        }
+    }
+
+    /// Record how many interrupts are pending as servicing starts
+    pub fn record_pending(&mut self, pending: u32) {
+        if pending > self.max_pending {
+            self.max_pending = pending;
+        }
+    }
+
+    /// Record that `interrupt` is being serviced
+    pub fn record_serviced(&mut self, interrupt: u32) {
+        if let Some(count) = self.counts.get_mut(interrupt as usize) {
+            *count = count.wrapping_add(1);
+        }
+    }
+
+    /// The number of times `interrupt` has been serviced
+    pub fn count(&self, interrupt: u32) -> u32 {
+        self.counts
+            .get(interrupt as usize)
+            .map_or(0, |&count| count)
+    }
+
+    /// The most interrupts that have been pending at once
+    pub fn max_pending(&self) -> u32 {
+        self.max_pending
+    }
+
+    pub fn reset(&mut self) {
+        *self = InterruptStatistics::new();
+    }
+
+    /// Print the statistics, listing only the sources that have fired.
+    ///
+    /// **NOTE:** The supplied `writer` must be synchronous.
+    pub fn statistics_str<W: Write>(&self, writer: &mut W) {
+        let _ = writer.write_fmt(format_args!(
+            "\r\n---| Interrupts |---\r\nMost pending at once: {}\r\n",
+            self.max_pending
+        ));
+        for (interrupt, &count) in self.counts.iter().enumerate() {
+            if count != 0 {
+                let _ = writer.write_fmt(format_args!("  IRQ {:2}: {}\r\n", interrupt, count));
+            }
+        }
+    }
+}
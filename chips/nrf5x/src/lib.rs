@@ -8,11 +8,23 @@ extern crate kernel;
 mod peripheral_registers;
 
 pub mod aes;
+pub mod ccm;
+pub mod clock;
 pub mod constants;
 pub mod gpio;
+pub mod helpers;
+pub mod input_capture;
+pub mod interrupt_statistics;
+pub mod nvmc;
 pub mod peripheral_interrupts;
+pub mod peripheral_snapshot;
 pub mod pinmux;
+pub mod power;
+pub mod ppi;
+pub mod radio_crc;
 pub mod rtc;
 pub mod temperature;
 pub mod timer;
+pub mod timestamp;
 pub mod trng;
+pub mod wdt;
@@ -0,0 +1,374 @@
+//! Non-Volatile Memory Controller, nRF5X-family
+//!
+//! Reads, writes and erases the internal flash one page at a time through
+//! `hil::flash::Flash`, so it can back a
+//! `capsules::nonvolatile_to_pages::NonvolatileToPages`. Pages are 1 kB on
+//! the nRF51 and 4 kB on the nRF52.
+//!
+//! The NVMC has no interrupt: the CPU is halted while the flash is written
+//! or erased when it executes from flash, so operations are done on the
+//! spot and their completion is reported from a deferred call. Writing a
+//! page erases it first. Erasing a page takes up to 22 ms on the nRF51 and
+//! 85 ms on the nRF52, during which no interrupt is serviced.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! pub static mut FLASH_PAGEBUFFER: nrf5x::nvmc::NrfPage = nrf5x::nvmc::NrfPage::new();
+//! let nv_to_page = static_init!(
+//!     capsules::nonvolatile_to_pages::NonvolatileToPages<'static, nrf5x::nvmc::Nvmc>,
+//!     capsules::nonvolatile_to_pages::NonvolatileToPages::new(
+//!         &mut nrf5x::nvmc::NVMC,
+//!         &mut FLASH_PAGEBUFFER
+//!     )
+//! );
+//! hil::flash::HasClient::set_client(&nrf5x::nvmc::NVMC, nv_to_page);
+//! ```
+
+use core::cell::Cell;
+use core::ops::{Index, IndexMut};
+use core::ptr;
+use helpers::{DeferredCall, Task};
+use kernel::common::regs::{ReadOnly, ReadWrite};
+use kernel::common::take_cell::TakeCell;
+use kernel::hil;
+use kernel::ReturnCode;
+use kernel::StaticRef;
+
+pub const NVMC_BASE: usize = 0x4001E400;
+#[repr(C)]
+struct NvmcRegisters {
+    /// Ready flag
+    /// Address 0x400 - 0x404
+    pub ready: ReadOnly<u32, Ready::Register>,
+    /// Reserved
+    _reserved1: [u32; 64],
+    /// Configuration register
+    /// Address: 0x504 - 0x508
+    pub config: ReadWrite<u32, Configuration::Register>,
+    /// Register for erasing a page in Code area
+    /// Address: 0x508 - 0x50C
+    pub erasepage: ReadWrite<u32, ErasePage::Register>,
+    /// Register for erasing all non-volatile user memory
+    /// Address: 0x50C - 0x510
+    pub eraseall: ReadWrite<u32, EraseAll::Register>,
+    _reserved2: u32,
+    /// Register for erasing User Information Configuration Registers
+    /// Address: 0x514 - 0x518
+    pub eraseuicr: ReadWrite<u32, EraseUicr::Register>,
+    /// Reserved
+    _reserved3: [u32; 10],
+    /// Configuration register, nRF52 only
+    /// Address: 0x540 - 0x544
+    pub icachecnf: ReadWrite<u32, CacheConfiguration::Register>,
+    /// Reserved
+    _reserved4: u32,
+    /// Configuration register
+    /// Address: 0x548 - 0x54c
+    pub ihit: ReadWrite<u32, CacheHit::Register>,
+    /// Configuration register
+    /// Address: 0x54C - 0x550
+    pub imiss: ReadWrite<u32, CacheMiss::Register>,
+}
+
+register_bitfields! [u32,
+    /// Ready flag
+    Ready [
+        /// NVMC is ready or busy
+        READY OFFSET(0) NUMBITS(1) [
+            /// NVMC is busy (on-going write or erase operation)
+            BUSY = 0,
+            /// NVMC is ready
+            READY = 1
+        ]
+    ],
+    /// Configuration register
+    Configuration [
+        /// Program memory access mode. It is strongly recommended
+        /// to only activate erase and write modes when they are actively
+        /// used. Enabling write or erase will invalidate the cache and keep
+        /// it invalidated.
+        WEN OFFSET(0) NUMBITS(2) [
+            /// Read only access
+            REN = 0,
+            /// Write Enabled 
+            WEN = 1,
+            /// Erase enabled
+            EEN = 2
+        ]
+    ],
+    /// Register for erasing a page in Code area
+    ErasePage [
+        /// Register for starting erase of a page in Code area
+        ERASEPAGE OFFSET(0) NUMBITS(32) []
+    ],
+    /// Register for erasing all non-volatile user memory
+    EraseAll [
+        /// Erase all non-volatile memory including UICR registers. Note
+        /// that code erase has to be enabled by CONFIG.EEN before the
+        /// UICR can be erased
+        ERASEALL OFFSET(0) NUMBITS(1) [
+            /// No operation
+            NOOPERATION = 0,
+            /// Start chip erase
+            ERASE = 1
+        ]
+    ],
+    /// Register for erasing User Information Configuration Registers
+    EraseUicr [
+        /// Register starting erase of all User Information Configuratio Registers. 
+        /// Note that code erase has to be enabled by CONFIG.EEN before the UICR can be erased
+        ERASEUICR OFFSET(0) NUMBITS(1) [
+            /// No operation
+            NOOPERATION = 0,
+            /// Start erase of UICR
+            ERASE = 1
+        ]
+    ],
+    /// I-Code cache configuration register 
+    CacheConfiguration [
+        /// Cache enabled
+        CACHEEN OFFSET(0) NUMBITS(1) [
+            /// Disable cache. Invalidates all cache entries
+            DISABLED = 0,
+            /// Enable cache
+            ENABLED = 1
+        ],
+        /// Cache profiling enable 
+        CACHEPROFEN OFFSET(8) NUMBITS(1) [
+            /// Disable cache profiling
+            DISABLED = 0,
+            /// Enable cache profiling
+            ENABLED = 1
+        ]
+    ],
+    /// I-Code cache hit counter 
+    CacheHit [
+        /// Number of cache hits
+        HITS OFFSET(0) NUMBITS(32) []
+    ],
+    /// I-Code cache miss counter
+    CacheMiss [
+        /// Number of cache misses
+        MISSES OFFSET(0) NUMBITS(32) []
+    ]
+];
+
+/// Number of code pages, in the factory information configuration registers
+const FICR_CODESIZE: StaticRef<ReadOnly<u32>> =
+    unsafe { StaticRef::new(0x10000014 as *const ReadOnly<u32>) };
+
+#[cfg(feature = "nrf51")]
+const PAGE_SIZE: usize = 1024;
+#[cfg(not(feature = "nrf51"))]
+const PAGE_SIZE: usize = 4096;
+
+static DEFERRED_CALL: DeferredCall = unsafe { DeferredCall::new(Task::Nvmc) };
+
+/// A page of internal flash, to be used as a buffer:
+///
+/// ```rust
+/// static mut PAGEBUFFER: NrfPage = NrfPage::new();
+/// ```
+pub struct NrfPage(pub [u8; PAGE_SIZE]);
+
+impl NrfPage {
+    pub const fn new() -> NrfPage {
+        NrfPage([0; PAGE_SIZE])
+    }
+}
+
+impl Index<usize> for NrfPage {
+    type Output = u8;
+
+    fn index(&self, idx: usize) -> &u8 {
+        &self.0[idx]
+    }
+}
+
+impl IndexMut<usize> for NrfPage {
+    fn index_mut(&mut self, idx: usize) -> &mut u8 {
+        &mut self.0[idx]
+    }
+}
+
+impl AsMut<[u8]> for NrfPage {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Operation {
+    Idle,
+    Read,
+    Write,
+    Erase,
+}
+
+pub struct Nvmc {
+    regs: *const NvmcRegisters,
+    client: Cell<Option<&'static hil::flash::Client<Nvmc>>>,
+    operation: Cell<Operation>,
+    buffer: TakeCell<'static, NrfPage>,
+}
+
+pub static mut NVMC: Nvmc = Nvmc::new();
+
+impl Nvmc {
+    pub const fn new() -> Nvmc {
+        Nvmc {
+            regs: NVMC_BASE as *const NvmcRegisters,
+            client: Cell::new(None),
+            operation: Cell::new(Operation::Idle),
+            buffer: TakeCell::empty(),
+        }
+    }
+
+    pub fn configure_writeable(&self) {
+        let regs = unsafe { &*self.regs };
+        regs.config.write(Configuration::WEN::WEN);
+    }
+
+    pub fn configure_eraseable(&self) {
+        let regs = unsafe { &*self.regs };
+        regs.config.write(Configuration::WEN::EEN);
+    }
+
+    pub fn configure_readonly(&self) {
+        let regs = unsafe { &*self.regs };
+        regs.config.write(Configuration::WEN::REN);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        let regs = unsafe { &*self.regs };
+        regs.ready.is_set(Ready::READY)
+    }
+
+    /// Number of pages of internal flash
+    pub fn page_count(&self) -> usize {
+        FICR_CODESIZE.get() as usize
+    }
+
+    pub fn page_size(&self) -> usize {
+        PAGE_SIZE
+    }
+
+    /// Check an operation on `page_number` can be started now.
+    fn check_request(&self, page_number: usize) -> ReturnCode {
+        if self.operation.get() != Operation::Idle {
+            ReturnCode::EBUSY
+        } else if page_number >= self.page_count() {
+            ReturnCode::EINVAL
+        } else {
+            ReturnCode::SUCCESS
+        }
+    }
+
+    fn erase(&self, page_number: usize) {
+        let regs = unsafe { &*self.regs };
+        self.configure_eraseable();
+        regs.erasepage.set((page_number * PAGE_SIZE) as u32);
+        while !self.is_ready() {}
+        self.configure_readonly();
+    }
+
+    fn read_page(&self, page_number: usize, buf: &'static mut NrfPage) -> ReturnCode {
+        let status = self.check_request(page_number);
+        if status != ReturnCode::SUCCESS {
+            return status;
+        }
+
+        // The flash is mapped at address 0
+        let address = (page_number * PAGE_SIZE) as *const u8;
+        for (i, byte) in buf.0.iter_mut().enumerate() {
+            *byte = unsafe { ptr::read_volatile(address.offset(i as isize)) };
+        }
+        self.buffer.replace(buf);
+        self.operation.set(Operation::Read);
+        DEFERRED_CALL.set();
+        ReturnCode::SUCCESS
+    }
+
+    fn write_page(&self, page_number: usize, buf: &'static mut NrfPage) -> ReturnCode {
+        let status = self.check_request(page_number);
+        if status != ReturnCode::SUCCESS {
+            return status;
+        }
+
+        self.erase(page_number);
+
+        // The NVMC only takes aligned full words
+        let address = (page_number * PAGE_SIZE) as *mut u32;
+        self.configure_writeable();
+        for (i, word) in buf.0.chunks(4).enumerate() {
+            let word = (word[0] as u32) | (word[1] as u32) << 8 | (word[2] as u32) << 16
+                | (word[3] as u32) << 24;
+            unsafe { ptr::write_volatile(address.offset(i as isize), word) };
+            while !self.is_ready() {}
+        }
+        self.configure_readonly();
+
+        self.buffer.replace(buf);
+        self.operation.set(Operation::Write);
+        DEFERRED_CALL.set();
+        ReturnCode::SUCCESS
+    }
+
+    fn erase_page(&self, page_number: usize) -> ReturnCode {
+        let status = self.check_request(page_number);
+        if status != ReturnCode::SUCCESS {
+            return status;
+        }
+
+        self.erase(page_number);
+        self.operation.set(Operation::Erase);
+        DEFERRED_CALL.set();
+        ReturnCode::SUCCESS
+    }
+
+    /// Called from the deferred call to report the operation done.
+    pub fn handle_interrupt(&self) {
+        let operation = self.operation.get();
+        self.operation.set(Operation::Idle);
+        self.client.get().map(|client| match operation {
+            Operation::Read => {
+                self.buffer.take().map(|buffer| {
+                    client.read_complete(buffer, hil::flash::Error::CommandComplete);
+                });
+            }
+            Operation::Write => {
+                self.buffer.take().map(|buffer| {
+                    client.write_complete(buffer, hil::flash::Error::CommandComplete);
+                });
+            }
+            Operation::Erase => {
+                client.erase_complete(hil::flash::Error::CommandComplete);
+            }
+            Operation::Idle => {}
+        });
+    }
+}
+
+impl<C: hil::flash::Client<Self>> hil::flash::HasClient<'static, C> for Nvmc {
+    fn set_client(&self, client: &'static C) {
+        self.client.set(Some(client));
+    }
+}
+
+impl hil::flash::Flash for Nvmc {
+    type Page = NrfPage;
+
+    fn read_page(&self, page_number: usize, buf: &'static mut Self::Page) -> ReturnCode {
+        self.read_page(page_number, buf)
+    }
+
+    fn write_page(&self, page_number: usize, buf: &'static mut Self::Page) -> ReturnCode {
+        self.write_page(page_number, buf)
+    }
+
+    fn erase_page(&self, page_number: usize) -> ReturnCode {
+        self.erase_page(page_number)
+    }
+}
@@ -47,3 +47,5 @@ pub const RTC2: u32 = 36;
 pub const I2S: u32 = 37;
 #[cfg(feature = "nrf52")]
 pub const FPU: u32 = 38;
+#[cfg(feature = "nrf52840")]
+pub const QSPI: u32 = 41;
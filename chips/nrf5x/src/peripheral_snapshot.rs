@@ -0,0 +1,98 @@
+//! Snapshot of peripheral registers for the panic report
+//!
+//! Many crashes come from a peripheral state machine in a state its driver
+//! did not expect: a radio that never reached TXIDLE, a timer compare that
+//! was missed, a UART that latched an error. That state is lost at reset, so
+//! the panic handler prints the registers that show it, read straight from
+//! the peripherals without going through their drivers.
+//!
+//! Only registers at the same address on the nRF51 and the nRF52 are read,
+//! and only ones whose read has no side effect. A peripheral that is powered
+//! down reads as zeros.
+
+use core::fmt::Write;
+use kernel::common::regs::ReadOnly;
+
+const CLOCK_BASE: usize = 0x40000000;
+const RADIO_BASE: usize = 0x40001000;
+const UART0_BASE: usize = 0x40002000;
+const TIMER0_BASE: usize = 0x40008000;
+
+fn read(base: usize, offset: usize) -> u32 {
+    let register = unsafe { &*((base + offset) as *const ReadOnly<u32>) };
+    register.get()
+}
+
+fn radio_state_name(state: u32) -> &'static str {
+    match state {
+        0 => "DISABLED",
+        1 => "RXRU",
+        2 => "RXIDLE",
+        3 => "RX",
+        4 => "RXDISABLE",
+        9 => "TXRU",
+        10 => "TXIDLE",
+        11 => "TX",
+        12 => "TXDISABLE",
+        _ => "?",
+    }
+}
+
+/// Print the registers of RADIO, TIMER0, CLOCK and UART0.
+///
+/// **NOTE:** The supplied `writer` must be synchronous.
+pub fn snapshot_str<W: Write>(writer: &mut W) {
+    let state = read(RADIO_BASE, 0x550);
+    let _ = writer.write_fmt(format_args!(
+        "\r\n---| Peripherals |---\r\n\
+         RADIO  state {} ({}), power {}, shorts {:#x}, inten {:#x}, frequency {}\r\n\
+         \x20      events ready {} address {} end {} disabled {}, crcstatus {}\r\n",
+        state,
+        radio_state_name(state),
+        read(RADIO_BASE, 0xFFC),
+        read(RADIO_BASE, 0x200),
+        read(RADIO_BASE, 0x304),
+        read(RADIO_BASE, 0x508),
+        read(RADIO_BASE, 0x100),
+        read(RADIO_BASE, 0x104),
+        read(RADIO_BASE, 0x10C),
+        read(RADIO_BASE, 0x110),
+        read(RADIO_BASE, 0x400)
+    ));
+    let _ = writer.write_fmt(format_args!(
+        "TIMER0 cc {:#x} {:#x} {:#x} {:#x}, events compare {} {} {} {}, \
+         shorts {:#x}, inten {:#x}\r\n",
+        read(TIMER0_BASE, 0x540),
+        read(TIMER0_BASE, 0x544),
+        read(TIMER0_BASE, 0x548),
+        read(TIMER0_BASE, 0x54C),
+        read(TIMER0_BASE, 0x140),
+        read(TIMER0_BASE, 0x144),
+        read(TIMER0_BASE, 0x148),
+        read(TIMER0_BASE, 0x14C),
+        read(TIMER0_BASE, 0x200),
+        read(TIMER0_BASE, 0x304)
+    ));
+    let _ = writer.write_fmt(format_args!(
+        "CLOCK  hfclk run {} stat {:#x}, lfclk run {} stat {:#x}, \
+         events hfclkstarted {} lfclkstarted {}\r\n",
+        read(CLOCK_BASE, 0x408),
+        read(CLOCK_BASE, 0x40C),
+        read(CLOCK_BASE, 0x414),
+        read(CLOCK_BASE, 0x418),
+        read(CLOCK_BASE, 0x100),
+        read(CLOCK_BASE, 0x104)
+    ));
+    let _ = writer.write_fmt(format_args!(
+        "UART0  enable {}, errorsrc {:#x}, inten {:#x}, \
+         events rxdrdy {} txdrdy {} error {} endrx {} endtx {}\r\n",
+        read(UART0_BASE, 0x500),
+        read(UART0_BASE, 0x480),
+        read(UART0_BASE, 0x304),
+        read(UART0_BASE, 0x108),
+        read(UART0_BASE, 0x11C),
+        read(UART0_BASE, 0x124),
+        read(UART0_BASE, 0x110),
+        read(UART0_BASE, 0x120)
+    ));
+}
@@ -7,8 +7,10 @@
 
 use kernel::common::VolatileCell;
 
-// Keep track of which pins has a `Pinmux` been created for.
-static mut USED_PINS: VolatileCell<u32> = VolatileCell::new(0);
+// Keep track of which pins has a `Pinmux` been created for. Pins are numbered
+// across ports as in `gpio`, which is also how the PSEL registers of the
+// nRF52840 select pins on port 1.
+static mut USED_PINS: VolatileCell<u64> = VolatileCell::new(0);
 
 /// An opaque wrapper around a configurable pin.
 #[derive(Copy, Clone)]
@@ -33,6 +35,14 @@ impl Pinmux {
     }
 }
 
+impl Pinmux {
+    /// A `Pinmux` for no pin at all, for hardware functions a board leaves
+    /// unconnected, such as the flow control lines of a UART.
+    pub const fn disconnected() -> Pinmux {
+        Pinmux(0xFFFFFFFF)
+    }
+}
+
 impl Into<u32> for Pinmux {
     fn into(self) -> u32 {
         self.0
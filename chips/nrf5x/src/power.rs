@@ -0,0 +1,145 @@
+//! Power management and reset through the POWER peripheral, nRF5X-family
+//!
+//! In System ON the chip sleeps in the low power sub-mode by default, where
+//! the regulators and clocks left without a user are switched off while the
+//! CPU waits in WFI. The constant latency sub-mode keeps them on, trading
+//! idle current for a fixed wake-up time. `set_sub_power_mode` selects one.
+//!
+//! System OFF is the deepest sleep: everything but the DETECT signal of the
+//! GPIO port is off and, by default, RAM is not retained. Wake sources in
+//! `gpio` raise DETECT, which wakes the chip with a reset. A board or capsule
+//! asks for it with `request_system_off`, the chip then enters System OFF
+//! instead of sleeping in WFI the next time the kernel has nothing to run.
+//! `RESETREAS_OFF` in `reset_reason` tells such a wake-up from other resets.
+//!
+//! The chip is reset with the SYSRESETREQ bit of the Cortex-M AIRCR
+//! register. The general purpose retention register (`GPREGRET`) of the
+//! POWER peripheral keeps its value across a soft reset, and Nordic's
+//! serial bootloaders read it at boot: `BOOTLOADER_DFU_START` there makes
+//! them wait for new firmware instead of starting the application.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! hil::reset::Reset::reset_to_bootloader(&nrf5x::power::POWER);
+//! ```
+
+use core::cell::Cell;
+use kernel::common::regs::{ReadOnly, ReadWrite, WriteOnly};
+use kernel::hil;
+use kernel::StaticRef;
+
+const POWER_BASE: usize = 0x40000000;
+
+/// Application Interrupt and Reset Control Register of the System Control
+/// Block
+const SCB_AIRCR: StaticRef<ReadWrite<u32>> =
+    unsafe { StaticRef::new(0xE000ED0C as *const ReadWrite<u32>) };
+const AIRCR_VECTKEY: u32 = 0x05FA << 16;
+const AIRCR_SYSRESETREQ: u32 = 1 << 2;
+
+/// `GPREGRET` value asking the bootloader to enter DFU mode
+pub const BOOTLOADER_DFU_START: u32 = 0xB1;
+
+/// `RESETREAS` bit set when DETECT woke the chip from System OFF
+pub const RESETREAS_OFF: u32 = 1 << 16;
+
+/// Sub power mode of System ON
+#[derive(Copy, Clone, PartialEq)]
+pub enum SubPowerMode {
+    ConstantLatency,
+    LowPower,
+}
+
+#[repr(C)]
+struct PowerRegisters {
+    _reserved_tasks: [u32; 30],
+    /// Enable the constant latency sub-mode
+    /// Address: 0x078 - 0x07C
+    task_constlat: WriteOnly<u32>,
+    /// Enable the low power sub-mode
+    /// Address: 0x07C - 0x080
+    task_lowpwr: WriteOnly<u32>,
+    _reserved0: [u32; 224],
+    /// Reset reason
+    /// Address: 0x400 - 0x404
+    resetreas: ReadOnly<u32>,
+    _reserved1: [u32; 63],
+    /// Enter System OFF
+    /// Address: 0x500 - 0x504
+    systemoff: WriteOnly<u32>,
+    _reserved2: [u32; 6],
+    /// General purpose retention register
+    /// Address: 0x51C - 0x520
+    gpregret: ReadWrite<u32>,
+}
+
+pub struct Power {
+    regs: *const PowerRegisters,
+    system_off_requested: Cell<bool>,
+}
+
+pub static mut POWER: Power = Power::new();
+
+impl Power {
+    const fn new() -> Power {
+        Power {
+            regs: POWER_BASE as *const PowerRegisters,
+            system_off_requested: Cell::new(false),
+        }
+    }
+
+    pub fn set_sub_power_mode(&self, mode: SubPowerMode) {
+        let regs = unsafe { &*self.regs };
+        match mode {
+            SubPowerMode::ConstantLatency => regs.task_constlat.set(1),
+            SubPowerMode::LowPower => regs.task_lowpwr.set(1),
+        }
+    }
+
+    /// Enter System OFF the next time the kernel sleeps, see the module
+    /// documentation. The chip stays in System ON if no pin is a wake source,
+    /// as nothing but a reset could wake it.
+    pub fn request_system_off(&self) {
+        self.system_off_requested.set(true);
+    }
+
+    /// Whether `request_system_off` was called
+    pub fn system_off_requested(&self) -> bool {
+        self.system_off_requested.get()
+    }
+
+    /// Enter System OFF now. The chip wakes with a reset.
+    pub fn system_off(&self) -> ! {
+        let regs = unsafe { &*self.regs };
+        regs.systemoff.set(1);
+        // In debug interface mode System OFF is only emulated and the CPU
+        // keeps running
+        loop {}
+    }
+
+    /// Bits of the `RESETREAS` register: what caused the last reset
+    pub fn reset_reason(&self) -> u32 {
+        let regs = unsafe { &*self.regs };
+        regs.resetreas.get()
+    }
+
+    fn system_reset(&self, gpregret: u32) -> ! {
+        let regs = unsafe { &*self.regs };
+        regs.gpregret.set(gpregret);
+        SCB_AIRCR.set(AIRCR_VECTKEY | AIRCR_SYSRESETREQ);
+        // The reset takes a few cycles to happen
+        loop {}
+    }
+}
+
+impl hil::reset::Reset for Power {
+    fn reset(&self) -> ! {
+        self.system_reset(0)
+    }
+
+    fn reset_to_bootloader(&self) -> ! {
+        self.system_reset(BOOTLOADER_DFU_START)
+    }
+}
@@ -0,0 +1,136 @@
+//! Programmable peripheral interconnect, nRF5X-family
+//!
+//! The PPI connects an event register of one peripheral to a task register of
+//! another so the task is triggered in hardware, without CPU involvement,
+//! whenever the event occurs. This module exposes the programmable channels,
+//! 0-15 on the nRF51 and 0-19 on the nRF52; the nRF52 specific registers,
+//! including its pre-programmed channels and channel groups, live in the
+//! `nrf52` crate, which builds typed end points on top of this allocator.
+//!
+//! Drivers get a programmable channel from `allocate` rather than picking a
+//! fixed number, so two drivers never wire up the same channel. Drivers that
+//! still use fixed channel numbers `reserve` them, which makes allocation
+//! skip them and turns a conflicting reservation into `EBUSY`.
+
+use core::cell::Cell;
+use kernel::common::regs::ReadWrite;
+use kernel::ReturnCode;
+
+const PPI_BASE: usize = 0x4001F000;
+
+/// Number of programmable PPI channels
+#[cfg(not(feature = "nrf52"))]
+pub const NUM_PROGRAMMABLE_CHANNELS: usize = 16;
+#[cfg(feature = "nrf52")]
+pub const NUM_PROGRAMMABLE_CHANNELS: usize = 20;
+/// Total number of channels, including the pre-programmed ones
+pub const NUM_CHANNELS: usize = 32;
+
+#[repr(C)]
+struct ChannelRegisters {
+    /// Channel event end-point
+    eep: ReadWrite<u32>,
+    /// Channel task end-point
+    tep: ReadWrite<u32>,
+}
+
+#[repr(C)]
+struct PpiRegisters {
+    /// Reserved, channel group tasks are not used
+    _reserved0: [u32; 320],
+    /// Channel enable
+    /// Address: 0x500 - 0x504
+    chen: ReadWrite<u32>,
+    /// Channel enable set
+    /// Address: 0x504 - 0x508
+    chenset: ReadWrite<u32>,
+    /// Channel enable clear
+    /// Address: 0x508 - 0x50C
+    chenclr: ReadWrite<u32>,
+    _reserved1: [u32; 1],
+    /// Channel end-points
+    /// Address: 0x510 - 0x590 (nRF51), 0x510 - 0x5B0 (nRF52)
+    ch: [ChannelRegisters; NUM_PROGRAMMABLE_CHANNELS],
+}
+
+pub struct Ppi {
+    regs: *const PpiRegisters,
+    /// Allocated or reserved channels, bit `n` for channel `n`
+    in_use: Cell<u32>,
+}
+
+pub static mut PPI: Ppi = Ppi::new();
+
+impl Ppi {
+    const fn new() -> Ppi {
+        Ppi {
+            regs: PPI_BASE as *const PpiRegisters,
+            in_use: Cell::new(0),
+        }
+    }
+
+    /// Claim a specific channel. Returns `EBUSY` if it was already allocated
+    /// or reserved.
+    pub fn reserve(&self, channel: usize) -> ReturnCode {
+        if channel >= NUM_CHANNELS {
+            return ReturnCode::EINVAL;
+        }
+        if self.is_reserved(channel) {
+            return ReturnCode::EBUSY;
+        }
+        self.in_use.set(self.in_use.get() | (1 << channel));
+        ReturnCode::SUCCESS
+    }
+
+    /// Claim any free programmable channel.
+    pub fn allocate(&self) -> Result<usize, ReturnCode> {
+        match (0..NUM_PROGRAMMABLE_CHANNELS).find(|&channel| !self.is_reserved(channel)) {
+            Some(channel) => {
+                self.in_use.set(self.in_use.get() | (1 << channel));
+                Ok(channel)
+            }
+            None => Err(ReturnCode::ENOMEM),
+        }
+    }
+
+    /// Disable a channel and return it to the allocator. Returns `EINVAL` if
+    /// there is no such channel.
+    pub fn release(&self, channel: usize) -> ReturnCode {
+        if channel >= NUM_CHANNELS {
+            return ReturnCode::EINVAL;
+        }
+        if channel < NUM_PROGRAMMABLE_CHANNELS {
+            self.disable(channel);
+        }
+        self.in_use.set(self.in_use.get() & !(1 << channel));
+        ReturnCode::SUCCESS
+    }
+
+    pub fn is_reserved(&self, channel: usize) -> bool {
+        channel < NUM_CHANNELS && self.in_use.get() & (1 << channel) != 0
+    }
+
+    /// Connect `event` to `task` on a programmable channel. Both are
+    /// register addresses. The channel is left disabled.
+    pub fn connect(&self, channel: usize, event: u32, task: u32) {
+        let regs = unsafe { &*self.regs };
+        regs.chenclr.set(1 << channel);
+        regs.ch[channel].eep.set(event);
+        regs.ch[channel].tep.set(task);
+    }
+
+    pub fn enable(&self, channel: usize) {
+        let regs = unsafe { &*self.regs };
+        regs.chenset.set(1 << channel);
+    }
+
+    pub fn disable(&self, channel: usize) {
+        let regs = unsafe { &*self.regs };
+        regs.chenclr.set(1 << channel);
+    }
+
+    pub fn is_enabled(&self, channel: usize) -> bool {
+        let regs = unsafe { &*self.regs };
+        regs.chen.get() & (1 << channel) != 0
+    }
+}
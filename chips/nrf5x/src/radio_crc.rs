@@ -0,0 +1,102 @@
+//! CRC engine of the nRF51 and nRF52 radios
+//!
+//! The radio computes and checks the CRC of every packet in hardware. The
+//! BLE drivers set it up for BLE, `RadioCrc` lets other protocols built on
+//! the radio, such as Enhanced ShockBurst or a proprietary telemetry link,
+//! set their own length, polynomial and initial value instead of computing
+//! checksums in software. The BLE drivers set the BLE configuration back
+//! before each advertisement or scan.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! // Enhanced ShockBurst: CRC-16-CCITT over the address and the payload
+//! nrf51::radio::RADIO.set_crc(CrcConfig {
+//!     length: CrcLength::Two,
+//!     polynomial: 0x11021,
+//!     init: 0xFFFF,
+//!     skip_address: false,
+//! });
+//! ```
+
+use constants;
+use kernel::ReturnCode;
+
+/// Number of CRC bytes sent after the payload
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CrcLength {
+    Disabled = 0,
+    One = 1,
+    Two = 2,
+    Three = 3,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CrcConfig {
+    pub length: CrcLength,
+    /// Polynomial, bit n set for the term x^n, up to x^24. The x^0 term is
+    /// always there.
+    pub polynomial: u32,
+    /// Initial value, 24 bits at most
+    pub init: u32,
+    /// Leave the address out of the CRC, as BLE does
+    pub skip_address: bool,
+}
+
+/// BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 3.1.1 CRC Generation
+pub const BLE: CrcConfig = CrcConfig {
+    length: CrcLength::Three,
+    polynomial: constants::RADIO_CRCPOLY_BLE,
+    init: constants::RADIO_CRCINIT_BLE,
+    skip_address: true,
+};
+
+const CRC_MAX: u32 = 0xFFFFFF;
+
+impl CrcConfig {
+    /// Check that the polynomial and the initial value fit the radio
+    pub fn validate(&self) -> ReturnCode {
+        if self.polynomial > CRC_MAX || self.init > CRC_MAX {
+            ReturnCode::EINVAL
+        } else {
+            ReturnCode::SUCCESS
+        }
+    }
+
+    /// Value of the CRCCNF register
+    pub fn crccnf(&self) -> u32 {
+        let skip_address = if self.skip_address {
+            constants::RADIO_CRCCNF_SKIPADDR << constants::RADIO_CRCCNF_SKIPADDR_POS
+        } else {
+            0
+        };
+        self.length as u32 | skip_address
+    }
+
+    /// Configuration in the CRCCNF, CRCPOLY and CRCINIT registers
+    pub fn from_registers(crccnf: u32, polynomial: u32, init: u32) -> CrcConfig {
+        CrcConfig {
+            length: match crccnf & 0x3 {
+                1 => CrcLength::One,
+                2 => CrcLength::Two,
+                3 => CrcLength::Three,
+                _ => CrcLength::Disabled,
+            },
+            polynomial: polynomial & CRC_MAX,
+            init: init & CRC_MAX,
+            skip_address: (crccnf >> constants::RADIO_CRCCNF_SKIPADDR_POS) & 0x3
+                == constants::RADIO_CRCCNF_SKIPADDR,
+        }
+    }
+}
+
+pub trait RadioCrc {
+    /// Configure the CRC of the packets sent and received from now on.
+    /// Returns `EINVAL` if the polynomial or the initial value are over 24
+    /// bits.
+    fn set_crc(&self, config: CrcConfig) -> ReturnCode;
+
+    /// Current CRC configuration
+    fn crc(&self) -> CrcConfig;
+}
@@ -1,5 +1,9 @@
 //! RTC driver, nRF5X-family
+//!
+//! The RTC counts the low frequency clock, which it requests from the clock
+//! driver while it is started.
 
+use clock::{ClockDomain, CLOCK};
 use core::cell::Cell;
 use core::mem;
 use kernel::hil::time::{self, Alarm, Freq32KHz, Time};
@@ -12,10 +16,12 @@ fn rtc1() -> &'static RTC1 {
 
 pub struct Rtc {
     callback: Cell<Option<&'static time::Client>>,
+    started: Cell<bool>,
 }
 
 pub static mut RTC: Rtc = Rtc {
     callback: Cell::new(None),
+    started: Cell::new(false),
 };
 
 impl Controller for Rtc {
@@ -37,6 +43,10 @@ impl Rtc {
     pub fn start(&self) {
         // This function takes a nontrivial amount of time
         // So it should only be called during initialization, not each tick
+        if !self.started.get() {
+            self.started.set(true);
+            unsafe { CLOCK.request(ClockDomain::Low) };
+        }
         rtc1().prescaler.set(0);
         rtc1().tasks_start.set(1);
     }
@@ -44,6 +54,10 @@ impl Rtc {
     pub fn stop(&self) {
         rtc1().cc[0].set(0);
         rtc1().tasks_stop.set(1);
+        if self.started.get() {
+            self.started.set(false);
+            unsafe { CLOCK.release(ClockDomain::Low) };
+        }
     }
 
     fn is_running(&self) -> bool {
@@ -2,6 +2,10 @@
 //!
 //! Generates a simple temperature measurement without sampling
 //!
+//! Readings are reported in hundredths of a degree Celsius with the sensor's
+//! 0.25 °C resolution. Negative temperatures are passed as a two's complement
+//! value in the `usize`.
+//!
 //! Authors
 //! -------------------
 //! * Niklas Adolfsson <niklasadolfsson1@gmail.com>
@@ -123,9 +127,13 @@ impl Temperature {
         self.disable_interrupts();
         let regs = unsafe { &*self.regs };
 
+        regs.event_datardy.write(Event::READY::CLEAR);
+
         // get temperature
         // Result of temperature measurement in °C, 2's complement format, 0.25 °C
-        let temp = (regs.temp.get() / 4) * 100;
+        // steps. Convert to hundredths of a degree without dropping the
+        // fraction or the sign.
+        let temp = regs.temp.get() as i32 * 25;
 
         // stop measurement
         regs.task_stop.write(Task::ENABLE::SET);
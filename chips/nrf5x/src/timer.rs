@@ -17,6 +17,14 @@
 //! the RTC from the low frequency clock (lower power) and the scheduler
 //! uses the high frequency clock.
 //!
+//! Drivers claim the capture/compare (CC) channels they use, so two drivers
+//! never share one. A driver that needs particular channels, such as the
+//! radio with the pre-programmed PPI channels wired to TIMER0, `reserve_cc`s
+//! them when it is initialized and treats `EBUSY` as a configuration error.
+//! Other drivers take any free channel with `allocate_cc`. The claims are
+//! kept per peripheral, so they hold across the `Timer` instances of the
+//! same timer.
+//!
 //! Authors
 //! --------
 //! * Philip Levis <pal@cs.stanford.edu>
@@ -26,8 +34,16 @@ use core::cell::Cell;
 use core::mem;
 use kernel::common::VolatileCell;
 use kernel::hil;
+use kernel::ReturnCode;
 use peripheral_registers;
 
+/// Number of capture/compare channels of each timer
+pub const NUM_CC: usize = 4;
+
+/// Claimed capture/compare channels of TIMER0, TIMER1 and TIMER2, bit `n`
+/// for CC[n]
+static mut CC_IN_USE: [Cell<u8>; 3] = [Cell::new(0), Cell::new(0), Cell::new(0)];
+
 #[derive(Copy, Clone)]
 pub enum Location {
     TIMER0,
@@ -90,6 +106,46 @@ impl Timer {
         self.client.set(Some(client));
     }
 
+    fn cc_in_use(&self) -> &'static Cell<u8> {
+        unsafe { &CC_IN_USE[self.which as usize] }
+    }
+
+    /// Claim a specific CC channel. Returns `EBUSY` if it was already
+    /// claimed.
+    pub fn reserve_cc(&self, which: usize) -> ReturnCode {
+        if which >= NUM_CC {
+            return ReturnCode::EINVAL;
+        }
+        if self.is_cc_reserved(which) {
+            return ReturnCode::EBUSY;
+        }
+        let in_use = self.cc_in_use();
+        in_use.set(in_use.get() | (1 << which));
+        ReturnCode::SUCCESS
+    }
+
+    /// Claim any free CC channel.
+    pub fn allocate_cc(&self) -> Result<u8, ReturnCode> {
+        match (0..NUM_CC).find(|&which| !self.is_cc_reserved(which)) {
+            Some(which) => {
+                let in_use = self.cc_in_use();
+                in_use.set(in_use.get() | (1 << which));
+                Ok(which as u8)
+            }
+            None => Err(ReturnCode::ENOMEM),
+        }
+    }
+
+    /// Return a CC channel to the allocator.
+    pub fn release_cc(&self, which: usize) {
+        let in_use = self.cc_in_use();
+        in_use.set(in_use.get() & !(1 << which));
+    }
+
+    pub fn is_cc_reserved(&self, which: usize) -> bool {
+        self.cc_in_use().get() & (1 << which) != 0
+    }
+
     pub fn start(&self) {
         self.timer().task_start.set(1);
     }
@@ -140,6 +196,18 @@ impl Timer {
         let _ = self.capture(which);
     }
 
+    /// Address of the CAPTURE task for the CC register specified by
+    /// which, for use as a PPI task end point.
+    pub fn capture_task_address(&self, which: u8) -> u32 {
+        &self.timer().task_capture[(which & 0x3) as usize] as *const _ as u32
+    }
+
+    /// Address of the COMPARE event for the CC register specified by
+    /// which, for use as a PPI event end point.
+    pub fn compare_event_address(&self, which: u8) -> u32 {
+        &self.timer().event_compare[(which & 0x3) as usize] as *const _ as u32
+    }
+
     /// Shortcuts can automatically stop or clear the timer on a particular
     /// compare event; refer to section 18.3 of the nRF reference manual
     /// for details. Implementation currently provides shortcuts as the
@@ -151,6 +219,12 @@ impl Timer {
         self.timer().shorts.set(shortcut);
     }
 
+    /// Value of the CC register specified by which, as last captured or
+    /// set.
+    pub fn get_cc(&self, which: u8) -> u32 {
+        self.timer().cc[(which & 0x3) as usize].get()
+    }
+
     pub fn get_cc0(&self) -> u32 {
         self.timer().cc[0].get()
     }
@@ -0,0 +1,147 @@
+//! Hardware event timestamping, nRF5X-family
+//!
+//! Latches the time of peripheral events in hardware, the way the radio
+//! captures TIMER0 on its ADDRESS and END events through PPI channels 26 and
+//! 27. A driver asks for "capture the time when event E fires" by passing the
+//! address of the event register. `TIMESTAMP` then allocates a PPI channel
+//! and a CC register of TIMER1 with `allocate_cc`, connects the event to the register's
+//! CAPTURE task, and hands back a `Capture`. The time of the most recent
+//! occurrence of the event is read from it with `read`, at any time after the
+//! event, so the timestamp does not depend on interrupt latency.
+//!
+//! Useful event sources are the `EVENTS_IN` registers of GPIOTE channels
+//! (`GPIOPin::gpiote_event_address`), the ADC END event and the comparator
+//! UP, DOWN and CROSS events. The nRF52 addresses of the latter are in
+//! `nrf52::ppi`.
+//!
+//! TIMER1 runs at 1MHz while at least one capture is set up. It is 32-bit
+//! and wraps around after about 71 minutes on the nRF52, but only 16-bit
+//! on the nRF51, where it wraps around after about 65 milliseconds. It
+//! cannot be used as `timer::ALARM1` at the same time. TIMER1 has four CC
+//! registers, so at most four events are timestamped at once, fewer if
+//! other drivers claimed some.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let pin = &nrf5x::gpio::PORT[3];
+//! pin.enable_interrupt(0, kernel::hil::gpio::InterruptMode::RisingEdge);
+//! let capture = unsafe {
+//!     nrf5x::timestamp::TIMESTAMP.capture(pin.gpiote_event_address().unwrap())
+//! }?;
+//! // Later, from the GPIO interrupt
+//! let time_us = unsafe { nrf5x::timestamp::TIMESTAMP.read(capture) };
+//! ```
+
+use core::cell::Cell;
+use kernel::hil;
+use kernel::ReturnCode;
+use ppi;
+use timer::{BitmodeValue, Location, Timer, NUM_CC};
+
+/// 16MHz divided by 2^4
+const PRESCALER: u8 = 4;
+
+/// Widest mode of TIMER1
+#[cfg(feature = "nrf51")]
+const BITMODE: BitmodeValue = BitmodeValue::Size16Bits;
+#[cfg(not(feature = "nrf51"))]
+const BITMODE: BitmodeValue = BitmodeValue::Size32Bits;
+
+/// Rate of the timestamps
+pub type Frequency = hil::time::Freq1MHz;
+
+/// An event connected to a CC register, see `Timestamper::capture`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Capture {
+    cc: u8,
+    ppi_channel: usize,
+}
+
+pub struct Timestamper {
+    timer: Timer,
+    /// PPI channel feeding each CC register this driver allocated
+    channels: [Cell<Option<usize>>; NUM_CC],
+}
+
+pub static mut TIMESTAMP: Timestamper = Timestamper::new(Location::TIMER1);
+
+impl Timestamper {
+    const fn new(location: Location) -> Timestamper {
+        Timestamper {
+            timer: Timer::new(location),
+            channels: [
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+            ],
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        self.channels.iter().any(|channel| channel.get().is_some())
+    }
+
+    /// Capture the time into a free CC register whenever the event register
+    /// at `event` fires. Returns `ENOMEM` if there is no free CC register or
+    /// PPI channel.
+    pub fn capture(&self, event: u32) -> Result<Capture, ReturnCode> {
+        let cc = self.timer.allocate_cc()? as usize;
+        let ppi_channel = match unsafe { ppi::PPI.allocate() } {
+            Ok(ppi_channel) => ppi_channel,
+            Err(rc) => {
+                self.timer.release_cc(cc);
+                return Err(rc);
+            }
+        };
+
+        if !self.is_running() {
+            // Free running timer
+            self.timer.stop();
+            self.timer.set_bitmode(BITMODE);
+            self.timer.set_prescaler(PRESCALER);
+            self.timer.clear();
+            self.timer.start();
+        }
+        self.channels[cc].set(Some(ppi_channel));
+
+        unsafe {
+            ppi::PPI.connect(
+                ppi_channel,
+                event,
+                self.timer.capture_task_address(cc as u8),
+            );
+            ppi::PPI.enable(ppi_channel);
+        }
+        Ok(Capture {
+            cc: cc as u8,
+            ppi_channel: ppi_channel,
+        })
+    }
+
+    /// Time of the latest occurrence of the event, in ticks of
+    /// `Frequency`, below 2^16 on the nRF51. Undefined until the event has
+    /// fired once.
+    pub fn read(&self, capture: Capture) -> u32 {
+        self.timer.get_cc(capture.cc)
+    }
+
+    /// Disconnect the event and free the CC register and the PPI channel.
+    /// The timer stops once no capture is left.
+    pub fn release(&self, capture: Capture) {
+        let cc = capture.cc as usize;
+        if self.channels[cc].get() != Some(capture.ppi_channel) {
+            return;
+        }
+        self.channels[cc].set(None);
+        unsafe {
+            ppi::PPI.release(capture.ppi_channel);
+        }
+        self.timer.release_cc(cc);
+        if !self.is_running() {
+            self.timer.shutdown();
+        }
+    }
+}
@@ -90,7 +90,7 @@ register_bitfields! [u32,
     /// Configuration register
     Config [
         /// Bias correction
-        DERCEN OFFSET(0) NUMBITS(32)
+        DERCEN OFFSET(0) NUMBITS(1)
     ],
 
     /// Output random number
@@ -182,6 +182,12 @@ impl<'a> Trng<'a> {
         // Reset `valrdy`
         regs.event_valrdy.write(Event::READY::CLEAR);
 
+        // Produce a single unbiased byte per start, the generator is stopped
+        // in hardware as soon as it is ready instead of running until the
+        // next interrupt is serviced
+        regs.config.write(Config::DERCEN::SET);
+        regs.shorts.write(Shorts::VALRDY_STOP::SET);
+
         // Enable interrupts
         self.enable_interrupts();
 
@@ -0,0 +1,197 @@
+//! Watchdog timer, nRF5X-family
+//!
+//! The watchdog counts down from a reload value at 32.768 kHz and resets the
+//! chip when it reaches zero. It is reloaded only once every enabled reload
+//! request register (`RR[n]`) has been written with the reload value since
+//! the last reload, so independent parts of the system can each be given a
+//! channel and the chip is reset if any one of them stops servicing it. The
+//! `hil::watchdog::Watchdog` implementation services channel 0; further
+//! channels are enabled with `enable_channel` and serviced with `reload`.
+//!
+//! The timeout, enabled channels and behaviour while sleeping or halted can
+//! only be configured before the watchdog is started. Once started, the
+//! watchdog cannot be stopped by anything short of a reset.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! nrf5x::wdt::WDT.enable_channel(1);
+//! hil::watchdog::Watchdog::start(&nrf5x::wdt::WDT, 1000);
+//! ...
+//! nrf5x::wdt::WDT.reload(1);
+//! ```
+
+use core::cell::Cell;
+use kernel::common::regs::{ReadOnly, ReadWrite, WriteOnly};
+use kernel::hil;
+
+const WDT_BASE: usize = 0x40010000;
+
+/// Number of reload request channels
+pub const NUM_CHANNELS: usize = 8;
+
+/// Value that must be written to a reload request register
+const RELOAD_VALUE: u32 = 0x6E524635;
+
+/// Shortest counter reload value accepted by the hardware
+const CRV_MIN: u32 = 0xF;
+
+#[repr(C)]
+struct WdtRegisters {
+    /// Start the watchdog
+    /// Address: 0x000 - 0x004
+    task_start: WriteOnly<u32, Task::Register>,
+    _reserved0: [u32; 63],
+    /// Watchdog timeout
+    /// Address: 0x100 - 0x104
+    event_timeout: ReadWrite<u32, Event::Register>,
+    _reserved1: [u32; 128],
+    /// Enable interrupt
+    /// Address: 0x304 - 0x308
+    intenset: ReadWrite<u32, Interrupt::Register>,
+    /// Disable interrupt
+    /// Address: 0x308 - 0x30C
+    intenclr: ReadWrite<u32, Interrupt::Register>,
+    _reserved2: [u32; 61],
+    /// Run status
+    /// Address: 0x400 - 0x404
+    runstatus: ReadOnly<u32, RunStatus::Register>,
+    /// Request status, one bit per reload request register
+    /// Address: 0x404 - 0x408
+    reqstatus: ReadOnly<u32>,
+    _reserved3: [u32; 63],
+    /// Counter reload value
+    /// Address: 0x504 - 0x508
+    crv: ReadWrite<u32>,
+    /// Enable register for reload request registers, one bit per register
+    /// Address: 0x508 - 0x50C
+    rren: ReadWrite<u32>,
+    /// Configuration register
+    /// Address: 0x50C - 0x510
+    config: ReadWrite<u32, Config::Register>,
+    _reserved4: [u32; 60],
+    /// Reload request registers
+    /// Address: 0x600 - 0x620
+    rr: [WriteOnly<u32>; NUM_CHANNELS],
+}
+
+register_bitfields! [u32,
+    Task [
+        ENABLE OFFSET(0) NUMBITS(1)
+    ],
+
+    Event [
+        READY OFFSET(0) NUMBITS(1)
+    ],
+
+    Interrupt [
+        TIMEOUT OFFSET(0) NUMBITS(1)
+    ],
+
+    RunStatus [
+        RUNNING OFFSET(0) NUMBITS(1)
+    ],
+
+    Config [
+        /// Keep counting while the CPU is sleeping
+        SLEEP OFFSET(0) NUMBITS(1) [
+            Pause = 0,
+            Run = 1
+        ],
+        /// Keep counting while the CPU is halted by the debugger
+        HALT OFFSET(3) NUMBITS(1) [
+            Pause = 0,
+            Run = 1
+        ]
+    ]
+];
+
+pub struct Wdt {
+    regs: *const WdtRegisters,
+    /// Reload request channels enabled when the watchdog is started
+    channels: Cell<u32>,
+    /// Whether the watchdog keeps counting while the CPU sleeps
+    run_in_sleep: Cell<bool>,
+}
+
+pub static mut WDT: Wdt = Wdt::new();
+
+impl Wdt {
+    const fn new() -> Wdt {
+        Wdt {
+            regs: WDT_BASE as *const WdtRegisters,
+            channels: Cell::new(1),
+            run_in_sleep: Cell::new(false),
+        }
+    }
+
+    /// Require `channel` to be reloaded as well before the watchdog is
+    /// reloaded. Has no effect once the watchdog is running.
+    pub fn enable_channel(&self, channel: usize) {
+        if channel < NUM_CHANNELS {
+            self.channels.set(self.channels.get() | (1 << channel));
+        }
+    }
+
+    /// Keep the watchdog counting while the CPU sleeps. By default it is
+    /// paused, since the kernel sleeps for as long as no process is
+    /// runnable. Has no effect once the watchdog is running.
+    pub fn set_run_in_sleep(&self, run: bool) {
+        self.run_in_sleep.set(run);
+    }
+
+    pub fn is_running(&self) -> bool {
+        let regs = unsafe { &*self.regs };
+        regs.runstatus.is_set(RunStatus::RUNNING)
+    }
+
+    /// Service a reload request channel.
+    pub fn reload(&self, channel: usize) {
+        let regs = unsafe { &*self.regs };
+        if channel < NUM_CHANNELS {
+            regs.rr[channel].set(RELOAD_VALUE);
+        }
+    }
+
+    /// Channels enabled but not yet serviced since the last reload.
+    pub fn pending_channels(&self) -> u32 {
+        let regs = unsafe { &*self.regs };
+        regs.reqstatus.get()
+    }
+
+    fn start(&self, period: usize) {
+        let regs = unsafe { &*self.regs };
+        if self.is_running() {
+            return;
+        }
+
+        // The counter runs from the 32.768 kHz low frequency clock
+        let crv = (period as u64 * 32768 / 1000) as u32;
+        regs.crv.set(if crv < CRV_MIN { CRV_MIN } else { crv });
+        regs.rren.set(self.channels.get());
+        let sleep = if self.run_in_sleep.get() {
+            Config::SLEEP::Run
+        } else {
+            Config::SLEEP::Pause
+        };
+        regs.config.write(Config::HALT::Pause + sleep);
+
+        regs.event_timeout.write(Event::READY::CLEAR);
+        regs.intenclr.write(Interrupt::TIMEOUT::SET);
+        regs.task_start.write(Task::ENABLE::SET);
+    }
+}
+
+impl hil::watchdog::Watchdog for Wdt {
+    fn start(&self, period: usize) {
+        self.start(period);
+    }
+
+    /// The nRF5X watchdog cannot be stopped once started.
+    fn stop(&self) {}
+
+    fn tickle(&self) {
+        self.reload(0);
+    }
+}
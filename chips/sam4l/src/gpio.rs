@@ -6,6 +6,7 @@ use core::ops::{Index, IndexMut};
 use core::sync::atomic::{AtomicUsize, Ordering};
 use kernel::common::regs::{ReadOnly, ReadWrite, WriteOnly};
 use kernel::hil;
+use kernel::ReturnCode;
 
 #[repr(C)]
 struct Register {
@@ -485,6 +486,13 @@ impl hil::gpio::PinCtl for GPIOPin {
             }
         }
     }
+    /// Only the standard push-pull drive is supported
+    fn set_drive_mode(&self, mode: hil::gpio::DriveMode) -> ReturnCode {
+        match mode {
+            hil::gpio::DriveMode::PushPull => ReturnCode::SUCCESS,
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
 }
 
 impl hil::gpio::Pin for GPIOPin {
@@ -38,7 +38,7 @@ pub mod usart;
 pub mod usbc;
 pub mod wdt;
 
-use cortexm4::{generic_isr, svc_handler, systick_handler};
+use cortexm4::{generic_isr, scb, svc_handler, systick_handler};
 
 unsafe extern "C" fn unhandled_interrupt() {
     let mut interrupt_number: u32;
@@ -159,11 +159,13 @@ unsafe extern "C" fn hard_fault_handler() {
 
         let mode_str = "Kernel";
 
-        let shcsr: u32 = core::ptr::read_volatile(0xE000ED24 as *const u32);
-        let cfsr: u32 = core::ptr::read_volatile(0xE000ED28 as *const u32);
-        let hfsr: u32 = core::ptr::read_volatile(0xE000ED2C as *const u32);
-        let mmfar: u32 = core::ptr::read_volatile(0xE000ED34 as *const u32);
-        let bfar: u32 = core::ptr::read_volatile(0xE000ED38 as *const u32);
+        let scb::FaultStatus {
+            shcsr,
+            cfsr,
+            hfsr,
+            mmfar,
+            bfar,
+        } = scb::fault_status();
 
         let iaccviol = (cfsr & 0x01) == 0x01;
         let daccviol = (cfsr & 0x02) == 0x02;
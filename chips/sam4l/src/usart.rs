@@ -633,6 +633,36 @@ impl USART {
         usart.registers.brgr.write(BaudRate::CD.val(cd));
     }
 
+    /// Set the character format, flow control and baud rate of the UART
+    fn set_mode(&self, usart: &USARTRegManager, params: hil::uart::UARTParams) {
+        // set USART mode register
+        let mut mode = Mode::OVER::SET; // OVER: oversample at 8x
+
+        mode += Mode::CHRL::BITS8; // CHRL: 8-bit characters
+        mode += Mode::USCLKS::CLK_USART; // USCLKS: select CLK_USART
+
+        mode += match params.stop_bits {
+            hil::uart::StopBits::One => Mode::NBSTOP::BITS_1_1,
+            hil::uart::StopBits::Two => Mode::NBSTOP::BITS_2_2,
+        };
+
+        mode += match params.parity {
+            hil::uart::Parity::None => Mode::PAR::NONE, // no parity
+            hil::uart::Parity::Odd => Mode::PAR::ODD,   // odd parity
+            hil::uart::Parity::Even => Mode::PAR::EVEN, // even parity
+        };
+
+        mode += match params.hw_flow_control {
+            true => Mode::MODE::HARD_HAND,
+            false => Mode::MODE::NORMAL,
+        };
+
+        usart.registers.mr.write(mode);
+
+        // Set baud rate
+        self.set_baud_rate(usart, params.baud_rate);
+    }
+
     /// In non-SPI mode, this drives RTS low.
     /// In SPI mode, this asserts (drives low) the chip select line.
     fn rts_enable_spi_assert_cs(&self, usart: &USARTRegManager) {
@@ -809,32 +839,7 @@ impl hil::uart::UART for USART {
         // stop any TX and RX and clear status
         self.reset(usart);
 
-        // set USART mode register
-        let mut mode = Mode::OVER::SET; // OVER: oversample at 8x
-
-        mode += Mode::CHRL::BITS8; // CHRL: 8-bit characters
-        mode += Mode::USCLKS::CLK_USART; // USCLKS: select CLK_USART
-
-        mode += match params.stop_bits {
-            hil::uart::StopBits::One => Mode::NBSTOP::BITS_1_1,
-            hil::uart::StopBits::Two => Mode::NBSTOP::BITS_2_2,
-        };
-
-        mode += match params.parity {
-            hil::uart::Parity::None => Mode::PAR::NONE, // no parity
-            hil::uart::Parity::Odd => Mode::PAR::ODD,   // odd parity
-            hil::uart::Parity::Even => Mode::PAR::EVEN, // even parity
-        };
-
-        mode += match params.hw_flow_control {
-            true => Mode::MODE::HARD_HAND,
-            false => Mode::MODE::NORMAL,
-        };
-
-        usart.registers.mr.write(mode);
-
-        // Set baud rate
-        self.set_baud_rate(usart, params.baud_rate);
+        self.set_mode(usart, params);
     }
 
     fn transmit(&self, tx_data: &'static mut [u8], tx_len: usize) {
@@ -879,6 +884,26 @@ impl hil::uart::UART for USART {
             self.rx_len.set(rx_len);
         });
     }
+
+    fn reconfigure(&self, params: hil::uart::UARTParams) -> ReturnCode {
+        match self.usart_mode.get() {
+            UsartMode::Uart => {}
+            _ => return ReturnCode::EINVAL,
+        }
+        if self.usart_tx_state.get() != USARTStateTX::Idle
+            || self.usart_rx_state.get() != USARTStateRX::Idle
+        {
+            return ReturnCode::EBUSY;
+        }
+        let usart = &USARTRegManager::new(&self);
+        self.set_mode(usart, params);
+        ReturnCode::SUCCESS
+    }
+
+    fn abort_receive(&self) {
+        let usart = &USARTRegManager::new(&self);
+        self.abort_rx(usart, hil::uart::Error::Aborted);
+    }
 }
 
 impl hil::uart::UARTAdvanced for USART {
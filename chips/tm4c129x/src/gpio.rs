@@ -6,6 +6,7 @@ use core::ops::{Index, IndexMut};
 use core::sync::atomic::{AtomicUsize, Ordering};
 use kernel::common::VolatileCell;
 use kernel::hil;
+use kernel::ReturnCode;
 use sysctl;
 
 const CLOCKS: [sysctl::RCGCGPIO; 15] = [
@@ -642,6 +643,13 @@ impl hil::gpio::PinCtl for GPIOPin {
             }
         }
     }
+    /// Only the standard push-pull drive is supported
+    fn set_drive_mode(&self, mode: hil::gpio::DriveMode) -> ReturnCode {
+        match mode {
+            hil::gpio::DriveMode::PushPull => ReturnCode::SUCCESS,
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
 }
 
 impl hil::gpio::Pin for GPIOPin {
@@ -4,6 +4,7 @@ use kernel;
 use kernel::common::take_cell::TakeCell;
 use kernel::common::VolatileCell;
 use kernel::hil;
+use kernel::ReturnCode;
 use sysctl;
 
 #[allow(dead_code)]
@@ -187,4 +188,23 @@ impl hil::uart::UART for UART {
     fn receive(&self, _rx_buffer: &'static mut [u8], _rx_len: usize) {
         unimplemented!()
     }
+
+    /// Only the baud rate can be changed, the UART is always 8N1
+    fn reconfigure(&self, params: hil::uart::UARTParams) -> ReturnCode {
+        match (params.parity, params.stop_bits, params.hw_flow_control) {
+            (hil::uart::Parity::None, hil::uart::StopBits::One, false) => {}
+            _ => return ReturnCode::EINVAL,
+        }
+        if self.buffer.is_some() {
+            return ReturnCode::EBUSY;
+        }
+        let regs: &UARTRegisters = unsafe { &*self.registers };
+        // The last byte may still be shifting out
+        while regs.fr.get() & (1 << 3) != 0 {} // BUSY
+        regs.ctl.set(regs.ctl.get() & !1); // UE
+        self.set_baud_rate(params.baud_rate);
+        ReturnCode::SUCCESS
+    }
+
+    fn abort_receive(&self) {}
 }
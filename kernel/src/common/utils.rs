@@ -1,14 +1,18 @@
 //! Utility macros including `static_init!`.
 
-/// Allocates a global array of static size to initialize data structures.
+/// Allocates a statically-sized global for a value and initializes it.
 ///
-/// The global array is initially set to zero. When this macro is hit, it will
-/// initialize the array to the value given and return a `&'static mut`
-/// reference to it.
+/// When this macro is hit, it will move the value given into a `static mut`
+/// of type `Option<T>` and return a `&'static mut T` to it. The size and
+/// alignment of that global come from its type, so the caller only names
+/// the type:
 ///
-/// Note that you will have to specify the array-size as an argument, but a
-/// wrong size will result in a compile-time error. This argument will be
-/// removed if `std::mem::size_of<T>` ever becomes a `const` function.
+/// ```rust
+/// let led = static_init!(
+///     capsules::led::LED<'static, nrf5x::gpio::GPIOPin>,
+///     capsules::led::LED::new(led_pins)
+/// );
+/// ```
 ///
 /// # Safety
 ///
@@ -19,20 +23,20 @@
 /// destructor.
 #[macro_export]
 macro_rules! static_init {
-    ($T:ty, $e:expr, $size:expr) => (static_init!($T, $e));
     ($T:ty, $e:expr) => {
-        // Ideally we could use mem::size_of<$T>, uninitialized or zerod here
-        // instead of having an `Option`, however that is not currently possible
-        // in Rust, so in some cases we're wasting up to a word.
         {
-            use core::{mem, ptr};
-            // Statically allocate a read-write buffer for the value, write our
-            // initial value into it (without dropping the initial zeros) and
-            // return a reference to it.
+            use core::ptr;
+            // The global holds an `Option` so that it can start out as
+            // `None`, the compiler then lays it out for `$T` wherever it
+            // keeps the value inside the `Option`. Write the value into it
+            // without dropping the previous contents and hand out a
+            // reference to the value inside.
             static mut BUF: Option<$T> = None;
-            let tmp : &'static mut $T = mem::transmute(&mut BUF);
-            ptr::write(tmp as *mut $T, $e);
-            tmp
+            ptr::write(&mut BUF as *mut Option<$T>, Some($e));
+            match BUF {
+                Some(ref mut value) => value,
+                None => unreachable!(),
+            }
         };
     }
 }
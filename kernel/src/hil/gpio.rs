@@ -1,7 +1,9 @@
 //! Interface for direct control of GPIO pins.
 
+use returncode::ReturnCode;
+
 /// Enum for configuring any pull-up or pull-down resistors on the GPIO pin.
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum InputMode {
     PullUp,
     PullDown,
@@ -9,17 +11,74 @@ pub enum InputMode {
 }
 
 /// Enum for selecting which edge to trigger interrupts on.
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug)]
 pub enum InterruptMode {
     RisingEdge,
     FallingEdge,
     EitherEdge,
 }
 
+/// Enum for selecting how an output pin is driven.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DriveMode {
+    /// Drive both high and low at standard strength.
+    PushPull,
+    /// Drive both high and low at high strength.
+    HighDrive,
+    /// Only drive low, for wired-and lines such as I2C.
+    OpenDrain,
+    /// Only drive high, for wired-or lines.
+    OpenSource,
+    /// Only drive low, at high strength.
+    OpenDrainHighDrive,
+    /// Only drive high, at high strength.
+    OpenSourceHighDrive,
+}
+
 pub trait PinCtl {
     /// Configure whether the pin should have a pull-up or pull-down resistor or
     /// neither.
     fn set_input_mode(&self, InputMode);
+
+    /// Configure how the pin drives its output, whether or not it currently
+    /// is an output. Returns `ENOSUPPORT` if the chip cannot drive the pin
+    /// that way, in which case the drive is left unchanged.
+    fn set_drive_mode(&self, DriveMode) -> ReturnCode;
+}
+
+/// Interface for configuring the electrical properties of a pin, for chips
+/// that can do more than `PinCtl` covers.
+pub trait Configure {
+    /// Make the pin an input with the given pull resistor.
+    fn configure_input(&self, mode: InputMode);
+
+    /// Make the pin an output driven as `drive`.
+    fn configure_output(&self, drive: DriveMode);
+
+    /// Disconnect both the input buffer and the output driver, leaving the
+    /// pin in its lowest power state.
+    fn disconnect(&self);
+
+    /// Get the pull resistor currently configured.
+    fn input_mode(&self) -> InputMode;
+
+    /// Get the output driver currently configured, even if the pin is an
+    /// input.
+    fn drive_mode(&self) -> DriveMode;
+
+    /// Whether the pin is currently an output.
+    fn is_output(&self) -> bool;
+}
+
+/// Interface for pins that can wake the chip from its deep sleep states.
+pub trait Wakeup {
+    /// Mark the pin as a wake source, or stop it being one. While the chip
+    /// sleeps, a change of a wake source from the level it had when the chip
+    /// went to sleep wakes it up.
+    fn set_wake_source(&self, wake: bool);
+
+    /// Whether the pin is a wake source.
+    fn is_wake_source(&self) -> bool;
 }
 
 /// Interface for synchronous GPIO pins.
@@ -0,0 +1,31 @@
+//! Interface for measuring the period and pulse width of a digital signal.
+//!
+//! An implementation timestamps consecutive edges of a signal in hardware
+//! (for example a timer capture triggered by the pin) so that the result does
+//! not depend on interrupt latency, and reports one full period of the
+//! signal.
+
+use hil::time::Frequency;
+use returncode::ReturnCode;
+
+pub trait InputCapture {
+    /// Unit of the reported measurements.
+    type Frequency: Frequency;
+
+    /// Start measuring one period of the signal. The client is called with
+    /// the result once three consecutive edges (rising, falling, rising)
+    /// have been seen. Returns `EBUSY` if a measurement is in progress.
+    fn measure(&self) -> ReturnCode;
+
+    /// Abandon the measurement in progress, if any.
+    fn stop(&self) -> ReturnCode;
+
+    fn set_client(&self, client: &'static Client);
+}
+
+pub trait Client {
+    /// Called when a measurement completes. `period` is the time between two
+    /// rising edges and `high_time` the time the signal spent high, both in
+    /// tics of the implementation's `Frequency`.
+    fn measurement_done(&self, period: u32, high_time: u32);
+}
@@ -8,9 +8,12 @@ pub mod flash;
 pub mod gpio;
 pub mod gpio_async;
 pub mod i2c;
+pub mod input_capture;
 pub mod led;
 pub mod nonvolatile_storage;
+pub mod pwm;
 pub mod radio;
+pub mod reset;
 pub mod rng;
 pub mod sensors;
 pub mod spi;
@@ -0,0 +1,29 @@
+//! Interface for pulse width modulation (PWM) outputs.
+
+use returncode::ReturnCode;
+
+/// A PWM peripheral with one or more output channels.
+pub trait Pwm {
+    /// The number of output channels.
+    fn channels(&self) -> usize;
+
+    /// Start generating a signal of `frequency_hz` on `channel`, high for
+    /// `duty_cycle` out of `get_maximum_duty_cycle()` of each period. If the
+    /// channel is already running its duty cycle is changed.
+    ///
+    /// Channels of a peripheral may share a single period, in which case
+    /// starting a channel at a frequency other than that of the channels
+    /// already running returns `EBUSY`. Returns `EINVAL` if the channel does
+    /// not exist or the frequency cannot be generated.
+    fn start(&self, channel: usize, frequency_hz: usize, duty_cycle: usize) -> ReturnCode;
+
+    /// Stop generating a signal on `channel`, the output going back to its
+    /// idle level.
+    fn stop(&self, channel: usize) -> ReturnCode;
+
+    /// The highest frequency `start` accepts.
+    fn get_maximum_frequency_hz(&self) -> usize;
+
+    /// The duty cycle for a signal that is always high.
+    fn get_maximum_duty_cycle(&self) -> usize;
+}
@@ -0,0 +1,10 @@
+//! Interface for resetting the chip.
+
+pub trait Reset {
+    /// Reset the chip, restarting the kernel and all applications.
+    fn reset(&self) -> !;
+
+    /// Reset the chip and ask the bootloader to stay in the bootloader, so
+    /// new firmware can be loaded, instead of starting the kernel.
+    fn reset_to_bootloader(&self) -> !;
+}
@@ -91,6 +91,12 @@ pub trait AES128Ctr {
     fn set_mode_aes128ctr(&self, encrypting: bool);
 }
 
+pub trait AES128Ecb {
+    /// Call before `AES128::crypt()` to perform AES128Ecb. Each block is
+    /// encrypted independently with the key, the IV is not used.
+    fn set_mode_aes128ecb(&self, encrypting: bool);
+}
+
 pub trait AES128CBC {
     /// Call before `AES128::crypt()` to perform AES128CBC
     fn set_mode_aes128cbc(&self, encrypting: bool);
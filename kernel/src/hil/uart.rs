@@ -1,5 +1,7 @@
 //! Interfaces for UART communications.
 
+use returncode::ReturnCode;
+
 #[derive(Copy, Clone, Debug)]
 pub enum StopBits {
     One = 0,
@@ -39,6 +41,9 @@ pub enum Error {
     /// UART hardware was reset
     ResetError,
 
+    /// The receive was stopped by `abort_receive`
+    Aborted,
+
     /// No error occurred and the command completed successfully
     CommandComplete,
 }
@@ -58,6 +63,20 @@ pub trait UART {
 
     /// Receive data until buffer is full.
     fn receive(&self, rx_buffer: &'static mut [u8], rx_len: usize);
+
+    /// Change the parameters of an initialized UART.
+    ///
+    /// Returns `EINVAL` if the parameters are not supported by the chip, even
+    /// while the UART is busy. Otherwise returns `EBUSY` while a transmission
+    /// or a reception is in progress: the caller waits for the transmission
+    /// to complete and aborts the reception first. The UART keeps its
+    /// previous parameters in both cases.
+    fn reconfigure(&self, params: UARTParams) -> ReturnCode;
+
+    /// Stop the reception in progress, if any. The client's
+    /// `receive_complete` is called with the bytes received so far and
+    /// `Error::Aborted`, possibly before this returns.
+    fn abort_receive(&self);
 }
 
 pub trait UARTAdvanced: UART {
@@ -61,12 +61,24 @@ pub fn main<P: Platform, C: Chip>(
         &mut process::PROCS
     };
 
+    // Process to run first on the next pass
+    let mut next_process = 0;
+
     loop {
+        platform.watchdog().map(|watchdog| watchdog.tickle());
+
         unsafe {
             chip.service_pending_interrupts();
 
-            for (i, p) in processes.iter_mut().enumerate() {
-                p.as_mut().map(|process| {
+            // Each pass starts where the previous one stopped. A pass is cut
+            // short whenever an interrupt is pending, and on a busy board
+            // always starting from the first process would leave the later
+            // ones waiting for their callbacks indefinitely.
+            let num_processes = processes.len();
+            for offset in 0..num_processes {
+                let i = (next_process + offset) % num_processes;
+                next_process = (i + 1) % num_processes;
+                processes[i].as_mut().map(|process| {
                     sched::do_process(platform, chip, process, AppId::new(i), ipc);
                 });
                 if chip.has_pending_interrupts() {
@@ -1,4 +1,5 @@
 use driver::Driver;
+use hil;
 
 pub mod mpu;
 pub mod systick;
@@ -10,6 +11,14 @@ pub trait Platform {
     fn with_driver<F, R>(&self, driver_num: usize, f: F) -> R
     where
         F: FnOnce(Option<&Driver>) -> R;
+
+    /// Watchdog serviced by the kernel main loop. Boards that start a
+    /// hardware watchdog return it here so it is tickled on every pass
+    /// through the loop, and the chip resets if the kernel stops making
+    /// progress.
+    fn watchdog(&self) -> Option<&hil::watchdog::Watchdog> {
+        None
+    }
 }
 
 /// Interface for individual MCUs.
@@ -61,6 +61,9 @@ pub trait MPU {
     /// Completely disable the MPU.
     fn disable_mpu(&self);
 
+    /// Number of regions the MPU supports, 0 if the chip has no MPU.
+    fn number_total_regions(&self) -> usize;
+
     /// Creates a new MPU-specific memory protection region
     ///
     /// `region_num`: an MPU region number 0-7
@@ -89,6 +92,10 @@ impl MPU for () {
 
     fn disable_mpu(&self) {}
 
+    fn number_total_regions(&self) -> usize {
+        0
+    }
+
     fn create_region(
         _: usize,
         _: usize,
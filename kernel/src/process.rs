@@ -42,6 +42,19 @@ extern "C" {
 
 pub static mut PROCS: &'static mut [Option<&mut Process<'static>>] = &mut [];
 
+/// Number of callbacks a process can have pending before further ones are
+/// dropped.
+static mut CALLBACK_QUEUE_DEPTH: usize = 10;
+
+/// Set how many callbacks each process can have pending. Boards with
+/// drivers that produce bursts of events for a process, such as BLE
+/// scanning, can raise it so bursts are not dropped before the process gets
+/// to run. The queue is allocated out of each process's memory when it is
+/// loaded, so this must be called before loading processes.
+pub unsafe fn set_callback_queue_depth(depth: usize) {
+    CALLBACK_QUEUE_DEPTH = depth;
+}
+
 /// Helper function to load processes from flash into an array of active
 /// processes. This is the default template for loading processes, but a board
 /// is able to create its own `load_processes()` function and use that instead.
@@ -83,6 +96,190 @@ pub unsafe fn load_processes(start_of_flash: *const u8,
     }
 }
 
+/// Load processes from the app flash region of `flash_size` bytes starting at
+/// `start_of_flash`, like `load_processes()`, but without reading past the end
+/// of the region and dividing `app_memory` evenly between the processes.
+///
+/// The region is walked once first to count the enabled apps, at most
+/// `procs.len()` of them. The walk, and loading, stops at the first image
+/// without a valid header or whose `total_size` runs past the end of the
+/// region. Each process then gets an equal share of `app_memory`, the largest
+/// power of two that fits, and takes the memory its header asks for from the
+/// start of its share. A process asking for more than a share panics the
+/// kernel, as running out of `app_memory` does with `load_processes()`.
+pub unsafe fn load_processes_in_region(start_of_flash: *const u8,
+                                       flash_size: usize,
+                                       app_memory: &mut [u8],
+                                       procs: &mut [Option<&mut Process<'static>>],
+                                       fault_response: FaultResponse) {
+    let mut num_apps = 0;
+    let mut flash_offset = 0;
+    while num_apps < procs.len() {
+        match app_image_in_region(start_of_flash.offset(flash_offset as isize),
+                                  flash_size - flash_offset) {
+            Some((total_size, is_enabled_app)) => {
+                if is_enabled_app {
+                    num_apps += 1;
+                }
+                flash_offset += total_size;
+            }
+            None => break,
+        }
+    }
+    if num_apps == 0 {
+        return;
+    }
+
+    let share: u32 = math::PowerOfTwo::floor((app_memory.len() / num_apps) as u32).as_num();
+    let share = share as usize;
+    let mut flash_offset = 0;
+    let mut app_memory_ptr = app_memory.as_mut_ptr();
+    let mut i = 0;
+    while i < num_apps {
+        let app_flash_ptr = start_of_flash.offset(flash_offset as isize);
+        match app_image_in_region(app_flash_ptr, flash_size - flash_offset) {
+            Some((total_size, true)) => {
+                let (process, _, _) = Process::create(app_flash_ptr,
+                                                      app_memory_ptr,
+                                                      share,
+                                                      fault_response);
+                procs[i] = process;
+                i += 1;
+                flash_offset += total_size;
+                app_memory_ptr = app_memory_ptr.offset(share as isize);
+            }
+            // Padding or a disabled app
+            Some((total_size, false)) => flash_offset += total_size,
+            None => break,
+        }
+    }
+}
+
+/// Check the image at `address` has a valid header and fits in the
+/// `remaining` bytes of its region. Returns its size and whether it is an
+/// enabled app, rather than padding or a disabled app.
+unsafe fn app_image_in_region(address: *const u8, remaining: usize) -> Option<(usize, bool)> {
+    // The version and total size are the first two words in all TBF header
+    // versions
+    if remaining < 8 {
+        return None;
+    }
+    let version = read_volatile(address as *const u16);
+    let total_size = read_volatile(address.offset(4) as *const u32) as usize;
+    let header_size = match version {
+        1 => mem::size_of::<TbfHeaderV1>(),
+        2 => mem::size_of::<TbfHeaderV2Base>(),
+        _ => return None,
+    };
+    if total_size < header_size || total_size > remaining {
+        return None;
+    }
+
+    parse_and_validate_tbf_header(address)
+        .map(|tbf_header| (total_size, tbf_header.is_app() && tbf_header.enabled()))
+}
+
+/// A store of app images the CPU cannot execute from directly, such as an
+/// external SPI flash. It is only read at boot, before the kernel loop runs,
+/// so reads are blocking.
+pub trait AppImageSource {
+    /// Size of the store in bytes.
+    fn size(&self) -> usize;
+
+    /// Fill `buf` with the bytes of the store starting at `offset`.
+    fn read(&self, offset: usize, buf: &mut [u8]) -> ReturnCode;
+}
+
+/// Load processes from app images held in an `AppImageSource`.
+///
+/// Each image is copied into `staging`, a region the CPU can execute from
+/// (RAM, or internal flash mapped as such), and then loaded from there just
+/// like `load_processes()` does for images in flash. Copies are verified by
+/// reading the image back from the source and comparing, and the TBF header
+/// checksum is checked as for any other image, so a corrupted image is not
+/// loaded. Loading stops at the first image that does not fit in `staging`
+/// or does not have a valid header, or when the source is exhausted.
+pub unsafe fn load_processes_from_source(source: &AppImageSource,
+                                         staging: &'static mut [u8],
+                                         app_memory: &mut [u8],
+                                         procs: &mut [Option<&mut Process<'static>>],
+                                         fault_response: FaultResponse) {
+    let mut source_offset = 0;
+    let mut staging_offset = 0;
+    let mut app_memory_ptr = app_memory.as_mut_ptr();
+    let mut app_memory_size = app_memory.len();
+    for i in 0..procs.len() {
+        let total_size = match copy_app_image(source, source_offset, &mut staging[staging_offset..]) {
+            Some(total_size) => total_size,
+            None => break,
+        };
+
+        let (process, flash_offset, memory_offset) = Process::create(staging[staging_offset..].as_ptr(),
+                                                                     app_memory_ptr,
+                                                                     app_memory_size,
+                                                                     fault_response);
+
+        if process.is_none() {
+            // As in `load_processes()`, skip padding and disabled apps, but
+            // stop if the image is not valid.
+            if flash_offset == 0 && memory_offset == 0 {
+                break;
+            }
+        } else {
+            procs[i] = process;
+        }
+
+        source_offset += total_size;
+        // Later images are only copied after this one, keep them aligned
+        staging_offset += align8!(total_size);
+        app_memory_ptr = app_memory_ptr.offset(memory_offset as isize);
+        app_memory_size -= memory_offset;
+        if staging_offset >= staging.len() {
+            break;
+        }
+    }
+}
+
+/// Copy the app image at `offset` in `source` to the start of `staging` and
+/// verify the copy. Returns the size of the image.
+unsafe fn copy_app_image(source: &AppImageSource,
+                         offset: usize,
+                         staging: &mut [u8])
+                         -> Option<usize> {
+    // The version and total size are the first two words in all TBF header
+    // versions
+    let mut header = [0u8; 8];
+    if offset + header.len() > source.size() ||
+       source.read(offset, &mut header) != ReturnCode::SUCCESS {
+        return None;
+    }
+    let version = header[0] as u16 | (header[1] as u16) << 8;
+    let total_size = (header[4] as usize) | (header[5] as usize) << 8 |
+                     (header[6] as usize) << 16 | (header[7] as usize) << 24;
+    if (version != 1 && version != 2) || total_size < header.len() ||
+       total_size > staging.len() || offset + total_size > source.size() {
+        return None;
+    }
+
+    if source.read(offset, &mut staging[..total_size]) != ReturnCode::SUCCESS {
+        return None;
+    }
+
+    // Read the image back and compare, a chunk at a time
+    let mut chunk = [0u8; 64];
+    let mut verified = 0;
+    while verified < total_size {
+        let len = ::core::cmp::min(chunk.len(), total_size - verified);
+        if source.read(offset + verified, &mut chunk[..len]) != ReturnCode::SUCCESS ||
+           chunk[..len] != staging[verified..verified + len] {
+            return None;
+        }
+        verified += len;
+    }
+
+    Some(total_size)
+}
+
 pub fn schedule(callback: FunctionCall, appid: AppId) -> bool {
     let procs = unsafe { &mut PROCS };
     let idx = appid.idx();
@@ -92,6 +289,7 @@ pub fn schedule(callback: FunctionCall, appid: AppId) -> bool {
 
     match procs[idx] {
         None => false,
+        Some(ref mut p) if p.state == State::Fault => false,
         Some(ref mut p) => {
             // TODO(alevy): validate appid liveness
             unsafe {
@@ -111,6 +309,127 @@ pub fn schedule(callback: FunctionCall, appid: AppId) -> bool {
     }
 }
 
+/// Package name of the process `appid` refers to, if it is loaded.
+pub fn package_name(appid: AppId) -> Option<&'static str> {
+    let procs = unsafe { &PROCS };
+    procs.get(appid.idx()).and_then(|p| p.as_ref()).map(|p| p.package_name)
+}
+
+/// Number of process slots, loaded or not. `AppId::new(i)` for `i` below it
+/// names every process.
+pub fn num_processes() -> usize {
+    unsafe { PROCS.len() }
+}
+
+/// Registers of a process when it stopped at a syscall breakpoint.
+#[derive(Copy, Clone, Debug)]
+pub struct SyscallBreak {
+    /// The syscall made, `None` for an unknown SVC number
+    pub syscall: Option<Syscall>,
+    pub r0: usize,
+    pub r1: usize,
+    pub r2: usize,
+    pub r3: usize,
+    /// Address the process resumes at after the syscall
+    pub pc: usize,
+}
+
+/// Told when a process stops at a syscall breakpoint, see
+/// `break_on_next_syscall`.
+pub trait ProcessDebugClient {
+    fn syscall_break(&self, appid: AppId, syscall: SyscallBreak);
+}
+
+static mut DEBUG_CLIENT: Option<&'static ProcessDebugClient> = None;
+
+pub fn set_debug_client(client: &'static ProcessDebugClient) {
+    unsafe {
+        DEBUG_CLIENT = Some(client);
+    }
+}
+
+fn with_process<F: FnOnce(&mut Process) -> ReturnCode>(appid: AppId, f: F) -> ReturnCode {
+    let procs = unsafe { &mut PROCS };
+    match procs.get_mut(appid.idx()) {
+        Some(&mut Some(ref mut p)) => f(p),
+        _ => ReturnCode::EINVAL,
+    }
+}
+
+/// Stop scheduling a process. Its callbacks are still queued, and run once
+/// it is resumed. The board does not sleep while a paused process has work
+/// pending.
+pub fn pause(appid: AppId) -> ReturnCode {
+    with_process(appid, |p| {
+        if p.paused {
+            return ReturnCode::EALREADY;
+        }
+        p.paused = true;
+        ReturnCode::SUCCESS
+    })
+}
+
+/// Schedule a paused process again.
+pub fn resume(appid: AppId) -> ReturnCode {
+    with_process(appid, |p| {
+        if !p.paused {
+            return ReturnCode::EALREADY;
+        }
+        p.paused = false;
+        ReturnCode::SUCCESS
+    })
+}
+
+/// Pause a process at its next syscall. The syscall is carried out, then
+/// the process is paused before it sees the result and the debug client is
+/// handed the syscall arguments.
+pub fn break_on_next_syscall(appid: AppId) -> ReturnCode {
+    with_process(appid, |p| {
+        p.break_on_syscall = true;
+        ReturnCode::SUCCESS
+    })
+}
+
+pub fn is_paused(appid: AppId) -> bool {
+    let procs = unsafe { &PROCS };
+    procs.get(appid.idx()).and_then(|p| p.as_ref()).map_or(false, |p| p.paused)
+}
+
+/// Scheduling state of the process `appid` refers to, if it is loaded. A
+/// process stopped after a fault stays in `State::Fault`.
+pub fn state(appid: AppId) -> Option<State> {
+    let procs = unsafe { &PROCS };
+    procs.get(appid.idx()).and_then(|p| p.as_ref()).map(|p| p.state)
+}
+
+/// Memory of a process, in bytes.
+#[derive(Copy, Clone, Debug)]
+pub struct MemoryUsage {
+    /// RAM given to the process, its grants included
+    pub ram: usize,
+    /// RAM below the application break: stack, data and heap
+    pub app: usize,
+    /// RAM the kernel allocated for grants, at the top of the process RAM
+    pub grants: usize,
+    /// Flash of the process, its header included
+    pub flash: usize,
+}
+
+/// Memory used by the process `appid` refers to, if it is loaded.
+pub fn memory_usage(appid: AppId) -> Option<MemoryUsage> {
+    let procs = unsafe { &PROCS };
+    procs.get(appid.idx()).and_then(|p| p.as_ref()).map(|p| {
+        let start = p.mem_start() as usize;
+        let end = p.mem_end() as usize;
+        MemoryUsage {
+            ram: end - start,
+            app: p.app_break as usize - start,
+            grants: end - p.kernel_memory_break as usize,
+            flash: p.text.len(),
+        }
+    })
+}
+
 /// Returns the full address of the start and end of the flash region that the
 /// app owns and can write to. This includes the app's code and data and any
 /// padding at the end of the app. It does not include the TBF header, or any
@@ -160,6 +479,9 @@ pub enum State {
 pub enum FaultResponse {
     Panic,
     Restart,
+    /// Stop the process and leave the rest of the system running. The
+    /// process stays in the `Fault` state and is never scheduled again.
+    Stop,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -677,6 +999,12 @@ pub struct Process<'a> {
     /// Whether the scheduler can schedule this app.
     state: State,
 
+    /// Left out by the scheduler whatever its state, see `pause`.
+    paused: bool,
+
+    /// Pause at the next syscall, see `break_on_next_syscall`.
+    break_on_syscall: bool,
+
     /// How to deal with Faults occurring in the process
     fault_response: FaultResponse,
 
@@ -693,6 +1021,10 @@ pub struct Process<'a> {
     /// 32-byte aligned.
     mpu_regions: [Cell<(*const u8, math::PowerOfTwo)>; 5],
 
+    /// Whether the grant region is protected by a power of two sized MPU
+    /// region. Assumed until `setup_mpu` finds out the chip has no MPU.
+    grant_region_aligned: Cell<bool>,
+
     /// Essentially a list of callbacks that want to call functions in the
     /// process.
     tasks: RingBuffer<'a, Task>,
@@ -735,6 +1067,9 @@ pub fn ipsr_isr_number_to_str(isr_number: usize) -> &'static str {
 
 impl<'a> Process<'a> {
     pub fn schedule_ipc(&mut self, from: AppId, cb_type: IPCType) {
+        if self.state == State::Fault {
+            return;
+        }
         unsafe {
             HAVE_WORK.set(HAVE_WORK.get() + 1);
         }
@@ -751,6 +1086,32 @@ impl<'a> Process<'a> {
         self.state
     }
 
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Called by the scheduler before it handles a syscall of the process.
+    /// If a breakpoint was set, reports the syscall and pauses the process,
+    /// which takes effect once the syscall is handled.
+    pub fn check_syscall_break(&mut self, appid: AppId) {
+        if !self.break_on_syscall {
+            return;
+        }
+        self.break_on_syscall = false;
+        self.paused = true;
+        let syscall_break = SyscallBreak {
+            syscall: self.svc_number(),
+            r0: self.r0(),
+            r1: self.r1(),
+            r2: self.r2(),
+            r3: self.r3(),
+            pc: self.pc(),
+        };
+        unsafe {
+            DEBUG_CLIENT.map(|client| client.syscall_break(appid, syscall_break));
+        }
+    }
+
     pub fn yield_state(&mut self) {
         if self.state == State::Running {
             self.state = State::Yielded;
@@ -762,6 +1123,7 @@ impl<'a> Process<'a> {
 
     pub unsafe fn fault_state(&mut self) {
         write_volatile(&mut APP_FAULT, 0);
+        let was_running = self.state == State::Running;
         self.state = State::Fault;
 
         match self.fault_response {
@@ -779,6 +1141,15 @@ impl<'a> Process<'a> {
                 // need to re-load() the app
                  */
             }
+            FaultResponse::Stop => {
+                // A running process counts as work until it yields, and so
+                // does every task still queued for it
+                if was_running {
+                    HAVE_WORK.set(HAVE_WORK.get() - 1);
+                }
+                while self.dequeue_task().is_some() {}
+                debug!("Process {} had a fault and was stopped", self.package_name);
+            }
         }
     }
 
@@ -840,6 +1211,8 @@ impl<'a> Process<'a> {
     }
 
     pub fn setup_mpu<MPU: mpu::MPU>(&self, mpu: &MPU) {
+        self.grant_region_aligned.set(mpu.number_total_regions() > 0);
+
         // Text segment read/execute (no write)
         let text_start = self.text.as_ptr() as usize;
         let text_len = self.text.len();
@@ -866,18 +1239,8 @@ impl<'a> Process<'a> {
         }
 
         // Disallow access to grant region
-        let grant_len = unsafe {
-            math::PowerOfTwo::ceiling(
-                self.memory.as_ptr().offset(self.memory.len() as isize) as u32 -
-                    (self.kernel_memory_break as u32)
-            ).as_num::<u32>()
-        };
-        let grant_base = unsafe {
-            self.memory
-                .as_ptr()
-                .offset(self.memory.len() as isize)
-                .offset(-(grant_len as isize))
-        };
+        let grant_base = self.grant_region_start(self.kernel_memory_break);
+        let grant_len = self.mem_end() as usize - grant_base as usize;
 
         match MPU::create_region(2, grant_base as usize, grant_len as usize,
                                  mpu::ExecutePermission::ExecutionNotPermitted,
@@ -962,7 +1325,7 @@ impl<'a> Process<'a> {
 
                 // Allocate memory for callback ring buffer.
                 let callback_size = mem::size_of::<Task>();
-                let callback_len = 10;
+                let callback_len = CALLBACK_QUEUE_DEPTH;
                 let callbacks_offset = callback_len * callback_size;
 
                 // Make room to store this process's metadata.
@@ -1044,6 +1407,8 @@ impl<'a> Process<'a> {
                 process.psr = 0x01000000;
 
                 process.state = State::Yielded;
+                process.paused = false;
+                process.break_on_syscall = false;
                 process.fault_response = fault_response;
 
                 process.mpu_regions = [Cell::new((ptr::null(), math::PowerOfTwo::zero())),
@@ -1051,6 +1416,7 @@ impl<'a> Process<'a> {
                               Cell::new((ptr::null(), math::PowerOfTwo::zero())),
                               Cell::new((ptr::null(), math::PowerOfTwo::zero())),
                               Cell::new((ptr::null(), math::PowerOfTwo::zero()))];
+                process.grant_region_aligned = Cell::new(true);
                 process.tasks = tasks;
                 process.package_name = package_name;
 
@@ -1094,10 +1460,14 @@ impl<'a> Process<'a> {
         self.brk(new_break)
     }
 
+    /// Move the end of the heap, growing or shrinking it within the process
+    /// memory. The heap can grow up to the grant region, which on chips with
+    /// an MPU is the region the MPU protects for the grants, and the MPU is
+    /// set up for the new break the next time the process runs.
     pub fn brk(&mut self, new_break: *const u8) -> Result<*const u8, Error> {
         if new_break < self.mem_start() || new_break >= self.mem_end() {
             Err(Error::AddressOutOfBounds)
-        } else if new_break > self.kernel_memory_break {
+        } else if new_break > self.grant_region_start(self.kernel_memory_break) {
             Err(Error::OutOfMemory)
         } else {
             let old_break = self.app_break;
@@ -1113,9 +1483,23 @@ impl<'a> Process<'a> {
         buf_start_addr >= self.mem_start() && buf_end_addr <= self.mem_end()
     }
 
+    /// Start of the grant region the MPU protects for a given kernel memory
+    /// break. MPU regions are a power of two long, so it is below the break
+    /// unless the grants are exactly a power of two. Without an MPU it is the
+    /// break itself.
+    fn grant_region_start(&self, kernel_memory_break: *const u8) -> *const u8 {
+        if !self.grant_region_aligned.get() {
+            return kernel_memory_break;
+        }
+        let grant_len = math::PowerOfTwo::ceiling(
+            self.mem_end() as u32 - kernel_memory_break as u32).as_num::<u32>();
+        (self.mem_end() as usize).saturating_sub(grant_len as usize) as *const u8
+    }
+
     pub unsafe fn alloc(&mut self, size: usize) -> Option<&mut [u8]> {
         let new_break = self.kernel_memory_break.offset(-(size as isize));
-        if new_break < self.app_break {
+        // The grant region must not take memory the heap already has
+        if new_break < self.app_break || self.grant_region_start(new_break) < self.app_break {
             None
         } else {
             self.kernel_memory_break = new_break;
@@ -35,6 +35,10 @@ pub unsafe fn do_process<P: Platform, C: Chip>(
             break;
         }
 
+        if process.is_paused() {
+            break;
+        }
+
         match process.current_state() {
             process::State::Running => {
                 process.setup_mpu(chip.mpu());
@@ -59,8 +63,8 @@ pub unsafe fn do_process<P: Platform, C: Chip>(
                 }
             },
             process::State::Fault => {
-                // we should never be scheduling a process in fault
-                panic!("Attempted to schedule a faulty process");
+                // A process stopped after a fault never runs again
+                break;
             }
         }
 
@@ -77,6 +81,7 @@ pub unsafe fn do_process<P: Platform, C: Chip>(
 
         // process had a system call, count it
         process.incr_syscall_count();
+        process.check_syscall_break(appid);
         match process.svc_number() {
             Some(Syscall::MEMOP) => {
                 let res = memop::memop(process);
@@ -55,7 +55,9 @@ cp -r boards/hail/target/thumbv7em-none-eabi/doc doc/rustdoc
 # Now can do all the rest.
 add_board imix
 add_board nrf51dk
+add_board microbit
 add_board nrf52dk
+add_board nrf52840dk
 add_board launchxl
 add_board ek-tm4c1294xl
 